@@ -0,0 +1,111 @@
+/// cache implements port::cache's operations with the data cache
+/// maintenance instructions, by virtual address to the point of coherency,
+/// so that a device that doesn't snoop the caches, such as the VideoCore,
+/// sees the same memory as the CPU.  See D7.5.9 of the Arm Architecture
+/// Reference Manual.
+///
+/// It also gives port::memfill DC ZVA, which zeroes a whole block of the
+/// size DCZID_EL0 gives, if DCZID_EL0 says it's permitted.
+use port::cache::CacheOps;
+use port::mem::VirtRange;
+use port::memfill::ZeroBlock;
+
+static CACHE_OPS: CacheOps =
+    CacheOps { line_size: dcache_line_size, clean, invalidate, clean_invalidate };
+
+static ZERO_BLOCK: ZeroBlock = ZeroBlock { size: zva_block_size, zero: zva };
+
+/// DCZID_EL0.DZP, set if DC ZVA is prohibited.
+const DCZID_DZP: usize = 1 << 4;
+
+/// Set port::cache's operations to the ones here, and port::memfill's block
+/// zeroing, if DC ZVA may be used.
+pub fn init() {
+    port::cache::set_cache_ops(&CACHE_OPS);
+    if dczid() & DCZID_DZP == 0 {
+        port::memfill::set_zero_block(&ZERO_BLOCK);
+    }
+}
+
+fn dczid() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let dczid: usize;
+        core::arch::asm!("mrs {dczid}, dczid_el0", dczid = out(reg) dczid);
+        dczid
+    }
+    #[cfg(test)]
+    DCZID_DZP
+}
+
+/// Return the size of the block DC ZVA zeroes, from the BS field of
+/// DCZID_EL0, the log2 of the number of words.
+fn zva_block_size() -> usize {
+    4 << (dczid() & 0xf)
+}
+
+/// Zero the block at `_va`, which is aligned to the block size, and mapped
+/// as normal memory.  DC ZVA on device memory takes an alignment fault.
+unsafe fn zva(_va: *mut u8) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("dc zva, {va}", va = in(reg) _va);
+    }
+}
+
+/// Return the smallest data cache line size, from the DminLine field of
+/// CTR_EL0, the log2 of the number of words.  Maintaining lines of this size
+/// covers every cache, whatever its line size.
+fn dcache_line_size() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let ctr: usize;
+        core::arch::asm!("mrs {ctr}, ctr_el0", ctr = out(reg) ctr);
+        4 << ((ctr >> 16) & 0xf)
+    }
+    #[cfg(test)]
+    64
+}
+
+/// Call `op` with the address of each line of `range`, which is aligned to
+/// lines, then wait for the maintenance to complete, so it's done before a
+/// device is told to access the memory, or the CPU reads it.
+fn for_each_line(range: &VirtRange, op: impl Fn(usize)) {
+    for va in (range.start().addr()..range.end().addr()).step_by(dcache_line_size()) {
+        op(va);
+    }
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("dsb sy");
+    }
+}
+
+/// Write dirty lines back to the point of coherency.
+fn clean(range: &VirtRange) {
+    for_each_line(range, |_va| {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("dc cvac, {va}", va = in(reg) _va);
+        }
+    });
+}
+
+/// Discard lines, without writing them back.
+fn invalidate(range: &VirtRange) {
+    for_each_line(range, |_va| {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("dc ivac, {va}", va = in(reg) _va);
+        }
+    });
+}
+
+/// Write dirty lines back, then discard them.
+fn clean_invalidate(range: &VirtRange) {
+    for_each_line(range, |_va| {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("dc civac, {va}", va = in(reg) _va);
+        }
+    });
+}
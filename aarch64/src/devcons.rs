@@ -1,11 +1,20 @@
 // Racy to start.
 
-use crate::param::KZERO;
+use crate::io::{read_reg, write_reg};
+use crate::kmem::early_mmio_range;
+use crate::param::{CONS_BUFFER_SIZE, EARLY_UART_PA};
+use crate::registers::{AUX_MU_IO, AUX_MU_LSR, rpi_mmio};
+use crate::semihosting::{self, Semihosting};
 use crate::uartmini::MiniUart;
+use crate::uartpl011::Pl011Uart;
+use crate::vmap::vmap_device;
 use core::cell::SyncUnsafeCell;
-use core::mem::MaybeUninit;
-use port::devcons::Console;
-use port::fdt::DeviceTree;
+use port::devcons::{self, Console, Uart};
+use port::fdt::{DeviceTree, Node};
+use port::info;
+use port::mem::{MemError, PhysRange, VirtRange};
+use port::mmio::MapDeviceError;
+use port::oncelock::OnceLock;
 
 // The aarch64 devcons implementation is focussed on Raspberry Pi 3, 4 for now.
 
@@ -32,17 +41,171 @@ use port::fdt::DeviceTree;
 //     https://wiki.osdev.org/Detecting_Raspberry_Pi_Board
 // - Break out mailbox, gpio code
 
+/// Uart drivers the console can use, chosen by compatible string.
+#[derive(Clone, Copy, Debug)]
+enum UartKind {
+    MiniUart,
+    Pl011,
+}
+
+const UART_COMPATIBLES: [(&str, UartKind); 2] =
+    [("brcm,bcm2835-aux-uart", UartKind::MiniUart), ("arm,pl011", UartKind::Pl011)];
+
+enum ConsUart {
+    MiniUart(MiniUart),
+    Pl011(Pl011Uart),
+}
+
+impl ConsUart {
+    fn new<E>(
+        dt: &DeviceTree,
+        kind: UartKind,
+        uart: Node,
+        map: impl Fn(&PhysRange) -> Result<VirtRange, E>,
+    ) -> Result<Self, MapDeviceError<E>> {
+        Ok(match kind {
+            UartKind::MiniUart => Self::MiniUart(MiniUart::new(dt, uart, map)?),
+            UartKind::Pl011 => Self::Pl011(Pl011Uart::new(dt, uart, map)?),
+        })
+    }
+
+    fn init(&self) {
+        match self {
+            Self::MiniUart(uart) => uart.init(),
+            Self::Pl011(uart) => uart.init(),
+        }
+    }
+}
+
+impl Uart for ConsUart {
+    fn putb(&self, b: u8) {
+        match self {
+            Self::MiniUart(uart) => uart.putb(b),
+            Self::Pl011(uart) => uart.putb(b),
+        }
+    }
+
+    fn getb(&self) -> Option<u8> {
+        match self {
+            Self::MiniUart(uart) => uart.getb(),
+            Self::Pl011(uart) => uart.getb(),
+        }
+    }
+}
+
+/// Find the console uart: the node given by stdout-path in /chosen, or else
+/// the serial0 alias, which the Raspberry Pi firmware points at the uart on
+/// the serial pins, or else the first enabled node with a driver.  It must be
+/// reachable through the early MMIO mapping.  Returns the uart's node and
+/// registers, or None if there's no such uart.
+fn find_console(dt: &DeviceTree) -> Option<(UartKind, Node, PhysRange)> {
+    let usable = |node: Node| -> Option<(UartKind, Node, PhysRange)> {
+        if !dt.is_enabled(&node) {
+            return None;
+        }
+        let kind = UART_COMPATIBLES
+            .iter()
+            .find(|(comp, _)| dt.is_compatible(&node, comp))
+            .map(|(_, kind)| *kind)?;
+        let range = dt.translated_regions(node).next()?.ok()?.phys_range()?;
+        let mmio = rpi_mmio()?;
+        if range.start() < mmio.start() || range.end() > mmio.end() {
+            return None;
+        }
+        Some((kind, node, range))
+    };
+    dt.stdout_path()
+        .and_then(usable)
+        .or_else(|| dt.resolve_path("serial0").and_then(usable))
+        .or_else(|| dt.nodes().find_map(usable))
+}
+
+/// The console to use if `find_console` doesn't find one: the mini uart,
+/// as it needs no extra configuration on the Raspberry Pi.
+fn default_console(dt: &DeviceTree) -> (UartKind, Node, PhysRange) {
+    let uart = dt.find_compatible("brcm,bcm2835-aux-uart").next().unwrap();
+    let region = dt.translated_regions(uart).next().unwrap().unwrap();
+    (UartKind::MiniUart, uart, PhysRange::from(&region.reg()))
+}
+
+/// Offset of the mini uart registers from the MMIO base.
+const MINIUART_OFFSET: u64 = 0x21_5040;
+
+/// Write `b` to the mini uart, which l.S has already set up, through the
+/// early MMIO mapping, without locking.  The registers are at EARLY_UART_PA,
+/// if set, or found from the board type.  Gives up waiting for the uart
+/// eventually rather than hang, as this is best effort.  It stops working
+/// once the early MMIO mapping is removed.
+fn early_putb(b: u8) {
+    let Some(pa) =
+        EARLY_UART_PA.or_else(|| rpi_mmio().map(|mmio| mmio.start().addr() + MINIUART_OFFSET))
+    else {
+        return;
+    };
+    let Ok(regs) = early_mmio_range(&PhysRange::with_len(pa, 0x40));
+    for _ in 0..100_000 {
+        // Transmitter can accept a byte
+        if read_reg(&regs, AUX_MU_LSR) & (1 << 5) != 0 {
+            break;
+        }
+    }
+    write_reg(&regs, AUX_MU_IO, b as u32);
+}
+
+/// Set up `port::early_println!`, for output before `init`, e.g. from early
+/// asserts or the panic handler.
+pub fn init_early() {
+    if cfg!(feature = "semihosting") {
+        devcons::set_early_putb(|b| Semihosting.putb(b));
+    } else {
+        devcons::set_early_putb(early_putb);
+    }
+}
+
+/// Console uart, with registers in the early MMIO mapping.
+static EARLY_UART: OnceLock<ConsUart> = OnceLock::new();
+
+/// Console uart, with registers mapped with vmap.
+static UART: OnceLock<ConsUart> = OnceLock::new();
+
+static CONS_BUFFER: SyncUnsafeCell<[u8; CONS_BUFFER_SIZE]> =
+    SyncUnsafeCell::new([0; CONS_BUFFER_SIZE]);
+
 pub fn init(dt: &DeviceTree) {
+    if CONS_BUFFER_SIZE > 0 {
+        devcons::buffer_output(unsafe { &mut *CONS_BUFFER.get() });
+    }
+
+    if cfg!(feature = "semihosting") {
+        semihosting::init();
+        info!("Console: semihosting");
+        return;
+    }
+
+    let found = find_console(dt);
+    let source = if found.is_some() { "device tree" } else { "default" };
+    let (kind, node, range) = found.unwrap_or_else(|| default_console(dt));
     Console::new(|| {
-        let uart = MiniUart::new(dt, KZERO);
-        uart.init();
-
-        static UART: SyncUnsafeCell<MaybeUninit<MiniUart>> =
-            SyncUnsafeCell::new(MaybeUninit::uninit());
-        unsafe {
-            let cons = &mut *UART.get();
-            cons.write(uart);
-            cons.assume_init_mut()
-        }
+        EARLY_UART.get_or_init(|| {
+            let uart = ConsUart::new(dt, kind, node, early_mmio_range)
+                .unwrap_or_else(|err| panic!("error:devcons:init:can't map uart: {err:?}"));
+            uart.init();
+            uart
+        })
     });
+
+    info!("Console: {:?} at {range} ({source})", kind);
+}
+
+/// Switch the console from the early MMIO mapping to the uart's registers
+/// mapped with vmap.  The uart is already initialised, so only its ranges
+/// change.
+pub fn init_vmap(dt: &DeviceTree) -> Result<(), MapDeviceError<MemError>> {
+    if cfg!(feature = "semihosting") {
+        return Ok(());
+    }
+    let (kind, node, _) = find_console(dt).unwrap_or_else(|| default_console(dt));
+    let uart = ConsUart::new(dt, kind, node, vmap_device)?;
+    Console::new(|| UART.get_or_init(|| uart));
+    Ok(())
 }
@@ -0,0 +1,168 @@
+/// dma allocates buffers for devices to read and write: physically
+/// contiguous pages, optionally below a limit for devices that can't address
+/// all of memory, mapped with vmap, and the address the device uses for
+/// them.  Buffers are mapped non-cacheable unless asked otherwise, in which
+/// case `sync_for_device` and `sync_for_cpu` do the cache maintenance the
+/// device needs.
+use crate::pagealloc;
+use crate::vmap::{vmap_memory, vunmap};
+use core::slice;
+use port::cache;
+use port::mem::{MapFlags, MemError, PAGE_SIZE_4K, PhysAddr, PhysRange, VirtRange};
+use port::memaccount::MemCategory;
+
+#[cfg(not(test))]
+use port::println;
+
+/// What a device needs of a buffer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DmaConstraints {
+    pub limit: Option<PhysAddr>, // The buffer must end at or below this
+    pub cacheable: bool,         // Map as cacheable, which needs syncing
+    pub bus_alias: u64,          // Ored into physical addresses to make bus addresses
+}
+
+impl DmaConstraints {
+    /// For the VideoCore, e.g. the mailbox: it can only address the first
+    /// 1GiB, and uses the 0xC0000000 alias of that to bypass its L2 cache,
+    /// as the ARM cores don't share it.
+    pub const VIDEOCORE: DmaConstraints = DmaConstraints {
+        limit: Some(PhysAddr::new(0x4000_0000)),
+        cacheable: false,
+        bus_alias: 0xc000_0000,
+    };
+
+    /// Return the number of pages for a buffer of `len` bytes.
+    fn pages_for(&self, len: usize) -> Result<usize, MemError> {
+        if len == 0 {
+            return Err(MemError::InvalidSize { size: len });
+        }
+        Ok(len.div_ceil(PAGE_SIZE_4K))
+    }
+
+    /// Return the bus address of `pa`.  The alias is ored in, so it relies on
+    /// `pa` being below the limit, where the alias bits are clear.
+    fn bus_addr(&self, pa: PhysAddr) -> u64 {
+        pa.addr() | self.bus_alias
+    }
+
+    fn map_flags(&self) -> MapFlags {
+        if self.cacheable { MapFlags::RW } else { MapFlags::RW | MapFlags::NON_CACHEABLE }
+    }
+}
+
+/// A buffer for DMA, which is unmapped and freed when dropped.
+pub struct DmaBuffer {
+    pages: PhysRange,
+    virt: VirtRange,
+    len: usize,
+    constraints: DmaConstraints,
+}
+
+impl DmaBuffer {
+    /// Allocate a zeroed buffer of `len` bytes, meeting `constraints`.
+    pub fn alloc(len: usize, constraints: DmaConstraints) -> Result<DmaBuffer, MemError> {
+        let page_count = constraints.pages_for(len)?;
+        let pages = match constraints.limit {
+            Some(limit) => pagealloc::allocate_contiguous_physpages_below_for(
+                page_count,
+                limit,
+                MemCategory::Dma,
+            ),
+            None => pagealloc::allocate_contiguous_physpages_for(page_count, MemCategory::Dma),
+        }?;
+
+        let virt = match vmap_memory(&pages, constraints.map_flags()) {
+            Ok(virt) => virt,
+            Err(err) => {
+                let _ = pagealloc::free_physpages_for(&pages, MemCategory::Dma);
+                return Err(err);
+            }
+        };
+        let mut buffer = DmaBuffer { pages, virt, len, constraints };
+        buffer.virt_mut().fill(0);
+        buffer.sync_for_device();
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The buffer, as the CPU sees it.
+    pub fn virt(&self) -> &[u8] {
+        // Safety: the buffer is mapped read-write until it's dropped
+        unsafe { slice::from_raw_parts(self.virt.start().addr() as *const u8, self.len) }
+    }
+
+    pub fn virt_mut(&mut self) -> &mut [u8] {
+        // Safety: the buffer is mapped read-write until it's dropped
+        unsafe { slice::from_raw_parts_mut(self.virt.start().addr() as *mut u8, self.len) }
+    }
+
+    /// The physical memory of the buffer.
+    #[allow(dead_code)]
+    pub fn phys(&self) -> PhysRange {
+        PhysRange::with_pa_len(self.pages.start(), self.len)
+    }
+
+    /// The address the device uses for the start of the buffer.
+    pub fn bus_addr(&self) -> u64 {
+        self.constraints.bus_addr(self.pages.start())
+    }
+
+    /// Write what the CPU wrote to the buffer back to memory, before the
+    /// device reads it.  Does nothing unless the buffer is cacheable.
+    pub fn sync_for_device(&self) {
+        if self.constraints.cacheable {
+            cache::clean(&self.virt);
+        }
+    }
+
+    /// Discard the buffer from the cache, after the device wrote it and
+    /// before the CPU reads it.  Does nothing unless the buffer is cacheable.
+    /// The buffer is whole pages, so no other data shares its lines.
+    pub fn sync_for_cpu(&self) {
+        if self.constraints.cacheable {
+            cache::invalidate(&self.virt);
+        }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Err(err) = vunmap(&self.virt) {
+            // The pages can't be freed while they might still be mapped
+            println!("error:dma:drop:couldn't unmap buffer:{} err:{err}", self.pages);
+            return;
+        }
+        if let Err(err) = pagealloc::free_physpages_for(&self.pages, MemCategory::Dma) {
+            println!("error:dma:drop:couldn't free buffer:{} err:{err}", self.pages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_for_len() {
+        let constraints = DmaConstraints::default();
+        assert_eq!(constraints.pages_for(0), Err(MemError::InvalidSize { size: 0 }));
+        assert_eq!(constraints.pages_for(1).unwrap(), 1);
+        assert_eq!(constraints.pages_for(PAGE_SIZE_4K).unwrap(), 1);
+        assert_eq!(constraints.pages_for(PAGE_SIZE_4K + 1).unwrap(), 2);
+    }
+
+    #[test]
+    fn bus_addrs_and_flags() {
+        let pa = PhysAddr::new(0x3b40_0000);
+        assert_eq!(DmaConstraints::default().bus_addr(pa), 0x3b40_0000);
+        assert_eq!(DmaConstraints::VIDEOCORE.bus_addr(pa), 0xfb40_0000);
+
+        assert_eq!(DmaConstraints::VIDEOCORE.map_flags(), MapFlags::RW | MapFlags::NON_CACHEABLE);
+        let cacheable = DmaConstraints { cacheable: true, ..Default::default() };
+        assert_eq!(cacheable.map_flags(), MapFlags::RW);
+    }
+}
@@ -0,0 +1,378 @@
+/// The direct map makes all physical RAM reachable at a fixed offset from
+/// the start of the DIRECT_MAP region, so that any page can be accessed, e.g.
+/// to zero it, without mapping it first.  This is separate from the KZERO offset mapping, which
+/// only covers the kernel image, and is built with the largest blocks
+/// possible.  Only RAM discovered from the device tree is mapped, and
+/// translating any other address fails.
+///
+/// Some of what the device tree calls RAM may belong to firmware or the
+/// secure world, and a cacheable mapping of it, even one never used, may be
+/// fetched from speculatively, causing SErrors or corruption.  Such ranges
+/// are excluded from the direct map: left unmapped, or if they need to be
+/// read, mapped read-only and uncached.  They're found from /reserved-memory
+/// nodes marked no-map, the board's entries in param::DMAP_EXCLUDED, and
+/// reserve= boot arguments, and translating addresses in them fails too.
+use crate::kmem::{record_addr_range, record_phys_range};
+use crate::param::{DIRECT_MAP, MAX_DMAP_EXCLUSIONS};
+use crate::vm::{AddressSpace, MapStats};
+use crate::vmap::phys_to_vmap;
+use port::addrmap::AddrKind;
+use port::debug::HexDump;
+use port::mcslock::{Lock, LockNode};
+use port::mem::{
+    MapFlags, MemError, PAGE_SIZE_4K, PhysAddr, PhysRange, PhysRangeSet, VirtAddr, VirtRange,
+};
+use port::memfill::zero_range;
+use port::physaccess::{PhysMappings, PhysWord, read_phys_with, write_phys_with};
+
+#[cfg(not(test))]
+use port::{print, println};
+
+/// Why a range of RAM is excluded from the direct map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExclusionReason {
+    NoMap,   // A /reserved-memory node marked no-map
+    Board,   // The board's entry in param::DMAP_EXCLUDED
+    BootArg, // A reserve= boot argument
+}
+
+impl ExclusionReason {
+    /// Name of ranges excluded for the reason, in the physical memory map.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NoMap => "dmap excluded: no-map",
+            Self::Board => "dmap excluded: board",
+            Self::BootArg => "dmap excluded: reserve=",
+        }
+    }
+}
+
+/// A range of RAM to exclude from the direct map on the boards whose root
+/// node is compatible with `compatible`.  If `uncached`, it's mapped
+/// read-only and uncached rather than left unmapped.
+pub struct BoardExclusion {
+    pub compatible: &'static str,
+    pub start: u64,
+    pub len: u64,
+    pub uncached: bool,
+}
+
+/// A range of RAM excluded from the direct map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exclusion {
+    pub range: PhysRange,
+    pub reason: ExclusionReason,
+    pub uncached: bool,
+}
+
+/// How uncached exclusions are mapped.
+const UNCACHED_FLAGS: MapFlags = MapFlags::READ.union(MapFlags::NON_CACHEABLE);
+
+/// The ranges excluded from the direct map, with room for
+/// MAX_DMAP_EXCLUSIONS.
+#[derive(Clone)]
+pub struct Exclusions {
+    list: [Option<Exclusion>; MAX_DMAP_EXCLUSIONS],
+}
+
+impl Exclusions {
+    pub const fn new() -> Self {
+        Self { list: [const { None }; MAX_DMAP_EXCLUSIONS] }
+    }
+
+    /// Exclude the pages covering `range`.  Fails with `TableFull` if there
+    /// are already MAX_DMAP_EXCLUSIONS ranges excluded.
+    pub fn add(
+        &mut self,
+        range: &PhysRange,
+        reason: ExclusionReason,
+        uncached: bool,
+    ) -> Result<(), MemError> {
+        let slot = self.list.iter_mut().find(|slot| slot.is_none()).ok_or(MemError::TableFull)?;
+        let range = range.round_out(PAGE_SIZE_4K as u64);
+        *slot = Some(Exclusion { range, reason, uncached });
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Exclusion> {
+        self.list.iter().flatten()
+    }
+}
+
+/// RAM mapped by the direct map, less the exclusions.  Empty until `init`.
+static DMAP_RAM: Lock<PhysRangeSet> = Lock::new("dmap", PhysRangeSet::new());
+
+/// The ranges excluded from the direct map by `init`.
+static DMAP_EXCLUDED: Lock<Exclusions> = Lock::new("dmap_excluded", Exclusions::new());
+
+/// RAM in the exclusions mapped uncached, read-only.  Empty until `init`.
+static DMAP_UNCACHED: Lock<PhysRangeSet> = Lock::new("dmap_uncached", PhysRangeSet::new());
+
+/// Map each range of `memory` into the direct map of `kernel_space`, which
+/// must be the current kernel address space, except for `exclusions`, which
+/// are left unmapped, or mapped uncached.  Only whole pages are mapped, and
+/// memory beyond the size of the DIRECT_MAP region is ignored.  Returns the
+/// number of entries of each size used.  The window and the RAM mapped in it
+/// are recorded in the kernel address map, and the exclusions in the
+/// physical memory map.
+pub fn init(
+    kernel_space: &mut AddressSpace,
+    memory: &PhysRangeSet,
+    exclusions: &Exclusions,
+) -> Result<MapStats, MemError> {
+    record_addr_range("dmap", AddrKind::DirectMap, DIRECT_MAP.range(), None);
+
+    let mut ram = PhysRangeSet::new();
+    for range in memory.iter() {
+        let start = range.start().round_up(PAGE_SIZE_4K as u64);
+        let end = range.end().round_down(PAGE_SIZE_4K as u64);
+        let end = end.min(PhysAddr::new(DIRECT_MAP.size as u64));
+        if start >= end {
+            println!("error:dmap:init:can't direct map memory range:{range}");
+            continue;
+        }
+        // Both sets have the same capacity, so this can't fail
+        let _ = ram.add(&PhysRange::new(start, end));
+    }
+    let mut mapped = ram.clone();
+    for exclusion in exclusions.iter() {
+        if mapped.remove(&exclusion.range).is_err() {
+            panic!("error:dmap:init:too many ranges, can't exclude {}", exclusion.range);
+        }
+    }
+
+    let mut stats = MapStats::default();
+    let mut uncached = PhysRangeSet::new();
+    for range in mapped.iter() {
+        let va = VirtAddr::new(DIRECT_MAP.start + range.start().addr() as usize);
+        stats += kernel_space.map_range(va, range, MapFlags::RW)?;
+        record_addr_range(
+            "dmap ram",
+            AddrKind::DirectMapRam,
+            VirtRange::with_len(va, range.size()),
+            Some(range.clone()),
+        );
+    }
+    for exclusion in exclusions.iter() {
+        let flags = exclusion.uncached.then_some(UNCACHED_FLAGS);
+        record_phys_range(exclusion.reason.name(), exclusion.range.clone(), flags);
+        if !exclusion.uncached {
+            continue;
+        }
+        for range in ram.iter() {
+            let start = range.start().max(exclusion.range.start());
+            let end = range.end().min(exclusion.range.end());
+            if start < end {
+                let range = PhysRange::new(start, end);
+                let va = VirtAddr::new(DIRECT_MAP.start + start.addr() as usize);
+                stats += kernel_space.map_range(va, &range, UNCACHED_FLAGS)?;
+                if uncached.add(&range).is_err() {
+                    println!("error:dmap:init:too many uncached ranges, can't record {range}");
+                }
+            }
+        }
+    }
+
+    let node = LockNode::new();
+    *DMAP_RAM.lock(&node) = mapped;
+    *DMAP_EXCLUDED.lock(&node) = exclusions.clone();
+    *DMAP_UNCACHED.lock(&node) = uncached;
+    Ok(stats)
+}
+
+/// Return the ranges excluded from the direct map.
+#[allow(dead_code)]
+pub fn exclusions() -> Exclusions {
+    let node = LockNode::new();
+    DMAP_EXCLUDED.lock(&node).clone()
+}
+
+/// Return the direct map addresses of `range`, if it's entirely within one
+/// of `mapped`, or `PhysNotMapped` with the first address of it that isn't.
+fn mapped_range(mapped: &PhysRangeSet, range: &PhysRange) -> Result<VirtRange, MemError> {
+    match mapped.iter().find(|r| r.start() <= range.start() && range.start() < r.end()) {
+        Some(r) if range.end() <= r.end() => {
+            let start = VirtAddr::new(DIRECT_MAP.start + range.start().addr() as usize);
+            Ok(VirtRange::with_len(start, range.size()))
+        }
+        Some(r) => Err(MemError::PhysNotMapped { pa: r.end() }),
+        None => Err(MemError::PhysNotMapped { pa: range.start() }),
+    }
+}
+
+/// Return the direct map addresses of `range`, if it's entirely within RAM
+/// excluded from the direct map, but mapped uncached, read-only.
+#[allow(dead_code)]
+pub fn dmap_uncached_range(range: &PhysRange) -> Result<VirtRange, MemError> {
+    let node = LockNode::new();
+    mapped_range(&DMAP_UNCACHED.lock(&node), range)
+}
+
+/// Return the direct map address of `pa`.  Fails with `PhysNotMapped` if
+/// it's not in RAM, or is excluded from the direct map.
+pub fn phys_to_dmap(pa: PhysAddr) -> Result<VirtAddr, MemError> {
+    dmap_range(&PhysRange::with_pa_len(pa, 1)).map(|range| range.start())
+}
+
+/// Return the direct map addresses of `range`.  Fails with `PhysNotMapped`,
+/// giving the first address that isn't mapped, unless it's entirely within
+/// one range of RAM mapped by the direct map, so not excluded.
+pub fn dmap_range(range: &PhysRange) -> Result<VirtRange, MemError> {
+    let node = LockNode::new();
+    let ram = DMAP_RAM.lock(&node);
+    let va = mapped_range(&ram, range)?;
+    // init only records RAM that fits in the window
+    debug_assert!(
+        range.end().addr() <= DIRECT_MAP.size as u64,
+        "dmap_range: {range} is beyond the direct map, which covers {}",
+        *ram
+    );
+    Ok(va)
+}
+
+/// Return the physical address of the direct map address `va`.  Fails with
+/// `OutOfRange` if it's not in the direct map, or `PhysNotMapped` if it's
+/// not RAM.
+#[allow(dead_code)]
+pub fn dmap_to_phys(va: VirtAddr) -> Result<PhysAddr, MemError> {
+    if !DIRECT_MAP.contains(va.addr()) {
+        let range = DIRECT_MAP.start as u64..DIRECT_MAP.end() as u64;
+        return Err(MemError::OutOfRange { addr: va.addr() as u64, range });
+    }
+    let pa = PhysAddr::new((va.addr() - DIRECT_MAP.start) as u64);
+    phys_to_dmap(pa).map(|_| pa)
+}
+
+/// Zero the physical memory in `range` through the direct map.  Ranges that
+/// aren't entirely within one range of RAM are refused, without writing
+/// anything.
+#[allow(dead_code)]
+pub fn zero_phys_range(range: &PhysRange) -> Result<(), MemError> {
+    let va = dmap_range(range)?;
+    // Safety: RAM is mapped read-write, as normal memory, in the direct map.
+    // The caller owns the memory.
+    unsafe { zero_range(&va) };
+    Ok(())
+}
+
+/// The kernel's mappings of physical memory: the direct map, for RAM, then
+/// the device mappings made with vmap.
+struct KernelMappings;
+
+impl PhysMappings for KernelMappings {
+    fn phys_to_virt(&self, range: &PhysRange) -> Option<VirtAddr> {
+        dmap_range(range).ok().or_else(|| phys_to_vmap(range)).map(|range| range.start())
+    }
+}
+
+/// Read the `T` at `pa`, which must be aligned, through the direct map, or
+/// a device mapping made with vmap.
+#[allow(dead_code)]
+pub fn read_phys<T: PhysWord>(pa: PhysAddr) -> Result<T, MemError> {
+    read_phys_with(&KernelMappings, pa)
+}
+
+/// Write `value` to the `T` at `pa`, which must be aligned, through the
+/// direct map, or a device mapping made with vmap.
+pub fn write_phys<T: PhysWord>(pa: PhysAddr, value: T) -> Result<(), MemError> {
+    write_phys_with(&KernelMappings, pa, value)
+}
+
+/// Print the physical memory in `range` to the console as a hex dump,
+/// labelled with physical addresses, reading it through the direct map.
+/// Ranges that aren't entirely within one range of RAM are refused, so
+/// device registers can't be read by mistake.
+#[allow(dead_code)]
+pub fn hexdump_phys(range: &PhysRange, skip_repeats: bool) {
+    let va = match dmap_range(range) {
+        Ok(va) => va,
+        Err(err) => {
+            println!("error:dmap:hexdump_phys:range not in RAM. range:{range} err:{err}");
+            return;
+        }
+    };
+    let start = va.start().addr() as *const u8;
+    // Safety: RAM is mapped readable in the direct map.
+    let bytes = unsafe { core::slice::from_raw_parts(start, va.size()) };
+    print!("{}", HexDump::new(bytes, range.start().addr() as usize, skip_repeats));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_only_ram() {
+        let mut ram = PhysRangeSet::new();
+        ram.add(&PhysRange::with_end(0, 0x3b40_0000)).unwrap();
+        ram.add(&PhysRange::with_end(0x4000_0000, 0x1_0000_0000)).unwrap();
+        let node = LockNode::new();
+        *DMAP_RAM.lock(&node) = ram;
+
+        assert_eq!(phys_to_dmap(PhysAddr::new(0)), Ok(VirtAddr::new(DIRECT_MAP.start)));
+        assert_eq!(
+            phys_to_dmap(PhysAddr::new(0x4000_1234)),
+            Ok(VirtAddr::new(DIRECT_MAP.start + 0x4000_1234))
+        );
+        assert_eq!(
+            dmap_range(&PhysRange::with_end(0x3b3f_f000, 0x3b40_0000)),
+            Ok(VirtRange::with_len(VirtAddr::new(DIRECT_MAP.start + 0x3b3f_f000), 0x1000))
+        );
+
+        // In the hole between the banks, spanning it, and beyond the end.
+        // Errors give the first address that isn't mapped.
+        let not_mapped = |pa| Err(MemError::PhysNotMapped { pa: PhysAddr::new(pa) });
+        assert_eq!(phys_to_dmap(PhysAddr::new(0x3b40_0000)), not_mapped(0x3b40_0000));
+        assert_eq!(
+            dmap_range(&PhysRange::with_end(0x3b3f_f000, 0x4000_1000)).map(|r| r.start()),
+            not_mapped(0x3b40_0000)
+        );
+        assert_eq!(phys_to_dmap(PhysAddr::new(0x1_0000_0000)), not_mapped(0x1_0000_0000));
+
+        // And back
+        assert_eq!(
+            dmap_to_phys(VirtAddr::new(DIRECT_MAP.start + 0x4000_1234)),
+            Ok(PhysAddr::new(0x4000_1234))
+        );
+        assert_eq!(
+            dmap_to_phys(VirtAddr::new(DIRECT_MAP.start + 0x3b40_0000)),
+            Err(MemError::PhysNotMapped { pa: PhysAddr::new(0x3b40_0000) })
+        );
+        assert!(matches!(
+            dmap_to_phys(VirtAddr::new(DIRECT_MAP.start - 0x1000)),
+            Err(MemError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn exclusions() {
+        let mut exclusions = Exclusions::new();
+        let range = PhysRange::with_len(0x3000_0010, 0x1000);
+        exclusions.add(&range, ExclusionReason::Board, true).unwrap();
+        let excluded: Vec<_> = exclusions.iter().collect();
+        let range = PhysRange::with_len(0x3000_0000, 0x2000);
+        assert_eq!(
+            excluded,
+            [&Exclusion { range: range.clone(), reason: ExclusionReason::Board, uncached: true }]
+        );
+        for _ in 1..MAX_DMAP_EXCLUSIONS {
+            exclusions.add(&range, ExclusionReason::BootArg, false).unwrap();
+        }
+        let full = exclusions.add(&range, ExclusionReason::NoMap, false);
+        assert_eq!(full, Err(MemError::TableFull));
+
+        // Only what init recorded as mapped uncached is translated
+        let mut uncached = PhysRangeSet::new();
+        uncached.add(&PhysRange::with_len(0x3000_1000, 0x1000)).unwrap();
+        let node = LockNode::new();
+        *DMAP_UNCACHED.lock(&node) = uncached;
+        assert_eq!(
+            dmap_uncached_range(&PhysRange::with_len(0x3000_1008, 8)),
+            Ok(VirtRange::with_len(VirtAddr::new(DIRECT_MAP.start + 0x3000_1008), 8))
+        );
+        assert_eq!(
+            dmap_uncached_range(&PhysRange::with_len(0x3000_0ff8, 16)),
+            Err(MemError::PhysNotMapped { pa: PhysAddr::new(0x3000_0ff8) })
+        );
+    }
+}
@@ -0,0 +1,44 @@
+/// Set up a framebuffer with the firmware, for early graphical output.
+use crate::mailbox::{self, MailboxError};
+use crate::vmap::vmap;
+use port::framebuffer::Framebuffer;
+use port::mem::{MapFlags, MemError};
+
+/// Bits per pixel, as the only depth port::framebuffer supports.
+const DEPTH: u32 = 32;
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum FramebufferError {
+    Mailbox(MailboxError),
+    Depth(u32), // The firmware allocated a framebuffer with a different depth
+    Vmap(MemError),
+}
+
+impl From<MailboxError> for FramebufferError {
+    fn from(err: MailboxError) -> FramebufferError {
+        FramebufferError::Mailbox(err)
+    }
+}
+
+impl From<MemError> for FramebufferError {
+    fn from(err: MemError) -> FramebufferError {
+        FramebufferError::Vmap(err)
+    }
+}
+
+/// Ask the firmware for a `width` by `height` framebuffer of 32 bit pixels,
+/// and map it write-combining with vmap.  The firmware may choose a different size.
+#[allow(dead_code)]
+pub fn init(width: u32, height: u32) -> Result<Framebuffer, FramebufferError> {
+    let info = mailbox::allocate_framebuffer(width, height, DEPTH)?;
+    if info.depth != DEPTH {
+        return Err(FramebufferError::Depth(info.depth));
+    }
+    let virt = vmap(&info.range, MapFlags::RW | MapFlags::WRITE_COMBINING)?;
+    // Safety: the range stays mapped, and nothing else in the kernel refers
+    // to it.
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(virt.start().addr() as *mut u8, virt.size()) };
+    Ok(Framebuffer::new(buf, info.width as usize, info.height as usize, info.pitch as usize))
+}
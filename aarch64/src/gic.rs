@@ -0,0 +1,528 @@
+/// gic drives the Arm Generic Interrupt Controller, version 2 (e.g. the
+/// GIC-400 on the Raspberry Pi 4) or 3, as found in the device tree.  Both
+/// have a distributor, which routes shared peripheral interrupts (SPIs,
+/// INTIDs 32 and up) to cores.  Software generated interrupts (SGIs, 0-15)
+/// and private peripheral interrupts (PPIs, 16-31), such as the timers, are
+/// banked per core: in the distributor on v2, and in each core's
+/// redistributor on v3, with the same register layout.  A core acknowledges
+/// and ends interrupts through its CPU interface, which is memory mapped on
+/// v2, and system registers on v3.
+///
+/// Handlers are registered for INTIDs, and called by `handle_irq` from the
+/// IRQ exception vector, with IRQs masked.
+///
+/// See the GIC architecture specification, Arm IHI 0069, and the GIC-400
+/// TRM, Arm DDI 0471.
+use crate::io::{read_reg, write_reg};
+use crate::param::MAX_CORES;
+use crate::registers;
+use crate::vmap::vmap_region;
+use alloc::vec::Vec;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::time::Duration;
+use port::fdt::{DeviceTree, Node};
+use port::mem::{MemError, VirtAddr, VirtRange};
+use port::oncelock::OnceLock;
+use port::percpu::PerCpu;
+use port::time::Deadline;
+use port::{info, println};
+
+// Distributor registers, and on v3, the same registers for SGIs and PPIs in
+// the SGI frame of each core's redistributor.  Registers with a bit, or a
+// byte, per INTID continue at the next word for the next INTIDs.
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800; // v2 only
+const GICD_ICFGR: usize = 0xc00;
+const GICD_SGIR: usize = 0xf00; // v2 only
+const GICD_IROUTER: usize = 0x6000; // v3 only, 64 bits per SPI
+
+const GICD_CTLR_ENABLE: u32 = 0b11; // Both groups, or group 1 and 1A on v3
+const GICD_CTLR_ARE: u32 = 1 << 4; // v3 affinity routing
+const GICD_CTLR_RWP: u32 = 1 << 31; // v3 register write pending
+
+// v2 CPU interface registers
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_BPR: usize = 0x08;
+const GICC_IAR: usize = 0x0c;
+const GICC_EOIR: usize = 0x10;
+
+// v3 redistributor registers, in a 64KiB frame for the core, followed by the
+// 64KiB SGI frame
+const GICR_STRIDE: usize = 0x20000;
+const GICR_SGI_FRAME: usize = 0x10000;
+const GICR_TYPER: usize = 0x008;
+const GICR_WAKER: usize = 0x014;
+const GICR_TYPER_LAST: u32 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Number of INTIDs that can be SGIs, PPIs and SPIs.  IDs from here to 1023
+/// are special, e.g. 1023 is returned on acknowledging if nothing's pending.
+pub const NUM_INTIDS: usize = 1020;
+const FIRST_PPI: u32 = 16;
+const FIRST_SPI: u32 = 32;
+const NUM_SGIS: u32 = 16;
+
+/// Priority given to every interrupt, and the mask that lets them all
+/// through.  Lower values are higher priority.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+const PRIORITY_MASK: u32 = 0xff;
+
+/// How long to wait for a v3 redistributor to wake, or a write to complete.
+const WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+static GIC: OnceLock<Gic> = OnceLock::new();
+
+/// Handler for each INTID, or null if there isn't one.
+static HANDLERS: [AtomicPtr<()>; NUM_INTIDS] = [const { AtomicPtr::new(null_mut()) }; NUM_INTIDS];
+
+/// Offset of each core's redistributor, on v3.
+static REDIST_OFFSETS: PerCpu<AtomicUsize, MAX_CORES> =
+    PerCpu::new([const { AtomicUsize::new(usize::MAX) }; MAX_CORES]);
+
+/// How an interrupt is signalled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+#[derive(Debug)]
+enum Version {
+    V2 { cpu: VirtRange },     // CPU interface registers
+    V3 { redists: VirtRange }, // Redistributors of all the cores
+}
+
+struct Gic {
+    dist: VirtRange,
+    version: Version,
+    num_intids: u32,
+}
+
+impl Gic {
+    /// Return the registers for `intid`: for SGIs and PPIs on v3, the
+    /// current core's SGI frame, and otherwise the distributor.
+    fn regs_for(&self, intid: u32) -> VirtRange {
+        match &self.version {
+            Version::V3 { redists } if intid < FIRST_SPI => {
+                let offset = REDIST_OFFSETS.get().load(Ordering::Relaxed);
+                let start = VirtAddr::new(redists.start().addr() + offset + GICR_SGI_FRAME);
+                VirtRange::with_len(start, GICR_STRIDE - GICR_SGI_FRAME)
+            }
+            _ => self.dist.clone(),
+        }
+    }
+
+    /// Set the bit for `intid` in the registers with a bit per INTID at
+    /// `base`, which are write-1-to-set or -clear.
+    fn set_bit(&self, base: usize, intid: u32) {
+        let (offset, bit) = bit_reg(base, intid);
+        write_reg(&self.regs_for(intid), offset, 1 << bit);
+    }
+
+    /// Set `bits` of `intid` in the registers with `width` bits per INTID at
+    /// `base`, leaving the others as they are.
+    fn update_field(&self, base: usize, intid: u32, width: u32, bits: u32) {
+        let regs = self.regs_for(intid);
+        let per_reg = 32 / width;
+        let offset = base + (intid / per_reg) as usize * 4;
+        let shift = (intid % per_reg) * width;
+        let mask = ((1 << width) - 1) << shift;
+        let value = read_reg(&regs, offset) & !mask;
+        write_reg(&regs, offset, value | (bits << shift));
+    }
+
+    /// Wait for a write to the v3 distributor to take effect.
+    fn wait_for_dist_write(&self) {
+        if !matches!(self.version, Version::V3 { .. }) {
+            return;
+        }
+        let deadline = Deadline::after(WAIT_TIMEOUT);
+        while read_reg(&self.dist, GICD_CTLR) & GICD_CTLR_RWP != 0 {
+            if deadline.expired() {
+                println!("error:gic:wait_for_dist_write:timed out");
+                return;
+            }
+        }
+    }
+
+    /// Set up the distributor, with every SPI disabled, in group 1, level
+    /// triggered, and routed to the current core.
+    fn init_distributor(&self) {
+        write_reg(&self.dist, GICD_CTLR, 0);
+        self.wait_for_dist_write();
+
+        for intid in (FIRST_SPI..self.num_intids).step_by(32) {
+            let (offset, _) = bit_reg(0, intid);
+            write_reg(&self.dist, GICD_ICENABLER + offset, u32::MAX);
+            write_reg(&self.dist, GICD_ICPENDR + offset, u32::MAX);
+            write_reg(&self.dist, GICD_IGROUPR + offset, u32::MAX);
+        }
+        let core = registers::core_id();
+        for intid in FIRST_SPI..self.num_intids {
+            self.update_field(GICD_IPRIORITYR, intid, 8, DEFAULT_PRIORITY as u32);
+            self.update_field(GICD_ICFGR, intid, 2, 0);
+            match self.version {
+                Version::V2 { .. } => self.update_field(GICD_ITARGETSR, intid, 8, 1 << core),
+                Version::V3 { .. } => {
+                    // Aff0 of the core, in the low word
+                    let offset = GICD_IROUTER + intid as usize * 8;
+                    write_reg(&self.dist, offset, core as u32);
+                    write_reg(&self.dist, offset + 4, 0);
+                }
+            }
+        }
+
+        let ctlr = match self.version {
+            Version::V2 { .. } => GICD_CTLR_ENABLE,
+            Version::V3 { .. } => GICD_CTLR_ENABLE | GICD_CTLR_ARE,
+        };
+        write_reg(&self.dist, GICD_CTLR, ctlr);
+        self.wait_for_dist_write();
+    }
+
+    /// Set up the current core's SGIs and PPIs, disabled, in group 1, and
+    /// enable its CPU interface.
+    fn init_core(&self) {
+        if let Version::V3 { redists } = &self.version {
+            let Some(offset) = find_redistributor(redists, registers::core_id()) else {
+                println!("error:gic:init_core:no redistributor for core {}", registers::core_id());
+                return;
+            };
+            REDIST_OFFSETS.get().store(offset, Ordering::Relaxed);
+            wake_redistributor(redists, offset);
+        }
+
+        let regs = self.regs_for(0);
+        write_reg(&regs, GICD_ICENABLER, u32::MAX);
+        write_reg(&regs, GICD_ICPENDR, u32::MAX);
+        write_reg(&regs, GICD_IGROUPR, u32::MAX);
+        for intid in 0..FIRST_SPI {
+            self.update_field(GICD_IPRIORITYR, intid, 8, DEFAULT_PRIORITY as u32);
+        }
+
+        match &self.version {
+            Version::V2 { cpu } => {
+                write_reg(cpu, GICC_PMR, PRIORITY_MASK);
+                write_reg(cpu, GICC_BPR, 0);
+                write_reg(cpu, GICC_CTLR, 0b11);
+            }
+            Version::V3 { .. } => icc::init_core(),
+        }
+    }
+
+    /// Acknowledge the highest priority pending interrupt, returning the
+    /// value to pass to `end_of_interrupt`, which includes its INTID.
+    fn acknowledge(&self) -> u32 {
+        match &self.version {
+            Version::V2 { cpu } => read_reg(cpu, GICC_IAR),
+            Version::V3 { .. } => icc::acknowledge(),
+        }
+    }
+
+    fn end_of_interrupt(&self, iar: u32) {
+        match &self.version {
+            Version::V2 { cpu } => write_reg(cpu, GICC_EOIR, iar),
+            Version::V3 { .. } => icc::end_of_interrupt(iar),
+        }
+    }
+}
+
+/// Return the offset of the register with the bit for `intid`, in the
+/// registers with a bit per INTID at `base`, and the bit.
+fn bit_reg(base: usize, intid: u32) -> (usize, u32) {
+    (base + (intid / 32) as usize * 4, intid % 32)
+}
+
+/// Return the offset of the redistributor for `core` in `redists`, by its
+/// Aff0 in GICR_TYPER.
+fn find_redistributor(redists: &VirtRange, core: usize) -> Option<usize> {
+    (0..redists.size()).step_by(GICR_STRIDE).find_map(|offset| {
+        let rd = VirtRange::with_len(VirtAddr::new(redists.start().addr() + offset), GICR_STRIDE);
+        let typer_lo = read_reg(&rd, GICR_TYPER);
+        let aff0 = read_reg(&rd, GICR_TYPER + 4) & 0xff;
+        if aff0 as usize == core {
+            Some(Some(offset))
+        } else if typer_lo & GICR_TYPER_LAST != 0 {
+            Some(None)
+        } else {
+            None
+        }
+    })?
+}
+
+/// Mark the redistributor at `offset` as in use, and wait for it to wake.
+fn wake_redistributor(redists: &VirtRange, offset: usize) {
+    let rd = VirtRange::with_len(VirtAddr::new(redists.start().addr() + offset), GICR_STRIDE);
+    let waker = read_reg(&rd, GICR_WAKER);
+    write_reg(&rd, GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+    let deadline = Deadline::after(WAIT_TIMEOUT);
+    while read_reg(&rd, GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+        if deadline.expired() {
+            println!("error:gic:wake_redistributor:timed out");
+            return;
+        }
+    }
+}
+
+/// The v3 CPU interface system registers, by encoding, so no assembler
+/// support for GICv3 is needed.
+mod icc {
+    /// Enable the system register interface, let every priority through,
+    /// and enable group 1 interrupts.
+    pub fn init_core() {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!(
+                "mrs {tmp}, S3_0_C12_C12_5", // ICC_SRE_EL1
+                "orr {tmp}, {tmp}, #1",
+                "msr S3_0_C12_C12_5, {tmp}",
+                "isb",
+                "msr S3_0_C4_C6_0, {pmr}",   // ICC_PMR_EL1
+                "msr S3_0_C12_C12_3, xzr",   // ICC_BPR1_EL1
+                "msr S3_0_C12_C12_7, {one}", // ICC_IGRPEN1_EL1
+                "isb",
+                tmp = out(reg) _,
+                pmr = in(reg) super::PRIORITY_MASK as u64,
+                one = in(reg) 1u64,
+            );
+        }
+    }
+
+    pub fn acknowledge() -> u32 {
+        #[cfg(not(test))]
+        unsafe {
+            let iar: u64;
+            // ICC_IAR1_EL1
+            core::arch::asm!("mrs {iar}, S3_0_C12_C12_0", iar = out(reg) iar);
+            iar as u32
+        }
+        #[cfg(test)]
+        1023
+    }
+
+    #[allow(unused_variables)]
+    pub fn end_of_interrupt(iar: u32) {
+        #[cfg(not(test))]
+        unsafe {
+            // ICC_EOIR1_EL1
+            core::arch::asm!("msr S3_0_C12_C12_1, {iar}", iar = in(reg) iar as u64);
+        }
+    }
+
+    /// Send SGI `sgi` to the core with Aff0 `core`, in the same cluster.
+    #[allow(unused_variables)]
+    pub fn send_sgi(sgi: u32, core: usize) {
+        #[cfg(not(test))]
+        unsafe {
+            let sgi1r = ((sgi as u64) << 24) | (1 << (core % 16)) | (((core / 16) as u64) << 44);
+            // ICC_SGI1R_EL1, with the core in the target list of range core / 16
+            core::arch::asm!("msr S3_0_C12_C11_5, {sgi1r}", "isb", sgi1r = in(reg) sgi1r);
+        }
+    }
+}
+
+/// Return the INTID and trigger of each interrupt in the interrupts property
+/// of `node`, which has 3 cells each: the type (0 for an SPI, 1 for a PPI),
+/// the number within the type, and flags, where the low 4 bits are the
+/// trigger.
+pub fn interrupts(dt: &DeviceTree, node: &Node) -> Vec<(u32, Trigger)> {
+    let Some(prop) = dt.property(node, "interrupts") else {
+        return Vec::new();
+    };
+    let cells: Vec<u32> = dt.property_value_as_u32_iter(&prop).collect();
+    cells
+        .chunks_exact(3)
+        .filter_map(|cells| decode_interrupt(cells[0], cells[1], cells[2]))
+        .collect()
+}
+
+fn decode_interrupt(kind: u32, num: u32, flags: u32) -> Option<(u32, Trigger)> {
+    let intid = match kind {
+        0 => FIRST_SPI + num,
+        1 => FIRST_PPI + num,
+        _ => return None,
+    };
+    let trigger = if flags & 0b11 != 0 { Trigger::Edge } else { Trigger::Level };
+    (intid < NUM_INTIDS as u32).then_some((intid, trigger))
+}
+
+/// Find the GIC in the device tree, map its registers with vmap, and set up
+/// the distributor and this core's interface.  Interrupts stay polled if
+/// there's no GIC, e.g. on the Raspberry Pi 3, or on the Raspberry Pi 4
+/// without enable_gic in config.txt.
+pub fn init(dt: &DeviceTree) -> Result<(), MemError> {
+    let v2 = ["arm,gic-400", "arm,cortex-a15-gic"]
+        .into_iter()
+        .find_map(|compatible| dt.find_compatible(compatible).next());
+    let (node, is_v3) = match v2 {
+        Some(node) => (node, false),
+        None => match dt.find_compatible("arm,gic-v3").next() {
+            Some(node) => (node, true),
+            None => {
+                info!("gic: none found, interrupts stay polled");
+                return Ok(());
+            }
+        },
+    };
+    let mut regs = dt.translated_regions(node);
+    let (Some(dist_reg), Some(cpu_reg)) = (regs.next(), regs.next()) else {
+        println!("error:gic:init:expected distributor and cpu interface registers");
+        return Ok(());
+    };
+    let (dist_reg, cpu_reg) = match (dist_reg, cpu_reg) {
+        (Ok(dist_reg), Ok(cpu_reg)) => (dist_reg, cpu_reg),
+        (Err(reg), _) | (_, Err(reg)) => {
+            println!("error:gic:init:can't translate {reg}");
+            return Ok(());
+        }
+    };
+    let dist = vmap_region(&dist_reg)?;
+    let cpu = vmap_region(&cpu_reg)?;
+    let version = if is_v3 { Version::V3 { redists: cpu } } else { Version::V2 { cpu } };
+    let lines = read_reg(&dist, GICD_TYPER) & 0x1f;
+    let num_intids = (32 * (lines + 1)).min(NUM_INTIDS as u32);
+    let gic = Gic { dist, version, num_intids };
+
+    gic.init_distributor();
+    gic.init_core();
+    info!(
+        "gic: {} at {:#x} with {} interrupts",
+        if is_v3 { "GICv3" } else { "GICv2" },
+        dist_reg.reg().addr,
+        num_intids
+    );
+    if GIC.set(gic).is_err() {
+        panic!("error:gic:init:already initialised");
+    }
+    Ok(())
+}
+
+/// Set up the current core's SGIs, PPIs and CPU interface, on a secondary
+/// core, if there's a GIC.
+pub fn init_core() {
+    if let Some(gic) = GIC.get() {
+        gic.init_core();
+    }
+}
+
+/// Return true if there's a GIC.
+pub fn available() -> bool {
+    GIC.get().is_some()
+}
+
+/// Call `handler` with the INTID for interrupts with `intid`, replacing any
+/// handler already registered.
+pub fn register_handler(intid: u32, handler: fn(u32)) {
+    match HANDLERS.get(intid as usize) {
+        Some(slot) => slot.store(handler as *mut (), Ordering::Release),
+        None => println!("error:gic:register_handler:invalid intid:{intid}"),
+    }
+}
+
+fn handler(intid: u32) -> Option<fn(u32)> {
+    let handler = HANDLERS.get(intid as usize)?.load(Ordering::Acquire);
+    // Safety: only register_handler stores to HANDLERS, and it stores a
+    // fn(u32)
+    (!handler.is_null()).then(|| unsafe { core::mem::transmute::<*mut (), fn(u32)>(handler) })
+}
+
+/// Enable `intid`.  SGIs and PPIs are only enabled on the current core.
+pub fn enable_irq(intid: u32) {
+    if let Some(gic) = GIC.get() {
+        gic.set_bit(GICD_ISENABLER, intid);
+    }
+}
+
+/// Disable `intid`.  SGIs and PPIs are only disabled on the current core.
+pub fn disable_irq(intid: u32) {
+    if let Some(gic) = GIC.get() {
+        gic.set_bit(GICD_ICENABLER, intid);
+        gic.wait_for_dist_write();
+    }
+}
+
+/// Set how `intid` is signalled.  Should be done while it's disabled.  SGIs
+/// are always edge triggered, and some PPIs' triggers are fixed.
+pub fn set_trigger(intid: u32, trigger: Trigger) {
+    if let Some(gic) = GIC.get() {
+        if intid >= NUM_SGIS {
+            let edge = if trigger == Trigger::Edge { 0b10 } else { 0 };
+            gic.update_field(GICD_ICFGR, intid, 2, edge);
+        }
+    }
+}
+
+/// Send SGI `sgi` to `core`.  Nothing handles SGIs yet, but they're for
+/// signalling other cores, e.g. to run the scheduler.
+#[allow(dead_code)]
+pub fn send_sgi(sgi: u32, core: usize) {
+    let Some(gic) = GIC.get() else {
+        return;
+    };
+    match gic.version {
+        Version::V2 { .. } => write_reg(&gic.dist, GICD_SGIR, (1 << (16 + core)) | sgi),
+        Version::V3 { .. } => icc::send_sgi(sgi, core),
+    }
+}
+
+/// Acknowledge and handle each pending interrupt, until there are none.
+/// Called from the IRQ exception vector, with IRQs masked.  An interrupt
+/// without a handler is disabled, so it doesn't fire forever.
+pub fn handle_irq() {
+    let Some(gic) = GIC.get() else {
+        return;
+    };
+    loop {
+        let iar = gic.acknowledge();
+        let intid = iar & 0x3ff;
+        if intid as usize >= NUM_INTIDS {
+            break;
+        }
+        match handler(intid) {
+            Some(handler) => handler(intid),
+            None => {
+                println!("error:gic:handle_irq:no handler for intid {intid}, disabling it");
+                disable_irq(intid);
+            }
+        }
+        gic.end_of_interrupt(iar);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_interrupts() {
+        // The rpi4 timer: PPIs 13, 14, 11 and 10, level low
+        assert_eq!(decode_interrupt(1, 11, 0xf08), Some((27, Trigger::Level)));
+        assert_eq!(decode_interrupt(0, 93, 4), Some((125, Trigger::Level)));
+        assert_eq!(decode_interrupt(0, 0, 1), Some((32, Trigger::Edge)));
+        assert_eq!(decode_interrupt(2, 0, 4), None);
+        assert_eq!(decode_interrupt(0, 1000, 4), None);
+    }
+
+    #[test]
+    fn rpi4_timer_interrupts() {
+        let dt = DeviceTree::new(include_bytes!("../lib/bcm2711-rpi-4-b.dtb")).unwrap();
+        let timer = dt.find_compatible("arm,armv8-timer").next().unwrap();
+        let intids: Vec<u32> = interrupts(&dt, &timer).iter().map(|(intid, _)| *intid).collect();
+        assert_eq!(intids, [29, 30, 27, 26]);
+    }
+
+    #[test]
+    fn bit_regs() {
+        assert_eq!(bit_reg(GICD_ISENABLER, 27), (0x100, 27));
+        assert_eq!(bit_reg(GICD_ISENABLER, 125), (0x10c, 29));
+    }
+}
@@ -1,8 +1,26 @@
-use crate::param::KZERO;
-use port::mem::{PhysAddr, PhysRange, VirtAddr};
+use crate::param::{
+    KERNEL_IMAGE, KSTACK_GUARD_SIZE, KSTACK_SIZE, KZERO, LAYOUT, MAX_CORES, STACKS,
+};
+use core::convert::Infallible;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use port::addrmap::{AddrKind, AddrMap, AddrRange};
+use port::layout::LayoutError;
+use port::mcslock::{Lock, LockNode};
+use port::mem::{
+    MapFlags, PAGE_SIZE_2M, PAGE_SIZE_4K, PhysAddr, PhysRange, PhysRangeSet, VirtAddr, VirtRange,
+};
+use port::memaccount::MemCategory;
+use port::physmap::{PhysMap, PhysRegion, Size};
+
+#[cfg(not(test))]
+use port::{print, println};
 
 // These map to definitions in kernel.ld
 unsafe extern "C" {
+    static boottext: [u64; 0];
     static eboottext: [u64; 0];
     static text: [u64; 0];
     static etext: [u64; 0];
@@ -12,15 +30,108 @@ unsafe extern "C" {
     static edata: [u64; 0];
     static bss: [u64; 0];
     static ebss: [u64; 0];
+    static stack: [u64; 0];
     static end: [u64; 0];
     static early_pagetables: [u64; 0];
     static eearly_pagetables: [u64; 0];
+    static secondary_start: [u64; 0];
+    static physicalpt3: [u64; 0];
+}
+
+/// Offset from the physical address the kernel image was linked for, its
+/// virtual address less KZERO, to where it was actually loaded, as computed
+/// by l.S.
+static LOAD_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Physical memory covered by the KZERO mapping, which translations to and
+/// from it are checked against in debug builds.  Empty, so unchecked, until
+/// `set_load_offset`.
+static KZERO_MAPPED: Lock<PhysRangeSet<4>> = Lock::new("kzero_mapped", PhysRangeSet::new());
+
+// Checks against KZERO_MAPPED skipped because it was being updated
+port::counter!(static KZERO_CHECKS_SKIPPED = "kmem.kzero_checks_skipped");
+
+/// Set the load offset passed to main9 by l.S.  Must be called before any
+/// addresses in the KZERO mapping are translated.
+pub fn set_load_offset(offset: usize) {
+    LOAD_OFFSET.store(offset, Ordering::Relaxed);
+    set_kzero_mapped([PhysRange::with_len(offset as u64, KERNEL_IMAGE.size)]);
+}
+
+/// Record that the KZERO mapping now covers `ranges`, e.g. once the kernel
+/// page tables replace the early ones.
+pub fn set_kzero_mapped(ranges: impl IntoIterator<Item = PhysRange>) {
+    let mut mapped = PhysRangeSet::new();
+    for range in ranges {
+        if mapped.add(&range).is_err() {
+            println!("error:kmem:set_kzero_mapped:too many ranges, ignoring {range}");
+        }
+    }
+    let node = LockNode::new();
+    *KZERO_MAPPED.lock(&node) = mapped;
+}
+
+/// Record that `range` has been unmapped from the KZERO mapping.
+pub fn set_kzero_unmapped(range: &PhysRange) {
+    let node = LockNode::new();
+    if KZERO_MAPPED.lock(&node).remove(range).is_err() {
+        println!("error:kmem:set_kzero_unmapped:too many ranges, can't remove {range}");
+    }
+}
+
+/// In debug builds, panic unless `range` is within the KZERO mapping, as
+/// translating it would give an address that isn't mapped, or is mapped to
+/// something else.  An empty range may be at the end of a mapped range, as
+/// the ends of ranges are translated too.
+///
+/// Translations are made by trap handlers too, which may have interrupted
+/// an update on this core, so the check is skipped rather than waiting for
+/// KZERO_MAPPED, and counted in KZERO_CHECKS_SKIPPED.
+#[track_caller]
+fn check_kzero_mapped(range: &PhysRange) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mapped = {
+        let node = LockNode::new();
+        let Some(mapped) = KZERO_MAPPED.try_lock(&node) else {
+            KZERO_CHECKS_SKIPPED.inc();
+            return;
+        };
+        mapped.clone()
+    };
+    let covered = |r: &PhysRange| r.start() <= range.start() && range.end() <= r.end();
+    if !mapped.is_empty() && !mapped.iter().any(covered) {
+        panic!("{range} isn't in the KZERO mapping, which covers {mapped}");
+    }
+}
+
+/// Return the offset from physical to virtual addresses in the KZERO mapping,
+/// which holds the kernel image, and the DTB until it's unmapped.  This is
+/// KZERO, less the load offset, so the image is at its linked addresses
+/// wherever it was loaded.
+pub fn kzero_offset() -> usize {
+    KZERO.wrapping_sub(LOAD_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Return the physical address the kernel image was linked to be loaded at.
+pub fn linked_phys_base() -> PhysAddr {
+    PhysAddr::new((boottext_addr() - KZERO) as u64)
+}
+
+/// Return the physical address the kernel image was loaded at.
+pub fn kernel_phys_base() -> PhysAddr {
+    from_virt_to_physaddr(VirtAddr::new(boottext_addr()))
 }
 
 fn base_addr() -> usize {
     KZERO
 }
 
+fn boottext_addr() -> usize {
+    unsafe { boottext.as_ptr().addr() }
+}
+
 fn eboottext_addr() -> usize {
     unsafe { eboottext.as_ptr().addr() }
 }
@@ -57,6 +168,10 @@ fn ebss_addr() -> usize {
     unsafe { ebss.as_ptr().addr() }
 }
 
+fn stack_addr() -> usize {
+    unsafe { stack.as_ptr().addr() }
+}
+
 fn end_addr() -> usize {
     unsafe { end.as_ptr().addr() }
 }
@@ -69,6 +184,70 @@ fn eearly_pagetables_addr() -> usize {
     unsafe { eearly_pagetables.as_ptr().addr() }
 }
 
+/// Return the physical address secondary cores start at, in l.S.
+pub fn secondary_start_physaddr() -> PhysAddr {
+    from_virt_to_physaddr(VirtAddr::new(unsafe { secondary_start.as_ptr().addr() }))
+}
+
+/// Size of the block l.S identity maps for the boot core.
+const BOOT_IDENTITY_SIZE: usize = 1 << 30;
+
+/// Return the physical memory l.S identity maps for the boot core, so it can
+/// keep running at its physical address as it turns on the MMU: the GiB the
+/// kernel was loaded in.
+pub fn boot_identity_window() -> PhysRange {
+    let start = kernel_phys_base().round_down(BOOT_IDENTITY_SIZE as u64);
+    PhysRange::with_pa_len(start, BOOT_IDENTITY_SIZE)
+}
+
+/// Return the physical address of the entry in l.S's physicalpt3 that maps
+/// `boot_identity_window`.
+pub fn boot_identity_entry_physaddr() -> PhysAddr {
+    let table = from_virt_to_physaddr(VirtAddr::new(unsafe { physicalpt3.as_ptr().addr() }));
+    let index = boot_identity_window().start().addr() / BOOT_IDENTITY_SIZE as u64;
+    table + index * size_of::<u64>() as u64
+}
+
+/// A range of physical memory identity mapped in the user half, for code
+/// that runs at its physical address as the MMU is turned on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityWindow {
+    pub name: &'static str,
+    pub range: PhysRange,
+}
+
+/// The identity windows still mapped: the boot core's, until it's torn down,
+/// and the one secondary cores start through.
+static IDENTITY_WINDOWS: Lock<[Option<IdentityWindow>; 2]> =
+    Lock::new("identity_windows", [const { None }; 2]);
+
+/// Record that `range` is identity mapped.
+pub fn record_identity_window(name: &'static str, range: PhysRange) {
+    let node = LockNode::new();
+    let mut windows = IDENTITY_WINDOWS.lock(&node);
+    match windows.iter_mut().find(|window| window.is_none()) {
+        Some(slot) => *slot = Some(IdentityWindow { name, range }),
+        None => println!("error:kmem:record_identity_window:too many windows, ignoring {name}"),
+    }
+}
+
+/// Record that the identity window `name` has been unmapped.
+pub fn remove_identity_window(name: &str) {
+    let node = LockNode::new();
+    let mut windows = IDENTITY_WINDOWS.lock(&node);
+    for slot in windows.iter_mut() {
+        if slot.as_ref().is_some_and(|window| window.name == name) {
+            *slot = None;
+        }
+    }
+}
+
+/// Return the identity windows still mapped.
+pub fn identity_windows() -> [Option<IdentityWindow>; 2] {
+    let node = LockNode::new();
+    IDENTITY_WINDOWS.lock(&node).clone()
+}
+
 pub fn boottext_range() -> PhysRange {
     PhysRange(from_virt_to_physaddr(VirtAddr::new(base_addr()))..from_virt_to_physaddr(VirtAddr::new(eboottext_addr())))
 }
@@ -89,25 +268,421 @@ pub fn bss_range() -> PhysRange {
     PhysRange(from_virt_to_physaddr(VirtAddr::new(bss_addr()))..from_virt_to_physaddr(VirtAddr::new(ebss_addr())))
 }
 
+/// A section of the kernel image, and the flags it's intended to be mapped
+/// with once the kernel is up.  Empty flags mean it's not mapped at all.
+pub struct KernelSection {
+    pub name: &'static str,
+    pub range: PhysRange,
+    pub flags: MapFlags,
+}
+
+/// Return the sections of the kernel image, in address order.
+pub fn kernel_sections() -> [KernelSection; 5] {
+    [
+        // Only needed until main9 is called, so it's mapped with text until
+        // then and dropped after.  Secondary cores run it at its physical
+        // address, before their MMU is on, so it's kept in memory.
+        KernelSection { name: "boottext", range: boottext_range(), flags: MapFlags::empty() },
+        KernelSection { name: "text", range: text_range(), flags: MapFlags::RX },
+        KernelSection { name: "rodata", range: rodata_range(), flags: MapFlags::READ },
+        KernelSection { name: "data", range: data_range(), flags: MapFlags::RW },
+        KernelSection { name: "bss", range: bss_range(), flags: MapFlags::RW },
+    ]
+}
+
+/// A kernel stack, and the core it belongs to.  The lowest page of the stack
+/// is a guard page, which is left unmapped so that overflowing the stack
+/// faults immediately.  The stack is in the bss, and aliased in the STACKS
+/// region, which is where the cores run on it once the kernel page tables
+/// are set up.
+pub struct KernelStack {
+    pub core: usize,
+    pub range: PhysRange,
+}
+
+impl KernelStack {
+    pub fn guard_range(&self) -> PhysRange {
+        PhysRange::with_pa_len(self.range.start(), KSTACK_GUARD_SIZE)
+    }
+
+    /// Return the stack's alias in the STACKS region, including its guard
+    /// page.
+    pub fn virt_range(&self) -> VirtRange {
+        VirtRange::with_len(VirtAddr::new(STACKS.start + self.core * KSTACK_SIZE), KSTACK_SIZE)
+    }
+
+    /// Return the guard page of the stack's alias, which is never mapped.
+    pub fn guard_virt_range(&self) -> VirtRange {
+        VirtRange::with_len(self.virt_range().start(), KSTACK_GUARD_SIZE)
+    }
+}
+
+/// Return the kernel stacks, one per core in order of core id, starting with
+/// the boot stack used by core 0.  l.S sets up the same layout.
+pub fn kernel_stacks() -> [KernelStack; MAX_CORES] {
+    let stacks = from_virt_to_physaddr(VirtAddr::new(stack_addr()));
+    core::array::from_fn(|core| {
+        let start = PhysAddr::new(stacks.addr() + (core * KSTACK_SIZE) as u64);
+        KernelStack { core, range: PhysRange::with_pa_len(start, KSTACK_SIZE) }
+    })
+}
+
+/// Move the boot core from its stack in the bss to the stack's alias in the
+/// STACKS region, where the secondary cores start, and call `f` there.  The
+/// frames already on the stack stay where they are in memory, so anything
+/// they point to is still valid through the KZERO mapping.  The kernel page
+/// tables, with the alias mapped, must be active.  `f` must not return.
+pub fn continue_on_kernel_stack<F: FnOnce()>(f: F) -> ! {
+    extern "C" fn call<F: FnOnce()>(f: *mut F) -> ! {
+        // Safety: f is moved out of exactly once, and never dropped in place
+        unsafe { f.read()() };
+        panic!("error:kmem:continue_on_kernel_stack:returned");
+    }
+
+    let mut f = ManuallyDrop::new(f);
+    let f: *mut F = &mut *f;
+    let boot_stack = &kernel_stacks()[0];
+    let bss_stack = physrange_as_virtrange_offset_from_kzero(&boot_stack.range);
+    let offset = boot_stack.virt_range().start().addr().wrapping_sub(bss_stack.start().addr());
+
+    #[cfg(not(test))]
+    // Safety: the alias maps the same memory as the stack in the bss, so
+    // moving sp by the offset between them keeps the frames above it intact.
+    // A zero frame pointer ends the chain of frame records for backtraces, as
+    // they can't follow it back across the stacks.
+    unsafe {
+        core::arch::asm!(
+            "add sp, sp, {offset}",
+            "mov x29, xzr",
+            "br {call}",
+            offset = in(reg) offset,
+            call = in(reg) call::<F> as usize,
+            in("x0") f,
+            options(noreturn)
+        );
+    }
+    #[cfg(test)]
+    {
+        let _ = offset;
+        call(f)
+    }
+}
+
+/// Alignment l.S needs the kernel's load offset to have, as it maps the
+/// image with 2MiB blocks.
+pub const LOAD_ALIGN: usize = PAGE_SIZE_2M;
+
+/// The parts of the physical memory the kernel claims when it's loaded: the
+/// image sections, the bss, which isn't in the file, the boot stacks within
+/// the bss, and the early page tables after it.  Each is a range of linked
+/// virtual addresses, from the linker symbols, so the footprint can be
+/// worked out for any load address.
+#[derive(Clone, Debug)]
+pub struct ImageLayout {
+    pub start: usize,
+    pub parts: [(&'static str, Range<usize>); 7],
+}
+
+/// Why the kernel can't be loaded at an address.
+#[derive(Debug, PartialEq)]
+pub enum PlacementError {
+    /// The offset from the linked address isn't a multiple of LOAD_ALIGN.
+    Misaligned,
+    /// The address is below the linked address.
+    BelowLinked,
+}
+
+impl ImageLayout {
+    /// Return the layout of the running kernel.
+    pub fn linked() -> ImageLayout {
+        let stacks = stack_addr()..stack_addr() + MAX_CORES * KSTACK_SIZE;
+        ImageLayout {
+            start: boottext_addr(),
+            parts: [
+                ("boottext", boottext_addr()..eboottext_addr()),
+                ("text", text_addr()..etext_addr()),
+                ("rodata", rodata_addr()..erodata_addr()),
+                ("data", data_addr()..edata_addr()),
+                ("bss", bss_addr()..ebss_addr()),
+                ("stacks", stacks),
+                ("early_pagetables", early_pagetables_addr()..eearly_pagetables_addr()),
+            ],
+        }
+    }
+
+    /// Return the physical address the image was linked to be loaded at.
+    pub fn linked_phys_base(&self) -> PhysAddr {
+        PhysAddr::new((self.start - KZERO) as u64)
+    }
+
+    /// Return the number of contiguous bytes from the load address the
+    /// kernel claims.
+    pub fn size(&self) -> usize {
+        self.parts.iter().map(|(_, range)| range.end).max().unwrap_or(self.start) - self.start
+    }
+
+    /// Return the name and physical range of each part, were the image
+    /// loaded at `load_base`.  The boot stacks are within the bss.
+    pub fn footprint(self, load_base: PhysAddr) -> impl Iterator<Item = (&'static str, PhysRange)> {
+        let image_start = self.start;
+        self.parts.into_iter().map(move |(name, range)| {
+            let start = load_base.addr() + (range.start - image_start) as u64;
+            (name, PhysRange::with_len(start, range.len()))
+        })
+    }
+
+    /// Check l.S can run the image loaded at `load_base`.
+    #[allow(dead_code)]
+    pub fn check_placement(&self, load_base: PhysAddr) -> Result<(), PlacementError> {
+        let offset = load_base
+            .addr()
+            .checked_sub(self.linked_phys_base().addr())
+            .ok_or(PlacementError::BelowLinked)?;
+        if !offset.is_multiple_of(LOAD_ALIGN as u64) {
+            return Err(PlacementError::Misaligned);
+        }
+        Ok(())
+    }
+}
+
+/// Return the name and physical range of each part of the memory the kernel
+/// claims, were it loaded at `load_base`.
+#[allow(dead_code)]
+pub fn footprint(load_base: PhysAddr) -> impl Iterator<Item = (&'static str, PhysRange)> {
+    ImageLayout::linked().footprint(load_base)
+}
+
+/// Formats the footprint of `layout` loaded at `load_base` for tools, one
+/// line per range: a total line with the contiguous range needed, and the
+/// alignment and address it must be loaded at modulo that alignment, then
+/// a line for each part.
+pub struct FootprintReport<'a> {
+    pub layout: &'a ImageLayout,
+    pub load_base: PhysAddr,
+}
+
+impl fmt::Display for FootprintReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let base = self.load_base.addr();
+        let limit = base + self.layout.size() as u64;
+        let phase = self.layout.linked_phys_base().addr() % LOAD_ALIGN as u64;
+        writeln!(f, "footprint total {base:#x} {limit:#x} align={LOAD_ALIGN:#x} phase={phase:#x}")?;
+        for (name, range) in self.layout.clone().footprint(self.load_base) {
+            writeln!(f, "footprint {name} {:#x} {:#x}", range.start().addr(), range.end().addr())?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the digests xtask embedded of the kernel's read-only sections
+/// against their contents now, printing the result for each, and return the
+/// number that don't match.  Each must be within text or rodata, as the
+/// rest of the image may be written at runtime.
+pub fn check_image_digests() -> usize {
+    let Some(table) = port::digest::kernel_table() else {
+        println!("Image digests: none embedded");
+        return 0;
+    };
+    let read_only_ranges = [text_range(), rodata_range()]
+        .map(|range| physrange_as_virtrange_offset_from_kzero(&range));
+    let mut failed = 0;
+    for section in table.iter() {
+        let start = VirtAddr::new(section.start as usize);
+        let range = VirtRange::with_len(start, section.len as usize);
+        let read_only = read_only_ranges
+            .iter()
+            .any(|within| within.start() <= range.start() && range.end() <= within.end());
+        if !read_only {
+            println!("Image digest {}: FAIL: {range} isn't in text or rodata", section.name);
+            failed += 1;
+            continue;
+        }
+        // Safety: the range is within text or rodata, which are mapped
+        // readable, and never written
+        let bytes =
+            unsafe { core::slice::from_raw_parts(range.start().addr() as *const u8, range.size()) };
+        let digest = port::digest::fnv1a(bytes);
+        if digest == section.digest {
+            println!("Image digest {}: pass", section.name);
+        } else {
+            println!(
+                "Image digest {}: FAIL: {digest:#018x}, expected {:#018x}",
+                section.name, section.digest
+            );
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Check the kernel address space layout in param.rs, that the kernel image
+/// is within its kernel image region, and that the aliases of the kernel
+/// stacks are within the stacks region, then register the layout with port.
+pub fn init_layout() -> Result<(), LayoutError> {
+    port::layout::check(&LAYOUT)?;
+    for section in kernel_sections() {
+        let virt = physrange_as_virtrange_offset_from_kzero(&section.range);
+        KERNEL_IMAGE.check_contains(section.name, &virt)?;
+    }
+    for kstack in kernel_stacks() {
+        STACKS.check_contains("kernel stack", &kstack.virt_range())?;
+    }
+    port::layout::set_layout(&LAYOUT);
+    Ok(())
+}
+
+/// Ranges of kernel address space recorded by the memory subsystems, so that
+/// the trap handler can describe faulting addresses without taking locks.
+pub static KERNEL_ADDR_MAP: AddrMap<32> = AddrMap::new();
+
+/// Record a range of kernel address space in KERNEL_ADDR_MAP.
+pub fn record_addr_range(
+    name: &'static str,
+    kind: AddrKind,
+    virt: VirtRange,
+    phys: Option<PhysRange>,
+) {
+    let range = AddrRange { name, kind, virt, phys };
+    if let Err(err) = KERNEL_ADDR_MAP.record(range) {
+        println!("error:kmem:record_addr_range:can't record range. name:{name} err:{err:?}");
+    }
+}
+
+/// Record the kernel image sections and the guard pages beneath the kernel
+/// stacks, both in the KZERO mapping and in the stacks' aliases.
+pub fn record_kernel_addr_ranges() {
+    for section in kernel_sections() {
+        let virt = physrange_as_virtrange_offset_from_kzero(&section.range);
+        record_addr_range(section.name, AddrKind::KernelSection, virt, Some(section.range));
+    }
+    for kstack in kernel_stacks() {
+        let guard = kstack.guard_range();
+        let virt = physrange_as_virtrange_offset_from_kzero(&guard);
+        record_addr_range("kernel stack guard", AddrKind::StackGuard, virt, Some(guard.clone()));
+        let alias = kstack.guard_virt_range();
+        record_addr_range("kernel stack guard", AddrKind::StackGuard, alias, Some(guard));
+    }
+}
+
+/// Ranges of physical memory recorded by the memory subsystems as they're set
+/// up, for `report_memory_map`.
+pub static PHYS_MAP: PhysMap<48> = PhysMap::new();
+
+/// Record a range of physical memory in PHYS_MAP, with the flags it's mapped
+/// with, if it's relevant.
+pub fn record_phys_range(name: &'static str, range: PhysRange, flags: Option<MapFlags>) {
+    if let Err(err) = PHYS_MAP.record(PhysRegion { name, range, flags }) {
+        println!("error:kmem:record_phys_range:can't record range. name:{name} err:{err:?}");
+    }
+}
+
+/// Record the physical memory used by the kernel image sections, the kernel
+/// stacks and the early page tables.
+pub fn record_kernel_phys_ranges() {
+    for section in kernel_sections() {
+        record_phys_range(section.name, section.range, Some(section.flags));
+    }
+    for kstack in kernel_stacks() {
+        record_phys_range("kernel stack", kstack.range, None);
+    }
+    record_phys_range("early pagetables", early_pages_range(), None);
+}
+
+/// Print the physical memory map, in address order, and the memory the page
+/// allocator has free, unless it's locked, then the kernel virtual memory
+/// layout.
+pub fn report_memory_map() {
+    let free = crate::pagealloc::try_stats().map(|stats| stats.free_pages * PAGE_SIZE_4K);
+    print!("{}", PHYS_MAP.report(free));
+    println!("Identity windows:");
+    for window in identity_windows().into_iter().flatten() {
+        println!("  {} {:>10}  {}", window.range, Size(window.range.size()), window.name);
+    }
+    print!("{}", port::layout::report());
+}
+
+/// Return the memory used for each category that isn't allocated from the
+/// page allocator: the kernel image, the kernel stacks in its bss, and the
+/// DTB and initrd.
+fn static_memory_usage() -> [(MemCategory, usize); 3] {
+    let image: usize = kernel_sections().iter().map(|section| section.range.size()).sum();
+    let stacks: usize = kernel_stacks().iter().map(|kstack| kstack.range.size()).sum();
+    let boot_data = PHYS_MAP
+        .iter()
+        .filter(|region| matches!(region.name, "dtb" | "initrd"))
+        .map(|region| region.range.size())
+        .sum();
+    [
+        (MemCategory::KernelImage, image - stacks),
+        (MemCategory::Stacks, stacks),
+        (MemCategory::BootData, boot_data),
+    ]
+}
+
+/// Print the memory used for each category, and the memory the page
+/// allocator has free, unless it's locked, then the memory in each zone.
+pub fn report_memory_usage() {
+    let free = crate::pagealloc::try_stats().map(|stats| stats.free_pages * PAGE_SIZE_4K);
+    print!("{}", crate::pagealloc::MEM_ACCOUNTS.report(&static_memory_usage(), free));
+    print!("{}", crate::pagealloc::ZONES.report());
+}
+
+/// The kernel image, and the memory below it from KZERO's physical address.
+/// The start is worked out directly, as it's only in the KZERO mapping until
+/// the kernel page tables are set up.
 pub fn total_kernel_range() -> PhysRange {
-    PhysRange(from_virt_to_physaddr(VirtAddr::new(base_addr()))..from_virt_to_physaddr(VirtAddr::new(end_addr())))
+    let start = PhysAddr::new(base_addr().wrapping_sub(kzero_offset()) as u64);
+    PhysRange(start..from_virt_to_physaddr(VirtAddr::new(end_addr())))
 }
 
 /// Transform the physical address to a virtual address, under the assumption that
-/// the virtual address is the physical address offset from KZERO.
-pub const fn physaddr_as_ptr_mut_offset_from_kzero<T>(pa: PhysAddr) -> *mut T {
-    (pa.addr() as usize).wrapping_add(KZERO) as *mut T
+/// the virtual address is in the KZERO mapping (see kzero_offset), which debug
+/// builds check for the whole of a T.
+#[track_caller]
+pub fn physaddr_as_ptr_mut_offset_from_kzero<T>(pa: PhysAddr) -> *mut T {
+    check_kzero_mapped(&PhysRange::with_pa_len(pa, size_of::<T>()));
+    (pa.addr() as usize).wrapping_add(kzero_offset()) as *mut T
+}
+
+/// Transform the physical range to a virtual range, under the assumption that
+/// the virtual range is in the KZERO mapping (see kzero_offset), which debug
+/// builds check.
+#[track_caller]
+pub fn physrange_as_virtrange_offset_from_kzero(range: &PhysRange) -> VirtRange {
+    check_kzero_mapped(range);
+    let start = VirtAddr::new((range.start().addr() as usize).wrapping_add(kzero_offset()));
+    VirtRange::with_len(start, range.size())
+}
+
+/// Return the virtual range of the physical range in the early MMIO mapping,
+/// which l.S sets up at a fixed offset of KZERO, wherever the kernel was
+/// loaded.
+pub fn early_mmio_virtrange(range: &PhysRange) -> VirtRange {
+    let start = VirtAddr::new((range.start().addr() as usize).wrapping_add(KZERO));
+    VirtRange::with_len(start, range.size())
+}
+
+/// Return the virtual range of the device registers at `range` in the early
+/// MMIO mapping.  Drivers should move to ranges mapped with vmap once it's
+/// available, so this can't fail, but has the same signature as vmap_device.
+pub fn early_mmio_range(range: &PhysRange) -> Result<VirtRange, Infallible> {
+    Ok(early_mmio_virtrange(range))
 }
 
 /// Given a virtual address, return the physical address.  Makes a massive assumption
-/// that the code is mapped offset to KZERO, so should be used with extreme care.
+/// that the address is in the KZERO mapping, e.g. in the kernel image, which debug
+/// builds check.
+#[track_caller]
 pub fn from_virt_to_physaddr(va: VirtAddr) -> PhysAddr {
     debug_assert!(va.addr() >= KZERO, "from_virt_to_physaddr: va {:?} must be >= KZERO ({})", va, KZERO);
-    PhysAddr::new((va.addr() - KZERO) as u64)
+    let pa = PhysAddr::new(va.addr().wrapping_sub(kzero_offset()) as u64);
+    check_kzero_mapped(&PhysRange::with_pa_len(pa, 0));
+    pa
 }
 
 /// Given an address, return the physical address.  Makes a massive assumption
-/// that the code is mapped offset to KZERO, so should be used with extreme care.
+/// that the code is mapped offset to KZERO, which debug builds check.
+#[track_caller]
 pub fn from_ptr_to_physaddr_offset_from_kzero<T>(a: *const T) -> PhysAddr {
     from_virt_to_physaddr(VirtAddr::new(a.addr()))
 }
@@ -118,3 +693,78 @@ pub fn early_pages_range() -> PhysRange {
         from_virt_to_physaddr(VirtAddr::new(eearly_pagetables_addr())),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A layout like the linker script's, linked at KZERO + 0x80000.
+    fn layout() -> ImageLayout {
+        let at = |from: usize, to: usize| KZERO + from..KZERO + to;
+        ImageLayout {
+            start: KZERO + 0x8_0000,
+            parts: [
+                ("boottext", at(0x8_0000, 0x8_2000)),
+                ("text", at(0x8_2000, 0x20_0000)),
+                ("rodata", at(0x20_0000, 0x40_0000)),
+                ("data", at(0x40_0000, 0x40_3000)),
+                ("bss", at(0x40_3000, 0x60_0000)),
+                ("stacks", at(0x40_4000, 0x41_8000)),
+                ("early_pagetables", at(0x60_0000, 0x62_0000)),
+            ],
+        }
+    }
+
+    #[test]
+    fn footprint_at_any_base() {
+        let layout = layout();
+        assert_eq!(layout.linked_phys_base(), PhysAddr::new(0x8_0000));
+        assert_eq!(layout.size(), 0x5a_0000);
+        let parts: Vec<_> = layout.clone().footprint(PhysAddr::new(0x4008_0000)).collect();
+        assert_eq!(parts[0], ("boottext", PhysRange::with_end(0x4008_0000, 0x4008_2000)));
+        assert_eq!(parts[4], ("bss", PhysRange::with_end(0x4040_3000, 0x4060_0000)));
+        assert_eq!(parts[5], ("stacks", PhysRange::with_end(0x4040_4000, 0x4041_8000)));
+        assert_eq!(parts[6], ("early_pagetables", PhysRange::with_end(0x4060_0000, 0x4062_0000)));
+        let linked: Vec<_> = layout.footprint(PhysAddr::new(0x8_0000)).collect();
+        assert_eq!(linked[1], ("text", PhysRange::with_end(0x8_2000, 0x20_0000)));
+    }
+
+    #[test]
+    fn placement_checked() {
+        let layout = layout();
+        assert_eq!(layout.check_placement(PhysAddr::new(0x8_0000)), Ok(()));
+        assert_eq!(layout.check_placement(PhysAddr::new(0x3a08_0000)), Ok(()));
+        assert_eq!(
+            layout.check_placement(PhysAddr::new(0x10_0000)),
+            Err(PlacementError::Misaligned)
+        );
+        assert_eq!(layout.check_placement(PhysAddr::new(0)), Err(PlacementError::BelowLinked));
+    }
+
+    #[test]
+    fn footprint_report() {
+        let layout = layout();
+        let load_base = PhysAddr::new(0x28_0000);
+        let report = FootprintReport { layout: &layout, load_base }.to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "footprint total 0x280000 0x820000 align=0x200000 phase=0x80000");
+        assert_eq!(lines[1], "footprint boottext 0x280000 0x282000");
+        assert_eq!(lines[7], "footprint early_pagetables 0x800000 0x820000");
+    }
+
+    #[test]
+    fn stack_aliases_in_layout() {
+        assert_eq!(port::layout::check(&LAYOUT), Ok(()));
+        let stacks: [_; MAX_CORES] = core::array::from_fn(|core| {
+            let start = PhysAddr::new(0x40_4000 + (core * KSTACK_SIZE) as u64);
+            KernelStack { core, range: PhysRange::with_pa_len(start, KSTACK_SIZE) }
+        });
+        for kstack in &stacks {
+            assert_eq!(STACKS.check_contains("kernel stack", &kstack.virt_range()), Ok(()));
+            assert_eq!(kstack.guard_virt_range().start(), kstack.virt_range().start());
+            assert_eq!(kstack.guard_virt_range().size(), KSTACK_GUARD_SIZE);
+        }
+        assert_eq!(stacks[1].virt_range().start(), stacks[0].virt_range().end());
+    }
+}
@@ -0,0 +1,512 @@
+/// ktests are tests of the kernel that can only run once it's booted, run
+/// under QEMU by `cargo xtask qemu --ktest`.  See port::ktest.
+use crate::dma::{DmaBuffer, DmaConstraints};
+use crate::kmem::{
+    early_pages_range, kernel_sections, kernel_stacks, physrange_as_virtrange_offset_from_kzero,
+    total_kernel_range,
+};
+use crate::memsnapshot::{self, MemSnapshot};
+use crate::param::DIRECT_MAP;
+use crate::vm::{self, AddressSpace, PageSize, VaMapping};
+use crate::{allocator, dmap, kmem, memory_ranges, memtest, pagealloc, safecopy, timer, vmap};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use port::fdt::DeviceTree;
+use port::ktest;
+use port::mem::{
+    MapFlags, MemError, MemType, PAGE_SIZE_4K, PhysAddr, PhysRange, VirtAddr, VirtRange,
+};
+use port::memaccount::MemCategory;
+use port::memtest::MemTestMode;
+
+/// Categories that keep the pages they grow into, so aren't leaking when
+/// they grow during a test: the heap, which never shrinks, and page tables,
+/// which stay once made, even when their mappings are removed.
+const RETAINED: [MemCategory; 2] = [MemCategory::Heap, MemCategory::PageTables];
+
+/// Fails tests that leave pages allocated, or vmap mappings mapped.
+pub struct MemLeakCheck {
+    before: MemSnapshot,
+    after: MemSnapshot,
+}
+
+/// The snapshots are a few KiB each, too big for the kernel stack.
+static mut LEAK_CHECK: MemLeakCheck =
+    MemLeakCheck { before: MemSnapshot::new(), after: MemSnapshot::new() };
+
+/// Return the leak check for port::ktest::run_checked.
+pub fn leak_check() -> &'static mut MemLeakCheck {
+    // Safety: only called once, to run the tests, on the boot core
+    unsafe { &mut *(&raw mut LEAK_CHECK) }
+}
+
+impl ktest::LeakCheck for MemLeakCheck {
+    fn before(&mut self) {
+        memsnapshot::take(&mut self.before);
+    }
+
+    fn after(&mut self) -> Option<impl fmt::Display + '_> {
+        memsnapshot::take(&mut self.after);
+        let diff = MemSnapshot::diff(&self.before, &self.after);
+        (diff.leaked_pages(&RETAINED) > 0 || diff.leaked_vmaps() > 0).then_some(diff)
+    }
+}
+
+ktest! {
+    fn heap_alloc_free_cycle() {
+        let before = allocator::try_stats().expect("heap locked");
+        for round in 0..8u8 {
+            // Sizes from 1 byte to 512KiB, so both small and large
+            // allocations are cycled
+            let blocks: Vec<Box<[u8]>> =
+                (0..20).map(|i| vec![round ^ i; 1 << i].into_boxed_slice()).collect();
+            for (i, block) in blocks.iter().enumerate() {
+                assert!(block.iter().all(|&b| b == round ^ i as u8), "block {i} corrupted");
+            }
+        }
+        let after = allocator::try_stats().expect("heap locked");
+        assert_eq!(after.allocated_bytes, before.allocated_bytes, "heap leaked");
+        assert_eq!(after.allocs - after.frees, before.allocs - before.frees, "heap leaked");
+    }
+}
+
+ktest! {
+    fn pagealloc_alloc_free_cycle() {
+        let before = pagealloc::stats();
+        for _ in 0..4 {
+            let pages: Vec<_> = (0..64).map(|_| pagealloc::allocate_physpage().unwrap()).collect();
+            for (i, pa) in pages.iter().enumerate() {
+                assert!(pa.is_multiple_of(PAGE_SIZE_4K as u64), "page {pa:?} not aligned");
+                assert!(!pages[..i].contains(pa), "page {pa:?} allocated twice");
+            }
+            for pa in pages {
+                pagealloc::free_physpage(pa).unwrap();
+            }
+        }
+        let contiguous = pagealloc::allocate_contiguous_physpages(16).unwrap();
+        assert_eq!(contiguous.size(), 16 * PAGE_SIZE_4K);
+        pagealloc::free_physpages(&contiguous).unwrap();
+        assert_eq!(pagealloc::stats().free_pages, before.free_pages, "pages leaked");
+    }
+}
+
+ktest! {
+    fn map_lookup_round_trip() {
+        let free_pages = pagealloc::stats().free_pages;
+        let mut space = AddressSpace::kernel();
+        let page = pagealloc::allocate_virtpage(
+            &mut space,
+            "ktest",
+            MapFlags::RW,
+            VaMapping::Offset(kmem::kzero_offset()),
+        )
+        .unwrap();
+        let va = VirtAddr::new(page.0.as_ptr().addr());
+
+        let mapping = vm::lookup(VirtAddr::new(va.addr() + 0x123)).expect("page not mapped");
+        assert_eq!(mapping.pa.addr() as usize + kmem::kzero_offset(), va.addr() + 0x123);
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert!(mapping.entry.flags().contains(MapFlags::RW));
+        assert_eq!(space.lookup(va), vm::lookup(va));
+
+        // The page is usable through the mapping and the direct map
+        page.0.fill(0x5a);
+        let dmap_va = dmap::phys_to_dmap(mapping.pa).unwrap();
+        assert_eq!(unsafe { *(dmap_va.addr() as *const u8) }, 0x5a);
+
+        pagealloc::unmap_virtpage(&mut space, va).unwrap();
+        assert!(vm::lookup(va).is_none());
+        assert_eq!(pagealloc::stats().free_pages, free_pages, "pages leaked");
+    }
+}
+
+ktest! {
+    fn set_mem_type_round_trip() {
+        let mut space = AddressSpace::kernel();
+        let page = pagealloc::allocate_virtpage(
+            &mut space,
+            "ktest",
+            MapFlags::RW,
+            VaMapping::Offset(kmem::kzero_offset()),
+        )
+        .unwrap();
+        let va = VirtAddr::new(page.0.as_ptr().addr());
+        let range = VirtRange::with_len(va, PAGE_SIZE_4K);
+        page.0.fill(0x5a);
+
+        // What was written while cached is cleaned to memory
+        space.set_mem_type(&range, MemType::NormalNonCacheable).unwrap();
+        let mapping = vm::lookup(va).expect("page not mapped");
+        assert_eq!(mapping.entry.flags(), MapFlags::RW | MapFlags::WRITE_COMBINING);
+        assert!(page.0.iter().all(|&b| b == 0x5a), "cached writes lost");
+        page.0.fill(0xa5);
+
+        space.set_mem_type(&range, MemType::NormalCached).unwrap();
+        assert_eq!(vm::lookup(va).expect("page not mapped").entry.flags(), MapFlags::RW);
+        assert!(page.0.iter().all(|&b| b == 0xa5), "uncached writes lost");
+        pagealloc::unmap_virtpage(&mut space, va).unwrap();
+    }
+}
+
+ktest! {
+    fn dma_buffer_alloc_free() {
+        let free_pages = pagealloc::stats().free_pages;
+        let mut buffer = DmaBuffer::alloc(PAGE_SIZE_4K + 1, DmaConstraints::VIDEOCORE).unwrap();
+        let phys = buffer.phys();
+        assert_eq!(phys.size(), PAGE_SIZE_4K + 1);
+        assert!(phys.start().is_multiple_of(PAGE_SIZE_4K as u64), "buffer {phys} not aligned");
+        assert!(phys.end().addr() <= 0x4000_0000, "buffer {phys} above the limit");
+        assert_eq!(buffer.bus_addr(), phys.start().addr() | 0xc000_0000);
+        assert!(buffer.virt().iter().all(|&b| b == 0), "buffer not zeroed");
+
+        buffer.virt_mut()[PAGE_SIZE_4K] = 0x5a;
+        assert_eq!(buffer.virt()[PAGE_SIZE_4K], 0x5a);
+        let va = VirtAddr::new(buffer.virt().as_ptr().addr());
+        let mapping = vm::lookup(va).expect("buffer not mapped");
+        assert_eq!(mapping.pa, phys.start());
+        assert!(mapping.entry.flags().contains(MapFlags::RW | MapFlags::NON_CACHEABLE));
+
+        drop(buffer);
+        assert!(vm::lookup(va).is_none(), "buffer still mapped");
+        assert_eq!(pagealloc::stats().free_pages, free_pages, "pages leaked");
+    }
+}
+
+ktest! {
+    fn vmap_shares_sub_page_windows() {
+        let free_pages = pagealloc::stats().free_pages;
+        let page = pagealloc::allocate_contiguous_zeroed_physpages(2).unwrap();
+        let phys = |offset, len| PhysRange::with_len(page.start().addr() + offset, len);
+
+        // Two small windows in the first page, and one spanning both pages
+        let first = vmap::vmap_memory(&phys(0x840, 0x100), MapFlags::RW).unwrap();
+        let second = vmap::vmap_memory(&phys(0x10, 0x20), MapFlags::RW).unwrap();
+        assert_eq!(first.start() - 0x840, second.start() - 0x10, "page not shared");
+        let spanning = vmap::vmap_memory(&phys(0xff0, 0x20), MapFlags::RW).unwrap();
+        assert_eq!(spanning.start().addr() & (PAGE_SIZE_4K - 1), 0xff0);
+
+        // Unmapping one window leaves the page mapped for the other
+        vmap::vunmap(&first).unwrap();
+        unsafe { (second.start().addr() as *mut u8).write_volatile(0x5a) };
+        let dmap_va = dmap::phys_to_dmap(page.start() + 0x10).unwrap();
+        assert_eq!(unsafe { *(dmap_va.addr() as *const u8) }, 0x5a);
+        vmap::vunmap(&second).unwrap();
+        assert!(vm::lookup(second.start()).is_none(), "page still mapped");
+
+        vmap::vunmap(&spanning).unwrap();
+        assert!(vm::lookup(spanning.start()).is_none(), "pages still mapped");
+        pagealloc::free_physpages(&page).unwrap();
+        assert_eq!(pagealloc::stats().free_pages, free_pages, "pages leaked");
+    }
+}
+
+ktest! {
+    fn memtest_releases_good_memory() {
+        let free_pages = pagealloc::stats().free_pages;
+        memtest::run(MemTestMode::Sparse);
+        assert_eq!(pagealloc::stats().free_pages, free_pages, "pages lost");
+        assert_eq!(pagealloc::MEM_ACCOUNTS.pages(MemCategory::Quarantined), 0);
+    }
+}
+
+ktest! {
+    fn kmem_layout() {
+        let total = total_kernel_range();
+        let sections = kernel_sections();
+        for (i, section) in sections.iter().enumerate() {
+            let range = &section.range;
+            assert!(range.start() <= range.end(), "{} is inverted: {range}", section.name);
+            assert!(
+                range.start().is_multiple_of(PAGE_SIZE_4K as u64),
+                "{} isn't page aligned: {range}",
+                section.name
+            );
+            assert!(
+                total.start() <= range.start() && range.end() <= total.end(),
+                "{} isn't in the kernel: {range}",
+                section.name
+            );
+            if let Some(prev) = i.checked_sub(1).map(|i| &sections[i]) {
+                assert!(
+                    prev.range.end() <= range.start(),
+                    "{} {} overlaps {} {range}",
+                    prev.name,
+                    prev.range,
+                    section.name
+                );
+            }
+            if !section.flags.is_empty() {
+                vm::assert_mapped(&physrange_as_virtrange_offset_from_kzero(range), section.flags);
+            }
+        }
+
+        let bss = kmem::bss_range();
+        for kstack in kernel_stacks() {
+            let stack = &kstack.range;
+            assert!(bss.start() <= stack.start() && stack.end() <= bss.end(), "stack {stack}");
+            let guard = physrange_as_virtrange_offset_from_kzero(&kstack.guard_range());
+            assert!(vm::lookup(guard.start()).is_none(), "stack guard {guard} is mapped");
+            let alias_guard = kstack.guard_virt_range();
+            assert!(vm::lookup(alias_guard.start()).is_none(), "stack guard {alias_guard} is mapped");
+            let mapping = vm::lookup(alias_guard.end()).expect("stack alias isn't mapped");
+            assert_eq!(mapping.pa, kstack.guard_range().end());
+        }
+        let early_pages = early_pages_range();
+        assert!(bss.end() <= early_pages.start(), "early pagetables {early_pages}");
+        assert!(early_pages.end() <= total.end(), "early pagetables {early_pages}");
+
+        // The tests are in the data section, where the linker script put them
+        let data = physrange_as_virtrange_offset_from_kzero(&kmem::data_range());
+        let tests = port::ktest::registered().as_ptr_range();
+        assert!(data.start().addr() <= tests.start.addr() && tests.end.addr() <= data.end().addr());
+    }
+}
+
+ktest! {
+    fn kernel_mapping_matches_layout() {
+        vm::verify_kernel_mapping();
+    }
+}
+
+ktest! {
+    fn safecopy_faults() {
+        // Reading a stack guard page faults, rather than panicking
+        let guard = physrange_as_virtrange_offset_from_kzero(&kernel_stacks()[0].guard_range());
+        let mut buf = [0u8; 16];
+        let fault = safecopy::copy_from(&mut buf, guard.start()).expect_err("guard is readable");
+        assert_eq!(fault.addr, guard.start());
+
+        // As does writing read-only data, which is left unchanged
+        static RODATA: [u8; 4] = [1, 2, 3, 4];
+        let rodata = VirtAddr::new(RODATA.as_ptr().addr());
+        let fault = unsafe { safecopy::copy_to(rodata, &[5, 6]) }.expect_err("rodata is writable");
+        assert_eq!((fault.addr, RODATA), (rodata, [1, 2, 3, 4]));
+
+        // While copying what's mapped works
+        assert_eq!(safecopy::copy_from(&mut buf[..4], rodata), Ok(()));
+        assert_eq!(buf[..4], RODATA);
+    }
+}
+
+ktest! {
+    fn boot_identity_window_torn_down() {
+        // The kernel's data was identity mapped by l.S, and isn't in the
+        // window the secondary cores start through
+        let data = kmem::data_range().start();
+        assert!(kmem::boot_identity_window().0.contains(&data));
+        let windows = kmem::identity_windows();
+        assert!(windows.iter().flatten().all(|window| !window.range.0.contains(&data)));
+        assert!(windows.iter().flatten().all(|window| window.name != "boot"));
+
+        let mut buf = [0u8; 8];
+        let va = VirtAddr::new(data.addr() as usize);
+        let fault = safecopy::copy_from(&mut buf, va).expect_err("identity map still live");
+        assert_eq!(fault.addr, va);
+    }
+}
+
+ktest! {
+    fn fdt_from_qemu() {
+        let dtb = kmem::PHYS_MAP.iter().find(|r| r.name == "dtb").expect("no dtb recorded");
+        let dtb_va = dmap::dmap_range(&dtb.range).expect("dtb isn't in RAM");
+        let dt = unsafe { DeviceTree::from_usize(dtb_va.start().addr()) }.unwrap();
+        assert_eq!(dt.size(), dtb.range.size());
+
+        let root = dt.root().unwrap();
+        let compatible = dt.property(&root, "compatible").unwrap();
+        assert!(!dt.property_value_as_str(&compatible).unwrap().is_empty());
+
+        // The memory we're using is all in the DTB's memory nodes
+        let memory = memory_ranges(&dt);
+        assert!(memory.iter().next().is_some(), "no memory nodes");
+        for region in kmem::PHYS_MAP.iter().filter(|r| r.name == "memory") {
+            let range = &region.range;
+            assert!(
+                memory.iter().any(|m| m.start() <= range.start() && range.end() <= m.end()),
+                "memory {range} isn't in the DTB"
+            );
+        }
+
+        // And the uart the console uses, unless it's using semihosting
+        let uart = dt.find_compatible("arm,pl011").next().expect("no pl011 uart");
+        let region = dt.translated_regions(uart).next().expect("uart has no regs");
+        let range = region.expect("uart regs aren't reachable").phys_range();
+        assert!(range.is_some_and(|range| range.size() > 0), "uart has no regs");
+        assert!(dt.find_by_path("/chosen").is_some());
+    }
+}
+
+/// A page of its own to watch, so nothing else is caught writing it.
+#[cfg(debug_assertions)]
+#[repr(align(4096))]
+struct WatchedPage([u64; PAGE_SIZE_4K / 8]);
+
+#[cfg(debug_assertions)]
+static mut WATCHED_PAGE: WatchedPage = WatchedPage([0; PAGE_SIZE_4K / 8]);
+
+#[cfg(debug_assertions)]
+ktest! {
+    fn watched_page_logs_once() {
+        use crate::watch::{self, WatchError, WatchMode};
+
+        let page = unsafe { &raw mut WATCHED_PAGE.0 };
+        let va = VirtAddr::new(page.addr());
+        watch::protect_page(va, WatchMode::LogOnce).unwrap();
+        let flags = vm::lookup(va).unwrap().entry.flags();
+        assert!(!flags.contains(MapFlags::WRITE), "watched page mapped {flags}");
+        assert!(matches!(
+            watch::protect_page(va, WatchMode::Trap),
+            Err(WatchError::AlreadyWatched)
+        ));
+
+        // The write faults, is reported, and is retried once the page is
+        // writable again, which also removes the watch
+        unsafe { (*page)[3] = 0x1234 };
+        assert_eq!(unsafe { (*page)[3] }, 0x1234);
+        assert!(vm::lookup(va).unwrap().entry.flags().contains(MapFlags::RW));
+        assert!(matches!(watch::unprotect_page(va), Err(WatchError::NotWatched)));
+
+        watch::protect_page(va, WatchMode::Trap).unwrap();
+        watch::unprotect_page(va).unwrap();
+        unsafe { (*page)[4] = 0x5678 };
+        assert_eq!(unsafe { (*page)[4] }, 0x5678);
+    }
+}
+
+ktest! {
+    fn zero_phys_range_through_dmap() {
+        let pa = pagealloc::allocate_physpage().unwrap();
+        let va = dmap::phys_to_dmap(pa).unwrap();
+        let page = unsafe { core::slice::from_raw_parts_mut(va.addr() as *mut u8, PAGE_SIZE_4K) };
+        page.fill(0x5a);
+
+        // Unaligned at both ends, so DC ZVA, if allowed, only does the middle
+        dmap::zero_phys_range(&PhysRange::new(pa + 0x13, pa + 0xfe1)).unwrap();
+        assert!(page[..0x13].iter().all(|&b| b == 0x5a), "zeroed before the range");
+        assert!(page[0x13..0xfe1].iter().all(|&b| b == 0), "range not zeroed");
+        assert!(page[0xfe1..].iter().all(|&b| b == 0x5a), "zeroed after the range");
+
+        // Memory that isn't RAM is refused
+        let beyond = PhysRange::with_len(u64::MAX - 0xfff, 0x100);
+        let not_mapped = Err(MemError::PhysNotMapped { pa: beyond.start() });
+        assert_eq!(dmap::zero_phys_range(&beyond), not_mapped);
+        pagealloc::free_physpage(pa).unwrap();
+    }
+}
+
+ktest! {
+    fn phys_access_checks() {
+        let pa = pagealloc::allocate_physpage().unwrap();
+        dmap::write_phys(pa + 8, 0x1122_3344_5566_7788u64).unwrap();
+        dmap::write_phys(pa + 0x12, 0xaabbu16).unwrap();
+        assert_eq!(dmap::read_phys::<u32>(pa + 0xc), Ok(0x1122_3344));
+        assert_eq!(dmap::read_phys::<u32>(pa + 0x10), Ok(0xaabb_0000));
+
+        // Misaligned and unmapped accesses are refused, rather than faulting
+        let misaligned = Err(MemError::Unaligned { addr: (pa + 4).addr(), required: 8 });
+        assert_eq!(dmap::read_phys::<u64>(pa + 4), misaligned);
+        let misaligned = Err(MemError::Unaligned { addr: (pa + 1).addr(), required: 2 });
+        assert_eq!(dmap::write_phys(pa + 1, 0u16), misaligned);
+        let unmapped = PhysAddr::new(u64::MAX & !0xfff);
+        assert_eq!(dmap::read_phys::<u8>(unmapped), Err(MemError::PhysNotMapped { pa: unmapped }));
+        pagealloc::free_physpage(pa).unwrap();
+    }
+}
+
+ktest! {
+    fn dmap_exclusions_refused() {
+        // param::DMAP_EXCLUDED has pages for the ktests on QEMU's boards
+        let exclusions = dmap::exclusions();
+        assert!(exclusions.iter().next().is_some(), "nothing excluded from the direct map");
+        for exclusion in exclusions.iter() {
+            let range = &exclusion.range;
+            assert!(dmap::phys_to_dmap(range.start()).is_err(), "{range} is in the direct map");
+            assert!(dmap::dmap_range(range).is_err(), "{range} is in the direct map");
+            let pa = range.start() + 8;
+            assert_eq!(dmap::read_phys::<u64>(pa), Err(MemError::PhysNotMapped { pa }));
+
+            // Those mapped uncached are only readable, uncached
+            let va = dmap::dmap_uncached_range(range);
+            assert_eq!(va.is_ok(), exclusion.uncached, "{range} mapped wrongly");
+            let Ok(va) = va else {
+                assert!(vm::lookup(VirtAddr::new(DIRECT_MAP.start + pa.addr() as usize)).is_none());
+                continue;
+            };
+            let flags = vm::lookup(va.start()).expect("uncached range isn't mapped").entry.flags();
+            assert_eq!(flags, MapFlags::READ | MapFlags::NON_CACHEABLE);
+            let _ = unsafe { (va.start().addr() as *const u64).read_volatile() };
+        }
+    }
+}
+
+ktest! {
+    fn alarms_fire_in_order() {
+        use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+        use core::time::Duration;
+        use port::alarm::AlarmError;
+        use port::time::{self, Deadline};
+
+        // Order each alarm fired in, and when, set by its callback
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        static ORDER: [AtomicUsize; 3] = [const { AtomicUsize::new(usize::MAX) }; 3];
+        static FIRED_AT: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+        fn fired(i: usize) {
+            ORDER[i].store(NEXT.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+            FIRED_AT[i].store(time::now(), Ordering::Relaxed);
+        }
+
+        let start = time::now();
+        let deadlines = [30, 10, 20].map(|ms| Deadline::at(start + ms * 1_000_000));
+        let callbacks: [fn(_); 3] = [|_| fired(0), |_| fired(1), |_| fired(2)];
+        let mut handles = Vec::new();
+        for (deadline, callback) in deadlines.into_iter().zip(callbacks) {
+            match timer::set_alarm(deadline, callback) {
+                Ok(handle) => handles.push(handle),
+                Err(AlarmError::NoTimer) => return, // No GIC, e.g. on the raspi3
+                Err(err) => panic!("couldn't set alarm: {err:?}"),
+            }
+        }
+        let cancelled = timer::set_alarm(Deadline::at(start + 15_000_000), |_| {
+            panic!("cancelled alarm fired")
+        })
+        .unwrap();
+        assert!(timer::cancel_alarm(cancelled));
+
+        let timeout = Deadline::after(Duration::from_secs(1));
+        while NEXT.load(Ordering::Relaxed) < 3 {
+            assert!(!timeout.expired(), "only {} alarms fired", NEXT.load(Ordering::Relaxed));
+            core::hint::spin_loop();
+        }
+        let order = ORDER.each_ref().map(|o| o.load(Ordering::Relaxed));
+        assert_eq!(order, [2, 0, 1]);
+
+        // Never early, and not much late, allowing for a slow emulator
+        const TOLERANCE_NS: u64 = 20_000_000;
+        for (deadline, fired_at) in deadlines.iter().zip(&FIRED_AT) {
+            let fired_at = fired_at.load(Ordering::Relaxed);
+            assert!(fired_at >= deadline.nanos(), "fired {}ns early", deadline.nanos() - fired_at);
+            assert!(fired_at - deadline.nanos() < TOLERANCE_NS, "fired too late");
+        }
+        assert!(!timer::cancel_alarm(handles[0]));
+    }
+}
+
+ktest! {
+    fn footprint_matches_kernel() {
+        let load_base = kmem::kernel_phys_base();
+        assert_eq!(kmem::ImageLayout::linked().check_placement(load_base), Ok(()));
+        let footprint: Vec<_> = kmem::footprint(load_base).collect();
+        for section in kmem::kernel_sections().iter().skip(1) {
+            let part = footprint.iter().find(|(name, _)| *name == section.name);
+            assert_eq!(part.map(|(_, range)| range), Some(&section.range), "{}", section.name);
+        }
+        let stacks = kmem::kernel_stacks();
+        let (_, range) = footprint.iter().find(|(name, _)| *name == "stacks").unwrap();
+        assert_eq!(range.start(), stacks[0].range.start());
+        assert_eq!(range.end(), stacks[stacks.len() - 1].range.end());
+        assert_eq!(footprint.last().map(|(_, range)| range), Some(&kmem::early_pages_range()));
+        assert_eq!(footprint[0].1.start(), load_base);
+    }
+}
@@ -1,10 +1,23 @@
+use crate::dma::{DmaBuffer, DmaConstraints};
 use crate::io::{read_reg, write_reg};
-use crate::param::KZERO;
+use crate::kmem::early_mmio_range;
+use crate::pagealloc;
+use crate::vmap::vmap_device;
 use core::cell::SyncUnsafeCell;
 use core::mem::MaybeUninit;
+use core::slice;
+use core::time::Duration;
+use port::cache;
 use port::fdt::DeviceTree;
 use port::mcslock::{Lock, LockNode};
-use port::mem::{PhysAddr, PhysRange, VirtRange};
+use port::mem::{MemError, PAGE_SIZE_4K, PhysRange, VirtRange};
+use port::memaccount::MemCategory;
+use port::mmio::{MapDeviceError, map_device_mmio};
+use port::pagealloc::ReserveError;
+use port::time::Deadline;
+
+#[cfg(not(test))]
+use port::println;
 
 const MBOX_READ: usize = 0x00;
 const MBOX_STATUS: usize = 0x18;
@@ -13,6 +26,19 @@ const MBOX_WRITE: usize = 0x20;
 const MBOX_FULL: u32 = 0x8000_0000;
 const MBOX_EMPTY: u32 = 0x4000_0000;
 
+/// How long to wait for the VideoCore to respond to a request.
+const MBOX_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Response code for a message the firmware processed successfully.
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+/// Set in a tag's code once the firmware has responded to it.  The remaining
+/// bits are the length of the response.
+const TAG_RESPONSE: u32 = 0x8000_0000;
+
+/// The top bits of VideoCore bus addresses select the cache alias, and the
+/// rest is the ARM physical address.
+const VC_BUS_ADDR_MASK: u32 = 0x3fff_ffff;
+
 static MAILBOX: Lock<Option<&'static mut Mailbox>> = Lock::new("mailbox", None);
 
 /// Mailbox init.  Mainly initialises a lock to ensure only one mailbox request
@@ -26,51 +52,112 @@ pub fn init(dt: &DeviceTree) {
             SyncUnsafeCell::new(MaybeUninit::uninit());
         unsafe {
             let maybe_mailbox = &mut *MAYBE_MAILBOX.get();
-            maybe_mailbox.write(Mailbox::new(dt, KZERO));
+            let early_mailbox = Mailbox::new(dt, early_mmio_range)
+                .unwrap_or_else(|err| panic!("error:mailbox:init:can't map registers: {err:?}"));
+            maybe_mailbox.write(early_mailbox);
             maybe_mailbox.assume_init_mut()
         }
     });
 }
 
+/// Move the mailbox from the early MMIO mapping to registers mapped with vmap,
+/// and give it a DMA buffer for messages.
+pub fn init_vmap(dt: &DeviceTree) -> Result<(), MapDeviceError<MemError>> {
+    let node = LockNode::new();
+    let mut mailbox = MAILBOX.lock(&node);
+    if let Some(mailbox) = mailbox.as_deref_mut() {
+        *mailbox = Mailbox::new(dt, vmap_device)?;
+        match DmaBuffer::alloc(PAGE_SIZE_4K, DmaConstraints::VIDEOCORE) {
+            Ok(buffer) => mailbox.buffer = Some(buffer),
+            Err(err) => println!("error:mailbox:init_vmap:couldn't allocate buffer: {err}"),
+        }
+    }
+    Ok(())
+}
+
 /// https://developer.arm.com/documentation/ddi0306/b/CHDGHAIG
 /// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
 struct Mailbox {
     pub mbox_range: VirtRange,
+    buffer: Option<DmaBuffer>, // For messages, once there's a page allocator
 }
 
 impl Mailbox {
-    fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> Mailbox {
-        Mailbox {
-            mbox_range: VirtRange::from(
-                &dt.find_compatible("brcm,bcm2835-mbox")
-                    .next()
-                    .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                    .and_then(|reg| reg.regblock())
-                    .unwrap()
-                    .with_offset(mmio_virt_offset as u64),
-            ),
-        }
+    /// Find the mailbox in the device tree, mapping its registers with `map`.
+    fn new<E>(
+        dt: &DeviceTree,
+        map: impl Fn(&PhysRange) -> Result<VirtRange, E>,
+    ) -> Result<Mailbox, MapDeviceError<E>> {
+        let mbox = dt.find_compatible("brcm,bcm2835-mbox").next().unwrap();
+        Ok(Mailbox { mbox_range: map_device_mmio(dt, mbox, 0, map)?, buffer: None })
     }
 
-    fn request<T, U>(&self, req: &mut Message<T, U>)
+    /// Send the message `req`, and wait for the response, which replaces it.
+    /// The message is copied to the DMA buffer, if there is one, otherwise
+    /// the VideoCore reads it where it is, with the cache maintained around
+    /// the request.
+    fn request<T, U>(&mut self, req: &mut Message<T, U>)
     where
         T: Copy,
         U: Copy,
     {
+        let size = size_of::<Message<T, U>>();
+        let range = cache::range_of(req);
+        // Safety: the message is plain data, which the response replaces
+        let msg = unsafe { slice::from_raw_parts_mut(req as *mut _ as *mut u8, size) };
+        match self.buffer.as_mut() {
+            Some(buffer) if size <= buffer.len() => {
+                buffer.virt_mut()[..size].copy_from_slice(msg);
+                buffer.sync_for_device();
+                Self::submit(&self.mbox_range, buffer.bus_addr() as u32);
+                buffer.sync_for_cpu();
+                msg.copy_from_slice(&buffer.virt()[..size]);
+            }
+            _ => {
+                // The VideoCore doesn't snoop the caches, so write the
+                // request back to memory before it reads it, and discard
+                // any lines of it after, so the response is read from memory
+                cache::clean(&range);
+                Self::submit(&self.mbox_range, msg.as_ptr().addr() as u32);
+                cache::invalidate(&range);
+            }
+        }
+    }
+
+    /// Submit the message at bus address `addr` to the mailbox registers at
+    /// `mbox_range`, and wait for the response.  If the VideoCore doesn't
+    /// respond in time, the message is left as it was, which callers see as
+    /// an unsuccessful response.
+    fn submit(mbox_range: &VirtRange, addr: u32) {
+        let deadline = Deadline::after(MBOX_TIMEOUT);
+        let timed_out = |waiting_for| {
+            let expired = deadline.expired();
+            if expired {
+                println!("error:mailbox:submit:timed out waiting for {waiting_for}");
+            }
+            expired
+        };
+
         // Read status register until full flag not set
-        while (read_reg(&self.mbox_range, MBOX_STATUS) & MBOX_FULL) != 0 {}
+        while (read_reg(mbox_range, MBOX_STATUS) & MBOX_FULL) != 0 {
+            if timed_out("space to write") {
+                return;
+            }
+        }
 
         // Write the request address combined with the channel to the write register
         let channel = ChannelId::ArmToVc as u32;
-        let uart_mbox_u32 = req as *const _ as u32;
-        let r = (uart_mbox_u32 & !0xF) | channel;
-        write_reg(&self.mbox_range, MBOX_WRITE, r);
+        let r = (addr & !0xF) | channel;
+        write_reg(mbox_range, MBOX_WRITE, r);
 
-        // Wait for response
-        // FIXME: two infinite loops - can go awry
+        // Wait for the response, ignoring any to other requests
         loop {
-            while (read_reg(&self.mbox_range, MBOX_STATUS) & MBOX_EMPTY) != 0 {}
-            let response = read_reg(&self.mbox_range, MBOX_READ);
+            while (read_reg(mbox_range, MBOX_STATUS) & MBOX_EMPTY) != 0 {
+                if timed_out("the response") {
+                    return;
+                }
+            }
+            let response = read_reg(mbox_range, MBOX_READ);
             if response == r {
                 break;
             }
@@ -109,7 +196,10 @@ struct Tag<T> {
     end_tag: u32,
 }
 
-#[repr(C, align(16))]
+/// The mailbox needs messages 16 byte aligned, and aligning them to a cache
+/// line means they don't share lines with anything else, which would be
+/// lost when the message is invalidated after the response.
+#[repr(C, align(64))]
 #[derive(Clone, Copy)]
 union Message<T: Copy, U: Copy> {
     request: Request<T>,
@@ -118,12 +208,62 @@ union Message<T: Copy, U: Copy> {
 
 type MessageWithTags<T, U> = Message<Tag<T>, Tag<U>>;
 
+/// One of several tags in a message.  The tags are followed by an end tag.
+/// The body is used for both the request and the response, so must be big
+/// enough for either.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TagEntry<T: Copy> {
+    tag_id: TagId,
+    buffer_size: u32,
+    code: u32,
+    body: T,
+}
+
+impl<T: Copy> TagEntry<T> {
+    fn new(tag_id: TagId, body: T) -> Self {
+        Self { tag_id, buffer_size: size_of::<T>() as u32, code: 0, body }
+    }
+
+    /// Return the body of the response, or an error with the tag's code if
+    /// the firmware didn't respond to the tag.
+    fn response(&self) -> Result<T, MailboxError> {
+        if self.code & TAG_RESPONSE == 0 {
+            return Err(MailboxError::Tag(self.tag_id, self.code));
+        }
+        Ok(self.body)
+    }
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum MailboxError {
+    Response(u32),   // The message failed, with the response code
+    Tag(TagId, u32), // The tag failed, with the tag's code
+}
+
+/// Send a message made of several tags, returning the tags with the
+/// firmware's responses filled in.  `tags` must be a repr(C) struct of
+/// TagEntry, followed by a u32 end tag of 0.
+fn request_tags<T: Copy>(tags: T) -> Result<T, MailboxError> {
+    let size = size_of::<Message<T, T>>() as u32;
+    let mut msg = Message::<T, T> { request: Request { size, code: 0, tags } };
+    let node = LockNode::new();
+    let mut mailbox = MAILBOX.lock(&node);
+    mailbox.as_deref_mut().unwrap().request(&mut msg);
+    let res = unsafe { msg.response };
+    if res.code != RESPONSE_SUCCESS {
+        return Err(MailboxError::Response(res.code));
+    }
+    Ok(res.tags)
+}
+
 fn request<T, U>(code: u32, tags: &Tag<T>) -> U
 where
     T: Copy,
     U: Copy,
 {
-    let size = size_of::<Message<T, U>>() as u32;
+    let size = size_of::<MessageWithTags<T, U>>() as u32;
     let req = Request::<Tag<T>> { size, code, tags: *tags };
     let mut msg = MessageWithTags { request: req };
     let node = LockNode::new();
@@ -135,8 +275,8 @@ where
 
 // https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface#tags-arm-to-vc
 #[repr(u32)]
-#[derive(Debug, Clone, Copy)]
-enum TagId {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagId {
     GetFirmwareRevision = 0x0000_0001,
     GetBoardModel = 0x0001_0001,
     GetBoardRevision = 0x0001_0002,
@@ -145,6 +285,12 @@ enum TagId {
     GetArmMemory = 0x0001_0005,
     GetVcMemory = 0x0001_0006,
     SetClockRate = 0x0003_8002,
+    AllocateBuffer = 0x0004_0001,
+    GetPitch = 0x0004_0008,
+    SetPhysicalSize = 0x0004_8003,
+    SetVirtualSize = 0x0004_8004,
+    SetDepth = 0x0004_8005,
+    SetPixelOrder = 0x0004_8006,
 }
 
 #[repr(C)]
@@ -194,71 +340,29 @@ pub struct MemoryInfo {
     pub end: u32,
 }
 
-#[allow(dead_code)]
-pub fn get_arm_memory() -> PhysRange {
+/// Ask the firmware for a range of memory, with GetArmMemory or GetVcMemory.
+fn get_memory(tag_id: TagId) -> PhysRange {
     let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetArmMemory,
-        tag_buffer_size0: 12,
+        tag_id0: tag_id,
+        tag_buffer_size0: size_of::<MemoryResponse>() as u32,
         tag_code0: 0,
         body: EmptyRequest {},
         end_tag: 0,
     };
     let res: MemoryResponse = request(0, &tags);
-    let start = res.base_addr;
-    let size = res.size;
-    let end = start + size;
-
-    PhysRange::new(PhysAddr::new(start as u64), PhysAddr::new(end as u64))
+    PhysRange::with_len(res.base_addr as u64, res.size as usize)
 }
 
-#[allow(dead_code)]
-pub fn get_vc_memory() -> PhysRange {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetVcMemory,
-        tag_buffer_size0: 12,
-        tag_code0: 0,
-        body: EmptyRequest {},
-        end_tag: 0,
-    };
-    let res: MemoryResponse = request(0, &tags);
-    let start = res.base_addr;
-    let size = res.size;
-    let end = start + size;
-
-    PhysRange::new(PhysAddr::new(start as u64), PhysAddr::new(end as u64))
-}
-
-pub fn get_firmware_revision() -> u32 {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetFirmwareRevision,
-        tag_buffer_size0: 4,
-        tag_code0: 0,
-        body: EmptyRequest {},
-        end_tag: 0,
-    };
-    request::<_, u32>(0, &tags)
-}
-
-pub fn get_board_model() -> u32 {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetBoardModel,
-        tag_buffer_size0: 4,
-        tag_code0: 0,
-        body: EmptyRequest {},
-        end_tag: 0,
-    };
-    request::<_, u32>(0, &tags)
+/// Return the memory the firmware has given to the ARM cores.  On boards with
+/// more than 1GiB this only covers the memory below the VideoCore's.
+pub fn get_arm_memory() -> PhysRange {
+    get_memory(TagId::GetArmMemory)
 }
 
-pub fn get_board_revision() -> u32 {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetBoardRevision,
-        tag_buffer_size0: 4,
-        tag_code0: 0,
-        body: EmptyRequest {},
-        end_tag: 0,
-    };
-    request::<_, u32>(0, &tags)
+/// Return the memory the firmware has kept for the VideoCore, which depends on
+/// the gpu_mem setting in config.txt.
+pub fn get_vc_memory() -> PhysRange {
+    get_memory(TagId::GetVcMemory)
 }
 
 #[repr(C)]
@@ -272,27 +376,240 @@ pub struct MacAddress {
     pub f: u8,
 }
 
-pub fn get_board_macaddr() -> MacAddress {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetBoardMacAddress,
-        tag_buffer_size0: 6,
-        tag_code0: 0,
-        body: EmptyRequest {},
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BoardInfoTags {
+    firmware_revision: TagEntry<u32>,
+    model: TagEntry<u32>,
+    revision: TagEntry<u32>,
+    mac_address: TagEntry<MacAddress>,
+    serial: TagEntry<[u32; 2]>, // A u64 would be misaligned in the message
+    end_tag: u32,
+}
+
+/// Information about the board, from the firmware.
+#[derive(Debug)]
+pub struct BoardInfo {
+    pub firmware_revision: u32,
+    pub model: u32,
+    pub revision: BoardRevision,
+    pub mac_address: MacAddress,
+    pub serial: u64,
+}
+
+/// Ask the firmware about the board, in a single message.
+pub fn get_board_info() -> Result<BoardInfo, MailboxError> {
+    let tags = request_tags(BoardInfoTags {
+        firmware_revision: TagEntry::new(TagId::GetFirmwareRevision, 0),
+        model: TagEntry::new(TagId::GetBoardModel, 0),
+        revision: TagEntry::new(TagId::GetBoardRevision, 0),
+        mac_address: TagEntry::new(
+            TagId::GetBoardMacAddress,
+            MacAddress { a: 0, b: 0, c: 0, d: 0, e: 0, f: 0 },
+        ),
+        serial: TagEntry::new(TagId::GetBoardSerial, [0; 2]),
         end_tag: 0,
-    };
-    request::<_, MacAddress>(0, &tags)
+    })?;
+    let [serial_lo, serial_hi] = tags.serial.response()?;
+    Ok(BoardInfo {
+        firmware_revision: tags.firmware_revision.response()?,
+        model: tags.model.response()?,
+        revision: BoardRevision(tags.revision.response()?),
+        mac_address: tags.mac_address.response()?,
+        serial: ((serial_hi as u64) << 32) | serial_lo as u64,
+    })
 }
 
-pub fn get_board_serial() -> u64 {
-    let tags = Tag::<EmptyRequest> {
-        tag_id0: TagId::GetBoardSerial,
-        tag_buffer_size0: 8,
-        tag_code0: 0,
-        body: EmptyRequest {},
+/// Board revision code, which for new-style codes encodes the model,
+/// processor, memory size and so on.
+/// https://github.com/raspberrypi/documentation/blob/develop/documentation/asciidoc/computers/raspberry-pi/revision-codes.adoc
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoardRevision(pub u32);
+
+impl BoardRevision {
+    const NEW_STYLE: u32 = 1 << 23;
+
+    fn field(&self, shift: u32, bits: u32) -> u32 {
+        (self.0 >> shift) & ((1 << bits) - 1)
+    }
+
+    pub fn is_new_style(&self) -> bool {
+        self.0 & Self::NEW_STYLE != 0
+    }
+
+    /// Return the name of the model, if it's a new-style code for a known
+    /// model.
+    pub fn model_name(&self) -> Option<&'static str> {
+        if !self.is_new_style() {
+            return None;
+        }
+        Some(match self.field(4, 8) {
+            0x00 => "Raspberry Pi A",
+            0x01 => "Raspberry Pi B",
+            0x02 => "Raspberry Pi A+",
+            0x03 => "Raspberry Pi B+",
+            0x04 => "Raspberry Pi 2B",
+            0x06 => "Raspberry Pi Compute Module 1",
+            0x08 => "Raspberry Pi 3B",
+            0x09 => "Raspberry Pi Zero",
+            0x0a => "Raspberry Pi Compute Module 3",
+            0x0c => "Raspberry Pi Zero W",
+            0x0d => "Raspberry Pi 3B+",
+            0x0e => "Raspberry Pi 3A+",
+            0x10 => "Raspberry Pi Compute Module 3+",
+            0x11 => "Raspberry Pi 4B",
+            0x12 => "Raspberry Pi Zero 2 W",
+            0x13 => "Raspberry Pi 400",
+            0x14 => "Raspberry Pi Compute Module 4",
+            0x15 => "Raspberry Pi Compute Module 4S",
+            0x17 => "Raspberry Pi 5",
+            0x18 => "Raspberry Pi Compute Module 5",
+            0x19 => "Raspberry Pi 500",
+            0x1a => "Raspberry Pi Compute Module 5 Lite",
+            _ => return None,
+        })
+    }
+
+    /// Return the name of the SoC, if it's a new-style code for a known SoC.
+    pub fn processor(&self) -> Option<&'static str> {
+        if !self.is_new_style() {
+            return None;
+        }
+        ["BCM2835", "BCM2836", "BCM2837", "BCM2711", "BCM2712"]
+            .get(self.field(12, 4) as usize)
+            .copied()
+    }
+
+    /// Return the size of memory in bytes, if it's a new-style code.
+    pub fn memory_size(&self) -> Option<u64> {
+        self.is_new_style().then(|| (256 << 20) << self.field(20, 3))
+    }
+
+    /// Return the board revision, e.g. 2 for 1.2, if it's a new-style code.
+    pub fn board_revision(&self) -> Option<u32> {
+        self.is_new_style().then(|| self.field(0, 4))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct AllocateBufferRequest {
+    alignment: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union AllocateBufferBody {
+    request: AllocateBufferRequest,
+    response: MemoryResponse,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DisplaySize {
+    width: u32,
+    height: u32,
+}
+
+/// Pixel order for SetPixelOrder
+const PIXEL_ORDER_RGB: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FramebufferTags {
+    physical_size: TagEntry<DisplaySize>,
+    virtual_size: TagEntry<DisplaySize>,
+    depth: TagEntry<u32>,
+    pixel_order: TagEntry<u32>,
+    allocate: TagEntry<AllocateBufferBody>,
+    pitch: TagEntry<u32>,
+    end_tag: u32,
+}
+
+/// A framebuffer allocated by the firmware.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct FramebufferInfo {
+    pub range: PhysRange,
+    pub width: u32,  // In pixels
+    pub height: u32, // In pixels
+    pub depth: u32,  // Bits per pixel
+    pub pitch: u32,  // Bytes per row
+}
+
+/// Ask the firmware to allocate a `width` by `height` framebuffer with
+/// `depth` bits per pixel in RGB order, returning what it actually
+/// allocated, which may differ.  If the framebuffer is in memory managed by
+/// the page allocator, the pages are reserved so they aren't handed out.
+/// Usually it's in memory set aside for the GPU, so there's nothing to
+/// reserve.
+#[allow(dead_code)]
+pub fn allocate_framebuffer(
+    width: u32,
+    height: u32,
+    depth: u32,
+) -> Result<FramebufferInfo, MailboxError> {
+    let size = DisplaySize { width, height };
+    let tags = request_tags(FramebufferTags {
+        physical_size: TagEntry::new(TagId::SetPhysicalSize, size),
+        virtual_size: TagEntry::new(TagId::SetVirtualSize, size),
+        depth: TagEntry::new(TagId::SetDepth, depth),
+        pixel_order: TagEntry::new(TagId::SetPixelOrder, PIXEL_ORDER_RGB),
+        allocate: TagEntry::new(
+            TagId::AllocateBuffer,
+            AllocateBufferBody { request: AllocateBufferRequest { alignment: 16 } },
+        ),
+        pitch: TagEntry::new(TagId::GetPitch, 0),
         end_tag: 0,
-    };
-    // FIXME: Treating this a `u64` gets us a memory address. Pointer fun ahead.
-    // Wrapping in a struct holding a single u64 doesn't work either.
-    let res: [u32; 2] = request(0, &tags);
-    ((res[0] as u64) << 32) | res[1] as u64
+    })?;
+
+    let DisplaySize { width, height } = tags.physical_size.response()?;
+    tags.virtual_size.response()?;
+    let depth = tags.depth.response()?;
+    tags.pixel_order.response()?;
+    let buffer = unsafe { tags.allocate.response()?.response };
+    let pitch = tags.pitch.response()?;
+    if buffer.size == 0 {
+        return Err(MailboxError::Tag(TagId::AllocateBuffer, tags.allocate.code));
+    }
+
+    let start = buffer.base_addr & VC_BUS_ADDR_MASK;
+    let range = PhysRange::with_len(start as u64, buffer.size as usize);
+    match pagealloc::reserve_physpages_for(&range, MemCategory::Dma) {
+        Ok(reserved) => println!("mailbox:allocate_framebuffer:reserved {}", reserved),
+        Err(ReserveError::NotInMemory(_)) => {}
+        Err(err) => {
+            println!("error:mailbox:allocate_framebuffer:couldn't reserve {}: {:?}", range, err)
+        }
+    }
+    Ok(FramebufferInfo { range, width, height, depth, pitch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_board_revision() {
+        // Raspberry Pi 3B 1.2, 1GiB, made by Sony UK
+        let pi3b = BoardRevision(0xa02082);
+        assert!(pi3b.is_new_style());
+        assert_eq!(pi3b.model_name(), Some("Raspberry Pi 3B"));
+        assert_eq!(pi3b.processor(), Some("BCM2837"));
+        assert_eq!(pi3b.memory_size(), Some(1 << 30));
+        assert_eq!(pi3b.board_revision(), Some(2));
+
+        let pi4b = BoardRevision(0xd03115);
+        assert_eq!(pi4b.model_name(), Some("Raspberry Pi 4B"));
+        assert_eq!(pi4b.processor(), Some("BCM2711"));
+        assert_eq!(pi4b.memory_size(), Some(8 << 30));
+
+        // Old-style codes are just a number
+        let old = BoardRevision(0x000e);
+        assert!(!old.is_new_style());
+        assert_eq!(old.model_name(), None);
+        assert_eq!(old.memory_size(), None);
+
+        assert_eq!(BoardRevision(0x80_0ff0).model_name(), None);
+    }
 }
@@ -5,48 +5,96 @@
 #![feature(alloc_error_handler)]
 #![feature(core_intrinsics)]
 #![feature(sync_unsafe_cell)]
+#![feature(unsigned_is_multiple_of)]
 #![forbid(unsafe_op_in_unsafe_fn)]
 
 mod allocator;
+mod cache;
 mod devcons;
+mod dma;
+mod dmap;
+mod framebuffer;
+mod gic;
 mod io;
 mod kmem;
+#[cfg(feature = "ktest")]
+mod ktests;
 mod mailbox;
+mod memsnapshot;
+mod memtest;
+mod mmu;
 mod pagealloc;
 mod param;
+mod psci;
 mod registers;
+mod safecopy;
+mod semihosting;
+mod smp;
 mod swtch;
+mod timer;
 mod trap;
 mod uartmini;
 mod uartpl011;
 mod vm;
+mod vmap;
 mod vmdebug;
+#[cfg(debug_assertions)]
+mod watch;
 
 extern crate alloc;
 
+use crate::dmap::ExclusionReason;
 use crate::kmem::from_virt_to_physaddr;
-use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::ptr::{self, null_mut};
-use kmem::{boottext_range, bss_range, data_range, rodata_range, text_range, total_kernel_range};
-use param::KZERO;
+use kmem::{
+    boottext_range, bss_range, data_range, early_pages_range, kernel_sections,
+    physrange_as_virtrange_offset_from_kzero, rodata_range, text_range, total_kernel_range,
+};
+use param::{KZERO, MAX_CORES};
+use port::bootargs::BootArgs;
+use port::counters::{self, Stopwatch};
 use port::fdt::DeviceTree;
-use port::mem::{PhysRange, VirtAddr};
-use port::println;
-use vm::{Entry, RootPageTable, RootPageTableType, VaMapping};
+use port::initrd::Initrd;
+use port::lockdebug::CoreHeld;
+use port::log::Level;
+use port::mem::{MapFlags, PAGE_SIZE_4K, PhysRange, PhysRangeSet, VirtAddr};
+use port::{debug, error, info, print, println, warn};
+use registers::rpi_mmio;
+use vm::{AddressSpace, RootPageTable, RootPageTableType, VaMapping};
 
 #[cfg(not(test))]
-core::arch::global_asm!(include_str!("l.S"));
+core::arch::global_asm!(
+    include_str!("l.S"),
+    TCR_EL1 = const mmu::BOOT_TCR_EL1.0,
+    MAIR_EL1 = const mmu::BOOT_MAIR_EL1.0,
+    SCTLR_EL1 = const mmu::BOOT_SCTLR_EL1.0,
+    MAIR_NORMAL = const vm::Mair::Normal as u8,
+    MAIR_DEVICE = const vm::Mair::Device as u8,
+);
 
 static mut KERNEL_PAGETABLE: RootPageTable = RootPageTable::empty();
 static mut USER_PAGETABLE: RootPageTable = RootPageTable::empty();
 
+// The locks each core holds, for port::lockdebug
+static HELD_LOCKS: [CoreHeld; MAX_CORES] = [const { CoreHeld::new() }; MAX_CORES];
+
+// Time spent in each phase of boot, in nanoseconds
+port::counter!(static BOOT_EARLY_NS = "boot.early_ns");
+port::counter!(static BOOT_PAGE_TABLES_NS = "boot.page_tables_ns");
+port::counter!(static BOOT_PAGE_ALLOC_NS = "boot.page_alloc_ns");
+port::counter!(static BOOT_DIRECT_MAP_NS = "boot.direct_map_ns");
+port::counter!(static BOOT_DEVICES_NS = "boot.devices_ns");
+port::counter!(static BOOT_SMP_NS = "boot.smp_ns");
+
 unsafe fn print_memory_range(name: &str, range: &PhysRange) {
     let size = range.size();
-    println!("  {name}{range} ({size:#x})");
+    debug!("  {name}{range} ({size:#x})");
 }
 
 fn print_binary_sections() {
-    println!("Binary sections:");
+    debug!("Binary sections:");
     unsafe {
         print_memory_range("boottext:\t", &boottext_range());
         print_memory_range("text:\t\t", &text_range());
@@ -57,45 +105,230 @@ fn print_binary_sections() {
     }
 }
 
+/// Return all the physical memory ranges described by the device tree.
+fn memory_ranges(dt: &DeviceTree) -> PhysRangeSet {
+    let mut memory = PhysRangeSet::new();
+    for range in dt
+        .find_device_type("memory")
+        .flat_map(|memory| dt.property_translated_reg_iter(memory).flat_map(|r| r.regblock()))
+        .map(|memory| PhysRange::from(&memory))
+    {
+        if memory.add(&range).is_err() {
+            error!("too many memory ranges, ignoring {range}");
+        }
+    }
+    memory
+}
+
+/// Check the memory from the device tree against the split between the ARM
+/// cores and the VideoCore reported by the firmware, and remove any memory
+/// owned by the VideoCore, which the page allocator must never hand out.
+fn exclude_vc_memory(memory: &mut PhysRangeSet) {
+    let arm = mailbox::get_arm_memory();
+    let vc = mailbox::get_vc_memory();
+    debug!("Memory split: ARM: {arm} VideoCore: {vc}");
+    if !memory.iter().any(|range| range.overlaps(&vc)) {
+        return;
+    }
+    warn!("device tree memory includes VideoCore memory {vc}, excluding it");
+    if memory.remove(&vc).is_err() {
+        panic!("error:too many memory ranges, can't exclude {vc}");
+    }
+}
+
+/// Apply the memory boot arguments: limit memory to the lowest mem= bytes,
+/// and remove each reserve= range, which `reserved_ranges` also reserves.
+fn apply_memory_bootargs(memory: &mut PhysRangeSet, bootargs: &BootArgs) {
+    if let Some(limit) = bootargs.mem_limit {
+        if limit < memory.size() {
+            warn!("mem={limit:#x}: limiting memory from {:#x} bytes", memory.size());
+            memory.truncate(limit);
+        }
+    }
+    for range in bootargs.reserved.iter() {
+        if memory.remove(range).is_err() {
+            error!("too many memory ranges, can't remove reserve={range} from memory");
+        }
+    }
+}
+
+/// Return the ranges of RAM to exclude from the direct map: those the device
+/// tree lists under /reserved-memory as no-map, those param::DMAP_EXCLUDED
+/// lists for the board, and any reserve= boot arguments.
+fn dmap_exclusions(dt: &DeviceTree, bootargs: &BootArgs) -> dmap::Exclusions {
+    let mut exclusions = dmap::Exclusions::new();
+    let mut exclude = |range: &PhysRange, reason, uncached| {
+        if exclusions.add(range, reason, uncached).is_err() {
+            panic!("error:too many direct map exclusions, can't exclude {range}");
+        }
+    };
+
+    if let Some(resmem) = dt.find_by_path("/reserved-memory") {
+        for node in dt.children(&resmem).filter(|node| dt.property(node, "no-map").is_some()) {
+            for regblock in dt.property_translated_reg_iter(node).flat_map(|r| r.regblock()) {
+                exclude(&PhysRange::from(&regblock), ExclusionReason::NoMap, false);
+            }
+        }
+    }
+    if let Some(root) = dt.root() {
+        for board in param::DMAP_EXCLUDED.iter().filter(|b| dt.is_compatible(&root, b.compatible)) {
+            let range = PhysRange::with_len(board.start, board.len as usize);
+            exclude(&range, ExclusionReason::Board, board.uncached);
+        }
+    }
+    for range in bootargs.reserved.iter() {
+        exclude(range, ExclusionReason::BootArg, false);
+    }
+    exclusions
+}
+
+/// Return the ranges of physical memory that mustn't be handed out by the page
+/// allocator: the kernel image, early page tables, the DTB, the initrd,
+/// anything the device tree lists under /reserved-memory, any reserve=
+/// boot arguments, and anything excluded from the direct map.  All but the
+/// kernel image and early page tables, which kmem records, and the
+/// exclusions, which dmap records, are recorded in the physical memory map.
+fn reserved_ranges(
+    dt: &DeviceTree,
+    dtb_range: &PhysRange,
+    bootargs: &BootArgs,
+    exclusions: &dmap::Exclusions,
+) -> PhysRangeSet {
+    let mut reserved = PhysRangeSet::new();
+    let mut reserve = |range: PhysRange| {
+        if reserved.add(&range).is_err() {
+            panic!("error:too many reserved ranges, can't reserve {range}");
+        }
+    };
+
+    reserve(total_kernel_range());
+    reserve(early_pages_range());
+    reserve(dtb_range.clone());
+    kmem::record_phys_range("dtb", dtb_range.clone(), None);
+    if let Some(initrd) = dt.initrd_range() {
+        reserve(initrd.clone());
+        kmem::record_phys_range("initrd", initrd, None);
+    }
+
+    // Note that we can't use the heap yet, so no collecting of nodes
+    if let Some(resmem) = dt.find_by_path("/reserved-memory") {
+        for node in dt.children(&resmem) {
+            for regblock in dt.property_translated_reg_iter(node).flat_map(|r| r.regblock()) {
+                reserve(PhysRange::from(&regblock));
+                kmem::record_phys_range("reserved-memory", PhysRange::from(&regblock), None);
+            }
+        }
+    }
+    for range in bootargs.reserved.iter() {
+        reserve(range.clone());
+        kmem::record_phys_range("reserve=", range.clone(), None);
+    }
+    for exclusion in exclusions.iter() {
+        reserve(exclusion.range.clone());
+    }
+    reserved
+}
+
+/// Make the initrd at `range`, which `reserved_ranges` kept from the page
+/// allocator, read-only in the direct map, and set it as port's initrd, so
+/// it's kept until the files are served.  Its entries are logged.
+fn init_initrd(kernel_space: &mut AddressSpace, range: PhysRange) {
+    let pages = range.round_out(PAGE_SIZE_4K as u64);
+    let va_pages = match dmap::dmap_range(&pages) {
+        Ok(va_pages) => va_pages,
+        Err(err) => {
+            println!("error:main:init_initrd:initrd isn't in RAM:{range} err:{err}");
+            return;
+        }
+    };
+    if let Err(err) = kernel_space.protect(&va_pages, MapFlags::READ) {
+        println!("error:main:init_initrd:can't protect initrd:{range} err:{err:?}");
+        return;
+    }
+    let offset = (range.start().addr() - pages.start().addr()) as usize;
+    let start = (va_pages.start().addr() + offset) as *const u8;
+    // Safety: the pages are reserved, so nothing else uses them, and they're
+    // mapped read-only in the direct map for as long as the kernel runs.
+    let bytes = unsafe { core::slice::from_raw_parts(start, range.size()) };
+    match Initrd::new(range.clone(), bytes) {
+        Ok(initrd) => {
+            print!("{}", initrd.listing());
+            port::initrd::init(initrd);
+        }
+        Err(err) => println!("error:main:init_initrd:invalid initrd:{range} err:{err}"),
+    }
+}
+
 fn print_memory_info() {
-    println!("Memory usage:");
+    debug!("Memory usage:");
     let (used, total) = pagealloc::usage_bytes();
-    println!("  Used:\t\t{used:#016x}");
-    println!("  Total:\t{total:#016x}");
-}
-
-// https://github.com/raspberrypi/documentation/blob/develop/documentation/asciidoc/computers/raspberry-pi/revision-codes.adoc
-fn print_pi_name(board_revision: u32) {
-    let name = match board_revision {
-        0xa21041 => "Raspberry Pi 2B",
-        0xa02082 => "Raspberry Pi 3B",
-        0xb03115 => "Raspberry Pi 4B",
-        0xa220a0 => "Raspberry Compute Module 3",
-        _ => "Unrecognised",
-    };
-    println!("  Board Name:\t{name}");
+    debug!("  Used:\t\t{used:#016x}");
+    debug!("  Total:\t{total:#016x}");
+    debug!("  {}", pagealloc::stats());
+    pagealloc::for_each_region(|range, stats| debug!("  Region {range}: {stats}"));
+    if let Some(stats) = allocator::try_stats() {
+        debug!("  Heap: {stats}");
+    }
+}
+
+/// Allocate more than the static heap holds, so the heap has to grow with
+/// pages from the page allocator, and check the memory can be used.
+fn test_heap_growth() {
+    let blocks: Vec<Vec<u8>> = (0..6).map(|i| vec![i; 1024 * 1024]).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(block.iter().all(|&b| b == i as u8), "heap block {i} corrupted");
+    }
+    drop(blocks);
+    if let Some(stats) = allocator::try_stats() {
+        debug!("Heap: {stats}");
+    }
+}
+
+/// Print the kernel and user page tables, if debug logging is enabled.
+fn debug_print_tables() {
+    if port::log::enabled(Level::Debug) {
+        vmdebug::print_recursive_tables(RootPageTableType::Kernel);
+        vmdebug::print_recursive_tables(RootPageTableType::User);
+    }
 }
 
 fn print_board_info() {
-    println!("Board information:");
-    let board_revision = mailbox::get_board_revision();
-    print_pi_name(board_revision);
-    println!("  Board Rev:\t{board_revision:#010x}");
-    let model = mailbox::get_board_model();
-    println!("  Board Model:\t{model:#010x}");
-    let serial = mailbox::get_board_serial();
-    println!("  Serial Num:\t{serial:#010x}");
-    let mailbox::MacAddress { a, b, c, d, e, f } = mailbox::get_board_macaddr();
-    println!("  MAC Address:\t{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}");
-    let fw_revision = mailbox::get_firmware_revision();
-    println!("  Firmware Rev:\t{fw_revision:#010x}");
-}
-
-/// dtb_va is the virtual address of the DTB structure.  The physical address is
-/// assumed to be dtb_va-KZERO.
+    let info = match mailbox::get_board_info() {
+        Ok(info) => info,
+        Err(err) => {
+            warn!("couldn't get board information: {err:?}");
+            return;
+        }
+    };
+    let rev = info.revision;
+    info!(
+        "Board: {} rev 1.{} ({:#08x}) {} {}MiB",
+        rev.model_name().unwrap_or("Unrecognised"),
+        rev.board_revision().unwrap_or(0),
+        rev.0,
+        rev.processor().unwrap_or("unknown SoC"),
+        rev.memory_size().unwrap_or(0) >> 20
+    );
+    debug!("  Board Model:\t{:#010x}", info.model);
+    debug!("  Serial Num:\t{:#018x}", info.serial);
+    let mailbox::MacAddress { a, b, c, d, e, f } = info.mac_address;
+    debug!("  MAC Address:\t{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}");
+    debug!("  Firmware Rev:\t{:#010x}", info.firmware_revision);
+}
+
+/// dtb_va is the virtual address of the DTB structure, in the KZERO mapping,
+/// so its physical address is from_virt_to_physaddr(dtb_va).  load_offset is
+/// the offset from where the kernel was linked to be loaded to where it was.
 #[unsafe(no_mangle)]
-pub extern "C" fn main9(dtb_va: usize) {
+pub extern "C" fn main9(dtb_va: usize, load_offset: usize) {
+    kmem::set_load_offset(load_offset);
+    port::percpu::set_core_id_fn(registers::core_id);
+    port::lockdebug::set_held_stacks(&HELD_LOCKS);
+    port::time::set_clock(registers::counter, registers::counter_freq());
+    let mut boot_phase = Stopwatch::start();
+    devcons::init_early();
     trap::init();
+    cache::init();
 
     // Parse the DTB before we set up memory so we can correctly map it
     let dt = unsafe { DeviceTree::from_usize(dtb_va).unwrap() };
@@ -103,44 +336,188 @@ pub extern "C" fn main9(dtb_va: usize) {
     // Set up uart so we can log as early as possible
     mailbox::init(&dt);
     devcons::init(&dt);
+    port::log::set_max_level(param::LOG_LEVEL);
+    let bootargs = BootArgs::parse(dt.bootargs().unwrap_or(""));
+    if let Some(level) = bootargs.log_level {
+        port::log::set_max_level(level);
+    }
 
     println!();
     println!("r9 from the Internet");
-    println!("DTB found at: {:#x}", dtb_va);
+    debug!("DTB found at: {}", VirtAddr::new(dtb_va));
     println!("midr_el1: {:?}", registers::MidrEl1::read());
+    mmu::check_config();
+    info!("Kernel loaded at {}, linked for {}", kmem::kernel_phys_base(), kmem::linked_phys_base());
+    if bootargs.footprint {
+        let (layout, load_base) = (kmem::ImageLayout::linked(), kmem::kernel_phys_base());
+        print!("{}", kmem::FootprintReport { layout: &layout, load_base });
+    }
+    psci::init(&dt);
 
     print_binary_sections();
+    if let Err(err) = kmem::init_layout() {
+        panic!("error:main9:invalid kernel address space layout: {err}");
+    }
+    kmem::record_kernel_addr_ranges();
+    kmem::record_kernel_phys_ranges();
+    kmem::record_identity_window("boot", kmem::boot_identity_window());
     print_board_info();
 
     pagealloc::init_page_allocator();
 
+    let mut memory = memory_ranges(&dt);
+    exclude_vc_memory(&mut memory);
+    apply_memory_bootargs(&mut memory, &bootargs);
+    info!("Physical Memory:");
+    for range in memory.iter() {
+        info!("  {range}");
+        kmem::record_phys_range("memory", range.clone(), None);
+    }
+
+    BOOT_EARLY_NS.add_lap(&mut boot_phase);
+
     // Map address space accurately using rust VM code to manage page tables
+    let dtb_range =
+        PhysRange::with_len(from_virt_to_physaddr(VirtAddr::new(dtb_va)).addr(), dt.size());
+    let mut kernel_space = unsafe {
+        AddressSpace::from_static(
+            &mut *ptr::addr_of_mut!(KERNEL_PAGETABLE),
+            RootPageTableType::Kernel,
+        )
+    };
+    vm::init_kernel_page_tables(&mut kernel_space, dtb_range.clone());
+    unsafe { kernel_space.activate() };
+
+    // Nothing is mapped in the user half until a user address space is set up
     unsafe {
-        let dtb_range = PhysRange::with_len(from_virt_to_physaddr(VirtAddr::new(dtb_va)).addr(), dt.size());
-        vm::init_kernel_page_tables(&dt, &mut *ptr::addr_of_mut!(KERNEL_PAGETABLE), dtb_range);
-        vm::switch(&*ptr::addr_of!(KERNEL_PAGETABLE), RootPageTableType::Kernel);
+        AddressSpace::from_static(&mut *ptr::addr_of_mut!(USER_PAGETABLE), RootPageTableType::User)
+            .activate()
+    };
+
+    // Carry on with the boot core on its kernel stack in the stacks region,
+    // where the secondary cores start on theirs
+    kmem::continue_on_kernel_stack(move || {
+        main9_continued(dt, bootargs, memory, dtb_range, kernel_space, boot_phase)
+    })
+}
+
+/// The rest of main9, once the kernel page tables are active and the boot
+/// core is on its kernel stack in the stacks region.
+fn main9_continued(
+    dt: DeviceTree,
+    bootargs: BootArgs,
+    memory: PhysRangeSet,
+    dtb_range: PhysRange,
+    mut kernel_space: AddressSpace,
+    mut boot_phase: Stopwatch,
+) -> ! {
+    // Check the kernel image is mapped, at least as permissively as intended
+    for section in kernel_sections() {
+        vm::assert_mapped(&physrange_as_virtrange_offset_from_kzero(&section.range), section.flags);
+    }
+    if port::log::enabled(Level::Debug) {
+        vmdebug::print_mappings(RootPageTableType::Kernel);
+    }
+
+    BOOT_PAGE_TABLES_NS.add_lap(&mut boot_phase);
 
-        vm::init_user_page_tables(&mut *ptr::addr_of_mut!(USER_PAGETABLE));
-        vm::switch(&*ptr::addr_of!(USER_PAGETABLE), RootPageTableType::User);
+    // Map all of RAM into the direct map, with tables from the early pages,
+    // so the page allocator can keep its state anywhere in RAM
+    let exclusions = dmap_exclusions(&dt, &bootargs);
+    match dmap::init(&mut kernel_space, &memory, &exclusions) {
+        Ok(stats) => debug!("Direct map entries: {stats}"),
+        Err(err) => panic!("error:Couldn't set up direct map: err: {:?}", err),
+    }
+    BOOT_DIRECT_MAP_NS.add_lap(&mut boot_phase);
+
+    let reserved = reserved_ranges(&dt, &dtb_range, &bootargs, &exclusions);
+    match pagealloc::init_from(&memory, reserved.as_slice()) {
+        Ok(summary) => info!(
+            "Page allocator: total pages: {} reserved pages: {} free pages: {}",
+            summary.total_pages, summary.reserved_pages, summary.free_pages
+        ),
+        Err(err) => panic!("error:Couldn't initialise page allocator: err: {:?}", err),
     }
 
     // From this point we can use the global allocator
 
+    // Now tables can be allocated, tighten the kernel image permissions,
+    // splitting blocks where needed, and check they're as intended.  The
+    // trampoline is needed to replace the mappings of the kernel text.
+    if let Err(err) = vm::init_trampoline(&mut kernel_space) {
+        panic!("error:Couldn't map break-before-make trampoline: err: {:?}", err);
+    }
+    if let Err(err) = vm::protect_kernel_sections(&mut kernel_space) {
+        panic!("error:Couldn't protect kernel sections: err: {:?}", err);
+    }
+    vm::verify_kernel_mapping();
+    BOOT_PAGE_ALLOC_NS.add_lap(&mut boot_phase);
+
+    // Switch to reading the DTB through the direct map, so the DTB no longer
+    // needs its own mapping
+    pagealloc::direct_map_ready();
+    if let Some(mode) = bootargs.memtest {
+        memtest::run(mode);
+    }
+    allocator::init();
+    let dtb_dmap = dmap::phys_to_dmap(dtb_range.start())
+        .unwrap_or_else(|err| panic!("DTB at {} isn't in RAM: {err}", dtb_range.start()));
+    let dt = unsafe { DeviceTree::from_usize(dtb_dmap.addr()).unwrap() };
+    let dtb_pages = dtb_range.round_out(PAGE_SIZE_4K as u64);
+    if let Err(err) = kernel_space.unmap(&physrange_as_virtrange_offset_from_kzero(&dtb_pages)) {
+        panic!("error:Couldn't unmap DTB: err: {:?}", err);
+    }
+    kmem::set_kzero_unmapped(&dtb_pages);
+    if let Some(initrd) = dt.initrd_range() {
+        init_initrd(&mut kernel_space, initrd);
+    }
+    BOOT_DIRECT_MAP_NS.add_lap(&mut boot_phase);
+
+    vmap::init();
+
+    // Move the drivers to registers mapped with vmap, so the early MMIO
+    // mapping is no longer needed
+    if let Err(err) = devcons::init_vmap(&dt).and_then(|_| mailbox::init_vmap(&dt)) {
+        panic!("error:Couldn't vmap device registers: err: {:?}", err);
+    }
+    let early_mmio = kmem::early_mmio_virtrange(&rpi_mmio().unwrap());
+    if let Err(err) = kernel_space.unmap(&early_mmio) {
+        panic!("error:Couldn't unmap early MMIO: err: {:?}", err);
+    }
+
+    if let Err(err) = gic::init(&dt) {
+        panic!("error:Couldn't vmap GIC registers: err: {:?}", err);
+    }
+    timer::init(&dt);
+    BOOT_DEVICES_NS.add_lap(&mut boot_phase);
+
+    smp::start_secondaries(&dt);
+    vm::teardown_boot_identity_window();
+    BOOT_SMP_NS.add_lap(&mut boot_phase);
+
+    // Mapping and remapping the image is done, so check it's intact
+    let failed = kmem::check_image_digests();
+    if failed > 0 && cfg!(feature = "strict_digests") {
+        panic!("error:{failed} kernel image sections don't match their digests");
+    }
+    if port::log::enabled(Level::Debug) {
+        kernel_space.dump();
+    }
+
     print_memory_info();
+    kmem::report_memory_map();
+    kmem::report_memory_usage();
+    counters::report::<MAX_CORES>();
 
-    vmdebug::print_recursive_tables(RootPageTableType::Kernel);
-    vmdebug::print_recursive_tables(RootPageTableType::User);
+    debug_print_tables();
 
     {
-        let page_table = unsafe { &mut *ptr::addr_of_mut!(KERNEL_PAGETABLE) };
-        let entry = Entry::rw_kernel_data();
         for i in 0..3 {
             let alloc_result = pagealloc::allocate_virtpage(
-                page_table,
+                &mut kernel_space,
                 "testkernel",
-                entry,
-                VaMapping::Offset(KZERO),
-                RootPageTableType::Kernel,
+                MapFlags::RW,
+                VaMapping::Offset(kmem::kzero_offset()),
             );
             match alloc_result {
                 Ok(_allocated_page) => {}
@@ -152,19 +529,31 @@ pub extern "C" fn main9(dtb_va: usize) {
         }
     }
 
-    vmdebug::print_recursive_tables(RootPageTableType::Kernel);
-    vmdebug::print_recursive_tables(RootPageTableType::User);
+    debug_print_tables();
 
     println!("Set up a user process");
 
     test_sysexit();
 
-    vmdebug::print_recursive_tables(RootPageTableType::Kernel);
-    vmdebug::print_recursive_tables(RootPageTableType::User);
+    debug_print_tables();
+
+    test_heap_growth();
 
-    let _b = Box::new("ddododo");
+    #[cfg(feature = "debug_prompt")]
+    debug_prompt();
+
+    #[cfg(all(feature = "ktest", not(test)))]
+    port::ktest::run_checked(port::ktest::registered(), ktests::leak_check());
+
+    // Booting this far is the test, as failures above panic
+    if cfg!(feature = "qemu_test") {
+        // List what's still allocated, to spot leaks in debug builds
+        allocator::dump_leaks();
+        port::qemu::exit_qemu(true);
+    }
 
     println!("looping now");
+    port::devcons::flush();
 
     #[allow(clippy::empty_loop)]
     loop {}
@@ -172,17 +561,72 @@ pub extern "C" fn main9(dtb_va: usize) {
 
 mod runtime;
 
+/// Run a minimal interactive prompt on the console, until `continue`.
+#[cfg(feature = "debug_prompt")]
+fn debug_prompt() {
+    let mut buf = [0u8; 64];
+    loop {
+        port::print!("r9> ");
+        match port::devcons::read_line(&mut buf).trim() {
+            "" => {}
+            "help" => {
+                println!("commands: help mem maps addrs peek <addr> counters rxerrors fb continue")
+            }
+            "mem" => print_memory_info(),
+            "maps" => vmdebug::print_mappings(RootPageTableType::Kernel),
+            "addrs" => {
+                for range in kmem::KERNEL_ADDR_MAP.iter() {
+                    println!("  {:<12} {:?} {}", range.name, range.kind, range.virt);
+                }
+            }
+            cmd if cmd.starts_with("peek ") => peek(cmd["peek ".len()..].trim()),
+            "counters" => counters::report::<MAX_CORES>(),
+            "rxerrors" => println!("uart receive errors: {}", port::devcons::rx_errors()),
+            "fb" => match framebuffer::init(640, 480) {
+                Ok(mut fb) => {
+                    println!("framebuffer: {}x{} pitch {}", fb.width, fb.height, fb.pitch);
+                    fb.fill_rect(0, 0, fb.width, fb.height, 0x0000_0040);
+                    fb.draw_str(16, 16, "r9 from the Internet", 0x00ff_ffff, 0x0000_0040);
+                }
+                Err(err) => println!("error:couldn't set up framebuffer: {err:?}"),
+            },
+            "continue" => break,
+            cmd => println!("unknown command: {cmd}"),
+        }
+        port::devcons::flush();
+    }
+}
+
+/// Dump the 64 bytes at `addr`, or where reading them faulted.
+#[cfg(feature = "debug_prompt")]
+fn peek(addr: &str) {
+    let Some(addr) = port::mem::parse_size(addr) else {
+        println!("usage: peek <addr>");
+        return;
+    };
+    let addr = VirtAddr::new(addr as usize);
+    let mut bytes = [0u8; 64];
+    match safecopy::copy_from(&mut bytes, addr) {
+        Ok(()) => port::print!("{}", port::debug::HexDump::new(&bytes, addr.addr(), false)),
+        Err(fault) => println!("fault at {:?}", fault.addr),
+    }
+}
+
 fn test_sysexit() {
-    let page_table = unsafe { &mut *ptr::addr_of_mut!(USER_PAGETABLE) };
+    // Give the process an address space of its own
+    let mut user_space = match AddressSpace::new() {
+        Ok(user_space) => user_space,
+        Err(err) => panic!("error:Couldn't create user address space: err: {:?}", err),
+    };
+    unsafe { user_space.activate() };
 
     // Allocate pages for a user process
     let user_text = {
         let user_text = pagealloc::allocate_virtpage(
-            page_table,
+            &mut user_space,
             "usertext",
-            Entry::rw_user_text(),
+            MapFlags::RW | MapFlags::EXECUTE | MapFlags::USER,
             VaMapping::Addr(VirtAddr::new(0x1000)),
-            RootPageTableType::User,
         )
         .expect("couldn't allocate user_text");
 
@@ -198,11 +642,10 @@ fn test_sysexit() {
     let user_text_va = user_text as *const _ as u64;
 
     let user_stack = pagealloc::allocate_virtpage(
-        page_table,
+        &mut user_space,
         "userstack",
-        Entry::rw_user_data(),
-            VaMapping::Addr(VirtAddr::new(KZERO - 0x1000)),
-        RootPageTableType::User,
+        MapFlags::RW | MapFlags::USER,
+        VaMapping::Addr(VirtAddr::new(KZERO - 0x1000)),
     )
     .expect("couldn't allocate user_stack");
 
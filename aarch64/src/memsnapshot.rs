@@ -0,0 +1,24 @@
+/// memsnapshot takes snapshots of the state of memory, as port::memsnapshot
+/// records it, with room for all the page allocator's regions and vmap's
+/// mappings.  Comparing snapshots from before and after something that
+/// should give back all it takes shows what it leaked.  The ktests are
+/// checked this way.
+use crate::param::{MAX_VMAPS, PAGEALLOC_REGIONS};
+use crate::{allocator, pagealloc, vmap};
+use port::memsnapshot::VmapEntry;
+
+pub type MemSnapshot = port::memsnapshot::MemSnapshot<PAGEALLOC_REGIONS, MAX_VMAPS>;
+
+/// Take a snapshot of the page allocator, the memory accounts, the heap and
+/// vmap, in place of `snapshot`.  It doesn't allocate, so it doesn't change
+/// what it's recording.
+#[allow(dead_code)]
+pub fn take(snapshot: &mut MemSnapshot) {
+    snapshot.clear();
+    pagealloc::for_each_region(|range, stats| snapshot.add_region(range, stats));
+    snapshot.add_categories(&pagealloc::MEM_ACCOUNTS);
+    snapshot.set_heap(allocator::try_stats());
+    vmap::for_each_map(|pages, va, flags, refs| {
+        snapshot.add_vmap(VmapEntry { pages: pages.clone(), va: va.clone(), flags, refs })
+    });
+}
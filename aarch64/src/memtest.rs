@@ -0,0 +1,104 @@
+/// memtest runs port::memtest over the RAM the page allocator has free, when
+/// the memtest= boot argument asks for it, to check a new board's memory.  It
+/// runs as soon as the direct map is up, when little but the kernel's page
+/// tables has been allocated.  The free memory is reserved while it's tested,
+/// then released, except for the pages that fail, which stay reserved,
+/// counted as quarantined, so they're never handed out.  If there are too
+/// many failures to keep track of, the whole of the free range holding the
+/// failure stays reserved instead.
+use crate::{dmap, kmem, pagealloc};
+use port::mem::{PAGE_SIZE_2M, PAGE_SIZE_4K, PhysRange, PhysRangeSet};
+use port::memaccount::MemCategory;
+use port::memtest::{MemTestFailure, MemTestMode, test_ranges};
+use port::physmap::Size;
+use port::{info, println, warn};
+
+/// Maximum number of free ranges tested.
+const MAX_TESTED_RANGES: usize = 32;
+
+/// Maximum number of separate ranges quarantined.
+const MAX_QUARANTINED: usize = 16;
+
+/// Maximum number of failures printed.  Any more are only counted.
+const MAX_REPORTED: usize = 16;
+
+/// Return the unit to quarantine for a failure at `failure`, within `range`:
+/// its page, or if `quarantine` is full, the 2MiB around it, in the hope it
+/// merges with a range already quarantined.
+fn quarantine_unit(
+    quarantine: &PhysRangeSet<MAX_QUARANTINED>,
+    range: &PhysRange,
+    failure: &MemTestFailure,
+) -> PhysRange {
+    let page = PhysRange::with_pa_len(failure.pa.round_down(PAGE_SIZE_4K as u64), PAGE_SIZE_4K);
+    if quarantine.len() < MAX_QUARANTINED {
+        return page;
+    }
+    let block = page.round_out(PAGE_SIZE_2M as u64);
+    PhysRange::new(block.start().max(range.start()), block.end().min(range.end()))
+}
+
+/// Test the free memory in `mode`, then quarantine the pages that fail.
+pub fn run(mode: MemTestMode) {
+    let free = pagealloc::reserve_free_ranges::<MAX_TESTED_RANGES>(MemCategory::Unknown);
+    info!("memtest: testing {} of free memory ({mode:?})", Size(free.size()));
+
+    let mut quarantine = PhysRangeSet::<MAX_QUARANTINED>::new();
+    let mut withheld = [false; MAX_TESTED_RANGES];
+    let mut failures = 0;
+    let tested = test_ranges(
+        free.as_slice(),
+        mode,
+        |chunk| dmap::dmap_range(chunk).ok().map(|range| range.start().addr() as *mut u64),
+        |failure| {
+            failures += 1;
+            if failures <= MAX_REPORTED {
+                println!(
+                    "memtest: failure at {}: expected {:#018x} read {:#018x}",
+                    failure.pa, failure.expected, failure.actual
+                );
+            }
+            let index = free.iter().position(|r| r.start() <= failure.pa && failure.pa < r.end());
+            let (index, range) = index.map(|i| (i, &free.as_slice()[i])).unwrap();
+            if withheld[index] {
+                return;
+            }
+            let unit = quarantine_unit(&quarantine, range, &failure);
+            if quarantine.add(&unit).is_err() {
+                println!("error:memtest:run:too many ranges to quarantine {unit}, keeping {range}");
+                withheld[index] = true;
+            }
+        },
+    );
+
+    // A free range with a failure that couldn't be quarantined stays
+    // reserved as a whole, so the failing page is never handed out.  The
+    // quarantined ranges are all within the free ranges, so removing a whole
+    // free range from them never splits one.
+    for (range, withheld) in free.iter().zip(withheld) {
+        let _ = pagealloc::release_physpages_for(range, MemCategory::Unknown);
+        if withheld {
+            let _ = quarantine.remove(range);
+        }
+    }
+    let withheld_ranges = free.iter().zip(withheld).filter_map(|(range, w)| w.then_some(range));
+    let mut quarantined = 0;
+    for range in quarantine.iter().chain(withheld_ranges) {
+        if let Err(err) = pagealloc::reserve_physpages_for(range, MemCategory::Quarantined) {
+            println!("error:memtest:run:can't quarantine {range}: {err:?}");
+            continue;
+        }
+        kmem::record_phys_range("quarantined", range.clone(), None);
+        quarantined += range.size();
+    }
+
+    if failures == 0 {
+        info!("memtest: {} tested, no failures", Size(tested));
+    } else {
+        warn!(
+            "memtest: {} tested, {failures} failures, {} quarantined",
+            Size(tested),
+            Size(quarantined)
+        );
+    }
+}
@@ -0,0 +1,344 @@
+/// mmu holds the translation regime the kernel runs with: the values of
+/// TCR_EL1, MAIR_EL1 and SCTLR_EL1 that l.S writes before enabling the MMU,
+/// on the boot core and on each secondary.  They're built here from typed
+/// fields and handed to l.S as operands of its global_asm!, so there's only
+/// one definition.  `mmu_config` reads back the live registers, which can be
+/// printed decoded, and `check_config` warns if they aren't what was
+/// intended, which has meant the assembly and rust have drifted apart.
+use crate::vm::Mair;
+use bitstruct::bitstruct;
+use core::fmt;
+use port::mem::PhysAddr;
+use port::{debug, warn};
+
+bitstruct! {
+    /// Translation Control Register.  See D19.2.139 of the Arm Architecture
+    /// Reference Manual.
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct TcrEl1(pub u64) {
+        pub t0sz: u8 = 0..6;
+        pub irgn0: u8 = 8..10;
+        pub orgn0: u8 = 10..12;
+        pub sh0: u8 = 12..14;
+        pub tg0: u8 = 14..16;
+        pub t1sz: u8 = 16..22;
+        pub irgn1: u8 = 24..26;
+        pub orgn1: u8 = 26..28;
+        pub sh1: u8 = 28..30;
+        pub tg1: u8 = 30..32;
+        pub ips: u8 = 32..35;
+    }
+}
+
+bitstruct! {
+    /// System Control Register, just the fields the kernel sets.
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct SctlrEl1(pub u64) {
+        pub mmu: bool = 0;
+        pub dcache: bool = 2;
+        pub icache: bool = 12;
+    }
+}
+
+bitstruct! {
+    /// Translation Table Base Register, for either half of the address space.
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct TtbrEl1(pub u64) {
+        pub baddr: u64 = 1..48;
+        pub asid: u16 = 48..64;
+    }
+}
+
+impl TtbrEl1 {
+    /// Return the physical address of the root table.
+    pub fn table(&self) -> PhysAddr {
+        PhysAddr::new(self.baddr() << 1)
+    }
+}
+
+/// Memory Attribute Indirection Register: an attribute byte for each of the
+/// 8 indices page table entries can refer to.
+#[derive(Copy, Clone, PartialEq)]
+pub struct MairEl1(pub u64);
+
+impl MairEl1 {
+    pub const fn attr(&self, index: usize) -> u8 {
+        (self.0 >> (index * 8)) as u8
+    }
+
+    #[must_use]
+    pub const fn with_attr(self, index: Mair, attr: u8) -> Self {
+        let shift = index as u64 * 8;
+        Self(self.0 & !(0xff << shift) | (attr as u64) << shift)
+    }
+}
+
+/// Virtual address bits in each half of the address space, so 4 levels of
+/// 4KiB tables, as vm.rs expects.
+pub const VA_BITS: u8 = 48;
+
+// TCR_EL1 field values
+const TG0_4K: u8 = 0;
+const TG1_4K: u8 = 2;
+const WRITE_BACK_WRITE_ALLOCATE: u8 = 1;
+const INNER_SHAREABLE: u8 = 3;
+const IPS_44_BITS: u8 = 4;
+
+// MAIR_EL1 attributes
+const ATTR_NORMAL: u8 = 0xff; // Normal, inner and outer write-back cacheable
+const ATTR_DEVICE_NGNRNE: u8 = 0x00; // No gathering, reordering or early write ack
+const ATTR_NORMAL_NC: u8 = 0x44; // Normal, inner and outer non-cacheable
+const ATTR_DEVICE_NGNRE: u8 = 0x04; // Device, with early write ack
+
+/// Both halves of the address space use 4KiB granules, and tables that are
+/// cached write-back and inner shareable.
+pub const BOOT_TCR_EL1: TcrEl1 = TcrEl1(0)
+    .with_t0sz(64 - VA_BITS)
+    .with_irgn0(WRITE_BACK_WRITE_ALLOCATE)
+    .with_orgn0(WRITE_BACK_WRITE_ALLOCATE)
+    .with_sh0(INNER_SHAREABLE)
+    .with_tg0(TG0_4K)
+    .with_t1sz(64 - VA_BITS)
+    .with_irgn1(WRITE_BACK_WRITE_ALLOCATE)
+    .with_orgn1(WRITE_BACK_WRITE_ALLOCATE)
+    .with_sh1(INNER_SHAREABLE)
+    .with_tg1(TG1_4K)
+    .with_ips(IPS_44_BITS);
+
+/// The attribute for each index in `Mair`.
+pub const BOOT_MAIR_EL1: MairEl1 = MairEl1(0)
+    .with_attr(Mair::Normal, ATTR_NORMAL)
+    .with_attr(Mair::Device, ATTR_DEVICE_NGNRNE)
+    .with_attr(Mair::NonCacheable, ATTR_NORMAL_NC)
+    .with_attr(Mair::DeviceRelaxed, ATTR_DEVICE_NGNRE);
+
+/// The MMU and both caches are enabled.
+pub const BOOT_SCTLR_EL1: SctlrEl1 = SctlrEl1(0).with_mmu(true).with_dcache(true).with_icache(true);
+
+/// The bits of SCTLR_EL1 the kernel sets.  Only these are compared, as some
+/// of the others read as one whatever's written.
+const SCTLR_EL1_MASK: u64 = BOOT_SCTLR_EL1.0;
+
+/// The translation regime, as set, or as intended.
+#[derive(Copy, Clone)]
+pub struct MmuConfig {
+    pub tcr: TcrEl1,
+    pub mair: MairEl1,
+    pub sctlr: SctlrEl1,
+    pub ttbr0: TtbrEl1,
+    pub ttbr1: TtbrEl1,
+}
+
+impl MmuConfig {
+    /// Return the registers that differ from `intended`, with their
+    /// intended and live values.  The table bases depend on the tables in
+    /// use, so aren't compared.
+    pub fn divergences(&self, intended: &MmuConfig) -> impl Iterator<Item = (&str, u64, u64)> {
+        let sctlr = self.sctlr.0 & SCTLR_EL1_MASK;
+        [
+            ("tcr_el1", intended.tcr.0, self.tcr.0),
+            ("mair_el1", intended.mair.0, self.mair.0),
+            ("sctlr_el1", intended.sctlr.0, sctlr),
+        ]
+        .into_iter()
+        .filter(|(_, intended, live)| intended != live)
+    }
+}
+
+/// Return the intended translation regime.  The table bases are set as the
+/// address spaces are activated, so are left 0.
+pub const fn intended_config() -> MmuConfig {
+    MmuConfig {
+        tcr: BOOT_TCR_EL1,
+        mair: BOOT_MAIR_EL1,
+        sctlr: BOOT_SCTLR_EL1,
+        ttbr0: TtbrEl1(0),
+        ttbr1: TtbrEl1(0),
+    }
+}
+
+/// Read the live translation regime of this core.
+pub fn mmu_config() -> MmuConfig {
+    #[cfg(not(test))]
+    unsafe {
+        let (tcr, mair, sctlr, ttbr0, ttbr1): (u64, u64, u64, u64, u64);
+        core::arch::asm!(
+            "mrs {tcr}, tcr_el1",
+            "mrs {mair}, mair_el1",
+            "mrs {sctlr}, sctlr_el1",
+            "mrs {ttbr0}, ttbr0_el1",
+            "mrs {ttbr1}, ttbr1_el1",
+            tcr = out(reg) tcr,
+            mair = out(reg) mair,
+            sctlr = out(reg) sctlr,
+            ttbr0 = out(reg) ttbr0,
+            ttbr1 = out(reg) ttbr1,
+        );
+        MmuConfig {
+            tcr: TcrEl1(tcr),
+            mair: MairEl1(mair),
+            sctlr: SctlrEl1(sctlr),
+            ttbr0: TtbrEl1(ttbr0),
+            ttbr1: TtbrEl1(ttbr1),
+        }
+    }
+    #[cfg(test)]
+    intended_config()
+}
+
+/// Print the live translation regime at debug level, and warn about each
+/// register that isn't as intended.
+pub fn check_config() {
+    let live = mmu_config();
+    debug!("MMU config:\n{live}");
+    for (reg, intended, value) in live.divergences(&intended_config()) {
+        warn!(
+            "mmu: {reg} is {value:#018x}, but should be {intended:#018x}: \
+             have l.S and mmu.rs drifted apart?"
+        );
+    }
+}
+
+fn granule(tg: u8, is_tg1: bool) -> &'static str {
+    match (tg, is_tg1) {
+        (0, false) | (2, true) => "4KiB",
+        (1, false) | (3, true) => "64KiB",
+        (2, false) | (1, true) => "16KiB",
+        _ => "reserved",
+    }
+}
+
+fn cacheability(rgn: u8) -> &'static str {
+    ["non-cacheable", "write-back", "write-through", "write-back no-allocate"][rgn as usize & 3]
+}
+
+fn shareability(sh: u8) -> &'static str {
+    ["non-shareable", "reserved", "outer shareable", "inner shareable"][sh as usize & 3]
+}
+
+fn mair_attr(attr: u8) -> &'static str {
+    match attr {
+        ATTR_NORMAL => "normal",
+        ATTR_NORMAL_NC => "normal non-cacheable",
+        ATTR_DEVICE_NGNRNE => "device nGnRnE",
+        ATTR_DEVICE_NGNRE => "device nGnRE",
+        _ => "other",
+    }
+}
+
+impl fmt::Display for TcrEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let halves = [
+            ("ttbr0", self.t0sz(), self.tg0(), false, self.irgn0(), self.orgn0(), self.sh0()),
+            ("ttbr1", self.t1sz(), self.tg1(), true, self.irgn1(), self.orgn1(), self.sh1()),
+        ];
+        write!(f, "{:#018x}", self.0)?;
+        for (name, tsz, tg, is_tg1, irgn, orgn, sh) in halves {
+            write!(
+                f,
+                " {name}: {} bit VA, {} granule, tables inner {} outer {} {};",
+                64 - tsz,
+                granule(tg, is_tg1),
+                cacheability(irgn),
+                cacheability(orgn),
+                shareability(sh)
+            )?;
+        }
+        let pa_bits = [32, 36, 40, 42, 44, 48, 52].get(self.ips() as usize);
+        match pa_bits {
+            Some(bits) => write!(f, " {bits} bit PA"),
+            None => write!(f, " reserved PA size"),
+        }
+    }
+}
+
+impl fmt::Display for MairEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        for index in 0..4 {
+            write!(f, " [{index}] {}", mair_attr(self.attr(index)))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SctlrEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on = |enabled| if enabled { "on" } else { "off" };
+        write!(
+            f,
+            "{:#018x} mmu {} dcache {} icache {}",
+            self.0,
+            on(self.mmu()),
+            on(self.dcache()),
+            on(self.icache())
+        )
+    }
+}
+
+impl fmt::Display for TtbrEl1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x} table {} asid {}", self.0, self.table(), self.asid())
+    }
+}
+
+impl fmt::Display for MmuConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  tcr_el1:   {}", self.tcr)?;
+        writeln!(f, "  mair_el1:  {}", self.mair)?;
+        writeln!(f, "  sctlr_el1: {}", self.sctlr)?;
+        writeln!(f, "  ttbr0_el1: {}", self.ttbr0)?;
+        write!(f, "  ttbr1_el1: {}", self.ttbr1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_values() {
+        // The values l.S used to define itself
+        assert_eq!(BOOT_TCR_EL1.0, 0x4_b510_3510);
+        assert_eq!(BOOT_MAIR_EL1.0, 0x0444_00ff);
+        assert_eq!(BOOT_SCTLR_EL1.0, 0x1005);
+        assert_eq!(BOOT_MAIR_EL1.attr(Mair::NonCacheable as usize), ATTR_NORMAL_NC);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            BOOT_TCR_EL1.to_string(),
+            "0x00000004b5103510 \
+             ttbr0: 48 bit VA, 4KiB granule, tables inner write-back outer write-back inner shareable; \
+             ttbr1: 48 bit VA, 4KiB granule, tables inner write-back outer write-back inner shareable; \
+             44 bit PA"
+        );
+        assert_eq!(
+            BOOT_MAIR_EL1.to_string(),
+            "0x00000000044400ff [0] normal [1] device nGnRnE [2] normal non-cacheable [3] device nGnRE"
+        );
+        assert_eq!(BOOT_SCTLR_EL1.to_string(), "0x0000000000001005 mmu on dcache on icache on");
+        assert_eq!(
+            TtbrEl1(0x0001_0000_4008_1000).to_string(),
+            "0x0001000040081000 table 0x0000000040081000 asid 1"
+        );
+    }
+
+    #[test]
+    fn divergences() {
+        let intended = intended_config();
+        let mut live = intended;
+        live.ttbr1 = TtbrEl1(0x4008_1000);
+        live.sctlr.0 |= 1 << 29; // RES1, so ignored
+        assert_eq!(live.divergences(&intended).count(), 0);
+
+        live.tcr = live.tcr.with_t1sz(25);
+        live.sctlr = live.sctlr.with_dcache(false);
+        let diffs: Vec<_> = live.divergences(&intended).collect();
+        assert_eq!(
+            diffs,
+            [("tcr_el1", 0x4_b510_3510, 0x4_b519_3510), ("sctlr_el1", 0x1005, 0x1001)]
+        );
+    }
+}
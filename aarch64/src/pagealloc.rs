@@ -2,21 +2,41 @@
 /// arch-specific use of it.
 ///
 /// The page allocator is constructed and finalised in a number of phases:
-/// 1. `init_page_allocator` to create a fixed size allocator assuming everything
-///    is in use except a small number of statically defined pages available for
-///    setting up the initial page tables.
-/// 2. `free_unused_ranges` to mark available ranges as the inverse of the
-///    physical memory map within the bounds of the available memory.
+/// 1. `init_page_allocator` to initialise the allocator with only a small
+///    number of statically defined pages available for setting up the initial
+///    page tables and the direct map.  The allocator's state is carved out of
+///    these pages, which are reached through the kernel image mapping.
+/// 2. `init_from` to mark the physical memory as available, less any reserved
+///    ranges such as the kernel, DTB and early page tables.  The allocator's
+///    state is carved out of memory again, and reached through the direct map.
+use crate::dmap;
 use crate::kmem;
-use crate::vm::Entry;
-use crate::vm::RootPageTable;
-use crate::vm::RootPageTableType;
+use crate::param::{PAGEALLOC_REGIONS, ZONE_DMA_END, ZONE_DMA32_END};
+use crate::vm::AddressSpace;
+use crate::vm::PageSize;
 use crate::vm::VaMapping;
 use crate::vm::VirtPage4K;
+use core::ptr::NonNull;
+#[cfg(feature = "bitmap_pagealloc")]
 use port::bitmapalloc::BitmapPageAlloc;
+#[cfg(not(feature = "bitmap_pagealloc"))]
+use port::buddyalloc::BuddyPageAlloc;
+use port::devcons::Console;
+use port::framerefs::{RefCount, RefCountPageAlloc};
+use port::mem::MapFlags;
+use port::mem::MemError;
 use port::mem::PhysAddr;
 use port::mem::PhysRange;
-use port::pagealloc::PageAllocError;
+use port::mem::PhysRangeSet;
+use port::mem::VirtAddr;
+use port::mem::VirtRange;
+use port::memaccount::{MemAccounts, MemCategory};
+use port::pagealloc::{PageAlloc, PageAllocStats, PageAllocSummary, PageMapper, ReserveError};
+#[cfg(debug_assertions)]
+use port::pagepoison::PoisonPageAlloc;
+use port::regionalloc::RegionPageAlloc;
+use port::slab::SlabPages;
+use port::zones::{ZoneAccounts, ZoneBounds, ZoneRequest};
 use port::{
     mcslock::{Lock, LockNode},
     mem::PAGE_SIZE_4K,
@@ -24,93 +44,669 @@ use port::{
 
 #[cfg(not(test))]
 use port::println;
+use port::trace;
 
-/// Set up bitmap page allocator assuming everything is allocated.
-static PAGE_ALLOC: Lock<BitmapPageAlloc<32, PAGE_SIZE_4K>> = Lock::new(
-    "page_alloc",
-    const { BitmapPageAlloc::<32, PAGE_SIZE_4K>::new_all_allocated(PAGE_SIZE_4K) },
-);
+/// The buddy allocator is used by default.  The `bitmap_pagealloc` feature
+/// selects the bitmap allocator instead, e.g. for comparison.  Each covers
+/// 2GiB of physical memory.
+#[cfg(feature = "bitmap_pagealloc")]
+type BackendPageAlloc = BitmapPageAlloc<16, PAGE_SIZE_4K>;
+#[cfg(not(feature = "bitmap_pagealloc"))]
+type BackendPageAlloc = BuddyPageAlloc<16, PAGE_SIZE_4K>;
 
-/// The bitmap allocator has all pages marked as allocated initially.  We'll
-/// add some pages (mark free) to allow us to set up the page tables and build
-/// a memory map.  Once the memory map has been build, we can mark all the unused
-/// space as available.  This allows us to use only one page allocator throughout.
+/// Physical memory may be made up of several banks with holes between them,
+/// e.g. the Raspberry Pi 4 has RAM below 1GiB and above 4GiB, so memory is
+/// managed as up to PAGEALLOC_REGIONS regions of 2GiB.
+type RegionsPageAlloc = RegionPageAlloc<BackendPageAlloc, PAGEALLOC_REGIONS>;
+
+/// Debug builds poison freed pages to catch writes after free.
+#[cfg(debug_assertions)]
+type PoisonedPageAlloc = PoisonPageAlloc<RegionsPageAlloc, DmapMapper>;
+#[cfg(not(debug_assertions))]
+type PoisonedPageAlloc = RegionsPageAlloc;
+
+/// Frames are reference counted, so that they can be mapped in more than one
+/// place and only freed once the last mapping is gone.
+type PageAllocImpl = RefCountPageAlloc<PoisonedPageAlloc, DmapMapper>;
+
+/// Pages allocated for each category, updated as pages are allocated and
+/// freed with a category.  Pages allocated without one are counted as
+/// Unknown.
+pub static MEM_ACCOUNTS: MemAccounts = MemAccounts::new(PAGE_SIZE_4K);
+
+/// Pages in each memory zone, and how many are free, counted from when the
+/// allocator is initialised with all of memory.
+pub static ZONES: ZoneAccounts =
+    ZoneAccounts::new(ZoneBounds::new(ZONE_DMA_END, ZONE_DMA32_END), PAGE_SIZE_4K);
+
+// Pages allocated and freed, other than those reserved and released
+port::counter!(static PAGES_ALLOCATED = "pagealloc.alloc_pages");
+port::counter!(static PAGES_FREED = "pagealloc.free_pages");
+
+/// Count the pages covering `range`, just taken from the allocator for
+/// `category`, in the memory and zone accounts.
+fn account_alloc(range: &PhysRange, category: MemCategory) {
+    let pages = range.round_out(PAGE_SIZE_4K as u64).size() / PAGE_SIZE_4K;
+    MEM_ACCOUNTS.alloc(category, pages);
+    ZONES.alloc(range);
+}
+
+/// Undo `account_alloc` for `range`, just returned to the allocator.
+fn account_free(range: &PhysRange, category: MemCategory) {
+    let pages = range.round_out(PAGE_SIZE_4K as u64).size() / PAGE_SIZE_4K;
+    MEM_ACCOUNTS.free(category, pages);
+    ZONES.free(range);
+}
+
+/// Set up page allocator assuming everything is allocated.
+static PAGE_ALLOC: Lock<PageAllocImpl> = Lock::new("page_alloc", const { new_page_alloc() });
+
+const fn new_regions_page_alloc() -> RegionsPageAlloc {
+    RegionPageAlloc::new(
+        [const { BackendPageAlloc::new_all_allocated(PAGE_SIZE_4K) }; PAGEALLOC_REGIONS],
+    )
+}
+
+#[cfg(debug_assertions)]
+const fn new_poisoned_page_alloc() -> PoisonedPageAlloc {
+    PoisonPageAlloc::new(new_regions_page_alloc(), DmapMapper)
+}
+
+#[cfg(not(debug_assertions))]
+const fn new_poisoned_page_alloc() -> PoisonedPageAlloc {
+    new_regions_page_alloc()
+}
+
+const fn new_page_alloc() -> PageAllocImpl {
+    RefCountPageAlloc::new(new_poisoned_page_alloc(), DmapMapper)
+}
+
+/// Reaches physical pages through the direct map, for zeroing and poisoning
+/// pages.  No pages are reachable until the direct map has been set up.
+struct DmapMapper;
+
+/// Reaches the early pages through the kernel image mapping at KZERO, so the
+/// allocator can keep its state there until the direct map is set up.
+struct EarlyPagesMapper;
+
+unsafe impl PageMapper for EarlyPagesMapper {
+    fn page_ptr(&self, pa: PhysAddr, page_size: usize) -> Option<*mut u8> {
+        let early_pages = kmem::early_pages_range();
+        let range = PhysRange::with_pa_len(pa, page_size);
+        (early_pages.start() <= range.start() && range.end() <= early_pages.end())
+            .then(|| kmem::physaddr_as_ptr_mut_offset_from_kzero(pa))
+    }
+}
+
+unsafe impl PageMapper for DmapMapper {
+    fn page_ptr(&self, pa: PhysAddr, page_size: usize) -> Option<*mut u8> {
+        dmap::dmap_range(&PhysRange::with_pa_len(pa, page_size))
+            .ok()
+            .map(|range| range.start().addr() as *mut u8)
+    }
+}
+
+/// Gives slab caches pages from the page allocator, through the direct map,
+/// so it can only be used once the direct map is ready.
+#[allow(dead_code)]
+pub struct DmapSlabPages;
+
+unsafe impl SlabPages for DmapSlabPages {
+    fn alloc_page(&self) -> Option<NonNull<u8>> {
+        let pa = allocate_physpage().ok()?;
+        match dmap::phys_to_dmap(pa) {
+            Ok(va) => NonNull::new(va.addr() as *mut u8),
+            Err(err) => {
+                println!("error:pagealloc:DmapSlabPages:page not in direct map: {err}");
+                let _ = free_physpage(pa);
+                None
+            }
+        }
+    }
+
+    fn free_page(&self, page: NonNull<u8>) {
+        match dmap::dmap_to_phys(VirtAddr::new(page.addr().get())) {
+            Ok(pa) => {
+                let _ = free_physpage(pa);
+            }
+            Err(err) => {
+                println!("error:pagealloc:DmapSlabPages:page {page:?} not in direct map: {err}")
+            }
+        }
+    }
+}
+
+/// The allocator has no memory initially.  We'll give it only the early pages
+/// to allow us to set up the page tables and build a memory map.  Once the
+/// memory map has been built, we can make all the unused space available.
+/// This allows us to use only one page allocator throughout.
 pub fn init_page_allocator() {
     let node = LockNode::new();
     let mut lock = PAGE_ALLOC.lock(&node);
     let page_alloc = &mut *lock;
 
     let early_pages_range = kmem::early_pages_range();
-    if let Err(err) = page_alloc.mark_free(&early_pages_range) {
+    let mut early_pages = PhysRangeSet::<1>::new();
+    let result = early_pages
+        .add(&early_pages_range)
+        .map_err(MemError::from)
+        .and_then(|_| page_alloc.init_from(&early_pages, &[], &EarlyPagesMapper));
+    if let Err(err) = result {
         panic!(
-            "error:pagealloc:init_page_allocator:couldn't mark early pages free: range: {} err: {:?}",
+            "error:pagealloc:init_page_allocator:couldn't make early pages available: range: {} err: {}",
             early_pages_range, err
         );
     }
 }
 
-/// Free unused pages in mem that aren't covered by the memory map.  Assumes
-/// that custom_map is sorted.
-pub fn free_unused_ranges<'a>(
-    available_mem: &PhysRange,
-    used_ranges: impl Iterator<Item = &'a PhysRange>,
-) -> Result<(), PageAllocError> {
+/// Make all physical memory available for allocation, except the reserved
+/// ranges.  Memory may be made up of several discontiguous banks, such as the
+/// `/memory` nodes of the device tree.  This replaces the early state of the allocator, so the reserved
+/// ranges must include the early pages, since they're all mapped, and some
+/// are in use for the page tables.  The allocator's state is carved out of
+/// memory reached through the direct map, which must already be set up.
+/// The downside is that we lose access to the unallocated early pages.
+pub fn init_from(
+    memory: &PhysRangeSet,
+    reserved: &[PhysRange],
+) -> Result<PageAllocSummary, MemError> {
     let node = LockNode::new();
     let mut lock = PAGE_ALLOC.lock(&node);
     let page_alloc = &mut *lock;
+    let summary = page_alloc.init_from(memory, reserved, &DmapMapper)?;
+    page_alloc.for_each_free_range(|range| ZONES.add_memory(range));
+    page_alloc.for_each_metadata_range(|range| {
+        kmem::record_phys_range("pagealloc metadata", range.clone(), None);
+    });
+    Ok(summary)
+}
 
-    page_alloc.free_unused_ranges(available_mem, used_ranges)?;
-
-    // Mark all the early pages as used.  The early pages are all mapped, but we want to
-    // assume that past this point all pages are unmapped.  The mapping can then be always
-    // done after allocating a page.  The downside is that we lose access to the unallocated
-    // early pages.
-    page_alloc.mark_allocated(&kmem::early_pages_range())
+/// Called once the direct map has been set up, so that frame references
+/// start being counted, and in debug builds, freed pages start being poisoned.
+pub fn direct_map_ready() {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    #[cfg(debug_assertions)]
+    lock.inner_mut().enable();
+    lock.enable();
 }
 
 /// Try to allocate a physical page.  Note that this is NOT mapped.
-pub fn allocate_physpage() -> Result<PhysAddr, PageAllocError> {
+pub fn allocate_physpage() -> Result<PhysAddr, MemError> {
+    allocate_physpage_for(MemCategory::Unknown)
+}
+
+/// Try to allocate a physical page, counted as used for `category`.  Note
+/// that this is NOT mapped.
+pub fn allocate_physpage_for(category: MemCategory) -> Result<PhysAddr, MemError> {
     let node = LockNode::new();
     let mut lock = PAGE_ALLOC.lock(&node);
     let page_alloc = &mut *lock;
 
     match page_alloc.allocate() {
         Ok(page_pa) => {
-            println!("pagealloc:allocate_physpage pa:{:?}", page_pa);
+            trace!("allocate_physpage pa:{page_pa:?}");
+            account_alloc(&PhysRange::with_pa_len(page_pa, PAGE_SIZE_4K), category);
+            PAGES_ALLOCATED.inc();
+            Ok(page_pa)
+        }
+        Err(err) => {
+            println!("error:pagealloc:allocate_physpage:failed to allocate: {}", err);
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate a physical page, zeroed through the direct map.  Note that
+/// this is NOT mapped to a new address.  Until the direct map is set up, pages
+/// can't be zeroed, so in that case the page is freed and `PhysNotMapped`
+/// returned, and the caller must zero the page once it's mapped.
+#[allow(dead_code)]
+pub fn allocate_zeroed_physpage() -> Result<PhysAddr, MemError> {
+    allocate_zeroed_physpage_for(MemCategory::Unknown)
+}
+
+/// As `allocate_zeroed_physpage`, with the page counted as used for
+/// `category`.
+pub fn allocate_zeroed_physpage_for(category: MemCategory) -> Result<PhysAddr, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_zeroed(&DmapMapper) {
+        Ok(page_pa) => {
+            trace!("allocate_zeroed_physpage pa:{page_pa:?}");
+            account_alloc(&PhysRange::with_pa_len(page_pa, PAGE_SIZE_4K), category);
+            PAGES_ALLOCATED.inc();
+            Ok(page_pa)
+        }
+        Err(err @ MemError::PhysNotMapped { .. }) => Err(err),
+        Err(err) => {
+            println!("error:pagealloc:allocate_zeroed_physpage:failed to allocate: {}", err);
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate `page_count` physically contiguous pages.  Note that these
+/// are NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_contiguous_physpages(page_count: usize) -> Result<PhysRange, MemError> {
+    allocate_contiguous_physpages_for(page_count, MemCategory::Unknown)
+}
+
+/// Try to allocate `page_count` physically contiguous pages, counted as used
+/// for `category`.  Note that these are NOT mapped.
+pub fn allocate_contiguous_physpages_for(
+    page_count: usize,
+    category: MemCategory,
+) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_contiguous(page_count) {
+        Ok(range) => {
+            trace!("allocate_contiguous_physpages range:{range}");
+            account_alloc(&range, category);
+            PAGES_ALLOCATED.add(page_count as u64);
+            Ok(range)
+        }
+        Err(err) => {
+            println!(
+                "error:pagealloc:allocate_contiguous_physpages:failed to allocate {} pages: {}",
+                page_count, err
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate `page_count` physically contiguous pages, zeroed through
+/// the direct map.  As for `allocate_zeroed_physpage`, if the pages aren't
+/// reachable through the direct map, they're freed and `PhysNotMapped` is
+/// returned.
+#[allow(dead_code)]
+pub fn allocate_contiguous_zeroed_physpages(page_count: usize) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_contiguous_zeroed(page_count, &DmapMapper) {
+        Ok(range) => {
+            trace!("allocate_contiguous_zeroed_physpages range:{range}");
+            account_alloc(&range, MemCategory::Unknown);
+            PAGES_ALLOCATED.add(page_count as u64);
+            Ok(range)
+        }
+        Err(err) => {
+            println!(
+                "error:pagealloc:allocate_contiguous_zeroed_physpages:failed to allocate {} pages: {}",
+                page_count, err
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate `page_count` physically contiguous pages, starting at an
+/// address aligned to `align`, so that the range can be mapped using pages of
+/// that size.  Note that these are NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_aligned_physpages(
+    page_count: usize,
+    align: PageSize,
+) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_aligned(page_count, align.size() as u64) {
+        Ok(range) => {
+            trace!("allocate_aligned_physpages range:{range}");
+            account_alloc(&range, MemCategory::Unknown);
+            PAGES_ALLOCATED.add(page_count as u64);
+            Ok(range)
+        }
+        Err(err) => {
+            println!(
+                "error:pagealloc:allocate_aligned_physpages:failed to allocate {} pages aligned to {:?}: {}",
+                page_count, align, err
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate a physical page below `limit`, for devices that can't
+/// address all of physical memory.  Note that this is NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_physpage_below(limit: PhysAddr) -> Result<PhysAddr, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_below(limit) {
+        Ok(page_pa) => {
+            trace!("allocate_physpage_below pa:{page_pa:?}");
+            account_alloc(&PhysRange::with_pa_len(page_pa, PAGE_SIZE_4K), MemCategory::Unknown);
+            PAGES_ALLOCATED.inc();
             Ok(page_pa)
         }
         Err(err) => {
-            println!("error:pagealloc:allocate_physpage:failed to allocate: {:?}", err);
+            println!(
+                "error:pagealloc:allocate_physpage_below:failed to allocate below {:?}: {}",
+                limit, err
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate `page_count` physically contiguous pages below `limit`,
+/// for devices that can't address all of physical memory.  Note that these
+/// are NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_contiguous_physpages_below(
+    page_count: usize,
+    limit: PhysAddr,
+) -> Result<PhysRange, MemError> {
+    allocate_contiguous_physpages_below_for(page_count, limit, MemCategory::Unknown)
+}
+
+/// Try to allocate `page_count` physically contiguous pages below `limit`,
+/// counted as used for `category`.  Note that these are NOT mapped.
+pub fn allocate_contiguous_physpages_below_for(
+    page_count: usize,
+    limit: PhysAddr,
+    category: MemCategory,
+) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    match page_alloc.allocate_contiguous_below(page_count, limit) {
+        Ok(range) => {
+            trace!("allocate_contiguous_physpages_below range:{range}");
+            account_alloc(&range, category);
+            PAGES_ALLOCATED.add(page_count as u64);
+            Ok(range)
+        }
+        Err(err) => {
+            println!(
+                "error:pagealloc:allocate_contiguous_physpages_below:failed to allocate {} pages below {:?}: {}",
+                page_count, limit, err
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Try to allocate `page_count` physically contiguous pages from the zones
+/// `request` allows, in the order it gives, counted as used for `category`.
+/// Note that these are NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_contiguous_physpages_in_for(
+    page_count: usize,
+    request: ZoneRequest,
+    category: MemCategory,
+) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+
+    let available = page_alloc.stats().free_pages * PAGE_SIZE_4K;
+    let mut result = Err(MemError::OutOfMemory { requested: page_count * PAGE_SIZE_4K, available });
+    for zone in request.zones() {
+        result = page_alloc.allocate_contiguous_within(page_count, &ZONES.bounds().range(zone));
+        if result.is_ok() {
+            break;
+        }
+    }
+    match result {
+        Ok(range) => {
+            trace!("allocate_contiguous_physpages_in range:{range}");
+            account_alloc(&range, category);
+            PAGES_ALLOCATED.add(page_count as u64);
+            Ok(range)
+        }
+        Err(err) => {
+            println!(
+                "error:pagealloc:allocate_contiguous_physpages_in:failed to allocate {} pages in {:?}: {}",
+                page_count, request, err
+            );
             Err(err)
         }
     }
 }
 
+/// Return a physical page to the allocator.  The page must not be mapped.
+/// Pages outside physical memory, e.g. in a hole between banks, are rejected.
+#[allow(dead_code)]
+pub fn free_physpage(pa: PhysAddr) -> Result<(), MemError> {
+    free_physpage_for(pa, MemCategory::Unknown)
+}
+
+/// Return a physical page allocated for `category` to the allocator.  The
+/// page must not be mapped.
+pub fn free_physpage_for(pa: PhysAddr, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc
+        .free(pa)
+        .inspect(|_| {
+            account_free(&PhysRange::with_pa_len(pa, PAGE_SIZE_4K), category);
+            PAGES_FREED.inc();
+        })
+        .inspect_err(|err| {
+            println!("error:pagealloc:free_physpage:failed to free pa:{:?}: {}", pa, err);
+        })
+}
+
+/// Return a range of physical pages to the allocator, e.g. as allocated by
+/// `allocate_contiguous_physpages`.  The pages must not be mapped.
+#[allow(dead_code)]
+pub fn free_physpages(range: &PhysRange) -> Result<(), MemError> {
+    free_physpages_for(range, MemCategory::Unknown)
+}
+
+/// Return a range of physical pages allocated for `category` to the
+/// allocator.  The pages must not be mapped.
+pub fn free_physpages_for(range: &PhysRange, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc
+        .free_range(range)
+        .inspect(|_| {
+            account_free(range, category);
+            PAGES_FREED.add(range.size().div_ceil(PAGE_SIZE_4K) as u64);
+        })
+        .inspect_err(|err| {
+            println!("error:pagealloc:free_physpages:failed to free range:{}: {}", range, err);
+        })
+}
+
+/// Mark the physical pages covering `range` as in use, e.g. for a buffer
+/// placed by firmware, returning the range reserved, which is rounded outward
+/// to whole pages.  Fails if any page is already allocated, or isn't in
+/// memory managed by the allocator.
+#[allow(dead_code)]
+pub fn reserve_physpages(range: &PhysRange) -> Result<PhysRange, ReserveError> {
+    reserve_physpages_for(range, MemCategory::Unknown)
+}
+
+/// As `reserve_physpages`, with the pages counted as used for `category`.
+pub fn reserve_physpages_for(
+    range: &PhysRange,
+    category: MemCategory,
+) -> Result<PhysRange, ReserveError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.reserve_range(range).inspect(|reserved| account_alloc(reserved, category))
+}
+
+/// Reserve all the free pages for `category`, returning the ranges reserved,
+/// e.g. so the free memory can be tested at boot.  Only the first `N` free
+/// ranges are reserved, and any more are left free.
+pub fn reserve_free_ranges<const N: usize>(category: MemCategory) -> PhysRangeSet<N> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    let mut free = PhysRangeSet::<N>::new();
+    page_alloc.for_each_free_range(|range| {
+        if free.add(range).is_err() {
+            println!("error:pagealloc:reserve_free_ranges:too many ranges, leaving free:{range}");
+        }
+    });
+    let mut reserved = PhysRangeSet::new();
+    for range in free.iter() {
+        match page_alloc.reserve_range(range) {
+            Ok(range) => {
+                account_alloc(&range, category);
+                // There are no more ranges than were free
+                let _ = reserved.add(&range);
+            }
+            Err(err) => {
+                println!("error:pagealloc:reserve_free_ranges:can't reserve {range}: {err:?}");
+            }
+        }
+    }
+    reserved
+}
+
+/// Release physical pages reserved by `reserve_physpages`.
+#[allow(dead_code)]
+pub fn release_physpages(range: &PhysRange) -> Result<(), MemError> {
+    release_physpages_for(range, MemCategory::Unknown)
+}
+
+/// Release physical pages reserved for `category` by
+/// `reserve_physpages_for`.
+#[allow(dead_code)]
+pub fn release_physpages_for(range: &PhysRange, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.release_range(range).inspect(|_| account_free(range, category)).inspect_err(|err| {
+        println!("error:pagealloc:release_physpages:failed to release range:{}: {}", range, err);
+    })
+}
+
+/// Add a reference to the allocated physical page at `pa`, e.g. because it's
+/// being mapped at another address, returning the new count.
+pub fn get_physpage(pa: PhysAddr) -> Result<RefCount, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.get(pa).inspect_err(|err| {
+        println!("error:pagealloc:get_physpage:failed to get pa:{:?}: {}", pa, err);
+    })
+}
+
+/// Drop a reference to the physical page at `pa`, e.g. because a mapping of
+/// it has been removed, returning the number left.  The page is freed once no
+/// references are left, so must no longer be mapped anywhere.
+pub fn put_physpage(pa: PhysAddr) -> Result<RefCount, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc
+        .put(pa)
+        .inspect(|&count| {
+            // Pages mapped with allocate_virtpage aren't given a category
+            if count == 0 {
+                account_free(&PhysRange::with_pa_len(pa, PAGE_SIZE_4K), MemCategory::Unknown);
+                PAGES_FREED.inc();
+            }
+        })
+        .inspect_err(|err| {
+            println!("error:pagealloc:put_physpage:failed to put pa:{:?}: {}", pa, err);
+        })
+}
+
+/// Return the number of references to the physical page containing `pa`, or
+/// None if they aren't counted for it, or the allocator is locked.  Doesn't
+/// block, so can be used when dumping the page tables from the panic handler.
+pub fn try_ref_count(pa: PhysAddr) -> Option<RefCount> {
+    let node = LockNode::new();
+    PAGE_ALLOC.try_lock(&node).and_then(|lock| lock.ref_count(pa))
+}
+
 /// Try to allocate a physical page and map it into virtual memory at va.
 pub fn allocate_virtpage(
-    page_table: &mut RootPageTable,
+    space: &mut AddressSpace,
     debug_name: &str,
-    entry: Entry,
+    flags: MapFlags,
     va: VaMapping,
-    pgtype: RootPageTableType,
-) -> Result<&'static mut VirtPage4K, PageAllocError> {
+) -> Result<&'static mut VirtPage4K, MemError> {
     let page_pa = allocate_physpage()?;
-    let range = PhysRange::with_pa_len(page_pa, PAGE_SIZE_4K);
-    if let Ok(page_va) = page_table.map_phys_range(
-        debug_name,
-        &range,
-        va,
-        entry,
-        crate::vm::PageSize::Page4K,
-        pgtype,
-    ) {
-        println!("pagealloc:allocate_virtpage:va:{:#x} -> physpage:{:?}", page_va.0, page_pa);
-        let virtpage = page_va.0 as *mut VirtPage4K;
-        Ok(unsafe { &mut *virtpage })
-    } else {
-        println!("error:pagealloc:allocate_virtpage:unable to map");
-        Err(PageAllocError::UnableToMap)
+    // The allocation's reference becomes the mapping's
+    match map_page(space, debug_name, page_pa, flags, va) {
+        Ok(page_va) => {
+            trace!("allocate_virtpage va:{page_va} -> physpage:{page_pa}");
+            let virtpage = page_va.addr() as *mut VirtPage4K;
+            Ok(unsafe { &mut *virtpage })
+        }
+        Err(err) => {
+            println!("error:pagealloc:allocate_virtpage:unable to map pa:{page_pa}: {err}");
+            let _ = put_physpage(page_pa);
+            Err(err)
+        }
+    }
+}
+
+/// Map the allocated physical page at `pa` into `space` at va, as well as
+/// wherever it's already mapped, adding a reference to it for the new
+/// mapping.  Returns the virtual address it's mapped at.
+#[allow(dead_code)]
+pub fn map_physpage(
+    space: &mut AddressSpace,
+    debug_name: &str,
+    pa: PhysAddr,
+    flags: MapFlags,
+    va: VaMapping,
+) -> Result<VirtAddr, MemError> {
+    get_physpage(pa)?;
+    map_page(space, debug_name, pa, flags, va).inspect_err(|err| {
+        println!("error:pagealloc:map_physpage:unable to map pa:{pa}: {err}");
+        let _ = put_physpage(pa);
+    })
+}
+
+fn map_page(
+    space: &mut AddressSpace,
+    debug_name: &str,
+    pa: PhysAddr,
+    flags: MapFlags,
+    va: VaMapping,
+) -> Result<VirtAddr, MemError> {
+    let range = PhysRange::with_pa_len(pa, PAGE_SIZE_4K);
+    space
+        .map_phys_range(debug_name, &range, va, flags, PageSize::Page4K)
+        .map(|(page_va, _)| VirtAddr::new(page_va))
+}
+
+/// Unmap the page mapped at `va` in `space`, e.g. by `allocate_virtpage` or
+/// `map_physpage`, and drop the mapping's reference to the physical page,
+/// freeing it if that was the last one.
+#[allow(dead_code)]
+pub fn unmap_virtpage(space: &mut AddressSpace, va: VirtAddr) -> Result<(), MemError> {
+    let Some(mapping) = space.lookup(va) else {
+        println!("error:pagealloc:unmap_virtpage:va:{:?} isn't mapped", va);
+        return Err(MemError::NotMapped { va });
+    };
+    if mapping.page_size != PageSize::Page4K {
+        println!("error:pagealloc:unmap_virtpage:va:{:?} isn't mapped by a page", va);
+        return Err(MemError::NotMapped { va });
     }
+    let page = VirtRange::with_len(va.round_down(PAGE_SIZE_4K), PAGE_SIZE_4K);
+    space.unmap(&page).inspect_err(|err| {
+        println!("error:pagealloc:unmap_virtpage:unable to unmap va:{:?}: {}", va, err);
+    })?;
+    put_physpage(mapping.pa.round_down(PAGE_SIZE_4K as u64)).map(|_| ())
 }
 
 /// Return a tuple of (bytes used, total bytes available) based on the page allocator.
@@ -120,3 +716,35 @@ pub fn usage_bytes() -> (usize, usize) {
     let page_alloc = &mut *lock;
     page_alloc.usage_bytes()
 }
+
+/// Return the page allocator statistics.
+pub fn stats() -> PageAllocStats {
+    let node = LockNode::new();
+    let lock = PAGE_ALLOC.lock(&node);
+    lock.stats()
+}
+
+/// Call `f` with the physical range and statistics of each region of memory
+/// managed by the page allocator.
+pub fn for_each_region(f: impl FnMut(&PhysRange, PageAllocStats)) {
+    let node = LockNode::new();
+    let lock = PAGE_ALLOC.lock(&node);
+    lock.for_each_region(f)
+}
+
+/// Return the page allocator statistics if the allocator isn't locked.  For
+/// use where we can't risk blocking, e.g. when panicking while allocating.
+#[allow(dead_code)]
+pub fn try_stats() -> Option<PageAllocStats> {
+    let node = LockNode::new();
+    PAGE_ALLOC.try_lock(&node).map(|lock| lock.stats())
+}
+
+/// Print the page allocator regions and stats to the console, and optionally
+/// the runs of used and free pages.
+#[allow(dead_code)]
+pub fn dump(show_map: bool) {
+    let node = LockNode::new();
+    let lock = PAGE_ALLOC.lock(&node);
+    let _ = lock.dump(&mut Console, show_map);
+}
@@ -1,2 +1,125 @@
+use crate::dmap::BoardExclusion;
+use port::layout::{Region, RegionKind};
+use port::log::Level;
+use port::mem::{PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K};
+
 // This needs to match KZERO in l.S
 pub const KZERO: usize = 0xffff_8000_0000_0000;
+
+// Size of each kernel stack, including the guard page at its base, which is
+// left unmapped so that overflowing the stack faults.  KSTACK_SIZE needs to
+// match STACKSZ in l.S
+pub const KSTACK_GUARD_SIZE: usize = 4096;
+pub const KSTACK_SIZE: usize = 4 * 4096 + KSTACK_GUARD_SIZE;
+
+// Number of cores started, each with its own kernel stack.  This needs to
+// match MAX_CORES in l.S
+pub const MAX_CORES: usize = 4;
+
+// Layout of the kernel virtual address space, checked at boot by
+// port::layout::check.  Code using each region takes its bounds from here.
+//
+// The kernel image region is the KZERO mapping set up by l.S, from the load
+// offset.
+pub const KERNEL_IMAGE: Region = Region::new(RegionKind::KernelImage, KZERO, 1 << 30, PAGE_SIZE_2M);
+
+// The kernel stacks, one after the other in core order.  l.S sets them up in
+// the bss, and each is aliased here, without its guard page, once the kernel
+// page tables are set up, so that the cores run on them from then on.  This
+// needs to match KSTACKS_VA in l.S
+pub const STACKS: Region =
+    Region::new(RegionKind::Stacks, KZERO + (1 << 30), PAGE_SIZE_2M, PAGE_SIZE_4K);
+
+// Reserved for the kernel heap.  For now it grows with pages reached through
+// the direct map, so nothing is mapped here yet.
+pub const HEAP: Region = Region::new(RegionKind::Heap, KZERO + (2 << 30), 1 << 30, PAGE_SIZE_2M);
+
+// Device registers mapped by l.S at a fixed offset of KZERO, up to 4GiB.  The
+// Raspberry Pi 3's registers are below 1GiB, so are reached through the
+// kernel image region instead.
+pub const EARLY_MMIO: Region =
+    Region::new(RegionKind::EarlyMmio, KZERO + (3 << 30), 1 << 30, PAGE_SIZE_2M);
+
+// The direct map of physical RAM, which covers physical addresses up to its
+// size
+pub const DIRECT_MAP: Region =
+    Region::new(RegionKind::DirectMap, 0xffff_a000_0000_0000, 1 << 45, PAGE_SIZE_1G);
+
+// Allocated at runtime by vmap, e.g. for device registers
+pub const VMAP: Region = Region::new(RegionKind::Vmap, DIRECT_MAP.end(), 1 << 30, PAGE_SIZE_2M);
+
+// The page aliasing the break-before-make code, so it can run while the
+// mapping of the kernel text is being replaced
+pub const TRAMPOLINE: Region =
+    Region::new(RegionKind::Trampoline, VMAP.end(), PAGE_SIZE_4K, PAGE_SIZE_4K);
+
+pub const LAYOUT: [Region; 7] =
+    [KERNEL_IMAGE, STACKS, HEAP, EARLY_MMIO, DIRECT_MAP, VMAP, TRAMPOLINE];
+
+// Number of regions of up to 2GiB of physical memory the page allocator
+// manages, e.g. one for each bank of RAM
+pub const PAGEALLOC_REGIONS: usize = 4;
+
+// Maximum number of ranges vmap can map at once
+pub const MAX_VMAPS: usize = 64;
+
+// Maximum number of alarms pending on each core
+pub const MAX_ALARMS: usize = 16;
+
+// Maximum number of ranges of RAM excluded from the direct map
+pub const MAX_DMAP_EXCLUSIONS: usize = 16;
+
+// Ranges of RAM to exclude from the direct map on particular boards, as well
+// as those the device tree marks no-map, e.g. firmware or secure world memory
+// the device tree doesn't describe.  They're kept from the page allocator too.
+#[cfg(not(feature = "ktest"))]
+pub const DMAP_EXCLUDED: &[BoardExclusion] = &[];
+
+// The ktests check that a page excluded from the direct map, and one mapped
+// uncached, can't be reached through it on QEMU's Raspberry Pi boards
+#[cfg(feature = "ktest")]
+pub const DMAP_EXCLUDED: &[BoardExclusion] = &[
+    BoardExclusion {
+        compatible: "raspberrypi,3-model-b",
+        start: 0x3000_0000,
+        len: PAGE_SIZE_4K as u64,
+        uncached: false,
+    },
+    BoardExclusion {
+        compatible: "raspberrypi,3-model-b",
+        start: 0x3000_1000,
+        len: PAGE_SIZE_4K as u64,
+        uncached: true,
+    },
+    BoardExclusion {
+        compatible: "raspberrypi,4-model-b",
+        start: 0x3000_0000,
+        len: PAGE_SIZE_4K as u64,
+        uncached: false,
+    },
+    BoardExclusion {
+        compatible: "raspberrypi,4-model-b",
+        start: 0x3000_1000,
+        len: PAGE_SIZE_4K as u64,
+        uncached: true,
+    },
+];
+
+// Size of the buffer holding console output until it's flushed to the uart, or
+// 0 to write output straight to the uart
+pub const CONS_BUFFER_SIZE: usize = 16 * 1024;
+
+// Most verbose level of log output printed, until changed at runtime.  Debug
+// and trace output can be compiled out entirely with port's log features.
+pub const LOG_LEVEL: Level = Level::Info;
+
+// Physical address of the mini uart registers used for output before the
+// console is set up, e.g. by early_println!, or None to derive it from the
+// board type
+pub const EARLY_UART_PA: Option<u64> = None;
+
+// Where the DMA and DMA32 memory zones end.  The Raspberry Pi's VideoCore,
+// and the DMA engines of the Pi 4, can only address the first 1GiB, and
+// other devices are limited to 32 bit addresses
+pub const ZONE_DMA_END: u64 = 1 << 30;
+pub const ZONE_DMA32_END: u64 = 1 << 32;
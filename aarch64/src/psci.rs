@@ -0,0 +1,243 @@
+/// psci calls the firmware's Power State Coordination Interface, to start
+/// cores and to reset or power off the machine.  Calls use the SMC Calling
+/// Convention, through HVC or SMC as the /psci node of the device tree says,
+/// with the function id in x0, arguments in x1-x3, and the result in x0.
+/// See Arm DEN 0022 for PSCI, and DEN 0028 for the SMCCC.
+///
+/// Only PSCI 0.2 and later is supported, as 0.1 has no standard function
+/// ids, or SYSTEM_OFF and SYSTEM_RESET.
+use core::fmt;
+use port::fdt::DeviceTree;
+use port::mem::PhysAddr;
+use port::oncelock::OnceLock;
+use port::power::PowerOps;
+
+#[cfg(not(test))]
+use port::println;
+
+// Function ids.  CPU_ON takes addresses, so uses the SMC64 id.
+const PSCI_VERSION: u32 = 0x8400_0000;
+const PSCI_CPU_ON: u32 = 0xc400_0003;
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+static PSCI: OnceLock<Psci> = OnceLock::new();
+
+static POWER_OPS: PowerOps = PowerOps { reboot, power_off };
+
+/// How the firmware is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+/// A PSCI version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    const V0_2: Version = Version { major: 0, minor: 2 };
+
+    /// Return the version returned by PSCI_VERSION.
+    fn from_raw(raw: u32) -> Version {
+        Version { major: (raw >> 16) as u16, minor: raw as u16 }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The failures PSCI functions return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i32),
+}
+
+impl PsciError {
+    /// Return the result of a function returning a status, where 0 is
+    /// success.
+    fn check(status: i32) -> Result<(), PsciError> {
+        Err(match status {
+            0 => return Ok(()),
+            -1 => PsciError::NotSupported,
+            -2 => PsciError::InvalidParameters,
+            -3 => PsciError::Denied,
+            -4 => PsciError::AlreadyOn,
+            -5 => PsciError::OnPending,
+            -6 => PsciError::InternalFailure,
+            -7 => PsciError::NotPresent,
+            -8 => PsciError::Disabled,
+            -9 => PsciError::InvalidAddress,
+            status => PsciError::Unknown(status),
+        })
+    }
+}
+
+/// The firmware's PSCI.
+#[derive(Debug)]
+pub struct Psci {
+    conduit: Conduit,
+    version: Version,
+}
+
+impl Psci {
+    /// Return the PSCI described by the /psci node of `dt`, with the highest
+    /// version it's compatible with, if there is one we support.
+    fn from_dt(dt: &DeviceTree) -> Option<Psci> {
+        let node = dt.find_by_path("/psci")?;
+        let version = [("arm,psci-1.0", 1, 0), ("arm,psci-0.2", 0, 2)]
+            .into_iter()
+            .find(|(compatible, _, _)| dt.is_compatible(&node, compatible))
+            .map(|(_, major, minor)| Version { major, minor })?;
+        let conduit = match dt.property(&node, "method").and_then(|p| dt.property_value_as_str(&p))
+        {
+            Some("hvc") => Conduit::Hvc,
+            Some("smc") => Conduit::Smc,
+            method => {
+                println!("error:psci:from_dt:unknown method:{method:?}");
+                return None;
+            }
+        };
+        Some(Psci { conduit, version })
+    }
+
+    /// Call PSCI function `fid` with the arguments, returning x0.
+    #[allow(unused_variables)]
+    fn call(&self, fid: u32, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+        #[cfg(not(test))]
+        unsafe {
+            let mut ret = fid as u64;
+            match self.conduit {
+                Conduit::Hvc => core::arch::asm!(
+                    "hvc #0",
+                    inout("x0") ret,
+                    in("x1") arg1,
+                    in("x2") arg2,
+                    in("x3") arg3,
+                    clobber_abi("C"),
+                ),
+                // The assembler only accepts smc when targeting EL3, so
+                // encode `smc #0` by hand.
+                Conduit::Smc => core::arch::asm!(
+                    ".inst 0xd4000003",
+                    inout("x0") ret,
+                    in("x1") arg1,
+                    in("x2") arg2,
+                    in("x3") arg3,
+                    clobber_abi("C"),
+                ),
+            }
+            ret
+        }
+        #[cfg(test)]
+        0
+    }
+
+    /// Ask the firmware for its PSCI version.
+    pub fn psci_version(&self) -> Version {
+        Version::from_raw(self.call(PSCI_VERSION, 0, 0, 0) as u32)
+    }
+
+    /// Start the core with affinity `mpidr` at the physical address `entry`,
+    /// with its MMU off, and `context_id` in x0.
+    pub fn cpu_on(&self, mpidr: u64, entry: PhysAddr, context_id: u64) -> Result<(), PsciError> {
+        PsciError::check(self.call(PSCI_CPU_ON, mpidr, entry.addr(), context_id) as i32)
+    }
+
+    /// Power off the machine.  Only returns if the firmware fails to.
+    pub fn system_off(&self) -> PsciError {
+        let status = self.call(PSCI_SYSTEM_OFF, 0, 0, 0) as i32;
+        PsciError::check(status).err().unwrap_or(PsciError::Unknown(status))
+    }
+
+    /// Reset the machine.  Only returns if the firmware fails to.
+    pub fn system_reset(&self) -> PsciError {
+        let status = self.call(PSCI_SYSTEM_RESET, 0, 0, 0) as i32;
+        PsciError::check(status).err().unwrap_or(PsciError::Unknown(status))
+    }
+}
+
+/// Find PSCI in the device tree, and use it for port::power, if it's there.
+pub fn init(dt: &DeviceTree) {
+    let Some(mut psci) = Psci::from_dt(dt) else {
+        return;
+    };
+    psci.version = psci.psci_version();
+    if psci.version < Version::V0_2 {
+        println!("error:psci:init:unsupported version:{}", psci.version);
+        return;
+    }
+    println!("psci: version {} using {:?}", psci.version, psci.conduit);
+    if PSCI.set(psci).is_err() {
+        panic!("error:psci:init:already initialised");
+    }
+    port::power::set_power_ops(&POWER_OPS);
+}
+
+/// Return the firmware's PSCI, if there is one.
+pub fn get() -> Option<&'static Psci> {
+    PSCI.get()
+}
+
+fn reboot() -> ! {
+    if let Some(psci) = get() {
+        let err = psci.system_reset();
+        println!("error:psci:reboot:SYSTEM_RESET failed:{err:?}");
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+fn power_off() -> ! {
+    if let Some(psci) = get() {
+        let err = psci.system_off();
+        println!("error:psci:power_off:SYSTEM_OFF failed:{err:?}");
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(PsciError::check(0), Ok(()));
+        assert_eq!(PsciError::check(-4), Err(PsciError::AlreadyOn));
+        assert_eq!(PsciError::check(-9), Err(PsciError::InvalidAddress));
+        assert_eq!(PsciError::check(-10), Err(PsciError::Unknown(-10)));
+    }
+
+    #[test]
+    fn versions() {
+        let v1_1 = Version::from_raw(0x0001_0001);
+        assert_eq!(v1_1, Version { major: 1, minor: 1 });
+        assert_eq!(v1_1.to_string(), "1.1");
+        assert!(Version::from_raw(0x0000_0001) < Version::V0_2);
+    }
+
+    #[test]
+    fn rpi4_has_no_psci() {
+        let dt = DeviceTree::new(include_bytes!("../lib/bcm2711-rpi-4-b.dtb")).unwrap();
+        assert!(Psci::from_dt(&dt).is_none());
+    }
+}
@@ -6,21 +6,34 @@ use bitstruct::bitstruct;
 use core::fmt;
 use num_enum::TryFromPrimitive;
 use port::mem::{PAGE_SIZE_2M, PhysRange};
+use port::mmio_registers;
 
 // GPIO registers
 pub const GPFSEL1: usize = 0x04; // GPIO function select register 1
 pub const GPPUD: usize = 0x94; // GPIO pin pull up/down enable
 pub const GPPUDCLK0: usize = 0x98; // GPIO pin pull up/down enable clock 0
 
+// GPIO registers that are set up through Mmio
+mmio_registers! {
+    pub struct GpioReg {
+        GPPUD: u32, WriteOnly, GPPUD;         // GPIO pin pull up/down enable
+        GPPUDCLK0: u32, WriteOnly, GPPUDCLK0; // GPIO pin pull up/down enable clock 0
+    }
+}
+
 // UART 0 (PL011) registers
-pub const UART0_DR: usize = 0x00; // Data register
-pub const UART0_FR: usize = 0x18; // Flag register
-pub const UART0_IBRD: usize = 0x24; // Integer baud rate divisor
-pub const UART0_FBRD: usize = 0x28; // Fractional baud rate divisor
-pub const UART0_LCRH: usize = 0x2c; // Line control register
-pub const UART0_CR: usize = 0x30; // Control register
-pub const UART0_IMSC: usize = 0x38; // Interrupt mask set clear register
-pub const UART0_ICR: usize = 0x44; // Interrupt clear register
+mmio_registers! {
+    pub struct Pl011Reg {
+        DR: u32, ReadWrite, 0x00;   // Data register
+        FR: u32, ReadOnly, 0x18;    // Flag register
+        IBRD: u32, ReadWrite, 0x24; // Integer baud rate divisor
+        FBRD: u32, ReadWrite, 0x28; // Fractional baud rate divisor
+        LCRH: u32, ReadWrite, 0x2c; // Line control register
+        CR: u32, ReadWrite, 0x30;   // Control register
+        IMSC: u32, ReadWrite, 0x38; // Interrupt mask set clear register
+        ICR: u32, WriteOnly, 0x44;  // Interrupt clear register
+    }
+}
 
 // AUX registers, offset from aux_reg
 pub const AUX_ENABLE: usize = 0x04; // AUX enable register (Mini Uart, SPIs)
@@ -35,6 +48,43 @@ pub const AUX_MU_LSR: usize = 0x14; // Mini Uart line status register
 pub const AUX_MU_CNTL: usize = 0x20; // Mini Uart control register
 pub const AUX_MU_BAUD: usize = 0x28; // Mini Uart baudrate register
 
+/// Return the current core's id, from the Aff0 field of MPIDR_EL1, which
+/// numbers the cores from 0 on the Raspberry Pi and QEMU's virt machine.
+pub fn core_id() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let mpidr: usize;
+        core::arch::asm!("mrs {mpidr}, mpidr_el1", mpidr = out(reg) mpidr);
+        mpidr & 0xff
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Return the physical count of the generic timer, from CNTPCT_EL0.
+pub fn counter() -> u64 {
+    #[cfg(not(test))]
+    unsafe {
+        let count: u64;
+        core::arch::asm!("isb", "mrs {count}, cntpct_el0", count = out(reg) count);
+        count
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Return the frequency of the generic timer in Hz, from CNTFRQ_EL0.
+pub fn counter_freq() -> u64 {
+    #[cfg(not(test))]
+    unsafe {
+        let freq: u64;
+        core::arch::asm!("mrs {freq}, cntfrq_el0", freq = out(reg) freq);
+        freq
+    }
+    #[cfg(test)]
+    1
+}
+
 bitstruct! {
     #[derive(Copy, Clone)]
     pub struct MidrEl1(pub u64) {
@@ -187,6 +237,128 @@ impl EsrEl1IssInstructionAbort {
     }
 }
 
+bitstruct! {
+    #[derive(Copy, Clone)]
+    pub struct EsrEl1IssDataAbort(pub u32) {
+        dfsc: u8 = 0..6;
+        wnr: bool = 6;
+        fnv: bool = 10;
+        srt: u8 = 16..21;
+        sas: u8 = 22..24;
+        isv: bool = 24;
+    }
+}
+
+/// The access that caused an abort.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AbortAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The kind of fault given by the fault status code of an abort, with the
+/// level of the table walk where it applies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    Alignment,
+    Other(u8), // Any other fault status code
+}
+
+impl FaultStatus {
+    pub fn from_fsc(fsc: u8) -> FaultStatus {
+        match fsc {
+            0..=3 => Self::AddressSize(fsc),
+            4..=7 => Self::Translation(fsc - 4),
+            8..=11 => Self::AccessFlag(fsc - 8),
+            12..=15 => Self::Permission(fsc - 12),
+            0x21 => Self::Alignment,
+            _ => Self::Other(fsc),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddressSize(level) => write!(f, "address size fault, level {level}"),
+            Self::Translation(level) => write!(f, "translation fault, level {level}"),
+            Self::AccessFlag(level) => write!(f, "access flag fault, level {level}"),
+            Self::Permission(level) => write!(f, "permission fault, level {level}"),
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::Other(fsc) => write!(f, "fault status {fsc:#04x}"),
+        }
+    }
+}
+
+/// A data or instruction abort, decoded from ESR_EL1.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Abort {
+    pub access: AbortAccess,
+    pub status: FaultStatus,
+    pub from_user: bool,            // Taken from EL0 rather than EL1
+    pub far_valid: bool,            // FAR_EL1 holds the faulting address
+    pub transfer: Option<Transfer>, // Register loaded or stored, if the syndrome holds it
+}
+
+/// The register and size of the single register load or store that caused a
+/// data abort, given by the syndrome for most such instructions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Transfer {
+    pub reg: u8,     // General purpose register number, with 31 the zero register
+    pub size: usize, // Size of the access in bytes
+}
+
+impl Abort {
+    /// Decode the abort, or return None if the exception isn't an abort.
+    pub fn from_esr_el1(r: EsrEl1) -> Option<Abort> {
+        let ec = r.exception_class_enum().ok()?;
+        let from_user = matches!(
+            ec,
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::InstructionAbortLowerEl
+        );
+        match ec {
+            ExceptionClass::DataAbortSameEl | ExceptionClass::DataAbortLowerEl => {
+                let iss = EsrEl1IssDataAbort(r.iss());
+                Some(Abort {
+                    access: if iss.wnr() { AbortAccess::Write } else { AbortAccess::Read },
+                    status: FaultStatus::from_fsc(iss.dfsc()),
+                    from_user,
+                    far_valid: !iss.fnv(),
+                    transfer: iss.isv().then(|| Transfer { reg: iss.srt(), size: 1 << iss.sas() }),
+                })
+            }
+            ExceptionClass::InstructionAbortSameEl | ExceptionClass::InstructionAbortLowerEl => {
+                let iss = EsrEl1IssInstructionAbort(r.iss());
+                Some(Abort {
+                    access: AbortAccess::Execute,
+                    status: FaultStatus::from_fsc(iss.ifsc()),
+                    from_user,
+                    far_valid: !iss.fnv(),
+                    transfer: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, access) = match self.access {
+            AbortAccess::Read => ("data", "read"),
+            AbortAccess::Write => ("data", "write"),
+            AbortAccess::Execute => ("instruction", "execute"),
+        };
+        let el = if self.from_user { "EL0" } else { "EL1" };
+        write!(f, "{kind} abort from {el}: {} on {access}", self.status)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum InstructionFaultStatusCode {
@@ -254,4 +426,30 @@ mod tests {
             InstructionFaultStatusCode::TranslationFaultLevel0
         );
     }
+
+    #[test]
+    fn test_decode_aborts() {
+        // Write to an unmapped page at EL1
+        let abort = Abort::from_esr_el1(EsrEl1(0x96000047)).unwrap();
+        assert_eq!(abort.access, AbortAccess::Write);
+        assert_eq!(abort.status, FaultStatus::Translation(3));
+        assert!(!abort.from_user && abort.far_valid);
+        assert_eq!(format!("{abort}"), "data abort from EL1: translation fault, level 3 on write");
+        assert_eq!(abort.transfer, None);
+
+        // Store of w1 to a read-only page at EL1, with a valid syndrome
+        let abort = Abort::from_esr_el1(EsrEl1(0x9781004f)).unwrap();
+        assert_eq!(abort.status, FaultStatus::Permission(3));
+        assert_eq!(abort.transfer, Some(Transfer { reg: 1, size: 4 }));
+
+        // Read of a kernel page from EL0
+        let abort = Abort::from_esr_el1(EsrEl1(0x9200000f)).unwrap();
+        assert_eq!(format!("{abort}"), "data abort from EL0: permission fault, level 3 on read");
+
+        assert_eq!(
+            format!("{}", Abort::from_esr_el1(EsrEl1(0x86000004)).unwrap()),
+            "instruction abort from EL1: translation fault, level 0 on execute"
+        );
+        assert_eq!(Abort::from_esr_el1(EsrEl1(0x56000000)), None);
+    }
 }
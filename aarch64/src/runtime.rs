@@ -5,20 +5,68 @@ extern crate alloc;
 use alloc::alloc::Layout;
 use core::panic::PanicInfo;
 
+use port::lockdebug::HeldLocks;
 #[cfg(not(test))]
-use port::println;
+use port::{early_print, early_println, print, println};
 
 // TODO
 //  - Add qemu integration test
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    println!("{}\n", info);
+    if !port::devcons::console_ready() || port::devcons::locked_here() {
+        // Too early for the console, or the panic is from inside a print, so
+        // this is all we can say, after anything printed before the console
+        // came up
+        port::devcons::dump_early_output();
+        early_println!("{}\n", info);
+        early_print!("{HeldLocks}");
+    } else {
+        // Write out anything buffered, and make sure nothing more is held back
+        port::devcons::unbuffer();
+        println!("{}\n", info);
+        port::ktest::report_panic();
+        crate::trap::print_backtrace(frame_pointer());
+        print!("{HeldLocks}");
+        match crate::pagealloc::try_stats() {
+            Some(stats) => println!("pagealloc: {}", stats),
+            None => println!("pagealloc: stats unavailable, allocator locked"),
+        }
+        match crate::allocator::try_stats() {
+            Some(stats) => println!("heap: {}", stats),
+            None => println!("heap: stats unavailable, allocator locked"),
+        }
+        crate::kmem::report_memory_map();
+        #[cfg(feature = "dump_pagetables_on_panic")]
+        crate::vmdebug::print_mappings(crate::vm::RootPageTableType::Kernel);
+    }
+    if cfg!(feature = "qemu_test") {
+        port::qemu::exit_qemu(false);
+    }
+    if cfg!(feature = "reboot_on_panic") {
+        port::power::reboot();
+    }
 
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+/// Return the frame pointer of the caller's frame.
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { core::arch::asm!("mov {fp}, x29", fp = out(reg) fp) };
+    fp
+}
+
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    panic!("oom");
+fn oom(layout: Layout) -> ! {
+    // Show where memory has gone, unless printing is what ran out
+    if port::devcons::console_ready() && !port::devcons::locked_here() {
+        crate::kmem::report_memory_usage();
+    }
+    let (size, align) = (layout.size(), layout.align());
+    match crate::allocator::try_stats() {
+        Some(stats) => panic!("oom: allocating {size} bytes, align {align}, heap: {stats}"),
+        None => panic!("oom: allocating {size} bytes, align {align}, heap locked"),
+    }
 }
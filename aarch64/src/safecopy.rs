@@ -0,0 +1,131 @@
+/// safecopy copies to and from addresses that may not be mapped, returning
+/// the address that faulted rather than panicking, e.g. for probing addresses
+/// from the debug prompt, and later for copying to and from user space.  The
+/// instructions that may abort are listed in the exception fixup table, each
+/// with where to resume if it does, which the trap handler looks up.
+use port::mem::VirtAddr;
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("safecopy.S"));
+
+/// A copy faulted at `addr`.
+#[derive(Debug, PartialEq)]
+pub struct Fault {
+    pub addr: VirtAddr,
+}
+
+/// An entry in the exception fixup table.
+#[repr(C)]
+struct Fixup {
+    fault_pc: usize, // Instruction that may abort
+    fixup_pc: usize, // Where to resume if it does
+}
+
+/// What safecopy returns, in x0 and x1.
+#[repr(C)]
+struct CopyResult {
+    faulted: u64,
+    fault_addr: u64, // Only if faulted
+}
+
+#[cfg(not(test))]
+unsafe extern "C" {
+    fn safecopy(dst: *mut u8, src: *const u8, len: usize) -> CopyResult;
+}
+
+/// Host tests can't fault, so just copy.
+#[cfg(test)]
+unsafe fn safecopy(dst: *mut u8, src: *const u8, len: usize) -> CopyResult {
+    unsafe { core::ptr::copy(src, dst, len) };
+    CopyResult { faulted: 0, fault_addr: 0 }
+}
+
+/// Return the exception fixup table, from the .extable section.
+#[cfg(not(test))]
+fn fixups() -> &'static [Fixup] {
+    // These map to definitions in kernel.ld
+    unsafe extern "C" {
+        static extable: [u64; 0];
+        static eextable: [u64; 0];
+    }
+    // Safety: safecopy.S puts pairs of addresses between extable and
+    // eextable, which are never written.
+    unsafe {
+        let start = extable.as_ptr() as *const Fixup;
+        let end = eextable.as_ptr() as *const Fixup;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+#[cfg(test)]
+fn fixups() -> &'static [Fixup] {
+    &[]
+}
+
+fn find_fixup(table: &[Fixup], pc: usize) -> Option<usize> {
+    table.iter().find(|fixup| fixup.fault_pc == pc).map(|fixup| fixup.fixup_pc)
+}
+
+/// Return where to resume after an abort at `pc`, if the instruction there
+/// is in the exception fixup table.
+pub fn fixup_for(pc: usize) -> Option<usize> {
+    find_fixup(fixups(), pc)
+}
+
+fn to_result(result: CopyResult) -> Result<(), Fault> {
+    if result.faulted == 0 {
+        Ok(())
+    } else {
+        Err(Fault { addr: VirtAddr::new(result.fault_addr as usize) })
+    }
+}
+
+/// Copy `dst.len()` bytes from `src` into `dst`, or return where the copy
+/// faulted, in which case only the bytes before the fault are copied.
+#[allow(dead_code)]
+pub fn copy_from(dst: &mut [u8], src: VirtAddr) -> Result<(), Fault> {
+    // Safety: only dst is written, and reading src can't abort the kernel.
+    to_result(unsafe { safecopy(dst.as_mut_ptr(), src.addr() as *const u8, dst.len()) })
+}
+
+/// Copy `src` to `dst`, or return where the copy faulted, in which case only
+/// the bytes before the fault are copied.
+///
+/// # Safety
+///
+/// Whatever is mapped at `dst` mustn't be memory the kernel relies on, e.g.
+/// it must be user memory, as it's overwritten.
+#[allow(dead_code)]
+pub unsafe fn copy_to(dst: VirtAddr, src: &[u8]) -> Result<(), Fault> {
+    // Safety: the caller guarantees writing dst is safe, and writing it
+    // can't abort the kernel.
+    to_result(unsafe { safecopy(dst.addr() as *mut u8, src.as_ptr(), src.len()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixup_lookup() {
+        let table = [
+            Fixup { fault_pc: 0x1000, fixup_pc: 0x1100 },
+            Fixup { fault_pc: 0x1004, fixup_pc: 0x1100 },
+        ];
+        assert_eq!(find_fixup(&table, 0x1004), Some(0x1100));
+        assert_eq!(find_fixup(&table, 0x1008), None);
+        assert_eq!(fixup_for(0x1000), None);
+    }
+
+    #[test]
+    fn copies_both_ways() {
+        let src = [1u8, 2, 3, 4];
+        let mut dst = [0u8; 4];
+        assert_eq!(copy_from(&mut dst, VirtAddr::new(src.as_ptr().addr())), Ok(()));
+        assert_eq!(dst, src);
+
+        let mut dst = [0u8; 4];
+        assert_eq!(unsafe { copy_to(VirtAddr::new(dst.as_mut_ptr().addr()), &src) }, Ok(()));
+        assert_eq!(dst, src);
+    }
+}
@@ -0,0 +1,57 @@
+/// Semihosting lets the kernel ask the emulator or debugger it's running
+/// under to do things on its behalf, such as writing to its console, or
+/// exiting.  It needs no device setup at all, so it's useful for automated
+/// tests under QEMU, which must be run with `-semihosting`.
+/// https://github.com/ARM-software/abi-aa/blob/main/semihosting/semihosting.rst
+use port::devcons::{Console, Uart};
+
+const SYS_WRITEC: usize = 0x03;
+const SYS_WRITE0: usize = 0x04;
+const SYS_EXIT: usize = 0x18;
+
+/// Reason for SYS_EXIT: the program finished, with an exit code.
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x2_0026;
+
+/// Make the semihosting call `op`, with `arg` usually pointing to a block of
+/// parameters.
+#[allow(unused_variables)]
+unsafe fn call(op: usize, arg: usize) -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let ret;
+        core::arch::asm!("hlt #0xf000", inout("x0") op => ret, in("x1") arg, options(nostack));
+        ret
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Console output through semihosting.
+pub struct Semihosting;
+
+impl Uart for Semihosting {
+    fn putb(&self, b: u8) {
+        unsafe { call(SYS_WRITEC, &b as *const u8 as usize) };
+    }
+}
+
+/// Write a nul terminated string in one call, rather than byte by byte.
+#[allow(dead_code)]
+pub fn write0(s: &core::ffi::CStr) {
+    unsafe { call(SYS_WRITE0, s.as_ptr() as usize) };
+}
+
+/// Stop QEMU with exit code 0 on success, or 1 otherwise.
+fn exit(success: bool) -> ! {
+    let block: [usize; 2] = [ADP_STOPPED_APPLICATION_EXIT, if success { 0 } else { 1 }];
+    unsafe { call(SYS_EXIT, block.as_ptr() as usize) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Use semihosting for the console, and for port::qemu::exit_qemu.
+pub fn init() {
+    Console::new(|| &Semihosting);
+    port::qemu::set_exit_fn(exit);
+}
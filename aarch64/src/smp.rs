@@ -0,0 +1,250 @@
+/// smp starts the secondary cores, which the firmware leaves waiting to be
+/// released, using the enable-method each core's node in /cpus gives:
+/// either writing the entry point to its spin-table release address and
+/// waking it with SEV, or asking the firmware to start it with PSCI CPU_ON
+/// (see psci).
+///
+/// Each core starts at secondary_start in l.S, with its MMU and caches off,
+/// so anything it reads before enabling its MMU must be in memory, not just
+/// the boot core's cache: the release address, SECONDARY_TTBR1, which holds
+/// the root of the kernel page tables, and SECONDARY_TTBR0, the root of the
+/// identity map the core turns on its MMU with.  These are cleaned to the
+/// point of coherency before the core is released.
+use crate::dmap;
+use crate::gic;
+use crate::kmem::{self, secondary_start_physaddr};
+use crate::param::MAX_CORES;
+use crate::psci;
+use crate::registers;
+use crate::timer;
+use crate::trap;
+use crate::vm::AddressSpace;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use port::cache;
+use port::fdt::DeviceTree;
+use port::mem::{MapFlags, MemError, PhysAddr, PhysRange, VirtAddr};
+use port::oncelock::OnceLock;
+use port::percpu::PerCpu;
+use port::time::Deadline;
+use port::{info, println};
+
+/// How long to wait for the secondary cores to come up.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Root of the kernel page tables, read by secondary_start in l.S.
+#[unsafe(export_name = "secondary_ttbr1")]
+static SECONDARY_TTBR1: AtomicU64 = AtomicU64::new(0);
+
+/// Root of the identity map secondary cores run secondary_start through as
+/// they turn on their MMU, read by secondary_start in l.S.
+#[unsafe(export_name = "secondary_ttbr0")]
+static SECONDARY_TTBR0: AtomicU64 = AtomicU64::new(0);
+
+/// The user address space the boot core has active, for the secondary cores
+/// to activate in place of the early identity map.
+static USER_SPACE: OnceLock<AddressSpace> = OnceLock::new();
+
+/// Whether each core is running in secondary_main.  The boot core is online
+/// from the start.
+static ONLINE: PerCpu<AtomicBool, MAX_CORES> =
+    PerCpu::new([const { AtomicBool::new(false) }; MAX_CORES]);
+
+/// How a core is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EnableMethod {
+    SpinTable(PhysAddr), // The release address
+    Psci,
+}
+
+/// A core from /cpus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cpu {
+    core: usize,                         // Core id, the Aff0 field of the MPIDR
+    mpidr: u64,                          // The reg property, the affinity fields of the MPIDR
+    enable_method: Option<EnableMethod>, // None if it's not one we support
+}
+
+/// Return the enabled cores in /cpus, in the order they're listed.
+fn cpus(dt: &DeviceTree) -> Vec<Cpu> {
+    let Some(cpus) = dt.find_by_path("/cpus") else {
+        return Vec::new();
+    };
+    dt.children(&cpus)
+        .filter(|node| {
+            let device_type = dt.property(node, "device_type");
+            device_type.and_then(|p| dt.property_value_as_str(&p)) == Some("cpu")
+        })
+        .filter(|node| dt.is_enabled(node))
+        .filter_map(|node| {
+            let mpidr = dt.property_reg_iter(node).next()?.addr;
+            let enable_method = match dt
+                .property(&node, "enable-method")
+                .and_then(|p| dt.property_value_as_str(&p))
+            {
+                Some("spin-table") => {
+                    // One or two cells, most significant first
+                    let release_addr = dt.property(&node, "cpu-release-addr").map(|p| {
+                        dt.property_value_as_u32_iter(&p).fold(0, |a, c| (a << 32) | c as u64)
+                    });
+                    release_addr.map(|addr| EnableMethod::SpinTable(PhysAddr::new(addr)))
+                }
+                Some("psci") => Some(EnableMethod::Psci),
+                _ => None,
+            };
+            Some(Cpu { core: (mpidr & 0xff) as usize, mpidr, enable_method })
+        })
+        .collect()
+}
+
+/// Release a core waiting on `release_addr` by writing the entry point
+/// there, cleaning it so the core sees it with its caches off, and waking it.
+fn start_spin_table(release_addr: PhysAddr, entry: PhysAddr) -> bool {
+    let release = match dmap::dmap_range(&PhysRange::with_pa_len(release_addr, 8)) {
+        Ok(release) => release,
+        Err(err) => {
+            println!("error:smp:start_spin_table:release address not in RAM:{release_addr}: {err}");
+            return false;
+        }
+    };
+    if let Err(err) = dmap::write_phys(release_addr, entry.addr()) {
+        println!("error:smp:start_spin_table:can't write release address {release_addr}: {err}");
+        return false;
+    }
+    cache::clean(&release);
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("sev");
+    }
+    true
+}
+
+/// Start the core with `mpidr` with PSCI CPU_ON.
+fn start_psci(mpidr: u64, entry: PhysAddr) -> bool {
+    let Some(psci) = psci::get() else {
+        println!("error:smp:start_psci:no psci for core:{mpidr:#x}");
+        return false;
+    };
+    match psci.cpu_on(mpidr, entry, 0) {
+        Ok(()) => true,
+        Err(err) => {
+            println!("error:smp:start_psci:CPU_ON failed for core:{mpidr:#x} err:{err:?}");
+            false
+        }
+    }
+}
+
+/// Set up the identity map secondary cores start with: just boottext, which
+/// holds secondary_start, rather than the GiB l.S maps for the boot core.  It
+/// stays mapped, so cores can be started again later.
+fn init_trampoline_space() -> Result<AddressSpace, MemError> {
+    let boottext = kmem::boottext_range();
+    let mut space = AddressSpace::new()?;
+    let va = VirtAddr::new(boottext.start().addr() as usize);
+    space.map_range(va, &boottext, MapFlags::RX)?;
+    kmem::record_identity_window("secondary trampoline", boottext);
+    Ok(space)
+}
+
+/// Start the cores in /cpus other than this one, and wait for them to come
+/// up, or time out.  Must be called once, after the kernel page tables, the
+/// direct map, and the console are set up.
+pub fn start_secondaries(dt: &DeviceTree) {
+    let boot_core = registers::core_id();
+    ONLINE.get_for(boot_core).store(true, Ordering::Release);
+
+    // Share what the cores need before they're released
+    if USER_SPACE.set(AddressSpace::user()).is_err() {
+        panic!("error:smp:start_secondaries:called more than once");
+    }
+    SECONDARY_TTBR1.store(AddressSpace::kernel().root().addr(), Ordering::Release);
+    cache::clean(&cache::range_of(&SECONDARY_TTBR1));
+    match init_trampoline_space() {
+        Ok(space) => SECONDARY_TTBR0.store(space.root().addr(), Ordering::Release),
+        Err(err) => {
+            println!("error:smp:start_secondaries:can't map trampoline, not starting cores: {err}");
+            return;
+        }
+    }
+    cache::clean(&cache::range_of(&SECONDARY_TTBR0));
+
+    let entry = secondary_start_physaddr();
+    let mut expected = 0;
+    for cpu in cpus(dt) {
+        if cpu.core == boot_core {
+            continue;
+        }
+        if cpu.core >= MAX_CORES {
+            println!("error:smp:start_secondaries:core {} beyond MAX_CORES, not started", cpu.core);
+            continue;
+        }
+        let started = match cpu.enable_method {
+            Some(EnableMethod::SpinTable(release_addr)) => start_spin_table(release_addr, entry),
+            Some(EnableMethod::Psci) => start_psci(cpu.mpidr, entry),
+            None => {
+                println!("error:smp:start_secondaries:core {} has no enable-method", cpu.core);
+                false
+            }
+        };
+        if started {
+            expected += 1;
+        }
+    }
+
+    // Wait for them, counting the boot core as up
+    let online = || ONLINE.iter().filter(|up| up.load(Ordering::Acquire)).count() - 1;
+    let deadline = Deadline::after(STARTUP_TIMEOUT);
+    while online() < expected {
+        if deadline.expired() {
+            println!(
+                "error:smp:start_secondaries:timed out after {STARTUP_TIMEOUT:?} waiting for \
+                 cores, {} of {expected} up",
+                online()
+            );
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    info!("{} cores up", expected + 1);
+}
+
+/// Entered by each secondary core from l.S, on its own stack, with the MMU
+/// on and running in the higher half.
+#[unsafe(no_mangle)]
+pub extern "C" fn secondary_main(core: usize) -> ! {
+    // Replace the trampoline identity map with the boot core's user space
+    if let Some(user_space) = USER_SPACE.get() {
+        unsafe { user_space.activate() };
+    }
+    trap::init();
+    gic::init_core();
+    timer::init_core();
+    ONLINE.get().store(true, Ordering::Release);
+    println!("core {core} up");
+
+    #[allow(clippy::empty_loop)]
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("wfe");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rpi4_cpus_use_spin_table() {
+        let dtb = include_bytes!("../lib/bcm2711-rpi-4-b.dtb");
+        let dt = DeviceTree::new(dtb).unwrap();
+        let cpus = cpus(&dt);
+        assert_eq!(cpus.len(), 4);
+        for (i, (cpu, release_addr)) in cpus.iter().zip([0xd8, 0xe0, 0xe8, 0xf0]).enumerate() {
+            let method = EnableMethod::SpinTable(PhysAddr::new(release_addr));
+            assert_eq!(*cpu, Cpu { core: i, mpidr: i as u64, enable_method: Some(method) });
+        }
+    }
+}
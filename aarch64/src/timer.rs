@@ -0,0 +1,160 @@
+/// timer ticks periodically using the virtual timer of the generic timer,
+/// which each core has, and which interrupts through a PPI: the third in the
+/// interrupts property of the arm,armv8-timer node.  The timer counts down
+/// from CNTV_TVAL_EL0, and interrupts at 0 until it's reloaded.
+///
+/// Alarms use the non-secure physical timer, the second PPI, which
+/// interrupts once the physical count reaches CNTP_CVAL_EL0, until the
+/// compare value is moved on, or the timer disabled.
+use crate::gic;
+use crate::param::{MAX_ALARMS, MAX_CORES};
+use crate::registers;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use port::alarm::{AlarmError, AlarmFn, AlarmHandle, AlarmTimer, Alarms};
+use port::fdt::DeviceTree;
+use port::time::{self, Deadline};
+use port::{info, println};
+
+/// Ticks per second.
+const TICK_HZ: u64 = 100;
+
+/// Index of the virtual timer in the interrupts property, after the secure
+/// and non-secure physical timers.
+const VIRTUAL_TIMER: usize = 2;
+
+/// Index of the non-secure physical timer in the interrupts property.
+const PHYSICAL_TIMER: usize = 1;
+
+/// Timer ticks since init, on any core.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counter ticks between timer ticks.
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Start the virtual timer counting down `interval` counter ticks, with its
+/// interrupt unmasked.
+#[allow(unused_variables)]
+fn start(interval: u64) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "msr cntv_tval_el0, {interval}",
+            "msr cntv_ctl_el0, {enable}",
+            "isb",
+            interval = in(reg) interval,
+            enable = in(reg) 1u64,
+        );
+    }
+}
+
+fn tick(_intid: u32) {
+    start(INTERVAL.load(Ordering::Relaxed));
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the number of timer ticks so far.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+static ALARMS: Alarms<MAX_CORES, MAX_ALARMS> = Alarms::new();
+
+/// INTID of the physical timer, for enabling it on each core, or 0 if
+/// there's none.
+static ALARM_INTID: AtomicU32 = AtomicU32::new(0);
+
+static PHYSICAL_TIMER_ALARMS: AlarmTimer = AlarmTimer { arm: arm_alarm, disarm: disarm_alarm };
+
+/// Interrupt once the physical count reaches the deadline.
+#[allow(unused_variables)]
+fn arm_alarm(deadline: Deadline) {
+    let compare = time::nanos_to_ticks(deadline.nanos(), registers::counter_freq());
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "msr cntp_cval_el0, {compare}",
+            "msr cntp_ctl_el0, {enable}",
+            "isb",
+            compare = in(reg) compare,
+            enable = in(reg) 1u64,
+        );
+    }
+}
+
+fn disarm_alarm() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("msr cntp_ctl_el0, xzr", "isb");
+    }
+}
+
+fn alarm(_intid: u32) {
+    ALARMS.fire();
+}
+
+/// Call `callback` from this core's timer interrupt, with interrupts
+/// disabled, once `deadline` has passed.
+#[allow(dead_code)]
+pub fn set_alarm(deadline: Deadline, callback: AlarmFn) -> Result<AlarmHandle, AlarmError> {
+    ALARMS.set_alarm(deadline, callback)
+}
+
+/// Cancel the alarm with `handle`, returning true if it hadn't yet fired.
+#[allow(dead_code)]
+pub fn cancel_alarm(handle: AlarmHandle) -> bool {
+    ALARMS.cancel(handle)
+}
+
+/// Enable the physical timer's interrupt on this core, so alarms set on it
+/// fire, on a secondary core.
+pub fn init_core() {
+    let intid = ALARM_INTID.load(Ordering::Relaxed);
+    if intid != 0 {
+        disarm_alarm();
+        gic::enable_irq(intid);
+    }
+}
+
+/// Start ticking on this core, and check ticks arrive, if there's a GIC to
+/// deliver them.
+pub fn init(dt: &DeviceTree) {
+    if !gic::available() {
+        return;
+    }
+    let Some(node) = ["arm,armv8-timer", "arm,armv7-timer"]
+        .into_iter()
+        .find_map(|compatible| dt.find_compatible(compatible).next())
+    else {
+        println!("error:timer:init:no generic timer in device tree");
+        return;
+    };
+    let interrupts = gic::interrupts(dt, &node);
+    let Some(&(intid, trigger)) = interrupts.get(VIRTUAL_TIMER) else {
+        println!("error:timer:init:no virtual timer interrupt");
+        return;
+    };
+
+    let interval = registers::counter_freq() / TICK_HZ;
+    INTERVAL.store(interval, Ordering::Relaxed);
+    gic::register_handler(intid, tick);
+    gic::set_trigger(intid, trigger);
+    start(interval);
+    gic::enable_irq(intid);
+
+    match interrupts.get(PHYSICAL_TIMER) {
+        Some(&(alarm_intid, alarm_trigger)) => {
+            gic::register_handler(alarm_intid, alarm);
+            gic::set_trigger(alarm_intid, alarm_trigger);
+            ALARM_INTID.store(alarm_intid, Ordering::Relaxed);
+            init_core();
+            ALARMS.set_timer(&PHYSICAL_TIMER_ALARMS);
+        }
+        None => println!("error:timer:init:no physical timer interrupt, so no alarms"),
+    }
+
+    // Ticking proves interrupts are delivered, acknowledged and ended
+    let start_ticks = ticks();
+    port::time::delay(Duration::from_millis(100));
+    info!("timer: {} ticks in 100ms at {TICK_HZ}Hz, intid {intid}", ticks() - start_ticks);
+}
@@ -1,10 +1,50 @@
-use crate::registers::EsrEl1;
-use port::println;
+use crate::gic;
+use crate::kmem::{
+    KERNEL_ADDR_MAP, KernelStack, kernel_stacks, physrange_as_virtrange_offset_from_kzero,
+};
+use crate::registers::{Abort, EsrEl1};
+#[cfg(debug_assertions)]
+use crate::registers::{AbortAccess, FaultStatus};
+use crate::safecopy;
+#[cfg(debug_assertions)]
+use crate::watch;
+use port::addrmap::AddrKind;
+use port::backtrace::{Backtrace, FrameLayout};
+use port::devcons;
+use port::interrupts::{self, Interrupts};
+use port::mem::{VirtAddr, VirtRange};
+use port::symbols::Symbolized;
+use port::{print, println};
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("trap.S"));
 
+/// Masks IRQs on the current core with DAIF.
+struct Daif;
+
+impl Interrupts for Daif {
+    fn disable() -> usize {
+        #[cfg(not(test))]
+        unsafe {
+            let daif: usize;
+            core::arch::asm!("mrs {daif}, daif", "msr DAIFSet, #2", daif = out(reg) daif);
+            daif
+        }
+        #[cfg(test)]
+        0
+    }
+
+    #[allow(unused_variables)]
+    fn restore(state: usize) {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("msr daif, {daif}", daif = in(reg) state);
+        }
+    }
+}
+
 pub fn init() {
+    interrupts::init::<Daif>();
     #[cfg(not(test))]
     unsafe {
         // Set up a vector table for any exception that is taken to EL1, then enable IRQ
@@ -17,6 +57,12 @@ pub fn init() {
     }
 }
 
+// Interrupt types, as passed by trap.S, of the IRQs it returns from, and of
+// synchronous exceptions from EL1, which it returns from if they're fixed up
+const IRQ_EL1H: u64 = 5;
+const IRQ_EL0_64: u64 = 9;
+const SYNC_EL1H: u64 = 4;
+
 /// Register frame at time interrupt was taken
 #[derive(Debug)]
 #[repr(C, align(16))]
@@ -56,6 +102,18 @@ pub struct TrapFrame {
     elr_el1: u64,
     far_el1: u64,
     interrupt_type: u64,
+    spsr_el1: u64,
+}
+
+impl TrapFrame {
+    /// Return general purpose register `n`, or 0 for 31, which is the zero
+    /// register where a load or store gives it.
+    #[cfg(debug_assertions)]
+    fn register(&self, n: u8) -> u64 {
+        // Safety: the frame starts with x0 to x30, in order.
+        let regs = unsafe { &*(self as *const TrapFrame as *const [u64; 31]) };
+        regs.get(n as usize).copied().unwrap_or(0)
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -64,15 +122,144 @@ pub extern "C" fn trap_unsafe(frame: *mut TrapFrame) {
 }
 
 fn trap(frame: &mut TrapFrame) {
+    if matches!(frame.interrupt_type, IRQ_EL1H | IRQ_EL0_64) {
+        gic::handle_irq();
+        return;
+    }
+    if frame.interrupt_type == SYNC_EL1H && resume_at_fixup(frame) {
+        return;
+    }
+    #[cfg(debug_assertions)]
+    if watched_write(frame) {
+        return;
+    }
+
     if frame.esr_el1.ec() == 0x15 {
         // Handle syscall
         let syscallid = frame.esr_el1.iss();
         println!("Syscall {syscallid}");
+    } else if let Some(abort) = Abort::from_esr_el1(frame.esr_el1) {
+        report_abort(frame, &abort);
+        print_backtrace(frame.frame_pointer as usize);
     } else {
         println!("Unrecognised interrupt");
+        println!("  pc: {}", Symbolized(frame.elr_el1 as usize));
+        print_backtrace(frame.frame_pointer as usize);
     }
+    devcons::flush();
 
     loop {
         core::hint::spin_loop();
     }
 }
+
+/// If the exception is an abort at an instruction in the exception fixup
+/// table, set the frame to resume at its fixup, with the fault address in x1,
+/// and return true.
+fn resume_at_fixup(frame: &mut TrapFrame) -> bool {
+    if Abort::from_esr_el1(frame.esr_el1).is_none() {
+        return false;
+    }
+    let Some(fixup) = safecopy::fixup_for(frame.elr_el1 as usize) else {
+        return false;
+    };
+    frame.elr_el1 = fixup as u64;
+    frame.x1 = frame.far_el1;
+    true
+}
+
+/// If the exception is a permission fault on a kernel write to a page watched
+/// with watch::protect_page, report it, and return true if the write should
+/// be retried.
+#[cfg(debug_assertions)]
+fn watched_write(frame: &TrapFrame) -> bool {
+    let Some(abort) = Abort::from_esr_el1(frame.esr_el1) else {
+        return false;
+    };
+    if abort.access != AbortAccess::Write
+        || !matches!(abort.status, FaultStatus::Permission(_))
+        || abort.from_user
+        || !abort.far_valid
+    {
+        return false;
+    }
+    let value = abort.transfer.map(|transfer| {
+        let mask = u64::MAX >> (64 - 8 * transfer.size);
+        (frame.register(transfer.reg) & mask, transfer.size)
+    });
+    watch::write_fault(VirtAddr::new(frame.far_el1 as usize), frame.elr_el1 as usize, value)
+}
+
+/// Print the decoded abort, and what the faulting address is, as far as the
+/// kernel address map knows.  Nothing here takes locks, since the abort may
+/// have been taken while holding one.
+fn report_abort(frame: &TrapFrame, abort: &Abort) {
+    println!("{abort}");
+    println!("  pc: {}", Symbolized(frame.elr_el1 as usize));
+    if !abort.far_valid {
+        println!("  fault address: unknown");
+        return;
+    }
+    let fault_va = VirtAddr::new(frame.far_el1 as usize);
+    println!("  fault address: {fault_va:#}");
+
+    if let Some(stack) = overflowed_stack(fault_va) {
+        let stack_range = physrange_as_virtrange_offset_from_kzero(&stack.range);
+        let guard_range = physrange_as_virtrange_offset_from_kzero(&stack.guard_range());
+        println!("  kernel stack overflow on core {}", stack.core);
+        println!("  stack: {stack_range} guard: {guard_range}");
+        return;
+    }
+
+    let Some(range) = KERNEL_ADDR_MAP.find(fault_va) else {
+        println!("  wild address: not in any range known to the kernel");
+        return;
+    };
+    let what = match range.kind {
+        AddrKind::KernelSection => "in kernel section",
+        AddrKind::StackGuard => "in kernel stack guard page (stack overflow)",
+        AddrKind::DirectMap => "in direct map, but not RAM (use of a freed or bogus frame?)",
+        AddrKind::DirectMapRam => "in direct map of RAM",
+        AddrKind::Vmap => "in vmap region (device registers)",
+    };
+    println!("  {what} {}: {}", range.name, range.virt);
+    if let Some((name, offset)) = port::symbols::resolve(fault_va) {
+        println!("  in function {name}+{offset:#x}");
+    }
+    if let Some(phys) = &range.phys {
+        let offset = fault_va.addr() - range.virt.start().addr();
+        println!("  physical: {} in {phys}", phys.start() + offset as u64);
+    }
+}
+
+/// Print the return addresses in the chain of frame records from the frame
+/// pointer `fp`, which must be on a kernel stack, as the records are only
+/// followed while they're on the same stack.
+pub fn print_backtrace(fp: usize) {
+    let stack = kernel_stacks().into_iter().find_map(|stack| {
+        // The guard page isn't mapped, so leave it out.  The stack is reached
+        // both through the KZERO mapping and through its alias.
+        let guard = physrange_as_virtrange_offset_from_kzero(&stack.guard_range());
+        let kzero = physrange_as_virtrange_offset_from_kzero(&stack.range);
+        let aliases = [
+            VirtRange(guard.end()..kzero.end()),
+            VirtRange(stack.guard_virt_range().end()..stack.virt_range().end()),
+        ];
+        aliases.into_iter().find(|usable| usable.start().addr() <= fp && fp <= usable.end().addr())
+    });
+    match stack {
+        // Safety: kernel stacks are mapped, apart from the guard pages.
+        Some(stack) => print!("{}", unsafe { Backtrace::new(fp, stack, FrameLayout::AARCH64) }),
+        None => println!("backtrace: frame pointer {fp:#x} not on a kernel stack"),
+    }
+}
+
+/// If the fault address is in the guard page beneath a kernel stack, return
+/// that stack.  The trap handler runs on its own stack, so it can still
+/// report the overflow.
+fn overflowed_stack(fault_va: VirtAddr) -> Option<KernelStack> {
+    kernel_stacks().into_iter().find(|stack| {
+        physrange_as_virtrange_offset_from_kzero(&stack.guard_range()).0.contains(&fault_va)
+            || stack.guard_virt_range().0.contains(&fault_va)
+    })
+}
@@ -1,6 +1,7 @@
-use port::devcons::Uart;
-use port::fdt::DeviceTree;
-use port::mem::VirtRange;
+use port::devcons::{Uart, count_rx_error};
+use port::fdt::{DeviceTree, Node};
+use port::mem::{PhysRange, VirtRange};
+use port::mmio::{MapDeviceError, map_device_mmio};
 
 use crate::io::{delay, read_reg, write_or_reg, write_reg};
 use crate::registers::{
@@ -19,40 +20,29 @@ pub struct MiniUart {
 
 #[allow(dead_code)]
 impl MiniUart {
-    pub fn new(dt: &DeviceTree, mmio_virt_offset: usize) -> MiniUart {
+    /// Find the blocks the `uart` node depends on in the device tree, and map
+    /// their registers and the uart's with `map`.
+    pub fn new<E>(
+        dt: &DeviceTree,
+        uart: Node,
+        map: impl Fn(&PhysRange) -> Result<VirtRange, E>,
+    ) -> Result<MiniUart, MapDeviceError<E>> {
         // Bcm2835 and bcm2711 are essentially the same for our needs here.
         // If fdt.rs supported aliases well, we could try to just look up 'gpio'.
-        let gpio_range = VirtRange::from(
-            &dt.find_compatible("brcm,bcm2835-gpio")
-                .next()
-                .or_else(|| dt.find_compatible("brcm,bcm2711-gpio").next())
-                .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                .and_then(|reg| reg.regblock())
-                .unwrap()
-                .with_offset(mmio_virt_offset as u64),
-        );
+        let gpio = dt
+            .find_compatible("brcm,bcm2835-gpio")
+            .next()
+            .or_else(|| dt.find_compatible("brcm,bcm2711-gpio").next())
+            .unwrap();
+        let gpio_range = map_device_mmio(dt, gpio, 0, &map)?;
 
         // Find a compatible aux
-        let aux_range = VirtRange::from(
-            &dt.find_compatible("brcm,bcm2835-aux")
-                .next()
-                .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                .and_then(|reg| reg.regblock())
-                .unwrap()
-                .with_offset(mmio_virt_offset as u64),
-        );
+        let aux = dt.find_compatible("brcm,bcm2835-aux").next().unwrap();
+        let aux_range = map_device_mmio(dt, aux, 0, &map)?;
 
-        // Find a compatible miniuart
-        let miniuart_range = VirtRange::from(
-            &dt.find_compatible("brcm,bcm2835-aux-uart")
-                .next()
-                .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-                .and_then(|reg| reg.regblock())
-                .unwrap()
-                .with_offset(mmio_virt_offset as u64),
-        );
+        let miniuart_range = map_device_mmio(dt, uart, 0, &map)?;
 
-        MiniUart { gpio_range, aux_range, miniuart_range }
+        Ok(MiniUart { gpio_range, aux_range, miniuart_range })
     }
 
     pub fn init(&self) {
@@ -90,7 +80,7 @@ impl MiniUart {
         //write_reg(self.miniuart_reg, AUX_MU_BAUD, baud_rate_reg as u32);
         write_reg(&self.miniuart_range, AUX_MU_BAUD, 270);
 
-        // Finally enable transmit
+        // Finally enable receive and transmit
         write_reg(&self.miniuart_range, AUX_MU_CNTL, 3);
     }
 }
@@ -103,4 +93,19 @@ impl Uart for MiniUart {
         }
         write_reg(&self.miniuart_range, AUX_MU_IO, b as u32);
     }
+
+    fn getb(&self) -> Option<u8> {
+        // Reading the status clears the overrun flag
+        let lsr = read_reg(&self.miniuart_range, AUX_MU_LSR);
+        if lsr & 1 == 0 {
+            return None;
+        }
+        let b = read_reg(&self.miniuart_range, AUX_MU_IO) as u8;
+        // Receiver overrun: bytes have been lost, so don't trust this one
+        if lsr & (1 << 1) != 0 {
+            count_rx_error();
+            return None;
+        }
+        Some(b)
+    }
 }
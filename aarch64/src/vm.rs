@@ -4,22 +4,35 @@
 /// Note that currently there are a lot of assumptions that we're dealing with
 /// 4KiB tables here, although it supports various sizes of pages.
 use crate::{
+    dmap,
     kmem::{
-        boottext_range, bss_range, data_range, from_ptr_to_physaddr_offset_from_kzero,
-        physaddr_as_ptr_mut_offset_from_kzero, rodata_range, text_range,
+        boot_identity_entry_physaddr, early_mmio_virtrange, from_ptr_to_physaddr_offset_from_kzero,
+        from_virt_to_physaddr, kernel_sections, kernel_stacks, kzero_offset,
+        physaddr_as_ptr_mut_offset_from_kzero, physrange_as_virtrange_offset_from_kzero,
+        remove_identity_window, set_kzero_mapped, total_kernel_range,
     },
     pagealloc,
-    param::KZERO,
+    param::TRAMPOLINE,
     registers::rpi_mmio,
+    vmdebug,
 };
 use bitstruct::bitstruct;
+use core::cmp::min;
 use core::fmt;
+use core::ops;
 use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
 use num_enum::{FromPrimitive, IntoPrimitive};
 use port::{
-    fdt::DeviceTree,
-    mem::{PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K, PhysAddr, PhysRange, VirtAddr},
-    pagealloc::PageAllocError,
+    debug,
+    framerefs::RefCount,
+    mem::{
+        MapFlags, MapFlagsError, MemError, MemType, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K,
+        PhysAddr, PhysRange, VirtAddr, VirtRange,
+    },
+    memaccount::MemCategory,
+    memfill::zero_range,
+    tableframes::TableFrameSource,
 };
 
 #[cfg(not(test))]
@@ -34,7 +47,7 @@ pub enum PageSize {
 }
 
 impl PageSize {
-    const fn size(&self) -> usize {
+    pub const fn size(&self) -> usize {
         match self {
             PageSize::Page4K => PAGE_SIZE_4K,
             PageSize::Page2M => PAGE_SIZE_2M,
@@ -49,9 +62,9 @@ pub struct PhysPage4K([u8; PAGE_SIZE_4K]);
 
 impl PhysPage4K {
     pub fn clear(&mut self) {
-        unsafe {
-            core::intrinsics::volatile_set_memory(&mut self.0, 0u8, 1);
-        }
+        let page = VirtRange::with_len(VirtAddr::new(self.0.as_ptr().addr()), PAGE_SIZE_4K);
+        // Safety: the page is borrowed mutably, and is normal memory
+        unsafe { zero_range(&page) };
     }
 }
 
@@ -61,12 +74,37 @@ pub struct VirtPage4K(pub [u8; PAGE_SIZE_4K]);
 
 impl VirtPage4K {}
 
-#[derive(Debug, IntoPrimitive, FromPrimitive)]
+/// Indices of the memory attributes in MAIR_EL1, as mmu::BOOT_MAIR_EL1 sets
+/// them, a slot for each `MemType`: normal write-back cached, Device-nGnRnE,
+/// normal non-cacheable, and Device-nGnRE.
+#[derive(Debug, Clone, Copy, IntoPrimitive, FromPrimitive)]
 #[repr(u8)]
 pub enum Mair {
     #[num_enum(default)]
     Normal = 0,
     Device = 1,
+    NonCacheable = 2,
+    DeviceRelaxed = 3,
+}
+
+impl Mair {
+    fn from_mem_type(mem_type: MemType) -> Mair {
+        match mem_type {
+            MemType::NormalCached => Mair::Normal,
+            MemType::NormalNonCacheable => Mair::NonCacheable,
+            MemType::DeviceStrict => Mair::Device,
+            MemType::DeviceRelaxed => Mair::DeviceRelaxed,
+        }
+    }
+
+    fn mem_type(self) -> MemType {
+        match self {
+            Mair::Normal => MemType::NormalCached,
+            Mair::NonCacheable => MemType::NormalNonCacheable,
+            Mair::Device => MemType::DeviceStrict,
+            Mair::DeviceRelaxed => MemType::DeviceRelaxed,
+        }
+    }
 }
 
 #[derive(Debug, IntoPrimitive, FromPrimitive)]
@@ -127,59 +165,42 @@ impl Entry {
             .with_valid(true)
     }
 
-    pub fn ro_kernel_data() -> Self {
-        Entry(0)
-            .with_access_permission(AccessPermission::PrivRo)
-            .with_shareable(Shareable::Inner)
-            .with_accessed(true)
-            .with_uxn(true)
-            .with_pxn(true)
-            .with_mair_index(Mair::Normal)
-            .with_valid(true)
-    }
-
-    pub fn ro_kernel_text() -> Self {
-        Entry(0)
-            .with_access_permission(AccessPermission::PrivRo)
-            .with_shareable(Shareable::Inner)
-            .with_accessed(true)
-            .with_uxn(true)
-            .with_pxn(false)
-            .with_mair_index(Mair::Normal)
-            .with_valid(true)
-    }
-
-    pub fn rw_device() -> Self {
-        Entry(0)
-            .with_access_permission(AccessPermission::PrivRw)
-            .with_shareable(Shareable::Inner)
-            .with_accessed(true)
-            .with_uxn(true)
-            .with_pxn(true)
-            .with_mair_index(Mair::Device)
-            .with_valid(true)
-    }
-
-    pub fn rw_user_text() -> Self {
-        Entry(0)
-            .with_access_permission(AccessPermission::AllRw)
+    /// Return a page or block entry with the permissions and attributes of
+    /// `flags`, or an error if they're an invalid combination.
+    pub fn from_flags(flags: MapFlags) -> Result<Self, MapFlagsError> {
+        let flags = flags.validate()?;
+        let user = flags.contains(MapFlags::USER);
+        let execute = flags.contains(MapFlags::EXECUTE);
+        let access_permission = match (user, flags.contains(MapFlags::WRITE)) {
+            (false, true) => AccessPermission::PrivRw,
+            (true, true) => AccessPermission::AllRw,
+            (false, false) => AccessPermission::PrivRo,
+            (true, false) => AccessPermission::AllRo,
+        };
+        let mair_index = Mair::from_mem_type(flags.mem_type());
+        Ok(Entry(0)
+            .with_access_permission(access_permission)
             .with_shareable(Shareable::Inner)
             .with_accessed(true)
-            .with_uxn(false)
-            .with_pxn(true)
-            .with_mair_index(Mair::Normal)
-            .with_valid(true)
+            .with_uxn(!(user && execute))
+            .with_pxn(user || !execute)
+            .with_mair_index(mair_index)
+            .with_valid(true))
     }
 
-    pub fn rw_user_data() -> Self {
-        Entry(0)
-            .with_access_permission(AccessPermission::AllRw)
-            .with_shareable(Shareable::Inner)
-            .with_accessed(true)
-            .with_uxn(true)
-            .with_pxn(true)
-            .with_mair_index(Mair::Normal)
-            .with_valid(true)
+    /// Decode the permissions and attributes of a page or block entry.
+    pub fn flags(self) -> MapFlags {
+        let (user, write) = match self.access_permission() {
+            AccessPermission::PrivRw => (false, true),
+            AccessPermission::AllRw => (true, true),
+            AccessPermission::PrivRo => (false, false),
+            AccessPermission::AllRo => (true, false),
+        };
+        let mut flags = MapFlags::READ;
+        flags.set(MapFlags::USER, user);
+        flags.set(MapFlags::WRITE, write);
+        flags.set(MapFlags::EXECUTE, if user { !self.uxn() } else { !self.pxn() });
+        flags.with_mem_type(self.mair_index().mem_type())
     }
 
     const fn with_phys_addr(self, pa: PhysAddr) -> Self {
@@ -255,6 +276,17 @@ impl Level {
             Level::Level3 => 3,
         }
     }
+
+    /// Size of the region of virtual memory covered by a single entry at
+    /// this level.
+    pub fn entry_size(&self) -> usize {
+        match self {
+            Level::Level0 => 512 * PAGE_SIZE_1G,
+            Level::Level1 => PAGE_SIZE_1G,
+            Level::Level2 => PAGE_SIZE_2M,
+            Level::Level3 => PAGE_SIZE_4K,
+        }
+    }
 }
 
 pub fn va_index(va: VirtAddr, level: Level) -> usize {
@@ -291,21 +323,6 @@ fn recursive_table_addr(pgtype: RootPageTableType, va: VirtAddr, level: Level) -
     VirtAddr::new(msbits | recursive_indices | ((indices >> shift) & indices_mask))
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub enum PageTableError {
-    AllocationFailed(PageAllocError),
-    EntryIsNotTable,
-    PhysRangeIsZero,
-    PhysRangeIsNotOnPageBoundary,
-}
-
-impl From<PageAllocError> for PageTableError {
-    fn from(err: PageAllocError) -> PageTableError {
-        PageTableError::AllocationFailed(err)
-    }
-}
-
 #[repr(C, align(4096))]
 pub struct Table {
     pub entries: [Entry; 512],
@@ -315,7 +332,7 @@ impl Table {
     /// Return a mutable entry from the table based on the virtual address and
     /// the level.  (It uses the level to extract the index from the correct
     /// part of the virtual address).
-    pub fn entry_mut(&mut self, level: Level, va: VirtAddr) -> Result<&mut Entry, PageTableError> {
+    pub fn entry_mut(&mut self, level: Level, va: VirtAddr) -> Result<&mut Entry, MemError> {
         let idx = va_index(va, level);
         Ok(&mut self.entries[idx])
     }
@@ -326,19 +343,29 @@ impl Table {
         pgtype: RootPageTableType,
         level: Level,
         va: VirtAddr,
-    ) -> Result<&mut Table, PageTableError> {
+    ) -> Result<&mut Table, MemError> {
         // Try to get a valid page table entry.  If it doesn't exist, create it.
         let index = va_index(va, level);
         let mut entry = self.entries[index];
         if !entry.valid() {
             // Create a new page table and write the entry into the parent table
-            let page_pa = pagealloc::allocate_physpage();
+            // Page tables are zeroed through the direct map where possible.
+            // Until it's set up, the frame is cleared through the recursive
+            // mapping once the entry is installed.
+            let tables = MemCategory::PageTables;
+            let (page_pa, needs_clear) = match pagealloc::allocate_zeroed_physpage_for(tables) {
+                Ok(p) => (Ok(p), false),
+                Err(MemError::PhysNotMapped { .. }) => {
+                    (pagealloc::allocate_physpage_for(tables), true)
+                }
+                Err(err) => (Err(err), false),
+            };
             //let table = Self::alloc_pagetable();
             let page_pa = match page_pa {
                 Ok(p) => p,
                 Err(err) => {
-                    println!("error:vm:next_mut:can't allocate physpage");
-                    return Err(PageTableError::AllocationFailed(err));
+                    println!("error:vm:next_mut:can't allocate physpage: {err}");
+                    return Err(err);
                 }
             };
             entry = Entry::rw_kernel_data().with_phys_addr(page_pa).with_page_or_table(true);
@@ -347,12 +374,14 @@ impl Table {
             }
 
             // Clear out the new page
-            let recursive_page_addr = recursive_table_addr(pgtype, va, level.next().unwrap());
-            let page = unsafe { &mut *(recursive_page_addr.addr() as *mut PhysPage4K) };
-            page.clear();
-            } else if !entry.is_table(level) {
-                println!("error:vm:next_mut:entry is not a valid table entry:{entry:?} {level:?}");
-                return Err(PageTableError::EntryIsNotTable);
+            if needs_clear {
+                let recursive_page_addr = recursive_table_addr(pgtype, va, level.next().unwrap());
+                let page = unsafe { &mut *(recursive_page_addr.addr() as *mut PhysPage4K) };
+                page.clear();
+            }
+        } else if !entry.is_table(level) {
+            println!("error:vm:next_mut:entry is not a valid table entry:{entry:?} {level:?}");
+            return Err(MemError::AlreadyMapped { va });
         }
 
         // Return the address of the next table as a recursive address
@@ -361,6 +390,560 @@ impl Table {
     }
 }
 
+/// Operations needed to walk and change a page table hierarchy: how tables
+/// are reached, the TLB maintenance needed as entries change, and where the
+/// frames for new tables come from.  The table manipulation below is written
+/// against this, and never touches system registers itself, so it can be
+/// exercised over tables built in ordinary memory.
+trait TableWalker {
+    type Frames: TableFrameSource;
+
+    /// Return the table referenced by `entry`, found at `level` while walking
+    /// to `va`.
+    fn next_table(&mut self, entry: Entry, level: Level, va: VirtAddr) -> *mut Table;
+
+    /// Invalidate any cached translations for `va`, whose entry has just been
+    /// changed or cleared.
+    fn invalidate(&mut self, va: VirtAddr);
+
+    /// Invalidate any cached access to the table referenced by the entry at
+    /// `level` for `va`, which has just been changed or cleared.
+    fn invalidate_table(&mut self, level: Level, va: VirtAddr);
+
+    /// Replace the valid page, block or table entry `entry`, found at `level`
+    /// while walking to `va`, with `new_entry`, using break-before-make.
+    fn replace_entry(
+        &mut self,
+        entry: &mut Entry,
+        new_entry: Entry,
+        level: Level,
+        va: VirtAddr,
+    ) -> Result<(), MemError>;
+
+    /// Return the source of frames for new tables, and to free emptied ones
+    /// to.
+    fn frames(&mut self) -> &mut Self::Frames;
+}
+
+/// Frames for the kernel's tables, from the page allocator.  Tables are
+/// reached through the direct map when they're reached by physical address.
+struct KernelFrames;
+
+impl TableFrameSource for KernelFrames {
+    fn alloc_frame(&mut self) -> Result<PhysAddr, MemError> {
+        pagealloc::allocate_physpage_for(MemCategory::PageTables)
+    }
+
+    fn free_frame(&mut self, pa: PhysAddr) {
+        // Errors are logged by the allocator, and there's nothing more to do
+        let _ = pagealloc::free_physpage_for(pa, MemCategory::PageTables);
+    }
+
+    fn frame_ptr(&mut self, pa: PhysAddr) -> *mut u8 {
+        let va = dmap::phys_to_dmap(pa)
+            .unwrap_or_else(|err| panic!("page table {pa} isn't in RAM: {err}"));
+        va.addr() as *mut u8
+    }
+}
+
+/// Walks the hierarchy through the recursive mapping, with tables allocated
+/// from, and emptied ones returned to, the page allocator.
+struct RecursiveTableWalker {
+    pgtype: RootPageTableType,
+    frames: KernelFrames,
+}
+
+impl RecursiveTableWalker {
+    fn new(pgtype: RootPageTableType) -> Self {
+        Self { pgtype, frames: KernelFrames }
+    }
+}
+
+impl TableWalker for RecursiveTableWalker {
+    type Frames = KernelFrames;
+
+    fn next_table(&mut self, _entry: Entry, level: Level, va: VirtAddr) -> *mut Table {
+        recursive_table_addr(self.pgtype, va, level.next().unwrap()).addr() as *mut Table
+    }
+
+    fn invalidate(&mut self, va: VirtAddr) {
+        unsafe { invalidate_tlb_entry(va) };
+    }
+
+    fn invalidate_table(&mut self, level: Level, va: VirtAddr) {
+        // The table is mapped through the recursive entry
+        let table_va = recursive_table_addr(self.pgtype, va, level.next().unwrap());
+        unsafe { invalidate_tlb_entry(table_va) };
+    }
+
+    fn replace_entry(
+        &mut self,
+        entry: &mut Entry,
+        new_entry: Entry,
+        level: Level,
+        va: VirtAddr,
+    ) -> Result<(), MemError> {
+        break_before_make(entry, new_entry, level, va)
+    }
+
+    fn frames(&mut self) -> &mut KernelFrames {
+        &mut self.frames
+    }
+}
+
+/// Ensure writes to table entries are visible to the table walker before any
+/// later writes, e.g. filling a table before installing it.
+unsafe fn table_write_barrier() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("dsb ishst");
+    }
+}
+
+// Replace a valid entry using the break-before-make sequence the architecture
+// requires: invalidate the entry, invalidate the TLB entries for its VA, then
+// install the new entry.  Nothing the entry maps may be accessed until the
+// sequence is complete, so interrupts are masked, and the sequence uses no
+// memory other than the entry itself, which is accessed through the recursive
+// mapping.  It's in a page of its own so that it can be aliased in the
+// TRAMPOLINE region, and run from there when the entry maps the kernel text.
+//
+// x0: address of the entry, x1: new entry, x2: VA page number for tlbi
+#[cfg(not(test))]
+core::arch::global_asm!(
+    ".section .text.bbm, \"ax\"",
+    ".balign 4096",
+    ".globl bbm_replace_entry",
+    "bbm_replace_entry:",
+    "mrs x3, daif",      // save the interrupt mask
+    "msr daifset, #0xf", // mask interrupts while the mapping is broken
+    "str xzr, [x0]",     // break
+    "dsb ishst",         // ensure the invalid entry is visible to the walker
+    "tlbi vae1is, x2",   // invalidate the old translation
+    "dsb ish",           // ensure invalidation has completed
+    "isb",               // synchronize context
+    "str x1, [x0]",      // make
+    "dsb ishst",         // ensure the new entry is visible to the walker
+    "isb",               // synchronize context
+    "msr daif, x3",      // restore the interrupt mask
+    "ret",
+    ".balign 4096",
+);
+
+#[cfg(not(test))]
+unsafe extern "C" {
+    fn bbm_replace_entry(entry: *mut Entry, new_entry: u64, tlbi_page: usize);
+}
+
+#[cfg(test)]
+unsafe extern "C" fn bbm_replace_entry(entry: *mut Entry, new_entry: u64, _tlbi_page: usize) {
+    unsafe { write_volatile(entry, Entry(new_entry)) };
+}
+
+type BbmReplaceEntryFn = unsafe extern "C" fn(*mut Entry, u64, usize);
+
+/// Set once the break-before-make code is aliased in the TRAMPOLINE region.
+static TRAMPOLINE_MAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Alias the page holding the break-before-make code in the TRAMPOLINE region,
+/// so that mappings covering the kernel text can be replaced.  The page tables
+/// and page allocator must be set up first.
+pub fn init_trampoline(kernel_space: &mut AddressSpace) -> Result<(), MemError> {
+    let code_va = VirtAddr::new(bbm_replace_entry as usize).round_down(PAGE_SIZE_4K);
+    let code = PhysRange::with_pa_len(from_virt_to_physaddr(code_va), PAGE_SIZE_4K);
+    kernel_space.map_range(VirtAddr::new(TRAMPOLINE.start), &code, MapFlags::RX)?;
+    TRAMPOLINE_MAPPED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Replace the valid entry `entry`, found at `level` while walking to `va`,
+/// with `new_entry`, using break-before-make.  Everything `entry` maps is
+/// briefly unmapped, so the caller mustn't rely on it being accessible from
+/// other cores meanwhile.  If it maps the break-before-make code itself, the
+/// code is run through the trampoline, which fails if that isn't mapped yet.
+fn break_before_make(
+    entry: &mut Entry,
+    new_entry: Entry,
+    level: Level,
+    va: VirtAddr,
+) -> Result<(), MemError> {
+    let entry_size = level.entry_size();
+    let entry_start = va.addr() & !(entry_size - 1);
+    let code_va = bbm_replace_entry as usize;
+    let mut replace_fn: BbmReplaceEntryFn = bbm_replace_entry;
+    if (entry_start..=entry_start + (entry_size - 1)).contains(&code_va) {
+        if !TRAMPOLINE_MAPPED.load(Ordering::Acquire) {
+            println!(
+                "error:vm:break_before_make:entry maps the code, but there's no trampoline. va:{va:?} level:{level:?}"
+            );
+            return Err(MemError::NoTrampoline { va });
+        }
+        let trampoline_va = TRAMPOLINE.start + (code_va & (PAGE_SIZE_4K - 1));
+        replace_fn = unsafe { core::mem::transmute::<usize, BbmReplaceEntryFn>(trampoline_va) };
+    }
+    let tlbi_page = (va.addr() >> 12) & 0xfff_ffff_ffff;
+    unsafe { replace_fn(entry, new_entry.0, tlbi_page) };
+    TLBI_PAGE.inc();
+    Ok(())
+}
+
+/// Replace the block entry for `va` in `table` at `level` with a table of next
+/// level entries, mapping the same memory with the same attributes.  The new
+/// table is filled through the frame source before it's installed, then
+/// swapped for the block with break-before-make, so the only time any of the
+/// block is unmapped is during that sequence.  If that fails, the block is
+/// left as it was, and the new table is freed.
+fn split_block(
+    table: &mut Table,
+    level: Level,
+    va: VirtAddr,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    let index = va_index(va, level);
+    let block = table.entries[index];
+    let next_level = level.next().unwrap();
+
+    let table_pa = walker.frames().alloc_frame()?;
+    let table_entry = Entry::rw_kernel_data().with_phys_addr(table_pa).with_page_or_table(true);
+
+    // Entries at level 3 should have the page flag set
+    let new_table = unsafe { &mut *(walker.frames().frame_ptr(table_pa) as *mut Table) };
+    let block_pa = block.addr() << 12;
+    let next_entry_size = next_level.entry_size();
+    for (i, entry) in new_table.entries.iter_mut().enumerate() {
+        let pa = PhysAddr::new(block_pa + (i * next_entry_size) as u64);
+        let entry_val = block.with_phys_addr(pa).with_page_or_table(next_level == Level::Level3);
+        unsafe { write_volatile(entry, entry_val) };
+    }
+
+    unsafe { table_write_barrier() };
+    walker.replace_entry(&mut table.entries[index], table_entry, level, va).inspect_err(|_| {
+        walker.frames().free_frame(table_pa);
+    })
+}
+
+/// A translation found by walking the page tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub pa: PhysAddr,        // Physical address the virtual address maps to
+    pub page_size: PageSize, // Size of the page or block containing it
+    pub entry: Entry,        // Leaf entry, holding the attributes
+}
+
+impl Mapping {
+    /// Return the number of references to the physical page mapped, if
+    /// they're counted for it, so shared pages can be spotted.
+    pub fn ref_count(&self) -> Option<RefCount> {
+        pagealloc::try_ref_count(self.pa)
+    }
+}
+
+/// Walk the tables from `root` to the page or block entry translating `va`.
+/// Returns None if there's no valid entry for `va` at any level.
+fn walk(root: &Table, va: VirtAddr, walker: &mut impl TableWalker) -> Option<Mapping> {
+    let mut table = root;
+    let mut level = Level::Level0;
+    loop {
+        let entry = table.entries[va_index(va, level)];
+        if !entry.valid() {
+            return None;
+        }
+        if entry.is_table(level) {
+            table = unsafe { &*walker.next_table(entry, level, va) };
+            level = level.next()?;
+            continue;
+        }
+
+        let page_size = match level {
+            // There are no blocks at level 0 with 4KiB granules
+            Level::Level0 => return None,
+            Level::Level1 => PageSize::Page1G,
+            Level::Level2 => PageSize::Page2M,
+            // Valid level 3 entries must have the page flag set
+            Level::Level3 if !entry.page_or_table() => return None,
+            Level::Level3 => PageSize::Page4K,
+        };
+        let offset = va.addr() & (page_size.size() - 1);
+        let pa = PhysAddr::new((entry.addr() << 12) + offset as u64);
+        return Some(Mapping { pa, page_size, entry });
+    }
+}
+
+/// Return the mapping for `va` in the current page tables, or None if it
+/// isn't mapped.
+pub fn lookup(va: VirtAddr) -> Option<Mapping> {
+    let pgtype = match va.addr() >> 48 {
+        0xffff => RootPageTableType::Kernel,
+        0 => RootPageTableType::User,
+        _ => return None,
+    };
+    let mut walker = RecursiveTableWalker::new(pgtype);
+    walk(root_page_table(pgtype), va, &mut walker)
+}
+
+/// Panic unless all of `range` is mapped in the current page tables, with at
+/// least the given flags.
+pub fn assert_mapped(range: &VirtRange, flags: MapFlags) {
+    let mut va = range.start();
+    while va < range.end() {
+        let Some(mapping) = lookup(va) else {
+            panic!("vm:assert_mapped:{va:?} isn't mapped. range:{range}");
+        };
+        let actual = mapping.entry.flags();
+        assert!(
+            actual.contains(flags),
+            "vm:assert_mapped:{va:?} is mapped {actual}, expected at least {flags}. range:{range}"
+        );
+
+        let page_size = mapping.page_size.size();
+        match (va.addr() & !(page_size - 1)).checked_add(page_size) {
+            Some(next_va) => va = VirtAddr::new(next_va),
+            None => break,
+        }
+    }
+}
+
+/// Clear the entries in `table` at `level` that map `start..end`, descending
+/// into next level tables and freeing any that are left empty.  Blocks only
+/// partly covered by the range are split.  Entries that aren't valid are
+/// skipped.  Returns true if `table` no longer has any valid entries.
+fn unmap_entries(
+    table: &mut Table,
+    level: Level,
+    start: usize,
+    end: usize,
+    walker: &mut impl TableWalker,
+) -> Result<bool, MemError> {
+    let entry_size = level.entry_size();
+    let mut va = start;
+    while va < end {
+        let entry_start = va & !(entry_size - 1);
+        let entry_end = entry_start.saturating_add(entry_size);
+        let sub_end = min(entry_end, end);
+
+        let index = va_index(VirtAddr::new(va), level);
+        let entry = table.entries[index];
+        if !entry.valid() {
+            // Nothing mapped here
+        } else if entry.is_table(level) {
+            let next_table = unsafe { &mut *walker.next_table(entry, level, VirtAddr::new(va)) };
+            if unmap_entries(next_table, level.next().unwrap(), va, sub_end, walker)? {
+                unsafe { write_volatile(&mut table.entries[index], Entry::empty()) };
+                walker.invalidate(VirtAddr::new(va));
+                walker.invalidate_table(level, VirtAddr::new(va));
+                walker.frames().free_frame(PhysAddr::new(entry.addr() << 12));
+            }
+        } else if va != entry_start || sub_end != entry_end {
+            // Only part of a block is covered, so split it and try again
+            split_block(table, level, VirtAddr::new(va), walker)?;
+            continue;
+        } else {
+            unsafe { write_volatile(&mut table.entries[index], Entry::empty()) };
+            walker.invalidate(VirtAddr::new(va));
+        }
+
+        va = sub_end;
+    }
+    Ok(table.entries.iter().all(|e| !e.valid()))
+}
+
+/// Change the permissions and attributes of the pages and blocks in `table` at
+/// `level` that map `start..end` to those of `template`, descending into next
+/// level tables.  Blocks only partly covered by the range are split.  Entries
+/// that aren't valid are skipped.
+fn protect_entries(
+    table: &mut Table,
+    level: Level,
+    start: usize,
+    end: usize,
+    template: Entry,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    let update =
+        |entry: Entry| template.with_addr(entry.addr()).with_page_or_table(entry.page_or_table());
+    update_entries(table, level, start, end, &update, walker)
+}
+
+/// Replace each page and block entry in `table` at `level` that maps
+/// `start..end` with `update` of it, using break-before-make where it
+/// changes, and descending into next level tables.  Blocks only partly
+/// covered by the range are split.  Entries that aren't valid are skipped.
+fn update_entries(
+    table: &mut Table,
+    level: Level,
+    start: usize,
+    end: usize,
+    update: &impl Fn(Entry) -> Entry,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    let entry_size = level.entry_size();
+    let mut va = start;
+    while va < end {
+        let entry_start = va & !(entry_size - 1);
+        let entry_end = entry_start.saturating_add(entry_size);
+        let sub_end = min(entry_end, end);
+
+        let index = va_index(VirtAddr::new(va), level);
+        let entry = table.entries[index];
+        if !entry.valid() {
+            // Nothing mapped here
+        } else if entry.is_table(level) {
+            let next_table = unsafe { &mut *walker.next_table(entry, level, VirtAddr::new(va)) };
+            update_entries(next_table, level.next().unwrap(), va, sub_end, update, walker)?;
+        } else if va != entry_start || sub_end != entry_end {
+            // Only part of a block is covered, so split it and try again
+            split_block(table, level, VirtAddr::new(va), walker)?;
+            continue;
+        } else {
+            let new_entry = update(entry);
+            if new_entry != entry {
+                walker.replace_entry(
+                    &mut table.entries[index],
+                    new_entry,
+                    level,
+                    VirtAddr::new(va),
+                )?;
+            }
+        }
+
+        va = sub_end;
+    }
+    Ok(())
+}
+
+// Page and block entries written when mapping, by size, and TLB invalidations
+port::counter!(static ENTRIES_1G = "vm.entries_1g");
+port::counter!(static ENTRIES_2M = "vm.entries_2m");
+port::counter!(static ENTRIES_4K = "vm.entries_4k");
+port::counter!(static TLBI_PAGE = "vm.tlbi_page");
+port::counter!(static TLBI_ALL = "vm.tlbi_all");
+
+/// Number of page and block entries written by a mapping operation.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MapStats {
+    pub blocks_1g: usize,
+    pub blocks_2m: usize,
+    pub pages_4k: usize,
+}
+
+impl MapStats {
+    fn add_entry(&mut self, level: Level) {
+        match level {
+            Level::Level1 => {
+                self.blocks_1g += 1;
+                ENTRIES_1G.inc();
+            }
+            Level::Level2 => {
+                self.blocks_2m += 1;
+                ENTRIES_2M.inc();
+            }
+            Level::Level3 => {
+                self.pages_4k += 1;
+                ENTRIES_4K.inc();
+            }
+            Level::Level0 => unreachable!("level 0 entries can't be blocks"),
+        }
+    }
+}
+
+impl ops::AddAssign for MapStats {
+    fn add_assign(&mut self, other: MapStats) {
+        self.blocks_1g += other.blocks_1g;
+        self.blocks_2m += other.blocks_2m;
+        self.pages_4k += other.pages_4k;
+    }
+}
+
+impl fmt::Display for MapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "1G:{} 2M:{} 4K:{}", self.blocks_1g, self.blocks_2m, self.pages_4k)
+    }
+}
+
+/// Map `start..end` in `table` at `level` to the physical range starting at
+/// `pa`, with the attributes of `template`.  Each part of the range is mapped
+/// with the largest block that both the virtual and physical addresses are
+/// aligned to, and that the rest of the range covers, so 4K pages are only
+/// used at the edges.  Next level tables are allocated as needed.  Fails
+/// without overwriting if any part of the range is already mapped, leaving
+/// whatever was mapped before that point.
+#[allow(clippy::too_many_arguments)]
+fn map_entries(
+    table: &mut Table,
+    level: Level,
+    start: usize,
+    end: usize,
+    pa: PhysAddr,
+    template: Entry,
+    walker: &mut impl TableWalker,
+    stats: &mut MapStats,
+) -> Result<(), MemError> {
+    let entry_size = level.entry_size();
+    let mut va = start;
+    while va < end {
+        let entry_start = va & !(entry_size - 1);
+        let entry_end = entry_start.saturating_add(entry_size);
+        let sub_end = min(entry_end, end);
+        let sub_pa = pa + (va - start) as u64;
+
+        let index = va_index(VirtAddr::new(va), level);
+        let entry = table.entries[index];
+        let whole_entry = va == entry_start && sub_end == entry_end;
+        if level != Level::Level0 && whole_entry && sub_pa.is_multiple_of(entry_size as u64) {
+            if entry.valid() {
+                println!("error:vm:map_entries:already mapped. va:{va:#x} level:{level:?}");
+                return Err(MemError::AlreadyMapped { va: VirtAddr::new(va) });
+            }
+            // Entries at level 3 should have the page flag set
+            let new_entry =
+                template.with_phys_addr(sub_pa).with_page_or_table(level == Level::Level3);
+            unsafe { write_volatile(&mut table.entries[index], new_entry) };
+            stats.add_entry(level);
+        } else {
+            if !entry.valid() {
+                let table_pa = walker.frames().alloc_frame()?;
+                let table_entry =
+                    Entry::rw_kernel_data().with_phys_addr(table_pa).with_page_or_table(true);
+                unsafe { write_volatile(&mut table.entries[index], table_entry) };
+                walker.invalidate_table(level, VirtAddr::new(va));
+                let next_table =
+                    unsafe { &mut *walker.next_table(table_entry, level, VirtAddr::new(va)) };
+                for entry in next_table.entries.iter_mut() {
+                    unsafe { write_volatile(entry, Entry::empty()) };
+                }
+            } else if !entry.is_table(level) {
+                println!(
+                    "error:vm:map_entries:already mapped by block. va:{va:#x} level:{level:?}"
+                );
+                return Err(MemError::AlreadyMapped { va: VirtAddr::new(va) });
+            }
+            let next_table =
+                unsafe { &mut *walker.next_table(table.entries[index], level, VirtAddr::new(va)) };
+            map_entries(
+                next_table,
+                level.next().unwrap(),
+                va,
+                sub_end,
+                sub_pa,
+                template,
+                walker,
+                stats,
+            )?;
+        }
+
+        va = sub_end;
+    }
+    Ok(())
+}
+
+/// Return the first end of `range` that isn't a multiple of `align`, if any.
+fn unaligned_phys(range: &PhysRange, align: usize) -> Option<u64> {
+    [range.start(), range.end()]
+        .into_iter()
+        .map(|pa| pa.addr())
+        .find(|pa| !pa.is_multiple_of(align as u64))
+}
+
 impl fmt::Debug for Table {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:x}", (self as *const Self).addr())
@@ -381,79 +964,131 @@ impl VaMapping {
     }
 }
 
+/// Storage for a root table, e.g. statically allocated for the kernel.
 pub type RootPageTable = Table;
 
 impl RootPageTable {
     pub const fn empty() -> RootPageTable {
         RootPageTable { entries: [Entry::empty(); 512] }
     }
+}
 
-    /// Ensure there's a mapping from va to entry, creating any intermediate
-    /// page tables that don't already exist.  If a mapping already exists,
-    /// replace it.
-    /// root_page_table should be a direct va - not a recursive va.
-    fn map_to(
-        &mut self,
-        entry: Entry,
-        va: VirtAddr,
-        page_size: PageSize,
-        root_page_table: &mut RootPageTable,
+/// An address space: a hierarchy of recursive page tables, identified by the
+/// physical address of its root table, and whether it translates the user
+/// half, through TTBR0, or the kernel half, through TTBR1.  Its methods
+/// operate on its own tables, whether or not they're the active ones, by
+/// pointing the recursive entry of the active root of the same type at them
+/// for the duration.
+///
+/// Changing the tables that are currently active needs care:
+/// - map_range only writes entries that aren't valid, so it's always safe.
+/// - unmap is safe as long as nothing is still using the range.
+/// - protect, unmap of part of a block, and map_to/map_phys_range over an
+///   existing mapping replace valid entries with break-before-make, so the
+///   affected range is briefly unmapped.  The sequence runs with interrupts
+///   masked and touches no memory but the entry, so this is safe for ranges
+///   holding the running code or the current stack, provided init_trampoline
+///   has been called first if they include the kernel text.  It isn't safe
+///   while other cores may be using the range.
+pub struct AddressSpace {
+    root: PhysAddr,
+    pgtype: RootPageTableType,
+}
+
+impl AddressSpace {
+    /// Create an address space whose root is `root_table`, which must be in
+    /// the kernel image, such as a static.  Anything but the recursive entry
+    /// already in the table is kept.
+    pub unsafe fn from_static(
+        root_table: &'static mut RootPageTable,
         pgtype: RootPageTableType,
-    ) -> Result<(), PageTableError> {
-        // We change the last entry of the root page table to the address of
-        // self for the duration of this method.  This allows us to work with
-        // this hierarchy of pagetables even if it's not the current translation
-        // table.  We *must* return it to its original state on exit.
-        // TODO Only do this if self != kernel_root()
-        let old_recursive_entry = root_page_table.entries[511];
-        let temp_recursive_entry = Entry::rw_kernel_data()
-            .with_phys_addr(from_ptr_to_physaddr_offset_from_kzero(self))
-            .with_page_or_table(true);
+    ) -> AddressSpace {
+        unsafe { init_empty_root_page_table(root_table) };
+        AddressSpace { root: from_ptr_to_physaddr_offset_from_kzero(root_table), pgtype }
+    }
 
-        unsafe {
-            write_volatile(&mut root_page_table.entries[511], temp_recursive_entry);
-            // TODO Need to invalidate the single cache entry
-            invalidate_all_tlb_entries();
-        };
+    /// Return the active kernel address space.
+    pub fn kernel() -> AddressSpace {
+        AddressSpace { root: ttbr1_el1(), pgtype: RootPageTableType::Kernel }
+    }
 
-        let dest_entry = match page_size {
-            PageSize::Page4K => self
-                .next_mut(pgtype, Level::Level0, va)
-                .and_then(|t1| t1.next_mut(pgtype, Level::Level1, va))
-                .and_then(|t2| t2.next_mut(pgtype, Level::Level2, va))
-                .and_then(|t3| t3.entry_mut(Level::Level3, va)),
-            PageSize::Page2M => self
-                .next_mut(pgtype, Level::Level0, va)
-                .and_then(|t1| t1.next_mut(pgtype, Level::Level1, va))
-                .and_then(|t2| t2.entry_mut(Level::Level2, va)),
-            PageSize::Page1G => self
-                .next_mut(pgtype, Level::Level0, va)
-                .and_then(|t1| t1.entry_mut(Level::Level1, va)),
-        };
-        let dest_entry = match dest_entry {
-            Ok(e) => e,
-            Err(err) => {
-                println!(
-                    "error:vm:map_to:couldn't find page table entry. va:{:?} err:{:?}",
-                    va, err
-                );
-                return Err(err);
-            }
-        };
+    /// Return the active user address space.
+    pub fn user() -> AddressSpace {
+        AddressSpace { root: ttbr0_el1(), pgtype: RootPageTableType::User }
+    }
 
-        // Entries at level 3 should have the page flag set
-        let entry =
-            if page_size == PageSize::Page4K { entry.with_page_or_table(true) } else { entry };
+    /// Create a user address space with nothing mapped, in a newly allocated
+    /// root table.  The kernel half is translated through TTBR1 by the kernel
+    /// address space, so it's shared without copying anything.  The direct
+    /// map must be set up first, since the root is initialised through it.
+    pub fn new() -> Result<AddressSpace, MemError> {
+        let tables = MemCategory::PageTables;
+        let root = pagealloc::allocate_zeroed_physpage_for(tables).inspect_err(|err| {
+            println!("error:vm:AddressSpace::new:can't allocate root table: {err}");
+        })?;
+        let root_va = dmap::phys_to_dmap(root).inspect_err(|err| {
+            println!("error:vm:AddressSpace::new:root table not in direct map: {err}");
+        })?;
+        let root_table = unsafe { &mut *(root_va.addr() as *mut RootPageTable) };
+        let entry = Entry::rw_kernel_data().with_phys_addr(root).with_page_or_table(true);
+        unsafe { write_volatile(&mut root_table.entries[511], entry) };
+        Ok(AddressSpace { root, pgtype: RootPageTableType::User })
+    }
 
-        unsafe {
-            write_volatile(dest_entry, entry);
-            // Return the recursive entry to its original state
-            write_volatile(&mut root_page_table.entries[511], old_recursive_entry);
-            // TODO Need to invalidate the single cache entry (+ optionally the recursive entry)
-            invalidate_all_tlb_entries();
-        }
+    /// Return the physical address of the root table.
+    #[allow(dead_code)]
+    pub fn root(&self) -> PhysAddr {
+        self.root
+    }
 
-        Ok(())
+    /// Ensure there's a mapping from va to entry, creating any intermediate
+    /// page tables that don't already exist.  If a mapping already exists,
+    /// replace it.
+    fn map_to(&mut self, entry: Entry, va: VirtAddr, page_size: PageSize) -> Result<(), MemError> {
+        let pgtype = self.pgtype;
+        self.with_recursive_walker(|root, _walker| {
+            let dest_entry = match page_size {
+                PageSize::Page4K => root
+                    .next_mut(pgtype, Level::Level0, va)
+                    .and_then(|t1| t1.next_mut(pgtype, Level::Level1, va))
+                    .and_then(|t2| t2.next_mut(pgtype, Level::Level2, va))
+                    .and_then(|t3| t3.entry_mut(Level::Level3, va)),
+                PageSize::Page2M => root
+                    .next_mut(pgtype, Level::Level0, va)
+                    .and_then(|t1| t1.next_mut(pgtype, Level::Level1, va))
+                    .and_then(|t2| t2.entry_mut(Level::Level2, va)),
+                PageSize::Page1G => root
+                    .next_mut(pgtype, Level::Level0, va)
+                    .and_then(|t1| t1.entry_mut(Level::Level1, va)),
+            };
+            let dest_entry = match dest_entry {
+                Ok(e) => e,
+                Err(err) => {
+                    println!(
+                        "error:vm:map_to:couldn't find page table entry. va:{:?} err:{}",
+                        va, err
+                    );
+                    return Err(err);
+                }
+            };
+
+            // Entries at level 3 should have the page flag set
+            let entry =
+                if page_size == PageSize::Page4K { entry.with_page_or_table(true) } else { entry };
+
+            // An existing mapping must be replaced with break-before-make
+            if dest_entry.valid() {
+                let level = match page_size {
+                    PageSize::Page4K => Level::Level3,
+                    PageSize::Page2M => Level::Level2,
+                    PageSize::Page1G => Level::Level1,
+                };
+                break_before_make(dest_entry, entry, level, va)
+            } else {
+                unsafe { write_volatile(dest_entry, entry) };
+                Ok(())
+            }
+        })
     }
 
     /// Map the physical range using the requested page size.
@@ -467,21 +1102,21 @@ impl RootPageTable {
         debug_name: &str,
         range: &PhysRange,
         va_mapping: VaMapping,
-        entry: Entry,
+        flags: MapFlags,
         page_size: PageSize,
-        pgtype: RootPageTableType,
-    ) -> Result<(usize, usize), PageTableError> {
-        if !range.start().is_multiple_of(page_size.size() as u64)
-            || !range.end().is_multiple_of(page_size.size() as u64)
-        {
+    ) -> Result<(usize, usize), MemError> {
+        let entry = Entry::from_flags(flags).inspect_err(|err| {
+            println!(
+                "error:vm:map_phys_range:invalid flags. debug_name:{debug_name} flags:{flags:?} err:{err:?}"
+            );
+        })?;
+        if let Some(addr) = unaligned_phys(range, page_size.size()) {
             println!(
                 "error:vm:map_phys_range:range not on page boundary. debug_name:{debug_name} range:{range} page_size:{page_size:?}",
             );
-            return Err(PageTableError::PhysRangeIsNotOnPageBoundary);
+            return Err(MemError::Unaligned { addr, required: page_size.size() });
         }
 
-        let root_page_table = root_page_table(pgtype);
-
         let mut mapped_start_va: Option<VirtAddr> = None;
         // Initialize with a dummy value, it will be updated before being returned.
         let mut mapped_end_va: VirtAddr = VirtAddr::new(0);
@@ -492,97 +1127,406 @@ impl RootPageTable {
                 mapped_start_va = Some(current_target_va);
             }
             mapped_end_va = current_target_va + page_size.size();
-            self.map_to(entry.with_phys_addr(pa), current_target_va, page_size, root_page_table, pgtype)?;
+            self.map_to(entry.with_phys_addr(pa), current_target_va, page_size)?;
         }
 
         mapped_start_va
             .map(|start_va| Ok((start_va.addr(), mapped_end_va.addr())))
-            .unwrap_or(Err(PageTableError::PhysRangeIsZero))
+            .unwrap_or(Err(MemError::InvalidSize { size: 0 }))
+    }
+
+    /// Map the physical range to consecutive virtual addresses starting at
+    /// `va`, using 1G and 2M blocks wherever the alignment of both addresses
+    /// and the remaining length allow, and 4K pages elsewhere.  Intermediate
+    /// tables are allocated as needed.  Returns the number of entries of each
+    /// size written.  If any part of the range is already mapped, nothing is
+    /// overwritten and AlreadyMapped is returned.
+    pub fn map_range(
+        &mut self,
+        va: VirtAddr,
+        phys: &PhysRange,
+        flags: MapFlags,
+    ) -> Result<MapStats, MemError> {
+        let template = Entry::from_flags(flags).inspect_err(|err| {
+            println!("error:vm:map_range:invalid flags. phys:{phys} flags:{flags:?} err:{err:?}");
+        })?;
+        if let Some(addr) = unaligned_phys(phys, PAGE_SIZE_4K) {
+            println!("error:vm:map_range:range not on page boundary. phys:{phys}");
+            return Err(MemError::Unaligned { addr, required: PAGE_SIZE_4K });
+        }
+        let range = VirtRange::with_len(va, phys.size());
+        if !Self::check_walk_range(&range, "map_range")? {
+            return Ok(MapStats::default());
+        }
+        let mut stats = MapStats::default();
+        self.with_recursive_walker(|root, walker| {
+            let result = map_entries(
+                root,
+                Level::Level0,
+                range.start().addr(),
+                range.end().addr(),
+                phys.start(),
+                template,
+                walker,
+                &mut stats,
+            );
+            unsafe { table_write_barrier() };
+            result
+        })?;
+        Ok(stats)
+    }
+
+    /// Check that `range` can be walked by map_range, unmap or protect,
+    /// returning false if it's empty.
+    fn check_walk_range(range: &VirtRange, fn_name: &str) -> Result<bool, MemError> {
+        let unaligned = [range.start(), range.end()]
+            .into_iter()
+            .find(|va| !va.addr().is_multiple_of(PAGE_SIZE_4K));
+        if let Some(va) = unaligned {
+            println!("error:vm:{fn_name}:range not on page boundary. range:{range}");
+            return Err(MemError::Unaligned { addr: va.addr() as u64, required: PAGE_SIZE_4K });
+        }
+        if range.start() >= range.end() {
+            return Ok(false);
+        }
+        // The last entry of the root table is the recursive entry, which must
+        // never be changed.
+        if va_index(range.end() - 1, Level::Level0) == 511 {
+            println!("error:vm:{fn_name}:range overlaps recursive entry. range:{range}");
+            let last = (range.end() - 1).addr() as u64;
+            let recursive_start = (last & !((1 << 48) - 1)) | (511 << 39);
+            let recursive = recursive_start..recursive_start + (1 << 39);
+            return Err(MemError::OutOfRange { addr: last, range: recursive });
+        }
+        Ok(true)
+    }
+
+    /// Call `f` with the root table and a walker for this address space.  The
+    /// recursive entry of the active root table of the same type is pointed
+    /// at this root for the duration, so this works even if it isn't the
+    /// active one, and the root is reached through the recursive mapping.  It
+    /// *must* be returned to its original state before anything else uses the
+    /// recursive mapping.
+    fn with_recursive_walker<R>(
+        &self,
+        f: impl FnOnce(&mut Table, &mut RecursiveTableWalker) -> R,
+    ) -> R {
+        let pgtype = self.pgtype;
+        let active_root = root_page_table(pgtype);
+        let old_recursive_entry = active_root.entries[511];
+        let temp_recursive_entry =
+            Entry::rw_kernel_data().with_phys_addr(self.root).with_page_or_table(true);
+
+        unsafe {
+            write_volatile(&mut active_root.entries[511], temp_recursive_entry);
+            invalidate_all_tlb_entries();
+        };
+
+        let root_va = recursive_table_addr(pgtype, VirtAddr::new(0), Level::Level0);
+        let root = unsafe { &mut *(root_va.addr() as *mut Table) };
+        let result = f(root, &mut RecursiveTableWalker::new(pgtype));
+
+        unsafe {
+            // Return the recursive entry to its original state
+            write_volatile(&mut active_root.entries[511], old_recursive_entry);
+            invalidate_all_tlb_entries();
+        }
+
+        result
+    }
+
+    /// Unmap the virtual range, clearing the entries that map it, invalidating
+    /// them in the TLB, and returning any intermediate tables left empty to the
+    /// page allocator.  Parts of the range that aren't mapped are skipped, so
+    /// unmapping a range that was never mapped does nothing.  Blocks only
+    /// partly covered by the range are split, so the rest stays mapped.
+    pub fn unmap(&mut self, range: &VirtRange) -> Result<(), MemError> {
+        if !Self::check_walk_range(range, "unmap")? {
+            return Ok(());
+        }
+        self.with_recursive_walker(|root, walker| {
+            unmap_entries(root, Level::Level0, range.start().addr(), range.end().addr(), walker)
+        })
+        .map(|_| ())
+    }
+
+    /// Change the flags of the mappings in the virtual range, keeping the
+    /// physical addresses they map to.  Parts of the range that aren't mapped
+    /// are skipped.  Blocks only partly covered by the range are split, so
+    /// the rest keep their flags.
+    pub fn protect(&mut self, range: &VirtRange, flags: MapFlags) -> Result<(), MemError> {
+        let template = Entry::from_flags(flags).inspect_err(|err| {
+            println!("error:vm:protect:invalid flags. range:{range} flags:{flags:?} err:{err:?}");
+        })?;
+        if !Self::check_walk_range(range, "protect")? {
+            return Ok(());
+        }
+        self.with_recursive_walker(|root, walker| {
+            protect_entries(
+                root,
+                Level::Level0,
+                range.start().addr(),
+                range.end().addr(),
+                template,
+                walker,
+            )
+        })
+    }
+
+    /// Change the memory type of the mappings in the virtual range, keeping
+    /// their permissions, e.g. to make a buffer write-combining.  As for
+    /// `protect`, entries are replaced with break-before-make, unmapped parts
+    /// are skipped, and blocks only partly covered are split.  Lines cached
+    /// while the memory was cacheable are then cleaned and invalidated, so
+    /// they can't be written back over later uncached writes, which is only
+    /// possible for the address space in use.
+    #[allow(dead_code)]
+    pub fn set_mem_type(&mut self, range: &VirtRange, mem_type: MemType) -> Result<(), MemError> {
+        if !Self::check_walk_range(range, "set_mem_type")? {
+            return Ok(());
+        }
+        let mair_index = Mair::from_mem_type(mem_type);
+        self.with_recursive_walker(|root, walker| {
+            let update = |entry: Entry| entry.with_mair_index(mair_index);
+            update_entries(
+                root,
+                Level::Level0,
+                range.start().addr(),
+                range.end().addr(),
+                &update,
+                walker,
+            )
+        })?;
+
+        if mem_type != MemType::NormalCached {
+            let mut va = range.start().addr();
+            while va < range.end().addr() {
+                let Some(mapping) = self.lookup(VirtAddr::new(va)) else {
+                    va += PAGE_SIZE_4K;
+                    continue;
+                };
+                let entry_size = mapping.page_size.size();
+                let next_va = min((va & !(entry_size - 1)) + entry_size, range.end().addr());
+                let cached = VirtRange(VirtAddr::new(va)..VirtAddr::new(next_va));
+                port::cache::clean_invalidate(&cached);
+                va = next_va;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the mapping for `va` in this address space, or None if it
+    /// isn't mapped.
+    #[allow(dead_code)]
+    pub fn lookup(&self, va: VirtAddr) -> Option<Mapping> {
+        self.with_recursive_walker(|root, walker| walk(root, va, walker))
+    }
+
+    /// Print a summary of the mappings in this address space.
+    #[allow(dead_code)]
+    pub fn dump(&self) {
+        self.with_recursive_walker(|_, _| vmdebug::print_mappings(self.pgtype));
+    }
+
+    /// Make this the active address space of its type.
+    pub unsafe fn activate(&self) {
+        unsafe { switch(self.root, self.pgtype) };
     }
 }
 
-/// Return the root user or kernel level page table
+/// Return the active user or kernel level root page table.  This is reached
+/// through the direct map once it's set up, since roots allocated from the
+/// page allocator aren't in the KZERO mapping, and through KZERO before that,
+/// when the only roots are in the kernel image.
 pub fn root_page_table(pgtype: RootPageTableType) -> &'static mut RootPageTable {
     let page_table_pa = match pgtype {
         RootPageTableType::User => ttbr0_el1(),
         RootPageTableType::Kernel => ttbr1_el1(),
     };
-    unsafe { &mut *physaddr_as_ptr_mut_offset_from_kzero::<RootPageTable>(page_table_pa) }
-}
-
-pub unsafe fn init_kernel_page_tables(
-    dt: &DeviceTree,
-    new_kernel_root_page_table: &mut RootPageTable,
-    dtb_range: PhysRange,
-) {
-    // We use recursive page tables, but we have to be careful in the init call,
-    // since the kpage_table is not currently pointed to by ttbr1_el1.  Any
-    // recursive addressing of (511, 511, 511, 511) always points to the
-    // physical address of the root page table, which isn't what we want here
-    // because kpage_table hasn't been switched to yet.
-    unsafe { init_empty_root_page_table(new_kernel_root_page_table) };
-
-    // We only use the first memory range for now.
-    // TODO Handle multiple memory ranges
-    let available_mem = dt
-        .find_device_type("memory")
-        .flat_map(|memory| dt.property_translated_reg_iter(memory).flat_map(|r| r.regblock()))
-        .map(|memory| PhysRange::from(&memory))
-        .next()
-        .expect("No memory range found in device tree");
-    println!("Physical Memory:");
-    println!("  {}", &available_mem);
+    let page_table = match dmap::phys_to_dmap(page_table_pa) {
+        Ok(va) => va.addr() as *mut RootPageTable,
+        Err(_) => physaddr_as_ptr_mut_offset_from_kzero::<RootPageTable>(page_table_pa),
+    };
+    unsafe { &mut *page_table }
+}
 
+pub fn init_kernel_page_tables(kernel_space: &mut AddressSpace, dtb_range: PhysRange) {
     // TODO leave the first page unmapped to catch null pointer dereferences in unsafe code
+    let kzero = |range: &PhysRange| physrange_as_virtrange_offset_from_kzero(range).start();
     let custom_map = {
         // The DTB range might not end on a page boundary, so round up.
-        let dtb_page_size = PageSize::Page4K;
-        let dtb_range =
-            PhysRange(dtb_range.start()..dtb_range.end().round_up(dtb_page_size.size() as u64));
+        let dtb_range = dtb_range.round_out(PAGE_SIZE_4K as u64);
 
-        let text_range = boottext_range().add(&text_range());
-        let ro_data_range = rodata_range();
-        let data_range = data_range().add(&bss_range());
+        // Sections are grouped so that as much as possible is mapped with
+        // blocks.  Their flags are tightened once the kernel is up.
+        let [boottext, text, rodata, data, bss] = kernel_sections();
+        let text_range = boottext.range.add(&text.range);
+        let data_range = data.range.add(&bss.range);
         let mmio_range = rpi_mmio().expect("mmio base detect failed");
 
+        // Everything is in the KZERO mapping, except the early MMIO mapping,
+        // which doesn't move with the kernel
         let mut map = [
-            ("DTB", dtb_range, Entry::ro_kernel_data(), dtb_page_size),
-            ("Kernel Text", text_range, Entry::ro_kernel_text(), PageSize::Page2M),
-            ("Kernel RO Data", ro_data_range, Entry::ro_kernel_data(), PageSize::Page2M),
-            ("Kernel Data", data_range, Entry::rw_kernel_data(), PageSize::Page2M),
-            ("MMIO", mmio_range, Entry::rw_device(), PageSize::Page2M),
+            ("DTB", kzero(&dtb_range), dtb_range, MapFlags::READ),
+            ("Kernel Text", kzero(&text_range), text_range, text.flags),
+            ("Kernel RO Data", kzero(&rodata.range), rodata.range, rodata.flags),
+            ("Kernel Data", kzero(&data_range), data_range, data.flags),
+            (
+                "Early MMIO",
+                early_mmio_virtrange(&mmio_range).start(),
+                mmio_range,
+                MapFlags::RW | MapFlags::DEVICE,
+            ),
         ];
-        map.sort_by_key(|a| a.1.start());
+        map.sort_by_key(|a| a.2.start());
         map
     };
 
-    println!("Memory map:");
-    for (name, range, flags, page_size) in custom_map.iter() {
-        let mapped_range = new_kernel_root_page_table
-            .map_phys_range(
-                name,
-                range,
-                VaMapping::Offset(KZERO), // KZERO is usize, VirtAddr::new(KZERO) might be better if used as Addr
-                *flags,
-                *page_size,
-                RootPageTableType::Kernel,
-            )
-            .expect("error:init:mapping failed");
+    debug!("Memory map:");
+    let mut total_stats = MapStats::default();
+    for (name, va, range, flags) in custom_map.iter() {
+        let stats = kernel_space.map_range(*va, range, *flags).unwrap_or_else(|err| {
+            panic!("error:init:mapping {name} {range} at {va} failed: {err}")
+        });
+        total_stats += stats;
+
+        debug!(
+            "  {:16}{} to {} flags: {} entries: {}",
+            name,
+            range,
+            VirtRange::with_len(*va, range.size()),
+            flags,
+            stats
+        );
+    }
 
-        println!(
-            "  {:16}{} to {:#018x}..{:#018x} flags: {:?} page_size: {:?}",
-            name, range, mapped_range.0, mapped_range.1, flags, page_size
+    // Each kernel stack is aliased in the stacks region, apart from its guard
+    // page, for the cores to run on
+    for kstack in kernel_stacks() {
+        let range = PhysRange::new(kstack.guard_range().end(), kstack.range.end());
+        let va = kstack.guard_virt_range().end();
+        let stats = kernel_space.map_range(va, &range, MapFlags::RW).unwrap_or_else(|err| {
+            panic!("error:init:mapping kernel stack {range} at {va} failed: {err}")
+        });
+        total_stats += stats;
+
+        debug!(
+            "  {:16}{} to {} flags: {} entries: {}",
+            "Kernel Stack",
+            range,
+            VirtRange::with_len(va, range.size()),
+            MapFlags::RW,
+            stats
         );
     }
+    debug!("  Total entries: {total_stats}");
 
-    if let Err(err) = pagealloc::free_unused_ranges(&available_mem, custom_map.map(|m| m.1).iter())
-    {
-        panic!("error:Couldn't mark unused pages as free: err: {:?}", err);
+    // The KZERO mapping covers just the entries mapped there once these
+    // tables are active
+    let kzero_mapped = custom_map.into_iter().filter(|(_, va, range, _)| *va == kzero(range));
+    set_kzero_mapped(kzero_mapped.map(|(_, _, range, _)| range));
+}
+
+/// Remap each section of the kernel image with its intended flags, once the
+/// kernel page tables are live, so that text isn't writable and data isn't
+/// executable.  Sections with no flags, such as boottext, are unmapped, as are
+/// the guard pages of the kernel stacks.
+pub fn protect_kernel_sections(kernel_space: &mut AddressSpace) -> Result<(), MemError> {
+    for section in kernel_sections() {
+        let range = physrange_as_virtrange_offset_from_kzero(&section.range);
+        if section.flags.is_empty() {
+            kernel_space.unmap(&range)?;
+        } else {
+            kernel_space.protect(&range, section.flags)?;
+        }
+    }
+    for stack in kernel_stacks() {
+        let guard = physrange_as_virtrange_offset_from_kzero(&stack.guard_range());
+        kernel_space.unmap(&guard)?;
     }
+    Ok(())
 }
 
-pub unsafe fn init_user_page_tables(new_user_root_page_table: &mut RootPageTable) {
-    unsafe { init_empty_root_page_table(new_user_root_page_table) };
+/// Unmap the GiB l.S identity maps for the boot core, once nothing can run
+/// through it: the boot core switched to its own user address space when the
+/// kernel page tables were set up, and secondary cores start through their
+/// own trampoline window.  The entry is cleared through the direct map, as
+/// boottext, which holds it, is no longer in the KZERO mapping.
+pub fn teardown_boot_identity_window() {
+    let entry_pa = boot_identity_entry_physaddr();
+    let entry_va = match dmap::phys_to_dmap(entry_pa) {
+        Ok(va) => va,
+        Err(err) => {
+            println!("error:vm:teardown_boot_identity_window:entry not in direct map: {err}");
+            return;
+        }
+    };
+    unsafe {
+        write_volatile(entry_va.addr() as *mut Entry, Entry::empty());
+        invalidate_all_tlb_entries();
+    }
+    remove_identity_window("boot");
+}
+
+/// Check the kernel image is mapped in the current page tables as the linker
+/// laid it out.  Each page of each section must map to its own frame with
+/// exactly the section's flags, or be unmapped if the section has no flags or
+/// the page is a kernel stack guard page.  Pages of the image outside the
+/// sections, such as padding and the early page tables, must not be
+/// executable.  The aliases of the kernel stacks must map to the stacks,
+/// read-write, apart from their guard pages.  Each offending page is listed
+/// before panicking.
+pub fn verify_kernel_mapping() {
+    let sections = kernel_sections();
+    let guards = kernel_stacks().map(|stack| stack.guard_range());
+    let mut failures = 0;
+    for pa in total_kernel_range().step_by_rounded(PAGE_SIZE_4K) {
+        let va = VirtAddr::new((pa.addr() as usize).wrapping_add(kzero_offset()));
+        let actual = lookup(va).map(|mapping| (mapping.pa, mapping.entry.flags()));
+        let ok = match sections.iter().find(|section| section.range.0.contains(&pa)) {
+            Some(section) => {
+                let in_guard = guards.iter().any(|guard| guard.0.contains(&pa));
+                let expected =
+                    (!section.flags.is_empty() && !in_guard).then_some((pa, section.flags));
+                if actual != expected {
+                    println!(
+                        "error:vm:verify_kernel_mapping:{} page {va:?} expected:{expected:?} actual:{actual:?}",
+                        section.name
+                    );
+                }
+                actual == expected
+            }
+            None => {
+                let executable = actual.is_some_and(|(_, flags)| flags.contains(MapFlags::EXECUTE));
+                if executable {
+                    println!(
+                        "error:vm:verify_kernel_mapping:page {va:?} outside the sections is executable. actual:{actual:?}"
+                    );
+                }
+                !executable
+            }
+        };
+        failures += usize::from(!ok);
+    }
+    for stack in kernel_stacks() {
+        let guard = stack.guard_virt_range();
+        for pa in stack.range.step_by_rounded(PAGE_SIZE_4K) {
+            let offset = (pa.addr() - stack.range.start().addr()) as usize;
+            let va = stack.virt_range().start() + offset;
+            let actual = lookup(va).map(|mapping| (mapping.pa, mapping.entry.flags()));
+            let expected = (!guard.0.contains(&va)).then_some((pa, MapFlags::RW));
+            if actual != expected {
+                println!(
+                    "error:vm:verify_kernel_mapping:kernel stack page {va:?} expected:{expected:?} actual:{actual:?}"
+                );
+                failures += 1;
+            }
+        }
+    }
+    assert_eq!(
+        failures, 0,
+        "vm:verify_kernel_mapping:{failures} pages of the kernel image mapped wrongly"
+    );
 }
 
 /// Given an empty, statically allocated page table.  We need to write a
@@ -631,17 +1575,20 @@ fn ttbr1_el1() -> PhysAddr {
     PhysAddr::new(0)
 }
 
-// TODO this should just call invalidate_all_tlb_entries afterwards?
+/// Load `root` into the translation table base register for `pgtype`, and
+/// invalidate the TLB, so nothing is translated with the old tables.
 #[allow(unused_variables)]
-pub unsafe fn switch(page_table: &RootPageTable, pgtype: RootPageTableType) {
+unsafe fn switch(root: PhysAddr, pgtype: RootPageTableType) {
     #[cfg(not(test))]
     unsafe {
-        let pt_phys = from_ptr_to_physaddr_offset_from_kzero(page_table).addr();
+        let pt_phys = root.addr();
         // https://forum.osdev.org/viewtopic.php?t=36412&p=303237
         match pgtype {
             RootPageTableType::User => {
                 core::arch::asm!(
+                    "dsb ishst",      // ensure table writes are visible to the walker
                     "msr ttbr0_el1, {pt_phys}",
+                    "isb",            // ensure the new root is in use
                     "tlbi vmalle1is", // invalidate all TLB entries
                     "dsb ish",      // ensure write has completed
                     "isb",          // synchronize context and ensure that no instructions
@@ -650,7 +1597,9 @@ pub unsafe fn switch(page_table: &RootPageTable, pgtype: RootPageTableType) {
             }
             RootPageTableType::Kernel => {
                 core::arch::asm!(
+                    "dsb ishst",      // ensure table writes are visible to the walker
                     "msr ttbr1_el1, {pt_phys}",
+                    "isb",            // ensure the new root is in use
                     "tlbi vmalle1is", // invalidate all TLB entries
                     "dsb ish",      // ensure write has completed
                     "isb",          // synchronize context and ensure that no instructions
@@ -661,8 +1610,26 @@ pub unsafe fn switch(page_table: &RootPageTable, pgtype: RootPageTableType) {
     }
 }
 
+/// Invalidate the TLB entries for the page containing `va`, at all levels of
+/// the walk.
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_entry(va: VirtAddr) {
+    TLBI_PAGE.inc();
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",           // ensure the cleared entry is visible to the walker
+            "tlbi vae1is, {page}", // invalidate the page's entries
+            "dsb ish",             // ensure invalidation has completed
+            "isb",                 // synchronize context so no instructions are
+                                   // fetched using the old translation
+            page = in(reg) (va.addr() >> 12) & 0xfff_ffff_ffff);
+    }
+}
+
 #[allow(unused_variables)]
 pub unsafe fn invalidate_all_tlb_entries() {
+    TLBI_ALL.inc();
     #[cfg(not(test))]
     unsafe {
         // https://forum.osdev.org/viewtopic.php?t=36412&p=303237
@@ -678,6 +1645,7 @@ pub unsafe fn invalidate_all_tlb_entries() {
 #[cfg(test)]
 mod tests {
     use crate::vmdebug::va_indices;
+    use port::tableframes::VecFrames;
 
     use super::*;
 
@@ -739,4 +1707,482 @@ mod tests {
             (511, 256, 0, 64)
         );
     }
+
+    /// Software walker over tables in hosted frames, recording the entries
+    /// invalidated and replaced.  Replacing entries fails while
+    /// `fail_replace` is set, as if there were no trampoline.
+    struct TestWalker {
+        frames: VecFrames,
+        invalidated: Vec<VirtAddr>,
+        replaced: Vec<VirtAddr>,
+        fail_replace: bool,
+    }
+
+    impl TestWalker {
+        /// Frames are allocated from 0x8000_0000.
+        fn new() -> Self {
+            let frames = VecFrames::new(PhysAddr::new(0x8000_0000));
+            Self { frames, invalidated: Vec::new(), replaced: Vec::new(), fail_replace: false }
+        }
+
+        /// Allocate an empty table, returning its physical address.
+        fn alloc_table(&mut self) -> PhysAddr {
+            let pa = self.frames.alloc_frame().unwrap();
+            let table = unsafe { &mut *(self.frames.frame_ptr(pa) as *mut Table) };
+            table.entries.fill(Entry::empty());
+            pa
+        }
+
+        /// Allocate an empty root table, which is always at 0x8000_0000.
+        fn new_root(&mut self) -> &'static mut Table {
+            let pa = self.alloc_table();
+            unsafe { &mut *(self.frames.frame_ptr(pa) as *mut Table) }
+        }
+
+        /// Return the table referenced by `entry`.
+        fn table(&mut self, entry: Entry) -> &'static Table {
+            unsafe { &*(self.frames.frame_ptr(PhysAddr::new(entry.addr() << 12)) as *const Table) }
+        }
+    }
+
+    impl TableWalker for TestWalker {
+        type Frames = VecFrames;
+
+        fn next_table(&mut self, entry: Entry, _level: Level, _va: VirtAddr) -> *mut Table {
+            self.frames.frame_ptr(PhysAddr::new(entry.addr() << 12)) as *mut Table
+        }
+
+        fn invalidate(&mut self, va: VirtAddr) {
+            self.invalidated.push(va);
+        }
+
+        fn invalidate_table(&mut self, _level: Level, _va: VirtAddr) {}
+
+        fn replace_entry(
+            &mut self,
+            entry: &mut Entry,
+            new_entry: Entry,
+            _level: Level,
+            va: VirtAddr,
+        ) -> Result<(), MemError> {
+            if self.fail_replace {
+                return Err(MemError::NoTrampoline { va });
+            }
+            *entry = new_entry;
+            self.replaced.push(va);
+            Ok(())
+        }
+
+        fn frames(&mut self) -> &mut VecFrames {
+            &mut self.frames
+        }
+    }
+
+    /// Map `va` with a page or block entry at `leaf_level`, creating any
+    /// intermediate tables.
+    fn test_map(walker: &mut TestWalker, root: &mut Table, va: usize, leaf_level: Level) {
+        let va = VirtAddr::new(va);
+        let mut table = root as *mut Table;
+        let mut level = Level::Level0;
+        while level != leaf_level {
+            let index = va_index(va, level);
+            let entries = unsafe { &mut (*table).entries };
+            if !entries[index].valid() {
+                let next_pa = walker.alloc_table();
+                entries[index] =
+                    Entry::rw_kernel_data().with_phys_addr(next_pa).with_page_or_table(true);
+            }
+            table = walker.next_table(entries[index], level, va);
+            level = level.next().unwrap();
+        }
+        unsafe { &mut *table }.entries[va_index(va, level)] = Entry::rw_kernel_data()
+            .with_phys_addr(PhysAddr::new(va.addr() as u64))
+            .with_page_or_table(level == Level::Level3);
+    }
+
+    #[test]
+    fn unmap_frees_empty_tables() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000_0000, Level::Level3);
+        test_map(&mut walker, root, 0x1000_1000, Level::Level3);
+
+        // The level 3 table still has a page mapped, so nothing is freed
+        assert_eq!(walker.frames.allocated(), 4);
+        assert!(
+            !unmap_entries(root, Level::Level0, 0x1000_0000, 0x1000_1000, &mut walker).unwrap()
+        );
+        assert_eq!(walker.invalidated, [VirtAddr::new(0x1000_0000)]);
+        assert_eq!(walker.frames.allocated(), 4);
+
+        // Unmapping the last page frees the level 3, 2 and 1 tables
+        walker.invalidated.clear();
+        assert!(unmap_entries(root, Level::Level0, 0x1000_0000, 0x1000_2000, &mut walker).unwrap());
+        assert_eq!(
+            walker.invalidated,
+            [0x1000_1000, 0x1000_0000, 0x1000_0000, 0x1000_0000].map(VirtAddr::new)
+        );
+        assert_eq!(walker.frames.allocated(), 1);
+        assert!(root.entries.iter().all(|e| !e.valid()));
+    }
+
+    #[test]
+    fn unmap_unmapped_is_noop() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        assert!(unmap_entries(root, Level::Level0, 0, 0x4000_0000, &mut walker).unwrap());
+        assert!(walker.invalidated.is_empty());
+        assert_eq!(walker.frames.allocated(), 1);
+    }
+
+    #[test]
+    fn unmap_partially_mapped() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000, Level::Level3);
+        test_map(&mut walker, root, 0x3000, Level::Level3);
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+        test_map(&mut walker, root, 0x40_0000, Level::Level2);
+
+        // Unmap the pages, the holes between them, and the first block
+        assert_eq!(walker.frames.allocated(), 4);
+        assert!(!unmap_entries(root, Level::Level0, 0, 0x40_0000, &mut walker).unwrap());
+        assert_eq!(walker.invalidated, [0x1000, 0x3000, 0, 0x20_0000].map(VirtAddr::new));
+        assert_eq!(walker.frames.allocated(), 3);
+
+        // Unmapping the rest frees the remaining tables
+        walker.invalidated.clear();
+        assert!(unmap_entries(root, Level::Level0, 0, 0x80_0000, &mut walker).unwrap());
+        assert_eq!(walker.invalidated, [0x40_0000, 0, 0].map(VirtAddr::new));
+        assert_eq!(walker.frames.allocated(), 1);
+    }
+
+    #[test]
+    fn unmap_splits_partial_block() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+
+        // The block is split into pages, and only the first is unmapped
+        assert!(!unmap_entries(root, Level::Level0, 0x20_0000, 0x20_1000, &mut walker).unwrap());
+        assert_eq!(walker.replaced, [VirtAddr::new(0x20_0000)]);
+        assert_eq!(walk(root, VirtAddr::new(0x20_0000), &mut walker), None);
+        let mapping = walk(root, VirtAddr::new(0x20_1000), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x20_1000));
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+        let mapping = walk(root, VirtAddr::new(0x3f_f000), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x3f_f000));
+
+        // Only the block's entry has changed
+        let l1 = walker.table(root.entries[0]);
+        let l2 = walker.table(l1.entries[0]);
+        assert_eq!(l2.entries.iter().filter(|e| e.valid()).count(), 1);
+
+        assert_eq!(walker.frames.allocated(), 4);
+        assert!(unmap_entries(root, Level::Level0, 0x20_0000, 0x40_0000, &mut walker).unwrap());
+        assert_eq!(walker.frames.allocated(), 1);
+    }
+
+    #[test]
+    fn protect_splits_partial_block() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+        test_map(&mut walker, root, 0x4000_0000, Level::Level1);
+
+        // Split a 2M block into pages, and a 1G block into 2M blocks
+        let template = Entry::from_flags(MapFlags::RX).unwrap();
+        protect_entries(root, Level::Level0, 0x20_0000, 0x30_0000, template, &mut walker).unwrap();
+        protect_entries(root, Level::Level0, 0x4000_0000, 0x4020_0000, template, &mut walker)
+            .unwrap();
+
+        for (va, page_size, flags) in [
+            (0x20_0000, PageSize::Page4K, MapFlags::RX),
+            (0x2f_f000, PageSize::Page4K, MapFlags::RX),
+            (0x30_0000, PageSize::Page4K, MapFlags::RW),
+            (0x3f_f000, PageSize::Page4K, MapFlags::RW),
+            (0x4000_0000, PageSize::Page2M, MapFlags::RX),
+            (0x4020_0000, PageSize::Page2M, MapFlags::RW),
+            (0x7fe0_0000, PageSize::Page2M, MapFlags::RW),
+        ] {
+            let mapping = walk(root, VirtAddr::new(va), &mut walker).unwrap();
+            assert_eq!(mapping.pa, PhysAddr::new(va as u64));
+            assert_eq!(mapping.page_size, page_size);
+            assert_eq!(mapping.entry.flags(), flags);
+        }
+
+        // A block in the table split from the 1G block can be split again,
+        // though the table is full
+        protect_entries(root, Level::Level0, 0x4020_0000, 0x4030_0000, template, &mut walker)
+            .unwrap();
+        for (va, flags) in [(0x4020_0000, MapFlags::RX), (0x4030_0000, MapFlags::RW)] {
+            let mapping = walk(root, VirtAddr::new(va), &mut walker).unwrap();
+            assert_eq!(mapping.page_size, PageSize::Page4K);
+            assert_eq!(mapping.entry.flags(), flags);
+        }
+    }
+
+    #[test]
+    fn split_block_failure_frees_table() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+        assert_eq!(walker.frames.allocated(), 3);
+
+        // The block can't be replaced, so it's left whole, and the table
+        // allocated for the split is freed again
+        walker.fail_replace = true;
+        let template = Entry::from_flags(MapFlags::RX).unwrap();
+        assert!(matches!(
+            protect_entries(root, Level::Level0, 0x20_0000, 0x30_0000, template, &mut walker),
+            Err(MemError::NoTrampoline { .. })
+        ));
+        assert_eq!(walker.frames.allocated(), 3);
+        let mapping = walk(root, VirtAddr::new(0x30_0000), &mut walker).unwrap();
+        assert_eq!(mapping.page_size, PageSize::Page2M);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+
+        // Once it can be, the split succeeds
+        walker.fail_replace = false;
+        protect_entries(root, Level::Level0, 0x20_0000, 0x30_0000, template, &mut walker).unwrap();
+        assert_eq!(walker.frames.allocated(), 4);
+    }
+
+    #[test]
+    fn protect_skips_unmapped() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000, Level::Level3);
+        test_map(&mut walker, root, 0x3000, Level::Level3);
+
+        let template = Entry::from_flags(MapFlags::READ).unwrap();
+        protect_entries(root, Level::Level0, 0, 0x4000_0000, template, &mut walker).unwrap();
+        assert_eq!(walker.replaced, [0x1000, 0x3000].map(VirtAddr::new));
+        assert_eq!(walk(root, VirtAddr::new(0x2000), &mut walker), None);
+        let mapping = walk(root, VirtAddr::new(0x3000), &mut walker).unwrap();
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert_eq!(mapping.entry.flags(), MapFlags::READ);
+    }
+
+    #[test]
+    fn update_entries_changes_mem_type() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000, Level::Level3);
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+
+        // Each entry whose type changes is replaced with break-before-make,
+        // and keeps its permissions
+        let mair_index = Mair::from_mem_type(MemType::NormalNonCacheable);
+        let update = |entry: Entry| entry.with_mair_index(mair_index);
+        update_entries(root, Level::Level0, 0, 0x40_0000, &update, &mut walker).unwrap();
+        assert_eq!(walker.replaced, [0x1000, 0x20_0000].map(VirtAddr::new));
+        for va in [0x1000, 0x20_0000] {
+            let mapping = walk(root, VirtAddr::new(va), &mut walker).unwrap();
+            assert_eq!(mapping.entry.flags(), MapFlags::RW | MapFlags::WRITE_COMBINING);
+        }
+
+        // Entries already of the type are left alone
+        walker.replaced.clear();
+        update_entries(root, Level::Level0, 0, 0x40_0000, &update, &mut walker).unwrap();
+        assert!(walker.replaced.is_empty());
+    }
+
+    #[test]
+    fn break_before_make_needs_trampoline_for_own_code() {
+        let mut entry = Entry::rw_kernel_data().with_page_or_table(true);
+        let new_entry = Entry::from_flags(MapFlags::RX).unwrap().with_page_or_table(true);
+        let other_va = VirtAddr::new(0x1000);
+        assert!(break_before_make(&mut entry, new_entry, Level::Level3, other_va).is_ok());
+        assert_eq!(entry, new_entry);
+
+        // The trampoline isn't mapped, so the entry mapping the code can't be
+        // replaced
+        let code_va = VirtAddr::new(bbm_replace_entry as usize);
+        assert!(matches!(
+            break_before_make(&mut entry, Entry::empty(), Level::Level3, code_va),
+            Err(MemError::NoTrampoline { va }) if va == code_va
+        ));
+        assert_eq!(entry, new_entry);
+    }
+
+    /// Map `start..end` read-write to the physical range starting at `pa`.
+    fn test_map_range(
+        walker: &mut TestWalker,
+        root: &mut Table,
+        start: usize,
+        end: usize,
+        pa: u64,
+    ) -> Result<MapStats, MemError> {
+        let template = Entry::from_flags(MapFlags::RW).unwrap();
+        let mut stats = MapStats::default();
+        let pa = PhysAddr::new(pa);
+        map_entries(root, Level::Level0, start, end, pa, template, walker, &mut stats)?;
+        Ok(stats)
+    }
+
+    #[test]
+    fn map_uses_largest_blocks() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+
+        // Pages and 2M blocks at the edges of a 1G block
+        let stats =
+            test_map_range(&mut walker, root, 0x3fe0_0000, 0x8000_1000, 0x3fe0_0000).unwrap();
+        assert_eq!(stats, MapStats { blocks_1g: 1, blocks_2m: 1, pages_4k: 1 });
+        for (va, page_size) in [
+            (0x3fe0_0000, PageSize::Page2M),
+            (0x4000_0000, PageSize::Page1G),
+            (0x8000_0000, PageSize::Page4K),
+        ] {
+            let mapping = walk(root, VirtAddr::new(va), &mut walker).unwrap();
+            assert_eq!(mapping.pa, PhysAddr::new(va as u64));
+            assert_eq!(mapping.page_size, page_size);
+            assert_eq!(mapping.entry.flags(), MapFlags::RW);
+        }
+
+        // The physical address isn't aligned, so only pages can be used
+        let stats = test_map_range(&mut walker, root, 0x20_0000, 0x40_0000, 0x1000).unwrap();
+        assert_eq!(stats, MapStats { blocks_1g: 0, blocks_2m: 0, pages_4k: 512 });
+        let mapping = walk(root, VirtAddr::new(0x3f_f000), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x20_0000));
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+    }
+
+    #[test]
+    fn map_fails_if_already_mapped() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000, Level::Level3);
+        test_map(&mut walker, root, 0x40_0000, Level::Level2);
+
+        // An existing page, a page within an existing block, and a block
+        // over an existing table of pages
+        for (start, end) in [(0x1000, 0x2000), (0x40_1000, 0x40_2000), (0, 0x20_0000)] {
+            assert_eq!(
+                test_map_range(&mut walker, root, start, end, start as u64),
+                Err(MemError::AlreadyMapped { va: VirtAddr::new(start) })
+            );
+        }
+
+        // The existing mappings are unchanged
+        let mapping = walk(root, VirtAddr::new(0x1000), &mut walker).unwrap();
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        let mapping = walk(root, VirtAddr::new(0x40_1000), &mut walker).unwrap();
+        assert_eq!(mapping.page_size, PageSize::Page2M);
+    }
+
+    #[test]
+    fn walk_finds_pages_and_blocks() {
+        let mut walker = TestWalker::new();
+        let root = walker.new_root();
+        test_map(&mut walker, root, 0x1000, Level::Level3);
+        test_map(&mut walker, root, 0x20_0000, Level::Level2);
+        test_map(&mut walker, root, 0x4000_0000, Level::Level1);
+
+        let mapping = walk(root, VirtAddr::new(0x1234), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x1234));
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+
+        let mapping = walk(root, VirtAddr::new(0x2f_f000), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x2f_f000));
+        assert_eq!(mapping.page_size, PageSize::Page2M);
+
+        let mapping = walk(root, VirtAddr::new(0x4567_8000), &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x4567_8000));
+        assert_eq!(mapping.page_size, PageSize::Page1G);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+
+        // Unmapped in the level 3 table, an invalid level 2 entry, and an
+        // invalid level 0 entry
+        assert_eq!(walk(root, VirtAddr::new(0x2000), &mut walker), None);
+        assert_eq!(walk(root, VirtAddr::new(0x40_0000), &mut walker), None);
+        assert_eq!(walk(root, VirtAddr::new(0x80_0000_0000), &mut walker), None);
+    }
+
+    #[test]
+    fn map_writes_exact_descriptors() {
+        // Intermediate tables are read-write kernel data, pointing at the
+        // next frame allocated
+        const TABLE: u64 = 0x0060_0000_0000_0703;
+        let attrs = [
+            (MapFlags::READ, 0x0060_0000_0000_0780),
+            (MapFlags::RW, 0x0060_0000_0000_0700),
+            (MapFlags::RX, 0x0040_0000_0000_0780),
+            (MapFlags::RW | MapFlags::DEVICE, 0x0060_0000_0000_0704),
+            (MapFlags::RW | MapFlags::NON_CACHEABLE, 0x0060_0000_0000_0708),
+            (MapFlags::RW | MapFlags::DEVICE_RELAXED, 0x0060_0000_0000_070c),
+            (MapFlags::READ | MapFlags::USER, 0x0060_0000_0000_07c0),
+            (MapFlags::RW | MapFlags::USER, 0x0060_0000_0000_0740),
+            (MapFlags::RX | MapFlags::USER, 0x0020_0000_0000_07c0),
+        ];
+        let sizes = [
+            (PageSize::Page4K, Level::Level3),
+            (PageSize::Page2M, Level::Level2),
+            (PageSize::Page1G, Level::Level1),
+        ];
+        let va = VirtAddr::new(0x80_4000_0000);
+        let pa = PhysAddr::new(0x1_4000_0000);
+
+        for (flags, attrs) in attrs {
+            for (page_size, leaf_level) in sizes {
+                let mut walker = TestWalker::new();
+                let root = walker.new_root();
+                let template = Entry::from_flags(flags).unwrap();
+                let (start, end) = (va.addr(), va.addr() + page_size.size());
+                let mut stats = MapStats::default();
+                let walker = &mut walker;
+                map_entries(root, Level::Level0, start, end, pa, template, walker, &mut stats)
+                    .unwrap();
+
+                let mut table: &Table = root;
+                let mut level = Level::Level0;
+                let mut table_pa = 0x8000_1000;
+                while level != leaf_level {
+                    let entry = table.entries[va_index(va, level)];
+                    assert_eq!(entry.0, TABLE | table_pa, "{flags:?} {level:?}");
+                    table = walker.table(entry);
+                    table_pa += 0x1000;
+                    level = level.next().unwrap();
+                }
+                let page = if leaf_level == Level::Level3 { 0b11 } else { 0b01 };
+                let leaf = table.entries[va_index(va, level)];
+                assert_eq!(leaf.0, attrs | pa.addr() | page, "{flags:?} {page_size:?}");
+                let mapping = Mapping { pa, page_size, entry: leaf };
+                assert_eq!(walk(root, va, walker), Some(mapping));
+
+                // Only the entries on the path were written
+                let valid = |table: &Table| table.entries.iter().filter(|e| e.valid()).count();
+                assert_eq!(valid(root), 1);
+                assert_eq!(walker.frames.allocated(), leaf_level as usize + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn entry_flags() {
+        for flags in [
+            MapFlags::READ,
+            MapFlags::RW,
+            MapFlags::RX,
+            MapFlags::RW | MapFlags::DEVICE,
+            MapFlags::RW | MapFlags::NON_CACHEABLE,
+            MapFlags::RW | MapFlags::DEVICE_RELAXED,
+            MapFlags::READ | MapFlags::USER,
+            MapFlags::RW | MapFlags::EXECUTE | MapFlags::USER,
+        ] {
+            assert_eq!(Entry::from_flags(flags).unwrap().flags(), flags);
+        }
+
+        let entry = Entry::from_flags(MapFlags::RX).unwrap();
+        assert!(!entry.pxn() && entry.uxn());
+        let entry = Entry::from_flags(MapFlags::RX | MapFlags::USER).unwrap();
+        assert!(entry.pxn() && !entry.uxn());
+
+        assert!(matches!(
+            Entry::from_flags(MapFlags::RW | MapFlags::EXECUTE),
+            Err(MapFlagsError::KernelWriteExecute)
+        ));
+    }
 }
@@ -0,0 +1,177 @@
+/// vmap maps physical ranges, such as device registers, at virtual addresses
+/// allocated from a region of kernel address space reserved for the purpose,
+/// rather than at fixed addresses.  The kernel page tables and the page
+/// allocator must be set up before use, since new tables may be needed.
+///
+/// Ranges are mapped a page at a time, so several small ranges in the same
+/// pages, e.g. the register blocks of devices sharing a page, share one
+/// mapping, which is only unmapped once all of them have been unmapped.
+use crate::kmem::record_addr_range;
+use crate::param::{MAX_VMAPS, VMAP};
+use crate::vm::AddressSpace;
+use port::addrmap::AddrKind;
+use port::fdt::{DeviceRegion, Translated};
+use port::mcslock::{Lock, LockNode};
+use port::mem::{
+    MapFlags, MemError, MemType, PAGE_SIZE_2M, PAGE_SIZE_4K, PhysRange, VirtAddr, VirtRange,
+};
+use port::sharedmaps::{SharedMapError, SharedMaps};
+use port::vaalloc::{VaAlloc, VaAllocError};
+
+#[cfg(not(test))]
+use port::println;
+
+/// The virtual ranges allocated, and the mappings made in them.
+struct Vmaps {
+    alloc: VaAlloc<MAX_VMAPS>,
+    maps: SharedMaps<MAX_VMAPS>,
+}
+
+static VMAPS_LOCK: Lock<Vmaps> = Lock::new(
+    "vmap",
+    Vmaps { alloc: VaAlloc::new(VirtAddr::new(VMAP.start), VMAP.size), maps: SharedMaps::new() },
+);
+
+impl Vmaps {
+    /// Return the number of bytes of the vmap region not allocated.
+    fn free_size(&self) -> usize {
+        VMAP.size - self.alloc.allocated().iter().map(VirtRange::size).sum::<usize>()
+    }
+
+    /// Return the error for a failure to allocate `size` bytes of the region.
+    fn alloc_error(&self, err: VaAllocError, size: usize) -> MemError {
+        match err {
+            VaAllocError::Exhausted => {
+                MemError::OutOfMemory { requested: size, available: self.free_size() }
+            }
+            VaAllocError::TooManyAllocations | VaAllocError::NotAllocated => MemError::TableFull,
+        }
+    }
+}
+
+/// Return the error for a failure to record or release a mapping at `va`.
+fn shared_map_error(err: SharedMapError, va: VirtAddr) -> MemError {
+    match err {
+        SharedMapError::TooManyMappings => MemError::TableFull,
+        SharedMapError::NotMapped => MemError::NotMapped { va },
+    }
+}
+
+port::counter!(static VMAPS = "vmap.maps");
+port::counter!(static VUNMAPS = "vmap.unmaps");
+port::counter!(static VMAP_SHARES = "vmap.shares");
+
+/// Record the vmap region in the kernel address map.
+pub fn init() {
+    record_addr_range("vmap", AddrKind::Vmap, VMAP.range(), None);
+}
+
+/// Map the physical range as device memory with `flags` at a free range of
+/// the vmap region, returning the virtual range it's mapped at.  If `flags`
+/// select another memory type, it's used instead, e.g. WRITE_COMBINING for a
+/// framebuffer, or DEVICE_RELAXED for registers that can take gathered
+/// writes.  The pages covering the range are mapped, but the returned range
+/// starts and ends at the same offsets within them as `phys`.  If the pages
+/// are already mapped with the same flags, that mapping is shared.  Ranges of
+/// 2MiB or more are 2MiB aligned, so they can be mapped with blocks where
+/// possible.
+pub fn vmap(phys: &PhysRange, flags: MapFlags) -> Result<VirtRange, MemError> {
+    let flags = if flags.mem_type() == MemType::NormalCached {
+        flags.with_mem_type(MemType::DeviceStrict)
+    } else {
+        flags
+    };
+    vmap_memory(phys, flags)
+}
+
+/// As `vmap`, but the range is mapped with exactly `flags`, so it's normal,
+/// cacheable memory unless they select another memory type, e.g. for a DMA
+/// buffer.
+pub fn vmap_memory(phys: &PhysRange, flags: MapFlags) -> Result<VirtRange, MemError> {
+    let pages = phys.round_out(PAGE_SIZE_4K as u64);
+    let align = if pages.size() >= PAGE_SIZE_2M { PAGE_SIZE_2M } else { PAGE_SIZE_4K };
+
+    let node = LockNode::new();
+    let mut vmaps = VMAPS_LOCK.lock(&node);
+    if let Some(window) = vmaps.maps.get(phys, flags) {
+        VMAP_SHARES.inc();
+        return Ok(window);
+    }
+    let va_range = match vmaps.alloc.alloc(pages.size(), align) {
+        Ok(va_range) => va_range,
+        Err(err) => {
+            let err = vmaps.alloc_error(err, pages.size());
+            println!(
+                "error:vmap:vmap_memory:couldn't allocate virtual range. phys:{phys} err:{err}"
+            );
+            return Err(err);
+        }
+    };
+
+    let mut kernel_space = AddressSpace::kernel();
+    let result = kernel_space.map_range(va_range.start(), &pages, flags).and_then(|_| {
+        let va = va_range.start();
+        vmaps.maps.insert(phys, va, flags).map_err(|err| shared_map_error(err, va))
+    });
+    let window = match result {
+        Ok(window) => window,
+        Err(err) => {
+            println!("error:vmap:vmap_memory:couldn't map range. phys:{phys} err:{err}");
+            // Tidy up whatever was mapped before the failure
+            let _ = kernel_space.unmap(&va_range);
+            let _ = vmaps.alloc.free(&va_range);
+            return Err(err);
+        }
+    };
+
+    VMAPS.inc();
+    Ok(window)
+}
+
+/// Unmap a range returned by vmap.  If it shares its mapping with other
+/// ranges, the mapping is left for them, otherwise it's torn down, and its
+/// virtual addresses released.
+pub fn vunmap(range: &VirtRange) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut vmaps = VMAPS_LOCK.lock(&node);
+    let unmapped = vmaps.maps.put(range).map_err(|err| {
+        let err = shared_map_error(err, range.start());
+        println!("error:vmap:vunmap:range wasn't mapped by vmap. range:{range} err:{err}");
+        err
+    })?;
+    let Some(va_range) = unmapped else {
+        return Ok(());
+    };
+    vmaps.alloc.free(&va_range).map_err(|_| MemError::NotMapped { va: va_range.start() })?;
+    AddressSpace::kernel().unmap(&va_range)?;
+    VUNMAPS.inc();
+    Ok(())
+}
+
+/// Return the virtual range `phys` is mapped at by vmap, if all of it is,
+/// writable.
+pub fn phys_to_vmap(phys: &PhysRange) -> Option<VirtRange> {
+    let node = LockNode::new();
+    let vmaps = VMAPS_LOCK.lock(&node);
+    let (window, flags) = vmaps.maps.find(phys)?;
+    flags.contains(MapFlags::RW).then_some(window)
+}
+
+/// Call `f` with the pages, virtual range, flags and number of users of each
+/// mapping made by vmap.
+pub fn for_each_map(f: impl FnMut(&PhysRange, &VirtRange, MapFlags, usize)) {
+    let node = LockNode::new();
+    let vmaps = VMAPS_LOCK.lock(&node);
+    vmaps.maps.for_each(f);
+}
+
+/// Map the device registers at `phys` with vmap, read-write.
+pub fn vmap_device(phys: &PhysRange) -> Result<VirtRange, MemError> {
+    vmap(phys, MapFlags::RW)
+}
+
+/// Map the registers of `region` with vmap, read-write.  The region must
+/// have been translated, so its address is physical.
+pub fn vmap_region(region: &DeviceRegion<Translated>) -> Result<VirtRange, MemError> {
+    vmap_device(&PhysRange::from(&region.reg()))
+}
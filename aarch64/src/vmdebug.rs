@@ -1,9 +1,13 @@
 //! Debug tools for VM code
 
 #[cfg(not(test))]
-use port::println;
+use port::{print, println};
 
-use crate::vm::{Entry, Level, RootPageTable, RootPageTableType, Table};
+use crate::kmem::{kernel_sections, physrange_as_virtrange_offset_from_kzero};
+use crate::vm::{Entry, Level, Mapping, PageSize, RootPageTable, RootPageTableType, Table};
+use core::fmt;
+use port::framerefs::RefCount;
+use port::mem::{PhysAddr, VirtAddr};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 struct PteIndices {
@@ -162,9 +166,179 @@ fn print_pte_table(indent: usize, i: usize, pte: Entry, table_va: usize) {
     );
 }
 
+/// A run of mappings, contiguous in both virtual and physical memory, with the
+/// same page size and attributes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MappingRun {
+    va: usize,
+    pa: u64,
+    size: usize,
+    mapping: Mapping,       // First mapping in the run
+    refs: Option<RefCount>, // References to each page in the run, if counted
+}
+
+impl MappingRun {
+    fn new(va: usize, mapping: Mapping, refs: Option<RefCount>) -> Self {
+        Self { va, pa: mapping.pa.addr(), size: mapping.page_size.size(), mapping, refs }
+    }
+
+    /// Extend the run with the mapping at `va` if it follows on from the run
+    /// and has the same attributes and reference count, returning false
+    /// otherwise.
+    fn extend(&mut self, va: usize, mapping: &Mapping, refs: Option<RefCount>) -> bool {
+        let attrs = |m: &Mapping| m.entry.with_addr(0);
+        if va != self.va.wrapping_add(self.size)
+            || mapping.pa.addr() != self.pa + self.size as u64
+            || mapping.page_size != self.mapping.page_size
+            || attrs(mapping) != attrs(&self.mapping)
+            || refs != self.refs
+        {
+            return false;
+        }
+        self.size += mapping.page_size.size();
+        true
+    }
+}
+
+/// Formats a size in the largest whole unit.
+struct ByteSize(usize);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            n if n != 0 && n.is_multiple_of(1 << 30) => write!(f, "{}GiB", n >> 30),
+            n if n != 0 && n.is_multiple_of(1 << 20) => write!(f, "{}MiB", n >> 20),
+            n if n.is_multiple_of(1 << 10) => write!(f, "{}KiB", n >> 10),
+            n => write!(f, "{}B", n),
+        }
+    }
+}
+
+impl fmt::Display for MappingRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (page_size, kind) = match self.mapping.page_size {
+            PageSize::Page4K => ("4K", "page"),
+            PageSize::Page2M => ("2M", "block"),
+            PageSize::Page1G => ("1G", "block"),
+        };
+        let plural = if self.size > self.mapping.page_size.size() { "s" } else { "" };
+        write!(
+            f,
+            "{}..+{} -> {} {} ({} {}{})",
+            VirtAddr::new(self.va),
+            ByteSize(self.size),
+            PhysAddr::new(self.pa),
+            self.mapping.entry.flags(),
+            page_size,
+            kind,
+            plural,
+        )?;
+        // Only shared pages are marked, since most have a single reference
+        match self.refs {
+            Some(refs) if refs > 1 => write!(f, " refs:{refs}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Print a run, labelled with any kernel sections it overlaps.
+fn print_run(run: &MappingRun) {
+    print!("  {run}");
+    let run_end = run.va.wrapping_add(run.size);
+    for section in kernel_sections() {
+        let range = physrange_as_virtrange_offset_from_kzero(&section.range);
+        if range.start().addr() < run_end && run.va < range.end().addr() {
+            print!(" {}", section.name);
+        }
+    }
+    println!();
+}
+
+/// Call `f` with the virtual address and mapping of each page or block in
+/// the table and its children.
+fn for_each_mapping_at_level(
+    page_table: &Table,
+    level: Level,
+    table_va: usize,
+    pgtype: RootPageTableType,
+    pte_indices: PteIndices,
+    f: &mut impl FnMut(usize, Mapping),
+) {
+    for i in 0..512 {
+        let pte = page_table.entries[i];
+        if !pte.valid() {
+            continue;
+        }
+        let Some(pte_indices) = pte_indices.with_next_index(i) else {
+            continue;
+        };
+
+        if !pte.is_table(level) {
+            let page_size = match level {
+                Level::Level0 => continue,
+                Level::Level1 => PageSize::Page1G,
+                Level::Level2 => PageSize::Page2M,
+                Level::Level3 => PageSize::Page4K,
+            };
+            let pa = PhysAddr::new(pte.addr() << 12);
+            f(pte_indices.to_va(), Mapping { pa, page_size, entry: pte });
+        } else if i != 511 {
+            // Recurse into child table (unless it's the recursive index)
+            let child_table_va = match pgtype {
+                RootPageTableType::User => ((table_va << 9) | (i << 12)) & 0x0000_ffff_ffff_ffff,
+                RootPageTableType::Kernel => (table_va << 9) | (i << 12),
+            };
+            let child_table = unsafe { &*(child_table_va as *const Table) };
+            let next_level = level.next().unwrap();
+            for_each_mapping_at_level(
+                child_table,
+                next_level,
+                child_table_va,
+                pgtype,
+                pte_indices,
+                f,
+            );
+        }
+    }
+}
+
+/// Print a summary of the mappings in the current page tables, collapsing
+/// contiguous runs of mappings with the same attributes into single lines.
+/// This doesn't allocate, so can be used from the panic handler, but must
+/// only be called once the recursive page tables are live.
+pub fn print_mappings(pgtype: RootPageTableType) {
+    println!("Mappings ({pgtype:?}):");
+    let mut run: Option<MappingRun> = None;
+    for_each_mapping_at_level(
+        recursive_root_page_table(pgtype),
+        Level::Level0,
+        recursive_root_page_table_va(pgtype),
+        pgtype,
+        PteIndices::none(pgtype),
+        &mut |va, mapping| {
+            // Blocks map the kernel image and the direct map, which aren't
+            // shared, so only pages are worth checking
+            let refs = match mapping.page_size {
+                PageSize::Page4K => mapping.ref_count(),
+                _ => None,
+            };
+            if run.as_mut().is_some_and(|run| run.extend(va, &mapping, refs)) {
+                return;
+            }
+            if let Some(run) = &run {
+                print_run(run);
+            }
+            run = Some(MappingRun::new(va, mapping, refs));
+        },
+    );
+    if let Some(run) = &run {
+        print_run(run);
+    }
+}
+
 /// Returns a tuple of page table indices for the given virtual address
 #[cfg(test)]
-pub fn va_indices(va: usize) -> (usize, usize, usize, usize) {
+pub fn va_indices(va: port::mem::VirtAddr) -> (usize, usize, usize, usize) {
     use crate::vm::va_index;
 
     (
@@ -178,6 +352,7 @@ pub fn va_indices(va: usize) -> (usize, usize, usize, usize) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use port::mem::{MapFlags, VirtAddr};
 
     #[test]
     fn test_pte_indices() {
@@ -197,12 +372,56 @@ mod tests {
         assert_eq!(p, PteIndices::new(RootPageTableType::User, Some(1), Some(2), Some(3), Some(4)));
 
         let p = PteIndices::new(RootPageTableType::Kernel, Some(15), Some(0), Some(400), Some(4));
-        assert_eq!(va_indices(p.to_va()), (15, 0, 400, 4));
+        assert_eq!(va_indices(VirtAddr::new(p.to_va())), (15, 0, 400, 4));
 
         let p = PteIndices::new(RootPageTableType::User, Some(0), Some(10), Some(40), Some(23));
-        assert_eq!(va_indices(p.to_va()), (0, 10, 40, 23));
+        assert_eq!(va_indices(VirtAddr::new(p.to_va())), (0, 10, 40, 23));
 
-        let va = 0x0000000000001000;
+        let va = VirtAddr::new(0x0000000000001000);
         assert_eq!(va_indices(va), (0, 0, 0, 1));
     }
+
+    #[test]
+    fn test_mapping_runs() {
+        let mapping = |pa: u64, page_size, flags| Mapping {
+            pa: PhysAddr::new(pa),
+            page_size,
+            entry: Entry::from_flags(flags).unwrap().with_addr(pa >> 12),
+        };
+
+        let mut run =
+            MappingRun::new(0x1000, mapping(0x8000, PageSize::Page4K, MapFlags::RW), Some(1));
+        assert!(run.extend(0x2000, &mapping(0x9000, PageSize::Page4K, MapFlags::RW), Some(1)));
+        assert_eq!(run.size, 0x2000);
+
+        // Not contiguous in virtual or physical memory
+        assert!(!run.extend(0x4000, &mapping(0xa000, PageSize::Page4K, MapFlags::RW), Some(1)));
+        assert!(!run.extend(0x3000, &mapping(0xb000, PageSize::Page4K, MapFlags::RW), Some(1)));
+        // Different attributes, page size or reference count
+        assert!(!run.extend(0x3000, &mapping(0xa000, PageSize::Page4K, MapFlags::READ), Some(1)));
+        assert!(!run.extend(0x3000, &mapping(0xa000, PageSize::Page2M, MapFlags::RW), Some(1)));
+        assert!(!run.extend(0x3000, &mapping(0xa000, PageSize::Page4K, MapFlags::RW), Some(2)));
+        assert_eq!(run.size, 0x2000);
+
+        assert_eq!(
+            format!("{run}"),
+            "0x0000000000001000..+8KiB -> 0x0000000000008000 RW- normal (4K pages)"
+        );
+
+        let run = MappingRun::new(0x1000, mapping(0x8000, PageSize::Page4K, MapFlags::RW), Some(2));
+        assert_eq!(
+            format!("{run}"),
+            "0x0000000000001000..+4KiB -> 0x0000000000008000 RW- normal (4K page) refs:2"
+        );
+
+        let run = MappingRun::new(
+            0xffff_8000_0000_0000,
+            mapping(0x4000_0000, PageSize::Page2M, MapFlags::RX),
+            None,
+        );
+        assert_eq!(
+            format!("{run}"),
+            "0xffff800000000000..+2MiB -> 0x0000000040000000 R-X normal (2M block)"
+        );
+    }
 }
@@ -0,0 +1,134 @@
+/// watch catches stray writes to a page, e.g. one being corrupted by unknown
+/// code, by mapping it read-only and reporting writes to it from the data
+/// abort handler.  Only the mapping of the page at the address given is
+/// watched, not any aliases of it, such as its direct map address.  It's a
+/// debugging aid, so is only built in debug builds.
+use crate::vm::{self, AddressSpace};
+use port::mcslock::{Lock, LockNode};
+use port::mem::{MapFlags, MemError, PAGE_SIZE_4K, VirtAddr, VirtRange};
+use port::symbols::Symbolized;
+
+#[cfg(not(test))]
+use port::println;
+
+/// Maximum number of pages watched at once.
+const MAX_WATCHES: usize = 4;
+
+/// What to do when a watched page is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum WatchMode {
+    /// Report the first write, then make the page writable again and remove
+    /// the watch, so the write goes ahead.
+    LogOnce,
+    /// Report the write, then panic.
+    Trap,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum WatchError {
+    NotKernelAddress,
+    NotMapped,
+    NotWritable,
+    AlreadyWatched,
+    TooManyWatches,
+    NotWatched,
+    PageTable(MemError),
+}
+
+impl From<MemError> for WatchError {
+    fn from(err: MemError) -> WatchError {
+        WatchError::PageTable(err)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Watch {
+    page: VirtAddr,
+    flags: MapFlags, // Flags the page was mapped with before it was watched
+    mode: WatchMode,
+}
+
+static WATCHES: Lock<[Option<Watch>; MAX_WATCHES]> = Lock::new("watches", [None; MAX_WATCHES]);
+
+fn page_range(page: VirtAddr) -> VirtRange {
+    VirtRange::with_len(page, PAGE_SIZE_4K)
+}
+
+/// Map the kernel page holding `va` read-only, and watch it for writes,
+/// which are handled as `mode` says.  A block mapping the page is split.
+#[allow(dead_code)]
+pub fn protect_page(va: VirtAddr, mode: WatchMode) -> Result<(), WatchError> {
+    if va.addr() >> 48 != 0xffff {
+        return Err(WatchError::NotKernelAddress);
+    }
+    let page = va.round_down(PAGE_SIZE_4K);
+    let flags = vm::lookup(page).ok_or(WatchError::NotMapped)?.entry.flags();
+    if !flags.contains(MapFlags::WRITE) {
+        return Err(WatchError::NotWritable);
+    }
+
+    let node = LockNode::new();
+    let mut watches = WATCHES.lock(&node);
+    if watches.iter().flatten().any(|watch| watch.page == page) {
+        return Err(WatchError::AlreadyWatched);
+    }
+    let slot = watches.iter_mut().find(|slot| slot.is_none()).ok_or(WatchError::TooManyWatches)?;
+    AddressSpace::kernel().protect(&page_range(page), flags - MapFlags::WRITE)?;
+    *slot = Some(Watch { page, flags, mode });
+    Ok(())
+}
+
+/// Stop watching the page holding `va`, and map it with its original flags.
+#[allow(dead_code)]
+pub fn unprotect_page(va: VirtAddr) -> Result<(), WatchError> {
+    let page = va.round_down(PAGE_SIZE_4K);
+    let node = LockNode::new();
+    let mut watches = WATCHES.lock(&node);
+    let slot = watches
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|watch| watch.page == page))
+        .ok_or(WatchError::NotWatched)?;
+    let watch = slot.take().unwrap();
+    AddressSpace::kernel().protect(&page_range(page), watch.flags)?;
+    Ok(())
+}
+
+/// Handle a permission fault on a write to `va` by the instruction at `pc`,
+/// which stored `value` of the given size, if the syndrome says.  Returns
+/// false if the page isn't watched.  Otherwise the write is reported, then
+/// for a Trap watch this panics, and for a LogOnce watch the page is made
+/// writable again and this returns true, so the write can be retried.  The
+/// watches are only tried, since the fault may be taken with them locked.
+pub fn write_fault(va: VirtAddr, pc: usize, value: Option<(u64, usize)>) -> bool {
+    let node = LockNode::new();
+    let Some(mut watches) = WATCHES.try_lock(&node) else {
+        return false;
+    };
+    let page = va.round_down(PAGE_SIZE_4K);
+    let Some(slot) = watches.iter_mut().find(|slot| slot.is_some_and(|watch| watch.page == page))
+    else {
+        return false;
+    };
+    let watch = slot.unwrap();
+
+    println!("watch: write to watched page {page}");
+    println!("  pc: {}", Symbolized(pc));
+    println!("  address: {va:#}");
+    match value {
+        Some((value, size)) => println!("  value: {value:#x} ({size} bytes)"),
+        None => println!("  value: unknown"),
+    }
+    if watch.mode == WatchMode::Trap {
+        drop(watches);
+        panic!("watch:write to watched page {page}");
+    }
+
+    if let Err(err) = AddressSpace::kernel().protect(&page_range(page), watch.flags) {
+        println!("error:watch:write_fault:can't restore flags. page:{page:?} err:{err}");
+        return false;
+    }
+    *slot = None;
+    true
+}
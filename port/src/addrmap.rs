@@ -0,0 +1,131 @@
+/// addrmap is a registry of the ranges of kernel address space set up by the
+/// memory subsystems, e.g. the kernel image sections and the direct map, so
+/// that an address can be described in terms of what the kernel knows about
+/// it.  It's intended for fault handlers, so lookups never block or take
+/// locks.  Ranges can only be added, and are published with a per-slot flag,
+/// so a reader sees each range either completely or not at all.
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::mem::{PhysRange, VirtAddr, VirtRange};
+
+/// What a range of address space is used for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddrKind {
+    KernelSection, // Section of the kernel image, e.g. text
+    StackGuard,    // Unmapped guard page beneath a kernel stack
+    DirectMap,     // Window for the direct map, only mapped where there's RAM
+    DirectMapRam,  // RAM mapped by the direct map
+    Vmap,          // Window for mapping device registers, etc.
+}
+
+/// A range of virtual addresses, and the physical addresses it maps to, if
+/// they're known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddrRange {
+    pub name: &'static str,
+    pub kind: AddrKind,
+    pub virt: VirtRange,
+    pub phys: Option<PhysRange>,
+}
+
+/// Error returned when there are no free slots to record a range.
+#[derive(Debug, PartialEq)]
+pub struct AddrMapFullError;
+
+struct Slot {
+    ready: AtomicBool,
+    range: UnsafeCell<MaybeUninit<AddrRange>>,
+}
+
+/// Registry of up to N ranges.
+pub struct AddrMap<const N: usize> {
+    slots: [Slot; N],
+    next: AtomicUsize, // Index of the next slot to claim
+}
+
+// Safety: each slot is written once, by the only caller to claim it, before
+// it's marked ready, and only read once it's ready.
+unsafe impl<const N: usize> Sync for AddrMap<N> {}
+
+impl<const N: usize> AddrMap<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    ready: AtomicBool::new(false),
+                    range: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record a range.  Ranges may overlap, e.g. a section within a larger
+    /// window, in which case `find` prefers the smaller.
+    pub fn record(&self, range: AddrRange) -> Result<(), AddrMapFullError> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        let Some(slot) = self.slots.get(i) else {
+            return Err(AddrMapFullError);
+        };
+        // Safety: the slot was claimed above, so nothing else writes it, and
+        // it isn't read until it's marked ready.
+        unsafe { (*slot.range.get()).write(range) };
+        slot.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Iterate over the ranges recorded so far.
+    pub fn iter(&self) -> impl Iterator<Item = &AddrRange> {
+        self.slots.iter().filter(|slot| slot.ready.load(Ordering::Acquire)).map(|slot| {
+            // Safety: ready slots have been written, and are never written again.
+            unsafe { (*slot.range.get()).assume_init_ref() }
+        })
+    }
+
+    /// Return the smallest range containing `va`, or None if no range does.
+    pub fn find(&self, va: VirtAddr) -> Option<&AddrRange> {
+        self.iter().filter(|r| r.virt.0.contains(&va)).min_by_key(|r| r.virt.size())
+    }
+}
+
+impl<const N: usize> Default for AddrMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(name: &'static str, kind: AddrKind, start: usize, len: usize) -> AddrRange {
+        AddrRange {
+            name,
+            kind,
+            virt: VirtRange::with_len(VirtAddr::new(start), len),
+            phys: Some(PhysRange::with_len(start as u64 & 0xffff_ffff, len)),
+        }
+    }
+
+    #[test]
+    fn find_smallest_containing_range() {
+        let map = AddrMap::<3>::new();
+        assert_eq!(map.find(VirtAddr::new(0x1000)), None);
+
+        let window = range("dmap", AddrKind::DirectMap, 0xa000_0000_0000, 0x1000_0000);
+        let ram = range("ram", AddrKind::DirectMapRam, 0xa000_0000_0000, 0x10_0000);
+        let text = range("text", AddrKind::KernelSection, 0x8000_0010_0000, 0x2000);
+        map.record(window.clone()).unwrap();
+        map.record(ram.clone()).unwrap();
+        map.record(text.clone()).unwrap();
+        assert_eq!(map.record(text.clone()), Err(AddrMapFullError));
+        assert_eq!(map.iter().count(), 3);
+
+        assert_eq!(map.find(VirtAddr::new(0xa000_0000_1234)), Some(&ram));
+        assert_eq!(map.find(VirtAddr::new(0xa000_0010_0000)), Some(&window));
+        assert_eq!(map.find(VirtAddr::new(0x8000_0010_1fff)), Some(&text));
+        assert_eq!(map.find(VirtAddr::new(0x8000_0010_2000)), None);
+    }
+}
@@ -0,0 +1,331 @@
+/// alarm runs callbacks once, at a deadline, for timeouts such as draining a
+/// uart's transmit FIFO, or waiting for a secondary core to start.  Each core
+/// has a table of its pending alarms, sorted by deadline, and its timer is
+/// armed to interrupt at the earliest of them.  The arch's timer interrupt
+/// handler calls `Alarms::fire`, which runs the callbacks that are due, then
+/// re-arms the timer for the next alarm, if there is one.
+///
+/// Callbacks run in the timer interrupt, with interrupts disabled, so they
+/// must be short, e.g. setting a flag.  They may set further alarms.
+///
+/// The arch sets its timer with `Alarms::set_timer`, once it can take the
+/// timer's interrupt.  Until it does, setting an alarm fails, as it would
+/// never fire.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::mcslock::{Lock, LockNode};
+use crate::percpu::{PerCpu, core_id};
+use crate::time::{self, Deadline};
+
+/// A core's timer, which can interrupt it once at a given time.
+pub struct AlarmTimer {
+    /// Interrupt the current core at the deadline, or at once if it's
+    /// passed, replacing any time already set.
+    pub arm: fn(Deadline),
+    /// Stop the current core's timer interrupting.
+    pub disarm: fn(),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AlarmError {
+    /// The arch hasn't set a timer, so the alarm would never fire.
+    NoTimer,
+    /// The current core already has as many alarms pending as it can hold.
+    TableFull,
+}
+
+/// Identifies a pending alarm, to cancel it, and is passed to its callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlarmHandle {
+    core: usize,
+    id: u64,
+}
+
+impl AlarmHandle {
+    /// Return the id of the core the alarm fires on.
+    pub fn core(&self) -> usize {
+        self.core
+    }
+}
+
+/// Called when an alarm fires, with the alarm's handle.
+pub type AlarmFn = fn(AlarmHandle);
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    deadline: Deadline,
+    handle: AlarmHandle,
+    callback: AlarmFn,
+}
+
+/// A core's pending alarms.  The first `len` are set, in order of deadline,
+/// and alarms with the same deadline in the order they were set.
+struct Table<const N: usize> {
+    alarms: [Option<Alarm>; N],
+    len: usize,
+    next_id: u64,
+}
+
+impl<const N: usize> Table<N> {
+    const fn new() -> Self {
+        Self { alarms: [None; N], len: 0, next_id: 0 }
+    }
+
+    /// Return the earliest deadline.
+    fn first(&self) -> Option<Deadline> {
+        self.alarms[0].map(|alarm| alarm.deadline)
+    }
+
+    /// Add an alarm on `core`, returning its handle.
+    fn insert(
+        &mut self,
+        core: usize,
+        deadline: Deadline,
+        callback: AlarmFn,
+    ) -> Result<AlarmHandle, AlarmError> {
+        if self.len == N {
+            return Err(AlarmError::TableFull);
+        }
+        let handle = AlarmHandle { core, id: self.next_id };
+        self.next_id += 1;
+        let pos = self.alarms[..self.len]
+            .iter()
+            .position(|alarm| alarm.is_some_and(|alarm| alarm.deadline > deadline))
+            .unwrap_or(self.len);
+        self.alarms[pos..=self.len].rotate_right(1);
+        self.alarms[pos] = Some(Alarm { deadline, handle, callback });
+        self.len += 1;
+        Ok(handle)
+    }
+
+    /// Remove and return the alarm at `pos`.
+    fn remove_at(&mut self, pos: usize) -> Option<Alarm> {
+        let alarm = self.alarms[pos].take();
+        self.alarms[pos..self.len].rotate_left(1);
+        self.len -= 1;
+        alarm
+    }
+
+    /// Remove the alarm with `handle`, returning true if it was pending.
+    fn remove(&mut self, handle: AlarmHandle) -> bool {
+        let pos = self.alarms[..self.len]
+            .iter()
+            .position(|alarm| alarm.is_some_and(|alarm| alarm.handle == handle));
+        pos.and_then(|pos| self.remove_at(pos)).is_some()
+    }
+
+    /// Remove and return the earliest alarm, if it's due at `now`.
+    fn pop_due(&mut self, now: u64) -> Option<Alarm> {
+        let first = self.first()?;
+        if first.nanos() > now {
+            return None;
+        }
+        self.remove_at(0)
+    }
+}
+
+/// The pending alarms of each of `CORES` cores, up to `N` per core.
+pub struct Alarms<const CORES: usize, const N: usize> {
+    timer: AtomicPtr<AlarmTimer>,
+    tables: PerCpu<Lock<Table<N>>, CORES>,
+}
+
+impl<const CORES: usize, const N: usize> Alarms<CORES, N> {
+    pub const fn new() -> Self {
+        Self {
+            timer: AtomicPtr::new(null_mut()),
+            tables: PerCpu::new([const { Lock::new("alarms", Table::new()) }; CORES]),
+        }
+    }
+
+    /// Arm `timer` for alarms from now on.
+    pub fn set_timer(&self, timer: &'static AlarmTimer) {
+        self.timer.store(timer as *const AlarmTimer as *mut AlarmTimer, Ordering::Release);
+    }
+
+    fn timer(&self) -> Option<&'static AlarmTimer> {
+        // Safety: only set_timer stores to timer, from a 'static
+        unsafe { self.timer.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Call `callback` on the current core, from its timer interrupt, once
+    /// `deadline` has passed.
+    pub fn set_alarm(
+        &self,
+        deadline: Deadline,
+        callback: AlarmFn,
+    ) -> Result<AlarmHandle, AlarmError> {
+        let timer = self.timer().ok_or(AlarmError::NoTimer)?;
+        let node = LockNode::new();
+        let mut table = self.tables.get().lock_irqsave(&node);
+        let handle = table.insert(core_id(), deadline, callback)?;
+        if table.first() == Some(deadline) {
+            (timer.arm)(deadline);
+        }
+        Ok(handle)
+    }
+
+    /// Cancel the alarm with `handle`, returning true if it hadn't yet
+    /// fired.  An alarm on another core is removed from its table, but that
+    /// core's timer is left armed, so it may take an interrupt that finds
+    /// nothing due.
+    pub fn cancel(&self, handle: AlarmHandle) -> bool {
+        let node = LockNode::new();
+        let mut table = self.tables.get_for(handle.core).lock_irqsave(&node);
+        let first = table.first();
+        if !table.remove(handle) {
+            return false;
+        }
+        if handle.core == core_id() && table.first() != first {
+            self.rearm(table.first());
+        }
+        true
+    }
+
+    /// Return the number of alarms pending on the current core.
+    pub fn pending(&self) -> usize {
+        let node = LockNode::new();
+        self.tables.get().lock_irqsave(&node).len
+    }
+
+    /// Run the callbacks of the current core's alarms that are due, and arm
+    /// the timer for the next, returning the number run.  Called from the
+    /// timer interrupt.
+    pub fn fire(&self) -> usize {
+        self.fire_at(time::now())
+    }
+
+    /// Fire the alarms due at `now`.  The callbacks are run once the table
+    /// is unlocked, so they can set alarms.
+    fn fire_at(&self, now: u64) -> usize {
+        let mut due = [None; N];
+        let node = LockNode::new();
+        let mut table = self.tables.get().lock_irqsave(&node);
+        let mut count = 0;
+        while let Some(alarm) = table.pop_due(now) {
+            due[count] = Some(alarm);
+            count += 1;
+        }
+        self.rearm(table.first());
+        drop(table);
+
+        for alarm in due.iter().flatten() {
+            (alarm.callback)(alarm.handle);
+        }
+        count
+    }
+
+    /// Arm the current core's timer for `deadline`, or disarm it if there's
+    /// none.
+    fn rearm(&self, deadline: Option<Deadline>) {
+        if let Some(timer) = self.timer() {
+            match deadline {
+                Some(deadline) => (timer.arm)(deadline),
+                None => (timer.disarm)(),
+            }
+        }
+    }
+}
+
+impl<const CORES: usize, const N: usize> Default for Alarms<CORES, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    // Host tests give each thread its own core, see percpu::core_id
+    static ALARMS: Alarms<1024, 4> = Alarms::new();
+
+    thread_local! {
+        static ARMED: Cell<Option<Deadline>> = const { Cell::new(None) };
+        static FIRED: RefCell<Vec<AlarmHandle>> = const { RefCell::new(Vec::new()) };
+    }
+
+    static FAKE_TIMER: AlarmTimer =
+        AlarmTimer { arm: |deadline| ARMED.set(Some(deadline)), disarm: || ARMED.set(None) };
+
+    fn record(handle: AlarmHandle) {
+        FIRED.with_borrow_mut(|fired| fired.push(handle));
+    }
+
+    fn fired() -> Vec<AlarmHandle> {
+        FIRED.take()
+    }
+
+    fn at(nanos: u64) -> Deadline {
+        Deadline::at(nanos)
+    }
+
+    #[test]
+    fn fire_in_deadline_order() {
+        ALARMS.set_timer(&FAKE_TIMER);
+        let b = ALARMS.set_alarm(at(300), record).unwrap();
+        assert_eq!(ARMED.get(), Some(at(300)));
+        let a = ALARMS.set_alarm(at(100), record).unwrap();
+        assert_eq!(ARMED.get(), Some(at(100)));
+        let c = ALARMS.set_alarm(at(300), record).unwrap();
+        let d = ALARMS.set_alarm(at(200), record).unwrap();
+        assert_eq!(ARMED.get(), Some(at(100)));
+        assert_eq!(a.core(), core_id());
+
+        assert_eq!(ALARMS.fire_at(99), 0);
+        assert_eq!(ALARMS.fire_at(200), 2);
+        assert_eq!(fired(), [a, d]);
+        assert_eq!(ARMED.get(), Some(at(300)));
+        assert_eq!(ALARMS.fire_at(1000), 2);
+        assert_eq!(fired(), [b, c]);
+        assert_eq!(ARMED.get(), None);
+        assert_eq!(ALARMS.pending(), 0);
+    }
+
+    #[test]
+    fn cancel_rearms() {
+        ALARMS.set_timer(&FAKE_TIMER);
+        let a = ALARMS.set_alarm(at(100), record).unwrap();
+        let b = ALARMS.set_alarm(at(200), record).unwrap();
+        assert!(ALARMS.cancel(a));
+        assert!(!ALARMS.cancel(a));
+        assert_eq!(ARMED.get(), Some(at(200)));
+        assert!(ALARMS.cancel(b));
+        assert_eq!(ARMED.get(), None);
+        assert_eq!(ALARMS.fire_at(1000), 0);
+        assert_eq!(fired(), []);
+    }
+
+    #[test]
+    fn full_table_is_an_error() {
+        ALARMS.set_timer(&FAKE_TIMER);
+        for i in 0..4 {
+            ALARMS.set_alarm(at(i), record).unwrap();
+        }
+        assert_eq!(ALARMS.set_alarm(at(0), record), Err(AlarmError::TableFull));
+        assert_eq!(ALARMS.fire_at(3), 4);
+        assert!(ALARMS.set_alarm(at(0), record).is_ok());
+    }
+
+    #[test]
+    fn callbacks_can_set_alarms() {
+        ALARMS.set_timer(&FAKE_TIMER);
+        ALARMS
+            .set_alarm(at(100), |_| {
+                ALARMS.set_alarm(at(150), record).unwrap();
+            })
+            .unwrap();
+        assert_eq!(ALARMS.fire_at(100), 1);
+        assert_eq!(ARMED.get(), Some(at(150)));
+        assert_eq!(ALARMS.fire_at(150), 1);
+        assert_eq!(fired().len(), 1);
+    }
+
+    #[test]
+    fn no_timer() {
+        let alarms: Alarms<1024, 1> = Alarms::new();
+        assert_eq!(alarms.set_alarm(at(0), record), Err(AlarmError::NoTimer));
+    }
+}
@@ -8,6 +8,7 @@
 #![allow(clippy::too_long_first_doc_paragraph)]
 
 use alloc::alloc::{AllocError, Allocator, Layout};
+use core::fmt;
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{mem, ptr};
@@ -101,6 +102,13 @@ impl BumpAlloc {
         let block = unsafe { Block::new_from_raw_parts(ptr, size) };
         Some((prefix, block))
     }
+
+    /// Returns the unallocated part of the arena.
+    fn remaining(&self) -> Block {
+        let cursor = self.cursor.load(Ordering::Relaxed);
+        let (_, rest) = self.arena.split_at_mut(cursor).expect("cursor beyond arena");
+        rest
+    }
 }
 
 /// BumpAlloc<T> implements the allocator interface, and is
@@ -108,7 +116,7 @@ impl BumpAlloc {
 /// unimplemented and will panic.
 unsafe impl Allocator for BumpAlloc {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let (_, block) = self.try_alloc(layout.size(), layout.align()).ok_or(AllocError)?;
+        let (_, block) = self.try_alloc(layout.align(), layout.size()).ok_or(AllocError)?;
         Ok(NonNull::slice_from_raw_parts(block.ptr, block.len()))
     }
 
@@ -179,6 +187,32 @@ impl Header {
     }
 }
 
+/// Statistics about a QuickFit heap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapStats {
+    pub arenas: usize,          // Number of arenas, including the first
+    pub arena_bytes: usize,     // Total size of the arenas
+    pub allocated_bytes: usize, // Bytes allocated, after rounding up sizes
+    pub peak_bytes: usize,      // Most bytes allocated at once
+    pub allocs: usize,          // Number of allocations
+    pub frees: usize,           // Number of frees
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB in {} arenas, {} KiB allocated, {} KiB peak, {} allocs, {} frees",
+            self.arena_bytes / 1024,
+            self.arenas,
+            self.allocated_bytes / 1024,
+            self.peak_bytes / 1024,
+            self.allocs,
+            self.frees
+        )
+    }
+}
+
 /// The QuickFit allocator itself.  The allocator takes
 /// ownership of a bump allocator for the tail, and contains a
 /// set of lists for the quick blocks, as well as a misc list
@@ -192,6 +226,7 @@ pub struct QuickFit {
     qlists: [Option<NonNull<Header>>; NUM_QLISTS],
     misc: Option<NonNull<Header>>,
     allocated_misc: [Option<NonNull<Header>>; NUM_HASH_BUCKETS],
+    stats: HeapStats,
 }
 
 impl QuickFit {
@@ -200,16 +235,53 @@ impl QuickFit {
         let qlists = [None; NUM_QLISTS];
         let misc = None;
         let allocated_misc = [None; NUM_HASH_BUCKETS];
-        QuickFit { tail, qlists, misc, allocated_misc }
+        let stats = HeapStats {
+            arenas: 1,
+            arena_bytes: tail.arena.len,
+            allocated_bytes: 0,
+            peak_bytes: 0,
+            allocs: 0,
+            frees: 0,
+        };
+        QuickFit { tail, qlists, misc, allocated_misc, stats }
+    }
+
+    /// Adds `arena` to the heap, for allocations that don't fit
+    /// in what's left of the current tail.  What's left is freed
+    /// to the quick lists, so it isn't wasted.
+    pub fn add_arena(&mut self, arena: Block) {
+        let rest = mem::replace(&mut self.tail, BumpAlloc::new(arena)).remaining();
+        if rest.len() >= MIN_ALLOC_SIZE {
+            self.free_prefix(rest);
+        }
+        self.stats.arenas += 1;
+        self.stats.arena_bytes += arena.len();
+    }
+
+    /// Returns statistics about the heap.
+    pub fn stats(&self) -> HeapStats {
+        self.stats
     }
 
     /// Allocates a block of memory of the requested size and
     /// alignment.  Returns a pointer to such a block, or nil if
     /// the block cannot be allocated.
     pub fn malloc(&mut self, layout: Layout) -> *mut u8 {
+        let p = self.alloc_block(layout);
+        if p.is_some() {
+            self.stats.allocs += 1;
+            self.stats.allocated_bytes += Self::adjust(layout).0;
+            self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.allocated_bytes);
+        }
+        p.map(|p| p.as_ptr()).unwrap_or(ptr::null_mut())
+    }
+
+    /// Allocates a block without counting it in the statistics,
+    /// as for headers.
+    fn alloc_block(&mut self, layout: Layout) -> Option<NonNull<u8>> {
         let (size, align) = Self::adjust(layout);
         let p = self.alloc_quick(size, align);
-        p.or_else(|| self.alloc_tail(size, align)).map(|p| p.as_ptr()).unwrap_or(ptr::null_mut())
+        p.or_else(|| self.alloc_tail(size, align))
     }
 
     /// Adjusts the given layout so that blocks allocated from
@@ -263,7 +335,7 @@ impl QuickFit {
     /// to the minimum allocation unit into the quick lists
     /// until it is.
     fn alloc_tail(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
-        let (prefix, block) = { self.tail.try_alloc(size, align)? };
+        let (prefix, block) = { self.tail.try_alloc(align, size)? };
         self.free_prefix(prefix);
         Some(block.ptr)
     }
@@ -295,7 +367,7 @@ impl QuickFit {
             let size = 1 << (k + ALLOC_UNIT_SHIFT);
             if prefix.len() >= size && ptr.align_offset(size) == 0 {
                 let (_, rest) = prefix.split_at_mut(size)?;
-                self.free(ptr, Layout::from_size_align(size, size).unwrap());
+                self.free_block(ptr, Layout::from_size_align(size, size).unwrap());
                 return (rest.len() >= MIN_ALLOC_SIZE).then_some(rest);
             }
         }
@@ -354,6 +426,17 @@ impl QuickFit {
     /// quick lists, it is; otherwise, it is treated as a misc
     /// block and freed there.
     pub fn free(&mut self, block: *mut u8, layout: Layout) {
+        if block.is_null() {
+            return;
+        }
+        self.stats.frees += 1;
+        self.stats.allocated_bytes -= Self::adjust(layout).0;
+        self.free_block(block, layout);
+    }
+
+    /// Frees a block without counting it in the statistics, as
+    /// for memory that was never allocated, such as prefixes.
+    fn free_block(&mut self, block: *mut u8, layout: Layout) {
         let Some(block) = NonNull::new(block) else {
             return;
         };
@@ -390,10 +473,10 @@ impl QuickFit {
         let mut header = self
             .unlink_allocated_misc(block)
             .or_else(|| {
-                let hblock = self.malloc(Layout::new::<Header>()).cast::<Header>();
-                let hblock = hblock
-                    .is_null()
-                    .then(|| {
+                let hblock = self
+                    .alloc_block(Layout::new::<Header>())
+                    .map(|hblock| hblock.as_ptr().cast::<Header>())
+                    .unwrap_or_else(|| {
                         let offset = block.align_offset(MIN_ALLOC_SIZE);
                         let hblock = block.as_ptr().wrapping_add(offset);
                         let next = hblock.wrapping_add(MIN_ALLOC_SIZE);
@@ -401,8 +484,7 @@ impl QuickFit {
                         size -= offset + MIN_ALLOC_SIZE;
                         align = MIN_ALLOC_SIZE;
                         hblock.cast()
-                    })
-                    .expect("allocated header block");
+                    });
                 let header = Header::new(block, size, align, None);
                 unsafe {
                     ptr::write(hblock, header);
@@ -478,10 +560,16 @@ impl QuickFit {
 
 #[cfg(not(test))]
 pub mod global {
-    use super::QuickFit;
+    use super::{Block, HeapStats, QuickFit};
+    #[cfg(debug_assertions)]
+    use crate::heapcheck::HeapCheck;
+    use crate::mcslock::{Lock, LockNode};
+    use crate::mem::PAGE_SIZE_4K;
     use alloc::alloc::{GlobalAlloc, Layout};
-    use core::ptr;
+    #[cfg(debug_assertions)]
+    use core::panic::Location;
     use core::sync::atomic::{AtomicPtr, Ordering};
+    use core::{mem, ptr};
 
     const GLOBAL_HEAP_SIZE: usize = 4 * 1024 * 1024;
 
@@ -501,32 +589,266 @@ pub mod global {
         }
     }
 
+    /// Size the heap grows by, at least, when it runs out.
+    const GROW_SIZE: usize = 1024 * 1024;
+
+    /// The heap, and in debug builds, its live allocations.
+    struct Heap {
+        quick: QuickFit,
+        #[cfg(debug_assertions)]
+        check: HeapCheck,
+    }
+
     /// GlobalQuickAlloc is a wrapper around a QuickFit over a
-    /// GlobalHeap that uses interior mutability to implement
-    /// the GlobalAlloc trait.
-    pub struct GlobalQuickAlloc(pub AtomicPtr<QuickFit>);
+    /// GlobalHeap that uses a lock to implement the GlobalAlloc
+    /// trait.  Once a grow function is set, the heap grows with
+    /// memory from it when full.  In debug builds, allocations
+    /// are checked for overruns and leaks with a HeapCheck.
+    pub struct GlobalQuickAlloc {
+        heap: Lock<Heap>,
+        grow: AtomicPtr<()>, // fn(usize) -> Option<Block>, or null
+    }
+
     impl GlobalQuickAlloc {
-        fn with_allocator<F, R>(&self, thunk: F) -> R
+        pub const fn new(quick: QuickFit) -> GlobalQuickAlloc {
+            let heap = Heap {
+                quick,
+                #[cfg(debug_assertions)]
+                check: HeapCheck::new(),
+            };
+            GlobalQuickAlloc {
+                heap: Lock::new("heap", heap),
+                grow: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+
+        /// Set the function the heap grows with.  It's called
+        /// with the number of bytes wanted, and returns a page
+        /// aligned block of at least that size, e.g. pages from
+        /// the page allocator through the direct map, or None if
+        /// there's no more memory.  It's called with the heap
+        /// locked, so it mustn't allocate from the heap.
+        pub fn set_grow_fn(&self, grow: fn(usize) -> Option<Block>) {
+            self.grow.store(grow as *mut (), Ordering::Release);
+        }
+
+        /// Returns statistics about the heap, or None if it's
+        /// locked, e.g. when called from the allocator.  In debug
+        /// builds, sizes include the canaries around allocations.
+        pub fn try_stats(&self) -> Option<HeapStats> {
+            let node = LockNode::new();
+            self.heap.try_lock(&node).map(|heap| heap.quick.stats())
+        }
+
+        /// Prints the live allocations, e.g. to find leaks after a
+        /// test.  Allocations are only recorded in debug builds.
+        pub fn dump_leaks(&self) {
+            #[cfg(debug_assertions)]
+            self.with_heap(|heap| crate::print!("{}", heap.check.leaks()));
+            #[cfg(not(debug_assertions))]
+            crate::println!("heap: allocations are only recorded in debug builds");
+        }
+
+        fn with_heap<F, R>(&self, thunk: F) -> R
         where
-            F: FnOnce(&mut QuickFit) -> R,
+            F: FnOnce(&mut Heap) -> R,
         {
-            let a = self.0.swap(ptr::null_mut(), Ordering::Relaxed);
-            assert!(!a.is_null(), "global allocator is nil");
-            let r = thunk(unsafe { &mut *a });
-            self.0.swap(a, Ordering::Relaxed);
-            r
+            let node = LockNode::new();
+            let mut heap = self.heap.lock_irqsave(&node);
+            thunk(&mut heap)
+        }
+
+        /// Adds an arena big enough for `layout` from the grow
+        /// function, if there is one, returning false otherwise.
+        fn grow(&self, quick: &mut QuickFit, layout: Layout) -> bool {
+            let grow = self.grow.load(Ordering::Acquire);
+            if grow.is_null() {
+                return false;
+            }
+            // Safety: only set_grow_fn stores to grow, and it
+            // stores a fn(usize) -> Option<Block>
+            let grow = unsafe { mem::transmute::<*mut (), fn(usize) -> Option<Block>>(grow) };
+            // Arenas are page aligned, so only larger alignments need
+            // room to align the block
+            let align = if layout.align() > PAGE_SIZE_4K { layout.align() } else { 0 };
+            let size = (layout.size() + align).next_multiple_of(GROW_SIZE);
+            grow(size).map(|arena| quick.add_arena(arena)).is_some()
+        }
+
+        /// Allocates from the heap, growing it if it's full.
+        fn malloc(&self, quick: &mut QuickFit, layout: Layout) -> *mut u8 {
+            let p = quick.malloc(layout);
+            if p.is_null() && self.grow(quick, layout) {
+                return quick.malloc(layout);
+            }
+            p
+        }
+
+        /// Allocates with canaries around the object, recording
+        /// it as allocated at `location`.
+        #[cfg(debug_assertions)]
+        fn malloc_checked(&self, layout: Layout, location: &'static Location<'static>) -> *mut u8 {
+            let Some((padded, _)) = HeapCheck::padded(layout) else {
+                return ptr::null_mut();
+            };
+            self.with_heap(|heap| {
+                let block = self.malloc(&mut heap.quick, padded);
+                if block.is_null() {
+                    return block;
+                }
+                unsafe { heap.check.on_alloc(block, layout, location) }
+            })
+        }
+
+        /// Checks the canaries around `ptr`, and frees it.
+        #[cfg(debug_assertions)]
+        fn free_checked(&self, ptr: *mut u8, layout: Layout) {
+            let (padded, _) = HeapCheck::padded(layout).expect("padded layout");
+            self.with_heap(|heap| {
+                let block = unsafe { heap.check.on_free(ptr, layout) };
+                heap.quick.free(block, padded);
+            });
         }
     }
 
     unsafe impl GlobalAlloc for GlobalQuickAlloc {
+        #[cfg(debug_assertions)]
+        #[track_caller]
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.malloc_checked(layout, Location::caller())
+        }
+
+        #[cfg(not(debug_assertions))]
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            self.with_allocator(|quick| quick.malloc(layout))
+            self.with_heap(|heap| self.malloc(&mut heap.quick, layout))
         }
+
+        #[cfg(debug_assertions)]
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-            self.with_allocator(|quick| quick.free(ptr, layout));
+            self.free_checked(ptr, layout);
         }
+
+        #[cfg(not(debug_assertions))]
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.with_heap(|heap| heap.quick.free(ptr, layout));
+        }
+
+        /// Reallocation moves the object, so that its canaries
+        /// are rewritten.
+        #[cfg(debug_assertions)]
+        #[track_caller]
         unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-            self.with_allocator(|quick| unsafe { quick.realloc(ptr, layout, new_size) })
+            let new_layout = Layout::from_size_align(new_size, layout.align()).expect("layout");
+            let p = self.malloc_checked(new_layout, Location::caller());
+            if !p.is_null() {
+                unsafe { ptr::copy_nonoverlapping(ptr, p, layout.size().min(new_size)) };
+                self.free_checked(ptr, layout);
+            }
+            p
         }
+
+        #[cfg(not(debug_assertions))]
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.with_heap(|heap| {
+                let quick = &mut heap.quick;
+                let p = unsafe { quick.realloc(ptr, layout, new_size) };
+                if !p.is_null() {
+                    return p;
+                }
+                let new_layout = Layout::from_size_align(new_size, layout.align()).expect("layout");
+                if !self.grow(quick, new_layout) {
+                    return p;
+                }
+                unsafe { quick.realloc(ptr, layout, new_size) }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; 4096]);
+
+    /// Returns a new page aligned arena of `pages` pages.
+    fn arena(pages: usize) -> Block {
+        let pages = Box::leak((0..pages).map(|_| Page([0; 4096])).collect::<Box<[Page]>>());
+        unsafe { Block::new_from_raw_parts(pages.as_mut_ptr().cast(), pages.len() * 4096) }
+    }
+
+    fn contains(block: Block, p: *mut u8, size: usize) -> bool {
+        let start = block.as_ptr().addr();
+        start <= p.addr() && p.addr() + size <= start + block.len()
+    }
+
+    #[test]
+    fn tail_allocations_are_sized_and_aligned() {
+        let block = arena(16);
+        let mut quick = QuickFit::new(BumpAlloc::new(block));
+
+        // Too big for the quick lists
+        let big = Layout::from_size_align(20000, 8).unwrap();
+        let p = quick.malloc(big);
+        assert!(contains(block, p, 20000));
+
+        // Aligned beyond its size, up to a page
+        let aligned = Layout::from_size_align(100, 4096).unwrap();
+        let q = quick.malloc(aligned);
+        assert!(contains(block, q, 100));
+        assert_eq!(q.addr() % 4096, 0);
+        assert!(q.addr() >= p.addr() + 20000);
+
+        quick.free(p, big);
+        quick.free(q, aligned);
+        let r = quick.malloc(big);
+        assert_eq!(r, p);
+    }
+
+    #[test]
+    fn add_arena_when_full() {
+        let first = arena(4);
+        let mut quick = QuickFit::new(BumpAlloc::new(first));
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let p = quick.malloc(layout);
+            if p.is_null() {
+                break;
+            }
+            blocks.push(p);
+        }
+        assert_eq!(blocks.len(), 16);
+
+        let second = arena(4);
+        quick.add_arena(second);
+        let p = quick.malloc(layout);
+        assert!(contains(second, p, 1024));
+        blocks.push(p);
+
+        let stats = quick.stats();
+        assert_eq!((stats.arenas, stats.arena_bytes), (2, 8 * 4096));
+        assert_eq!((stats.allocs, stats.allocated_bytes), (17, 17 * 1024));
+        for p in blocks {
+            quick.free(p, layout);
+        }
+        let stats = quick.stats();
+        assert_eq!((stats.frees, stats.allocated_bytes), (17, 0));
+    }
+
+    #[test]
+    fn leftover_tail_is_reused() {
+        let first = arena(1);
+        let mut quick = QuickFit::new(BumpAlloc::new(first));
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let p = quick.malloc(small);
+        assert!(contains(first, p, 64));
+
+        // Doesn't fit in the rest of the first arena, which is
+        // freed to the quick lists when the second is added
+        quick.add_arena(arena(4));
+        let q = quick.malloc(small);
+        assert!(contains(first, q, 64));
     }
 }
@@ -0,0 +1,159 @@
+/// backtrace walks the chain of frame records that functions built with frame
+/// pointers push on the stack, to print the return addresses of the calls
+/// that led to a panic or exception, with the functions they're in when
+/// the kernel has a symbol table.  Each record holds the caller's frame
+/// pointer and the return address, and the walk stops when the chain ends,
+/// when a frame pointer leaves the stack, or after `MAX_DEPTH` frames, so that
+/// a corrupted chain can't send it anywhere or loop forever.
+use core::fmt;
+
+use crate::mem::VirtRange;
+use crate::symbols::Symbolized;
+
+/// Most frames printed.
+const MAX_DEPTH: usize = 32;
+
+/// Where the frame record is, relative to the frame pointer.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLayout {
+    saved_fp: isize, // Offset of the caller's frame pointer
+    saved_ra: isize, // Offset of the return address
+}
+
+impl FrameLayout {
+    /// x29 points at the frame record: the caller's x29, then x30.
+    pub const AARCH64: FrameLayout = FrameLayout { saved_fp: 0, saved_ra: 8 };
+    /// s0 points just above the frame, with ra and then the caller's s0
+    /// saved beneath it.
+    pub const RISCV64: FrameLayout = FrameLayout { saved_fp: -16, saved_ra: -8 };
+}
+
+/// A backtrace from a frame pointer, which can be printed.
+pub struct Backtrace {
+    fp: usize,
+    stack: VirtRange,
+    layout: FrameLayout,
+}
+
+impl Backtrace {
+    /// Returns a backtrace starting from the frame pointer `fp`, following
+    /// frame records only while they're within `stack`.
+    ///
+    /// # Safety
+    ///
+    /// All of `stack` must be mapped and readable.
+    pub unsafe fn new(fp: usize, stack: VirtRange, layout: FrameLayout) -> Self {
+        Self { fp, stack, layout }
+    }
+
+    /// Returns the caller's frame pointer and the return address from the
+    /// frame record for `fp`, or None if the record isn't within the stack.
+    fn frame_record(&self, fp: usize) -> Option<(usize, usize)> {
+        let saved_fp = fp.checked_add_signed(self.layout.saved_fp)?;
+        let saved_ra = fp.checked_add_signed(self.layout.saved_ra)?;
+        let (lo, hi) = (saved_fp.min(saved_ra), saved_fp.max(saved_ra).checked_add(8)?);
+        let in_stack = self.stack.start().addr() <= lo && hi <= self.stack.end().addr();
+        if !in_stack || fp % 8 != 0 {
+            return None;
+        }
+        // Safety: both are aligned and within the stack, which `new`
+        // requires to be readable.
+        unsafe { Some(((saved_fp as *const usize).read(), (saved_ra as *const usize).read())) }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        let mut fp = self.fp;
+        for depth in 0..MAX_DEPTH {
+            // A zero frame pointer or return address ends the chain
+            if fp == 0 {
+                return Ok(());
+            }
+            let Some((next_fp, ra)) = self.frame_record(fp) else {
+                return writeln!(f, "  stopped: frame pointer {fp:#x} not in stack {}", self.stack);
+            };
+            if ra == 0 {
+                return Ok(());
+            }
+            writeln!(f, "  #{depth:<2} {}", Symbolized(ra))?;
+            // Callers' frames are further up the stack
+            if next_fp != 0 && next_fp <= fp {
+                return writeln!(f, "  stopped: frame pointer {next_fp:#x} below {fp:#x}");
+            }
+            fp = next_fp;
+        }
+        writeln!(f, "  stopped: more than {MAX_DEPTH} frames")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::VirtAddr;
+
+    /// Returns a backtrace over `stack`, starting from the frame pointer at
+    /// word `start` of it.
+    fn backtrace(stack: &[usize], start: usize, layout: FrameLayout) -> String {
+        let range = VirtRange::with_len(VirtAddr::new(stack.as_ptr().addr()), size_of_val(stack));
+        let fp = stack[start..].as_ptr().addr();
+        unsafe { Backtrace::new(fp, range, layout) }.to_string()
+    }
+
+    fn addr_of(stack: &[usize], i: usize) -> usize {
+        stack[i..].as_ptr().addr()
+    }
+
+    #[test]
+    fn walk_aarch64_chain() {
+        let mut stack = [0usize; 8];
+        // Records at words 1, 4 and 6, the last ending the chain
+        stack[1] = addr_of(&stack, 4);
+        stack[2] = 0x1000;
+        stack[4] = addr_of(&stack, 6);
+        stack[5] = 0x2000;
+        stack[6] = 0;
+        stack[7] = 0x3000;
+        let bt = backtrace(&stack, 1, FrameLayout::AARCH64);
+        assert_eq!(
+            bt,
+            "backtrace:\n  #0  0x0000000000001000\n  #1  0x0000000000002000\n  #2  0x0000000000003000\n"
+        );
+    }
+
+    #[test]
+    fn walk_riscv64_chain() {
+        let mut stack = [0usize; 6];
+        // Frame pointers point just above {fp, ra}
+        stack[0] = addr_of(&stack, 4);
+        stack[1] = 0x1000;
+        stack[2] = 0;
+        stack[3] = 0x2000;
+        let bt = backtrace(&stack, 2, FrameLayout::RISCV64);
+        assert_eq!(bt, "backtrace:\n  #0  0x0000000000001000\n  #1  0x0000000000002000\n");
+    }
+
+    #[test]
+    fn stop_outside_stack() {
+        let mut stack = [0usize; 4];
+        let wild = addr_of(&stack, 0) + 0x1000;
+        stack[0] = wild;
+        stack[1] = 0x1000;
+        let bt = backtrace(&stack, 0, FrameLayout::AARCH64);
+        let lines: Vec<_> = bt.lines().collect();
+        assert_eq!(lines[1], "  #0  0x0000000000001000");
+        assert!(lines[2].starts_with(&format!("  stopped: frame pointer {wild:#x} not in stack ")));
+    }
+
+    #[test]
+    fn stop_on_loop() {
+        let mut stack = [0usize; 4];
+        stack[2] = addr_of(&stack, 2);
+        stack[3] = 0x1000;
+        let bt = backtrace(&stack, 2, FrameLayout::AARCH64);
+        let lines: Vec<_> = bt.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("  stopped: frame pointer "), "{bt}");
+    }
+}
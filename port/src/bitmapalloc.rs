@@ -1,25 +1,33 @@
 /// bitmapalloc implements a very simple bitmap page allocator.
 ///
+/// The bitmaps are carved out of memory by `init_from`, only as many as are
+/// needed to cover memory up to its end.
+///
 /// Benefits of the current implementation:
 ///  - Doesn't require any allocations, so can be used without fear while
 ///    manipulating the page tables.
 ///
 /// Downsides:
 ///  - Can't be dynamically resized.
-use core::fmt;
+use core::{fmt, slice};
 
 use crate::{
-    mem::{PhysAddr, PhysRange},
-    pagealloc::PageAllocError,
+    mem::{MemError, PhysAddr, PhysRange, PhysRangeSet},
+    pagealloc::{
+        CarvedPageAlloc, Metadata, PageAlloc, PageAllocStats, PageAllocSummary, PageMapper,
+        ReserveError, carve_metadata, check_free_range, check_reserve_range, out_of_memory,
+        page_runs, usable_ranges, write_page_runs,
+    },
 };
 
 /// Simple bitmap.  Bear in mind that logically, bit 0 is the rightmost bit,
 /// so writing out as bytes will have the bits logically reversed.
-struct Bitmap<const SIZE_BYTES: usize> {
+pub(crate) struct Bitmap<const SIZE_BYTES: usize> {
     bytes: [u8; SIZE_BYTES],
 }
 
 impl<const SIZE_BYTES: usize> Bitmap<SIZE_BYTES> {
+    #[cfg(test)]
     pub const fn new(init_value: u8) -> Self {
         Self { bytes: [init_value; SIZE_BYTES] }
     }
@@ -42,29 +50,81 @@ impl<const SIZE_BYTES: usize> Bitmap<SIZE_BYTES> {
             self.bytes[byte_idx] &= !(1 << bit_idx);
         }
     }
+
+    /// Return the 64 bits starting at bit `word_idx * 64`, with bit 0 of the
+    /// bitmap as the rightmost bit.  Bytes beyond the end of the bitmap are
+    /// filled with `pad`.
+    fn word(&self, word_idx: usize, pad: u8) -> u64 {
+        let mut word_bytes = [pad; 8];
+        let start = word_idx * 8;
+        let end = (start + 8).min(SIZE_BYTES);
+        word_bytes[..end - start].copy_from_slice(&self.bytes[start..end]);
+        u64::from_le_bytes(word_bytes)
+    }
+
+    /// Return the index of the first set bit in `from..end`, scanning a word
+    /// at a time.
+    pub fn first_set(&self, from: usize, end: usize) -> Option<usize> {
+        self.first_matching(from, end, false)
+    }
+
+    /// Return the index of the first clear bit in `from..end`, scanning a
+    /// word at a time.
+    pub fn first_clear(&self, from: usize, end: usize) -> Option<usize> {
+        self.first_matching(from, end, true)
+    }
+
+    /// Scan for the first set bit in `from..end`, or if `invert`, the first
+    /// clear bit.
+    fn first_matching(&self, from: usize, end: usize, invert: bool) -> Option<usize> {
+        let mut i = from;
+        while i < end {
+            let word = self.word(i / 64, if invert { 0xff } else { 0 });
+            let word = if invert { !word } else { word };
+            // Ignore the bits below i
+            let word = word & !((1u64 << (i % 64)) - 1);
+            if word != 0 {
+                let found = i - i % 64 + word.trailing_zeros() as usize;
+                return (found < end).then_some(found);
+            }
+            i += 64 - i % 64;
+        }
+        None
+    }
 }
 
 /// Allocator where each page is represented by a single bit.
 ///   0: free, 1: allocated
 /// `end` is used to indicate the extent of the memory.  Anything beyond this
-/// will be marked as allocated.
+/// will be marked as allocated.  There are up to `NUM_BITMAPS` bitmaps, each
+/// covering the same amount of memory.
 pub struct BitmapPageAlloc<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> {
-    bitmaps: [Bitmap<BITMAP_SIZE_BYTES>; NUM_BITMAPS],
-    alloc_page_size: usize,    // Size of pages represented by single bit
-    end: PhysAddr,             // Upper bound of physical memory
-    next_pa_to_scan: PhysAddr, // PhysAddr from which to start scanning for next allocation
+    bitmaps: &'static mut [Bitmap<BITMAP_SIZE_BYTES>], // Bitmaps, carved out by init_from
+    metadata: PhysRange,                               // Memory holding the bitmaps
+    alloc_page_size: usize,                            // Size of pages represented by single bit
+    end: PhysAddr,                                     // Upper bound of physical memory
+    next_free_hint: usize, // Page index from which to start scanning for next allocation
+    regions: PhysRangeSet, // Physical memory managed by the allocator
+    total_pages: usize,    // Pages available for allocation after init
+    free_pages: usize,     // Pages currently marked free
+    peak_allocated_pages: usize, // Most pages allocated at once since init
 }
 
 impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
     BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
 {
+    /// Create an allocator with no bitmaps, so no pages, until `init_from`.
     pub const fn new_all_allocated(alloc_page_size: usize) -> Self {
-        let end = PhysAddr::new((NUM_BITMAPS * BITMAP_SIZE_BYTES * 8 * alloc_page_size) as u64);
         Self {
-            bitmaps: [const { Bitmap::<BITMAP_SIZE_BYTES>::new(0xff) }; NUM_BITMAPS],
+            bitmaps: &mut [],
+            metadata: Metadata::empty().range,
             alloc_page_size,
-            end,
-            next_pa_to_scan: PhysAddr::new(0),
+            end: PhysAddr::new(0),
+            next_free_hint: 0,
+            regions: PhysRangeSet::new(),
+            total_pages: 0,
+            free_pages: 0,
+            peak_allocated_pages: 0,
         }
     }
 
@@ -78,21 +138,14 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         BITMAP_SIZE_BYTES * self.bytes_per_bitmap_byte()
     }
 
-    /// Returns number of physical bytes covered by all bitmaps.
+    /// Returns number of physical bytes that `NUM_BITMAPS` bitmaps can cover.
     const fn max_bytes(&self) -> usize {
         NUM_BITMAPS * self.bytes_per_bitmap()
     }
 
-    /// Mark the bits corresponding to the given physical range as allocated,
-    /// regardless of the existing state.
-    pub fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), PageAllocError> {
-        self.mark_range(range, true, true)
-    }
-
-    /// Mark the bits corresponding to the given physical range as free,
-    /// regardless of the existing state.
-    pub fn mark_free(&mut self, range: &PhysRange) -> Result<(), PageAllocError> {
-        self.mark_range(range, false, true)
+    /// Returns number of physical bytes covered by the bitmaps carved out.
+    fn covered_bytes(&self) -> usize {
+        self.bitmaps.len() * self.bytes_per_bitmap()
     }
 
     /// Free unused pages in mem that aren't covered by the memory map.  Assumes
@@ -102,7 +155,7 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         &mut self,
         available_mem: &PhysRange,
         used_ranges: impl Iterator<Item = &'a PhysRange>,
-    ) -> Result<(), PageAllocError> {
+    ) -> Result<(), MemError> {
         let mut next_start = available_mem.start();
         for range in used_ranges {
             if next_start < range.0.start {
@@ -119,65 +172,47 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         self.end = available_mem.0.end;
 
         // Mark everything past the end point as allocated
-        let end_range = PhysRange::new(self.end, PhysAddr::new(self.max_bytes() as u64));
+        let end_range = PhysRange::new(self.end, PhysAddr::new(self.covered_bytes() as u64));
         self.mark_range(&end_range, true, false)?;
 
-        self.next_pa_to_scan = PhysAddr::new(0); // Just set to 0 for simplicity - could be smarter
+        self.next_free_hint = 0;
+        self.regions = PhysRangeSet::new();
+        self.regions.add(available_mem)?;
+        self.total_pages = self.free_pages;
+        self.peak_allocated_pages = 0;
 
         Ok(())
     }
 
-    /// Try to allocate the next available page.
-    pub fn allocate(&mut self) -> Result<PhysAddr, PageAllocError> {
-        let (first_bitmap_idx, first_byte_idx, _) = self.physaddr_as_indices(self.next_pa_to_scan);
-
-        let found_indices = self
-            .indices_from(first_bitmap_idx, first_byte_idx)
-            .find(|indices| self.byte(indices) != 0xff);
-
-        if let Some(indices) = found_indices {
-            // Mark the page as allocated and return the address
-            let byte = &mut self.bitmaps[indices.bitmap].bytes[indices.byte];
-            let num_leading_ones = byte.trailing_ones() as usize;
-            *byte |= 1 << num_leading_ones;
-
-            let pa = self.indices_as_physaddr(indices.bitmap, indices.byte, num_leading_ones);
-            self.next_pa_to_scan = pa;
-            Ok(pa)
-        } else {
-            Err(PageAllocError::OutOfSpace)
+    /// Allocate a run of `page_count` free pages, starting on a page index that
+    /// is a multiple of `align_pages`, and lying within `within`.
+    fn allocate_run(
+        &mut self,
+        page_count: usize,
+        align_pages: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        if page_count == 0 {
+            return Err(MemError::InvalidPageCount { count: page_count });
         }
-    }
-
-    /// Deallocate the page corresponding to the given PhysAddr.
-    pub fn deallocate(&mut self, pa: PhysAddr) -> Result<(), PageAllocError> {
-        if pa > self.end {
-            return Err(PageAllocError::OutOfBounds);
+        let page_size = self.alloc_page_size as u64;
+        let start_page = within.start().addr().div_ceil(page_size) as usize;
+        let end_page = self.num_pages().min((within.end().addr() / page_size) as usize);
+        let out_of_memory = || out_of_memory(page_count, self.alloc_page_size, self.stats());
+        if start_page.saturating_add(page_count) > end_page {
+            return Err(out_of_memory());
         }
 
-        let (bitmap_idx, byte_idx, bit_idx) = self.physaddr_as_indices(pa);
-
-        let bitmap = &mut self.bitmaps[bitmap_idx];
-        if !bitmap.is_set(8 * byte_idx + bit_idx) {
-            return Err(PageAllocError::NotAllocated);
+        let first_page = self
+            .find_free_run(page_count, align_pages, start_page, end_page)
+            .ok_or_else(out_of_memory)?;
+        for page_idx in first_page..first_page + page_count {
+            self.set_page(page_idx, true);
         }
-        bitmap.set(bit_idx, false);
+        self.update_peak();
 
-        self.next_pa_to_scan = pa; // Next allocation will reuse this
-
-        Ok(())
-    }
-
-    /// Return a tuple of (bytes used, total bytes available) based on the page allocator.
-    pub fn usage_bytes(&self) -> (usize, usize) {
-        // We count free because the last bits might be marked partially 'allocated'
-        // if the end comes in the middle of a byte in the bitmap.
-        let mut free_bytes: usize = 0;
-        for indices in self.indices() {
-            free_bytes += self.byte(&indices).count_zeros() as usize * self.alloc_page_size;
-        }
-        let total = self.end.0 as usize;
-        (total - free_bytes, total)
+        let start = PhysAddr::new((first_page * self.alloc_page_size) as u64);
+        Ok(PhysRange::with_pa_len(start, page_count * self.alloc_page_size))
     }
 
     /// For the given physaddr, returns a tuple of (the bitmap containing pa,
@@ -211,24 +246,141 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         )
     }
 
+    /// Return the number of pages managed by the allocator, up to `end`.
+    fn num_pages(&self) -> usize {
+        self.end.addr() as usize / self.alloc_page_size
+    }
+
+    /// Is the page with the given index (counting from physical address 0)
+    /// allocated?
+    fn is_page_allocated(&self, page_idx: usize) -> bool {
+        let bits_per_bitmap = BITMAP_SIZE_BYTES * 8;
+        self.bitmaps[page_idx / bits_per_bitmap].is_set(page_idx % bits_per_bitmap)
+    }
+
+    /// Mark the page with the given index (counting from physical address 0)
+    /// as allocated or free, keeping the count of free pages up to date.
+    fn set_page(&mut self, page_idx: usize, allocated: bool) {
+        let bits_per_bitmap = BITMAP_SIZE_BYTES * 8;
+        let bitmap = &mut self.bitmaps[page_idx / bits_per_bitmap];
+        let bit_idx = page_idx % bits_per_bitmap;
+        if bitmap.is_set(bit_idx) != allocated {
+            bitmap.set(bit_idx, allocated);
+            if allocated {
+                self.free_pages -= 1;
+            } else {
+                self.free_pages += 1;
+            }
+        }
+    }
+
+    /// Return the number of pages currently allocated.  Pages freed that were
+    /// never part of the available memory (e.g. reserved pages) may push the
+    /// free count beyond the total, so saturate rather than underflow.
+    fn allocated_pages(&self) -> usize {
+        self.total_pages.saturating_sub(self.free_pages)
+    }
+
+    /// Record the high-water mark of allocated pages.  Called after each
+    /// allocation.
+    fn update_peak(&mut self) {
+        self.peak_allocated_pages = self.peak_allocated_pages.max(self.allocated_pages());
+    }
+
+    /// Return the index of the page at `pa`, ensuring that it's page aligned,
+    /// within the managed region, and currently allocated.
+    fn allocated_page_index(&self, pa: PhysAddr) -> Result<usize, MemError> {
+        if !pa.is_multiple_of(self.alloc_page_size as u64) {
+            return Err(MemError::Unaligned { addr: pa.addr(), required: self.alloc_page_size });
+        }
+        if pa >= self.end {
+            return Err(MemError::OutOfRange { addr: pa.addr(), range: 0..self.end.addr() });
+        }
+        let page_idx = pa.addr() as usize / self.alloc_page_size;
+        if !self.is_page_allocated(page_idx) {
+            return Err(MemError::NotAllocated { pa });
+        }
+        Ok(page_idx)
+    }
+
+    /// Return the index of the first free page in `from..end_page`, scanning
+    /// a word of the bitmaps at a time.
+    fn first_free_page(&self, from: usize, end_page: usize) -> Option<usize> {
+        let bits_per_bitmap = BITMAP_SIZE_BYTES * 8;
+        let mut bitmap_start = from - from % bits_per_bitmap;
+        let mut page_idx = from;
+        while page_idx < end_page {
+            let bitmap_end = (end_page - bitmap_start).min(bits_per_bitmap);
+            if let Some(bit) = self.bitmaps[bitmap_start / bits_per_bitmap]
+                .first_clear(page_idx - bitmap_start, bitmap_end)
+            {
+                return Some(bitmap_start + bit);
+            }
+            bitmap_start += bits_per_bitmap;
+            page_idx = bitmap_start;
+        }
+        None
+    }
+
+    /// Move the hint back to `page_idx` if it's lower, so that freed pages
+    /// are found by the next allocation.
+    fn lower_hint(&mut self, page_idx: usize) {
+        self.next_free_hint = self.next_free_hint.min(page_idx);
+    }
+
+    /// Find the index of the first page of a run of `page_count` free pages,
+    /// where the index of the first page is a multiple of `align_pages`, and
+    /// the run starts at or after `start_page` and ends at or before
+    /// `end_page`.
+    fn find_free_run(
+        &self,
+        page_count: usize,
+        align_pages: usize,
+        start_page: usize,
+        end_page: usize,
+    ) -> Option<usize> {
+        let mut run_start = start_page.next_multiple_of(align_pages);
+        let mut page_idx = run_start;
+        while run_start + page_count <= end_page {
+            if page_idx == run_start + page_count {
+                return Some(run_start);
+            }
+            if self.is_page_allocated(page_idx) {
+                // Restart the run at the next suitably aligned page that could
+                // be free
+                let next_free = self.first_free_page(page_idx + 1, end_page)?;
+                run_start = next_free.next_multiple_of(align_pages);
+                page_idx = run_start;
+            } else {
+                page_idx += 1;
+            }
+        }
+        None
+    }
+
     fn mark_range(
         &mut self,
         range: &PhysRange,
         mark_allocated: bool,
         check_end: bool,
-    ) -> Result<(), PageAllocError> {
+    ) -> Result<(), MemError> {
         if check_end && range.0.end > self.end {
-            return Err(PageAllocError::OutOfBounds);
+            return Err(MemError::OutOfRange {
+                addr: range.end().addr(),
+                range: 0..self.end.addr(),
+            });
         }
 
         for pa in range.step_by_rounded(self.alloc_page_size) {
-            let (bitmap_idx, byte_idx, bit_idx) = self.physaddr_as_indices(pa);
+            let (bitmap_idx, _, _) = self.physaddr_as_indices(pa);
             if bitmap_idx >= self.bitmaps.len() {
-                return Err(PageAllocError::OutOfBounds);
+                return Err(MemError::OutOfRange {
+                    addr: pa.addr(),
+                    range: 0..self.covered_bytes() as u64,
+                });
             }
 
-            let bitmap = &mut self.bitmaps[bitmap_idx];
-            bitmap.set(8 * byte_idx + bit_idx, mark_allocated);
+            self.set_page(pa.addr() as usize / self.alloc_page_size, mark_allocated);
         }
         Ok(())
     }
@@ -285,6 +437,48 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         self.bitmaps[indices.bitmap].bytes[indices.byte]
     }
 
+    /// Return the end of `memory`, as far as the allocator can describe it.
+    fn memory_end<const N: usize>(&self, memory: &PhysRangeSet<N>) -> PhysAddr {
+        memory.iter().map(|r| r.end()).max().unwrap_or_default().min(self.max_end())
+    }
+
+    /// Set up the bitmaps in `metadata` for memory up to the end of `memory`,
+    /// with every page allocated other than the pages in `usable`.
+    ///
+    /// # Safety
+    ///
+    /// As for `init_with_metadata`.
+    unsafe fn init_bitmaps<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        regions: PhysRangeSet,
+        usable: PhysRangeSet<N>,
+        metadata: Metadata,
+    ) -> Result<PageAllocSummary, MemError> {
+        let end = self.memory_end(memory);
+        let size = self.metadata_size(end);
+        // Safety: the caller guarantees the pointer covers `size` bytes, and
+        // every bitmap byte is valid once set
+        self.bitmaps = unsafe {
+            metadata.ptr.write_bytes(0xff, size);
+            slice::from_raw_parts_mut(metadata.ptr.cast(), size / BITMAP_SIZE_BYTES)
+        };
+        self.metadata = metadata.range;
+        self.end = end;
+        self.free_pages = 0;
+        for range in usable.iter() {
+            self.mark_free(range)?;
+        }
+        let total_pages = regions.size() / self.alloc_page_size;
+        let free_pages = self.free_pages;
+        self.next_free_hint = 0;
+        self.regions = regions;
+        self.total_pages = free_pages;
+        self.peak_allocated_pages = 0;
+
+        Ok(PageAllocSummary { total_pages, reserved_pages: total_pages - free_pages, free_pages })
+    }
+
     #[cfg(test)]
     fn bytes(&self) -> Vec<u8> {
         self.indices().map(|idx| self.byte(&idx)).collect::<Vec<u8>>()
@@ -298,11 +492,200 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
     }
 }
 
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> PageAlloc
+    for BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    fn page_size(&self) -> usize {
+        self.alloc_page_size
+    }
+
+    fn max_end(&self) -> PhysAddr {
+        PhysAddr::new(self.max_bytes() as u64)
+    }
+
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, true, true)
+    }
+
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, false, true)
+    }
+
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError> {
+        let (regions, mut usable) =
+            usable_ranges(memory, reserved, self.alloc_page_size, self.max_end())?;
+        let size = self.metadata_size(self.memory_end(memory));
+        let metadata = carve_metadata(&mut usable, size, self.alloc_page_size, mapper)?;
+        // Safety: carve_metadata took the memory out of `usable`, and the
+        // PageMapper guarantees the pointer covers it
+        unsafe { self.init_bitmaps(memory, regions, usable, metadata) }
+    }
+
+    fn for_each_metadata_range(&self, mut f: impl FnMut(&PhysRange)) {
+        if !self.metadata.is_empty() {
+            f(&self.metadata);
+        }
+    }
+
+    fn remap_metadata(&mut self, mapper: &impl PageMapper) -> Result<(), MemError> {
+        if self.metadata.is_empty() {
+            return Ok(());
+        }
+        let pa = self.metadata.start();
+        let ptr =
+            mapper.page_ptr(pa, self.metadata.size()).ok_or(MemError::PhysNotMapped { pa })?;
+        // Safety: the PageMapper guarantees the pointer covers the bitmaps
+        self.bitmaps = unsafe { slice::from_raw_parts_mut(ptr.cast(), self.bitmaps.len()) };
+        Ok(())
+    }
+
+    /// Try to allocate the next available page, scanning from just after the
+    /// last page allocated, or the lowest page freed since, and wrapping
+    /// around to the start before giving up.
+    fn allocate(&mut self) -> Result<PhysAddr, MemError> {
+        let num_pages = self.num_pages();
+        let hint = self.next_free_hint.min(num_pages);
+        let page_idx = self
+            .first_free_page(hint, num_pages)
+            .or_else(|| self.first_free_page(0, hint))
+            .ok_or_else(|| out_of_memory(1, self.alloc_page_size, self.stats()))?;
+
+        self.set_page(page_idx, true);
+        self.update_peak();
+        self.next_free_hint = page_idx + 1;
+        Ok(PhysAddr::new((page_idx * self.alloc_page_size) as u64))
+    }
+
+    /// This is a simple first-fit search from the start of memory.  Runs may
+    /// cross from one bitmap into the next, since consecutive bitmaps cover
+    /// consecutive physical memory.
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError> {
+        if !align.is_power_of_two() || align > self.end.addr() {
+            return Err(MemError::InvalidAlignment { align });
+        }
+        let align_pages = (align as usize / self.alloc_page_size).max(1);
+        self.allocate_run(page_count, align_pages, &PhysRange::new(PhysAddr::new(0), self.end))
+    }
+
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        self.allocate_run(page_count, 1, within)
+    }
+
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        let page_idx = self.allocated_page_index(pa)?;
+        self.set_page(page_idx, false);
+        self.lower_hint(page_idx);
+
+        Ok(())
+    }
+
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        check_free_range(range, self.alloc_page_size, |pa| self.allocated_page_index(pa))?;
+
+        for pa in range.step_by_rounded(self.alloc_page_size) {
+            self.set_page(pa.addr() as usize / self.alloc_page_size, false);
+        }
+        self.lower_hint(range.start().addr() as usize / self.alloc_page_size);
+
+        Ok(())
+    }
+
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError> {
+        let range = check_reserve_range(range, self.alloc_page_size, &self.regions, |pa| {
+            self.is_page_allocated(pa.addr() as usize / self.alloc_page_size)
+        })?;
+        for pa in range.step_by_rounded(self.alloc_page_size) {
+            self.set_page(pa.addr() as usize / self.alloc_page_size, true);
+        }
+        self.update_peak();
+        Ok(range)
+    }
+
+    fn stats(&self) -> PageAllocStats {
+        PageAllocStats {
+            total_pages: self.total_pages,
+            free_pages: self.free_pages,
+            allocated_pages: self.allocated_pages(),
+            peak_allocated_pages: self.peak_allocated_pages,
+        }
+    }
+
+    fn for_each_free_range(&self, mut f: impl FnMut(&PhysRange)) {
+        page_runs(self.num_pages(), self.alloc_page_size, |page_idx| {
+            self.is_page_allocated(page_idx)
+        })
+        .filter(|(_, allocated)| !allocated)
+        .for_each(|(range, _)| f(&range));
+    }
+
+    fn usage_bytes(&self) -> (usize, usize) {
+        // We count free because the last bits might be marked partially 'allocated'
+        // if the end comes in the middle of a byte in the bitmap.
+        let mut free_bytes: usize = 0;
+        for indices in self.indices() {
+            free_bytes += self.byte(&indices).count_zeros() as usize * self.alloc_page_size;
+        }
+        let total = self.end.0 as usize;
+        (total - free_bytes, total)
+    }
+
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result {
+        writeln!(
+            w,
+            "Bitmap page allocator: page size: {:#x} end: {:#x}",
+            self.alloc_page_size,
+            self.end.addr()
+        )?;
+        for region in self.regions.iter() {
+            writeln!(w, "  region: {}", region)?;
+        }
+        writeln!(w, "  {}", self.stats())?;
+
+        if show_map {
+            write_page_runs(w, self.num_pages(), self.alloc_page_size, |page_idx| {
+                self.is_page_allocated(page_idx)
+            })?;
+        }
+        Ok(())
+    }
+}
+
 struct ByteIndices {
     bitmap: usize,
     byte: usize,
 }
 
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> CarvedPageAlloc
+    for BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    /// A whole bitmap is needed for any memory it covers.
+    fn metadata_size(&self, end: PhysAddr) -> usize {
+        let end = end.min(self.max_end()).addr() as usize;
+        end.div_ceil(self.bytes_per_bitmap()) * BITMAP_SIZE_BYTES
+    }
+
+    unsafe fn init_with_metadata<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        metadata: Metadata,
+    ) -> Result<PageAllocSummary, MemError> {
+        let (regions, usable) =
+            usable_ranges(memory, reserved, self.alloc_page_size, self.max_end())?;
+        // Safety: the caller's guarantees are passed on
+        unsafe { self.init_bitmaps(memory, regions, usable, metadata) }
+    }
+}
+
 /// fmt::Debug is useful in small test cases, but would be too verbose for a
 /// realistic bitmap.
 impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> fmt::Debug
@@ -320,6 +703,18 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> fmt::Debug
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fakemem::{ScratchMapper, init_outside};
+
+    /// An allocator with all of its bitmaps, covering all the memory it can
+    /// describe, with every page allocated.
+    fn all_allocated<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>(
+        alloc_page_size: usize,
+    ) -> BitmapPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES> {
+        let mut alloc = BitmapPageAlloc::new_all_allocated(alloc_page_size);
+        alloc.bitmaps = Vec::leak((0..NUM_BITMAPS).map(|_| Bitmap::new(0xff)).collect());
+        alloc.end = alloc.max_end();
+        alloc
+    }
 
     #[test]
     fn bitmap_new() {
@@ -343,19 +738,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn bitmap_first_set_and_clear() {
+        // 12 bytes, so the second word is partial
+        let mut bitmap = Bitmap::<12>::new(0xff);
+        bitmap.set(3, false);
+        bitmap.set(70, false);
+        assert_eq!(bitmap.first_clear(0, 96), Some(3));
+        assert_eq!(bitmap.first_clear(4, 96), Some(70));
+        assert_eq!(bitmap.first_clear(4, 70), None);
+        assert_eq!(bitmap.first_clear(71, 96), None);
+
+        let mut bitmap = Bitmap::<12>::new(0);
+        bitmap.set(63, true);
+        bitmap.set(95, true);
+        assert_eq!(bitmap.first_set(0, 96), Some(63));
+        assert_eq!(bitmap.first_set(64, 96), Some(95));
+        assert_eq!(bitmap.first_set(64, 95), None);
+    }
+
     #[test]
     fn iterate() {
-        let alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let alloc = all_allocated::<2, 2>(4);
         assert_eq!(alloc.bytes(), vec![255; 4]);
         assert_eq!(alloc.bytes_from(1, 0), vec![255; 4]);
     }
 
     #[test]
-    fn bitmappagealloc_mark_allocated_and_free() -> Result<(), PageAllocError> {
+    fn bitmappagealloc_mark_allocated_and_free() -> Result<(), MemError> {
         // Create a new allocator and mark it all freed
         // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
         // 32 bits, 128 bytes physical memory
-        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut alloc = all_allocated::<2, 2>(4);
         alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
 
         // Mark a range as allocated - 10 bits
@@ -369,11 +783,11 @@ mod tests {
     }
 
     #[test]
-    fn bitmappagealloc_allocate_and_deallocate() -> Result<(), PageAllocError> {
+    fn bitmappagealloc_allocate_and_deallocate() -> Result<(), MemError> {
         // Create a new allocator and mark it all freed
         // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
         // 32 bits, 128 bytes physical memory
-        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut alloc = all_allocated::<2, 2>(4);
         alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
         assert_eq!(alloc.usage_bytes(), (0, 128));
 
@@ -393,14 +807,20 @@ mod tests {
             alloc.allocate()?;
         }
         assert_eq!(alloc.bytes(), [0xff, 0xff, 0xff, 0xff]);
-        assert_eq!(alloc.allocate().unwrap_err(), PageAllocError::OutOfSpace);
+        assert_eq!(
+            alloc.allocate().unwrap_err(),
+            MemError::OutOfMemory { requested: 4, available: 0 }
+        );
 
         // Now try to deallocate the second page
         assert!(alloc.deallocate(PhysAddr::new(4)).is_ok());
         assert_eq!(alloc.bytes(), [0xfd, 0xff, 0xff, 0xff]);
 
         // Ensure double deallocation fails
-        assert_eq!(alloc.deallocate(PhysAddr::new(4)).unwrap_err(), PageAllocError::NotAllocated);
+        assert_eq!(
+            alloc.deallocate(PhysAddr::new(4)).unwrap_err(),
+            MemError::NotAllocated { pa: PhysAddr::new(4) }
+        );
         assert_eq!(alloc.bytes(), [0xfd, 0xff, 0xff, 0xff]);
 
         // Allocate once more, expecting the physical address we just deallocated
@@ -409,6 +829,400 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn bitmappagealloc_allocate_contiguous() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+
+        // Leave gaps of 2 and 4 pages at the start
+        alloc.mark_allocated(&PhysRange::with_end(8, 12))?;
+        alloc.mark_allocated(&PhysRange::with_end(28, 32))?;
+        assert_eq!(alloc.bytes(), [0x84, 0x00, 0x00, 0x00]);
+
+        // First fit for 2 pages is at the start, but 3 pages must skip the first gap
+        assert_eq!(alloc.allocate_contiguous(2)?, PhysRange::with_end(0, 8));
+        assert_eq!(alloc.allocate_contiguous(3)?, PhysRange::with_end(12, 24));
+        assert_eq!(alloc.bytes(), [0xbf, 0x00, 0x00, 0x00]);
+        assert_eq!(alloc.usage_bytes(), (28, 128));
+
+        // Zero pages, and more pages than exist, are both errors
+        assert_eq!(
+            alloc.allocate_contiguous(0).unwrap_err(),
+            MemError::InvalidPageCount { count: 0 }
+        );
+        assert_eq!(
+            alloc.allocate_contiguous(33).unwrap_err(),
+            MemError::OutOfMemory { requested: 132, available: 100 }
+        );
+
+        // Larger than any free run
+        alloc.mark_allocated(&PhysRange::with_end(64, 68))?;
+        assert_eq!(
+            alloc.allocate_contiguous(17).unwrap_err(),
+            MemError::OutOfMemory { requested: 68, available: 96 }
+        );
+        assert_eq!(alloc.bytes(), [0xbf, 0x00, 0x01, 0x00]);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_contiguous_across_bitmaps() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes.  The first
+        // bitmap covers 0..64, the second 64..128.
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 56))?;
+
+        assert_eq!(alloc.allocate_contiguous(4)?, PhysRange::with_end(56, 72));
+        assert_eq!(alloc.bytes(), [0xff, 0xff, 0x03, 0x00]);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_aligned() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 4))?;
+
+        // Unaligned, the first free run starts at 4, but aligned to 16 it's at 16
+        assert_eq!(alloc.allocate_aligned(2, 16)?, PhysRange::with_end(16, 24));
+        assert_eq!(alloc.allocate_aligned(1, 32)?, PhysRange::with_end(32, 36));
+        assert_eq!(alloc.bytes(), [0x31, 0x01, 0x00, 0x00]);
+
+        // A run blocked by an allocated page restarts at the next aligned page
+        assert_eq!(alloc.allocate_aligned(4, 32)?, PhysRange::with_end(64, 80));
+
+        // Sub-page alignment is just page alignment
+        assert_eq!(alloc.allocate_aligned(1, 2)?, PhysRange::with_end(4, 8));
+
+        // Bad alignments
+        for align in [24, 0, 256] {
+            assert_eq!(
+                alloc.allocate_aligned(1, align).unwrap_err(),
+                MemError::InvalidAlignment { align }
+            );
+        }
+
+        // No aligned run large enough, even though there's enough free space
+        assert_eq!(
+            alloc.allocate_aligned(8, 64).unwrap_err(),
+            MemError::OutOfMemory { requested: 32, available: 92 }
+        );
+        assert_eq!(alloc.allocate_aligned(8, 32)?, PhysRange::with_end(96, 128));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_below() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 20))?;
+
+        // Only pages entirely below the limit are candidates
+        let limit = PhysAddr::new(34);
+        assert_eq!(alloc.allocate_below(limit)?, PhysAddr::new(20));
+        assert_eq!(alloc.allocate_contiguous_below(2, limit)?, PhysRange::with_end(24, 32));
+        assert_eq!(
+            alloc.allocate_below(limit).unwrap_err(),
+            MemError::OutOfMemory { requested: 4, available: 96 }
+        );
+        assert_eq!(
+            alloc.allocate_contiguous_below(2, limit).unwrap_err(),
+            MemError::OutOfMemory { requested: 8, available: 96 }
+        );
+
+        // Everything returned stays below the limit until it's exhausted
+        let limit = PhysAddr::new(64);
+        while let Ok(range) = alloc.allocate_contiguous_below(3, limit) {
+            assert!(range.end() <= limit);
+        }
+        assert_eq!(alloc.allocate_below(limit)?, PhysAddr::new(56));
+        assert_eq!(alloc.allocate_below(limit)?, PhysAddr::new(60));
+        assert_eq!(
+            alloc.allocate_below(limit).unwrap_err(),
+            MemError::OutOfMemory { requested: 4, available: 64 }
+        );
+        assert_eq!(alloc.bytes(), [0xff, 0xff, 0x00, 0x00]);
+
+        // A limit beyond the end of memory is the same as no limit
+        assert_eq!(alloc.allocate_below(PhysAddr::new(0x1000))?, PhysAddr::new(64));
+        assert_eq!(
+            alloc.allocate_below(PhysAddr::new(0)).unwrap_err(),
+            MemError::OutOfMemory { requested: 4, available: 60 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_free_and_reuse() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 36))?;
+        assert_eq!(alloc.usage_bytes(), (36, 128));
+
+        // Allocate a page outside the first byte, free it, and get it back again
+        let pa = alloc.allocate()?;
+        assert_eq!(pa, PhysAddr::new(36));
+        assert_eq!(alloc.usage_bytes(), (40, 128));
+        alloc.free(pa)?;
+        assert_eq!(alloc.usage_bytes(), (36, 128));
+        assert_eq!(alloc.bytes(), [0xff, 0x01, 0x00, 0x00]);
+        assert_eq!(alloc.allocate()?, pa);
+
+        // Free a contiguous range and reallocate the same range
+        let range = alloc.allocate_contiguous(3)?;
+        assert_eq!(range, PhysRange::with_end(40, 52));
+        assert_eq!(alloc.usage_bytes(), (52, 128));
+        alloc.free_range(&range)?;
+        assert_eq!(alloc.usage_bytes(), (40, 128));
+        assert_eq!(alloc.allocate_contiguous(3)?, range);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_next_free_hint() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 96))?;
+
+        // Scanning continues after the last allocation.  Marking pages free
+        // doesn't move the hint, so an earlier free page isn't found yet.
+        assert_eq!(alloc.allocate()?, PhysAddr::new(96));
+        assert_eq!(alloc.allocate()?, PhysAddr::new(100));
+        alloc.mark_free(&PhysRange::with_end(96, 100))?;
+        assert_eq!(alloc.allocate()?, PhysAddr::new(104));
+
+        // Freeing a lower page moves the hint back
+        alloc.free(PhysAddr::new(40))?;
+        assert_eq!(alloc.allocate()?, PhysAddr::new(40));
+        assert_eq!(alloc.allocate()?, PhysAddr::new(96));
+
+        // Once the end is reached, the search wraps around to the start
+        alloc.allocate_contiguous(5)?;
+        alloc.mark_free(&PhysRange::with_end(8, 12))?;
+        assert_eq!(alloc.allocate()?, PhysAddr::new(8));
+        assert_eq!(
+            alloc.allocate().unwrap_err(),
+            MemError::OutOfMemory { requested: 4, available: 0 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_many_pages() -> Result<(), MemError> {
+        // 16 bitmaps of 4096 bytes, mapped to pages of 4096 bytes, i.e. 2GiB
+        // of physical memory, of which the first 256MiB is used.  Allocate and
+        // free tens of thousands of pages.  After the first, each allocation
+        // finds its page within a page or two of the hint, since scanning
+        // resumes from the last allocation rather than the start.
+        let mut alloc = BitmapPageAlloc::<16, 4096>::new_all_allocated(4096);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, alloc.max_bytes() as u64)).unwrap();
+        alloc.init_from(&memory, &[PhysRange::with_end(0, 0x1000_0000)], &ScratchMapper)?;
+
+        let allocate_near_hint = |alloc: &mut BitmapPageAlloc<16, 4096>| {
+            let hint = alloc.next_free_hint;
+            let pa = alloc.allocate()?;
+            let page_idx = pa.addr() as usize / 4096;
+            assert!((hint..=hint + 1).contains(&page_idx), "page {page_idx} hint {hint}");
+            Ok::<_, MemError>(pa)
+        };
+
+        let mut pages = vec![alloc.allocate()?];
+        for _ in 1..50_000 {
+            pages.push(allocate_near_hint(&mut alloc)?);
+        }
+        assert!(pages.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(pages[0], PhysAddr::new(0x1000_0000));
+
+        // Free every other page, then reallocate them, lowest first
+        for pa in pages.iter().step_by(2) {
+            alloc.free(*pa)?;
+        }
+        for pa in pages.iter().step_by(2) {
+            assert_eq!(allocate_near_hint(&mut alloc)?, *pa);
+        }
+        for pa in &pages {
+            alloc.free(*pa)?;
+        }
+        assert_eq!(alloc.stats().allocated_pages, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_deallocate_invalid() -> Result<(), MemError> {
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(0, 8))?;
+
+        assert_eq!(
+            alloc.deallocate(PhysAddr::new(2)).unwrap_err(),
+            MemError::Unaligned { addr: 2, required: 4 }
+        );
+        assert_eq!(
+            alloc.deallocate(PhysAddr::new(128)).unwrap_err(),
+            MemError::OutOfRange { addr: 128, range: 0..128 }
+        );
+        assert_eq!(
+            alloc.deallocate(PhysAddr::new(8)).unwrap_err(),
+            MemError::NotAllocated { pa: PhysAddr::new(8) }
+        );
+        assert_eq!(alloc.bytes(), [0x03, 0x00, 0x00, 0x00]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(
+        expected = "can't free page PhysAddr(0x0000000000000004): page 0x0000000000000004 not allocated"
+    )]
+    fn bitmappagealloc_double_free_panics() {
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64)).unwrap();
+        alloc.mark_allocated(&PhysRange::with_end(0, 8)).unwrap();
+
+        alloc.free(PhysAddr::new(4)).unwrap();
+        let _ = alloc.free(PhysAddr::new(4));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "can't free page PhysAddr(0x0000000000000008)")]
+    fn bitmappagealloc_free_range_partially_free_panics() {
+        let mut alloc = all_allocated::<2, 2>(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64)).unwrap();
+        alloc.mark_allocated(&PhysRange::with_end(0, 8)).unwrap();
+
+        let _ = alloc.free_range(&PhysRange::with_end(0, 12));
+    }
+
+    #[test]
+    fn bitmappagealloc_init_from() -> Result<(), MemError> {
+        // 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes
+        // 32 bits, 128 bytes physical memory
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+
+        // Two banks of memory, the first not page aligned, and the second
+        // extending beyond the end of what the bitmaps can describe.
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(2, 46)).unwrap();
+        memory.add(&PhysRange::with_end(64, 256)).unwrap();
+
+        // Reserved ranges, including one outside memory, and one not page aligned
+        let reserved = [
+            PhysRange::with_end(8, 16),
+            PhysRange::with_end(30, 34),
+            PhysRange::with_end(200, 300),
+        ];
+
+        // The bitmaps need 4 bytes, so a page is carved from the top of the
+        // memory the bitmaps describe, and counted as reserved
+        let summary = alloc.init_from(&memory, &reserved, &ScratchMapper)?;
+        assert_eq!(
+            summary,
+            PageAllocSummary { total_pages: 26, reserved_pages: 5, free_pages: 21 }
+        );
+        assert_eq!(alloc.bytes(), [0x8d, 0xf9, 0x00, 0x80]);
+        assert_eq!(alloc.usage_bytes(), (44, 128));
+        let mut metadata = Vec::new();
+        alloc.for_each_metadata_range(|range| metadata.push(range.clone()));
+        assert_eq!(metadata, [PhysRange::with_end(124, 128)]);
+
+        // Allocations skip the reserved pages and the hole between the banks
+        assert_eq!(alloc.allocate_contiguous(3)?, PhysRange::with_end(16, 28));
+        assert_eq!(alloc.allocate_contiguous(2)?, PhysRange::with_end(36, 44));
+        assert_eq!(alloc.allocate_contiguous(2)?, PhysRange::with_end(64, 72));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_stats() -> Result<(), MemError> {
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 64)).unwrap();
+        init_outside(&mut alloc, &memory, &[PhysRange::with_end(0, 8)])?;
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 14,
+                allocated_pages: 0,
+                peak_allocated_pages: 0
+            }
+        );
+
+        let pa = alloc.allocate()?;
+        let range = alloc.allocate_contiguous(4)?;
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 9,
+                allocated_pages: 5,
+                peak_allocated_pages: 5
+            }
+        );
+
+        // Freeing doesn't lower the peak
+        alloc.free_range(&range)?;
+        alloc.free(pa)?;
+        alloc.allocate()?;
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 13,
+                allocated_pages: 1,
+                peak_allocated_pages: 5
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_dump() -> Result<(), MemError> {
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 64)).unwrap();
+        init_outside(&mut alloc, &memory, &[PhysRange::with_end(0, 8)])?;
+        alloc.allocate_contiguous(2)?;
+
+        let mut s = String::new();
+        alloc.dump(&mut s, false).unwrap();
+        assert_eq!(
+            s,
+            concat!(
+                "Bitmap page allocator: page size: 0x4 end: 0x40\n",
+                "  region: 0x0000000000000000..0x0000000000000040\n",
+                "  pages: total 14 free 12 allocated 2 peak 2\n",
+            )
+        );
+
+        s.clear();
+        alloc.dump(&mut s, true).unwrap();
+        assert_eq!(
+            s,
+            concat!(
+                "Bitmap page allocator: page size: 0x4 end: 0x40\n",
+                "  region: 0x0000000000000000..0x0000000000000040\n",
+                "  pages: total 14 free 12 allocated 2 peak 2\n",
+                "  0x0000000000000000..0x0000000000000010 used\n",
+                "  0x0000000000000010..0x0000000000000040 free\n",
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     fn physaddr_as_indices() {
         let alloc = BitmapPageAlloc::<2, 4096>::new_all_allocated(4096);
@@ -0,0 +1,152 @@
+/// bootargs parses the kernel command line the bootloader passes in the
+/// bootargs property of /chosen: arguments separated by whitespace, each a
+/// key, optionally followed by `=` and a value.  `args` iterates over them,
+/// and `BootArgs::parse` picks out those the kernel understands:
+///
+/// - `mem=<size>` limits usable memory to the lowest `<size>` bytes
+/// - `reserve=<start>+<len>` keeps a range from the page allocator, and may
+///   be repeated
+/// - `loglevel=<n>` sets the most verbose log level printed, as a number from
+///   1 (errors) to 5 (trace), or a name
+/// - `memtest=<n>` tests the free RAM at boot, quarantining pages that fail:
+///   0 skips the test, 1 tests the first page of every 2MiB, and 2 tests all
+///   of it
+/// - `footprint` prints the physical ranges the kernel claims, one per line,
+///   for tools that place it
+///
+/// Sizes and addresses are as `mem::parse_size` accepts.  Unknown keys are
+/// ignored, with one warning line listing them all, and malformed values
+/// are reported and leave the default, so a typo never stops the boot.
+use crate::log::Level;
+use crate::mem::{PhysRange, PhysRangeSet, parse_size};
+use crate::memtest::MemTestMode;
+use crate::{error, warn};
+use core::fmt;
+
+const KNOWN_KEYS: [&str; 5] = ["mem", "reserve", "loglevel", "memtest", "footprint"];
+
+/// Return an iterator over the keys of `cmdline`, and their values, if
+/// they have any.
+pub fn args(cmdline: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    cmdline.split_ascii_whitespace().map(|arg| match arg.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (arg, None),
+    })
+}
+
+/// The boot arguments the kernel understands.  Each is None, or empty, if it
+/// wasn't given or was malformed.
+#[derive(Debug, Default, PartialEq)]
+pub struct BootArgs {
+    pub mem_limit: Option<usize>,     // mem=
+    pub reserved: PhysRangeSet,       // Each reserve=
+    pub log_level: Option<Level>,     // loglevel=
+    pub memtest: Option<MemTestMode>, // memtest=
+    pub footprint: bool,              // footprint
+}
+
+impl BootArgs {
+    /// Parse the arguments in `cmdline`, logging any that are unknown or
+    /// malformed.
+    pub fn parse(cmdline: &str) -> BootArgs {
+        let mut bootargs = BootArgs::default();
+        for (key, value) in args(cmdline) {
+            match key {
+                "mem" => match value.and_then(parse_size).and_then(|n| n.try_into().ok()) {
+                    Some(size) => bootargs.mem_limit = Some(size),
+                    None => malformed(key, value, "expected a size, e.g. 512M"),
+                },
+                "reserve" => match value.and_then(PhysRange::parse) {
+                    Some(range) => {
+                        if bootargs.reserved.add(&range).is_err() {
+                            error!("too many reserve= ranges, ignoring {range}");
+                        }
+                    }
+                    None => malformed(key, value, "expected <start>+<len>, e.g. 0x8000000+16M"),
+                },
+                "loglevel" => match value.and_then(Level::parse) {
+                    Some(level) => bootargs.log_level = Some(level),
+                    None => malformed(key, value, "expected 1-5 or a level name"),
+                },
+                "memtest" => match value {
+                    Some("0") => bootargs.memtest = None,
+                    _ => match value.and_then(MemTestMode::parse) {
+                        Some(mode) => bootargs.memtest = Some(mode),
+                        None => malformed(key, value, "expected 0, 1 or 2"),
+                    },
+                },
+                "footprint" => bootargs.footprint = true,
+                _ => {}
+            }
+        }
+        if args(cmdline).any(|(key, _)| !KNOWN_KEYS.contains(&key)) {
+            warn!("ignoring unknown boot arguments:{}", UnknownKeys(cmdline));
+        }
+        bootargs
+    }
+}
+
+fn malformed(key: &str, value: Option<&str>, expected: &str) {
+    error!("ignoring malformed boot argument {key}={}: {expected}", value.unwrap_or(""));
+}
+
+/// Formats the unknown keys of a command line, each preceded by a space.
+struct UnknownKeys<'a>(&'a str);
+
+impl fmt::Display for UnknownKeys<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, _) in args(self.0).filter(|(key, _)| !KNOWN_KEYS.contains(key)) {
+            write!(f, " {key}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_args() {
+        let cmdline = "  mem=512M quiet\treserve=0x1000+4K coherent_pool=1M  ";
+        let parsed: Vec<_> = args(cmdline).collect();
+        assert_eq!(
+            parsed,
+            [
+                ("mem", Some("512M")),
+                ("quiet", None),
+                ("reserve", Some("0x1000+4K")),
+                ("coherent_pool", Some("1M"))
+            ]
+        );
+        assert_eq!(args("").count(), 0);
+    }
+
+    #[test]
+    fn parses_known_args() {
+        let bootargs = BootArgs::parse(
+            "mem=256M reserve=0x8000000+16M loglevel=debug reserve=0x1000+0x1000 quiet memtest=2 \
+             footprint",
+        );
+        assert_eq!(bootargs.mem_limit, Some(256 << 20));
+        assert_eq!(
+            bootargs.reserved.as_slice(),
+            [PhysRange::with_end(0x1000, 0x2000), PhysRange::with_end(0x800_0000, 0x900_0000)]
+        );
+        assert_eq!(bootargs.log_level, Some(Level::Debug));
+        assert_eq!(bootargs.memtest, Some(MemTestMode::Full));
+        assert!(bootargs.footprint);
+        assert_eq!(BootArgs::parse("memtest=1 memtest=0").memtest, None);
+        assert_eq!(UnknownKeys("mem=1G quiet a=b").to_string(), " quiet a");
+    }
+
+    #[test]
+    fn malformed_values_keep_defaults() {
+        let bootargs = BootArgs::parse("mem=lots mem reserve=0x1000 loglevel=9 memtest=3 memtest");
+        assert_eq!(bootargs, BootArgs::default());
+
+        // A later good value still applies
+        let bootargs = BootArgs::parse("loglevel=loud loglevel=2");
+        assert_eq!(bootargs.log_level, Some(Level::Warn));
+    }
+}
@@ -0,0 +1,720 @@
+/// buddyalloc implements a binary buddy page allocator.
+///
+/// Memory is divided into naturally aligned blocks of 2^order pages, up to
+/// `MAX_ORDER`.  Allocations take the lowest free block of the smallest order
+/// that fits, splitting larger blocks as needed.  Freeing a block coalesces it
+/// with its buddy whenever the buddy is also free.
+///
+/// The bitmaps are carved out of memory by `init_from`, only as many as are
+/// needed to cover memory up to its end.
+///
+/// Benefits of the current implementation:
+///  - Doesn't require any allocations, so can be used without fear while
+///    manipulating the page tables.  Free blocks are tracked with one bit per
+///    block per order rather than lists threaded through the free pages,
+///    since free pages aren't mapped.
+///  - Contiguous and aligned allocations only search the orders that can
+///    satisfy them, rather than scanning every page.
+///
+/// Downsides:
+///  - Can't be dynamically resized.
+///  - Contiguous allocations are limited to 2^MAX_ORDER pages.
+///  - Needs twice the metadata of the bitmap allocator.
+use core::{fmt, slice};
+
+use crate::{
+    bitmapalloc::Bitmap,
+    mem::{MemError, PhysAddr, PhysRange, PhysRangeSet},
+    pagealloc::{
+        CarvedPageAlloc, Metadata, PageAlloc, PageAllocStats, PageAllocSummary, PageMapper,
+        ReserveError, carve_metadata, check_free_range, check_reserve_range, out_of_memory,
+        page_runs, usable_ranges, write_page_runs,
+    },
+};
+
+/// Largest block order, i.e. blocks of up to 1024 pages (4MiB of 4KiB pages).
+pub const MAX_ORDER: usize = 10;
+
+/// Buddy allocator covering the same memory as a `BitmapPageAlloc` with the
+/// same parameters.  Free blocks of order 0 are tracked in `order0`, and free
+/// blocks of all higher orders are packed one order after another into
+/// `higher_orders`, which together need fewer bits than order 0.  Each has
+/// up to `NUM_BITMAPS` bitmaps, carved out of memory together.
+pub struct BuddyPageAlloc<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> {
+    order0: &'static mut [Bitmap<BITMAP_SIZE_BYTES>], // Free blocks of order 0
+    higher_orders: &'static mut [Bitmap<BITMAP_SIZE_BYTES>], // Free blocks of order 1 and up
+    metadata: PhysRange,                              // Memory holding the bitmaps
+    search_from: [usize; MAX_ORDER + 1], // Per order, no block below this index is free
+    alloc_page_size: usize,              // Size of a block of order 0
+    end: PhysAddr,                       // Upper bound of physical memory
+    regions: PhysRangeSet,               // Physical memory managed by the allocator
+    total_pages: usize,                  // Pages available for allocation after init
+    free_pages: usize,                   // Pages currently in free blocks
+    peak_allocated_pages: usize,         // Most pages allocated at once since init
+}
+
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
+    BuddyPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    const BITS_PER_BITMAP: usize = BITMAP_SIZE_BYTES * 8;
+
+    pub const fn new_all_allocated(alloc_page_size: usize) -> Self {
+        Self {
+            order0: &mut [],
+            higher_orders: &mut [],
+            metadata: Metadata::empty().range,
+            search_from: [0; MAX_ORDER + 1],
+            alloc_page_size,
+            end: PhysAddr::new(0),
+            regions: PhysRangeSet::new(),
+            total_pages: 0,
+            free_pages: 0,
+            peak_allocated_pages: 0,
+        }
+    }
+
+    /// Returns the number of pages the allocator can describe.
+    const fn max_pages(&self) -> usize {
+        NUM_BITMAPS * Self::BITS_PER_BITMAP
+    }
+
+    /// Returns the number of pages covered by the bitmaps carved out at init.
+    fn covered_pages(&self) -> usize {
+        self.order0.len() * Self::BITS_PER_BITMAP
+    }
+
+    /// Returns the size of the largest block.
+    fn max_block_bytes(&self) -> u64 {
+        (self.alloc_page_size << MAX_ORDER) as u64
+    }
+
+    /// Return the number of pages managed by the allocator, up to `end`.
+    fn num_pages(&self) -> usize {
+        self.end.addr() as usize / self.alloc_page_size
+    }
+
+    /// Return the number of blocks of the given order the allocator can
+    /// describe.  Only whole blocks are counted.
+    fn num_blocks(&self, order: usize) -> usize {
+        self.covered_pages() >> order
+    }
+
+    /// Return the index of the bit for block 0 of the given order, within
+    /// `order0` for order 0, or `higher_orders` otherwise.
+    fn order_offset(&self, order: usize) -> usize {
+        (1..order).map(|o| self.num_blocks(o)).sum()
+    }
+
+    /// Is block `idx` of the given order free?
+    fn is_free_block(&self, order: usize, idx: usize) -> bool {
+        let bitmaps = if order == 0 { &self.order0 } else { &self.higher_orders };
+        let bit = self.order_offset(order) + idx;
+        bitmaps[bit / Self::BITS_PER_BITMAP].is_set(bit % Self::BITS_PER_BITMAP)
+    }
+
+    /// Mark block `idx` of the given order as free or not, keeping the count of
+    /// free pages up to date.  Doesn't split or coalesce.
+    fn set_free_block(&mut self, order: usize, idx: usize, free: bool) {
+        let bit = self.order_offset(order) + idx;
+        let bitmaps = if order == 0 { &mut self.order0 } else { &mut self.higher_orders };
+        bitmaps[bit / Self::BITS_PER_BITMAP].set(bit % Self::BITS_PER_BITMAP, free);
+        if free {
+            self.free_pages += 1 << order;
+            self.search_from[order] = self.search_from[order].min(idx);
+        } else {
+            self.free_pages -= 1 << order;
+        }
+    }
+
+    /// Return the index of the lowest free block of the given order, that's
+    /// no lower than `from`.
+    fn find_free_block(&mut self, order: usize, from: usize) -> Option<usize> {
+        let bitmaps = if order == 0 { &self.order0 } else { &self.higher_orders };
+        let offset = self.order_offset(order);
+        let end = offset + self.num_blocks(order);
+
+        let search_from = self.search_from[order];
+        let mut bit = offset + search_from.max(from);
+        let mut found = None;
+        while bit < end && found.is_none() {
+            let bitmap_idx = bit / Self::BITS_PER_BITMAP;
+            let bitmap_start = bitmap_idx * Self::BITS_PER_BITMAP;
+            let bitmap_end = (end - bitmap_start).min(Self::BITS_PER_BITMAP);
+            found = bitmaps[bitmap_idx]
+                .first_set(bit - bitmap_start, bitmap_end)
+                .map(|i| bitmap_start + i - offset);
+            bit = bitmap_start + Self::BITS_PER_BITMAP;
+        }
+
+        // Nothing below the block found is free, so later searches can skip
+        // it, unless the search skipped free blocks below `from`
+        if from <= search_from {
+            self.search_from[order] = found.unwrap_or(self.num_blocks(order));
+        }
+        found
+    }
+
+    /// Free block `idx` of the given order, coalescing it with its buddy for as
+    /// long as the buddy is free.
+    fn free_block(&mut self, order: usize, idx: usize) {
+        let mut order = order;
+        let mut idx = idx;
+        while order < MAX_ORDER {
+            let buddy_idx = idx ^ 1;
+            if buddy_idx >= self.num_blocks(order) || !self.is_free_block(order, buddy_idx) {
+                break;
+            }
+            self.set_free_block(order, buddy_idx, false);
+            idx >>= 1;
+            order += 1;
+        }
+        self.set_free_block(order, idx, true);
+    }
+
+    /// Allocate the lowest block of `order` that starts at or after
+    /// `start_page` and ends at or before `end_page`, from the lowest suitable
+    /// free block of the smallest order no less than `order`.  Larger blocks
+    /// are split, returning the rest.  Returns the index of the first page of
+    /// the block.
+    fn allocate_block(
+        &mut self,
+        order: usize,
+        start_page: usize,
+        end_page: usize,
+    ) -> Option<usize> {
+        let first_aligned = start_page.next_multiple_of(1 << order);
+        for o in order..=MAX_ORDER {
+            // The first free block found may straddle start_page, and be too
+            // short beyond it, but the next one found won't be
+            let mut from = start_page >> o;
+            while let Some(idx) = self.find_free_block(o, from) {
+                let block_start = idx << o;
+                let first_page = block_start.max(first_aligned);
+                if first_page + (1 << order) > end_page {
+                    break;
+                }
+                if first_page + (1 << order) <= block_start + (1 << o) {
+                    self.set_free_block(o, idx, false);
+                    for split_order in (order..o).rev() {
+                        self.set_free_block(split_order, (first_page >> split_order) ^ 1, true);
+                    }
+                    return Some(first_page);
+                }
+                from = idx + 1;
+            }
+        }
+        None
+    }
+
+    /// Return the order and index of the free block containing the page with
+    /// the given index, if any.
+    fn containing_free_block(&self, page_idx: usize) -> Option<(usize, usize)> {
+        (0..=MAX_ORDER)
+            .map(|order| (order, page_idx >> order))
+            .find(|&(order, idx)| idx < self.num_blocks(order) && self.is_free_block(order, idx))
+    }
+
+    /// Is the page with the given index (counting from physical address 0)
+    /// allocated?
+    fn is_page_allocated(&self, page_idx: usize) -> bool {
+        self.containing_free_block(page_idx).is_none()
+    }
+
+    /// Allocate the page with the given index if it's free, splitting the free
+    /// block containing it and returning the rest.
+    fn claim_page(&mut self, page_idx: usize) {
+        if let Some((order, idx)) = self.containing_free_block(page_idx) {
+            self.set_free_block(order, idx, false);
+            for split_order in (0..order).rev() {
+                self.set_free_block(split_order, (page_idx >> split_order) ^ 1, true);
+            }
+        }
+    }
+
+    /// Allocate a run of `page_count` pages, starting on a page index that
+    /// is a multiple of `align_pages`, and lying within `within`.  The run is
+    /// taken from a single block, and any pages of the block beyond the run
+    /// are freed again.
+    fn allocate_run(
+        &mut self,
+        page_count: usize,
+        align_pages: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        if page_count == 0 {
+            return Err(MemError::InvalidPageCount { count: page_count });
+        }
+        let out_of_memory =
+            |alloc: &Self| out_of_memory(page_count, alloc.page_size(), alloc.stats());
+        let order = page_count
+            .checked_next_power_of_two()
+            .map(|block_pages| block_pages.max(align_pages).trailing_zeros() as usize)
+            .filter(|&order| order <= MAX_ORDER)
+            .ok_or_else(|| out_of_memory(self))?;
+        let page_size = self.alloc_page_size as u64;
+        let start_page = within.start().addr().div_ceil(page_size) as usize;
+        let end_page = self.num_pages().min((within.end().addr() / page_size) as usize);
+
+        let first_page =
+            self.allocate_block(order, start_page, end_page).ok_or_else(|| out_of_memory(self))?;
+        for page_idx in first_page + page_count..first_page + (1 << order) {
+            self.free_block(0, page_idx);
+        }
+        self.update_peak();
+
+        let start = PhysAddr::new((first_page * self.alloc_page_size) as u64);
+        Ok(PhysRange::with_pa_len(start, page_count * self.alloc_page_size))
+    }
+
+    /// Return the whole range the allocator manages.
+    fn all(&self) -> PhysRange {
+        PhysRange::new(PhysAddr::new(0), self.end)
+    }
+
+    /// Return the number of pages currently allocated.  Pages freed that were
+    /// never part of the available memory (e.g. reserved pages) may push the
+    /// free count beyond the total, so saturate rather than underflow.
+    fn allocated_pages(&self) -> usize {
+        self.total_pages.saturating_sub(self.free_pages)
+    }
+
+    /// Record the high-water mark of allocated pages.  Called after each
+    /// allocation.
+    fn update_peak(&mut self) {
+        self.peak_allocated_pages = self.peak_allocated_pages.max(self.allocated_pages());
+    }
+
+    /// Return the index of the page at `pa`, ensuring that it's page aligned,
+    /// within the managed region, and currently allocated.
+    fn allocated_page_index(&self, pa: PhysAddr) -> Result<usize, MemError> {
+        if !pa.is_multiple_of(self.alloc_page_size as u64) {
+            return Err(MemError::Unaligned { addr: pa.addr(), required: self.alloc_page_size });
+        }
+        if pa >= self.end {
+            return Err(MemError::OutOfRange { addr: pa.addr(), range: 0..self.end.addr() });
+        }
+        let page_idx = pa.addr() as usize / self.alloc_page_size;
+        if !self.is_page_allocated(page_idx) {
+            return Err(MemError::NotAllocated { pa });
+        }
+        Ok(page_idx)
+    }
+
+    /// Return the number of free blocks of the given order.
+    fn free_blocks(&self, order: usize) -> usize {
+        (0..self.num_blocks(order)).filter(|&idx| self.is_free_block(order, idx)).count()
+    }
+
+    fn mark_range(
+        &mut self,
+        range: &PhysRange,
+        mark_allocated: bool,
+        check_end: bool,
+    ) -> Result<(), MemError> {
+        if check_end && range.0.end > self.end {
+            return Err(MemError::OutOfRange {
+                addr: range.end().addr(),
+                range: 0..self.end.addr(),
+            });
+        }
+
+        for pa in range.step_by_rounded(self.alloc_page_size) {
+            let page_idx = pa.addr() as usize / self.alloc_page_size;
+            if page_idx >= self.covered_pages() {
+                let covered_end = (self.covered_pages() * self.alloc_page_size) as u64;
+                return Err(MemError::OutOfRange { addr: pa.addr(), range: 0..covered_end });
+            }
+
+            if mark_allocated {
+                self.claim_page(page_idx);
+            } else if self.is_page_allocated(page_idx) {
+                self.free_block(0, page_idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the end of `memory`, limited to what the allocator can describe.
+    fn memory_end<const N: usize>(&self, memory: &PhysRangeSet<N>) -> PhysAddr {
+        memory.iter().map(|r| r.end()).max().unwrap_or_default().min(self.max_end())
+    }
+
+    /// Set up the bitmaps in `metadata` for memory up to the end of `memory`,
+    /// with every page allocated other than the pages in `usable`.
+    ///
+    /// # Safety
+    ///
+    /// As for `init_with_metadata`.
+    unsafe fn init_blocks<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        regions: PhysRangeSet,
+        usable: PhysRangeSet<N>,
+        metadata: Metadata,
+    ) -> Result<PageAllocSummary, MemError> {
+        let end = self.memory_end(memory);
+        let size = self.metadata_size(end);
+        // Safety: the caller guarantees the pointer covers `size` bytes, and
+        // every bitmap byte is valid once cleared
+        let bitmaps: &'static mut [Bitmap<BITMAP_SIZE_BYTES>] = unsafe {
+            metadata.ptr.write_bytes(0, size);
+            slice::from_raw_parts_mut(metadata.ptr.cast(), size / BITMAP_SIZE_BYTES)
+        };
+        (self.order0, self.higher_orders) = bitmaps.split_at_mut(bitmaps.len() / 2);
+        self.metadata = metadata.range;
+        self.search_from = [0; MAX_ORDER + 1];
+        self.free_pages = 0;
+
+        self.end = end;
+        for range in usable.iter() {
+            self.mark_free(range)?;
+        }
+        let total_pages = regions.size() / self.alloc_page_size;
+        let free_pages = self.free_pages;
+        self.regions = regions;
+        self.total_pages = free_pages;
+        self.peak_allocated_pages = 0;
+
+        Ok(PageAllocSummary { total_pages, reserved_pages: total_pages - free_pages, free_pages })
+    }
+}
+
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> PageAlloc
+    for BuddyPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    fn page_size(&self) -> usize {
+        self.alloc_page_size
+    }
+
+    fn max_end(&self) -> PhysAddr {
+        PhysAddr::new((self.max_pages() * self.alloc_page_size) as u64)
+    }
+
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, true, true)
+    }
+
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, false, true)
+    }
+
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError> {
+        let (regions, mut usable) =
+            usable_ranges(memory, reserved, self.alloc_page_size, self.max_end())?;
+        let size = self.metadata_size(self.memory_end(memory));
+        let metadata = carve_metadata(&mut usable, size, self.alloc_page_size, mapper)?;
+        // Safety: carve_metadata took the memory out of `usable`, and the
+        // PageMapper guarantees the pointer covers it
+        unsafe { self.init_blocks(memory, regions, usable, metadata) }
+    }
+
+    fn for_each_metadata_range(&self, mut f: impl FnMut(&PhysRange)) {
+        if !self.metadata.is_empty() {
+            f(&self.metadata);
+        }
+    }
+
+    fn remap_metadata(&mut self, mapper: &impl PageMapper) -> Result<(), MemError> {
+        if self.metadata.is_empty() {
+            return Ok(());
+        }
+        let pa = self.metadata.start();
+        let ptr =
+            mapper.page_ptr(pa, self.metadata.size()).ok_or(MemError::PhysNotMapped { pa })?;
+        let len = self.order0.len();
+        // Safety: the PageMapper guarantees the pointer covers the bitmaps
+        let bitmaps: &'static mut [Bitmap<BITMAP_SIZE_BYTES>] =
+            unsafe { slice::from_raw_parts_mut(ptr.cast(), len * 2) };
+        (self.order0, self.higher_orders) = bitmaps.split_at_mut(len);
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PhysAddr, MemError> {
+        self.allocate_run(1, 1, &self.all()).map(|range| range.start())
+    }
+
+    /// Requests are rounded up to a block of a power of two pages, so may
+    /// fail where the bitmap allocator would succeed if memory is fragmented,
+    /// and can't exceed 2^MAX_ORDER pages.  Nor can the alignment, since no
+    /// larger block is ever aligned to it.
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError> {
+        if !align.is_power_of_two() || align > self.end.addr() || align > self.max_block_bytes() {
+            return Err(MemError::InvalidAlignment { align });
+        }
+        let align_pages = (align as usize / self.alloc_page_size).max(1);
+        self.allocate_run(page_count, align_pages, &self.all())
+    }
+
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        self.allocate_run(page_count, 1, within)
+    }
+
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        let page_idx = self.allocated_page_index(pa)?;
+        self.free_block(0, page_idx);
+        Ok(())
+    }
+
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        check_free_range(range, self.alloc_page_size, |pa| self.allocated_page_index(pa))?;
+
+        for pa in range.step_by_rounded(self.alloc_page_size) {
+            self.free_block(0, pa.addr() as usize / self.alloc_page_size);
+        }
+        Ok(())
+    }
+
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError> {
+        let range = check_reserve_range(range, self.alloc_page_size, &self.regions, |pa| {
+            self.is_page_allocated(pa.addr() as usize / self.alloc_page_size)
+        })?;
+        for pa in range.step_by_rounded(self.alloc_page_size) {
+            self.claim_page(pa.addr() as usize / self.alloc_page_size);
+        }
+        self.update_peak();
+        Ok(range)
+    }
+
+    fn stats(&self) -> PageAllocStats {
+        PageAllocStats {
+            total_pages: self.total_pages,
+            free_pages: self.free_pages,
+            allocated_pages: self.allocated_pages(),
+            peak_allocated_pages: self.peak_allocated_pages,
+        }
+    }
+
+    fn for_each_free_range(&self, mut f: impl FnMut(&PhysRange)) {
+        page_runs(self.num_pages(), self.alloc_page_size, |page_idx| {
+            self.is_page_allocated(page_idx)
+        })
+        .filter(|(_, allocated)| !allocated)
+        .for_each(|(range, _)| f(&range));
+    }
+
+    fn usage_bytes(&self) -> (usize, usize) {
+        let total = self.end.0 as usize;
+        (total - self.free_pages * self.alloc_page_size, total)
+    }
+
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result {
+        writeln!(
+            w,
+            "Buddy page allocator: page size: {:#x} end: {:#x}",
+            self.alloc_page_size,
+            self.end.addr()
+        )?;
+        for region in self.regions.iter() {
+            writeln!(w, "  region: {}", region)?;
+        }
+        writeln!(w, "  {}", self.stats())?;
+        write!(w, "  free blocks by order:")?;
+        for order in 0..=MAX_ORDER {
+            write!(w, " {}:{}", order, self.free_blocks(order))?;
+        }
+        writeln!(w)?;
+
+        if show_map {
+            write_page_runs(w, self.num_pages(), self.alloc_page_size, |page_idx| {
+                self.is_page_allocated(page_idx)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize> CarvedPageAlloc
+    for BuddyPageAlloc<NUM_BITMAPS, BITMAP_SIZE_BYTES>
+{
+    /// A whole bitmap is needed for any memory it covers, for order 0 and
+    /// again for the higher orders.
+    fn metadata_size(&self, end: PhysAddr) -> usize {
+        let end = end.min(self.max_end()).addr() as usize;
+        let bytes_per_bitmap = Self::BITS_PER_BITMAP * self.alloc_page_size;
+        end.div_ceil(bytes_per_bitmap) * BITMAP_SIZE_BYTES * 2
+    }
+
+    unsafe fn init_with_metadata<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        metadata: Metadata,
+    ) -> Result<PageAllocSummary, MemError> {
+        let (regions, usable) =
+            usable_ranges(memory, reserved, self.alloc_page_size, self.max_end())?;
+        // Safety: the caller's guarantees are passed on
+        unsafe { self.init_blocks(memory, regions, usable, metadata) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakemem::{ScratchMapper, init_outside};
+
+    /// 2 bitmaps, 2 bytes per bitmap, mapped to pages of 4 bytes, so 32 pages
+    /// and 128 bytes of physical memory, all free, with the bitmaps kept
+    /// elsewhere.
+    fn new_free_alloc() -> BuddyPageAlloc<2, 2> {
+        let mut alloc = BuddyPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 128)).unwrap();
+        init_outside(&mut alloc, &memory, &[]).unwrap();
+        alloc
+    }
+
+    fn free_blocks_by_order(alloc: &BuddyPageAlloc<2, 2>) -> Vec<usize> {
+        (0..=5).map(|order| alloc.free_blocks(order)).collect()
+    }
+
+    #[test]
+    fn buddypagealloc_init_from() {
+        let alloc = new_free_alloc();
+        assert_eq!(free_blocks_by_order(&alloc), [0, 0, 0, 0, 0, 1]);
+
+        // Unaligned memory is made up of blocks of several orders
+        let mut alloc = BuddyPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(4, 104)).unwrap();
+        init_outside(&mut alloc, &memory, &[]).unwrap();
+        assert_eq!(free_blocks_by_order(&alloc), [1, 2, 1, 2, 0, 0]);
+        assert_eq!(alloc.stats().free_pages, 25);
+    }
+
+    #[test]
+    fn buddypagealloc_init_from_carves_bitmaps() -> Result<(), MemError> {
+        // Memory up to 64 needs only the first bitmap of each kind, so 4
+        // bytes are taken from the top of memory
+        let mut alloc = BuddyPageAlloc::<2, 2>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 64)).unwrap();
+        let summary = alloc.init_from(&memory, &[], &ScratchMapper)?;
+        assert_eq!(
+            summary,
+            PageAllocSummary { total_pages: 16, reserved_pages: 1, free_pages: 15 }
+        );
+        assert_eq!((alloc.order0.len(), alloc.higher_orders.len()), (1, 1));
+        let mut metadata = Vec::new();
+        alloc.for_each_metadata_range(|range| metadata.push(range.clone()));
+        assert_eq!(metadata, [PhysRange::with_end(60, 64)]);
+        assert_eq!(free_blocks_by_order(&alloc), [1, 1, 1, 1, 0, 0]);
+        assert!(alloc.is_page_allocated(15));
+
+        // Only the memory the bitmaps cover can be marked
+        assert_eq!(
+            alloc.mark_free(&PhysRange::with_end(64, 68)),
+            Err(MemError::OutOfRange { addr: 68, range: 0..64 })
+        );
+        assert_eq!(
+            alloc.mark_range(&PhysRange::with_end(64, 68), false, false),
+            Err(MemError::OutOfRange { addr: 64, range: 0..64 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn buddypagealloc_split_and_coalesce() -> Result<(), MemError> {
+        let mut alloc = new_free_alloc();
+
+        // Allocating a page splits the single block, leaving one block of
+        // each lower order
+        assert_eq!(alloc.allocate()?, PhysAddr::new(0));
+        assert_eq!(free_blocks_by_order(&alloc), [1, 1, 1, 1, 1, 0]);
+        assert_eq!(alloc.allocate()?, PhysAddr::new(4));
+        assert_eq!(free_blocks_by_order(&alloc), [0, 1, 1, 1, 1, 0]);
+
+        // Freeing coalesces back into a single block only once both are free
+        alloc.deallocate(PhysAddr::new(0))?;
+        assert_eq!(free_blocks_by_order(&alloc), [1, 1, 1, 1, 1, 0]);
+        alloc.deallocate(PhysAddr::new(4))?;
+        assert_eq!(free_blocks_by_order(&alloc), [0, 0, 0, 0, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn buddypagealloc_contiguous_frees_excess() -> Result<(), MemError> {
+        let mut alloc = new_free_alloc();
+
+        // 3 pages come from a block of 4, and the last page is freed again
+        assert_eq!(alloc.allocate_contiguous(3)?, PhysRange::with_end(0, 12));
+        assert_eq!(free_blocks_by_order(&alloc), [1, 0, 1, 1, 1, 0]);
+        assert_eq!(alloc.stats().allocated_pages, 3);
+
+        // The freed page is used for the next single page
+        assert_eq!(alloc.allocate()?, PhysAddr::new(12));
+
+        alloc.free_range(&PhysRange::with_end(0, 12))?;
+        alloc.free(PhysAddr::new(12))?;
+        assert_eq!(free_blocks_by_order(&alloc), [0, 0, 0, 0, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn buddypagealloc_mark_allocated_splits() -> Result<(), MemError> {
+        let mut alloc = new_free_alloc();
+
+        alloc.mark_allocated(&PhysRange::with_end(20, 24))?;
+        assert!(alloc.is_page_allocated(5));
+        assert!(!alloc.is_page_allocated(4));
+        assert_eq!(free_blocks_by_order(&alloc), [1, 1, 1, 1, 1, 0]);
+        assert_eq!(alloc.stats().free_pages, 31);
+
+        alloc.mark_free(&PhysRange::with_end(20, 24))?;
+        assert_eq!(free_blocks_by_order(&alloc), [0, 0, 0, 0, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn buddypagealloc_max_order() -> Result<(), MemError> {
+        // 4096 pages
+        let mut alloc = BuddyPageAlloc::<1, 512>::new_all_allocated(4);
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 4096 * 4)).unwrap();
+        init_outside(&mut alloc, &memory, &[])?;
+        assert_eq!(alloc.free_blocks(MAX_ORDER), 4);
+
+        assert_eq!(
+            alloc.allocate_contiguous(1025),
+            Err(MemError::OutOfMemory { requested: 4100, available: 16384 })
+        );
+        assert_eq!(
+            alloc.allocate_aligned(1, 1024 * 4 * 2),
+            Err(MemError::InvalidAlignment { align: 1024 * 4 * 2 })
+        );
+        assert_eq!(alloc.allocate_aligned(1, 1024 * 4)?, PhysRange::with_len(0, 4));
+        alloc.free(PhysAddr::new(0))?;
+        assert_eq!(alloc.allocate_contiguous(1024)?, PhysRange::with_len(0, 1024 * 4));
+        assert_eq!(alloc.allocate_contiguous(1000)?, PhysRange::with_len(1024 * 4, 1000 * 4));
+        Ok(())
+    }
+
+    #[test]
+    fn buddypagealloc_dump() -> Result<(), MemError> {
+        let mut alloc = new_free_alloc();
+        alloc.allocate_contiguous(2)?;
+
+        let mut s = String::new();
+        alloc.dump(&mut s, true).unwrap();
+        assert_eq!(
+            s,
+            concat!(
+                "Buddy page allocator: page size: 0x4 end: 0x80\n",
+                "  region: 0x0000000000000000..0x0000000000000080\n",
+                "  pages: total 32 free 30 allocated 2 peak 2\n",
+                "  free blocks by order: 0:0 1:1 2:1 3:1 4:1 5:0 6:0 7:0 8:0 9:0 10:0\n",
+                "  0x0000000000000000..0x0000000000000008 used\n",
+                "  0x0000000000000008..0x0000000000000080 free\n",
+            )
+        );
+        Ok(())
+    }
+}
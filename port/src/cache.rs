@@ -0,0 +1,105 @@
+/// cache maintains the data cache for memory shared with devices that don't
+/// snoop it, such as the Raspberry Pi's VideoCore: a buffer is cleaned
+/// before a device reads it, so the device sees what the CPU wrote, and
+/// invalidated after a device writes it, so the CPU doesn't read stale
+/// lines.  Each arch sets its operations with `set_cache_ops`.  Until it
+/// does, or on archs and configs where devices are coherent with the
+/// caches, such as QEMU's virt machines, the operations do nothing.
+///
+/// The operations work on whole cache lines, so a range that isn't aligned
+/// to the line size is rounded out to it.  Cleaning the extra bytes is
+/// harmless, but invalidating them discards anything the CPU wrote to them
+/// that wasn't cleaned first, so buffers that devices write should be
+/// aligned to, and a multiple of, the line size, or not share their lines
+/// with anything the CPU writes while the device owns the buffer.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::mem::{VirtAddr, VirtRange};
+
+/// The cache operations of an arch.  Each operates on every line of the
+/// range, which has been rounded out to whole lines, and completes before
+/// returning.
+pub struct CacheOps {
+    pub line_size: fn() -> usize,
+    pub clean: fn(&VirtRange),
+    pub invalidate: fn(&VirtRange),
+    pub clean_invalidate: fn(&VirtRange),
+}
+
+/// The arch's cache operations, or null if there aren't any.
+static CACHE_OPS: AtomicPtr<CacheOps> = AtomicPtr::new(null_mut());
+
+/// Set the arch's cache operations.
+pub fn set_cache_ops(ops: &'static CacheOps) {
+    CACHE_OPS.store(ops as *const CacheOps as *mut CacheOps, Ordering::Release);
+}
+
+fn cache_ops() -> Option<&'static CacheOps> {
+    // Safety: only set_cache_ops stores to CACHE_OPS, from a 'static
+    unsafe { CACHE_OPS.load(Ordering::Acquire).as_ref() }
+}
+
+/// Return `range` rounded out to whole lines of `line_size`, which must be a
+/// power of 2.
+pub fn line_range(range: &VirtRange, line_size: usize) -> VirtRange {
+    let start = range.start().round_down(line_size);
+    let end = range.end().round_up(line_size);
+    VirtRange(start..end)
+}
+
+/// Call `op` with `range` rounded out to whole cache lines, if the arch has
+/// cache operations and the range isn't empty.
+fn with_lines(range: &VirtRange, op: impl Fn(&CacheOps) -> fn(&VirtRange)) {
+    if range.start() >= range.end() {
+        return;
+    }
+    if let Some(ops) = cache_ops() {
+        op(ops)(&line_range(range, (ops.line_size)()));
+    }
+}
+
+/// Write any dirty lines of `range` back to memory, so that a device reading
+/// memory sees them.
+pub fn clean(range: &VirtRange) {
+    with_lines(range, |ops| ops.clean);
+}
+
+/// Discard the lines of `range` from the cache, so the CPU next reads what a
+/// device wrote to memory.  Lines shared with bytes outside `range` are
+/// discarded too, losing anything written to those bytes but not cleaned.
+pub fn invalidate(range: &VirtRange) {
+    with_lines(range, |ops| ops.invalidate);
+}
+
+/// Write back, then discard, the lines of `range`.
+pub fn clean_invalidate(range: &VirtRange) {
+    with_lines(range, |ops| ops.clean_invalidate);
+}
+
+/// Return the range of memory `value` occupies, for the operations above.
+pub fn range_of<T: ?Sized>(value: &T) -> VirtRange {
+    let start = VirtAddr::new(value as *const T as *const u8 as usize);
+    VirtRange::with_len(start, size_of_val(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_range_rounds_out() {
+        let range = |start, end| VirtRange(VirtAddr::new(start)..VirtAddr::new(end));
+        assert_eq!(line_range(&range(0x1000, 0x1040), 64), range(0x1000, 0x1040));
+        assert_eq!(line_range(&range(0x1001, 0x1041), 64), range(0x1000, 0x1080));
+        assert_eq!(line_range(&range(0x103f, 0x1040), 64), range(0x1000, 0x1040));
+    }
+
+    #[test]
+    fn range_of_value() {
+        let buf = [0u32; 5];
+        let range = range_of(&buf);
+        assert_eq!(range.start().addr(), buf.as_ptr().addr());
+        assert_eq!(range.size(), 20);
+    }
+}
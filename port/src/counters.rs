@@ -0,0 +1,224 @@
+/// counters count events, such as pages allocated or TLB entries
+/// invalidated, and time spent, such as in each phase of boot, to see what
+/// operations cost.  Counters are statics declared with `counter!`, which
+/// puts them in the `.counters` section, so `report` can print them all.
+///
+/// Each core counts in its own cache line, so counting is a single relaxed
+/// atomic add that doesn't contend with other cores, and `total` sums the
+/// cores' counts.  There's a count for each of the arch's `param::MAX_CORES`
+/// cores.  Without the `counters` feature, counters are empty and counting
+/// does nothing, so they compile to nothing.
+#[cfg(feature = "counters")]
+use crate::percpu::PerCpu;
+#[cfg(feature = "counters")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A core's count, in a cache line of its own.
+#[cfg(feature = "counters")]
+#[repr(align(64))]
+struct CoreCount(AtomicU64);
+
+/// An event counter, declared with `counter!`, counting separately on each
+/// of `CORES` cores.
+pub struct Counter<const CORES: usize> {
+    #[cfg(feature = "counters")]
+    name: &'static str,
+    #[cfg(feature = "counters")]
+    counts: PerCpu<CoreCount, CORES>,
+}
+
+impl<const CORES: usize> Counter<CORES> {
+    #[allow(unused_variables)]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "counters")]
+            name,
+            #[cfg(feature = "counters")]
+            counts: PerCpu::new([const { CoreCount(AtomicU64::new(0)) }; CORES]),
+        }
+    }
+
+    #[cfg(feature = "counters")]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    #[allow(unused_variables)]
+    pub fn add(&self, n: u64) {
+        #[cfg(feature = "counters")]
+        self.counts.get().0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Add the nanoseconds since `stopwatch` was started.
+    #[inline]
+    #[allow(unused_variables)]
+    pub fn add_elapsed(&self, stopwatch: &Stopwatch) {
+        #[cfg(feature = "counters")]
+        self.add(crate::time::now().saturating_sub(stopwatch.start));
+    }
+
+    /// Add the nanoseconds since `stopwatch` was started, and restart it,
+    /// for timing consecutive phases.
+    #[inline]
+    pub fn add_lap(&self, stopwatch: &mut Stopwatch) {
+        self.add_elapsed(stopwatch);
+        *stopwatch = Stopwatch::start();
+    }
+
+    /// Return the sum of all the cores' counts.
+    #[cfg(feature = "counters")]
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|count| count.0.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Without the `counters` feature, nothing is counted.
+    #[cfg(not(feature = "counters"))]
+    pub fn total(&self) -> u64 {
+        0
+    }
+}
+
+/// The time something started, for `Counter::add_elapsed`, from the
+/// monotonic clock.
+pub struct Stopwatch {
+    #[cfg(feature = "counters")]
+    start: u64,
+}
+
+impl Stopwatch {
+    #[inline]
+    pub fn start() -> Self {
+        Self {
+            #[cfg(feature = "counters")]
+            start: crate::time::now(),
+        }
+    }
+}
+
+/// Declare a counter, registered for `report`, with a count for each of the
+/// declaring arch crate's `param::MAX_CORES` cores, e.g.
+///
+/// ```ignore
+/// port::counter!(static PAGES_ALLOCATED = "pagealloc.alloc");
+///
+/// PAGES_ALLOCATED.inc();
+/// ```
+#[cfg(feature = "counters")]
+// crate, not $crate, as the cores are those of the arch declaring the counter
+#[allow(clippy::crate_in_macro_def)]
+#[macro_export]
+macro_rules! counter {
+    ($vis:vis static $name:ident = $label:expr) => {
+        #[unsafe(link_section = ".counters")]
+        #[used]
+        $vis static $name: $crate::counters::Counter<{ crate::param::MAX_CORES }> =
+            $crate::counters::Counter::new($label);
+    };
+}
+
+#[cfg(not(feature = "counters"))]
+// crate, not $crate, as the cores are those of the arch declaring the counter
+#[allow(clippy::crate_in_macro_def)]
+#[macro_export]
+macro_rules! counter {
+    ($vis:vis static $name:ident = $label:expr) => {
+        $vis static $name: $crate::counters::Counter<{ crate::param::MAX_CORES }> =
+            $crate::counters::Counter::new($label);
+    };
+}
+
+/// The counters declared with `counter!`, from the `.counters` section, which
+/// all count on `CORES` cores.
+#[cfg(all(feature = "counters", not(test)))]
+pub fn registered<const CORES: usize>() -> &'static [Counter<CORES>] {
+    // These map to definitions in kernel.ld
+    unsafe extern "C" {
+        static counters: [u64; 0];
+        static ecounters: [u64; 0];
+    }
+    // Safety: the linker puts the Counter statics made by counter! between
+    // counters and ecounters, and they're all the same size and alignment.
+    unsafe {
+        let start = counters.as_ptr().cast::<Counter<CORES>>();
+        let len = ecounters.as_ptr().cast::<Counter<CORES>>().offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Print the name and total of each of `counters` that isn't 0.
+#[cfg(feature = "counters")]
+fn report_counters<const CORES: usize>(
+    counters: &[Counter<CORES>],
+    mut print: impl FnMut(&str, u64),
+) {
+    for counter in counters {
+        let total = counter.total();
+        if total != 0 {
+            print(counter.name(), total);
+        }
+    }
+}
+
+/// Print the counters that aren't 0, which count on `CORES` cores, i.e. the
+/// arch's `param::MAX_CORES`.  Does nothing without the `counters` feature.
+pub fn report<const CORES: usize>() {
+    #[cfg(all(feature = "counters", not(test)))]
+    {
+        crate::println!("Counters:");
+        report_counters(registered::<CORES>(), |name, total| {
+            crate::println!("  {name:<24} {total}")
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Host tests give each thread its own core id, so need more cores.
+    const CORES: usize = 1024;
+
+    #[test]
+    fn compiled_out_without_feature() {
+        assert_eq!(size_of::<Counter<CORES>>() == 0, cfg!(not(feature = "counters")));
+        assert_eq!(size_of::<Stopwatch>() == 0, cfg!(not(feature = "counters")));
+        let counter = Counter::<CORES>::new("test");
+        counter.inc();
+        assert_eq!(counter.total(), if cfg!(feature = "counters") { 1 } else { 0 });
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    fn counts_per_core() {
+        static COUNTER: Counter<CORES> = Counter::new("test.events");
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    COUNTER.inc();
+                    COUNTER.add(2);
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(COUNTER.total(), 12);
+        assert!(COUNTER.counts.iter().all(|count| count.0.load(Ordering::Relaxed) <= 3));
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    fn reports_non_zero() {
+        let counters: [Counter<CORES>; 3] =
+            [Counter::new("a"), Counter::new("b"), Counter::new("c")];
+        counters[0].add(5);
+        counters[2].inc();
+        let mut reported = Vec::new();
+        report_counters(&counters, |name, total| reported.push((name.to_string(), total)));
+        assert_eq!(reported, [("a".to_string(), 5), ("c".to_string(), 1)]);
+    }
+}
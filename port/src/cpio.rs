@@ -0,0 +1,225 @@
+/// cpio reads archives in the cpio "newc" format, as made by
+/// `cpio -o -H newc`, which is what the initrd holds.  Each entry is a
+/// header, the name, then the data, with the name and data each padded to a
+/// multiple of 4 bytes from the start of the archive:
+///
+/// ```text
+/// magic:     b"070701" or b"070702" (the latter has checksums, not checked)
+/// fields:    13 x 8 ASCII hex digits: ino, mode, uid, gid, nlink, mtime,
+///            filesize, devmajor, devminor, rdevmajor, rdevminor, namesize,
+///            check
+/// name:      namesize bytes, including a NUL terminator
+/// data:      filesize bytes
+/// ```
+///
+/// The archive ends with an entry named "TRAILER!!!".
+use core::fmt;
+
+const MAGIC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+// Index of the header fields used, after the magic
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CpioError {
+    InvalidMagic,
+    InvalidHeader,
+    InvalidName,
+    Truncated, // Includes a missing trailer
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::InvalidMagic => "invalid magic",
+            Self::InvalidHeader => "invalid header",
+            Self::InvalidName => "invalid name",
+            Self::Truncated => "truncated",
+        };
+        f.write_str(msg)
+    }
+}
+
+type Result<T> = core::result::Result<T, CpioError>;
+
+/// An entry in an archive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CpioEntry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl CpioEntry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+}
+
+/// Iterates over the entries in an archive, up to the trailer.  An error
+/// ends the iteration.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// Return an iterator over the entries in `archive`.
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries { archive, offset: 0, done: false }
+}
+
+/// Check `archive` is a well formed archive, ending with a trailer, and
+/// return the number of entries in it.
+pub fn validate(archive: &[u8]) -> Result<usize> {
+    let mut num_entries = 0;
+    for entry in entries(archive) {
+        entry?;
+        num_entries += 1;
+    }
+    Ok(num_entries)
+}
+
+fn align4(n: usize) -> usize {
+    n.next_multiple_of(4)
+}
+
+fn parse_hex(field: &[u8]) -> Result<u32> {
+    let s = core::str::from_utf8(field).map_err(|_| CpioError::InvalidHeader)?;
+    u32::from_str_radix(s, 16).map_err(|_| CpioError::InvalidHeader)
+}
+
+impl<'a> Entries<'a> {
+    fn next_entry(&mut self) -> Result<Option<CpioEntry<'a>>> {
+        let start = self.offset;
+        let header = self.archive.get(start..start + HEADER_LEN).ok_or(CpioError::Truncated)?;
+        let magic = &header[..MAGIC.len()];
+        if magic != MAGIC && magic != MAGIC_CRC {
+            return Err(CpioError::InvalidMagic);
+        }
+        let field = |i: usize| {
+            let at = MAGIC.len() + i * 8;
+            parse_hex(&header[at..at + 8])
+        };
+        let mode = field(FIELD_MODE)?;
+        let filesize = field(FIELD_FILESIZE)? as usize;
+        let namesize = field(FIELD_NAMESIZE)? as usize;
+
+        let name_start = start + HEADER_LEN;
+        let name =
+            self.archive.get(name_start..name_start + namesize).ok_or(CpioError::Truncated)?;
+        // The name is NUL terminated, and namesize includes the NUL
+        let name = match name.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| CpioError::InvalidName)?,
+            _ => return Err(CpioError::InvalidName),
+        };
+
+        let data_start = align4(name_start + namesize);
+        let data =
+            self.archive.get(data_start..data_start + filesize).ok_or(CpioError::Truncated)?;
+        self.offset = align4(data_start + filesize);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(CpioEntry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<CpioEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Append an entry to `archive`, as `cpio -H newc` would.
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08X}").as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn test_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "bin", 0o040755, &[]);
+        push_entry(&mut archive, "bin/hello", 0o100644, b"hello\n");
+        push_entry(&mut archive, "a", 0o100600, b"abcd");
+        push_entry(&mut archive, TRAILER, 0, &[]);
+        // Archives are usually padded to a block size
+        archive.resize(512, 0);
+        archive
+    }
+
+    #[test]
+    fn reads_entries() {
+        let archive = test_archive();
+        let entries: Vec<_> = entries(&archive).collect::<Result<_>>().unwrap();
+        assert_eq!(
+            entries,
+            [
+                CpioEntry { name: "bin", mode: 0o040755, data: &[] },
+                CpioEntry { name: "bin/hello", mode: 0o100644, data: b"hello\n" },
+                CpioEntry { name: "a", mode: 0o100600, data: b"abcd" },
+            ]
+        );
+        assert!(entries[0].is_dir() && !entries[0].is_file());
+        assert!(entries[1].is_file() && !entries[1].is_dir());
+        assert_eq!(validate(&archive), Ok(3));
+    }
+
+    #[test]
+    fn rejects_bad_archives() {
+        let archive = test_archive();
+        assert_eq!(validate(&[]), Err(CpioError::Truncated));
+        assert_eq!(validate(&archive[..200]), Err(CpioError::Truncated));
+
+        let mut bad_magic = archive.clone();
+        bad_magic[5] = b'9';
+        assert_eq!(validate(&bad_magic), Err(CpioError::InvalidMagic));
+
+        let mut bad_field = archive.clone();
+        bad_field[14] = b'x'; // In the mode
+        assert_eq!(validate(&bad_field), Err(CpioError::InvalidHeader));
+
+        // The entries are read, then the missing trailer is an error
+        let mut no_trailer = Vec::new();
+        push_entry(&mut no_trailer, "a", 0o100600, b"abcd");
+        assert_eq!(validate(&no_trailer), Err(CpioError::Truncated));
+        assert_eq!(entries(&no_trailer).count(), 2);
+    }
+}
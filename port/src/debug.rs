@@ -0,0 +1,125 @@
+/// debug has helpers for inspecting memory while debugging the kernel, such
+/// as `hexdump`, which prints memory in the classic 16 bytes per line format:
+///
+/// ```text
+/// 0000000000001000  d0 0d fe ed 00 00 05 2c  00 00 00 38 00 00 04 8c  |.......,...8....|
+/// ```
+///
+/// Nothing here allocates, so it can be used before the heap is set up, or
+/// when it's broken.
+use core::fmt::{self, Write};
+
+use crate::mem::VirtRange;
+use crate::print;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Formats bytes as a hex dump, labelled with addresses starting from
+/// `base`, ending with a line holding the address after the last byte.  If
+/// `skip_repeats` is set, runs of lines identical to the line before are
+/// printed as a single '*'.
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base: usize,
+    skip_repeats: bool,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(bytes: &'a [u8], base: usize, skip_repeats: bool) -> Self {
+        Self { bytes, base, skip_repeats }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev: Option<&[u8]> = None;
+        let mut skipping = false;
+        for (i, line) in self.bytes.chunks(BYTES_PER_LINE).enumerate() {
+            if self.skip_repeats && prev == Some(line) {
+                if !skipping {
+                    writeln!(f, "*")?;
+                    skipping = true;
+                }
+                continue;
+            }
+            prev = Some(line);
+            skipping = false;
+
+            write!(f, "{:016x} ", self.base + i * BYTES_PER_LINE)?;
+            for j in 0..BYTES_PER_LINE {
+                if j == BYTES_PER_LINE / 2 {
+                    f.write_char(' ')?;
+                }
+                match line.get(j) {
+                    Some(b) => write!(f, " {b:02x}")?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str("  |")?;
+            for &b in line {
+                let printable = b.is_ascii_graphic() || b == b' ';
+                f.write_char(if printable { b as char } else { '.' })?;
+            }
+            writeln!(f, "|")?;
+        }
+        writeln!(f, "{:016x}", self.base + self.bytes.len())
+    }
+}
+
+/// Print `bytes` to the console as a hex dump, labelled with their
+/// addresses.
+pub fn hexdump_bytes(bytes: &[u8], skip_repeats: bool) {
+    print!("{}", HexDump::new(bytes, bytes.as_ptr().addr(), skip_repeats));
+}
+
+/// Print the memory in `range` to the console as a hex dump.
+///
+/// # Safety
+///
+/// All of `range` must be mapped and readable, and not being written while
+/// it's dumped.
+pub unsafe fn hexdump(range: &VirtRange, skip_repeats: bool) {
+    let start = range.start().addr() as *const u8;
+    let bytes = unsafe { core::slice::from_raw_parts(start, range.size()) };
+    print!("{}", HexDump::new(bytes, range.start().addr(), skip_repeats));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_padded_and_grouped() {
+        let dump = HexDump::new(b"Hello, world!\n\x00\x7fabc", 0x1000, false).to_string();
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0000000000001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 7f  |Hello, world!...|",
+                "0000000000001010  61 62 63                                          |abc|",
+                "0000000000001013",
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_lines_are_skipped() {
+        let mut bytes = [0u8; 80];
+        bytes[70] = 0xff;
+        let dump = HexDump::new(&bytes, 0, true).to_string();
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("0000000000000000  00 00"));
+        assert_eq!(lines[1], "*");
+        assert!(lines[2].starts_with("0000000000000040  00 00 00 00 00 00 ff 00"));
+        assert_eq!(lines[3], "0000000000000050");
+
+        // Without skipping, every line is printed
+        assert_eq!(HexDump::new(&bytes, 0, false).to_string().lines().count(), 6);
+    }
+
+    #[test]
+    fn empty_dump_is_just_the_address() {
+        assert_eq!(HexDump::new(&[], 0x20, true).to_string(), "0000000000000020\n");
+    }
+}
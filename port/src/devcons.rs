@@ -1,26 +1,171 @@
 use crate::mcslock::{Lock, LockNode};
 use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 const fn ctrl(b: u8) -> u8 {
     b - b'@'
 }
 
-#[allow(dead_code)]
 const BACKSPACE: u8 = ctrl(b'H');
-#[allow(dead_code)]
 const DELETE: u8 = 0x7F;
 #[allow(dead_code)]
 const CTLD: u8 = ctrl(b'D');
 #[allow(dead_code)]
 const CTLP: u8 = ctrl(b'P');
-#[allow(dead_code)]
 const CTLU: u8 = ctrl(b'U');
 
 pub trait Uart {
     fn putb(&self, b: u8);
+
+    /// Return the next received byte, if there is one, without waiting.
+    /// Bytes received with errors should be discarded and counted with
+    /// `count_rx_error`.
+    fn getb(&self) -> Option<u8> {
+        None
+    }
 }
 
-static CONS: Lock<Option<&'static mut dyn Uart>> = Lock::new("cons", None);
+/// Number of received bytes discarded because of errors, e.g. overruns.
+static RX_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Note that a received byte was discarded because of an error.
+pub fn count_rx_error() {
+    RX_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of received bytes discarded because of errors so far.
+pub fn rx_errors() -> usize {
+    RX_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Ring buffer holding console output until it's written to the uart.  When
+/// the buffer is full and there's no uart to spill to, the oldest bytes are
+/// dropped, and counted so the loss can be reported when next flushed.
+pub struct ConsBuffer {
+    buf: &'static mut [u8],
+    start: usize,   // Index of the oldest byte
+    len: usize,     // Number of bytes held
+    dropped: usize, // Bytes dropped since the last flush
+}
+
+impl ConsBuffer {
+    pub fn new(buf: &'static mut [u8]) -> Self {
+        Self { buf, start: 0, len: 0, dropped: 0 }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.buf.len()
+    }
+
+    /// Append a byte, dropping the oldest if the buffer is full.
+    fn push(&mut self, b: u8) {
+        let cap = self.buf.len();
+        if cap == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.is_full() {
+            self.start = (self.start + 1) % cap;
+            self.len -= 1;
+            self.dropped += 1;
+        }
+        self.buf[(self.start + self.len) % cap] = b;
+        self.len += 1;
+    }
+
+    /// Write the buffered bytes to the uart, preceded by a note of how many
+    /// were dropped since the last flush, if any, and empty the buffer.
+    fn drain(&mut self, uart: &dyn Uart) {
+        if self.dropped > 0 {
+            use fmt::Write;
+            let _ = writeln!(UartWriter(uart), "[devcons: {} bytes dropped]", self.dropped);
+        }
+        let cap = self.buf.len();
+        for i in 0..self.len {
+            putb(uart, self.buf[(self.start + i) % cap]);
+        }
+        self.start = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+}
+
+/// Size of the buffer keeping output printed before the console has a uart.
+pub const EARLY_LOG_SIZE: usize = 4096;
+
+/// Output printed before the console has a uart, and before any buffer is set
+/// with `buffer_output`, kept so it can be replayed when the uart is set.  It's
+/// a fixed size array, so it works before there's an allocator.  `written`
+/// counts every byte pushed, and the array is used as a ring, so once it's
+/// full the oldest bytes are overwritten, and the number lost is known.
+struct EarlyLog<const N: usize> {
+    buf: [u8; N],
+    written: usize,
+}
+
+impl<const N: usize> EarlyLog<N> {
+    const fn new() -> Self {
+        Self { buf: [0; N], written: 0 }
+    }
+
+    fn push(&mut self, b: u8) {
+        self.buf[self.written % N] = b;
+        self.written += 1;
+    }
+
+    /// Write the bytes held, oldest first, to the uart, preceded by a note
+    /// of how many were overwritten, if any, and empty the log.
+    fn drain(&mut self, uart: &dyn Uart) {
+        let lost = self.written.saturating_sub(N);
+        if lost > 0 {
+            use fmt::Write;
+            let _ = writeln!(UartWriter(uart), "[devcons: {lost} bytes of early output lost]");
+        }
+        for i in lost..self.written {
+            putb(uart, self.buf[i % N]);
+        }
+        self.written = 0;
+    }
+}
+
+/// Writes formatted output straight to a uart.
+struct UartWriter<'a>(&'a dyn Uart);
+
+impl fmt::Write for UartWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            putb(self.0, b);
+        }
+        Ok(())
+    }
+}
+
+struct ConsState {
+    uart: Option<&'static dyn Uart>,
+    buffer: Option<ConsBuffer>, // Output is buffered until flushed, if set
+    early: EarlyLog<EARLY_LOG_SIZE>,
+}
+
+impl ConsState {
+    /// Write output held from before there was a uart, then anything
+    /// buffered since, to `uart`, so it comes out in the order printed.
+    fn drain(&mut self, uart: &dyn Uart) {
+        self.early.drain(uart);
+        if let Some(buffer) = &mut self.buffer {
+            buffer.drain(uart);
+        }
+    }
+}
+
+static CONS: Lock<ConsState> =
+    Lock::new("cons", ConsState { uart: None, buffer: None, early: EarlyLog::new() });
+
+/// Set once the console has a uart.
+static CONSOLE_READY: AtomicBool = AtomicBool::new(false);
+
+/// Function writing a byte for `early_print`, or null if there isn't one.
+static EARLY_PUTB: AtomicPtr<()> = AtomicPtr::new(null_mut());
 
 /// Console is what should be used in almost all cases, as it ensures threadsafe
 /// use of the console.
@@ -28,13 +173,20 @@ pub struct Console;
 
 impl Console {
     /// Create a locking console.  Assumes at this point we can use atomics.
+    /// Anything printed before there was a uart, and anything buffered, is
+    /// written to the new uart, before any later output.  Calling this
+    /// again switches the console to another uart, e.g. once the uart's
+    /// registers are mapped elsewhere.
     pub fn new<F>(uart_fn: F) -> Self
     where
-        F: FnOnce() -> &'static mut dyn Uart,
+        F: FnOnce() -> &'static dyn Uart,
     {
         let node = LockNode::new();
-        let mut cons = CONS.lock(&node);
-        *cons = Some(uart_fn());
+        let mut cons = CONS.lock_irqsave(&node);
+        let cons = &mut *cons;
+        let uart = *cons.uart.insert(uart_fn());
+        cons.drain(uart);
+        CONSOLE_READY.store(true, Ordering::Release);
         Self
     }
 
@@ -42,10 +194,23 @@ impl Console {
         // XXX: Just for testing.
 
         let node = LockNode::new();
-        let mut uart_guard = CONS.lock(&node);
-        let uart = uart_guard.as_deref_mut().unwrap();
+        let mut cons = CONS.lock_irqsave(&node);
+        let cons = &mut *cons;
+        let Some(buffer) = &mut cons.buffer else {
+            match cons.uart {
+                Some(uart) => s.bytes().for_each(|b| putb(uart, b)),
+                None => s.bytes().for_each(|b| cons.early.push(b)),
+            }
+            return;
+        };
         for b in s.bytes() {
-            putb(uart, b);
+            // Spill to the uart once the buffer is full, if there is one yet
+            if buffer.is_full() {
+                if let Some(uart) = cons.uart {
+                    buffer.drain(uart);
+                }
+            }
+            buffer.push(b);
         }
     }
 }
@@ -57,6 +222,113 @@ impl fmt::Write for Console {
     }
 }
 
+/// Buffer console output in `buf` until it fills, or `flush` is called,
+/// rather than waiting for the uart on every byte.  Output from before the
+/// uart is set up is kept too, as far as it fits.
+pub fn buffer_output(buf: &'static mut [u8]) {
+    let node = LockNode::new();
+    let mut cons = CONS.lock_irqsave(&node);
+    let cons = &mut *cons;
+    if let (Some(buffer), Some(uart)) = (&mut cons.buffer, cons.uart) {
+        buffer.drain(uart);
+    }
+    cons.buffer = Some(ConsBuffer::new(buf));
+}
+
+/// Write any buffered console output to the uart.  Does nothing if there's
+/// no uart yet.
+pub fn flush() {
+    let node = LockNode::new();
+    let mut cons = CONS.lock_irqsave(&node);
+    let cons = &mut *cons;
+    if let (Some(buffer), Some(uart)) = (&mut cons.buffer, cons.uart) {
+        buffer.drain(uart);
+    }
+}
+
+/// Flush the console and stop buffering, so that later output goes straight
+/// to the uart, e.g. when panicking, so nothing is lost.  Output from before
+/// there's a uart stays buffered.
+pub fn unbuffer() {
+    let node = LockNode::new();
+    let mut cons = CONS.lock_irqsave(&node);
+    let cons = &mut *cons;
+    if let Some(uart) = cons.uart {
+        if let Some(mut buffer) = cons.buffer.take() {
+            buffer.drain(uart);
+        }
+    }
+}
+
+/// Return the next byte received by the console uart, if there is one,
+/// without waiting.
+pub fn getb() -> Option<u8> {
+    let node = LockNode::new();
+    let cons = CONS.lock_irqsave(&node);
+    cons.uart.and_then(|uart| uart.getb())
+}
+
+/// Read a line from the console into `buf`, echoing it as it's typed, and
+/// return it without the terminating CR or LF.  Blocks until the line is
+/// complete.  Backspace and delete erase the last byte, and ctl-U the whole
+/// line.  Only printable ASCII is kept, and input beyond the end of `buf` is
+/// ignored.
+pub fn read_line(buf: &mut [u8]) -> &str {
+    // Make sure any prompt is visible before waiting
+    flush();
+    let len = edit_line(
+        buf,
+        || loop {
+            if let Some(b) = getb() {
+                break b;
+            }
+            core::hint::spin_loop();
+        },
+        |b| {
+            // Echo straight to the uart, so it isn't held back by buffering
+            let node = LockNode::new();
+            let cons = CONS.lock_irqsave(&node);
+            if let Some(uart) = cons.uart {
+                putb(uart, b);
+            }
+        },
+    );
+    // Only ASCII is kept, so this can't fail
+    core::str::from_utf8(&buf[..len]).unwrap_or_default()
+}
+
+/// Fill `buf` with bytes from `next` until CR or LF, handling line editing
+/// and echoing with `echo`.  Returns the length of the line.
+fn edit_line(buf: &mut [u8], mut next: impl FnMut() -> u8, mut echo: impl FnMut(u8)) -> usize {
+    let mut len = 0;
+    loop {
+        match next() {
+            b'\r' | b'\n' => {
+                echo(b'\n');
+                return len;
+            }
+            BACKSPACE | DELETE => {
+                if len > 0 {
+                    len -= 1;
+                    echo(BACKSPACE);
+                }
+            }
+            CTLU => {
+                while len > 0 {
+                    len -= 1;
+                    echo(BACKSPACE);
+                }
+            }
+            b @ b' '..=b'~' if len < buf.len() => {
+                buf[len] = b;
+                len += 1;
+                echo(b);
+            }
+            _ => {}
+        }
+    }
+}
+
 /// PanicConsole should only be used in the very early stages of booting, when
 /// we're not sure we can use locks.  This can be particularly useful for
 /// implementing an early panic handler.
@@ -79,7 +351,7 @@ where
         // XXX: Just for testing.
 
         for b in s.bytes() {
-            putb(&mut self.uart, b);
+            putb(&self.uart, b);
         }
     }
 }
@@ -101,6 +373,83 @@ pub fn print(args: fmt::Arguments) {
     cons.write_fmt(args).unwrap();
 }
 
+/// Return true once the console has a uart, so that printing works.  Until
+/// then, use `early_print`.
+pub fn console_ready() -> bool {
+    CONSOLE_READY.load(Ordering::Acquire)
+}
+
+/// Return true if the current core holds the console lock, e.g. when
+/// panicking from inside a print, so printing would deadlock.  Always false
+/// in release builds, which don't track lock holders.
+pub fn locked_here() -> bool {
+    CONS.held_here()
+}
+
+/// Set the function `early_print` uses to write a byte.  It must work with no
+/// setup beyond what the boot code does, e.g. writing a uart's data register
+/// through the boot mapping, or calling firmware.
+pub fn set_early_putb(putb: fn(u8)) {
+    EARLY_PUTB.store(putb as *mut (), Ordering::Release);
+}
+
+/// Uart writing with the function set by `set_early_putb`.
+struct EarlyUart(fn(u8));
+
+impl Uart for EarlyUart {
+    fn putb(&self, b: u8) {
+        (self.0)(b);
+    }
+}
+
+/// Print with the function set by `set_early_putb`, for output before the
+/// console is set up, e.g. from early asserts, or the panic handler.  This is
+/// best effort: nothing is locked, so it isn't interrupt safe, and output from
+/// several cores may be interleaved.  Does nothing if no function is set.
+pub fn early_print(args: fmt::Arguments) {
+    let putb = EARLY_PUTB.load(Ordering::Acquire);
+    if putb.is_null() {
+        return;
+    }
+    // Safety: only set_early_putb stores to EARLY_PUTB, and it stores a
+    // fn(u8)
+    let putb = unsafe { core::mem::transmute::<*mut (), fn(u8)>(putb) };
+    use fmt::Write;
+    let _ = PanicConsole::new(EarlyUart(putb)).write_fmt(args);
+}
+
+/// Write the output printed before the console had a uart with the function
+/// set by `set_early_putb`, for the panic handler when the console never came
+/// up, so it isn't lost.  Does nothing if the console is locked, or there's no
+/// function set.
+pub fn dump_early_output() {
+    let putb = EARLY_PUTB.load(Ordering::Acquire);
+    if putb.is_null() {
+        return;
+    }
+    // Safety: as in early_print
+    let putb = unsafe { core::mem::transmute::<*mut (), fn(u8)>(putb) };
+    let node = LockNode::new();
+    if let Some(mut cons) = CONS.try_lock(&node) {
+        if cons.uart.is_none() {
+            cons.drain(&EarlyUart(putb));
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! early_print {
+    ($($args:tt)*) => {{
+        $crate::devcons::early_print(format_args!($($args)*))
+    }};
+}
+
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -114,7 +463,7 @@ macro_rules! print {
     }};
 }
 
-fn putb(uart: &mut dyn Uart, b: u8) {
+fn putb(uart: &dyn Uart, b: u8) {
     if b == b'\n' {
         uart.putb(b'\r');
     } else if b == BACKSPACE {
@@ -123,3 +472,128 @@ fn putb(uart: &mut dyn Uart, b: u8) {
     }
     uart.putb(b);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    /// Uart that records what's written to it.
+    struct FakeUart(RefCell<Vec<u8>>);
+
+    impl Uart for FakeUart {
+        fn putb(&self, b: u8) {
+            self.0.borrow_mut().push(b);
+        }
+    }
+
+    /// Edit a line from `input`, returning the line and what was echoed.
+    fn edit(buf: &mut [u8], input: &[u8]) -> (String, Vec<u8>) {
+        let mut input = input.iter().copied();
+        let mut echoed = Vec::new();
+        let len = edit_line(buf, || input.next().unwrap(), |b| echoed.push(b));
+        (String::from_utf8(buf[..len].to_vec()).unwrap(), echoed)
+    }
+
+    fn new_buffer(size: usize) -> ConsBuffer {
+        ConsBuffer::new(Box::leak(vec![0; size].into_boxed_slice()))
+    }
+
+    #[test]
+    fn buffer_drains_in_order() {
+        let uart = FakeUart(RefCell::new(Vec::new()));
+        let mut buffer = new_buffer(8);
+        b"abc\n".iter().for_each(|&b| buffer.push(b));
+        buffer.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"abc\r\n");
+
+        // Wrapping around the end of the buffer
+        b"defghij".iter().for_each(|&b| buffer.push(b));
+        uart.0.borrow_mut().clear();
+        buffer.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"defghij");
+        assert_eq!(buffer.len, 0);
+    }
+
+    #[test]
+    fn buffer_drops_oldest_and_reports() {
+        let uart = FakeUart(RefCell::new(Vec::new()));
+        let mut buffer = new_buffer(4);
+        b"abcdef".iter().for_each(|&b| buffer.push(b));
+        assert!(buffer.is_full());
+        buffer.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"[devcons: 2 bytes dropped]\r\ncdef");
+
+        // The count is reset by the flush
+        uart.0.borrow_mut().clear();
+        buffer.push(b'g');
+        buffer.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"g");
+    }
+
+    #[test]
+    fn early_log_replays_in_order() {
+        let uart = FakeUart(RefCell::new(Vec::new()));
+        let mut early = EarlyLog::<8>::new();
+        b"early\n".iter().for_each(|&b| early.push(b));
+        early.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"early\r\n");
+
+        // Once full, the oldest bytes are overwritten, and counted
+        uart.0.borrow_mut().clear();
+        b"0123456789".iter().for_each(|&b| early.push(b));
+        early.drain(&uart);
+        let replayed = b"[devcons: 2 bytes of early output lost]\r\n23456789";
+        assert_eq!(uart.0.borrow().as_slice(), replayed);
+        uart.0.borrow_mut().clear();
+        early.drain(&uart);
+        assert!(uart.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn early_output_comes_before_buffered() {
+        // Output from before the buffer was set, then from before the uart
+        let uart = FakeUart(RefCell::new(Vec::new()));
+        let mut cons = ConsState { uart: None, buffer: None, early: EarlyLog::new() };
+        b"first ".iter().for_each(|&b| cons.early.push(b));
+        cons.buffer = Some(new_buffer(16));
+        b"second".iter().for_each(|&b| cons.buffer.as_mut().unwrap().push(b));
+        cons.drain(&uart);
+        assert_eq!(uart.0.borrow().as_slice(), b"first second");
+    }
+
+    #[test]
+    fn edit_line_handles_editing() {
+        let mut buf = [0u8; 8];
+        assert_eq!(edit(&mut buf, b"help\r"), ("help".into(), b"help\n".to_vec()));
+        assert_eq!(edit(&mut buf, b"\n"), ("".into(), b"\n".to_vec()));
+
+        // Backspace and delete erase, but not beyond the start of the line
+        let (line, echoed) = edit(&mut buf, b"\x08ab\x08c\x7fd\n");
+        assert_eq!(line, "ad");
+        assert_eq!(echoed, b"ab\x08c\x08d\n");
+
+        // Ctl-U erases the line, and control bytes are ignored
+        assert_eq!(edit(&mut buf, b"ab\x15c\x1bd\r").0, "cd");
+
+        // Input beyond the buffer is ignored
+        let (line, echoed) = edit(&mut buf, b"0123456789\r");
+        assert_eq!(line, "01234567");
+        assert_eq!(echoed, b"01234567\n");
+    }
+
+    static EARLY_OUTPUT: std::sync::Mutex<Vec<u8>> = std::sync::Mutex::new(Vec::new());
+
+    fn record_early_putb(b: u8) {
+        EARLY_OUTPUT.lock().unwrap().push(b);
+    }
+
+    #[test]
+    fn early_print_uses_early_putb() {
+        // Nothing happens without a function to write with
+        early_println!("lost");
+        set_early_putb(record_early_putb);
+        early_println!("early {}", 1);
+        assert_eq!(EARLY_OUTPUT.lock().unwrap().as_slice(), b"early 1\r\n");
+    }
+}
@@ -0,0 +1,216 @@
+/// digest checks the kernel image wasn't corrupted as it was mapped, e.g. by
+/// remapping text without write permission, or splitting blocks, by hashing
+/// its read-only sections once booted, and comparing with digests of them
+/// taken when it was built.  The hash is 64 bit FNV-1a: not cryptographic,
+/// but cheap, and it catches stray writes.
+///
+/// With the `digests` feature, space for a table of digests is reserved in
+/// the `.kdigests` section of the kernel, and xtask fills it in after
+/// linking, with a digest of each read-only section.  Sections modified at
+/// runtime, such as data and bss, aren't included.  Without the feature, or
+/// if the table wasn't filled in, `kernel_table` returns None.
+///
+/// The table is little endian:
+///
+/// ```text
+/// magic:   b"KDIG"
+/// count:   u32
+/// entries: [Entry; count]
+///
+/// Entry:
+/// name:    [u8; 16]   UTF-8 section name, padded with zeros
+/// start:   u64        Linked virtual address
+/// len:     u64        Size in bytes
+/// digest:  u64        FNV-1a of the section's contents
+/// ```
+pub const MAGIC: [u8; 4] = *b"KDIG";
+
+/// Space reserved for the table in the kernel with the `digests` feature.
+pub const TABLE_SIZE: usize = 512;
+
+/// Size of a section name in the table.
+pub const NAME_LEN: usize = 16;
+
+/// Size of each entry in the table.
+pub const ENTRY_SIZE: usize = NAME_LEN + 3 * size_of::<u64>();
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A 64 bit FNV-1a hash, which can be fed bytes in pieces.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub const fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+
+    /// Add `bytes` to the hash.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Return the hash of the bytes written so far.
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return the 64 bit FNV-1a hash of `bytes`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = Fnv1a::new();
+    hash.write(bytes);
+    hash.finish()
+}
+
+#[cfg(feature = "digests")]
+#[repr(C, align(8))]
+struct Reserved([u8; TABLE_SIZE]);
+
+/// Filled in by xtask after linking.
+#[cfg(feature = "digests")]
+#[unsafe(link_section = ".kdigests")]
+#[used]
+static KDIGESTS: Reserved = Reserved([0; TABLE_SIZE]);
+
+/// The expected digest of a section of the kernel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SectionDigest<'a> {
+    pub name: &'a str,
+    pub start: u64,
+    pub len: u64,
+    pub digest: u64,
+}
+
+/// A table of digests, in the format above.
+pub struct DigestTable<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> DigestTable<'a> {
+    /// Returns the table in `bytes`, or None if it's empty or malformed.
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let (magic, rest) = bytes.split_at_checked(4)?;
+        if magic != MAGIC {
+            return None;
+        }
+        let (count, rest) = rest.split_at_checked(4)?;
+        let count = u32::from_le_bytes(count.try_into().ok()?) as usize;
+        let (entries, _) = rest.split_at_checked(count.checked_mul(ENTRY_SIZE)?)?;
+        let table = Self { entries };
+        (table.iter().count() == count).then_some(table)
+    }
+
+    /// Number of sections.
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the sections' digests.  Entries with malformed names
+    /// are skipped, but `new` rejects tables with any.
+    pub fn iter(&self) -> impl Iterator<Item = SectionDigest<'a>> + use<'a> {
+        self.entries.chunks_exact(ENTRY_SIZE).filter_map(|entry| {
+            let (name, fields) = entry.split_at(NAME_LEN);
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+            let name = core::str::from_utf8(&name[..name_len]).ok()?;
+            let field = |i: usize| u64::from_le_bytes(fields[i * 8..i * 8 + 8].try_into().unwrap());
+            Some(SectionDigest { name, start: field(0), len: field(1), digest: field(2) })
+        })
+    }
+}
+
+/// Returns the kernel's table of digests, if it has one.
+#[cfg(feature = "digests")]
+pub fn kernel_table() -> Option<DigestTable<'static>> {
+    // The table is filled in after compiling, so hide its contents from the
+    // optimiser, which would otherwise see zeros.
+    DigestTable::new(core::hint::black_box(&KDIGESTS.0[..]))
+}
+
+#[cfg(not(feature = "digests"))]
+pub fn kernel_table() -> Option<DigestTable<'static>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a table of `sections`, as xtask would build it.
+    fn table(sections: &[(&str, u64, u64, u64)]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((sections.len() as u32).to_le_bytes());
+        for (name, start, len, digest) in sections {
+            let mut padded = [0u8; NAME_LEN];
+            padded[..name.len()].copy_from_slice(name.as_bytes());
+            bytes.extend(padded);
+            bytes.extend(start.to_le_bytes());
+            bytes.extend(len.to_le_bytes());
+            bytes.extend(digest.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn hashes_in_pieces() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let mut hash = Fnv1a::new();
+        for chunk in bytes.chunks(7) {
+            hash.write(chunk);
+        }
+        assert_eq!(hash.finish(), fnv1a(&bytes));
+        assert_ne!(fnv1a(&bytes[1..]), fnv1a(&bytes));
+    }
+
+    #[test]
+    fn parses_table() {
+        let mut bytes = table(&[
+            (".text", 0xffff_8000_0008_2000, 0x1_0000, 0x1234),
+            (".rodata", 0xffff_8000_0020_0000, 0x800, 0x5678),
+        ]);
+        bytes.resize(TABLE_SIZE, 0);
+        let table = DigestTable::new(&bytes).unwrap();
+        assert_eq!(table.len(), 2);
+        let digests: Vec<_> = table.iter().collect();
+        assert_eq!(
+            digests[1],
+            SectionDigest {
+                name: ".rodata",
+                start: 0xffff_8000_0020_0000,
+                len: 0x800,
+                digest: 0x5678
+            }
+        );
+        assert_eq!(digests[0].name, ".text");
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        assert!(DigestTable::new(&[0; TABLE_SIZE]).is_none());
+        let bytes = table(&[(".text", 0, 1, 2)]);
+        assert!(DigestTable::new(&bytes[..bytes.len() - 1]).is_none());
+        let mut bytes = table(&[(".text", 0, 1, 2)]);
+        bytes[8] = 0xff;
+        assert!(DigestTable::new(&bytes).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+/// fakemem is fake physical memory for host tests of the page allocators, and
+/// the wrappers around them that reach pages through a `PageMapper`, e.g. to
+/// zero or poison them.
+use crate::mem::{MemError, PhysAddr, PhysRange, PhysRangeSet};
+use crate::pagealloc::{CarvedPageAlloc, Metadata, PageAlloc, PageAllocSummary, PageMapper};
+
+/// Fake physical memory, as words of type `W` starting at physical address
+/// 0, where only the first `mapped_bytes` are mapped.
+pub struct FakeMemory<W = u64> {
+    pub words: Vec<W>,
+    pub mapped_bytes: usize,
+}
+
+impl<W: Copy> FakeMemory<W> {
+    /// `size_bytes` of memory, each word set to `fill`.
+    pub fn new(size_bytes: usize, mapped_bytes: usize, fill: W) -> Self {
+        Self { words: vec![fill; size_bytes / size_of::<W>()], mapped_bytes }
+    }
+
+    /// Return the words of the page at `pa`.
+    pub fn page(&self, pa: PhysAddr, page_size: usize) -> &[W] {
+        let start = pa.addr() as usize / size_of::<W>();
+        &self.words[start..start + page_size / size_of::<W>()]
+    }
+
+    /// Return a mapper for the memory, for as long as it isn't moved.
+    pub fn mapper(&mut self) -> *mut Self {
+        self
+    }
+}
+
+unsafe impl<W> PageMapper for *mut FakeMemory<W> {
+    fn page_ptr(&self, pa: PhysAddr, page_size: usize) -> Option<*mut u8> {
+        let mem = unsafe { &mut **self };
+        let pa = pa.addr() as usize;
+        if pa + page_size > mem.mapped_bytes {
+            return None;
+        }
+        Some(unsafe { mem.words.as_mut_ptr().add(pa / size_of::<W>()) } as *mut u8)
+    }
+}
+
+/// Maps every physical page to fresh host memory, which is leaked, so that
+/// allocators can carve their state out of memory that tests don't look at.
+pub struct ScratchMapper;
+
+unsafe impl PageMapper for ScratchMapper {
+    fn page_ptr(&self, _pa: PhysAddr, page_size: usize) -> Option<*mut u8> {
+        let words = vec![0u64; page_size.div_ceil(size_of::<u64>())];
+        Some(Box::leak(words.into_boxed_slice()).as_mut_ptr() as *mut u8)
+    }
+}
+
+/// Initialise `alloc`, which has 32 pages of 16 bytes, with memory from 0 to
+/// 512, less `reserved`.
+pub fn init_512(alloc: &mut impl PageAlloc, reserved: &[PhysRange]) -> PageAllocSummary {
+    let mut memory = PhysRangeSet::<4>::new();
+    memory.add(&PhysRange::with_end(0, 512)).unwrap();
+    alloc.init_from(&memory, reserved, &ScratchMapper).unwrap()
+}
+
+/// Initialise `alloc` from `memory`, less `reserved`, keeping its state in
+/// leaked host memory rather than carving it out of `memory`, so that every
+/// usable page of `memory` is free.
+pub fn init_outside<const N: usize>(
+    alloc: &mut impl CarvedPageAlloc,
+    memory: &PhysRangeSet<N>,
+    reserved: &[PhysRange],
+) -> Result<PageAllocSummary, MemError> {
+    let end = memory.iter().map(|r| r.end()).max().unwrap_or_default();
+    let ptr = ScratchMapper.page_ptr(PhysAddr::new(0), alloc.metadata_size(end)).unwrap();
+    // Safety: the memory is leaked, so outlives the allocator
+    unsafe { alloc.init_with_metadata(memory, reserved, Metadata { ptr, ..Metadata::empty() }) }
+}
@@ -1,6 +1,7 @@
 #![allow(clippy::too_long_first_doc_paragraph)]
 
-use core::{ffi::CStr, mem};
+use crate::mem::{PhysAddr, PhysRange};
+use core::{ffi::CStr, fmt, marker::PhantomData, mem};
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -167,6 +168,14 @@ impl<'a> DeviceTree<'a> {
         self.structs().get(prop.value_start..value_end).and_then(bytes_to_u32)
     }
 
+    /// Return the value of a string property, without the terminating nul.
+    pub fn property_value_as_str(&self, prop: &Property) -> Option<&str> {
+        let value_end = prop.value_start + prop.value_len;
+        let bytes = self.structs().get(prop.value_start..value_end)?;
+        let init_bytes = unsafe { bytes.assume_init_ref() };
+        CStr::from_bytes_until_nul(init_bytes).ok()?.to_str().ok()
+    }
+
     pub fn property_value_as_u32_iter(&self, prop: &Property) -> impl Iterator<Item = u32> + '_ {
         let mut value_i = prop.value_start;
         let value_end = prop.value_start + prop.value_len;
@@ -301,45 +310,55 @@ impl<'a> DeviceTree<'a> {
         &self,
         node: Node,
     ) -> impl Iterator<Item = TranslatedReg> + '_ {
-        let mut reg_iter = self.property_reg_iter(node);
-        let mut curr_reg = reg_iter.next();
-
-        // Work on each reg element in turn
-        core::iter::from_fn(move || {
-            if let Some(reg) = curr_reg {
-                curr_reg = reg_iter.next();
-
-                // Walk from child to parents, translating by ranges at each step
-                let mut translated_reg = reg;
-                let mut curr_parent = self.parent(&node);
-                while curr_parent.is_some() {
-                    if let Some(parent) = curr_parent {
-                        if parent.is_root() {
-                            return Some(TranslatedReg::Translated(translated_reg));
-                        }
-
-                        // Find a range containing the regblock
-                        let mut translated = false;
-                        for range in self.property_range_iter(parent) {
-                            if let Some(new_reg) = range.translate(translated_reg) {
-                                translated_reg = new_reg;
-                                translated = true;
-                                break;
-                            }
-                        }
-
-                        if !translated {
-                            return Some(TranslatedReg::Unreachable);
-                        }
+        self.property_reg_iter(node).map(move |reg| match self.translate_reg(node, reg) {
+            Some(reg) => TranslatedReg::Translated(reg),
+            None => TranslatedReg::Unreachable,
+        })
+    }
 
-                        curr_parent = self.parent(&parent);
-                    }
-                }
+    /// Translate `reg`, of `node`, by the ranges of each of its parents in
+    /// turn, to an address on the root bus.  Returns None if some parent's
+    /// ranges don't include it.
+    fn translate_reg(&self, node: Node, reg: RegBlock) -> Option<RegBlock> {
+        // Walk from child to parents, translating by ranges at each step
+        let mut translated_reg = reg;
+        let mut curr_parent = self.parent(&node);
+        while let Some(parent) = curr_parent {
+            if parent.is_root() {
+                break;
             }
-            None
+            // Find a range containing the regblock
+            translated_reg = self
+                .property_range_iter(parent)
+                .find_map(|range| range.translate(translated_reg))?;
+            curr_parent = self.parent(&parent);
+        }
+        Some(translated_reg)
+    }
+
+    /// Return the reg entries of `node`, as regions that know their node and
+    /// index, with their addresses on the node's parent bus.
+    pub fn regions(&self, node: Node) -> impl Iterator<Item = DeviceRegion<'_, Untranslated>> {
+        self.property_reg_iter(node).enumerate().map(move |(index, reg)| DeviceRegion {
+            dt: self,
+            node,
+            index,
+            reg,
+            _state: PhantomData,
         })
     }
 
+    /// Return the reg entries of `node`, translated through the bus ranges,
+    /// or left untranslated, as an error, if they can't be.
+    pub fn translated_regions(
+        &self,
+        node: Node,
+    ) -> impl Iterator<
+        Item = core::result::Result<DeviceRegion<'_, Translated>, DeviceRegion<'_, Untranslated>>,
+    > {
+        self.regions(node).map(DeviceRegion::translate)
+    }
+
     fn property_value_contains(&self, prop: &Property, bytes_to_find: &str) -> bool {
         if let Some(uninit_value) = self.property_value_bytes(prop) {
             let init_value = unsafe { uninit_value.assume_init_ref() };
@@ -385,17 +404,79 @@ impl<'a> DeviceTree<'a> {
         self.root().and_then(|node| find_subpath(self, &mut path_iter, &node, next_path_element))
     }
 
+    /// Return the node for `path`, which may be an absolute path or start
+    /// with an alias from /aliases, e.g. "serial0" or "serial0/child".  Any
+    /// options after a ':', as used in stdout-path, are ignored.
+    pub fn resolve_path(&self, path: &str) -> Option<Node> {
+        let path = path.split(':').next()?;
+        if path.starts_with('/') {
+            return self.find_by_path(path);
+        }
+        let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+        let aliases = self.find_by_path("/aliases")?;
+        let alias_path =
+            self.property(&aliases, alias).and_then(|p| self.property_value_as_str(&p))?;
+        let mut node = self.find_by_path(alias_path)?;
+        for name in rest.split_terminator('/') {
+            let child = self.children(&node).find(|c| self.node_name(c) == Some(name))?;
+            node = child;
+        }
+        Some(node)
+    }
+
+    /// Return the node for the console, as given by stdout-path in /chosen,
+    /// if there is one.
+    pub fn stdout_path(&self) -> Option<Node> {
+        let chosen = self.find_by_path("/chosen")?;
+        let prop = self
+            .property(&chosen, "stdout-path")
+            .or_else(|| self.property(&chosen, "linux,stdout-path"))?;
+        self.resolve_path(self.property_value_as_str(&prop)?)
+    }
+
+    /// Return the kernel command line the bootloader gave in /chosen, if there
+    /// is one.
+    pub fn bootargs(&self) -> Option<&str> {
+        let chosen = self.find_by_path("/chosen")?;
+        self.property_value_as_str(&self.property(&chosen, "bootargs")?)
+    }
+
+    /// Return the physical range of the initrd the bootloader gave in
+    /// /chosen, if there is one.
+    pub fn initrd_range(&self) -> Option<PhysRange> {
+        let chosen = self.find_by_path("/chosen")?;
+        let addr = |name| {
+            let bytes = self.property_value_bytes(&self.property(&chosen, name)?)?;
+            match bytes.len() {
+                4 => bytes_to_u32_as_u64(bytes),
+                8 => bytes_to_u64(bytes),
+                _ => None,
+            }
+        };
+        let start = addr("linux,initrd-start")?;
+        let end = addr("linux,initrd-end")?;
+        (start < end).then(|| PhysRange::new(PhysAddr::new(start), PhysAddr::new(end)))
+    }
+
+    /// Return true if the node's status is "okay", or it has no status.
+    pub fn is_enabled(&self, node: &Node) -> bool {
+        self.property(node, "status")
+            .and_then(|p| self.property_value_as_str(&p))
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// Return true if the node's compatible property includes 'comp'
+    pub fn is_compatible(&self, node: &Node, comp: &str) -> bool {
+        self.property(node, "compatible")
+            .is_some_and(|prop| self.property_value_contains(&prop, comp))
+    }
+
     /// Return the first node matching the compatible string 'comp'
     pub fn find_compatible(&'a self, comp: &'a str) -> impl Iterator<Item = Node> + 'a {
         // Iterate over all nodes.  For each node, iterate over all properties until we find a 'compatible'
         // property.  The 'compatible' property contains a list of null terminated strings.  If we find a matching
         // string, then return the node, otherwise return None.
-        self.nodes().filter(|n| {
-            if let Some(comp_prop) = self.property(n, "compatible") {
-                return self.property_value_contains(&comp_prop, comp);
-            }
-            false
-        })
+        self.nodes().filter(|n| self.is_compatible(n, comp))
     }
 
     /// Return iterator of nodes matching the device_type string 'device_type'
@@ -724,6 +805,113 @@ impl RegBlock {
     }
 }
 
+/// Marks a DeviceRegion whose address is as its node's reg gives it, an
+/// address on the node's parent bus.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Untranslated;
+
+/// Marks a DeviceRegion whose address has been translated through the ranges
+/// of every bus above its node, so is a CPU physical address.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Translated;
+
+/// A reg entry of a node, which knows the node it came from and its index in
+/// the node's reg, so it can say which it is when something's wrong with it.
+/// `S` is whether it's been translated through the bus ranges.  Only
+/// `Translated` regions give a `PhysRange`, so an address on some bus can't
+/// be mapped as though it were physical, nor translated twice.
+#[derive(Copy, Clone)]
+pub struct DeviceRegion<'a, S> {
+    dt: &'a DeviceTree<'a>,
+    node: Node,
+    index: usize,
+    reg: RegBlock,
+    _state: PhantomData<S>,
+}
+
+impl<'a, S> DeviceRegion<'a, S> {
+    pub fn node(&self) -> Node {
+        self.node
+    }
+
+    /// Index of the entry in the node's reg.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn reg(&self) -> RegBlock {
+        self.reg
+    }
+
+    pub fn node_name(&self) -> &'a str {
+        self.dt.node_name(&self.node).unwrap_or("")
+    }
+
+    /// Write the path of the region's node, e.g. /soc/serial@7e201000.
+    fn write_path(&self, f: &mut fmt::Formatter, node: Node) -> fmt::Result {
+        match self.dt.parent(&node) {
+            Some(parent) if !parent.is_root() => self.write_path(f, parent)?,
+            Some(_) => {}
+            None => return write!(f, "/"),
+        }
+        write!(f, "/{}", self.dt.node_name(&node).unwrap_or("?"))
+    }
+}
+
+impl<'a> DeviceRegion<'a, Untranslated> {
+    /// Translate the region through the ranges of the buses above its node.
+    /// If some bus doesn't reach it, the region is returned as it was.
+    pub fn translate(self) -> core::result::Result<DeviceRegion<'a, Translated>, Self> {
+        match self.dt.translate_reg(self.node, self.reg) {
+            Some(reg) => Ok(DeviceRegion {
+                dt: self.dt,
+                node: self.node,
+                index: self.index,
+                reg,
+                _state: PhantomData,
+            }),
+            None => Err(self),
+        }
+    }
+}
+
+impl DeviceRegion<'_, Translated> {
+    /// Return the physical range of the region, or None if it has no length.
+    pub fn phys_range(&self) -> Option<PhysRange> {
+        self.reg.len.map(|_| PhysRange::from(&self.reg))
+    }
+}
+
+impl<S: RegionState> fmt::Display for DeviceRegion<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_path(f, self.node)?;
+        write!(f, " reg[{}] {:#x}", self.index, self.reg.addr)?;
+        if let Some(len) = self.reg.len {
+            write!(f, "..{:#x}", self.reg.addr + len)?;
+        }
+        write!(f, " ({})", S::NAME)
+    }
+}
+
+impl<S: RegionState> fmt::Debug for DeviceRegion<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeviceRegion({self})")
+    }
+}
+
+/// Whether a DeviceRegion is translated, named for printing.
+pub trait RegionState {
+    const NAME: &'static str;
+}
+
+impl RegionState for Untranslated {
+    const NAME: &'static str = "untranslated";
+}
+
+impl RegionState for Translated {
+    const NAME: &'static str = "translated";
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum TranslatedReg {
     Translated(RegBlock),
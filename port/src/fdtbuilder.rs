@@ -0,0 +1,323 @@
+/// fdtbuilder assembles flattened devicetree blobs in memory, so tests can
+/// build the trees they need from Rust rather than checking in binary blobs.
+/// It follows the layout dtc emits, version 17: the header, then the memory
+/// reservation block, the structure block and the strings block, with
+/// property names shared in the strings block.  It's only compiled for tests,
+/// or with the fdt_builder feature, so it never ends up in the kernel image.
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u32 = 16;
+
+/// Append `value` to `out` as `cells` big-endian 32 bit cells, as in reg and
+/// ranges properties.  Panics if it doesn't fit.
+pub fn push_cells(out: &mut Vec<u8>, value: u64, cells: usize) {
+    assert!(
+        cells <= 2 && (cells == 2 || value >> (32 * cells) == 0),
+        "{value:#x} in {cells} cells"
+    );
+    for i in (0..cells).rev() {
+        out.extend_from_slice(&((value >> (32 * i)) as u32).to_be_bytes());
+    }
+}
+
+/// Builds a devicetree blob, a node at a time, with properties added to the
+/// node most recently begun.
+#[derive(Default)]
+pub struct FdtBuilder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    reserved: Vec<(u64, u64)>,
+    depth: usize,
+    boot_cpuid: u32,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entry to the memory reservation block.
+    pub fn reserve(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.reserved.push((addr, size));
+        self
+    }
+
+    /// Set the boot_cpuid_phys field of the header.
+    pub fn boot_cpuid(&mut self, cpuid: u32) -> &mut Self {
+        self.boot_cpuid = cpuid;
+        self
+    }
+
+    /// Begin a child of the current node, or the root node, named "".
+    pub fn begin_node(&mut self, name: &str) -> &mut Self {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.align_structs();
+        self.depth += 1;
+        self
+    }
+
+    /// End the current node.
+    pub fn end_node(&mut self) -> &mut Self {
+        assert!(self.depth > 0, "fdtbuilder:end_node:no node to end");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+        self
+    }
+
+    /// Add a property with a raw value, which may be empty, e.g. for ranges
+    /// giving an identity mapping.
+    pub fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        assert!(self.depth > 0, "fdtbuilder:property:{name} isn't in a node");
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(nameoff);
+        self.structs.extend_from_slice(value);
+        self.align_structs();
+        self
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) -> &mut Self {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    /// Add a property of 32 bit cells, e.g. interrupts.
+    pub fn property_u32s(&mut self, name: &str, values: &[u32]) -> &mut Self {
+        let value: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &value)
+    }
+
+    /// Add a nul terminated string property.
+    pub fn property_str(&mut self, name: &str, value: &str) -> &mut Self {
+        self.property_strs(name, &[value])
+    }
+
+    /// Add a string list property, e.g. compatible.
+    pub fn property_strs(&mut self, name: &str, values: &[&str]) -> &mut Self {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value)
+    }
+
+    /// Add a reg property of (address, size) pairs, with the cells the parent
+    /// node gives.  Sizes are left out if `size_cells` is 0.
+    pub fn property_reg(
+        &mut self,
+        regs: &[(u64, u64)],
+        address_cells: usize,
+        size_cells: usize,
+    ) -> &mut Self {
+        let mut value = Vec::new();
+        for &(addr, size) in regs {
+            push_cells(&mut value, addr, address_cells);
+            push_cells(&mut value, size, size_cells);
+        }
+        self.property("reg", &value)
+    }
+
+    /// Add a ranges property of (child address, parent address, size)
+    /// triples, with the cells of the node and its parent.
+    pub fn property_ranges(
+        &mut self,
+        ranges: &[(u64, u64, u64)],
+        address_cells: usize,
+        parent_address_cells: usize,
+        size_cells: usize,
+    ) -> &mut Self {
+        let mut value = Vec::new();
+        for &(child, parent, size) in ranges {
+            push_cells(&mut value, child, address_cells);
+            push_cells(&mut value, parent, parent_address_cells);
+            push_cells(&mut value, size, size_cells);
+        }
+        self.property("ranges", &value)
+    }
+
+    /// Return the blob.  Panics unless every node begun has been ended.
+    pub fn build(&self) -> Vec<u8> {
+        assert!(self.depth == 0, "fdtbuilder:build:{} nodes not ended", self.depth);
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + (self.reserved.len() + 1) * 16;
+        let size_dt_struct = self.structs.len() + 4;
+        let off_dt_strings = off_dt_struct + size_dt_struct;
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(totalsize);
+        for field in [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            VERSION,
+            LAST_COMP_VERSION,
+            self.boot_cpuid,
+            self.strings.len() as u32,
+            size_dt_struct as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for &(addr, size) in self.reserved.iter().chain(&[(0, 0)]) {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structs);
+        blob.extend_from_slice(&FDT_END.to_be_bytes());
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structs.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align_structs(&mut self) {
+        self.structs.resize(self.structs.len().next_multiple_of(4), 0);
+    }
+
+    /// Return the offset of `name` in the strings block, adding it if it isn't
+    /// already there.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split_inclusive(|&b| b == 0) {
+            if &s[..s.len() - 1] == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len();
+        }
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fdt::{DeviceTree, RegBlock};
+
+    fn u32_at(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_and_blocks() {
+        let mut fdt = FdtBuilder::new();
+        fdt.reserve(0x1000, 0x2000).boot_cpuid(2);
+        fdt.begin_node("").property_u32("#address-cells", 1).end_node();
+        let blob = fdt.build();
+
+        assert_eq!(u32_at(&blob, 0), FDT_MAGIC);
+        assert_eq!(u32_at(&blob, 4) as usize, blob.len());
+        let (off_struct, off_strings, off_rsvmap) =
+            (u32_at(&blob, 8) as usize, u32_at(&blob, 12) as usize, u32_at(&blob, 16) as usize);
+        assert_eq!((u32_at(&blob, 20), u32_at(&blob, 24), u32_at(&blob, 28)), (17, 16, 2));
+        assert!(off_rsvmap.is_multiple_of(8) && off_struct.is_multiple_of(4));
+
+        // One reservation, then the terminating empty entry
+        let rsv: Vec<u8> = [0x1000u64, 0x2000, 0, 0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        assert_eq!(&blob[off_rsvmap..off_struct], rsv);
+
+        // The structure block ends with FDT_END, and the strings follow it
+        assert_eq!(off_struct + u32_at(&blob, 36) as usize, off_strings);
+        assert_eq!(u32_at(&blob, off_strings - 4), FDT_END);
+        assert_eq!(&blob[off_strings..], b"#address-cells\0");
+        assert_eq!(u32_at(&blob, 32) as usize, blob.len() - off_strings);
+    }
+
+    #[test]
+    fn strings_are_shared() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("").property_str("compatible", "a").property_str("model", "m");
+        fdt.begin_node("child").property_str("compatible", "b").property_str("mod", "x");
+        fdt.end_node().end_node();
+        assert_eq!(fdt.strings, b"compatible\0model\0mod\0");
+
+        let blob = fdt.build();
+        let dt = DeviceTree::new(&blob).unwrap();
+        let child = dt.find_by_path("/child").unwrap();
+        let compatible = dt.property(&child, "compatible").unwrap();
+        assert_eq!(dt.property_value_as_str(&compatible), Some("b"));
+        let m = dt.property(&child, "mod").unwrap();
+        assert_eq!(dt.property_value_as_str(&m), Some("x"));
+    }
+
+    #[test]
+    fn properties_round_trip() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("").property_u32("#address-cells", 2).property_u32("#size-cells", 2);
+        fdt.begin_node("node@1000")
+            .property_u32("u32", 0x1234_5678)
+            .property_u64("u64", 0x1_0000_0002)
+            .property_u32s("cells", &[1, 2, 3])
+            .property_strs("compatible", &["vendor,one", "generic"])
+            .property("bytes", &[1, 2, 3, 4, 5])
+            .property("empty", &[])
+            .property_reg(&[(0x1_0000_1000, 0x2000), (0x3000, 0x10)], 2, 2)
+            .end_node();
+        fdt.end_node();
+        let blob = fdt.build();
+        let dt = DeviceTree::new(&blob).unwrap();
+
+        let node = dt.find_by_path("/node@1000").unwrap();
+        let prop = |name| dt.property(&node, name).unwrap();
+        assert_eq!(dt.property_value_as_u32(&prop("u32")), Some(0x1234_5678));
+        let u64_cells: Vec<u32> = dt.property_value_as_u32_iter(&prop("u64")).collect();
+        assert_eq!(u64_cells, [1, 2]);
+        let cells: Vec<u32> = dt.property_value_as_u32_iter(&prop("cells")).collect();
+        assert_eq!(cells, [1, 2, 3]);
+        assert!(dt.is_compatible(&node, "vendor,one") && dt.is_compatible(&node, "generic"));
+        let bytes = dt.property_value_bytes(&prop("bytes")).unwrap();
+        assert_eq!(unsafe { bytes.assume_init_ref() }, [1, 2, 3, 4, 5]);
+        assert_eq!(dt.property_value_bytes(&prop("empty")).unwrap().len(), 0);
+
+        // Properties after an unaligned value are still found
+        assert!(dt.property(&node, "reg").is_some());
+        let regs: Vec<RegBlock> = dt.property_reg_iter(node).collect();
+        assert_eq!(
+            regs,
+            [
+                RegBlock { addr: 0x1_0000_1000, len: Some(0x2000) },
+                RegBlock { addr: 0x3000, len: Some(0x10) }
+            ]
+        );
+    }
+
+    #[test]
+    fn cells() {
+        let mut out = Vec::new();
+        push_cells(&mut out, 0x1_0000_0002, 2);
+        push_cells(&mut out, 3, 1);
+        push_cells(&mut out, 0, 0);
+        assert_eq!(out, [0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn value_too_big_for_cells() {
+        push_cells(&mut Vec::new(), 1 << 32, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unbalanced_nodes() {
+        FdtBuilder::new().begin_node("").build();
+    }
+}
@@ -0,0 +1,127 @@
+/// framebuffer draws into a linear framebuffer of 32 bit pixels, such as one
+/// allocated by the firmware.  It's intended for early graphical output, and
+/// eventually a framebuffer console, so only supports filling rectangles and
+/// drawing text.
+use crate::font;
+
+/// Width of a character drawn by `draw_char`, in pixels.
+pub const CHAR_WIDTH: usize = 8;
+/// Height of a character drawn by `draw_char`, in pixels.
+pub const CHAR_HEIGHT: usize = 16;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+pub struct Framebuffer {
+    buf: &'static mut [u8],
+    pub width: usize,  // Visible width in pixels
+    pub height: usize, // Visible height in pixels
+    pub pitch: usize,  // Bytes between the starts of consecutive rows
+}
+
+impl Framebuffer {
+    /// Wrap `buf`, which holds `height` rows `pitch` bytes apart, each
+    /// starting with `width` 32 bit pixels.
+    pub fn new(buf: &'static mut [u8], width: usize, height: usize, pitch: usize) -> Self {
+        assert!(pitch >= width * BYTES_PER_PIXEL, "framebuffer: pitch {pitch} too small");
+        assert!(buf.len() >= pitch * height, "framebuffer: buffer too small");
+        Self { buf, width, height, pitch }
+    }
+
+    /// The framebuffer memory, including any padding at the ends of rows.
+    pub fn bytes(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    /// Fill the rectangle at (x, y) of `w` by `h` pixels with `colour`, which
+    /// is in the framebuffer's pixel format, e.g. 0x00rrggbb.  The rectangle
+    /// is clipped to the framebuffer.
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, colour: u32) {
+        let x_end = x.saturating_add(w).min(self.width);
+        let y_end = y.saturating_add(h).min(self.height);
+        for y in y..y_end {
+            for x in x..x_end {
+                self.put_pixel(x, y, colour);
+            }
+        }
+    }
+
+    /// Draw `c` with its top left corner at (x, y), clipped to the
+    /// framebuffer.  Anything other than printable ASCII is drawn as '?'.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: u8, fg: u32, bg: u32) {
+        let glyph = font::glyph(c);
+        for row in 0..CHAR_HEIGHT.min(self.height.saturating_sub(y)) {
+            let bits = glyph[row / 2];
+            for col in 0..CHAR_WIDTH.min(self.width.saturating_sub(x)) {
+                let colour = if bits & (0x80 >> col) != 0 { fg } else { bg };
+                self.put_pixel(x + col, y + row, colour);
+            }
+        }
+    }
+
+    /// Draw `s` on one line, starting at (x, y), clipped to the framebuffer.
+    pub fn draw_str(&mut self, x: usize, y: usize, s: &str, fg: u32, bg: u32) {
+        for (i, c) in s.bytes().enumerate() {
+            self.draw_char(x + i * CHAR_WIDTH, y, c, fg, bg);
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, colour: u32) {
+        let i = y * self.pitch + x * BYTES_PER_PIXEL;
+        self.buf[i..i + BYTES_PER_PIXEL].copy_from_slice(&colour.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_framebuffer(width: usize, height: usize, pitch: usize) -> Framebuffer {
+        let buf = Box::leak(vec![0; pitch * height].into_boxed_slice());
+        Framebuffer::new(buf, width, height, pitch)
+    }
+
+    fn pixel(fb: &mut Framebuffer, x: usize, y: usize) -> u32 {
+        let i = y * fb.pitch + x * BYTES_PER_PIXEL;
+        u32::from_le_bytes(fb.bytes()[i..i + BYTES_PER_PIXEL].try_into().unwrap())
+    }
+
+    #[test]
+    fn fill_rect_is_clipped() {
+        let mut fb = new_framebuffer(4, 3, 20);
+        fb.fill_rect(2, 1, 10, 10, 0x00ff_0000);
+        for y in 0..3 {
+            for x in 0..4 {
+                let expected = if x >= 2 && y >= 1 { 0x00ff_0000 } else { 0 };
+                assert_eq!(pixel(&mut fb, x, y), expected, "({x}, {y})");
+            }
+        }
+        // The padding at the end of each row is untouched
+        assert!(fb.bytes()[16..20].iter().all(|&b| b == 0));
+
+        fb.fill_rect(10, 10, 1, 1, 0xffff_ffff);
+        fb.fill_rect(0, 0, 0, 3, 0xffff_ffff);
+        assert_eq!(pixel(&mut fb, 0, 0), 0);
+    }
+
+    #[test]
+    fn draw_char_doubles_rows() {
+        let mut fb = new_framebuffer(12, 20, 12 * 4);
+        fb.draw_char(2, 1, b'|', 1, 2);
+        for row in 0..CHAR_HEIGHT {
+            for col in 0..CHAR_WIDTH {
+                // '|' is a line down the middle of the glyph, above the
+                // descender rows
+                let expected = if col == 3 && row < 14 { 1 } else { 2 };
+                assert_eq!(pixel(&mut fb, 2 + col, 1 + row), expected, "({col}, {row})");
+            }
+        }
+        assert_eq!(pixel(&mut fb, 1, 1), 0);
+        assert_eq!(pixel(&mut fb, 2, 0), 0);
+
+        // Unprintable characters are drawn as '?', and clipped at the edges
+        fb.draw_char(8, 10, 0x7f, 1, 2);
+        assert_eq!(pixel(&mut fb, 8, 10), 2);
+        assert_eq!(pixel(&mut fb, 10, 10), 1);
+        assert_eq!(font::glyph(0x7f), font::glyph(b'?'));
+    }
+}
@@ -0,0 +1,438 @@
+/// framerefs wraps a page allocator to count the references to each frame, so
+/// that a frame mapped in more than one place is only freed once the last
+/// mapping goes away.
+///
+/// The counts are kept in a table of one counter per frame of physical
+/// memory, which is carved out of memory by `init_from`.  The table is reached
+/// through a `PageMapper`, which usually isn't able to map it until later, so
+/// counting starts when `enable` is called.  At that point every allocated
+/// frame has one reference.  From then on, allocating a frame sets its count
+/// to one, `get` adds a reference, and `put` drops one, freeing the frame
+/// when none are left.
+use core::{fmt, slice};
+
+use crate::{
+    mem::{MemError, PhysAddr, PhysRange, PhysRangeSet},
+    pagealloc::{PageAlloc, PageAllocStats, PageAllocSummary, PageMapper, ReserveError},
+};
+
+/// Reference count of a single frame.
+pub type RefCount = u16;
+
+/// Page allocator that keeps a reference count for each frame.
+pub struct RefCountPageAlloc<A, M> {
+    alloc: A,
+    mapper: M,
+    base: PhysAddr,           // Address of the frame counted by counts[0]
+    num_frames: usize,        // Number of frames counted
+    table: Option<PhysRange>, // Pages holding the counts, once carved out
+    counts: Option<&'static mut [RefCount]>, // The counts, once enabled
+}
+
+impl<A: PageAlloc, M: PageMapper> RefCountPageAlloc<A, M> {
+    pub const fn new(alloc: A, mapper: M) -> Self {
+        Self { alloc, mapper, base: PhysAddr::new(0), num_frames: 0, table: None, counts: None }
+    }
+
+    /// Return the wrapped allocator, e.g. to enable features of its own.
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.alloc
+    }
+
+    /// Start counting references, if the table can be reached through the
+    /// mapper.  Every frame that's currently allocated, including reserved
+    /// frames and the table itself, starts with one reference.
+    pub fn enable(&mut self) {
+        let Some(table) = &self.table else {
+            return;
+        };
+        let Some(ptr) = self.mapper.page_ptr(table.start(), table.size()) else {
+            return;
+        };
+        // Safety: PageMapper guarantees the pointer covers the whole table,
+        // and the table pages stay allocated for the life of the allocator.
+        let counts = unsafe { slice::from_raw_parts_mut(ptr as *mut RefCount, self.num_frames) };
+        counts.fill(1);
+        let (base, page_size) = (self.base, self.page_size());
+        self.alloc.for_each_free_range(|range| {
+            for pa in range.step_by_rounded(page_size) {
+                if let Some(count) =
+                    frame_index(base, page_size, counts.len(), pa).and_then(|i| counts.get_mut(i))
+                {
+                    *count = 0;
+                }
+            }
+        });
+        self.counts = Some(counts);
+    }
+
+    /// Return the number of references to the frame containing `pa`, or None
+    /// if references aren't being counted for it.
+    pub fn ref_count(&self, pa: PhysAddr) -> Option<RefCount> {
+        let counts = self.counts.as_ref()?;
+        frame_index(self.base, self.page_size(), counts.len(), pa).map(|i| counts[i])
+    }
+
+    fn count_mut(&mut self, pa: PhysAddr) -> Option<&mut RefCount> {
+        let page_size = self.page_size();
+        let counts = self.counts.as_mut()?;
+        frame_index(self.base, page_size, counts.len(), pa).map(|i| &mut counts[i])
+    }
+
+    fn set_counts(&mut self, range: &PhysRange, count: RefCount) {
+        for pa in range.step_by_rounded(self.page_size()) {
+            if let Some(c) = self.count_mut(pa) {
+                *c = count;
+            }
+        }
+    }
+
+    /// Add a reference to the allocated frame at `pa`, e.g. when mapping it
+    /// at a second address, returning the new count.  Fails with `OutOfRange`
+    /// if references aren't being counted for the frame.  Adding a reference
+    /// to a free frame, or beyond the maximum count, fails, and in debug
+    /// builds panics with the frame address.
+    pub fn get(&mut self, pa: PhysAddr) -> Result<RefCount, MemError> {
+        let counted = self.counts.as_ref().map_or(0, |counts| counts.len() * self.page_size());
+        let range = self.base.addr()..self.base.addr() + counted as u64;
+        let count = self.count_mut(pa).ok_or(MemError::OutOfRange { addr: pa.addr(), range })?;
+        let result = match *count {
+            0 => Err(MemError::NotAllocated { pa }),
+            RefCount::MAX => Err(MemError::Overflow),
+            n => {
+                *count = n + 1;
+                return Ok(n + 1);
+            }
+        };
+        result.inspect_err(|err| {
+            if cfg!(debug_assertions) {
+                panic!("framerefs: can't get frame {:?}: {}", pa, err);
+            }
+        })
+    }
+
+    /// Drop a reference to the allocated frame at `pa`, e.g. when unmapping
+    /// it, returning the number left.  The frame is freed when none are left.
+    /// Frames that references aren't being counted for are treated as having
+    /// a single reference, so are freed.  Dropping a reference to a free frame
+    /// fails, and in debug builds panics with the frame address.
+    pub fn put(&mut self, pa: PhysAddr) -> Result<RefCount, MemError> {
+        let Some(&mut count) = self.count_mut(pa) else {
+            return self.alloc.free(pa).map(|_| 0);
+        };
+        let frame = PhysRange::with_pa_len(pa, 1);
+        match count {
+            0 => {
+                if cfg!(debug_assertions) {
+                    panic!("framerefs: can't put frame {:?}: no references left", pa);
+                }
+                Err(MemError::NotAllocated { pa })
+            }
+            1 => {
+                self.alloc.free(pa)?;
+                self.set_counts(&frame, 0);
+                Ok(0)
+            }
+            n => {
+                self.set_counts(&frame, n - 1);
+                Ok(n - 1)
+            }
+        }
+    }
+
+    /// Check that no frame in `range` has more than one reference, so that
+    /// the range can be freed.  In debug builds, panics with the first shared
+    /// frame.
+    fn check_unshared(&self, range: &PhysRange) -> Result<(), MemError> {
+        let page_size = self.page_size();
+        match range.step_by_rounded(page_size).find(|&pa| self.ref_count(pa) > Some(1)) {
+            Some(pa) if cfg!(debug_assertions) => panic!(
+                "framerefs: can't free frame {:?}: {} references",
+                pa,
+                self.ref_count(pa).unwrap_or(0)
+            ),
+            Some(pa) => Err(MemError::Shared { pa }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Return the index in the table of the frame containing `pa`, or None if
+/// the table, starting at `base`, doesn't cover it.
+fn frame_index(base: PhysAddr, page_size: usize, num_frames: usize, pa: PhysAddr) -> Option<usize> {
+    let index = (pa.addr().checked_sub(base.addr())? / page_size as u64) as usize;
+    (index < num_frames).then_some(index)
+}
+
+impl<A: PageAlloc, M: PageMapper> PageAlloc for RefCountPageAlloc<A, M> {
+    fn page_size(&self) -> usize {
+        self.alloc.page_size()
+    }
+
+    fn max_end(&self) -> PhysAddr {
+        self.alloc.max_end()
+    }
+
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.mark_allocated(range)?;
+        self.set_counts(range, 1);
+        Ok(())
+    }
+
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.mark_free(range)?;
+        self.set_counts(range, 0);
+        Ok(())
+    }
+
+    /// Once memory is made available, the table is allocated from it, sized
+    /// to cover every frame from the start of the first range of memory to
+    /// the end of the last.  The table's pages are counted as reserved.
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError> {
+        let mut summary = self.alloc.init_from(memory, reserved, mapper)?;
+        let (Some(first), Some(last)) = (memory.iter().next(), memory.iter().last()) else {
+            return Ok(summary);
+        };
+        let page_size = self.page_size();
+        let base = first.start().round_down(page_size as u64);
+        let end = last.end().min(self.max_end()).round_up(page_size as u64);
+        let num_frames = (end.addr().saturating_sub(base.addr()) / page_size as u64) as usize;
+        let table_bytes = num_frames * size_of::<RefCount>();
+        let table = self.alloc.allocate_contiguous(table_bytes.div_ceil(page_size))?;
+
+        summary.reserved_pages += table.size() / page_size;
+        summary.free_pages -= table.size() / page_size;
+        self.base = base;
+        self.num_frames = num_frames;
+        self.table = Some(table);
+        self.counts = None;
+        Ok(summary)
+    }
+
+    fn allocate(&mut self) -> Result<PhysAddr, MemError> {
+        let pa = self.alloc.allocate()?;
+        self.set_counts(&PhysRange::with_pa_len(pa, 1), 1);
+        Ok(pa)
+    }
+
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError> {
+        let range = self.alloc.allocate_aligned(page_count, align)?;
+        self.set_counts(&range, 1);
+        Ok(range)
+    }
+
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        let range = self.alloc.allocate_contiguous_within(page_count, within)?;
+        self.set_counts(&range, 1);
+        Ok(range)
+    }
+
+    /// Frames with more than one reference can't be deallocated, and fail
+    /// with `Shared`.  Use `put` to drop a reference instead.
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        if self.ref_count(pa) > Some(1) {
+            return Err(MemError::Shared { pa });
+        }
+        self.alloc.deallocate(pa)?;
+        self.set_counts(&PhysRange::with_pa_len(pa, 1), 0);
+        Ok(())
+    }
+
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.check_unshared(range)?;
+        self.alloc.free_range(range)?;
+        self.set_counts(range, 0);
+        Ok(())
+    }
+
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError> {
+        let range = self.alloc.reserve_range(range)?;
+        self.set_counts(&range, 1);
+        Ok(range)
+    }
+
+    fn release_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        let range = range.round_out(self.page_size() as u64);
+        self.check_unshared(&range)?;
+        self.alloc.release_range(&range)?;
+        self.set_counts(&range, 0);
+        Ok(())
+    }
+
+    fn stats(&self) -> PageAllocStats {
+        self.alloc.stats()
+    }
+
+    fn for_each_free_range(&self, f: impl FnMut(&PhysRange)) {
+        self.alloc.for_each_free_range(f)
+    }
+
+    fn for_each_region(&self, f: impl FnMut(&PhysRange, PageAllocStats)) {
+        self.alloc.for_each_region(f)
+    }
+
+    /// The table of counts is reported along with the wrapped allocator's
+    /// state.
+    fn for_each_metadata_range(&self, mut f: impl FnMut(&PhysRange)) {
+        self.alloc.for_each_metadata_range(&mut f);
+        if let Some(table) = &self.table {
+            f(table);
+        }
+    }
+
+    fn remap_metadata(&mut self, mapper: &impl PageMapper) -> Result<(), MemError> {
+        self.alloc.remap_metadata(mapper)
+    }
+
+    fn usage_bytes(&self) -> (usize, usize) {
+        self.alloc.usage_bytes()
+    }
+
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result {
+        match (&self.table, &self.counts) {
+            (Some(table), Some(counts)) => {
+                let shared = counts.iter().filter(|&&n| n > 1).count();
+                writeln!(w, "Frame refcounts: table {table}, {shared} shared frames")?;
+            }
+            (Some(table), None) => writeln!(w, "Frame refcounts: table {table}, disabled")?,
+            (None, _) => writeln!(w, "Frame refcounts: no table")?,
+        }
+        self.alloc.dump(w, show_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmapalloc::BitmapPageAlloc;
+    use crate::buddyalloc::BuddyPageAlloc;
+    use crate::fakemem::{FakeMemory, init_512};
+
+    fn new_mem() -> FakeMemory {
+        FakeMemory::new(512, 256, 0xaaaa_aaaa_aaaa_aaaa)
+    }
+
+    /// 32 pages of 16 bytes, with memory from 0 to 512, of which 0..256 is
+    /// mapped.  The table needs 64 bytes, so takes 4 pages, and is reported
+    /// along with any pages carved out by the wrapped allocator, at the top of
+    /// memory.
+    fn new_alloc<A: PageAlloc>(
+        alloc: A,
+        mem: &mut FakeMemory,
+    ) -> RefCountPageAlloc<A, *mut FakeMemory> {
+        let mut alloc = RefCountPageAlloc::new(alloc, mem.mapper());
+        let summary = init_512(&mut alloc, &[PhysRange::with_end(0, 16)]);
+        let mut metadata_pages = 0;
+        alloc.for_each_metadata_range(|range| metadata_pages += range.size() / 16);
+        assert!(metadata_pages >= 4);
+        assert_eq!(
+            summary,
+            PageAllocSummary {
+                total_pages: 32,
+                reserved_pages: 1 + metadata_pages,
+                free_pages: 31 - metadata_pages
+            }
+        );
+        alloc
+    }
+
+    fn get_and_put(alloc: impl PageAlloc) -> Result<(), MemError> {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(alloc, &mut mem);
+
+        // Nothing is counted until enabled, so put frees the frame
+        let pa = alloc.allocate()?;
+        assert_eq!(alloc.ref_count(pa), None);
+        assert!(
+            matches!(alloc.get(pa), Err(MemError::OutOfRange { addr, .. }) if addr == pa.addr())
+        );
+        assert_eq!(alloc.put(pa), Ok(0));
+        assert_eq!(alloc.stats().allocated_pages, 4);
+
+        // Reserved frames and the table start with one reference
+        alloc.enable();
+        assert_eq!(alloc.ref_count(PhysAddr::new(0)), Some(1));
+        assert_eq!(alloc.ref_count(alloc.table.clone().unwrap().start()), Some(1));
+        assert_eq!(alloc.ref_count(PhysAddr::new(480)), Some(0));
+        assert_eq!(alloc.ref_count(PhysAddr::new(512)), None);
+
+        // The frame is only freed once the last reference is dropped
+        let pa = alloc.allocate()?;
+        assert_eq!(alloc.ref_count(pa), Some(1));
+        assert_eq!(alloc.get(pa), Ok(2));
+        assert_eq!(alloc.get(pa), Ok(3));
+        assert_eq!(alloc.put(pa), Ok(2));
+        assert_eq!(alloc.put(pa), Ok(1));
+        assert_eq!(alloc.stats().allocated_pages, 5);
+        assert_eq!(alloc.put(pa), Ok(0));
+        assert_eq!(alloc.ref_count(pa), Some(0));
+        assert_eq!(alloc.stats().allocated_pages, 4);
+
+        // Ranges are counted per frame
+        let range = alloc.allocate_contiguous(2)?;
+        assert_eq!(alloc.ref_count(range.start() + 16), Some(1));
+        alloc.free_range(&range)?;
+        assert_eq!(alloc.ref_count(range.start() + 16), Some(0));
+        Ok(())
+    }
+
+    fn free_shared(alloc: impl PageAlloc) {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(alloc, &mut mem);
+        alloc.enable();
+
+        let pa = alloc.allocate().unwrap();
+        alloc.get(pa).unwrap();
+        let _ = alloc.free(pa);
+    }
+
+    #[test]
+    fn framerefs_bitmap_get_and_put() -> Result<(), MemError> {
+        get_and_put(BitmapPageAlloc::<2, 2>::new_all_allocated(16))
+    }
+
+    #[test]
+    fn framerefs_buddy_get_and_put() -> Result<(), MemError> {
+        get_and_put(BuddyPageAlloc::<2, 2>::new_all_allocated(16))
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "can't free page PhysAddr(0x0000000000000050): frame 0x0000000000000050 shared"
+    )]
+    fn framerefs_free_shared() {
+        free_shared(BitmapPageAlloc::<2, 2>::new_all_allocated(16));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "framerefs: can't put frame PhysAddr(0x00000000000001e0): no references left"
+    )]
+    fn framerefs_put_free_frame() {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(BitmapPageAlloc::<2, 2>::new_all_allocated(16), &mut mem);
+        alloc.enable();
+        let _ = alloc.put(PhysAddr::new(0x1e0));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't get frame PhysAddr(0x0000000000000050): count overflow")]
+    fn framerefs_overflow() {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(BitmapPageAlloc::<2, 2>::new_all_allocated(16), &mut mem);
+        alloc.enable();
+        let pa = alloc.allocate().unwrap();
+        for _ in 0..RefCount::MAX {
+            alloc.get(pa).unwrap();
+        }
+    }
+}
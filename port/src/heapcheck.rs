@@ -0,0 +1,238 @@
+/// heapcheck catches heap buffer overruns and leaks, for the global allocator
+/// in debug builds.  Each allocation is padded with a canary word before and
+/// after the object, which are checked when it's freed, and live allocations
+/// are recorded with their size and where they were allocated, so that
+/// `leaks` can list whatever is still live, e.g. after a test.
+///
+/// Only the first `MAX_LIVE` live allocations are recorded; canaries are
+/// checked for all of them.  Allocations are numbered in order, as those
+/// made through `Box`, `Vec` and so on are all attributed to the global
+/// allocator itself, since the compiler's allocation shims don't track their
+/// callers.
+use core::alloc::Layout;
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+
+/// Number of live allocations recorded.
+const MAX_LIVE: usize = 256;
+
+const CANARY_SIZE: usize = 8;
+const FRONT_CANARY: u64 = 0xfeed_face_cafe_f00d;
+const BACK_CANARY: u64 = 0xdead_beef_0bad_c0de;
+
+/// A live allocation.
+#[derive(Clone, Copy)]
+struct Live {
+    addr: usize, // Address of the object
+    size: usize, // Size of the object, without canaries
+    seq: u64,    // Number of allocations before this one
+    location: &'static Location<'static>,
+}
+
+/// Live allocations, behind the heap's lock.
+pub struct HeapCheck {
+    live: [Option<Live>; MAX_LIVE],
+    untracked: usize, // Live allocations that didn't fit in the table
+    next_seq: u64,
+}
+
+impl HeapCheck {
+    pub const fn new() -> HeapCheck {
+        HeapCheck { live: [None; MAX_LIVE], untracked: 0, next_seq: 0 }
+    }
+
+    /// Returns the layout of a block holding an object of `layout`
+    /// between canaries, and the offset of the object in it, or None if
+    /// it would be too big.
+    pub fn padded(layout: Layout) -> Option<(Layout, usize)> {
+        let front = layout.align().max(CANARY_SIZE);
+        let size = front.checked_add(layout.size())?.checked_add(CANARY_SIZE)?;
+        let padded = Layout::from_size_align(size, layout.align()).ok()?;
+        Some((padded, front))
+    }
+
+    /// Writes canaries around the object in `block`, allocated with the
+    /// layout from `padded(layout)`, and records it as allocated at
+    /// `location`.  Returns the object.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be valid for writes of `padded(layout)` bytes.
+    pub unsafe fn on_alloc(
+        &mut self,
+        block: *mut u8,
+        layout: Layout,
+        location: &'static Location<'static>,
+    ) -> *mut u8 {
+        let (_, front) = Self::padded(layout).expect("padded layout");
+        let object = block.wrapping_add(front);
+        unsafe {
+            ptr::write_unaligned(object.sub(CANARY_SIZE).cast::<u64>(), FRONT_CANARY);
+            ptr::write_unaligned(object.add(layout.size()).cast::<u64>(), BACK_CANARY);
+        }
+        let live = Live { addr: object.addr(), size: layout.size(), seq: self.next_seq, location };
+        self.next_seq += 1;
+        match self.live.iter_mut().find(|l| l.is_none()) {
+            Some(slot) => *slot = Some(live),
+            None => self.untracked += 1,
+        }
+        object
+    }
+
+    /// Checks the canaries around `object`, as returned by `on_alloc`,
+    /// and forgets it.  Returns the block to free.  Panics, with where
+    /// the object was allocated, if either canary was overwritten.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been returned by `on_alloc` for `layout`, and
+    /// not freed since.
+    pub unsafe fn on_free(&mut self, object: *mut u8, layout: Layout) -> *mut u8 {
+        let (_, front) = Self::padded(layout).expect("padded layout");
+        let slot = self.live.iter_mut().find(|l| l.is_some_and(|l| l.addr == object.addr()));
+        let live = match slot {
+            Some(slot) => slot.take(),
+            None => {
+                self.untracked = self.untracked.saturating_sub(1);
+                None
+            }
+        };
+        let front_ok =
+            unsafe { ptr::read_unaligned(object.sub(CANARY_SIZE).cast::<u64>()) } == FRONT_CANARY;
+        let back_ok =
+            unsafe { ptr::read_unaligned(object.add(layout.size()).cast::<u64>()) } == BACK_CANARY;
+        if !front_ok || !back_ok {
+            let which = if front_ok { "back" } else { "front" };
+            let (size, addr) = (layout.size(), object.addr());
+            match live {
+                Some(live) => panic!(
+                    "heap: {which} canary of {size} byte block at {addr:#x} overwritten, allocation #{} from {}",
+                    live.seq, live.location
+                ),
+                None => panic!(
+                    "heap: {which} canary of {size} byte block at {addr:#x} overwritten, allocated from unknown location"
+                ),
+            }
+        }
+        object.wrapping_sub(front)
+    }
+
+    /// Returns the live allocations, which can be printed.
+    pub fn leaks(&self) -> Leaks<'_> {
+        Leaks(self)
+    }
+}
+
+impl Default for HeapCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lists live allocations, a line each, after a summary line.
+pub struct Leaks<'a>(&'a HeapCheck);
+
+impl fmt::Display for Leaks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let check = self.0;
+        let tracked = check.live.iter().flatten().count();
+        write!(f, "heap: {} live allocations", tracked + check.untracked)?;
+        if check.untracked > 0 {
+            write!(f, ", {} not recorded", check.untracked)?;
+        }
+        writeln!(f)?;
+        let mut live: [Option<&Live>; MAX_LIVE] = [None; MAX_LIVE];
+        for (slot, l) in live.iter_mut().zip(check.live.iter().flatten()) {
+            *slot = Some(l);
+        }
+        let live = &mut live[..tracked];
+        live.sort_unstable_by_key(|l| l.map(|l| l.seq));
+        for l in live.iter().flatten() {
+            writeln!(f, "  #{}: {} bytes at {:#x} from {}", l.seq, l.size, l.addr, l.location)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block for `layout` from the host allocator.
+    fn block(layout: Layout) -> *mut u8 {
+        let (padded, _) = HeapCheck::padded(layout).unwrap();
+        unsafe { std::alloc::alloc(padded) }
+    }
+
+    #[test]
+    fn padding_keeps_alignment() {
+        let mut check = HeapCheck::new();
+        for layout in
+            [Layout::new::<u8>(), Layout::new::<u128>(), Layout::from_size_align(3, 64).unwrap()]
+        {
+            let b = block(layout);
+            let object = unsafe { check.on_alloc(b, layout, Location::caller()) };
+            assert_eq!(object.addr() % layout.align(), 0);
+            assert_eq!(unsafe { check.on_free(object, layout) }, b);
+        }
+    }
+
+    #[test]
+    fn leaks_are_listed_in_order() {
+        let mut check = HeapCheck::new();
+        let layout = Layout::new::<[u32; 4]>();
+        let location = Location::caller();
+        let objects: Vec<_> =
+            (0..3).map(|_| unsafe { check.on_alloc(block(layout), layout, location) }).collect();
+        unsafe { check.on_free(objects[1], layout) };
+        let leaks = check.leaks().to_string();
+        let lines: Vec<_> = leaks.lines().collect();
+        assert_eq!(lines[0], "heap: 2 live allocations");
+        assert_eq!(lines[1], format!("  #0: 16 bytes at {:#x} from {location}", objects[0].addr()));
+        assert!(lines[2].starts_with("  #2: 16 bytes at "));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn allocations_beyond_table_are_counted() {
+        let mut check = HeapCheck::new();
+        let layout = Layout::new::<u8>();
+        let objects: Vec<_> = (0..MAX_LIVE + 2)
+            .map(|_| unsafe { check.on_alloc(block(layout), layout, Location::caller()) })
+            .collect();
+        assert!(
+            check.leaks().to_string().starts_with("heap: 258 live allocations, 2 not recorded\n")
+        );
+        for object in objects {
+            unsafe { check.on_free(object, layout) };
+        }
+        assert_eq!(check.leaks().to_string(), "heap: 0 live allocations\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "back canary of 8 byte block")]
+    fn overrun_panics() {
+        let mut check = HeapCheck::new();
+        let layout = Layout::new::<u64>();
+        let object = unsafe { check.on_alloc(block(layout), layout, Location::caller()) };
+        unsafe { object.add(8).write(0) };
+        unsafe { check.on_free(object, layout) };
+    }
+
+    #[test]
+    fn underrun_panics_with_location() {
+        let mut check = HeapCheck::new();
+        let layout = Layout::new::<u64>();
+        let location = Location::caller();
+        let object = unsafe { check.on_alloc(block(layout), layout, location) };
+        unsafe { object.sub(1).write(0) };
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+            check.on_free(object, layout)
+        }))
+        .unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.starts_with("heap: front canary of 8 byte block"), "{msg}");
+        assert!(msg.ends_with(&format!("overwritten, allocation #0 from {location}")), "{msg}");
+    }
+}
@@ -0,0 +1,118 @@
+/// initrd holds the initial ramdisk the bootloader loaded, a cpio "newc"
+/// archive (see cpio).  The arch code reserves its pages from the page
+/// allocator, maps it read-only, and sets it with `init` during boot, so it
+/// survives until something serves the files.
+use core::fmt;
+
+use crate::cpio::{self, CpioError, Entries};
+use crate::mem::PhysRange;
+use crate::oncelock::OnceLock;
+use crate::physmap::Size;
+
+static INITRD: OnceLock<Initrd> = OnceLock::new();
+
+/// The initrd, as a read-only view of its bytes.
+pub struct Initrd {
+    range: PhysRange,
+    bytes: &'static [u8],
+}
+
+impl Initrd {
+    /// Make an Initrd of `bytes`, which are the contents of `range`, checking
+    /// it's a valid archive.
+    pub fn new(range: PhysRange, bytes: &'static [u8]) -> Result<Initrd, CpioError> {
+        cpio::validate(bytes)?;
+        Ok(Initrd { range, bytes })
+    }
+
+    pub fn range(&self) -> &PhysRange {
+        &self.range
+    }
+
+    pub fn as_bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// Return an iterator over the entries in the archive.
+    pub fn entries(&self) -> Entries<'static> {
+        cpio::entries(self.bytes)
+    }
+
+    /// Return something that prints the entries, a line each.
+    pub fn listing(&self) -> Listing<'_> {
+        Listing(self)
+    }
+}
+
+/// Lists the entries in an initrd, with the mode and size of each.
+pub struct Listing<'a>(&'a Initrd);
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let initrd = self.0;
+        writeln!(f, "initrd {} ({}):", initrd.range, Size(initrd.bytes.len()))?;
+        for entry in initrd.entries() {
+            match entry {
+                Ok(entry) => writeln!(
+                    f,
+                    "  {:06o} {:>10} {}",
+                    entry.mode,
+                    Size(entry.data.len()),
+                    entry.name
+                )?,
+                Err(err) => writeln!(f, "  error: {err}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the initrd, once, during boot.
+pub fn init(initrd: Initrd) {
+    if INITRD.set(initrd).is_err() {
+        panic!("error:initrd:init:initrd already set");
+    }
+}
+
+/// The initrd, if the bootloader loaded one and it's valid.
+pub fn get() -> Option<&'static Initrd> {
+    INITRD.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::PhysAddr;
+
+    #[test]
+    fn lists_entries() {
+        // A newc archive with one file, "hi", containing "hi\n", and the
+        // trailer
+        let mut archive = Vec::new();
+        for (name, mode, data) in [("hi", 0o100644, &b"hi\n"[..]), ("TRAILER!!!", 0, &[])] {
+            let fields =
+                [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+            archive.extend_from_slice(b"070701");
+            for field in fields {
+                archive.extend_from_slice(format!("{field:08X}").as_bytes());
+            }
+            archive.extend_from_slice(name.as_bytes());
+            archive.push(0);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+        }
+        let bytes: &'static [u8] = archive.leak();
+        let range = PhysRange::with_len(0x4800_0000, bytes.len());
+        assert!(Initrd::new(range.clone(), &bytes[..100]).is_err());
+
+        let initrd = Initrd::new(range, bytes).unwrap();
+        assert_eq!(initrd.as_bytes(), bytes);
+        assert_eq!(initrd.range().start(), PhysAddr::new(0x4800_0000));
+        assert_eq!(initrd.entries().count(), 1);
+        let listing = initrd.listing().to_string();
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[1], "  100644        3 B hi");
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -0,0 +1,47 @@
+/// interrupts lets portable code, such as `Lock::lock_irqsave`, mask
+/// interrupts on the current core.  Each arch implements `Interrupts` and
+/// registers it with `init`.  Until then, disabling and restoring do nothing,
+/// which is fine early in boot, while interrupts are still masked.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Masking and unmasking of interrupts on the current core.
+pub trait Interrupts {
+    /// Disable interrupts, returning the previous state for `restore`.
+    fn disable() -> usize;
+
+    /// Restore the interrupt state returned by `disable`.
+    fn restore(state: usize);
+}
+
+static DISABLE_FN: AtomicPtr<()> = AtomicPtr::new(null_mut());
+static RESTORE_FN: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// Use `I` to mask interrupts.
+pub fn init<I: Interrupts>() {
+    DISABLE_FN.store(I::disable as fn() -> usize as *mut (), Ordering::Release);
+    RESTORE_FN.store(I::restore as fn(usize) as *mut (), Ordering::Release);
+}
+
+/// Disable interrupts on the current core, returning the previous state for
+/// `restore`.
+pub fn disable() -> usize {
+    let disable_fn = DISABLE_FN.load(Ordering::Acquire);
+    if disable_fn.is_null() {
+        return 0;
+    }
+    // Safety: only init stores to DISABLE_FN, and it stores a fn() -> usize
+    let disable_fn = unsafe { core::mem::transmute::<*mut (), fn() -> usize>(disable_fn) };
+    disable_fn()
+}
+
+/// Restore the interrupt state returned by `disable`.
+pub fn restore(state: usize) {
+    let restore_fn = RESTORE_FN.load(Ordering::Acquire);
+    if restore_fn.is_null() {
+        return;
+    }
+    // Safety: only init stores to RESTORE_FN, and it stores a fn(usize)
+    let restore_fn = unsafe { core::mem::transmute::<*mut (), fn(usize)>(restore_fn) };
+    restore_fn(state);
+}
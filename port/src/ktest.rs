@@ -0,0 +1,263 @@
+/// ktest runs tests inside the kernel, for what can only be checked once
+/// booted, such as the page tables, or allocators working with the memory
+/// the firmware describes.  Tests are registered with the `ktest!` macro,
+/// which puts them in the `.ktest` section, and the kernel runs them with
+/// `run` when built with the `ktest` feature, printing the results in TAP
+/// format:
+///
+/// ```text
+/// TAP version 13
+/// 1..2
+/// ok 1 - aarch64::ktests::heap_alloc_free
+/// not ok 2 - aarch64::ktests::fdt_memory
+/// # passed 1 of 2
+/// ```
+///
+/// A test fails by panicking.  The kernel is built with panic=abort, so a
+/// panic can't be caught, but the panic handler calls `report_panic`, which
+/// reports the test that was running, and the rest aren't run.
+///
+/// With `run_checked`, a test also fails if it leaks, e.g. pages it didn't
+/// free, which the arch finds by comparing snapshots of its state from
+/// before and after the test.  What leaked is printed as TAP comments after
+/// the test's result, and the rest of the tests are still run.
+use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::{print, println};
+
+/// A test registered with `ktest!`.
+#[repr(C)]
+pub struct KTest {
+    pub name: &'static str,
+    pub func: fn(),
+}
+
+/// Register a kernel test, run by `run` when the kernel is built with the
+/// `ktest` feature.  The test fails if it panics.
+///
+/// ```ignore
+/// port::ktest! {
+///     fn heap_alloc_free() {
+///         let v = alloc::vec![0u8; 4096];
+///         assert_eq!(v.len(), 4096);
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! ktest {
+    (fn $name:ident() $body:block) => {
+        fn $name() $body
+
+        const _: () = {
+            #[unsafe(link_section = ".ktest")]
+            #[used]
+            static TEST: $crate::ktest::KTest = $crate::ktest::KTest {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                func: $name,
+            };
+        };
+    };
+}
+
+/// The test being run, for `report_panic`.
+static RUNNING: AtomicPtr<KTest> = AtomicPtr::new(null_mut());
+/// Number of the test being run, from 1, as in TAP.
+static RUNNING_NUM: AtomicUsize = AtomicUsize::new(0);
+/// Number of tests being run.
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// The tests registered with `ktest!`, from the `.ktest` section.
+#[cfg(not(test))]
+pub fn registered() -> &'static [KTest] {
+    // These map to definitions in kernel.ld
+    unsafe extern "C" {
+        static ktests: [u64; 0];
+        static ektests: [u64; 0];
+    }
+    // Safety: the linker puts the KTest statics made by ktest! between
+    // ktests and ektests, and they're all the same size and alignment.
+    unsafe {
+        let start = ktests.as_ptr().cast::<KTest>();
+        let len = ektests.as_ptr().cast::<KTest>().offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Result of a test, printed as a TAP test line.
+struct TestLine<'a> {
+    ok: bool,
+    num: usize,
+    test: &'a KTest,
+}
+
+impl fmt::Display for TestLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ok = if self.ok { "ok" } else { "not ok" };
+        write!(f, "{ok} {} - {}", self.num, self.test.name)
+    }
+}
+
+/// Checks a test gave back what it took, by comparing snapshots of the
+/// kernel's state from before and after it.
+pub trait LeakCheck {
+    /// Take a snapshot of the state before a test.
+    fn before(&mut self);
+
+    /// Take a snapshot of the state after the test, and if something leaked
+    /// since the one before, return a description of what, to print with
+    /// the test's failure.
+    fn after(&mut self) -> Option<impl fmt::Display + '_>;
+}
+
+/// Checks nothing.
+impl LeakCheck for () {
+    fn before(&mut self) {}
+
+    fn after(&mut self) -> Option<impl fmt::Display + '_> {
+        None::<&str>
+    }
+}
+
+/// Prints its contents as TAP comments, prefixing each line with "# ".
+struct Comment<D>(D);
+
+impl<D: fmt::Display> fmt::Display for Comment<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Prefixed<'a, 'b> {
+            f: &'a mut fmt::Formatter<'b>,
+            line_start: bool,
+        }
+        impl fmt::Write for Prefixed<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for line in s.split_inclusive('\n') {
+                    if self.line_start {
+                        self.f.write_str("# ")?;
+                    }
+                    self.f.write_str(line)?;
+                    self.line_start = line.ends_with('\n');
+                }
+                Ok(())
+            }
+        }
+        fmt::write(&mut Prefixed { f, line_start: true }, format_args!("{}", self.0))
+    }
+}
+
+/// Run `tests` in order, printing the results.  Returns once they've all
+/// passed.  If a test fails, it panics, and `report_panic` reports it.
+pub fn run(tests: &'static [KTest]) {
+    run_checked(tests, &mut ());
+}
+
+/// As `run`, but a test that `check` finds leaked fails too.  The rest are
+/// still run, then, if any leaked, this panics.
+pub fn run_checked(tests: &'static [KTest], check: &mut impl LeakCheck) {
+    println!("TAP version 13");
+    println!("1..{}", tests.len());
+    TOTAL.store(tests.len(), Ordering::Relaxed);
+    let mut leaked = 0;
+    for (i, test) in tests.iter().enumerate() {
+        if !run_leak_checked(i + 1, test, check) {
+            leaked += 1;
+        }
+    }
+    println!("# passed {} of {}", tests.len() - leaked, tests.len());
+    if leaked > 0 {
+        panic!("{leaked} of {} ktests leaked", tests.len());
+    }
+}
+
+/// Run `test`, printing its result, with what it leaked, if anything.
+/// Returns whether it passed.
+fn run_leak_checked(num: usize, test: &'static KTest, check: &mut impl LeakCheck) -> bool {
+    check.before();
+    run_one(num, test);
+    let leaks = check.after();
+    println!("{}", TestLine { ok: leaks.is_none(), num, test });
+    if let Some(leaks) = &leaks {
+        print!("{}", Comment(leaks));
+    }
+    leaks.is_none()
+}
+
+/// Run `test`, noting it's running for `report_panic`.
+fn run_one(num: usize, test: &'static KTest) {
+    RUNNING_NUM.store(num, Ordering::Relaxed);
+    RUNNING.store(test as *const KTest as *mut KTest, Ordering::Release);
+    (test.func)();
+    RUNNING.store(null_mut(), Ordering::Release);
+}
+
+/// Returns the number and the test `run` is running, if any.
+pub fn running() -> Option<(usize, &'static KTest)> {
+    let test = RUNNING.load(Ordering::Acquire);
+    // Safety: run only stores references to its 'static tests
+    let test = unsafe { test.as_ref() }?;
+    Some((RUNNING_NUM.load(Ordering::Relaxed), test))
+}
+
+/// Report the test `run` is running as failed, for the panic handler, with
+/// how many weren't run.  Does nothing if no test is running.
+pub fn report_panic() {
+    if let Some((num, test)) = running() {
+        println!("{}", TestLine { ok: false, num, test });
+        let total = TOTAL.load(Ordering::Relaxed);
+        println!("# panicked in {}, passed {} of {total}", test.name, num - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TESTS: [KTest; 2] = [
+        KTest { name: "first", func: || {} },
+        KTest { name: "second", func: || assert_eq!(running().unwrap().0, 2) },
+    ];
+
+    #[test]
+    fn running_is_set_only_while_running() {
+        run_one(2, &TESTS[1]);
+        assert!(running().is_none());
+    }
+
+    /// Counts snapshots, and finds a leak after the second test.
+    struct SecondLeaks(usize);
+
+    impl LeakCheck for SecondLeaks {
+        fn before(&mut self) {
+            self.0 += 1;
+        }
+
+        fn after(&mut self) -> Option<impl fmt::Display + '_> {
+            (self.0 == 2).then(|| format!("test {}\nsecond line\n", self.0))
+        }
+    }
+
+    #[test]
+    fn leaks_fail_tests() {
+        let mut check = SecondLeaks(0);
+        assert!(run_leak_checked(1, &TESTS[0], &mut check));
+        assert!(!run_leak_checked(1, &TESTS[0], &mut check));
+        assert!(run_leak_checked(1, &TESTS[0], &mut check));
+        assert!(running().is_none());
+    }
+
+    #[test]
+    fn comments() {
+        let leaks = "test 2\nsecond line\n";
+        assert_eq!(Comment(leaks).to_string(), "# test 2\n# second line\n");
+        assert_eq!(Comment(format_args!("a{}b\nc", '\n')).to_string(), "# a\n# b\n# c");
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(TestLine { ok: true, num: 1, test: &TESTS[0] }.to_string(), "ok 1 - first");
+        assert_eq!(
+            TestLine { ok: false, num: 2, test: &TESTS[1] }.to_string(),
+            "not ok 2 - second"
+        );
+    }
+}
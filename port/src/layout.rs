@@ -0,0 +1,292 @@
+/// layout describes the kernel virtual address space as a table of named
+/// regions, which each arch defines in its param.rs.  The code using each
+/// region takes its bounds from the table, so changing the layout is an edit
+/// to one file, and `check` catches regions that overlap, or that can't be
+/// mapped with the page size they're meant to use.  The arch registers its
+/// table with `set_layout` at boot, after checking it, so that it can be
+/// listed by `layout` and printed with `report`.
+use crate::mem::{VirtAddr, VirtRange};
+use crate::physmap::Size;
+use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// What a region of the kernel address space is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The kernel image, at its link address.
+    KernelImage,
+    /// Device registers mapped by the early page tables, before vmap.
+    EarlyMmio,
+    /// All of physical RAM, at a fixed offset.
+    DirectMap,
+    /// Mappings allocated at runtime, e.g. of device registers.
+    Vmap,
+    /// Code aliased while the mapping of the kernel text is replaced.
+    Trampoline,
+    Heap,
+    Stacks,
+}
+
+impl RegionKind {
+    pub const fn name(self) -> &'static str {
+        match self {
+            RegionKind::KernelImage => "kernel image",
+            RegionKind::EarlyMmio => "early mmio",
+            RegionKind::DirectMap => "direct map",
+            RegionKind::Vmap => "vmap",
+            RegionKind::Trampoline => "trampoline",
+            RegionKind::Heap => "heap",
+            RegionKind::Stacks => "stacks",
+        }
+    }
+}
+
+/// A region of kernel address space, and the largest page size it's mapped
+/// with, which it must be aligned to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub start: usize,
+    pub size: usize,
+    pub page_size: usize,
+}
+
+impl Region {
+    pub const fn new(kind: RegionKind, start: usize, size: usize, page_size: usize) -> Self {
+        Self { kind, start, size, page_size }
+    }
+
+    /// The address just past the region.  Regions can't reach the top of the
+    /// address space, so it doesn't overflow in a checked layout.
+    pub const fn end(&self) -> usize {
+        self.start.wrapping_add(self.size)
+    }
+
+    pub fn range(&self) -> VirtRange {
+        VirtRange::with_len(VirtAddr::new(self.start), self.size)
+    }
+
+    pub fn contains(&self, va: usize) -> bool {
+        va.wrapping_sub(self.start) < self.size
+    }
+
+    /// Check that `range`, holding `what`, is within the region.
+    pub fn check_contains(&self, what: &'static str, range: &VirtRange) -> Result<(), LayoutError> {
+        let (start, end) = (range.start().addr(), range.end().addr());
+        if start < self.start || end.wrapping_sub(self.start) > self.size {
+            return Err(LayoutError::Outside(what, self.kind));
+        }
+        Ok(())
+    }
+
+    /// The address just past the region, or None if it overflows.
+    fn checked_end(&self) -> Option<usize> {
+        self.start.checked_add(self.size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    Empty(RegionKind),
+    Overflow(RegionKind),
+    Misaligned(RegionKind),
+    Duplicate(RegionKind),
+    Overlap(RegionKind, RegionKind),
+    Outside(&'static str, RegionKind),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Empty(kind) => write!(f, "{} region is empty", kind.name()),
+            LayoutError::Overflow(kind) => {
+                write!(f, "{} region runs past the address space", kind.name())
+            }
+            LayoutError::Misaligned(kind) => {
+                write!(f, "{} region isn't aligned to its page size", kind.name())
+            }
+            LayoutError::Duplicate(kind) => write!(f, "{} region listed twice", kind.name()),
+            LayoutError::Overlap(a, b) => {
+                write!(f, "{} and {} regions overlap", a.name(), b.name())
+            }
+            LayoutError::Outside(what, kind) => {
+                write!(f, "{what} isn't within the {} region", kind.name())
+            }
+        }
+    }
+}
+
+/// Check that each region of `layout` is non-empty, has a power of two page
+/// size its start and size are multiples of, and doesn't overlap any other
+/// region, and that no kind of region is listed twice.
+pub fn check(layout: &[Region]) -> Result<(), LayoutError> {
+    for (i, region) in layout.iter().enumerate() {
+        if region.size == 0 {
+            return Err(LayoutError::Empty(region.kind));
+        }
+        let end = region.checked_end().ok_or(LayoutError::Overflow(region.kind))?;
+        if !region.page_size.is_power_of_two()
+            || !region.start.is_multiple_of(region.page_size)
+            || !region.size.is_multiple_of(region.page_size)
+        {
+            return Err(LayoutError::Misaligned(region.kind));
+        }
+        for other in &layout[..i] {
+            if other.kind == region.kind {
+                return Err(LayoutError::Duplicate(region.kind));
+            }
+            if region.start < other.end() && other.start < end {
+                return Err(LayoutError::Overlap(other.kind, region.kind));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The table given to `set_layout`.
+static LAYOUT: AtomicPtr<Region> = AtomicPtr::new(null_mut());
+static LAYOUT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the arch's layout table, which should have been checked with
+/// `check`.
+pub fn set_layout(layout: &'static [Region]) {
+    LAYOUT_LEN.store(layout.len(), Ordering::Relaxed);
+    LAYOUT.store(layout.as_ptr() as *mut Region, Ordering::Release);
+}
+
+/// Return the registered layout table, or an empty one until `set_layout`.
+pub fn layout() -> &'static [Region] {
+    let ptr = LAYOUT.load(Ordering::Acquire);
+    if ptr.is_null() {
+        return &[];
+    }
+    // Safety: set_layout stored a 'static slice, and the length before the
+    // pointer.
+    unsafe { core::slice::from_raw_parts(ptr, LAYOUT_LEN.load(Ordering::Relaxed)) }
+}
+
+/// Return the registered region of `kind`, if there is one.
+pub fn region(kind: RegionKind) -> Option<Region> {
+    layout().iter().find(|region| region.kind == kind).copied()
+}
+
+/// Lists the regions of a layout in address order, a line each, with their
+/// names, sizes and page sizes.
+pub struct Report<'a>(pub &'a [Region]);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Kernel virtual memory layout:")?;
+        let key = |region: &Region| (region.start, region.kind as u8);
+        let mut last = None;
+        // The tables are short, so find each region in turn rather than sort
+        while let Some(region) =
+            self.0.iter().filter(|r| last < Some(key(r))).min_by_key(|r| key(r))
+        {
+            writeln!(
+                f,
+                "  {} {:>10}  {} ({} pages)",
+                region.range(),
+                Size(region.size),
+                region.kind.name(),
+                Size(region.page_size)
+            )?;
+            last = Some(key(region));
+        }
+        Ok(())
+    }
+}
+
+/// Return a report of the registered layout.
+pub fn report() -> Report<'static> {
+    Report(layout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K};
+
+    const IMAGE: Region =
+        Region::new(RegionKind::KernelImage, 0xffff_8000_0000_0000, 1 << 30, PAGE_SIZE_2M);
+    const DMAP: Region =
+        Region::new(RegionKind::DirectMap, 0xffff_a000_0000_0000, 1 << 40, PAGE_SIZE_1G);
+    const VMAP: Region =
+        Region::new(RegionKind::Vmap, 0xffff_c000_0000_0000, 1 << 30, PAGE_SIZE_2M);
+    const TRAMPOLINE: Region =
+        Region::new(RegionKind::Trampoline, VMAP.end(), PAGE_SIZE_4K, PAGE_SIZE_4K);
+
+    #[test]
+    fn disjoint_aligned_layout_is_valid() {
+        assert_eq!(check(&[VMAP, IMAGE, TRAMPOLINE, DMAP]), Ok(()));
+        assert_eq!(check(&[]), Ok(()));
+    }
+
+    #[test]
+    fn bad_layouts_are_rejected() {
+        let empty = Region { size: 0, ..VMAP };
+        assert_eq!(check(&[IMAGE, empty]), Err(LayoutError::Empty(RegionKind::Vmap)));
+
+        let top = Region { start: 0xffff_ffff_8000_0000, ..VMAP };
+        assert_eq!(check(&[top]), Ok(()));
+        let past_top = Region { start: 0xffff_ffff_c000_0000, ..VMAP };
+        assert_eq!(check(&[past_top]), Err(LayoutError::Overflow(RegionKind::Vmap)));
+
+        let unaligned = Region { start: VMAP.start + PAGE_SIZE_4K, ..VMAP };
+        assert_eq!(check(&[unaligned]), Err(LayoutError::Misaligned(RegionKind::Vmap)));
+        let short = Region { size: PAGE_SIZE_1G + PAGE_SIZE_2M, ..DMAP };
+        assert_eq!(check(&[short]), Err(LayoutError::Misaligned(RegionKind::DirectMap)));
+        let odd_pages = Region { page_size: 3 * PAGE_SIZE_4K, ..TRAMPOLINE };
+        assert_eq!(check(&[odd_pages]), Err(LayoutError::Misaligned(RegionKind::Trampoline)));
+
+        assert_eq!(check(&[VMAP, IMAGE, VMAP]), Err(LayoutError::Duplicate(RegionKind::Vmap)));
+
+        let big_dmap = Region { size: VMAP.start - DMAP.start + PAGE_SIZE_1G, ..DMAP };
+        assert_eq!(
+            check(&[IMAGE, VMAP, big_dmap]),
+            Err(LayoutError::Overlap(RegionKind::Vmap, RegionKind::DirectMap))
+        );
+        let inside = Region { start: VMAP.start + PAGE_SIZE_2M, size: PAGE_SIZE_4K, ..TRAMPOLINE };
+        assert_eq!(
+            check(&[VMAP, inside]),
+            Err(LayoutError::Overlap(RegionKind::Vmap, RegionKind::Trampoline))
+        );
+    }
+
+    #[test]
+    fn report_in_address_order() {
+        let report = Report(&[VMAP, TRAMPOLINE, IMAGE]).to_string();
+        assert_eq!(
+            report,
+            "Kernel virtual memory layout:\n\
+             \x20 0xffff800000000000..0xffff800040000000      1 GiB  kernel image (2 MiB pages)\n\
+             \x20 0xffffc00000000000..0xffffc00040000000      1 GiB  vmap (2 MiB pages)\n\
+             \x20 0xffffc00040000000..0xffffc00040001000      4 KiB  trampoline (4 KiB pages)\n"
+        );
+    }
+
+    #[test]
+    fn ranges_within_region() {
+        let stacks = VirtRange::with_len(VirtAddr::new(IMAGE.start + 0x10_0000), 0x5000);
+        assert_eq!(IMAGE.check_contains("stacks", &stacks), Ok(()));
+        assert_eq!(IMAGE.check_contains("image", &IMAGE.range()), Ok(()));
+
+        let past_end = VirtRange::with_len(VirtAddr::new(IMAGE.end() - 0x1000), 0x2000);
+        let below = VirtRange::with_len(VirtAddr::new(IMAGE.start - 0x1000), 0x2000);
+        for range in [past_end, below] {
+            assert_eq!(
+                IMAGE.check_contains("stacks", &range),
+                Err(LayoutError::Outside("stacks", RegionKind::KernelImage))
+            );
+        }
+    }
+
+    #[test]
+    fn region_contains() {
+        assert!(TRAMPOLINE.contains(VMAP.end()));
+        assert!(!TRAMPOLINE.contains(VMAP.end() - 1));
+        assert!(!TRAMPOLINE.contains(TRAMPOLINE.end()));
+        assert_eq!(TRAMPOLINE.range().size(), PAGE_SIZE_4K);
+    }
+}
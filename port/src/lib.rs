@@ -9,11 +9,56 @@
 
 extern crate alloc;
 
+pub mod addrmap;
+pub mod alarm;
 pub mod allocator;
+pub mod backtrace;
 pub mod bitmapalloc;
+pub mod bootargs;
+pub mod buddyalloc;
+pub mod cache;
+pub mod counters;
+pub mod cpio;
 pub mod dat;
+pub mod debug;
 pub mod devcons;
+pub mod digest;
+#[cfg(test)]
+mod fakemem;
 pub mod fdt;
+#[cfg(any(test, feature = "fdt_builder"))]
+pub mod fdtbuilder;
+pub mod font;
+pub mod framebuffer;
+pub mod framerefs;
+pub mod heapcheck;
+pub mod initrd;
+pub mod interrupts;
+pub mod ktest;
+pub mod layout;
+pub mod lockdebug;
+pub mod log;
 pub mod mcslock;
 pub mod mem;
+pub mod memaccount;
+pub mod memfill;
+pub mod memsnapshot;
+pub mod memtest;
+pub mod mmio;
+pub mod oncelock;
 pub mod pagealloc;
+pub mod pagepoison;
+pub mod percpu;
+pub mod physaccess;
+pub mod physmap;
+pub mod power;
+pub mod qemu;
+pub mod regionalloc;
+pub mod rwlock;
+pub mod sharedmaps;
+pub mod slab;
+pub mod symbols;
+pub mod tableframes;
+pub mod time;
+pub mod vaalloc;
+pub mod zones;
@@ -0,0 +1,428 @@
+/// lockdebug checks how locks are used, in debug builds.  Each lock has a
+/// `Tracker`, which records the core holding the lock, and where it was
+/// taken.  Each core keeps a small stack of the locks it holds, so that:
+///
+/// - taking a lock the core already holds panics straight away, with both
+///   locations, rather than hanging;
+/// - waiting too long for a lock prints a warning, with where the holder
+///   took it;
+/// - the panic handler can print the locks each core holds, with
+///   `HeldLocks`.
+///
+/// Spin warnings are printed with `early_print`, as the console itself may
+/// be the lock in question.  In release builds, all of this compiles away.
+///
+/// Cores are identified with `percpu::core_id`.  The arch gives the stacks,
+/// one for each of its cores, with `set_held_stacks`, before taking any locks.
+/// Until it does, nothing is tracked.
+use core::fmt;
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use core::ptr::null_mut;
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(debug_assertions)]
+use crate::percpu::core_id;
+#[cfg(all(debug_assertions, not(test)))]
+use core::sync::atomic::AtomicUsize;
+
+/// Number of locks tracked per core.  Locks beyond this aren't checked.
+#[cfg(debug_assertions)]
+const MAX_HELD: usize = 8;
+
+/// Spins waiting for a lock between warnings.
+#[cfg(debug_assertions)]
+const SPIN_WARN: u64 = 100_000_000;
+
+/// A lock held by a core.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct Held {
+    lock: usize, // Address of the lock's tracker
+    name: &'static str,
+    location: &'static Location<'static>, // Where the lock was taken
+}
+
+/// Locks held by a core, in the order they were taken.
+#[cfg(debug_assertions)]
+struct HeldStack {
+    held: [Option<Held>; MAX_HELD],
+    len: usize, // May exceed MAX_HELD, if more are held than are tracked
+}
+
+#[cfg(debug_assertions)]
+impl HeldStack {
+    const fn new() -> Self {
+        Self { held: [None; MAX_HELD], len: 0 }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Held> {
+        self.held.iter().flatten()
+    }
+
+    fn find(&self, lock: usize) -> Option<Held> {
+        self.iter().find(|h| h.lock == lock).copied()
+    }
+
+    fn push(&mut self, held: Held) {
+        if let Some(slot) = self.held.get_mut(self.len) {
+            *slot = Some(held);
+        }
+        self.len += 1;
+    }
+
+    /// Remove the most recently taken entry for `lock`.  Locks needn't be
+    /// released in the order they were taken.
+    fn remove(&mut self, lock: usize) {
+        let tracked = self.len.min(MAX_HELD);
+        if let Some(i) = (0..tracked).rev().find(|&i| self.held[i].is_some_and(|h| h.lock == lock))
+        {
+            self.held.copy_within(i + 1..tracked, i);
+            self.held[tracked - 1] = None;
+        }
+        self.len = self.len.saturating_sub(1);
+    }
+}
+
+/// A core's stack of held locks, for `set_held_stacks`, e.g.
+///
+/// ```ignore
+/// static HELD_LOCKS: [CoreHeld; MAX_CORES] = [const { CoreHeld::new() }; MAX_CORES];
+/// ```
+///
+/// Zero sized in release builds, and in host tests, where each thread has
+/// its own stack.
+pub struct CoreHeld(#[cfg(all(debug_assertions, not(test)))] core::cell::UnsafeCell<HeldStack>);
+
+impl CoreHeld {
+    pub const fn new() -> Self {
+        Self(
+            #[cfg(all(debug_assertions, not(test)))]
+            core::cell::UnsafeCell::new(HeldStack::new()),
+        )
+    }
+}
+
+impl Default for CoreHeld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Safety: each core only changes its own stack, with interrupts disabled.
+// Other cores only read them, when printing `HeldLocks`.
+unsafe impl Sync for CoreHeld {}
+
+/// The stacks given to `set_held_stacks`, indexed by core id.
+#[cfg(all(debug_assertions, not(test)))]
+static HELD: AtomicPtr<CoreHeld> = AtomicPtr::new(null_mut());
+#[cfg(all(debug_assertions, not(test)))]
+static HELD_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Track the locks each core holds in `stacks`, indexed by core id, so
+/// there should be one for each core the arch starts.  Call once, before
+/// taking any locks.
+#[allow(unused_variables)]
+pub fn set_held_stacks(stacks: &'static [CoreHeld]) {
+    #[cfg(all(debug_assertions, not(test)))]
+    {
+        HELD_LEN.store(stacks.len(), Ordering::Relaxed);
+        HELD.store(stacks.as_ptr().cast_mut(), Ordering::Release);
+    }
+}
+
+/// Return the stacks given to `set_held_stacks`, if they have been.
+#[cfg(all(debug_assertions, not(test)))]
+fn held_stacks() -> Option<&'static [CoreHeld]> {
+    let stacks = HELD.load(Ordering::Acquire);
+    if stacks.is_null() {
+        return None;
+    }
+    // Safety: set_held_stacks stores the pointer to a 'static slice of
+    // HELD_LEN stacks before it publishes it
+    Some(unsafe { core::slice::from_raw_parts(stacks, HELD_LEN.load(Ordering::Relaxed)) })
+}
+
+/// Call `f` with the current core's stack of held locks, or return None if
+/// there aren't any stacks yet.  Panics if the core has no stack.
+#[cfg(all(debug_assertions, not(test)))]
+fn with_held<R>(f: impl FnOnce(&mut HeldStack) -> R) -> Option<R> {
+    let stacks = held_stacks()?;
+    let core = core_id();
+    let Some(held) = stacks.get(core) else {
+        panic!("lockdebug: no held lock stack for core {core}, only {} cores", stacks.len());
+    };
+    let state = crate::interrupts::disable();
+    // Safety: only this core uses its own stack mutably, and an interrupt
+    // handler can't run while it does
+    let r = f(unsafe { &mut *held.0.get() });
+    crate::interrupts::restore(state);
+    Some(r)
+}
+
+#[cfg(all(debug_assertions, test))]
+std::thread_local! {
+    static HELD: core::cell::RefCell<HeldStack> = const { core::cell::RefCell::new(HeldStack::new()) };
+}
+
+#[cfg(all(debug_assertions, test))]
+fn with_held<R>(f: impl FnOnce(&mut HeldStack) -> R) -> Option<R> {
+    Some(HELD.with_borrow_mut(f))
+}
+
+/// Records which core holds a lock, and where it was taken.  Embedded in
+/// each lock.  Zero sized in release builds.
+pub struct Tracker {
+    #[cfg(debug_assertions)]
+    owner: core::sync::atomic::AtomicUsize, // Core holding the lock exclusively, or usize::MAX
+    #[cfg(debug_assertions)]
+    location: AtomicPtr<Location<'static>>, // Where the owner took the lock
+}
+
+#[cfg(debug_assertions)]
+impl Tracker {
+    const NO_OWNER: usize = usize::MAX;
+
+    pub const fn new() -> Self {
+        Self {
+            owner: core::sync::atomic::AtomicUsize::new(Self::NO_OWNER),
+            location: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Call before waiting for the lock.  Panics if the current core already
+    /// holds it, as waiting would never end.
+    pub fn check_acquire(&self, name: &str, location: &'static Location<'static>) {
+        if let Some(held) = with_held(|h| h.find(self.addr())).flatten() {
+            panic!(
+                "lock {name}: taken again on core {} at {location}, already held from {}",
+                core_id(),
+                held.location
+            );
+        }
+    }
+
+    /// Call on each spin waiting for the lock.  Warns every `SPIN_WARN`
+    /// spins, with where the lock was taken, if a core holds it exclusively.
+    pub fn spin(&self, name: &str, spins: &mut Spins) {
+        spins.0 += 1;
+        if spins.0 % SPIN_WARN != 0 {
+            return;
+        }
+        let owner = self.owner.load(Ordering::Relaxed);
+        let location = self.location.load(Ordering::Relaxed);
+        if owner == Self::NO_OWNER || location.is_null() {
+            crate::early_println!(
+                "lock {name}: core {} waited {} spins, held shared",
+                core_id(),
+                spins.0
+            );
+        } else {
+            // Safety: only acquired stores to location, and it stores a
+            // &'static Location
+            let location = unsafe { &*location };
+            crate::early_println!(
+                "lock {name}: core {} waited {} spins, held by core {owner} from {location}",
+                core_id(),
+                spins.0
+            );
+        }
+    }
+
+    /// Call once the lock is taken.  `exclusive` is false for shared, i.e.
+    /// reader, locks, which aren't recorded as owned by the core.
+    pub fn acquired(
+        &self,
+        name: &'static str,
+        location: &'static Location<'static>,
+        exclusive: bool,
+    ) {
+        if exclusive {
+            self.owner.store(core_id(), Ordering::Relaxed);
+            self.location.store(location as *const _ as *mut _, Ordering::Relaxed);
+        }
+        with_held(|h| h.push(Held { lock: self.addr(), name, location }));
+    }
+
+    /// Call before the lock is released.
+    pub fn released(&self, exclusive: bool) {
+        if exclusive {
+            self.owner.store(Self::NO_OWNER, Ordering::Relaxed);
+            self.location.store(null_mut(), Ordering::Relaxed);
+        }
+        with_held(|h| h.remove(self.addr()));
+    }
+
+    /// Return true if the current core holds the lock.
+    pub fn held_here(&self) -> bool {
+        with_held(|h| h.find(self.addr()).is_some()).unwrap_or(false)
+    }
+}
+
+#[cfg(not(debug_assertions))]
+impl Tracker {
+    pub const fn new() -> Self {
+        Self {}
+    }
+
+    #[inline(always)]
+    pub fn check_acquire(&self, _name: &str, _location: &'static Location<'static>) {}
+
+    #[inline(always)]
+    pub fn spin(&self, _name: &str, _spins: &mut Spins) {}
+
+    #[inline(always)]
+    pub fn acquired(
+        &self,
+        _name: &'static str,
+        _location: &'static Location<'static>,
+        _exclusive: bool,
+    ) {
+    }
+
+    #[inline(always)]
+    pub fn released(&self, _exclusive: bool) {}
+
+    #[inline(always)]
+    pub fn held_here(&self) -> bool {
+        false
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Count of spins waiting for a lock, for `Tracker::spin`.  Zero sized in
+/// release builds.
+#[derive(Default)]
+pub struct Spins(#[cfg(debug_assertions)] u64);
+
+/// Lists the locks each core holds, a line per core, e.g. "core 0 holds:
+/// cons, pagealloc".  Cores holding nothing are left out, so this is empty
+/// in release builds.  Other cores may be taking and releasing locks while
+/// this is printed, so their lists are best effort.
+pub struct HeldLocks;
+
+#[cfg(debug_assertions)]
+fn fmt_held(f: &mut fmt::Formatter, core: usize, held: &HeldStack) -> fmt::Result {
+    if held.len == 0 {
+        return Ok(());
+    }
+    write!(f, "core {core} holds:")?;
+    for (i, h) in held.iter().enumerate() {
+        let sep = if i == 0 { " " } else { ", " };
+        write!(f, "{sep}{} (from {})", h.name, h.location)?;
+    }
+    if held.len > MAX_HELD {
+        write!(f, ", and {} more", held.len - MAX_HELD)?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for HeldLocks {
+    #[cfg(all(debug_assertions, not(test)))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (core, held) in held_stacks().unwrap_or_default().iter().enumerate() {
+            // Safety: see CoreHeld
+            fmt_held(f, core, unsafe { &*held.0.get() })?;
+        }
+        Ok(())
+    }
+
+    #[cfg(all(debug_assertions, test))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        HELD.with_borrow(|held| fmt_held(f, core_id(), held))
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use crate::mcslock::{Lock, LockNode};
+    use crate::rwlock::RwLock;
+
+    #[test]
+    fn held_locks_are_listed() {
+        let a = Lock::new("a", ());
+        let b = RwLock::new("b", ());
+        let node = LockNode::new();
+        let ga = a.lock(&node);
+        let gb = b.read();
+        assert!(a.held_here());
+        let held = HeldLocks.to_string();
+        assert!(held.starts_with(&format!("core {} holds: a (from ", core_id())), "{held}");
+        assert!(held.contains(", b (from "), "{held}");
+
+        // Released out of order
+        drop(ga);
+        assert!(!a.held_here());
+        assert!(!HeldLocks.to_string().contains(" a (from "));
+        drop(gb);
+        assert_eq!(HeldLocks.to_string(), "");
+    }
+
+    #[test]
+    fn lock_held_elsewhere_isnt_held_here() {
+        let lock = std::sync::Arc::new(Lock::new("elsewhere", ()));
+        let node = LockNode::new();
+        let _guard = lock.lock(&node);
+        let other = lock.clone();
+        std::thread::spawn(move || {
+            assert!(!other.held_here());
+            let node = LockNode::new();
+            assert!(other.try_lock(&node).is_none());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock again: taken again on core")]
+    fn taking_held_lock_panics() {
+        let lock = Lock::new("again", ());
+        let node1 = LockNode::new();
+        let node2 = LockNode::new();
+        let _g1 = lock.lock(&node1);
+        let _g2 = lock.lock(&node2);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock again: taken again on core")]
+    fn writing_while_reading_panics() {
+        let lock = RwLock::new("again", ());
+        let _r = lock.read();
+        let _w = lock.write();
+    }
+
+    #[test]
+    fn stack_tracks_more_than_fit() {
+        let mut stack = HeldStack::new();
+        let loc = Location::caller();
+        for lock in 0..MAX_HELD + 2 {
+            stack.push(Held { lock, name: "x", location: loc });
+        }
+        stack.remove(MAX_HELD + 1);
+        stack.remove(0);
+        assert_eq!(stack.len, MAX_HELD);
+        assert!(stack.find(0).is_none());
+        assert!(stack.find(1).is_some());
+        for lock in 1..MAX_HELD + 1 {
+            stack.remove(lock);
+        }
+        assert_eq!(stack.len, 0);
+        assert!(stack.iter().next().is_none());
+    }
+}
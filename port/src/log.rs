@@ -0,0 +1,150 @@
+/// log provides leveled console output: `error!`, `warn!`, `info!`, `debug!`
+/// and `trace!` print a line prefixed with the level and the module path, if
+/// the level is at or below the maximum.  The maximum can be changed at
+/// runtime, e.g. from the kernel command line, up to `STATIC_MAX_LEVEL`.
+/// Levels above `STATIC_MAX_LEVEL` are compiled out, as the check against it
+/// is constant, and the arguments are only formatted if the line is printed.
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Importance of a log line, from most to least important.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parse a level from its name, in any case, or its number, e.g. for a
+    /// loglevel= kernel argument.
+    pub fn parse(s: &str) -> Option<Level> {
+        if let Ok(n) = s.parse::<u8>() {
+            return (1..=5).contains(&n).then(|| Self::from_u8(n));
+        }
+        Self::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(s))
+    }
+
+    fn from_u8(n: u8) -> Level {
+        Self::ALL[(n.clamp(1, 5) - 1) as usize]
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// Most verbose level compiled in.  Trace lines are only compiled in with the
+/// log_trace feature.
+pub const STATIC_MAX_LEVEL: Level =
+    if cfg!(feature = "log_trace") { Level::Trace } else { Level::Debug };
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Return the most verbose level printed.
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Set the most verbose level printed, limited to `STATIC_MAX_LEVEL`.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level.min(STATIC_MAX_LEVEL) as u8, Ordering::Relaxed);
+}
+
+/// Return true if lines at `level` are printed.
+#[inline(always)]
+pub fn enabled(level: Level) -> bool {
+    level <= STATIC_MAX_LEVEL && level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Print a line at `level`.  Use the macros instead, which check the level
+/// before formatting anything.  Host tests have no console, so print to
+/// stdout.
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    #[cfg(not(test))]
+    crate::devcons::print(format_args!("{level:<5} {module}: {args}\n"));
+    #[cfg(test)]
+    std::println!("{level:<5} {module}: {args}");
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::log(level, module_path!(), format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_level() {
+        assert_eq!(Level::parse("debug"), Some(Level::Debug));
+        assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+        assert_eq!(Level::parse("1"), Some(Level::Error));
+        assert_eq!(Level::parse("5"), Some(Level::Trace));
+        assert_eq!(Level::parse("0"), None);
+        assert_eq!(Level::parse("verbose"), None);
+        assert_eq!(format!("[{:<5}]", Level::Info), "[INFO ]");
+    }
+
+    #[test]
+    fn max_level_limits_enabled() {
+        assert!(enabled(Level::Error));
+        set_max_level(Level::Warn);
+        assert_eq!(max_level(), Level::Warn);
+        assert!(enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+        set_max_level(Level::Trace);
+        assert_eq!(max_level(), STATIC_MAX_LEVEL);
+        assert!(enabled(Level::Debug));
+        assert_eq!(enabled(Level::Trace), cfg!(feature = "log_trace"));
+        set_max_level(Level::Info);
+    }
+}
@@ -10,10 +10,15 @@
 use core::cell::UnsafeCell;
 use core::hint;
 use core::marker::{Send, Sized, Sync};
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use core::panic::Location;
 use core::ptr;
 use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
+use crate::interrupts;
+use crate::lockdebug::{Spins, Tracker};
+
 /// Represents a node in the lock structure.  Note, is cacheline
 /// aligned.
 #[repr(align(64))]
@@ -34,18 +39,23 @@ impl Default for LockNode {
     }
 }
 
-/// An MCS lock.
+/// An MCS lock.  In debug builds, taking a lock the current core already
+/// holds panics, rather than hanging; see `lockdebug`.
 pub struct MCSLock {
-    _name: &'static str,
+    name: &'static str,
     queue: AtomicPtr<LockNode>,
+    tracker: Tracker,
 }
 
 impl MCSLock {
     pub const fn new(name: &'static str) -> MCSLock {
-        MCSLock { _name: name, queue: AtomicPtr::new(ptr::null_mut()) }
+        MCSLock { name, queue: AtomicPtr::new(ptr::null_mut()), tracker: Tracker::new() }
     }
 
+    #[cfg_attr(debug_assertions, track_caller)]
     pub fn lock<'a>(&self, node: &'a LockNode) -> &'a LockNode {
+        let location = Location::caller();
+        self.tracker.check_acquire(self.name, location);
         node.next.store(ptr::null_mut(), Ordering::Release);
         node.locked.store(false, Ordering::Release);
         let p = node as *const _ as *mut _;
@@ -54,14 +64,33 @@ impl MCSLock {
             let predecessor = unsafe { &*predecessor };
             node.locked.store(true, Ordering::Release);
             predecessor.next.store(p, Ordering::Release);
+            let mut spins = Spins::default();
             while node.locked.load(Ordering::Acquire) {
+                self.tracker.spin(self.name, &mut spins);
                 hint::spin_loop();
             }
         }
+        self.tracker.acquired(self.name, location, true);
         node
     }
 
+    /// Take the lock if it's free, without waiting.  Returns None if the lock
+    /// is already held.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock<'a>(&self, node: &'a LockNode) -> Option<&'a LockNode> {
+        let location = Location::caller();
+        node.next.store(ptr::null_mut(), Ordering::Release);
+        node.locked.store(false, Ordering::Release);
+        let p = node as *const _ as *mut _;
+        self.queue
+            .compare_exchange(ptr::null_mut(), p, Ordering::AcqRel, Ordering::Relaxed)
+            .ok()?;
+        self.tracker.acquired(self.name, location, true);
+        Some(node)
+    }
+
     pub fn unlock(&self, node: &LockNode) {
+        self.tracker.released(true);
         if node.next.load(Ordering::Acquire).is_null() {
             let p = node as *const _ as *mut _;
             if self
@@ -94,10 +123,35 @@ impl<T> Lock<T> {
         Lock { lock: UnsafeCell::new(MCSLock::new(name)), data: UnsafeCell::new(data) }
     }
 
+    #[cfg_attr(debug_assertions, track_caller)]
     pub fn lock<'a>(&'a self, node: &'a LockNode) -> LockGuard<'a, T> {
         let node = unsafe { &mut *self.lock.get() }.lock(node);
         LockGuard { lock: &self.lock, node, data: unsafe { &mut *self.data.get() } }
     }
+
+    /// Take the lock if it's free, without waiting.  Useful where blocking
+    /// could deadlock, e.g. in a panic handler.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock<'a>(&'a self, node: &'a LockNode) -> Option<LockGuard<'a, T>> {
+        let node = unsafe { &mut *self.lock.get() }.try_lock(node)?;
+        Some(LockGuard { lock: &self.lock, node, data: unsafe { &mut *self.data.get() } })
+    }
+
+    /// Disable interrupts on this core, then take the lock, restoring the
+    /// previous interrupt state once the lock is released.  Use this for
+    /// locks also taken by interrupt handlers, so a handler can't spin on a
+    /// lock held by the code it interrupted.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn lock_irqsave<'a>(&'a self, node: &'a LockNode) -> IrqSaveLockGuard<'a, T> {
+        let state = interrupts::disable();
+        IrqSaveLockGuard { guard: ManuallyDrop::new(self.lock(node)), state }
+    }
+
+    /// Return true if the current core holds the lock.  Always false in
+    /// release builds, which don't track lock holders.
+    pub fn held_here(&self) -> bool {
+        unsafe { &*self.lock.get() }.tracker.held_here()
+    }
 }
 
 pub struct LockGuard<'a, T: ?Sized + 'a> {
@@ -124,3 +178,94 @@ impl<T: ?Sized> Drop for LockGuard<'_, T> {
         unsafe { &mut *self.lock.get() }.unlock(self.node);
     }
 }
+
+/// Guard for a lock taken with interrupts disabled, which releases the lock,
+/// then restores the interrupt state.
+pub struct IrqSaveLockGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<LockGuard<'a, T>>,
+    state: usize, // Interrupt state from before the lock was taken
+}
+
+impl<T> Deref for IrqSaveLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSaveLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSaveLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: the guard isn't used again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        interrupts::restore(self.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::Interrupts;
+    use std::cell::RefCell;
+
+    #[test]
+    fn try_lock_fails_while_held() {
+        let lock = Lock::new("test", 1);
+        let node = LockNode::new();
+        let guard = lock.lock(&node);
+        let other = LockNode::new();
+        assert!(lock.try_lock(&other).is_none());
+        drop(guard);
+
+        let mut guard = lock.try_lock(&other).unwrap();
+        *guard += 1;
+        drop(guard);
+        assert_eq!(*lock.lock(&node), 2);
+    }
+
+    static IRQ_LOCK: Lock<u32> = Lock::new("irq", 0);
+
+    thread_local! {
+        static EVENTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn event(what: &str) {
+        let node = LockNode::new();
+        let held = IRQ_LOCK.try_lock(&node).is_none();
+        EVENTS.with_borrow_mut(|events| events.push(format!("{what} held:{held}")));
+    }
+
+    /// Records when interrupts are disabled and restored, and whether
+    /// IRQ_LOCK was held at the time, for this thread.
+    struct FakeInterrupts;
+
+    impl Interrupts for FakeInterrupts {
+        fn disable() -> usize {
+            event("disable");
+            7
+        }
+
+        fn restore(state: usize) {
+            event(&format!("restore {state}"));
+        }
+    }
+
+    #[test]
+    fn lock_irqsave_restores_after_unlock() {
+        interrupts::init::<FakeInterrupts>();
+        {
+            let node = LockNode::new();
+            let mut guard = IRQ_LOCK.lock_irqsave(&node);
+            *guard += 1;
+            event("locked");
+        }
+        let events = EVENTS.with_borrow_mut(std::mem::take);
+        assert_eq!(events, ["disable held:false", "locked held:true", "restore 7 held:false"]);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::fdt::RegBlock;
+use bitflags::bitflags;
 use core::{
     cmp::{max, min},
     fmt,
@@ -10,6 +11,10 @@ pub const PAGE_SIZE_4K: usize = 4 << 10;
 pub const PAGE_SIZE_2M: usize = 2 << 20;
 pub const PAGE_SIZE_1G: usize = 1 << 30;
 
+/// Width of a formatted virtual address, with its 0x prefix: every hex digit
+/// of the representation, so addresses and ranges line up in columns.
+const VIRT_ADDR_WIDTH: usize = 2 + 2 * size_of::<usize>();
+
 #[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(transparent)]
 pub struct VirtAddr(pub usize);
@@ -22,6 +27,16 @@ impl VirtAddr {
     pub const fn addr(&self) -> usize {
         self.0
     }
+
+    pub const fn round_up(&self, step: usize) -> VirtAddr {
+        assert!(step.is_power_of_two());
+        VirtAddr((self.0 + step - 1) & !(step - 1))
+    }
+
+    pub const fn round_down(&self, step: usize) -> VirtAddr {
+        assert!(step.is_power_of_two());
+        VirtAddr(self.0 & !(step - 1))
+    }
 }
 
 impl ops::Add<usize> for VirtAddr {
@@ -40,13 +55,41 @@ impl ops::Sub<usize> for VirtAddr {
     }
 }
 
-impl fmt::Debug for VirtAddr {
+/// Formats as the address in hex, zero padded to the full width.  The
+/// alternate form, `{:#}`, adds the region of the registered kernel layout
+/// the address is in, and the offset into it, e.g.
+/// `0xffff800000081000 (kernel image+0x81000)`.
+impl fmt::Display for VirtAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "VirtAddr({:#016x})", self.0)?;
+        write!(f, "{:#0width$x}", self.0, width = VIRT_ADDR_WIDTH)?;
+        if f.alternate() {
+            if let Some(region) = crate::layout::layout().iter().find(|r| r.contains(self.0)) {
+                write!(f, " ({}+{:#x})", region.kind.name(), self.0 - region.start)?;
+            }
+        }
         Ok(())
     }
 }
 
+impl fmt::Debug for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VirtAddr({self})")
+    }
+}
+
+impl fmt::LowerHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::UpperHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct VirtRange(pub Range<VirtAddr>);
 
 impl VirtRange {
@@ -66,6 +109,16 @@ impl VirtRange {
     pub fn end(&self) -> VirtAddr {
         self.0.end
     }
+
+    pub fn size(&self) -> usize {
+        self.0.end.addr() - self.0.start.addr()
+    }
+
+    /// Return the range with the start rounded down and the end rounded up to
+    /// multiples of `step`, which must be a power of two.
+    pub fn round_out(&self, step: usize) -> Self {
+        Self(self.start().round_down(step)..self.end().round_up(step))
+    }
 }
 
 impl From<&RegBlock> for VirtRange {
@@ -78,42 +131,51 @@ impl From<&RegBlock> for VirtRange {
 
 impl fmt::Display for VirtRange {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#018x}..{:#018x}", self.0.start.addr(), self.0.end.addr())
+        write!(f, "{}..{}", self.0.start, self.0.end)
     }
 }
 
-#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
+/// The integer physical addresses are held in.  It's independent of the
+/// pointer width, as physical addresses can be wider than virtual ones, e.g.
+/// RISC-V Sv32 has 34 bit physical addresses, so it's 64 bits on every target
+/// so far.
+pub type PAddrRepr = u64;
+
+/// Width of a formatted physical address, as for VIRT_ADDR_WIDTH.
+const PHYS_ADDR_WIDTH: usize = 2 + 2 * size_of::<PAddrRepr>();
+
+#[derive(Clone, Copy, Default, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(transparent)]
-pub struct PhysAddr(pub u64);
+pub struct PhysAddr(pub PAddrRepr);
 
 impl PhysAddr {
-    pub const fn new(value: u64) -> Self {
+    pub const fn new(value: PAddrRepr) -> Self {
         PhysAddr(value)
     }
 
-    pub const fn addr(&self) -> u64 {
+    pub const fn addr(&self) -> PAddrRepr {
         self.0
     }
 
-    pub const fn round_up(&self, step: u64) -> PhysAddr {
+    pub const fn round_up(&self, step: PAddrRepr) -> PhysAddr {
         assert!(step.is_power_of_two());
         PhysAddr((self.0 + step - 1) & !(step - 1))
     }
 
-    pub const fn round_down(&self, step: u64) -> PhysAddr {
+    pub const fn round_down(&self, step: PAddrRepr) -> PhysAddr {
         assert!(step.is_power_of_two());
         PhysAddr(self.0 & !(step - 1))
     }
 
-    pub const fn is_multiple_of(&self, n: u64) -> bool {
+    pub const fn is_multiple_of(&self, n: PAddrRepr) -> bool {
         self.0.is_multiple_of(n)
     }
 }
 
-impl ops::Add<u64> for PhysAddr {
+impl ops::Add<PAddrRepr> for PhysAddr {
     type Output = PhysAddr;
 
-    fn add(self, offset: u64) -> PhysAddr {
+    fn add(self, offset: PAddrRepr) -> PhysAddr {
         PhysAddr(self.0 + offset)
     }
 }
@@ -131,21 +193,40 @@ impl Step for PhysAddr {
     }
 
     fn forward_checked(startpa: Self, count: usize) -> Option<Self> {
-        startpa.0.checked_add(count as u64).map(PhysAddr)
+        startpa.0.checked_add(PAddrRepr::try_from(count).ok()?).map(PhysAddr)
     }
 
     fn backward_checked(startpa: Self, count: usize) -> Option<Self> {
-        startpa.0.checked_sub(count as u64).map(PhysAddr)
+        startpa.0.checked_sub(PAddrRepr::try_from(count).ok()?).map(PhysAddr)
+    }
+}
+
+/// Formats as the address in hex, zero padded to the full width.
+impl fmt::Display for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#0width$x}", self.0, width = PHYS_ADDR_WIDTH)
     }
 }
 
 impl fmt::Debug for PhysAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PhysAddr({:#016x})", self.0)?;
-        Ok(())
+        write!(f, "PhysAddr({self})")
+    }
+}
+
+impl fmt::LowerHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::UpperHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct PhysRange(pub Range<PhysAddr>);
 
 impl PhysRange {
@@ -153,20 +234,25 @@ impl PhysRange {
         Self(start..end)
     }
 
-    pub fn with_end(start: u64, end: u64) -> Self {
+    pub fn with_end(start: PAddrRepr, end: PAddrRepr) -> Self {
         Self(PhysAddr(start)..PhysAddr(end))
     }
 
-    pub fn with_len(start: u64, len: usize) -> Self {
-        Self(PhysAddr(start)..PhysAddr(start + len as u64))
+    /// Panics if the range would wrap past the top of the address space.
+    pub fn with_len(start: PAddrRepr, len: usize) -> Self {
+        Self::with_pa_len(PhysAddr(start), len)
     }
 
+    /// Panics if the range would wrap past the top of the address space.
     pub fn with_pa_len(start: PhysAddr, len: usize) -> Self {
-        Self(start..PhysAddr(start.0 + len as u64))
+        match PhysAddr::forward_checked(start, len) {
+            Some(end) => Self(start..end),
+            None => panic!("mem: range from {start:?} of {len:#x} bytes wraps"),
+        }
     }
 
     #[allow(dead_code)]
-    pub fn offset_addr(&self, offset: u64) -> Option<PhysAddr> {
+    pub fn offset_addr(&self, offset: PAddrRepr) -> Option<PhysAddr> {
         let addr = self.0.start + offset;
         if self.0.contains(&addr) { Some(addr) } else { None }
     }
@@ -179,24 +265,235 @@ impl PhysRange {
         self.0.end
     }
 
+    /// Return the number of bytes in the range, which may be more than a
+    /// usize can hold on 32-bit targets.
+    pub fn len(&self) -> PAddrRepr {
+        self.0.end.addr().saturating_sub(self.0.start.addr())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of bytes in the range, as a usize for sizing slices
+    /// and mappings.  Panics if it doesn't fit, e.g. a range of more than
+    /// 4GiB on a 32-bit target, rather than truncating it.
     pub fn size(&self) -> usize {
-        (self.0.end.addr() - self.0.start.addr()) as usize
+        match self.size_as() {
+            Some(size) => size,
+            None => panic!("mem: size of {self} doesn't fit in a usize"),
+        }
+    }
+
+    fn size_as<T: TryFrom<PAddrRepr>>(&self) -> Option<T> {
+        T::try_from(self.len()).ok()
     }
 
     pub fn step_by_rounded(&self, step_size: usize) -> StepBy<Range<PhysAddr>> {
-        let startpa = self.start().round_down(step_size as u64);
-        let endpa = self.end().round_up(step_size as u64);
+        let step = PAddrRepr::try_from(step_size).expect("step fits in a physical address");
+        let startpa = self.start().round_down(step);
+        let endpa = self.end().round_up(step);
         (startpa..endpa).step_by(step_size)
     }
 
     pub fn add(&self, other: &PhysRange) -> Self {
         Self(min(self.0.start, other.0.start)..max(self.0.end, other.0.end))
     }
+
+    /// Return the range with the start rounded down and the end rounded up to
+    /// multiples of `step`, which must be a power of two.
+    pub fn round_out(&self, step: PAddrRepr) -> Self {
+        Self(self.start().round_down(step)..self.end().round_up(step))
+    }
+
+    /// Return true if the ranges have any addresses in common.
+    pub fn overlaps(&self, other: &PhysRange) -> bool {
+        self.start() < other.end() && other.start() < self.end()
+    }
+
+    /// Parse a non-empty range written as `<start>+<len>`, with both parts as
+    /// `parse_size` accepts, e.g. `0x10000000+64M`.
+    pub fn parse(s: &str) -> Option<PhysRange> {
+        let (start, len) = s.split_once('+')?;
+        let start = parse_size(start)?;
+        let len = parse_size(len)?;
+        let end = start.checked_add(len)?;
+        (len > 0).then(|| PhysRange::with_end(start, end))
+    }
+}
+
+/// Parse a size or address, in decimal or in hex with a 0x prefix, with an
+/// optional K, M or G suffix (in any case) multiplying it by 2^10, 2^20 or
+/// 2^30, e.g. `512M`, `0x8000000` or `0x4K`.  Returns None if it's malformed
+/// or overflows.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+    n.checked_mul(1 << shift)
+}
+
+/// Error returned when a PhysRangeSet doesn't have the capacity for an
+/// operation.
+#[derive(Debug, PartialEq)]
+pub struct RangeSetFullError;
+
+/// A set of non-overlapping physical ranges, sorted by start address.
+/// Overlapping and adjacent ranges are merged as they're added, and removing a
+/// range may split an existing range in two.  The capacity is fixed so that it
+/// can be used before the heap is available.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysRangeSet<const N: usize = 16> {
+    ranges: [PhysRange; N],
+    len: usize,
+}
+
+impl<const N: usize> PhysRangeSet<N> {
+    pub const fn new() -> Self {
+        Self { ranges: [const { PhysRange(PhysAddr(0)..PhysAddr(0)) }; N], len: 0 }
+    }
+
+    pub fn as_slice(&self) -> &[PhysRange] {
+        &self.ranges[..self.len]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PhysRange> {
+        self.as_slice().iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Total number of bytes covered by all ranges in the set.
+    pub fn size(&self) -> usize {
+        self.iter().map(|r| r.size()).sum()
+    }
+
+    /// Add the range to the set, merging it with any ranges it overlaps or
+    /// touches.  Empty ranges are ignored.
+    pub fn add(&mut self, range: &PhysRange) -> Result<(), RangeSetFullError> {
+        if range.start() >= range.end() {
+            return Ok(());
+        }
+
+        // Ranges [first, last) overlap or touch the new range
+        let first = self.iter().position(|r| r.end() >= range.start()).unwrap_or(self.len);
+        let last = first
+            + self.ranges[first..self.len]
+                .iter()
+                .position(|r| r.start() > range.end())
+                .unwrap_or(self.len - first);
+
+        if first == last {
+            if self.len == N {
+                return Err(RangeSetFullError);
+            }
+            self.ranges[first..=self.len].rotate_right(1);
+            self.ranges[first] = range.clone();
+            self.len += 1;
+        } else {
+            let start = min(range.start(), self.ranges[first].start());
+            let end = max(range.end(), self.ranges[last - 1].end());
+            self.ranges[first] = PhysRange::new(start, end);
+            self.ranges[first + 1..self.len].rotate_left(last - first - 1);
+            self.len -= last - first - 1;
+        }
+        Ok(())
+    }
+
+    /// Remove the range from the set, trimming or splitting any ranges it
+    /// overlaps.  Empty ranges are ignored.
+    pub fn remove(&mut self, range: &PhysRange) -> Result<(), RangeSetFullError> {
+        if range.start() >= range.end() {
+            return Ok(());
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let r = self.ranges[i].clone();
+            if r.end() <= range.start() || r.start() >= range.end() {
+                i += 1;
+                continue;
+            }
+
+            let below = PhysRange::new(r.start(), range.start());
+            let above = PhysRange::new(range.end(), r.end());
+            match (r.start() < range.start(), r.end() > range.end()) {
+                (true, true) => {
+                    // Split in two.  No later range can overlap.
+                    if self.len == N {
+                        return Err(RangeSetFullError);
+                    }
+                    self.ranges[i] = below;
+                    self.ranges[i + 1..=self.len].rotate_right(1);
+                    self.ranges[i + 1] = above;
+                    self.len += 1;
+                    return Ok(());
+                }
+                (true, false) => {
+                    self.ranges[i] = below;
+                    i += 1;
+                }
+                (false, true) => {
+                    self.ranges[i] = above;
+                    i += 1;
+                }
+                (false, false) => {
+                    self.ranges[i..self.len].rotate_left(1);
+                    self.len -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep only the lowest `size` bytes of the set, trimming the range that
+    /// crosses the limit, and dropping any ranges above it.
+    pub fn truncate(&mut self, size: usize) {
+        let mut left = size;
+        for i in 0..self.len {
+            let range = &mut self.ranges[i];
+            if range.size() >= left {
+                *range = PhysRange::with_pa_len(range.start(), left);
+                self.len = if left == 0 { i } else { i + 1 };
+                return;
+            }
+            left -= range.size();
+        }
+    }
+}
+
+impl<const N: usize> Default for PhysRangeSet<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats the ranges separated by commas.
+impl<const N: usize> fmt::Display for PhysRangeSet<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.iter().enumerate() {
+            write!(f, "{}{range}", if i == 0 { "" } else { ", " })?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for PhysRange {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#018x}..{:#018x}", self.0.start.addr(), self.0.end.addr())
+        write!(f, "{}..{}", self.0.start, self.0.end)
     }
 }
 
@@ -208,9 +505,233 @@ impl From<&RegBlock> for PhysRange {
     }
 }
 
+bitflags! {
+    /// Architecture independent permissions and memory attributes for a
+    /// mapping.  Each arch converts these into its own descriptor bits.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MapFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        const USER = 1 << 3;           // Accessible from user mode
+        const DEVICE = 1 << 4;         // Device memory, strictly ordered, e.g. MMIO
+        const NON_CACHEABLE = 1 << 5;  // Normal memory, but uncached
+        const DEVICE_RELAXED = 1 << 6; // Device memory, with writes gathered
+
+        const RW = Self::READ.bits() | Self::WRITE.bits();
+        const RX = Self::READ.bits() | Self::EXECUTE.bits();
+
+        // Uncached normal memory, whose writes may be combined, e.g. a
+        // framebuffer
+        const WRITE_COMBINING = Self::NON_CACHEABLE.bits();
+    }
+}
+
+/// The memory type of a mapping, which decides whether it's cached, and how
+/// accesses to it may be merged and reordered.  `MapFlags` hold it as at most
+/// one of DEVICE, NON_CACHEABLE and DEVICE_RELAXED, with none meaning
+/// `NormalCached`.  Each arch maps these to the nearest type it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemType {
+    NormalCached,       // Cached normal memory, the default
+    NormalNonCacheable, // Uncached normal memory, also used for write combining
+    DeviceStrict,       // Device memory, accessed exactly as the program orders
+    DeviceRelaxed,      // Device memory, with writes gathered and acknowledged early
+}
+
+impl MemType {
+    pub const ALL: [MemType; 4] =
+        [Self::NormalCached, Self::NormalNonCacheable, Self::DeviceStrict, Self::DeviceRelaxed];
+
+    /// Return the flags selecting this memory type.
+    pub const fn flags(self) -> MapFlags {
+        match self {
+            Self::NormalCached => MapFlags::empty(),
+            Self::NormalNonCacheable => MapFlags::NON_CACHEABLE,
+            Self::DeviceStrict => MapFlags::DEVICE,
+            Self::DeviceRelaxed => MapFlags::DEVICE_RELAXED,
+        }
+    }
+
+    pub fn is_device(self) -> bool {
+        matches!(self, Self::DeviceStrict | Self::DeviceRelaxed)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NormalCached => "normal",
+            Self::NormalNonCacheable => "non-cacheable",
+            Self::DeviceStrict => "device",
+            Self::DeviceRelaxed => "device-relaxed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapFlagsError {
+    NotReadable,        // All mappings must be readable
+    KernelWriteExecute, // Kernel mappings can't be both writable and executable
+    DeviceExecute,      // Device memory can't be executable
+    DeviceCacheability, // Device memory has no cacheability to choose
+    MemTypes,           // More than one memory type
+}
+
+/// Errors from the memory subsystem: allocating physical pages, mapping and
+/// unmapping them, and translating or accessing addresses.  Each carries the
+/// addresses or sizes involved, so callers can report, or test for, exactly
+/// what failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemError {
+    /// Not enough free memory: `requested` bytes were asked for, and only
+    /// `available` bytes are free, though not necessarily contiguously.
+    OutOfMemory { requested: usize, available: usize },
+    /// The address isn't a multiple of `required`.
+    Unaligned { addr: u64, required: usize },
+    /// The address isn't within `range`, the addresses the operation covers.
+    OutOfRange { addr: u64, range: Range<u64> },
+    /// The virtual address isn't canonical for the translation regime.
+    NotCanonical { va: VirtAddr },
+    /// The virtual address is already mapped.
+    AlreadyMapped { va: VirtAddr },
+    /// The virtual address isn't mapped.
+    NotMapped { va: VirtAddr },
+    /// The physical address isn't in a mapping it has to be reached through,
+    /// e.g. the direct map.
+    PhysNotMapped { pa: PhysAddr },
+    /// The page isn't in memory managed by the allocator.
+    NotInMemory { pa: PhysAddr },
+    /// The page isn't allocated, e.g. a double free.
+    NotAllocated { pa: PhysAddr },
+    /// The frame is shared, so can't be freed or written by one owner.
+    Shared { pa: PhysAddr },
+    /// The number of pages isn't valid, e.g. zero.
+    InvalidPageCount { count: usize },
+    /// The size isn't valid, e.g. zero.
+    InvalidSize { size: usize },
+    /// The alignment isn't a power of two, or is larger than the memory
+    /// managed.
+    InvalidAlignment { align: u64 },
+    /// The flags can't be used together.
+    InvalidFlags(MapFlagsError),
+    /// A fixed size table, e.g. of ranges or page table entries, is full.
+    TableFull,
+    /// A count, such as a reference count, would overflow.
+    Overflow,
+    /// The entry mapping `va` maps the code that replaces it, and the
+    /// trampoline to run that code from elsewhere isn't set up yet.
+    NoTrampoline { va: VirtAddr },
+}
+
+impl From<MapFlagsError> for MemError {
+    fn from(err: MapFlagsError) -> MemError {
+        MemError::InvalidFlags(err)
+    }
+}
+
+impl From<RangeSetFullError> for MemError {
+    fn from(_: RangeSetFullError) -> MemError {
+        MemError::TableFull
+    }
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory { requested, available } => {
+                write!(f, "out of memory: requested {requested:#x} bytes, {available:#x} free")
+            }
+            Self::Unaligned { addr, required } => {
+                write!(f, "address {addr:#x} not aligned to {required:#x}")
+            }
+            Self::OutOfRange { addr, range } => {
+                write!(f, "address {addr:#x} outside {:#x}..{:#x}", range.start, range.end)
+            }
+            Self::NotCanonical { va } => write!(f, "virtual address {va} not canonical"),
+            Self::AlreadyMapped { va } => write!(f, "virtual address {va} already mapped"),
+            Self::NotMapped { va } => write!(f, "virtual address {va} not mapped"),
+            Self::PhysNotMapped { pa } => write!(f, "physical address {pa} not mapped"),
+            Self::NotInMemory { pa } => write!(f, "physical address {pa} not in managed memory"),
+            Self::NotAllocated { pa } => write!(f, "page {pa} not allocated"),
+            Self::Shared { pa } => write!(f, "frame {pa} shared"),
+            Self::InvalidPageCount { count } => write!(f, "invalid page count {count}"),
+            Self::InvalidSize { size } => write!(f, "invalid size {size:#x}"),
+            Self::InvalidAlignment { align } => write!(f, "invalid alignment {align:#x}"),
+            Self::InvalidFlags(err) => write!(f, "invalid flags: {err:?}"),
+            Self::TableFull => write!(f, "table full"),
+            Self::Overflow => write!(f, "count overflow"),
+            Self::NoTrampoline { va } => {
+                write!(f, "entry for {va} maps the replacing code, and there's no trampoline")
+            }
+        }
+    }
+}
+
+impl MapFlags {
+    /// The flags selecting the memory type.
+    const MEM_TYPES: MapFlags =
+        MapFlags::DEVICE.union(MapFlags::NON_CACHEABLE).union(MapFlags::DEVICE_RELAXED);
+
+    /// Return the memory type the flags select.  If they select more than
+    /// one, which `validate` rejects, the most restrictive is returned.
+    pub fn mem_type(self) -> MemType {
+        if self.contains(MapFlags::DEVICE) {
+            MemType::DeviceStrict
+        } else if self.contains(MapFlags::DEVICE_RELAXED) {
+            MemType::DeviceRelaxed
+        } else if self.contains(MapFlags::NON_CACHEABLE) {
+            MemType::NormalNonCacheable
+        } else {
+            MemType::NormalCached
+        }
+    }
+
+    /// Return the flags with the memory type replaced by `mem_type`.
+    pub fn with_mem_type(self, mem_type: MemType) -> MapFlags {
+        self.difference(Self::MEM_TYPES) | mem_type.flags()
+    }
+
+    /// Return the flags if they describe a valid mapping.  The arch specific
+    /// conversions rely on this, so the rules are enforced in one place.
+    pub fn validate(self) -> Result<MapFlags, MapFlagsError> {
+        if !self.contains(MapFlags::READ) {
+            Err(MapFlagsError::NotReadable)
+        } else if self.contains(MapFlags::WRITE | MapFlags::EXECUTE)
+            && !self.contains(MapFlags::USER)
+        {
+            Err(MapFlagsError::KernelWriteExecute)
+        } else if self.mem_type().is_device() && self.contains(MapFlags::EXECUTE) {
+            Err(MapFlagsError::DeviceExecute)
+        } else if self.mem_type().is_device() && self.contains(MapFlags::NON_CACHEABLE) {
+            Err(MapFlagsError::DeviceCacheability)
+        } else if self.contains(MapFlags::DEVICE | MapFlags::DEVICE_RELAXED) {
+            Err(MapFlagsError::MemTypes)
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+impl fmt::Display for MapFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(MapFlags::READ, 'R'),
+            flag(MapFlags::WRITE, 'W'),
+            flag(MapFlags::EXECUTE, 'X')
+        )?;
+        if self.contains(MapFlags::USER) {
+            write!(f, " user")?;
+        }
+        write!(f, " {}", self.mem_type().name())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::layout::{Region, RegionKind};
 
     #[test]
     fn virtaddr_ops() {
@@ -257,6 +778,58 @@ mod tests {
         assert_eq!(PhysAddr::new(0x1200).round_down(0x100), PhysAddr::new(0x1200));
     }
 
+    #[test]
+    fn address_formatting() {
+        let va = VirtAddr::new(0xffff_8000_0008_1000);
+        let pa = PhysAddr::new(0x8_1000);
+        assert_eq!(va.to_string(), "0xffff800000081000");
+        assert_eq!(pa.to_string(), "0x0000000000081000");
+        assert_eq!(
+            format!("{va:?} {pa:?}"),
+            "VirtAddr(0xffff800000081000) PhysAddr(0x0000000000081000)"
+        );
+
+        // Hex specifiers pass through, with their flags
+        assert_eq!(format!("{pa:x} {pa:#x} {pa:#08X}"), "81000 0x81000 0x081000");
+        assert_eq!(format!("{va:X}"), "FFFF800000081000");
+
+        // Ranges are formatted as their addresses, so columns line up
+        let range = PhysRange::with_len(0x8_1000, 0x1000);
+        assert_eq!(range.to_string(), format!("{}..{}", range.start(), range.end()));
+        assert_eq!(
+            VirtRange::with_len(va, 0x1000).to_string(),
+            "0xffff800000081000..0xffff800000082000"
+        );
+
+        // The alternate form names the layout region holding the address
+        static LAYOUT: [Region; 1] =
+            [Region::new(RegionKind::KernelImage, 0xffff_8000_0000_0000, 1 << 30, PAGE_SIZE_2M)];
+        crate::layout::set_layout(&LAYOUT);
+        assert_eq!(format!("{va:#}"), "0xffff800000081000 (kernel image+0x81000)");
+        assert_eq!(format!("{:#}", VirtAddr::new(0x1000)), "0x0000000000001000");
+    }
+
+    #[test]
+    fn mem_error_formatting() {
+        assert_eq!(
+            MemError::OutOfMemory { requested: 0x2000, available: 0x1000 }.to_string(),
+            "out of memory: requested 0x2000 bytes, 0x1000 free"
+        );
+        assert_eq!(
+            MemError::OutOfRange { addr: 0x4000, range: 0x1000..0x3000 }.to_string(),
+            "address 0x4000 outside 0x1000..0x3000"
+        );
+        assert_eq!(
+            MemError::AlreadyMapped { va: VirtAddr::new(0xffff_8000_0008_1000) }.to_string(),
+            "virtual address 0xffff800000081000 already mapped"
+        );
+        assert_eq!(
+            MemError::from(MapFlagsError::DeviceExecute).to_string(),
+            "invalid flags: DeviceExecute"
+        );
+        assert_eq!(MemError::from(RangeSetFullError), MemError::TableFull);
+    }
+
     #[test]
     fn physrange_ops() {
         let r1 = PhysRange::with_end(0x1000, 0x2000);
@@ -282,6 +855,190 @@ mod tests {
         let r_combined_overlap = r1.add(&r_overlapping); // (0x1000..0x2000) + (0x1500..0x2500) -> (0x1000..0x2500)
         assert_eq!(r_combined_overlap.start(), PhysAddr::new(0x1000));
         assert_eq!(r_combined_overlap.end(), PhysAddr::new(0x2500));
+
+        assert_eq!(
+            PhysRange::with_end(0x1800, 0x2001).round_out(0x1000),
+            PhysRange::with_end(0x1000, 0x3000)
+        );
+        assert_eq!(r1.round_out(0x1000), r1);
+
+        assert!(r1.overlaps(&r_overlapping));
+        assert!(r_overlapping.overlaps(&r1));
+        assert!(!r1.overlaps(&r2));
+        assert!(!r1.overlaps(&PhysRange::with_end(0x2000, 0x3000)));
+    }
+
+    #[test]
+    fn physrange_above_4g() {
+        let range = PhysRange::with_len(0x1_4000_0000, 0x2000);
+        assert!(range.start().addr() > u32::MAX as PAddrRepr);
+        assert_eq!(range.end(), PhysAddr::new(0x1_4000_2000));
+        assert_eq!((range.len(), range.size()), (0x2000, 0x2000));
+        assert_eq!(
+            range.round_out(PAGE_SIZE_2M as PAddrRepr).start(),
+            PhysAddr::new(0x1_4000_0000)
+        );
+        let pas = range.step_by_rounded(PAGE_SIZE_4K).collect::<Vec<PhysAddr>>();
+        assert_eq!(pas, [PhysAddr::new(0x1_4000_0000), PhysAddr::new(0x1_4000_1000)]);
+
+        // A 5GiB range needs more than 32 bits for its size
+        let big = PhysRange::with_end(0x8000_0000, 0x1_c000_0000);
+        assert_eq!(big.len(), 0x1_4000_0000);
+        assert_eq!(big.size_as::<u32>(), None);
+        assert_eq!(big.size_as::<u64>(), Some(0x1_4000_0000));
+    }
+
+    #[test]
+    fn physrange_size_at_top() {
+        let top = PhysRange::with_end(PAddrRepr::MAX - 0xfff, PAddrRepr::MAX);
+        assert_eq!((top.len(), top.size()), (0xfff, 0xfff));
+        let all = PhysRange::with_end(0, PAddrRepr::MAX);
+        assert_eq!(all.size_as::<u64>(), Some(u64::MAX));
+
+        // An inverted range is empty, rather than huge
+        let inverted = PhysRange::with_end(0x2000, 0x1000);
+        assert!(inverted.is_empty());
+        assert_eq!(inverted.size(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "bytes wraps")]
+    fn physrange_wrapping_panics() {
+        PhysRange::with_len(PAddrRepr::MAX - 0xfff, 0x1000);
+    }
+
+    #[test]
+    fn physrangeset_add() {
+        let mut set = PhysRangeSet::<4>::new();
+        assert!(set.is_empty());
+
+        // Added out of order, but kept sorted
+        set.add(&PhysRange::with_end(0x3000, 0x4000)).unwrap();
+        set.add(&PhysRange::with_end(0x1000, 0x2000)).unwrap();
+        set.add(&PhysRange::with_end(0x8000, 0x9000)).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            [
+                PhysRange::with_end(0x1000, 0x2000),
+                PhysRange::with_end(0x3000, 0x4000),
+                PhysRange::with_end(0x8000, 0x9000)
+            ]
+        );
+        assert_eq!(set.size(), 0x3000);
+
+        // Empty ranges are ignored, and adjacent and overlapping ranges are merged
+        set.add(&PhysRange::with_end(0x5000, 0x5000)).unwrap();
+        set.add(&PhysRange::with_end(0x2000, 0x3000)).unwrap();
+        set.add(&PhysRange::with_end(0x3800, 0x6000)).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            [PhysRange::with_end(0x1000, 0x6000), PhysRange::with_end(0x8000, 0x9000)]
+        );
+
+        // Spanning several ranges merges them all
+        set.add(&PhysRange::with_end(0xa000, 0xb000)).unwrap();
+        set.add(&PhysRange::with_end(0x0, 0x8800)).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            [PhysRange::with_end(0x0, 0x9000), PhysRange::with_end(0xa000, 0xb000)]
+        );
+
+        // Full
+        set.add(&PhysRange::with_end(0xc000, 0xd000)).unwrap();
+        set.add(&PhysRange::with_end(0xe000, 0xf000)).unwrap();
+        assert_eq!(set.add(&PhysRange::with_end(0x10000, 0x11000)), Err(RangeSetFullError));
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn physrangeset_remove() {
+        let mut set = PhysRangeSet::<4>::new();
+        set.add(&PhysRange::with_end(0x1000, 0x4000)).unwrap();
+        set.add(&PhysRange::with_end(0x6000, 0x9000)).unwrap();
+
+        // Split a range in two
+        set.remove(&PhysRange::with_end(0x2000, 0x3000)).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            [
+                PhysRange::with_end(0x1000, 0x2000),
+                PhysRange::with_end(0x3000, 0x4000),
+                PhysRange::with_end(0x6000, 0x9000)
+            ]
+        );
+
+        // Trim the end of one range, remove another, and trim the start of a third
+        set.remove(&PhysRange::with_end(0x1800, 0x7000)).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            [PhysRange::with_end(0x1000, 0x1800), PhysRange::with_end(0x7000, 0x9000)]
+        );
+
+        // No overlap
+        set.remove(&PhysRange::with_end(0x2000, 0x6000)).unwrap();
+        assert_eq!(set.len(), 2);
+
+        // Splitting when full fails without changing the set
+        set.add(&PhysRange::with_end(0xa000, 0xb000)).unwrap();
+        set.add(&PhysRange::with_end(0xc000, 0xd000)).unwrap();
+        assert_eq!(set.remove(&PhysRange::with_end(0xc400, 0xc800)), Err(RangeSetFullError));
+        assert_eq!(set.len(), 4);
+
+        set.remove(&PhysRange::with_end(0x0, 0x10000)).unwrap();
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn physrangeset_truncate() {
+        let mut set = PhysRangeSet::<4>::new();
+        set.add(&PhysRange::with_end(0x1000, 0x4000)).unwrap();
+        set.add(&PhysRange::with_end(0x6000, 0x9000)).unwrap();
+
+        set.truncate(0x10000);
+        assert_eq!(set.size(), 0x6000);
+        set.truncate(0x4000);
+        assert_eq!(
+            set.as_slice(),
+            [PhysRange::with_end(0x1000, 0x4000), PhysRange::with_end(0x6000, 0x7000)]
+        );
+        set.truncate(0x3000);
+        assert_eq!(set.as_slice(), [PhysRange::with_end(0x1000, 0x4000)]);
+        set.truncate(0);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn physrangeset_display() {
+        let mut set = PhysRangeSet::<4>::new();
+        assert_eq!(set.to_string(), "");
+        set.add(&PhysRange::with_end(0x6000, 0x9000)).unwrap();
+        set.add(&PhysRange::with_end(0x1000, 0x4000)).unwrap();
+        assert_eq!(
+            set.to_string(),
+            "0x0000000000001000..0x0000000000004000, 0x0000000000006000..0x0000000000009000"
+        );
+    }
+
+    #[test]
+    fn parse_sizes_and_ranges() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("0x1000"), Some(0x1000));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("0x4K"), Some(0x1000));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("12Q"), None);
+        assert_eq!(parse_size("0x"), None);
+        assert_eq!(parse_size("0xffffffffffffffffG"), None);
+
+        assert_eq!(
+            PhysRange::parse("0x10000000+64M"),
+            Some(PhysRange::with_end(0x1000_0000, 0x1400_0000))
+        );
+        assert_eq!(PhysRange::parse("0x1000"), None);
+        assert_eq!(PhysRange::parse("0x1000+0"), None);
+        assert_eq!(PhysRange::parse("0xffffffffffffffff+2"), None);
     }
 
     #[test]
@@ -307,4 +1064,61 @@ mod tests {
         let pas = range.step_by_rounded(PAGE_SIZE_2M).collect::<Vec<PhysAddr>>();
         assert_eq!(pas, [PhysAddr::new(0x3f000000), PhysAddr::new(0x3f000000 + 2 * 1024 * 1024)]);
     }
+
+    #[test]
+    fn mapflags_validate() {
+        assert_eq!(MapFlags::RX.validate(), Ok(MapFlags::RX));
+        assert_eq!(MapFlags::RW.validate(), Ok(MapFlags::RW));
+        assert_eq!(
+            (MapFlags::RW | MapFlags::DEVICE).validate(),
+            Ok(MapFlags::RW | MapFlags::DEVICE)
+        );
+        let user_rwx = MapFlags::RW | MapFlags::EXECUTE | MapFlags::USER;
+        assert_eq!(user_rwx.validate(), Ok(user_rwx));
+
+        assert_eq!(MapFlags::WRITE.validate(), Err(MapFlagsError::NotReadable));
+        assert_eq!(
+            (MapFlags::RW | MapFlags::EXECUTE).validate(),
+            Err(MapFlagsError::KernelWriteExecute)
+        );
+        assert_eq!((MapFlags::RX | MapFlags::DEVICE).validate(), Err(MapFlagsError::DeviceExecute));
+        assert_eq!(
+            (MapFlags::RW | MapFlags::DEVICE | MapFlags::NON_CACHEABLE).validate(),
+            Err(MapFlagsError::DeviceCacheability)
+        );
+        assert_eq!(
+            (MapFlags::RX | MapFlags::DEVICE_RELAXED).validate(),
+            Err(MapFlagsError::DeviceExecute)
+        );
+        assert_eq!(
+            (MapFlags::RW | MapFlags::DEVICE_RELAXED | MapFlags::WRITE_COMBINING).validate(),
+            Err(MapFlagsError::DeviceCacheability)
+        );
+        assert_eq!(
+            (MapFlags::RW | MapFlags::DEVICE | MapFlags::DEVICE_RELAXED).validate(),
+            Err(MapFlagsError::MemTypes)
+        );
+    }
+
+    #[test]
+    fn mapflags_mem_type() {
+        for mem_type in MemType::ALL {
+            let flags = MapFlags::RW.with_mem_type(mem_type);
+            assert_eq!(flags.mem_type(), mem_type);
+            assert_eq!(flags.validate(), Ok(flags));
+            // Replacing the type leaves only the new one
+            let device = flags.with_mem_type(MemType::DeviceRelaxed);
+            assert_eq!(device, MapFlags::RW | MapFlags::DEVICE_RELAXED);
+        }
+        assert_eq!(MapFlags::WRITE_COMBINING.mem_type(), MemType::NormalNonCacheable);
+    }
+
+    #[test]
+    fn mapflags_display() {
+        assert_eq!(format!("{}", MapFlags::RX), "R-X normal");
+        assert_eq!(format!("{}", MapFlags::RW | MapFlags::DEVICE), "RW- device");
+        assert_eq!(format!("{}", MapFlags::READ | MapFlags::USER), "R-- user normal");
+        assert_eq!(format!("{}", MapFlags::RW | MapFlags::NON_CACHEABLE), "RW- non-cacheable");
+        assert_eq!(format!("{}", MapFlags::RW | MapFlags::DEVICE_RELAXED), "RW- device-relaxed");
+    }
 }
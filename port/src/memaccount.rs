@@ -0,0 +1,178 @@
+/// memaccount counts what physical memory is used for, by category, e.g.
+/// page tables or the heap, so that a report can show where memory is going
+/// rather than just how much is free.  The page allocator updates the counts
+/// as pages are allocated and freed with a category, and anything allocated
+/// without one is counted as `Unknown`.  Memory that's never allocated, such
+/// as the kernel image, is added to the report by the caller.
+///
+/// The counts are atomics, so they can be updated and printed without locks,
+/// e.g. from the OOM handler.
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::physmap::Size;
+
+/// What memory is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemCategory {
+    Unknown,     // Allocated without a category
+    KernelImage, // Kernel text, data and bss, other than the stacks
+    PageTables,  // Page tables, including the root tables
+    Heap,        // Pages the kernel heap has grown into
+    Stacks,      // Kernel stacks
+    Dma,         // DMA buffers and reservations, e.g. for the framebuffer
+    BootData,    // The DTB and initrd
+    Quarantined, // Pages that failed the boot memory test
+}
+
+impl MemCategory {
+    pub const COUNT: usize = 8;
+
+    pub const ALL: [MemCategory; Self::COUNT] = [
+        Self::Unknown,
+        Self::KernelImage,
+        Self::PageTables,
+        Self::Heap,
+        Self::Stacks,
+        Self::Dma,
+        Self::BootData,
+        Self::Quarantined,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::KernelImage => "kernel image",
+            Self::PageTables => "page tables",
+            Self::Heap => "heap",
+            Self::Stacks => "stacks",
+            Self::Dma => "dma",
+            Self::BootData => "boot data",
+            Self::Quarantined => "quarantined",
+        }
+    }
+}
+
+/// Counts of the pages allocated for each category.
+pub struct MemAccounts {
+    pages: [AtomicUsize; MemCategory::COUNT],
+    page_size: usize,
+}
+
+impl MemAccounts {
+    pub const fn new(page_size: usize) -> Self {
+        Self { pages: [const { AtomicUsize::new(0) }; MemCategory::COUNT], page_size }
+    }
+
+    /// Count `pages` pages allocated for `category`.
+    pub fn alloc(&self, category: MemCategory, pages: usize) {
+        self.pages[category as usize].fetch_add(pages, Ordering::Relaxed);
+    }
+
+    /// Count `pages` pages of `category` freed.  The count stops at zero,
+    /// in case pages are freed with a different category to the one they
+    /// were allocated with.
+    pub fn free(&self, category: MemCategory, pages: usize) {
+        let _ = self.pages[category as usize].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |count| Some(count.saturating_sub(pages)),
+        );
+    }
+
+    /// Number of pages allocated for `category`.
+    pub fn pages(&self, category: MemCategory) -> usize {
+        self.pages[category as usize].load(Ordering::Relaxed)
+    }
+
+    /// Return a report of the memory used by each category, which can be
+    /// printed.  `statics` is memory that isn't allocated, but is used for
+    /// a category, e.g. the kernel image, in bytes.  `free_bytes` is the
+    /// memory the page allocator has free, if it's known.
+    pub fn report<'a>(
+        &'a self,
+        statics: &'a [(MemCategory, usize)],
+        free_bytes: Option<usize>,
+    ) -> Report<'a> {
+        Report { accounts: self, statics, free_bytes }
+    }
+}
+
+/// Lists the memory used by each category that has any, a line each, with
+/// the allocated and static parts totalled, then the free memory.
+pub struct Report<'a> {
+    accounts: &'a MemAccounts,
+    statics: &'a [(MemCategory, usize)],
+    free_bytes: Option<usize>,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Memory by category:")?;
+        for category in MemCategory::ALL {
+            let allocated = self.accounts.pages(category) * self.accounts.page_size;
+            let fixed: usize =
+                self.statics.iter().filter(|(c, _)| *c == category).map(|(_, bytes)| bytes).sum();
+            if allocated + fixed == 0 {
+                continue;
+            }
+            write!(f, "  {:<14}{:>10}", category.name(), Size(allocated + fixed))?;
+            if fixed != 0 && allocated != 0 {
+                write!(f, "  ({} static)", Size(fixed))?;
+            }
+            writeln!(f)?;
+        }
+        match self.free_bytes {
+            Some(free) => writeln!(f, "  {:<14}{:>10}", "free", Size(free)),
+            None => writeln!(f, "  {:<14}{:>10}", "free", "unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_category() {
+        let accounts = MemAccounts::new(4096);
+        accounts.alloc(MemCategory::PageTables, 3);
+        accounts.alloc(MemCategory::Heap, 16);
+        accounts.free(MemCategory::PageTables, 1);
+        assert_eq!(accounts.pages(MemCategory::PageTables), 2);
+        assert_eq!(accounts.pages(MemCategory::Heap), 16);
+        assert_eq!(accounts.pages(MemCategory::Unknown), 0);
+
+        // Freeing more than was counted stops at zero
+        accounts.free(MemCategory::Heap, 20);
+        assert_eq!(accounts.pages(MemCategory::Heap), 0);
+    }
+
+    #[test]
+    fn report_totals_static_and_allocated() {
+        let accounts = MemAccounts::new(4096);
+        accounts.alloc(MemCategory::PageTables, 4);
+        accounts.alloc(MemCategory::Unknown, 1);
+        let statics = [
+            (MemCategory::KernelImage, 0x20_0000),
+            (MemCategory::PageTables, 0x2_0000),
+            (MemCategory::BootData, 0x8000),
+            (MemCategory::BootData, 0x8000),
+        ];
+        let report = accounts.report(&statics, Some(0x100_0000)).to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Memory by category:",
+                "  unknown            4 KiB",
+                "  kernel image       2 MiB",
+                "  page tables      144 KiB  (128 KiB static)",
+                "  boot data         64 KiB",
+                "  free              16 MiB",
+            ]
+        );
+        let report = accounts.report(&[], None).to_string();
+        assert_eq!(report.lines().last(), Some("  free             unknown"));
+    }
+}
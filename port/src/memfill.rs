@@ -0,0 +1,185 @@
+/// memfill zeroes and fills ranges of memory, for the places that clear
+/// pages and tables, so they all share one routine whose edge cases are
+/// tested.  The middle of a range is written with aligned 128 bit stores, and
+/// the unaligned bytes at either end a byte at a time.  All stores are
+/// volatile, so they're never turned into calls to memset, or left out.
+///
+/// An arch may also set a `ZeroBlock` with `set_zero_block`, an instruction
+/// that zeroes a whole aligned block at once, such as DC ZVA on aarch64.
+/// Zeroing then uses it for the whole blocks within the range.  The block
+/// instruction may only work on normal memory, so the ranges zeroed
+/// mustn't be device memory.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::mem::VirtRange;
+
+/// An instruction that zeroes a whole block of memory.
+pub struct ZeroBlock {
+    /// Size of the block zeroed, a power of 2.
+    pub size: fn() -> usize,
+    /// Zero the block at the pointer, which is aligned to the block size.
+    pub zero: unsafe fn(*mut u8),
+}
+
+/// The arch's block zeroing instruction, or null if there isn't one.
+static ZERO_BLOCK: AtomicPtr<ZeroBlock> = AtomicPtr::new(null_mut());
+
+/// Set the arch's block zeroing instruction.
+pub fn set_zero_block(block: &'static ZeroBlock) {
+    ZERO_BLOCK.store(block as *const ZeroBlock as *mut ZeroBlock, Ordering::Release);
+}
+
+fn zero_block() -> Option<&'static ZeroBlock> {
+    // Safety: only set_zero_block stores to ZERO_BLOCK, from a 'static
+    unsafe { ZERO_BLOCK.load(Ordering::Acquire).as_ref() }
+}
+
+/// Fill `range` with `byte`.
+///
+/// # Safety
+///
+/// The whole range must be mapped writable, and not in use by anything else.
+pub unsafe fn fill_range(range: &VirtRange, byte: u8) {
+    // Safety: as the caller promised
+    unsafe { fill(range.start().addr() as *mut u8, range.size(), byte) };
+}
+
+/// Zero `range`, with the arch's block zeroing instruction if it has one.
+///
+/// # Safety
+///
+/// The whole range must be mapped writable as normal memory, and not in use
+/// by anything else.
+pub unsafe fn zero_range(range: &VirtRange) {
+    // Safety: as the caller promised
+    unsafe { zero(range.start().addr() as *mut u8, range.size(), zero_block()) };
+}
+
+/// Fill the `len` bytes at `ptr` with `byte`: bytes up to 16 byte alignment,
+/// then 128 bit words, then the bytes left over.
+unsafe fn fill(ptr: *mut u8, len: usize, byte: u8) {
+    let head = ptr.align_offset(size_of::<u128>()).min(len);
+    let words = (len - head) / size_of::<u128>();
+    let body = words * size_of::<u128>();
+    let pattern = u128::from_ne_bytes([byte; size_of::<u128>()]);
+    // Safety: the caller promised the whole range is writable, and the words
+    // start at 16 byte alignment
+    unsafe {
+        for i in 0..head {
+            ptr.add(i).write_volatile(byte);
+        }
+        let word_ptr = ptr.add(head) as *mut u128;
+        for i in 0..words {
+            word_ptr.add(i).write_volatile(pattern);
+        }
+        for i in head + body..len {
+            ptr.add(i).write_volatile(byte);
+        }
+    }
+}
+
+/// Zero the `len` bytes at `ptr`, using `block` for the aligned blocks
+/// within them, if there's one, and `fill` for the rest.
+unsafe fn zero(ptr: *mut u8, len: usize, block: Option<&ZeroBlock>) {
+    if let Some(block) = block {
+        let size = (block.size)();
+        let head = ptr.align_offset(size);
+        if head < len && len - head >= size {
+            let blocks = (len - head) / size;
+            let tail = head + blocks * size;
+            // Safety: the caller promised the whole range is writable, and
+            // the blocks are aligned to the block size
+            unsafe {
+                fill(ptr, head, 0);
+                for i in 0..blocks {
+                    (block.zero)(ptr.add(head + i * size));
+                }
+                fill(ptr.add(tail), len - tail, 0);
+            }
+            return;
+        }
+    }
+    // Safety: as the caller promised
+    unsafe { fill(ptr, len, 0) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::VirtAddr;
+    use std::cell::Cell;
+
+    const BLOCK: usize = 64;
+    const GUARD: u8 = 0xaa;
+
+    /// Buffer aligned to a block, so offsets into it are offsets from
+    /// alignment.
+    #[repr(C, align(64))]
+    struct Buffer([u8; 8 * BLOCK]);
+
+    thread_local! {
+        // Per thread, so tests running at the same time don't add to it
+        static BLOCKS_ZEROED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Stands in for DC ZVA, checking it's only used on whole aligned blocks.
+    unsafe fn fake_zero_block(ptr: *mut u8) {
+        assert!(ptr.addr().is_multiple_of(BLOCK), "block at {ptr:?} isn't aligned");
+        unsafe { ptr.write_bytes(0, BLOCK) };
+        BLOCKS_ZEROED.set(BLOCKS_ZEROED.get() + 1);
+    }
+
+    static FAKE_BLOCK: ZeroBlock = ZeroBlock { size: || BLOCK, zero: fake_zero_block };
+
+    /// Lengths around the word and block sizes, where the head, body and
+    /// tail split changes.
+    fn lengths() -> impl Iterator<Item = usize> {
+        let edges = [0, 16, BLOCK, 2 * BLOCK, 3 * BLOCK];
+        edges.into_iter().flat_map(|edge| edge.saturating_sub(17)..edge + 18)
+    }
+
+    /// Call `write` with every combination of offset into a block and
+    /// length, then check exactly those bytes were set to `expected`.
+    fn check_all(expected: u8, write: impl Fn(*mut u8, usize)) {
+        for offset in 0..BLOCK + 1 {
+            for len in lengths() {
+                let mut buf = Buffer([GUARD; 8 * BLOCK]);
+                write(buf.0[offset..].as_mut_ptr(), len);
+                for (i, &b) in buf.0.iter().enumerate() {
+                    let want = if (offset..offset + len).contains(&i) { expected } else { GUARD };
+                    assert_eq!(b, want, "offset {offset} len {len}: byte {i}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_every_alignment() {
+        check_all(0x5c, |ptr, len| {
+            let range = VirtRange::with_len(VirtAddr::new(ptr.addr()), len);
+            unsafe { fill_range(&range, 0x5c) };
+        });
+    }
+
+    #[test]
+    fn zero_every_alignment() {
+        check_all(0, |ptr, len| unsafe { zero(ptr, len, None) });
+        check_all(0, |ptr, len| unsafe { zero(ptr, len, Some(&FAKE_BLOCK)) });
+    }
+
+    #[test]
+    fn zero_uses_whole_blocks() {
+        let mut buf = Buffer([GUARD; 8 * BLOCK]);
+        let zeroed = |offset: usize, len: usize, buf: &mut Buffer| {
+            BLOCKS_ZEROED.set(0);
+            unsafe { zero(buf.0[offset..].as_mut_ptr(), len, Some(&FAKE_BLOCK)) };
+            BLOCKS_ZEROED.get()
+        };
+        assert_eq!(zeroed(0, 3 * BLOCK, &mut buf), 3);
+        assert_eq!(zeroed(1, 3 * BLOCK, &mut buf), 2);
+        assert_eq!(zeroed(1, 2 * BLOCK - 2, &mut buf), 0);
+        assert_eq!(zeroed(BLOCK - 1, BLOCK + 1, &mut buf), 1);
+        assert_eq!(zeroed(0, BLOCK - 1, &mut buf), 0);
+    }
+}
@@ -0,0 +1,344 @@
+/// memsnapshot records the state of memory at a point in time: the page
+/// allocator's stats for each region, the pages allocated for each category,
+/// the heap stats, and the vmap mappings.  Two snapshots, taken before and
+/// after something that should give back all it takes, such as a map and
+/// unmap cycle, or a device probe and teardown, are compared with `diff`,
+/// which lists only what changed, so a leak shows as the category that grew,
+/// or the mapping left behind.
+///
+/// Snapshots are kept in fixed size arrays, sized by the arch, so taking one
+/// doesn't allocate, which would change what it's measuring.  What doesn't
+/// fit is left out, and the snapshot marked incomplete.  With room for many
+/// mappings, they're a few KiB, so are best kept in statics, and cleared
+/// and refilled in place, rather than on a kernel stack.
+use core::fmt;
+
+use crate::allocator::HeapStats;
+use crate::mem::{MapFlags, PhysRange, VirtRange};
+use crate::memaccount::{MemAccounts, MemCategory};
+use crate::pagealloc::PageAllocStats;
+
+/// A mapping made by vmap, of `pages` at `va`, used by `refs` windows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmapEntry {
+    pub pages: PhysRange,
+    pub va: VirtRange,
+    pub flags: MapFlags,
+    pub refs: usize,
+}
+
+impl VmapEntry {
+    /// Whether `other` is the same mapping, whatever its references.
+    fn same_mapping(&self, other: &VmapEntry) -> bool {
+        self.pages == other.pages && self.va == other.va && self.flags == other.flags
+    }
+}
+
+impl fmt::Display for VmapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {} {} refs {}", self.va, self.pages, self.flags, self.refs)
+    }
+}
+
+/// The state of memory, with room for `R` page allocator regions and `V`
+/// vmap mappings.
+pub struct MemSnapshot<const R: usize, const V: usize> {
+    regions: [Option<(PhysRange, PageAllocStats)>; R],
+    categories: [usize; MemCategory::COUNT],
+    heap: Option<HeapStats>,
+    vmaps: [Option<VmapEntry>; V],
+    incomplete: bool,
+}
+
+impl<const R: usize, const V: usize> MemSnapshot<R, V> {
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { None }; R],
+            categories: [0; MemCategory::COUNT],
+            heap: None,
+            vmaps: [const { None }; V],
+            incomplete: false,
+        }
+    }
+
+    /// Empty the snapshot, to take another in its place.
+    pub fn clear(&mut self) {
+        self.regions.iter_mut().for_each(|slot| *slot = None);
+        self.categories = [0; MemCategory::COUNT];
+        self.heap = None;
+        self.vmaps.iter_mut().for_each(|slot| *slot = None);
+        self.incomplete = false;
+    }
+
+    /// Record the stats of the page allocator region covering `range`.
+    pub fn add_region(&mut self, range: &PhysRange, stats: PageAllocStats) {
+        match self.regions.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some((range.clone(), stats)),
+            None => self.incomplete = true,
+        }
+    }
+
+    /// Record the pages allocated for each category in `accounts`.
+    pub fn add_categories(&mut self, accounts: &MemAccounts) {
+        for category in MemCategory::ALL {
+            self.categories[category as usize] = accounts.pages(category);
+        }
+    }
+
+    /// Record the heap stats, which are None if they couldn't be read.
+    pub fn set_heap(&mut self, stats: Option<HeapStats>) {
+        self.heap = stats;
+    }
+
+    /// Record a vmap mapping.
+    pub fn add_vmap(&mut self, entry: VmapEntry) {
+        match self.vmaps.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(entry),
+            None => self.incomplete = true,
+        }
+    }
+
+    /// Whether something didn't fit in the snapshot.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    /// Number of pages free, over all the regions.
+    pub fn free_pages(&self) -> usize {
+        self.regions().map(|(_, stats)| stats.free_pages).sum()
+    }
+
+    /// Number of pages allocated for `category`.
+    pub fn category_pages(&self, category: MemCategory) -> usize {
+        self.categories[category as usize]
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &(PhysRange, PageAllocStats)> {
+        self.regions.iter().flatten()
+    }
+
+    fn vmaps(&self) -> impl Iterator<Item = &VmapEntry> {
+        self.vmaps.iter().flatten()
+    }
+
+    /// Compare the snapshot `before` with `after`.
+    pub fn diff<'a>(before: &'a Self, after: &'a Self) -> MemDiff<'a, R, V> {
+        MemDiff { before, after }
+    }
+}
+
+impl<const R: usize, const V: usize> Default for MemSnapshot<R, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The changes between two snapshots.  Printed, it lists only what changed,
+/// a line each.
+pub struct MemDiff<'a, const R: usize, const V: usize> {
+    before: &'a MemSnapshot<R, V>,
+    after: &'a MemSnapshot<R, V>,
+}
+
+impl<const R: usize, const V: usize> MemDiff<'_, R, V> {
+    /// Pages gained by `category`, or lost, if negative.
+    pub fn category_change(&self, category: MemCategory) -> isize {
+        self.after.category_pages(category) as isize - self.before.category_pages(category) as isize
+    }
+
+    /// Pages no longer free that aren't accounted for by the growth of the
+    /// `retained` categories, which are those expected to keep pages once
+    /// they have them, e.g. the heap, which never shrinks.
+    pub fn leaked_pages(&self, retained: &[MemCategory]) -> usize {
+        let lost = self.before.free_pages() as isize - self.after.free_pages() as isize;
+        let kept: isize = retained.iter().map(|&c| self.category_change(c).max(0)).sum();
+        (lost - kept).max(0) as usize
+    }
+
+    /// Number of references to vmap mappings taken and not dropped, counting
+    /// all those of a new mapping.
+    pub fn leaked_vmaps(&self) -> usize {
+        self.after
+            .vmaps()
+            .map(|entry| match self.before.vmaps().find(|e| e.same_mapping(entry)) {
+                Some(before) => entry.refs.saturating_sub(before.refs),
+                None => entry.refs,
+            })
+            .sum()
+    }
+
+    /// Whether nothing changed that's printed.
+    pub fn is_empty(&self) -> bool {
+        struct Counter(usize);
+        impl fmt::Write for Counter {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.0 += s.len();
+                Ok(())
+            }
+        }
+        let mut counter = Counter(0);
+        let _ = fmt::write(&mut counter, format_args!("{self}"));
+        counter.0 == 0
+    }
+}
+
+impl<const R: usize, const V: usize> fmt::Display for MemDiff<'_, R, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (range, after) in self.after.regions() {
+            let before = self.before.regions().find(|(r, _)| r == range).map(|(_, s)| s);
+            let free_before = before.map_or(0, |s| s.free_pages);
+            if free_before != after.free_pages {
+                let change = after.free_pages as isize - free_before as isize;
+                writeln!(
+                    f,
+                    "region {range}: free pages {free_before} -> {} ({change:+})",
+                    after.free_pages
+                )?;
+            }
+        }
+        for category in MemCategory::ALL {
+            let change = self.category_change(category);
+            if change != 0 {
+                writeln!(f, "{}: {change:+} pages", category.name())?;
+            }
+        }
+        if let (Some(before), Some(after)) = (self.before.heap, self.after.heap) {
+            if before.allocated_bytes != after.allocated_bytes
+                || before.arena_bytes != after.arena_bytes
+            {
+                writeln!(
+                    f,
+                    "heap: allocated {} -> {} bytes, arenas {} -> {} bytes",
+                    before.allocated_bytes,
+                    after.allocated_bytes,
+                    before.arena_bytes,
+                    after.arena_bytes
+                )?;
+            }
+        }
+        for entry in self.after.vmaps() {
+            match self.before.vmaps().find(|e| e.same_mapping(entry)) {
+                None => writeln!(f, "vmap added: {entry}")?,
+                Some(before) if before.refs != entry.refs => writeln!(
+                    f,
+                    "vmap {}: refs {} -> {}",
+                    entry.va.start(),
+                    before.refs,
+                    entry.refs
+                )?,
+                Some(_) => {}
+            }
+        }
+        for entry in self.before.vmaps() {
+            if !self.after.vmaps().any(|e| e.same_mapping(entry)) {
+                writeln!(f, "vmap removed: {entry}")?;
+            }
+        }
+        if self.before.incomplete || self.after.incomplete {
+            writeln!(f, "snapshots incomplete, some regions or vmaps didn't fit")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::VirtAddr;
+
+    type Snapshot = MemSnapshot<2, 4>;
+
+    fn stats(free_pages: usize) -> PageAllocStats {
+        PageAllocStats {
+            total_pages: 100,
+            free_pages,
+            allocated_pages: 100 - free_pages,
+            ..Default::default()
+        }
+    }
+
+    fn vmap(pa: u64, va: usize, refs: usize) -> VmapEntry {
+        VmapEntry {
+            pages: PhysRange::with_len(pa, 0x1000),
+            va: VirtRange::with_len(VirtAddr::new(va), 0x1000),
+            flags: MapFlags::RW,
+            refs,
+        }
+    }
+
+    fn snapshot(free: [usize; 2], accounts: &MemAccounts, vmaps: &[VmapEntry]) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.add_region(&PhysRange::with_len(0, 0x64000), stats(free[0]));
+        snapshot.add_region(&PhysRange::with_len(0x8000_0000, 0x64000), stats(free[1]));
+        snapshot.add_categories(accounts);
+        for entry in vmaps {
+            snapshot.add_vmap(entry.clone());
+        }
+        snapshot
+    }
+
+    #[test]
+    fn unchanged_is_empty() {
+        let accounts = MemAccounts::new(4096);
+        accounts.alloc(MemCategory::PageTables, 3);
+        let before = snapshot([50, 60], &accounts, &[vmap(0x1000, 0x10_0000, 1)]);
+        let after = snapshot([50, 60], &accounts, &[vmap(0x1000, 0x10_0000, 1)]);
+        let diff = Snapshot::diff(&before, &after);
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "");
+        assert_eq!((diff.leaked_pages(&[]), diff.leaked_vmaps()), (0, 0));
+    }
+
+    #[test]
+    fn diff_lists_changes() {
+        let accounts = MemAccounts::new(4096);
+        let before = snapshot([50, 60], &accounts, &[vmap(0x1000, 0x10_0000, 1)]);
+        accounts.alloc(MemCategory::Dma, 2);
+        accounts.alloc(MemCategory::Heap, 1);
+        let vmaps = [vmap(0x1000, 0x10_0000, 2), vmap(0x5000, 0x10_1000, 1)];
+        let after = snapshot([47, 60], &accounts, &vmaps);
+
+        let diff = Snapshot::diff(&before, &after);
+        assert!(!diff.is_empty());
+        let text = diff.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "region 0x0000000000000000..0x0000000000064000: free pages 50 -> 47 (-3)",
+                "heap: +1 pages",
+                "dma: +2 pages",
+                "vmap 0x0000000000100000: refs 1 -> 2",
+                "vmap added: 0x0000000000101000..0x0000000000102000 -> \
+                 0x0000000000005000..0x0000000000006000 RW- normal refs 1",
+            ]
+        );
+        assert_eq!(diff.leaked_pages(&[]), 3);
+        assert_eq!(diff.leaked_pages(&[MemCategory::Heap]), 2);
+        assert_eq!(diff.leaked_vmaps(), 2);
+
+        // Giving it all back leaks nothing, though the heap keeps its page
+        accounts.free(MemCategory::Dma, 2);
+        let end = snapshot([49, 60], &accounts, &[]);
+        let diff = Snapshot::diff(&before, &end);
+        assert_eq!(diff.leaked_pages(&[MemCategory::Heap]), 0);
+        assert_eq!(diff.leaked_vmaps(), 0);
+        assert!(diff.to_string().contains("vmap removed: 0x0000000000100000"));
+    }
+
+    #[test]
+    fn overflow_marks_incomplete() {
+        let accounts = MemAccounts::new(4096);
+        let vmaps: Vec<_> = (0..5).map(|i| vmap(i * 0x1000, 0x10_0000, 1)).collect();
+        let full = snapshot([1, 1], &accounts, &vmaps);
+        assert!(full.is_incomplete());
+        let diff = Snapshot::diff(&full, &full);
+        assert_eq!(diff.to_string(), "snapshots incomplete, some regions or vmaps didn't fit\n");
+
+        let mut cleared = snapshot([1, 1], &accounts, &vmaps);
+        cleared.clear();
+        assert!(!cleared.is_incomplete());
+        let empty = Snapshot::new();
+        assert_eq!(Snapshot::diff(&empty, &cleared).to_string(), "");
+    }
+}
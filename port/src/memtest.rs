@@ -0,0 +1,219 @@
+/// memtest checks that RAM works, and isn't aliased, by writing patterns to
+/// it and reading them back.  Each word tested is written with its own
+/// physical address, then read back, then the same with the complement, so
+/// every bit is tested both ways.  All the words are written before any is
+/// read back, so a write that lands on another address, e.g. because an
+/// address line is stuck, shows up as that address reading back wrong.
+///
+/// The sparse mode only tests the first page of every 2MiB, which is quick
+/// enough for every boot of a new board, while the full mode tests every
+/// word.  The memory is reached through a pointer the caller supplies for
+/// each chunk, so it works over the direct map in the kernel, and over
+/// ordinary buffers in tests.  Each chunk is cleaned and invalidated from
+/// the data cache once it's written, so the patterns are read back from RAM
+/// rather than from the cache, which would hide both bad cells and aliasing.
+use crate::mem::{PAGE_SIZE_2M, PAGE_SIZE_4K, PhysAddr, PhysRange, VirtAddr, VirtRange};
+use core::ptr::{read_volatile, write_volatile};
+
+/// How much of the memory to test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemTestMode {
+    /// The first page of every 2MiB.
+    Sparse,
+    /// Every word.
+    Full,
+}
+
+impl MemTestMode {
+    /// Parse the value of the memtest= boot argument: 1 for sparse, or 2
+    /// for full.
+    pub fn parse(s: &str) -> Option<MemTestMode> {
+        match s {
+            "1" => Some(MemTestMode::Sparse),
+            "2" => Some(MemTestMode::Full),
+            _ => None,
+        }
+    }
+
+    /// Return the chunks of `range` tested in this mode, a page at most
+    /// each.
+    fn chunks(self, range: &PhysRange) -> impl Iterator<Item = PhysRange> {
+        let step = match self {
+            MemTestMode::Sparse => PAGE_SIZE_2M,
+            MemTestMode::Full => PAGE_SIZE_4K,
+        };
+        let end = range.end();
+        (range.start()..end).step_by(step).map(move |start| {
+            let chunk_end = end.min(start + PAGE_SIZE_4K as u64);
+            PhysRange::new(start, chunk_end)
+        })
+    }
+}
+
+/// A word that didn't read back as written.
+#[derive(Debug, PartialEq)]
+pub struct MemTestFailure {
+    pub pa: PhysAddr,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+const WORD: usize = size_of::<u64>();
+
+/// Test the memory in `ranges`, whose ends should be word aligned, in `mode`.
+/// `chunk_ptr` returns a pointer to the start of each chunk tested, which is
+/// within a page, or None if it can't be reached, in which case it's skipped.
+/// `fail` is called for each word that reads back wrong, once for each
+/// pattern.  Returns the number of bytes tested.
+pub fn test_ranges(
+    ranges: &[PhysRange],
+    mode: MemTestMode,
+    mut chunk_ptr: impl FnMut(&PhysRange) -> Option<*mut u64>,
+    mut fail: impl FnMut(MemTestFailure),
+) -> usize {
+    let chunks = || ranges.iter().flat_map(move |range| mode.chunks(range));
+    let mut tested = 0;
+    for pattern in [|pa: u64| pa, |pa: u64| !pa] {
+        tested = 0;
+        for chunk in chunks() {
+            let Some(ptr) = chunk_ptr(&chunk) else { continue };
+            for i in 0..chunk.size() / WORD {
+                let pa = chunk.start().addr() + (i * WORD) as u64;
+                // Safety: chunk_ptr returned a pointer to the whole chunk
+                unsafe { write_volatile(ptr.add(i), pattern(pa)) };
+            }
+            crate::cache::clean_invalidate(&VirtRange::with_len(
+                VirtAddr::new(ptr.addr()),
+                chunk.size(),
+            ));
+            tested += chunk.size() / WORD * WORD;
+        }
+        for chunk in chunks() {
+            let Some(ptr) = chunk_ptr(&chunk) else { continue };
+            for i in 0..chunk.size() / WORD {
+                let pa = chunk.start().addr() + (i * WORD) as u64;
+                let expected = pattern(pa);
+                // Safety: as above
+                let actual = unsafe { read_volatile(ptr.add(i)) };
+                if actual != expected {
+                    fail(MemTestFailure { pa: PhysAddr::new(pa), expected, actual });
+                }
+            }
+        }
+    }
+    tested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x4000_0000;
+
+    /// Fake RAM of `pages` pages from BASE, with `alias` mapping one page to
+    /// the buffer of another, as if an address line were stuck.
+    struct FakeRam {
+        buf: Vec<u64>,
+        alias: Option<(u64, u64)>,
+    }
+
+    impl FakeRam {
+        fn new(pages: usize) -> Self {
+            Self { buf: vec![0x5a5a_5a5a_5a5a_5a5a; pages * PAGE_SIZE_4K / WORD], alias: None }
+        }
+
+        fn chunk_ptr(&mut self, chunk: &PhysRange) -> Option<*mut u64> {
+            let mut offset = chunk.start().addr() - BASE;
+            if let Some((from, to)) = self.alias {
+                if offset / PAGE_SIZE_4K as u64 == from {
+                    offset = to * PAGE_SIZE_4K as u64 + offset % PAGE_SIZE_4K as u64;
+                }
+            }
+            let index = offset as usize / WORD;
+            (index < self.buf.len()).then(|| self.buf[index..].as_mut_ptr())
+        }
+
+        fn word(&self, pa: u64) -> u64 {
+            self.buf[(pa - BASE) as usize / WORD]
+        }
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!(MemTestMode::parse("1"), Some(MemTestMode::Sparse));
+        assert_eq!(MemTestMode::parse("2"), Some(MemTestMode::Full));
+        assert_eq!(MemTestMode::parse("full"), None);
+    }
+
+    #[test]
+    fn good_memory_passes() {
+        let mut ram = FakeRam::new(4);
+        let ranges =
+            [PhysRange::with_len(BASE, 0x1000), PhysRange::with_len(BASE + 0x2000, 0x2000)];
+        let mut failures = Vec::new();
+        let tested = test_ranges(
+            &ranges,
+            MemTestMode::Full,
+            |chunk| ram.chunk_ptr(chunk),
+            |failure| failures.push(failure),
+        );
+        assert_eq!(tested, 0x3000);
+        assert!(failures.is_empty());
+
+        // The complement was written last, and the untested page is untouched
+        assert_eq!(ram.word(BASE + 0x2008), !(BASE + 0x2008));
+        assert_eq!(ram.word(BASE + 0x1000), 0x5a5a_5a5a_5a5a_5a5a);
+    }
+
+    #[test]
+    fn sparse_tests_first_page_of_each_2m() {
+        let chunks: Vec<_> =
+            MemTestMode::Sparse.chunks(&PhysRange::with_len(BASE + 0x1000, 0x40_0000)).collect();
+        assert_eq!(
+            chunks,
+            [
+                PhysRange::with_len(BASE + 0x1000, 0x1000),
+                PhysRange::with_len(BASE + 0x20_1000, 0x1000)
+            ]
+        );
+        let chunks: Vec<_> =
+            MemTestMode::Sparse.chunks(&PhysRange::with_len(BASE, 0x800)).collect();
+        assert_eq!(chunks, [PhysRange::with_len(BASE, 0x800)]);
+        assert_eq!(MemTestMode::Full.chunks(&PhysRange::with_len(BASE, 0)).count(), 0);
+    }
+
+    #[test]
+    fn aliased_page_fails() {
+        // Writes to the fourth page land on the second
+        let mut ram = FakeRam::new(4);
+        ram.alias = Some((3, 1));
+        let mut failures = Vec::new();
+        test_ranges(
+            &[PhysRange::with_len(BASE, 0x4000)],
+            MemTestMode::Full,
+            |chunk| ram.chunk_ptr(chunk),
+            |failure| failures.push(failure),
+        );
+
+        // Both pages read back the fourth page's pattern, so the second
+        // fails at every word, for both patterns
+        assert_eq!(failures.len(), 2 * PAGE_SIZE_4K / WORD);
+        assert_eq!(
+            failures[1],
+            MemTestFailure {
+                pa: PhysAddr::new(BASE + 0x1008),
+                expected: BASE + 0x1008,
+                actual: BASE + 0x3008
+            }
+        );
+        assert_eq!(
+            failures.last(),
+            Some(&MemTestFailure {
+                pa: PhysAddr::new(BASE + 0x1ff8),
+                expected: !(BASE + 0x1ff8),
+                actual: !(BASE + 0x3ff8)
+            })
+        );
+        assert!(failures.iter().all(|f| (BASE + 0x1000..BASE + 0x2000).contains(&f.pa.addr())));
+    }
+}
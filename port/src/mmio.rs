@@ -0,0 +1,309 @@
+/// mmio maps the device registers a device tree node describes, so drivers
+/// needn't each find the reg entry, translate it through the bus ranges to a
+/// CPU physical address, and round it out to pages before mapping it.  The
+/// mapping itself is done by the caller's mapper, e.g. vmap, or the early
+/// MMIO mapping, which is given whole pages.
+///
+/// Drivers then access the registers through Mmio, using register maps
+/// declared with mmio_registers!, which give each register's offset, width,
+/// and whether it can be read or written, so the accesses are always volatile,
+/// of the right width, and checked against the mapped range in debug builds.
+use crate::fdt::{DeviceRegion, DeviceTree, Node, Translated};
+use crate::mem::{PAGE_SIZE_4K, PhysRange, VirtRange};
+use core::marker::PhantomData;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Error mapping a reg entry, which is identified by its index.
+#[derive(Debug, PartialEq)]
+pub enum MapDeviceError<E> {
+    NoReg(usize),       // The node has no reg entry at the index
+    Unreachable(usize), // The bus ranges don't translate the entry
+    NoSize(usize),      // The entry has no length
+    Map(E),             // The mapper failed
+}
+
+/// Map the registers in reg entry `index` of `node`, passing the pages that
+/// cover them to `map`, which returns where it mapped them.  The returned
+/// range is of the registers themselves, so it starts at the same offset
+/// within its page as the registers, which needn't be page aligned.
+pub fn map_device_mmio<E>(
+    dt: &DeviceTree,
+    node: Node,
+    index: usize,
+    map: impl FnOnce(&PhysRange) -> Result<VirtRange, E>,
+) -> Result<VirtRange, MapDeviceError<E>> {
+    match dt.translated_regions(node).nth(index) {
+        Some(Ok(region)) => map_region_mmio(&region, map),
+        Some(Err(_)) => Err(MapDeviceError::Unreachable(index)),
+        None => Err(MapDeviceError::NoReg(index)),
+    }
+}
+
+/// Map the registers of `region`, as `map_device_mmio`.  The region must
+/// have been translated, so its address is physical.
+pub fn map_region_mmio<E>(
+    region: &DeviceRegion<Translated>,
+    map: impl FnOnce(&PhysRange) -> Result<VirtRange, E>,
+) -> Result<VirtRange, MapDeviceError<E>> {
+    let Some(regs) = region.phys_range().filter(|regs| regs.size() > 0) else {
+        return Err(MapDeviceError::NoSize(region.index()));
+    };
+    let pages = regs.round_out(PAGE_SIZE_4K as u64);
+    let va_pages = map(&pages).map_err(MapDeviceError::Map)?;
+    let offset = (regs.start().addr() - pages.start().addr()) as usize;
+    Ok(VirtRange::with_len(va_pages.start() + offset, regs.size()))
+}
+
+/// Access types of registers.
+pub struct ReadOnly;
+pub struct WriteOnly;
+pub struct ReadWrite;
+
+/// Access types of registers that can be read.
+pub trait Readable {}
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+
+/// Access types of registers that can be written.
+pub trait Writable {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// Widths of registers.
+pub trait RegWidth: Copy {}
+impl RegWidth for u8 {}
+impl RegWidth for u16 {}
+impl RegWidth for u32 {}
+impl RegWidth for u64 {}
+
+/// A register of type `T`, at `offset` in its block, with access `A`.
+pub struct Reg<T: RegWidth, A> {
+    offset: usize,
+    _marker: PhantomData<(T, A)>,
+}
+
+impl<T: RegWidth, A> Reg<T, A> {
+    pub const fn new(offset: usize) -> Self {
+        Self { offset, _marker: PhantomData }
+    }
+
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Return the same kind of register `n` registers further on, for
+    /// blocks of consecutive registers, e.g. one bit per interrupt.
+    pub const fn nth(&self, n: usize) -> Self {
+        Self::new(self.offset + n * size_of::<T>())
+    }
+}
+
+impl<T: RegWidth, A> Clone for Reg<T, A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: RegWidth, A> Copy for Reg<T, A> {}
+
+/// Declare a register map: a type with a `Reg` const for each register,
+/// giving its width, access and offset, e.g.
+///
+/// ```ignore
+/// mmio_registers! {
+///     pub struct UartReg {
+///         DR: u32, ReadWrite, 0x00; // Data register
+///         FR: u32, ReadOnly, 0x18;  // Flag register
+///     }
+/// }
+/// ```
+///
+/// so that the registers are accessed as `uart.read(UartReg::FR)`.
+#[macro_export]
+macro_rules! mmio_registers {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$reg_meta:meta])* $reg:ident: $width:ty, $access:ident, $offset:expr;)*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $(#[$reg_meta])*
+                pub const $reg: $crate::mmio::Reg<$width, $crate::mmio::$access> =
+                    $crate::mmio::Reg::new($offset);
+            )*
+        }
+    };
+}
+
+/// A block of device registers, mapped at a virtual range.
+pub struct Mmio {
+    range: VirtRange,
+}
+
+impl Mmio {
+    /// # Safety
+    ///
+    /// `range` must be mapped to the device's registers, as device memory,
+    /// for as long as the Mmio is used.
+    pub const unsafe fn new(range: VirtRange) -> Self {
+        Self { range }
+    }
+
+    pub fn range(&self) -> &VirtRange {
+        &self.range
+    }
+
+    /// Return a pointer to the `T` at `offset`, which must be within the
+    /// range and aligned.  Checked in debug builds, so a wrong offset can't
+    /// silently access a neighbouring device.
+    #[track_caller]
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        debug_assert!(
+            offset.checked_add(size_of::<T>()).is_some_and(|end| end <= self.range.size()),
+            "mmio offset {offset:#x} size {} outside {}",
+            size_of::<T>(),
+            self.range
+        );
+        debug_assert!(offset.is_multiple_of(align_of::<T>()), "unaligned mmio offset {offset:#x}");
+        (self.range.start().addr() + offset) as *mut T
+    }
+
+    /// Read `reg`.
+    #[track_caller]
+    pub fn read<T: RegWidth, A: Readable>(&self, reg: Reg<T, A>) -> T {
+        // Safety: new's caller guarantees the range is mapped, and ptr checks
+        // the offset is in it.
+        unsafe { read_volatile(self.ptr(reg.offset)) }
+    }
+
+    /// Write `value` to `reg`.
+    #[track_caller]
+    pub fn write<T: RegWidth, A: Writable>(&self, reg: Reg<T, A>, value: T) {
+        // Safety: as for read.
+        unsafe { write_volatile(self.ptr(reg.offset), value) }
+    }
+
+    /// Read `reg`, and write back the result of `f` on its value.
+    #[track_caller]
+    pub fn modify<T: RegWidth>(&self, reg: Reg<T, ReadWrite>, f: impl FnOnce(T) -> T) {
+        self.write(reg, f(self.read(reg)));
+    }
+
+    #[track_caller]
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.read(Reg::<u32, ReadOnly>::new(offset))
+    }
+
+    #[track_caller]
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.write(Reg::<u32, WriteOnly>::new(offset), value)
+    }
+
+    #[track_caller]
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.read(Reg::<u64, ReadOnly>::new(offset))
+    }
+
+    #[track_caller]
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write(Reg::<u64, WriteOnly>::new(offset), value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::VirtAddr;
+
+    static TEST1_DTB: &[u8] = include_bytes!("../lib/test/fdt/test1.dtb");
+
+    /// Map pages at a fixed offset, recording what was mapped.
+    fn mapper(mapped: &mut Vec<PhysRange>) -> impl FnOnce(&PhysRange) -> Result<VirtRange, ()> {
+        |pages| {
+            mapped.push(pages.clone());
+            let start = VirtAddr::new(pages.start().addr() as usize + 0x1_0000_0000);
+            Ok(VirtRange::with_len(start, pages.size()))
+        }
+    }
+
+    #[test]
+    fn maps_translated_pages_keeping_offset() {
+        let dt = DeviceTree::new(TEST1_DTB).unwrap();
+        let uart = dt.find_by_path("/soc/serial@7e215040").unwrap();
+        let mut mapped = Vec::new();
+
+        let regs = map_device_mmio(&dt, uart, 0, mapper(&mut mapped)).unwrap();
+        assert_eq!(mapped, [PhysRange::with_end(0x3f21_5000, 0x3f21_6000)]);
+        assert_eq!(regs, VirtRange::with_len(VirtAddr::new(0x1_3f21_5040), 0x40));
+    }
+
+    #[test]
+    fn maps_reg_by_index() {
+        let dt = DeviceTree::new(TEST1_DTB).unwrap();
+        let watchdog = dt.find_by_path("/soc/watchdog@7e100000").unwrap();
+        let mut mapped = Vec::new();
+
+        let regs = map_device_mmio(&dt, watchdog, 1, mapper(&mut mapped)).unwrap();
+        assert_eq!(mapped, [PhysRange::with_end(0x3f00_a000, 0x3f00_b000)]);
+        assert_eq!(regs, VirtRange::with_len(VirtAddr::new(0x1_3f00_a000), 0x24));
+
+        let mut mapped = Vec::new();
+        let err = map_device_mmio(&dt, watchdog, 2, mapper(&mut mapped));
+        assert_eq!(err, Err(MapDeviceError::NoReg(2)));
+        assert!(mapped.is_empty());
+    }
+
+    #[test]
+    fn reports_unmappable_regs() {
+        let dt = DeviceTree::new(TEST1_DTB).unwrap();
+        let spidev = dt.find_by_path("/soc/spi@7e204000/spidev@0").unwrap();
+        let map = |_: &PhysRange| -> Result<VirtRange, ()> { panic!("nothing to map") };
+        assert_eq!(map_device_mmio(&dt, spidev, 0, map), Err(MapDeviceError::Unreachable(0)));
+
+        let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
+        let failed = map_device_mmio(&dt, uart, 0, |_| Err("no space"));
+        assert_eq!(failed, Err(MapDeviceError::Map("no space")));
+    }
+
+    mmio_registers! {
+        struct TestReg {
+            A: u32, ReadWrite, 0x0;
+            B: u16, ReadOnly, 0x4;
+            C: u64, WriteOnly, 0x8;
+        }
+    }
+
+    /// Return an Mmio over `regs`.
+    fn mmio(regs: &mut [u64; 2]) -> Mmio {
+        let start = VirtAddr::new(regs.as_mut_ptr().addr());
+        unsafe { Mmio::new(VirtRange::with_len(start, size_of_val(regs))) }
+    }
+
+    #[test]
+    fn typed_registers() {
+        let mut regs = [0x1234_5678_9abc_def0u64, 0];
+        let mmio = mmio(&mut regs);
+        assert_eq!(mmio.read(TestReg::A), 0x9abc_def0);
+        assert_eq!(mmio.read(TestReg::B), 0x5678);
+        mmio.modify(TestReg::A, |a| a & 0xffff);
+        mmio.write(TestReg::C, 0x1122_3344_5566_7788);
+        assert_eq!(mmio.read32(0), 0xdef0);
+        assert_eq!(mmio.read64(8), 0x1122_3344_5566_7788);
+        mmio.write32(12, 0);
+        assert_eq!(regs, [0x1234_5678_0000_def0, 0x5566_7788]);
+        assert_eq!(TestReg::A.nth(2).offset(), 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "mmio offset 0xc size 8 outside")]
+    fn access_past_end() {
+        let mut regs = [0u64; 2];
+        mmio(&mut regs).read64(12);
+    }
+}
@@ -0,0 +1,181 @@
+/// oncelock holds values that are initialised once, and then only read, such
+/// as the console uart, without static mut or hand-rolled initialised flags.
+/// `OnceLock` is like std's: if several cores try to initialise it at once,
+/// one wins, and the others spin until the winner's value is ready.  `Lazy`
+/// initialises its value with a fixed function on first use.
+///
+/// If initialisation panics, the value is never set, and later attempts spin
+/// forever, which is fine as kernel panics don't return.
+use core::cell::UnsafeCell;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const UNINIT: u8 = 0; // No value, and no one is initialising it
+const RUNNING: u8 = 1; // Being initialised
+const DONE: u8 = 2; // Holds a value
+
+pub struct OnceLock<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Values are shared between cores once set, and may be set on any core
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> OnceLock<T> {
+        OnceLock { state: AtomicU8::new(UNINIT), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Return the value, or None if it isn't set, or is still being set.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == DONE {
+            // Safety: the value is written before DONE is stored, and never
+            // changed after
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Set the value, if it isn't already set.  If it's being set elsewhere,
+    /// waits until it is.  Returns `value` if it wasn't used.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the value, setting it with `f` first if it isn't set.  If it's
+    /// being set elsewhere, waits until it is, and `f` isn't called.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        match self.state.compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                // Safety: only this core can get here, and no one reads the
+                // value until DONE is stored
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(DONE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != DONE {
+                    hint::spin_loop();
+                }
+            }
+        }
+        // Safety: DONE has been stored
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == DONE {
+            // Safety: the value was set, and isn't used again
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value initialised by calling `init` on first use, e.g. a table built at
+/// runtime.  `init` is usually a fn, so that a `Lazy` can be a static.
+pub struct Lazy<T, F = fn() -> T> {
+    value: OnceLock<T>,
+    init: F,
+}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy { value: OnceLock::new(), init }
+    }
+
+    /// Return the value, initialising it if this is the first use.
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.value.get_or_init(&this.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn set_succeeds_once() {
+        let once = OnceLock::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(once.set(1), Ok(()));
+        assert_eq!(once.set(2), Err(2));
+        assert_eq!(once.get_or_init(|| 3), &1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test]
+    fn concurrent_init_runs_once() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let once = Arc::new(OnceLock::new());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let once = once.clone();
+                thread::spawn(move || {
+                    *once.get_or_init(|| {
+                        CALLS.fetch_add(1, Ordering::Relaxed);
+                        // Give the other threads time to find it running
+                        thread::sleep(std::time::Duration::from_millis(10));
+                        i
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|&v| v == values[0]));
+    }
+
+    #[test]
+    fn value_dropped_with_lock() {
+        let value = Arc::new(());
+        let once = OnceLock::new();
+        once.set(value.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&value), 2);
+        drop(once);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn lazy_inits_on_first_use() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static TABLE: Lazy<[u32; 4]> = Lazy::new(|| {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            core::array::from_fn(|i| 1 << i)
+        });
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        assert_eq!(TABLE[3], 8);
+        assert_eq!(*TABLE, [1, 2, 4, 8]);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
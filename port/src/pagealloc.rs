@@ -1,9 +1,893 @@
-/// General page allocation errors.  Not specific to any particular implementation, and also includes higher-level errors.
+use crate::mem::{MemError, PhysAddr, PhysRange, PhysRangeSet, VirtAddr, VirtRange};
+use crate::memfill::zero_range;
+use core::fmt;
+
+/// Errors from reserving a specific physical range, giving the first page
+/// that couldn't be reserved.
 #[derive(Debug, PartialEq)]
-pub enum PageAllocError {
-    OutOfBounds,
-    MisalignedAddr,
-    OutOfSpace,
-    NotAllocated,
-    UnableToMap,
+pub enum ReserveError {
+    Allocated(PhysAddr),   // The page is already allocated or reserved
+    NotInMemory(PhysAddr), // The page isn't in memory managed by the allocator
+}
+
+/// Summary of the pages managed by a page allocator after initialisation.
+#[derive(Debug, PartialEq)]
+pub struct PageAllocSummary {
+    pub total_pages: usize,    // Pages of physical memory managed by the allocator
+    pub reserved_pages: usize, // Pages excluded from allocation (kernel, DTB, etc.)
+    pub free_pages: usize,     // Pages available for allocation
+}
+
+/// Point-in-time statistics for a page allocator.  The counts are maintained
+/// as pages are allocated and freed, so are cheap to fetch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PageAllocStats {
+    pub total_pages: usize,          // Pages available for allocation after init
+    pub free_pages: usize,           // Pages currently free
+    pub allocated_pages: usize,      // Pages currently allocated
+    pub peak_allocated_pages: usize, // Most pages allocated at once since init
+}
+
+impl fmt::Display for PageAllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pages: total {} free {} allocated {} peak {}",
+            self.total_pages, self.free_pages, self.allocated_pages, self.peak_allocated_pages
+        )
+    }
+}
+
+/// Gives access to physical pages through a kernel mapping.
+///
+/// # Safety
+///
+/// Any pointer returned by `page_ptr` must be valid for reads and writes of
+/// the whole page, aligned to 8 bytes, and the page mustn't be in use by
+/// anything other than the page allocator or the caller it was allocated for.
+pub unsafe trait PageMapper {
+    /// Return a pointer to the page at `pa`, or None if the page isn't mapped.
+    fn page_ptr(&self, pa: PhysAddr, page_size: usize) -> Option<*mut u8>;
+}
+
+/// Memory holding a page allocator's own state, such as its bitmaps, which is
+/// carved out of usable memory by `init_from`.
+#[derive(Debug)]
+pub struct Metadata {
+    pub range: PhysRange, // Where the state is in physical memory, or empty
+    pub ptr: *mut u8,     // Where the state can be reached
+}
+
+impl Metadata {
+    /// No memory, for an allocator that needs none.  The pointer is aligned
+    /// for any of the allocators' state.
+    pub const fn empty() -> Self {
+        Self {
+            range: PhysRange(PhysAddr::new(0)..PhysAddr::new(0)),
+            ptr: core::ptr::dangling_mut::<u64>() as *mut u8,
+        }
+    }
+}
+
+/// Zero all pages in `range` through `mapper`.  Fails with `PhysNotMapped`,
+/// giving the first page that isn't reachable through `mapper`, without
+/// writing anything.
+pub fn zero_pages(
+    mapper: &impl PageMapper,
+    range: &PhysRange,
+    page_size: usize,
+) -> Result<(), MemError> {
+    if let Some(pa) =
+        range.step_by_rounded(page_size).find(|&pa| mapper.page_ptr(pa, page_size).is_none())
+    {
+        return Err(MemError::PhysNotMapped { pa });
+    }
+    for pa in range.step_by_rounded(page_size) {
+        if let Some(ptr) = mapper.page_ptr(pa, page_size) {
+            // Safety: PageMapper guarantees the pointer covers the whole page
+            unsafe { zero_range(&VirtRange::with_len(VirtAddr::new(ptr.addr()), page_size)) };
+        }
+    }
+    Ok(())
+}
+
+/// Interface to a physical page allocator, so that the implementation can be
+/// chosen without changing callers.  Addresses are physical, and nothing is
+/// mapped.
+pub trait PageAlloc {
+    /// Size of the pages handed out by the allocator.
+    fn page_size(&self) -> usize;
+
+    /// Return the end of the physical memory the allocator can describe.
+    /// Memory beyond this is ignored by `init_from`.
+    fn max_end(&self) -> PhysAddr;
+
+    /// Mark the pages in the given physical range as allocated, regardless
+    /// of the existing state.
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError>;
+
+    /// Mark the pages in the given physical range as free, regardless of the
+    /// existing state.
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError>;
+
+    /// Initialise the allocator from the physical memory ranges, excluding
+    /// the reserved ranges.  Only whole pages of memory are made available,
+    /// and any page touched by a reserved range is excluded.  Memory beyond
+    /// what the allocator can describe is ignored.  Everything that isn't
+    /// usable memory is marked as allocated, regardless of the existing state.
+    /// Any state the allocator keeps for the memory is carved out of the
+    /// usable memory, reached through `mapper`, and counted as reserved.
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError>;
+
+    /// Call `f` with each range of memory carved out by `init_from` for the
+    /// allocator's own state, in no particular order.
+    fn for_each_metadata_range(&self, _f: impl FnMut(&PhysRange)) {}
+
+    /// Reach the allocator's state through `mapper` from now on, e.g. once
+    /// translation is on and memory can't be reached at its physical address.
+    /// Fails with `PhysNotMapped` if any of it can't be reached, in which case
+    /// some of the state may still be reached the old way.
+    fn remap_metadata(&mut self, _mapper: &impl PageMapper) -> Result<(), MemError> {
+        Ok(())
+    }
+
+    /// Try to allocate a single page.
+    fn allocate(&mut self) -> Result<PhysAddr, MemError>;
+
+    /// Try to allocate a single page, zeroed through `mapper`.  Pages must be
+    /// reachable through `mapper` to be zeroed, and if the page allocated
+    /// isn't, it's freed again and `PhysNotMapped` is returned, so the caller can
+    /// zero it some other way, e.g. once it's mapped.
+    fn allocate_zeroed(&mut self, mapper: &impl PageMapper) -> Result<PhysAddr, MemError> {
+        self.allocate_contiguous_zeroed(1, mapper).map(|range| range.start())
+    }
+
+    /// Try to allocate `page_count` physically contiguous pages, returning the
+    /// range covering them.
+    fn allocate_contiguous(&mut self, page_count: usize) -> Result<PhysRange, MemError> {
+        self.allocate_aligned(page_count, self.page_size() as u64)
+    }
+
+    /// Try to allocate `page_count` physically contiguous pages, zeroed
+    /// through `mapper`.  As for `allocate_zeroed`, if the pages aren't all
+    /// reachable through `mapper`, they're freed and `PhysNotMapped` is returned.
+    fn allocate_contiguous_zeroed(
+        &mut self,
+        page_count: usize,
+        mapper: &impl PageMapper,
+    ) -> Result<PhysRange, MemError> {
+        let range = self.allocate_contiguous(page_count)?;
+        if let Err(err) = zero_pages(mapper, &range, self.page_size()) {
+            self.free_range(&range)?;
+            return Err(err);
+        }
+        Ok(range)
+    }
+
+    /// Try to allocate `page_count` physically contiguous pages, where the
+    /// start of the range is a multiple of `align`, e.g. to allow the range to
+    /// be mapped with 2MiB pages.  `align` must be a power of two no larger
+    /// than the managed region.  Alignments smaller than a page are treated
+    /// as page aligned.
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError>;
+
+    /// Try to allocate a single page that lies entirely below `limit`, e.g. for
+    /// a device that can only address the low 1GiB of physical memory.
+    fn allocate_below(&mut self, limit: PhysAddr) -> Result<PhysAddr, MemError> {
+        self.allocate_contiguous_below(1, limit).map(|range| range.start())
+    }
+
+    /// Try to allocate `page_count` physically contiguous pages, where the
+    /// whole range lies below `limit`.
+    fn allocate_contiguous_below(
+        &mut self,
+        page_count: usize,
+        limit: PhysAddr,
+    ) -> Result<PhysRange, MemError> {
+        self.allocate_contiguous_within(page_count, &PhysRange::new(PhysAddr::new(0), limit))
+    }
+
+    /// Try to allocate `page_count` physically contiguous pages, where the
+    /// whole range lies within `within`, e.g. a zone of memory.
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError>;
+
+    /// Deallocate the page corresponding to the given PhysAddr.  Fails if the
+    /// page isn't page aligned, isn't inside the managed region, or isn't
+    /// currently allocated.
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError>;
+
+    /// Free the page corresponding to the given PhysAddr.  Unlike `deallocate`,
+    /// in debug builds this panics with the offending address if the page
+    /// isn't page aligned, isn't inside the managed region, or isn't
+    /// currently allocated (e.g. a double free).
+    fn free(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        self.deallocate(pa).inspect_err(|err| {
+            if cfg!(debug_assertions) {
+                panic!("free: can't free page {:?}: {}", pa, err);
+            }
+        })
+    }
+
+    /// Free all pages in the given range, e.g. as returned by
+    /// `allocate_contiguous`.  The range must be page aligned.  All pages are
+    /// checked before any are freed, so on error the allocator is unchanged.
+    /// In debug builds, errors panic with the offending address, as for `free`.
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError>;
+
+    /// Mark the pages covering `range` as allocated, e.g. for a buffer at an
+    /// address chosen by firmware, returning the range reserved, which is
+    /// rounded outward to whole pages.  Unlike `mark_allocated`, this fails if
+    /// any page is already allocated or isn't in memory, giving the first such
+    /// page, and in that case nothing is reserved.
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError>;
+
+    /// Release a range reserved by `reserve_range`, rounded outward to whole
+    /// pages in the same way.  Errors are as for `free_range`.
+    fn release_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.free_range(&range.round_out(self.page_size() as u64))
+    }
+
+    /// Return the current page counts.  These are maintained as pages are
+    /// allocated and freed, rather than by scanning.  Before the allocator is
+    /// initialised, `total_pages` is zero.
+    fn stats(&self) -> PageAllocStats;
+
+    /// Call `f` with each maximal range of free pages, in address order.
+    fn for_each_free_range(&self, f: impl FnMut(&PhysRange));
+
+    /// Call `f` with the physical range covered by each region of memory
+    /// managed separately by the allocator, and the stats for that region, in
+    /// address order.  By default, there's a single region from address 0.
+    fn for_each_region(&self, mut f: impl FnMut(&PhysRange, PageAllocStats)) {
+        f(&PhysRange::new(PhysAddr::new(0), self.max_end()), self.stats());
+    }
+
+    /// Return a tuple of (bytes used, total bytes available).
+    fn usage_bytes(&self) -> (usize, usize);
+
+    /// Write the managed regions and stats to `w`.  If `show_map` is set, also
+    /// write each run of used or free pages, which can be long if memory is
+    /// fragmented.
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result;
+}
+
+/// A page allocator that keeps its state in memory carved out by `init_from`,
+/// which can instead be given memory carved out by the caller, e.g. so that
+/// the state for a region of memory that can't be reached yet can be kept in
+/// memory that can.
+pub trait CarvedPageAlloc: PageAlloc {
+    /// Return the number of bytes of state needed for memory up to `end`.
+    fn metadata_size(&self, end: PhysAddr) -> usize;
+
+    /// Initialise the allocator as for `init_from`, but keep its state in
+    /// `metadata` rather than carving it out of `memory`.
+    ///
+    /// # Safety
+    ///
+    /// `metadata.ptr` must be valid for reads and writes of `metadata_size`
+    /// bytes for the end of `memory`, aligned to 8 bytes, for as long as the
+    /// allocator is used, and the memory mustn't be usable memory.
+    unsafe fn init_with_metadata<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        metadata: Metadata,
+    ) -> Result<PageAllocSummary, MemError>;
+}
+
+/// Carve `size` bytes for an allocator's state out of `usable`, as whole
+/// pages from the top of the highest range that has room and can be reached
+/// through `mapper`.  Low memory is left alone, since some devices can only
+/// reach that.  Fails with `PhysNotMapped` if only ranges that can't be
+/// reached have room.
+pub(crate) fn carve_metadata<const N: usize>(
+    usable: &mut PhysRangeSet<N>,
+    size: usize,
+    page_size: usize,
+    mapper: &impl PageMapper,
+) -> Result<Metadata, MemError> {
+    if size == 0 {
+        return Ok(Metadata::empty());
+    }
+    let len = size.next_multiple_of(page_size);
+    let mut not_mapped = None;
+    for range in usable.as_slice().iter().rev().filter(|r| r.size() >= len) {
+        let range = PhysRange::with_end(range.end().addr() - len as u64, range.end().addr());
+        let Some(ptr) = mapper.page_ptr(range.start(), len) else {
+            not_mapped.get_or_insert(range.start());
+            continue;
+        };
+        usable.remove(&range)?;
+        return Ok(Metadata { range, ptr });
+    }
+    Err(match not_mapped {
+        Some(pa) => MemError::PhysNotMapped { pa },
+        None => MemError::OutOfMemory { requested: len, available: usable.size() },
+    })
+}
+
+/// Round the memory ranges inward to whole pages below `max_end`, returning
+/// those ranges, and the same ranges less the reserved ranges, which are
+/// rounded outward to whole pages.
+pub(crate) fn usable_ranges<const N: usize>(
+    memory: &PhysRangeSet<N>,
+    reserved: &[PhysRange],
+    page_size: usize,
+    max_end: PhysAddr,
+) -> Result<(PhysRangeSet, PhysRangeSet<N>), MemError> {
+    let page_size = page_size as u64;
+
+    let mut regions = PhysRangeSet::new();
+    let mut usable = PhysRangeSet::<N>::new();
+    for range in memory.iter() {
+        let start = range.start().round_up(page_size);
+        let end = range.end().min(max_end).round_down(page_size);
+        if start < end {
+            let range = PhysRange::new(start, end);
+            usable.add(&range)?;
+            regions.add(&range)?;
+        }
+    }
+
+    for range in reserved {
+        let start = range.start().round_down(page_size);
+        let end = range.end().round_up(page_size);
+        usable.remove(&PhysRange::new(start, end))?;
+    }
+
+    Ok((regions, usable))
+}
+
+/// Check that every page in `range` can be freed, using `allocated_page_index`
+/// to validate each page address, so that on error nothing need be freed.  In
+/// debug builds, errors panic with the offending address.
+pub(crate) fn check_free_range(
+    range: &PhysRange,
+    page_size: usize,
+    mut allocated_page_index: impl FnMut(PhysAddr) -> Result<usize, MemError>,
+) -> Result<(), MemError> {
+    let result = if !range.start().is_multiple_of(page_size as u64)
+        || !range.end().is_multiple_of(page_size as u64)
+    {
+        let addr = if range.start().is_multiple_of(page_size as u64) {
+            range.end()
+        } else {
+            range.start()
+        };
+        Err((addr, MemError::Unaligned { addr: addr.addr(), required: page_size }))
+    } else {
+        range
+            .step_by_rounded(page_size)
+            .try_for_each(|pa| allocated_page_index(pa).map(|_| ()).map_err(|e| (pa, e)))
+    };
+    if let Err((pa, err)) = result {
+        if cfg!(debug_assertions) {
+            panic!("free_range: can't free page {:?} in {}: {}", pa, range, err);
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Return the error for failing to allocate `page_count` pages of
+/// `page_size`, given the allocator's current `stats`.
+pub(crate) fn out_of_memory(
+    page_count: usize,
+    page_size: usize,
+    stats: PageAllocStats,
+) -> MemError {
+    MemError::OutOfMemory {
+        requested: page_count.saturating_mul(page_size),
+        available: stats.free_pages * page_size,
+    }
+}
+
+/// Round `range` outward to whole pages, and check that every page is within
+/// `regions`, and free according to `is_page_allocated`, so that the rounded
+/// range, which is returned, can be reserved.
+pub(crate) fn check_reserve_range(
+    range: &PhysRange,
+    page_size: usize,
+    regions: &PhysRangeSet,
+    is_page_allocated: impl Fn(PhysAddr) -> bool,
+) -> Result<PhysRange, ReserveError> {
+    let range = range.round_out(page_size as u64);
+    for pa in range.step_by_rounded(page_size) {
+        if !regions.iter().any(|r| r.start() <= pa && pa < r.end()) {
+            return Err(ReserveError::NotInMemory(pa));
+        }
+        if is_page_allocated(pa) {
+            return Err(ReserveError::Allocated(pa));
+        }
+    }
+    Ok(range)
+}
+
+/// Iterate over each run of used or free pages from page 0 up to `num_pages`,
+/// returning the range and whether it's allocated.
+pub(crate) fn page_runs(
+    num_pages: usize,
+    page_size: usize,
+    is_page_allocated: impl Fn(usize) -> bool,
+) -> impl Iterator<Item = (PhysRange, bool)> {
+    let mut run_start = 0;
+    (1..=num_pages).filter_map(move |page_idx| {
+        let allocated = is_page_allocated(run_start);
+        if page_idx == num_pages || is_page_allocated(page_idx) != allocated {
+            let range = PhysRange::new(
+                PhysAddr::new((run_start * page_size) as u64),
+                PhysAddr::new((page_idx * page_size) as u64),
+            );
+            run_start = page_idx;
+            Some((range, allocated))
+        } else {
+            None
+        }
+    })
+}
+
+/// Write each run of used or free pages from page 0 up to `num_pages`.
+pub(crate) fn write_page_runs(
+    w: &mut impl fmt::Write,
+    num_pages: usize,
+    page_size: usize,
+    is_page_allocated: impl Fn(usize) -> bool,
+) -> fmt::Result {
+    for (range, allocated) in page_runs(num_pages, page_size, is_page_allocated) {
+        writeln!(w, "  {} {}", range, if allocated { "used" } else { "free" })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmapalloc::BitmapPageAlloc;
+    use crate::buddyalloc::BuddyPageAlloc;
+    use crate::fakemem::{FakeMemory, ScratchMapper, init_outside};
+
+    // Tests of the PageAlloc behaviour common to all implementations.  Each
+    // allocator covers 32 pages of 4 bytes, i.e. 128 bytes of physical memory.
+    // Implementations may hand out different addresses, so the tests check
+    // properties of the allocations rather than exact addresses.  Other than
+    // where carving is tested, the allocators keep their state outside the
+    // memory they manage, so that every usable page is free.
+
+    fn init(alloc: &mut impl CarvedPageAlloc, memory: &[PhysRange], reserved: &[PhysRange]) {
+        let mut memory_set = PhysRangeSet::<4>::new();
+        for range in memory {
+            memory_set.add(range).unwrap();
+        }
+        init_outside(alloc, &memory_set, reserved).unwrap();
+    }
+
+    fn overlaps(a: &PhysRange, b: &PhysRange) -> bool {
+        a.start() < b.end() && b.start() < a.end()
+    }
+
+    fn allocate_all_and_free(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+
+        let mut pages = Vec::new();
+        while let Ok(pa) = alloc.allocate() {
+            assert!(pa.is_multiple_of(4) && pa.addr() < 128);
+            assert!(!pages.contains(&pa), "{:?} allocated twice", pa);
+            pages.push(pa);
+        }
+        assert_eq!(pages.len(), 32);
+        assert_eq!(alloc.allocate(), Err(MemError::OutOfMemory { requested: 4, available: 0 }));
+        assert_eq!(alloc.usage_bytes(), (128, 128));
+
+        for pa in pages {
+            alloc.free(pa)?;
+        }
+        assert_eq!(alloc.usage_bytes(), (0, 128));
+        assert_eq!(alloc.allocate_contiguous(32)?, PhysRange::with_end(0, 128));
+        Ok(())
+    }
+
+    fn allocate_contiguous(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+
+        let mut ranges: Vec<PhysRange> = Vec::new();
+        for page_count in [3, 1, 5, 2, 4] {
+            let range = alloc.allocate_contiguous(page_count)?;
+            assert_eq!(range.size(), page_count * 4);
+            assert!(range.start().is_multiple_of(4) && range.end().addr() <= 128);
+            assert!(!ranges.iter().any(|r| overlaps(r, &range)), "{} overlaps", range);
+            ranges.push(range);
+        }
+        assert_eq!(alloc.allocate_contiguous(0), Err(MemError::InvalidPageCount { count: 0 }));
+        assert_eq!(
+            alloc.allocate_contiguous(33),
+            Err(MemError::OutOfMemory { requested: 132, available: 68 })
+        );
+
+        for range in &ranges {
+            alloc.free_range(range)?;
+        }
+        assert_eq!(alloc.stats().allocated_pages, 0);
+        Ok(())
+    }
+
+    fn allocate_aligned(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+        alloc.allocate()?;
+
+        let range = alloc.allocate_aligned(2, 32)?;
+        assert_eq!(range.size(), 8);
+        assert!(range.start().is_multiple_of(32));
+        let range = alloc.allocate_aligned(1, 64)?;
+        assert!(range.start().is_multiple_of(64));
+
+        assert_eq!(alloc.allocate_aligned(1, 12), Err(MemError::InvalidAlignment { align: 12 }));
+        assert_eq!(alloc.allocate_aligned(1, 256), Err(MemError::InvalidAlignment { align: 256 }));
+        Ok(())
+    }
+
+    fn allocate_below(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+
+        let limit = PhysAddr::new(32);
+        let range = alloc.allocate_contiguous_below(3, limit)?;
+        assert!(range.end() <= limit);
+        let mut count = 3;
+        while let Ok(pa) = alloc.allocate_below(limit) {
+            assert!(pa < limit);
+            count += 1;
+        }
+        assert_eq!(count, 8);
+
+        // Memory remains above the limit
+        assert_eq!(
+            alloc.allocate_contiguous_below(1, limit),
+            Err(MemError::OutOfMemory { requested: 4, available: 96 })
+        );
+        assert!(alloc.allocate()? >= limit);
+        Ok(())
+    }
+
+    fn allocate_within(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+
+        let within = PhysRange::with_end(32, 64);
+        let range = alloc.allocate_contiguous_within(3, &within)?;
+        assert!(within.start() <= range.start() && range.end() <= within.end());
+        let mut count = 3;
+        while let Ok(range) = alloc.allocate_contiguous_within(1, &within) {
+            assert!(within.start() <= range.start() && range.end() <= within.end());
+            count += 1;
+        }
+        assert_eq!(count, 8);
+
+        // Memory remains on both sides
+        assert!(alloc.allocate()? < within.start());
+        assert!(
+            alloc.allocate_contiguous_within(2, &PhysRange::with_end(36, 128))?.start()
+                >= PhysAddr::new(64)
+        );
+        let unaligned = PhysRange::with_end(97, 107);
+        assert_eq!(alloc.allocate_contiguous_within(1, &unaligned)?, PhysRange::with_end(100, 104));
+        assert_eq!(
+            alloc.allocate_contiguous_within(1, &unaligned),
+            Err(MemError::OutOfMemory { requested: 4, available: 80 })
+        );
+        Ok(())
+    }
+
+    fn init_from_reserved(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        // Two banks of memory, the first not page aligned, and the second
+        // extending beyond the end of what the allocator can describe.
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(2, 46)).unwrap();
+        memory.add(&PhysRange::with_end(64, 256)).unwrap();
+
+        // Reserved ranges, including one outside memory, and one not page aligned
+        let reserved = [
+            PhysRange::with_end(8, 16),
+            PhysRange::with_end(30, 34),
+            PhysRange::with_end(200, 300),
+        ];
+
+        let summary = init_outside(&mut alloc, &memory, &reserved)?;
+        assert_eq!(
+            summary,
+            PageAllocSummary { total_pages: 26, reserved_pages: 4, free_pages: 22 }
+        );
+        assert_eq!(alloc.usage_bytes(), (40, 128));
+
+        // Only pages in memory and outside the reserved ranges are allocated
+        let usable = [
+            PhysRange::with_end(4, 8),
+            PhysRange::with_end(16, 28),
+            PhysRange::with_end(36, 44),
+            PhysRange::with_end(64, 128),
+        ];
+        let mut count = 0;
+        while let Ok(pa) = alloc.allocate() {
+            assert!(usable.iter().any(|r| r.start() <= pa && pa < r.end()), "{:?}", pa);
+            count += 1;
+        }
+        assert_eq!(count, 22);
+        Ok(())
+    }
+
+    fn init_from_carves_metadata(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        // The state is carved from the top of the highest usable memory with
+        // room for it, which is below the reserved ranges, and counted as
+        // reserved
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0, 96)).unwrap();
+        memory.add(&PhysRange::with_end(112, 116)).unwrap();
+        let pages = alloc.metadata_size(PhysAddr::new(116)).div_ceil(4);
+        let reserved = [PhysRange::with_end(92, 96), PhysRange::with_end(114, 116)];
+
+        let summary = alloc.init_from(&memory, &reserved, &ScratchMapper)?;
+        assert_eq!(
+            summary,
+            PageAllocSummary { total_pages: 25, reserved_pages: 2 + pages, free_pages: 23 - pages }
+        );
+        let carved = PhysRange::with_len(92 - 4 * pages as u64, 4 * pages);
+        let mut ranges = Vec::new();
+        alloc.for_each_metadata_range(|range| ranges.push(range.clone()));
+        assert_eq!(ranges, if pages > 0 { vec![carved.clone()] } else { vec![] });
+
+        while let Ok(pa) = alloc.allocate() {
+            assert!(pa < carved.start() || pa >= carved.end(), "{:?}", pa);
+        }
+        Ok(())
+    }
+
+    fn deallocate_invalid(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 64)], &[]);
+        let pa = alloc.allocate()?;
+
+        assert_eq!(
+            alloc.deallocate(pa + 2),
+            Err(MemError::Unaligned { addr: pa.addr() + 2, required: 4 })
+        );
+        assert_eq!(
+            alloc.deallocate(PhysAddr::new(64)),
+            Err(MemError::OutOfRange { addr: 64, range: 0..64 })
+        );
+        alloc.deallocate(pa)?;
+        assert_eq!(alloc.deallocate(pa), Err(MemError::NotAllocated { pa }));
+        Ok(())
+    }
+
+    fn free_range_invalid(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 64)], &[]);
+        let range = alloc.allocate_contiguous(4)?;
+        alloc.free(range.start())?;
+
+        // A partially free range is rejected, leaving the allocator unchanged
+        let before = alloc.stats();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            alloc.free_range(&range).unwrap_err()
+        }));
+        match result {
+            Ok(err) => assert_eq!(err, MemError::NotAllocated { pa: range.start() }),
+            Err(_) => assert!(cfg!(debug_assertions)),
+        }
+        assert_eq!(alloc.stats(), before);
+        Ok(())
+    }
+
+    fn allocate_zeroed(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        // 16 pages of 8 bytes, of which the first 8 are mapped
+        init(&mut alloc, &[PhysRange::with_end(0, 128)], &[]);
+        let mut mem = FakeMemory::new(128, 64, u64::MAX);
+        let mapper = mem.mapper();
+
+        let range = alloc.allocate_contiguous_zeroed(2, &mapper)?;
+        let mut zeroed = range.size();
+        while let Ok(pa) = alloc.allocate_zeroed(&mapper) {
+            assert!(pa.addr() < 64);
+            zeroed += 8;
+        }
+        assert_eq!(zeroed, 64);
+        assert_eq!(mem.words, [[0; 8], [u64::MAX; 8]].concat());
+
+        // Unmapped pages are returned to the allocator
+        let not_mapped = MemError::PhysNotMapped { pa: PhysAddr::new(64) };
+        assert_eq!(alloc.allocate_zeroed(&mapper), Err(not_mapped.clone()));
+        assert_eq!(alloc.allocate_contiguous_zeroed(2, &mapper), Err(not_mapped));
+        assert_eq!(alloc.stats().allocated_pages, 8);
+        Ok(())
+    }
+
+    fn reserve_and_release(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 64)], &[PhysRange::with_end(0, 8)]);
+
+        // Partial pages are rounded outward
+        let range = PhysRange::with_end(18, 30);
+        assert_eq!(alloc.reserve_range(&range), Ok(PhysRange::with_end(16, 32)));
+        assert_eq!(alloc.stats().allocated_pages, 4);
+
+        // Conflicts give the first page that can't be reserved, and leave the
+        // allocator unchanged
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(8, 24)),
+            Err(ReserveError::Allocated(PhysAddr::new(16)))
+        );
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(4, 12)),
+            Err(ReserveError::Allocated(PhysAddr::new(4)))
+        );
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(60, 68)),
+            Err(ReserveError::NotInMemory(PhysAddr::new(64)))
+        );
+        assert_eq!(alloc.stats().allocated_pages, 4);
+
+        // Reserved pages aren't allocated
+        while let Ok(pa) = alloc.allocate() {
+            assert!(pa < PhysAddr::new(16) || pa >= PhysAddr::new(32), "{:?}", pa);
+        }
+
+        alloc.release_range(&range)?;
+        assert_eq!(alloc.allocate_contiguous(4)?, PhysRange::with_end(16, 32));
+        Ok(())
+    }
+
+    fn stats(mut alloc: impl CarvedPageAlloc) -> Result<(), MemError> {
+        init(&mut alloc, &[PhysRange::with_end(0, 64)], &[PhysRange::with_end(0, 8)]);
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 14,
+                allocated_pages: 0,
+                peak_allocated_pages: 0
+            }
+        );
+
+        let pa = alloc.allocate()?;
+        let range = alloc.allocate_contiguous(4)?;
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 9,
+                allocated_pages: 5,
+                peak_allocated_pages: 5
+            }
+        );
+
+        // Freeing doesn't lower the peak
+        alloc.free_range(&range)?;
+        alloc.free(pa)?;
+        alloc.allocate()?;
+        assert_eq!(
+            alloc.stats(),
+            PageAllocStats {
+                total_pages: 14,
+                free_pages: 13,
+                allocated_pages: 1,
+                peak_allocated_pages: 5
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn carve_metadata_from_reachable_top() -> Result<(), MemError> {
+        // Only the first 128 bytes can be reached
+        let mut mem = FakeMemory::new(256, 128, 0u64);
+        let mapper = mem.mapper();
+        let mut usable = PhysRangeSet::<4>::new();
+        for range in [PhysRange::with_end(0, 64), PhysRange::with_end(96, 128)] {
+            usable.add(&range)?;
+        }
+        usable.add(&PhysRange::with_end(192, 256))?;
+
+        // The highest range can't be reached, so the next one with room is
+        // used, in whole pages
+        let metadata = carve_metadata(&mut usable, 20, 8, &mapper)?;
+        assert_eq!(metadata.range, PhysRange::with_end(104, 128));
+        assert_eq!(metadata.ptr, mapper.page_ptr(PhysAddr::new(104), 24).unwrap());
+        let metadata = carve_metadata(&mut usable, 64, 8, &mapper)?;
+        assert_eq!(metadata.range, PhysRange::with_end(0, 64));
+        let remaining: Vec<_> = usable.iter().cloned().collect();
+        assert_eq!(remaining, [PhysRange::with_end(96, 104), PhysRange::with_end(192, 256)]);
+
+        assert_eq!(
+            carve_metadata(&mut usable, 64, 8, &mapper).unwrap_err(),
+            MemError::PhysNotMapped { pa: PhysAddr::new(192) }
+        );
+        assert_eq!(
+            carve_metadata(&mut usable, 72, 8, &mapper).unwrap_err(),
+            MemError::OutOfMemory { requested: 72, available: 72 }
+        );
+        assert!(carve_metadata(&mut usable, 0, 8, &mapper)?.range.is_empty());
+        assert_eq!(usable.size(), 72);
+        Ok(())
+    }
+
+    macro_rules! page_alloc_tests {
+        ($($name:ident: $alloc:ty,)*) => {$(
+            mod $name {
+                use super::*;
+
+                fn new_alloc() -> $alloc {
+                    <$alloc>::new_all_allocated(4)
+                }
+
+                #[test]
+                fn allocate_all_and_free() -> Result<(), MemError> {
+                    super::allocate_all_and_free(new_alloc())
+                }
+
+                #[test]
+                fn allocate_contiguous() -> Result<(), MemError> {
+                    super::allocate_contiguous(new_alloc())
+                }
+
+                #[test]
+                fn allocate_aligned() -> Result<(), MemError> {
+                    super::allocate_aligned(new_alloc())
+                }
+
+                #[test]
+                fn allocate_below() -> Result<(), MemError> {
+                    super::allocate_below(new_alloc())
+                }
+
+                #[test]
+                fn allocate_within() -> Result<(), MemError> {
+                    super::allocate_within(new_alloc())
+                }
+
+                #[test]
+                fn init_from_reserved() -> Result<(), MemError> {
+                    super::init_from_reserved(new_alloc())
+                }
+
+                #[test]
+                fn init_from_carves_metadata() -> Result<(), MemError> {
+                    super::init_from_carves_metadata(new_alloc())
+                }
+
+                #[test]
+                fn deallocate_invalid() -> Result<(), MemError> {
+                    super::deallocate_invalid(new_alloc())
+                }
+
+                #[test]
+                fn free_range_invalid() -> Result<(), MemError> {
+                    super::free_range_invalid(new_alloc())
+                }
+
+                #[test]
+                fn allocate_zeroed() -> Result<(), MemError> {
+                    // Pages must be big enough to be zeroed a word at a time
+                    super::allocate_zeroed(<$alloc>::new_all_allocated(8))
+                }
+
+                #[test]
+                fn reserve_and_release() -> Result<(), MemError> {
+                    super::reserve_and_release(new_alloc())
+                }
+
+                #[test]
+                fn stats() -> Result<(), MemError> {
+                    super::stats(new_alloc())
+                }
+            }
+        )*};
+    }
+
+    page_alloc_tests! {
+        bitmap: BitmapPageAlloc<2, 2>,
+        buddy: BuddyPageAlloc<2, 2>,
+    }
 }
@@ -0,0 +1,284 @@
+/// pagepoison wraps a page allocator to help catch use-after-free bugs.
+///
+/// Once enabled, every page is filled with a poison pattern when it's freed.
+/// When the page is allocated again, the pattern is checked, and if any of it
+/// has been overwritten, something wrote to the page after it was freed, so
+/// we panic with the page and offset.  Otherwise the page is zeroed before
+/// being handed out.
+///
+/// Pages are accessed through a `PageMapper`, and pages it can't map are left
+/// alone, so poisoning only engages for pages covered by a kernel mapping.
+/// This is intended for debug builds only, since every free and allocation
+/// touches the whole page.
+use core::{fmt, slice};
+
+use crate::{
+    mem::{MemError, PhysAddr, PhysRange, PhysRangeSet, VirtAddr, VirtRange},
+    memfill::zero_range,
+    pagealloc::{PageAlloc, PageAllocStats, PageAllocSummary, PageMapper, ReserveError},
+};
+
+/// Pattern repeated over freed pages.
+pub const POISON: u32 = 0xdead_beef;
+
+/// Page allocator that poisons pages on free and checks them on allocation.
+/// Poisoning is disabled until `enable` is called, since the mapping used to
+/// reach the pages usually isn't set up when the allocator is created.
+pub struct PoisonPageAlloc<A, M> {
+    alloc: A,
+    mapper: M,
+    enabled: bool,
+}
+
+impl<A: PageAlloc, M: PageMapper> PoisonPageAlloc<A, M> {
+    pub const fn new(alloc: A, mapper: M) -> Self {
+        Self { alloc, mapper, enabled: false }
+    }
+
+    /// Start poisoning, first poisoning all pages that are currently free.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+        self.poison_free_pages();
+    }
+
+    /// Call `f` with the page at `pa` as a slice of words, if poisoning is
+    /// enabled and the page is mapped.
+    fn with_page_words(&self, pa: PhysAddr, f: impl FnOnce(&mut [u32])) {
+        if !self.enabled {
+            return;
+        }
+        let page_size = self.alloc.page_size();
+        if let Some(ptr) = self.mapper.page_ptr(pa, page_size) {
+            // Safety: PageMapper guarantees the pointer covers the whole page,
+            // and the allocator owns the page, so nothing else refers to it.
+            f(unsafe { slice::from_raw_parts_mut(ptr as *mut u32, page_size / 4) });
+        }
+    }
+
+    fn poison(&self, range: &PhysRange) {
+        for pa in range.step_by_rounded(self.alloc.page_size()) {
+            self.with_page_words(pa, |words| words.fill(POISON));
+        }
+    }
+
+    fn poison_free_pages(&self) {
+        self.alloc.for_each_free_range(|range| self.poison(range));
+    }
+
+    /// Check that the pages are still poisoned, panicking at the first word
+    /// that isn't, then zero them.
+    fn check_and_zero(&self, range: &PhysRange) {
+        for pa in range.step_by_rounded(self.alloc.page_size()) {
+            self.with_page_words(pa, |words| {
+                if let Some(i) = words.iter().position(|&w| w != POISON) {
+                    panic!(
+                        "pagepoison: page {:?} written after free: offset {:#x} value {:#010x}",
+                        pa,
+                        i * 4,
+                        words[i]
+                    );
+                }
+                let page =
+                    VirtRange::with_len(VirtAddr::new(words.as_ptr().addr()), words.len() * 4);
+                // Safety: the words are the page, which the allocator owns
+                unsafe { zero_range(&page) };
+            });
+        }
+    }
+}
+
+impl<A: PageAlloc, M: PageMapper> PageAlloc for PoisonPageAlloc<A, M> {
+    fn page_size(&self) -> usize {
+        self.alloc.page_size()
+    }
+
+    fn max_end(&self) -> PhysAddr {
+        self.alloc.max_end()
+    }
+
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.mark_allocated(range)
+    }
+
+    /// Pages marked free are poisoned, whatever their previous state.
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.mark_free(range)?;
+        self.poison(range);
+        Ok(())
+    }
+
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError> {
+        let summary = self.alloc.init_from(memory, reserved, mapper)?;
+        self.poison_free_pages();
+        Ok(summary)
+    }
+
+    fn allocate(&mut self) -> Result<PhysAddr, MemError> {
+        let pa = self.alloc.allocate()?;
+        self.check_and_zero(&PhysRange::with_pa_len(pa, self.page_size()));
+        Ok(pa)
+    }
+
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError> {
+        let range = self.alloc.allocate_aligned(page_count, align)?;
+        self.check_and_zero(&range);
+        Ok(range)
+    }
+
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        let range = self.alloc.allocate_contiguous_within(page_count, within)?;
+        self.check_and_zero(&range);
+        Ok(range)
+    }
+
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        self.alloc.deallocate(pa)?;
+        self.poison(&PhysRange::with_pa_len(pa, self.page_size()));
+        Ok(())
+    }
+
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.free_range(range)?;
+        self.poison(range);
+        Ok(())
+    }
+
+    /// Reserved pages aren't checked or zeroed, since they may hold data put
+    /// there by something other than the kernel, e.g. firmware.
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError> {
+        self.alloc.reserve_range(range)
+    }
+
+    fn release_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.alloc.release_range(range)?;
+        self.poison(&range.round_out(self.page_size() as u64));
+        Ok(())
+    }
+
+    fn stats(&self) -> PageAllocStats {
+        self.alloc.stats()
+    }
+
+    fn for_each_free_range(&self, f: impl FnMut(&PhysRange)) {
+        self.alloc.for_each_free_range(f)
+    }
+
+    fn for_each_region(&self, f: impl FnMut(&PhysRange, PageAllocStats)) {
+        self.alloc.for_each_region(f)
+    }
+
+    fn for_each_metadata_range(&self, f: impl FnMut(&PhysRange)) {
+        self.alloc.for_each_metadata_range(f)
+    }
+
+    fn remap_metadata(&mut self, mapper: &impl PageMapper) -> Result<(), MemError> {
+        self.alloc.remap_metadata(mapper)
+    }
+
+    fn usage_bytes(&self) -> (usize, usize) {
+        self.alloc.usage_bytes()
+    }
+
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result {
+        writeln!(w, "Page poisoning: {}", if self.enabled { "enabled" } else { "disabled" })?;
+        self.alloc.dump(w, show_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmapalloc::BitmapPageAlloc;
+    use crate::buddyalloc::BuddyPageAlloc;
+    use crate::fakemem::{FakeMemory, init_512};
+
+    fn new_mem() -> FakeMemory<u32> {
+        FakeMemory::new(512, 256, 0xaaaa_aaaa)
+    }
+
+    /// The buddy allocator takes the smallest free block first, which needn't
+    /// be mapped, so pages to check are taken from here.
+    fn mapped() -> PhysRange {
+        PhysRange::with_end(0, 256)
+    }
+
+    /// 32 pages of 16 bytes, with memory from 0 to 512, of which 0..256 is
+    /// mapped.
+    fn new_alloc<A: PageAlloc>(
+        alloc: A,
+        mem: &mut FakeMemory<u32>,
+    ) -> PoisonPageAlloc<A, *mut FakeMemory<u32>> {
+        let mut alloc = PoisonPageAlloc::new(alloc, mem.mapper());
+        init_512(&mut alloc, &[]);
+        alloc
+    }
+
+    fn poison_and_zero(alloc: impl PageAlloc) -> Result<(), MemError> {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(alloc, &mut mem);
+
+        // Nothing is touched until enabled
+        let pa = alloc.allocate()?;
+        alloc.free(pa)?;
+        assert_eq!(mem.page(pa, 16), [0xaaaa_aaaa; 4]);
+
+        // Enabling poisons all free, mapped pages, but leaves unmapped pages
+        alloc.enable();
+        assert_eq!(mem.page(PhysAddr::new(0), 16), [POISON; 4]);
+        assert_eq!(mem.page(PhysAddr::new(240), 16), [POISON; 4]);
+        assert_eq!(mem.page(PhysAddr::new(256), 16), [0xaaaa_aaaa; 4]);
+
+        // Allocated pages are zeroed, and poisoned again when freed
+        let range = alloc.allocate_contiguous_within(2, &mapped())?;
+        assert_eq!(mem.page(range.start(), 16), [0; 4]);
+        assert_eq!(mem.page(range.start() + 16, 16), [0; 4]);
+        alloc.free_range(&range)?;
+        assert_eq!(mem.page(range.start(), 16), [POISON; 4]);
+        assert_eq!(mem.page(range.start() + 16, 16), [POISON; 4]);
+        Ok(())
+    }
+
+    fn write_after_free(alloc: impl PageAlloc) {
+        let mut mem = new_mem();
+        let mut alloc = new_alloc(alloc, &mut mem);
+        alloc.enable();
+
+        let pa = alloc.allocate_contiguous_within(1, &mapped()).unwrap().start();
+        alloc.free(pa).unwrap();
+        mem.words[pa.addr() as usize / 4 + 2] = 0x1234_5678;
+
+        // Allocate until we get the modified page back
+        while alloc.allocate().unwrap() != pa {}
+    }
+
+    #[test]
+    fn pagepoison_bitmap_poison_and_zero() -> Result<(), MemError> {
+        poison_and_zero(BitmapPageAlloc::<2, 2>::new_all_allocated(16))
+    }
+
+    #[test]
+    fn pagepoison_buddy_poison_and_zero() -> Result<(), MemError> {
+        poison_and_zero(BuddyPageAlloc::<2, 2>::new_all_allocated(16))
+    }
+
+    #[test]
+    #[should_panic(expected = "written after free: offset 0x8 value 0x12345678")]
+    fn pagepoison_bitmap_write_after_free() {
+        write_after_free(BitmapPageAlloc::<2, 2>::new_all_allocated(16));
+    }
+
+    #[test]
+    #[should_panic(expected = "written after free: offset 0x8 value 0x12345678")]
+    fn pagepoison_buddy_write_after_free() {
+        write_after_free(BuddyPageAlloc::<2, 2>::new_all_allocated(16));
+    }
+}
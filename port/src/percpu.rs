@@ -0,0 +1,119 @@
+/// percpu holds state with a separate instance for each core, such as the
+/// locks a core holds, so cores don't contend for it.  Each arch sets how
+/// to find the current core's id with `set_core_id_fn`.  Until it does, or
+/// before secondary cores start, everything runs as core 0.
+///
+/// Slots are only ever shared references, as other cores may read them, e.g.
+/// to aggregate statistics, so state that changes needs interior mutability,
+/// such as atomics.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Function returning the current core's id, or null if there isn't one.
+static CORE_ID_FN: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// Set the function returning the current core's id.  Ids count from 0, and
+/// should be dense, as they index `PerCpu` slots.  It must be cheap, e.g.
+/// reading a register, as it's called on every access.
+pub fn set_core_id_fn(core_id: fn() -> usize) {
+    CORE_ID_FN.store(core_id as *mut (), Ordering::Release);
+}
+
+/// Return the current core's id.  Host tests give each thread its own id,
+/// so that threads behave like cores.
+#[cfg(not(test))]
+pub fn core_id() -> usize {
+    let core_id_fn = CORE_ID_FN.load(Ordering::Acquire);
+    if core_id_fn.is_null() {
+        return 0;
+    }
+    // Safety: only set_core_id_fn stores to CORE_ID_FN, and it stores a
+    // fn() -> usize
+    let core_id_fn = unsafe { core::mem::transmute::<*mut (), fn() -> usize>(core_id_fn) };
+    core_id_fn()
+}
+
+#[cfg(test)]
+pub fn core_id() -> usize {
+    use core::sync::atomic::AtomicUsize;
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    std::thread_local! {
+        static ID: usize = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+/// A `T` for each of `N` cores.  `N` is the arch's `param::MAX_CORES`, so
+/// every core the arch starts has a slot.
+pub struct PerCpu<T, const N: usize> {
+    slots: [T; N],
+}
+
+impl<T, const N: usize> PerCpu<T, N> {
+    /// Use `slots`, one per core, e.g. `PerCpu::new([const { T::new() }; N])`
+    /// for a static.
+    pub const fn new(slots: [T; N]) -> Self {
+        Self { slots }
+    }
+
+    /// Return the current core's slot.  Panics if the core's id is too large.
+    pub fn get(&self) -> &T {
+        self.get_for(core_id())
+    }
+
+    /// Return the slot of the core with id `core`.  Panics if `core` is too
+    /// large.
+    pub fn get_for(&self, core: usize) -> &T {
+        match self.slots.get(core) {
+            Some(slot) => slot,
+            None => panic!("percpu: no slot for core {core}, only {N} cores supported"),
+        }
+    }
+
+    /// Iterate over all the slots, in order of core id, e.g. to total
+    /// per-core counts.
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.slots.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn slots_are_per_core() {
+        let counts: PerCpu<AtomicUsize, 4> = PerCpu::new([const { AtomicUsize::new(0) }; 4]);
+        counts.get_for(1).fetch_add(2, Ordering::Relaxed);
+        counts.get_for(3).fetch_add(5, Ordering::Relaxed);
+        let total: usize = counts.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, 7);
+        assert_eq!(counts.get_for(0).load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn threads_get_their_own_slot() {
+        static COUNTS: PerCpu<AtomicUsize, 1024> =
+            PerCpu::new([const { AtomicUsize::new(0) }; 1024]);
+        let ids: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    COUNTS.get().fetch_add(1, Ordering::Relaxed);
+                    core_id()
+                })
+            })
+            .map(|t| t.join().unwrap())
+            .collect();
+        for id in ids {
+            assert_eq!(COUNTS.get_for(id).load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "percpu: no slot for core 4, only 4 cores supported")]
+    fn core_beyond_slots_panics() {
+        let slots: PerCpu<u8, 4> = PerCpu::new([0; 4]);
+        slots.get_for(4);
+    }
+}
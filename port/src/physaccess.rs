@@ -0,0 +1,158 @@
+/// physaccess reads and writes single words of physical memory, e.g. a
+/// spin-table release address, or a device's scratch register, checking the
+/// access before making it rather than faulting, or silently touching the
+/// wrong thing.  The address must be naturally aligned for the width of the
+/// access, and the whole word must be within a mapping the arch knows of,
+/// such as the direct map or a device mapping, which it gives with a
+/// `PhysMappings`.  The access is then made volatile, through that mapping.
+use crate::mem::{MemError, PhysAddr, PhysRange, VirtAddr};
+use core::ptr::{read_volatile, write_volatile};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The widths of word that can be accessed: u8, u16, u32 and u64.
+pub trait PhysWord: Copy + private::Sealed {}
+
+macro_rules! phys_words {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+            impl PhysWord for $t {}
+        )*
+    };
+}
+
+phys_words!(u8, u16, u32, u64);
+
+/// The mappings physical memory can be accessed through.
+pub trait PhysMappings {
+    /// Return the virtual address of the start of `range`, if the whole range
+    /// is mapped, contiguously, readable and writable.
+    fn phys_to_virt(&self, range: &PhysRange) -> Option<VirtAddr>;
+}
+
+/// Return the virtual address to access a `T` at `pa` through, checking it's
+/// aligned, and mapped by `mappings`.  Fails with `Unaligned`, or with
+/// `PhysNotMapped` if some of the word isn't within any mapping.
+fn word_va<T: PhysWord>(mappings: &impl PhysMappings, pa: PhysAddr) -> Result<VirtAddr, MemError> {
+    if !pa.addr().is_multiple_of(size_of::<T>() as u64) {
+        return Err(MemError::Unaligned { addr: pa.addr(), required: size_of::<T>() });
+    }
+    let range = PhysRange::with_pa_len(pa, size_of::<T>());
+    mappings.phys_to_virt(&range).ok_or(MemError::PhysNotMapped { pa })
+}
+
+/// Read the `T` at `pa` through `mappings`.
+pub fn read_phys_with<T: PhysWord>(
+    mappings: &impl PhysMappings,
+    pa: PhysAddr,
+) -> Result<T, MemError> {
+    let va = word_va::<T>(mappings, pa)?;
+    // Safety: the word is aligned, and PhysMappings promises it's mapped
+    Ok(unsafe { read_volatile(va.addr() as *const T) })
+}
+
+/// Write `value` to the `T` at `pa` through `mappings`.
+pub fn write_phys_with<T: PhysWord>(
+    mappings: &impl PhysMappings,
+    pa: PhysAddr,
+    value: T,
+) -> Result<(), MemError> {
+    let va = word_va::<T>(mappings, pa)?;
+    // Safety: as above
+    unsafe { write_volatile(va.addr() as *mut T, value) };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::UnsafeCell;
+
+    const BASE: u64 = 0x1000;
+
+    /// Maps 16 words from BASE to a buffer, and another 16 words at the
+    /// next page, which are mapped elsewhere, as for two vmaps.
+    struct FakeMappings {
+        words: UnsafeCell<[u64; 16]>,
+        other: UnsafeCell<[u64; 16]>,
+    }
+
+    impl FakeMappings {
+        fn new() -> Self {
+            Self { words: UnsafeCell::new([0; 16]), other: UnsafeCell::new([0; 16]) }
+        }
+
+        fn words(&self) -> [u64; 16] {
+            unsafe { *self.words.get() }
+        }
+
+        fn other(&self) -> [u64; 16] {
+            unsafe { *self.other.get() }
+        }
+    }
+
+    impl PhysMappings for FakeMappings {
+        fn phys_to_virt(&self, range: &PhysRange) -> Option<VirtAddr> {
+            let maps = [(BASE, &self.words), (BASE + 0x1000, &self.other)];
+            maps.into_iter().find_map(|(base, buf)| {
+                let mapped = PhysRange::with_len(base, size_of::<[u64; 16]>());
+                (mapped.start() <= range.start() && range.end() <= mapped.end()).then(|| {
+                    let offset = (range.start().addr() - base) as usize;
+                    VirtAddr::new(buf.get().addr() + offset)
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn access_words() {
+        let maps = FakeMappings::new();
+        let pa = |offset| PhysAddr::new(BASE + offset);
+        write_phys_with(&maps, pa(8), 0x1122_3344_5566_7788u64).unwrap();
+        write_phys_with(&maps, pa(0x10), 0xaabbu16).unwrap();
+        write_phys_with(&maps, pa(0x13), 0xccu8).unwrap();
+        assert_eq!(maps.words()[1], 0x1122_3344_5566_7788);
+        assert_eq!(maps.words()[2], 0xcc00_aabb);
+        assert_eq!(read_phys_with::<u32>(&maps, pa(0xc)), Ok(0x1122_3344));
+        assert_eq!(read_phys_with::<u8>(&maps, pa(0x7f)), Ok(0));
+        write_phys_with(&maps, PhysAddr::new(BASE + 0x1078), u64::MAX).unwrap();
+        assert_eq!(maps.other()[15], u64::MAX);
+    }
+
+    #[test]
+    fn misaligned_rejected() {
+        let maps = FakeMappings::new();
+        assert_eq!(
+            read_phys_with::<u64>(&maps, PhysAddr::new(BASE + 4)),
+            Err(MemError::Unaligned { addr: BASE + 4, required: 8 })
+        );
+        assert_eq!(
+            read_phys_with::<u32>(&maps, PhysAddr::new(BASE + 2)),
+            Err(MemError::Unaligned { addr: BASE + 2, required: 4 })
+        );
+        assert_eq!(
+            write_phys_with(&maps, PhysAddr::new(BASE + 1), 0u16),
+            Err(MemError::Unaligned { addr: BASE + 1, required: 2 })
+        );
+        assert_eq!(maps.words(), [0; 16]);
+    }
+
+    #[test]
+    fn unmapped_rejected() {
+        let maps = FakeMappings::new();
+        let not_mapped = |addr| MemError::PhysNotMapped { pa: PhysAddr::new(addr) };
+        assert_eq!(read_phys_with::<u32>(&maps, PhysAddr::new(0)), Err(not_mapped(0)));
+        assert_eq!(
+            read_phys_with::<u8>(&maps, PhysAddr::new(BASE + 0x80)),
+            Err(not_mapped(BASE + 0x80))
+        );
+        assert_eq!(
+            write_phys_with(&maps, PhysAddr::new(BASE + 0x1080), 1u64),
+            Err(not_mapped(BASE + 0x1080))
+        );
+        assert_eq!(maps.other(), [0; 16]);
+    }
+}
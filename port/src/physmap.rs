@@ -0,0 +1,203 @@
+/// physmap is a registry of what physical memory is used for, e.g. the RAM
+/// banks, the kernel image sections, the DTB and firmware reservations, which
+/// subsystems add to as they're set up, so that `Report` can print one map of
+/// memory, in address order.  Like addrmap, it's intended to be printed from
+/// the panic handler, so it never blocks or takes locks.  Ranges can only be
+/// added, and are published with a per-slot flag, so a reader sees each
+/// range either completely or not at all.
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::mem::{MapFlags, PhysRange};
+
+/// A range of physical memory, and what it's used for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysRegion {
+    pub name: &'static str,
+    pub range: PhysRange,
+    pub flags: Option<MapFlags>, // How it's mapped, e.g. for kernel sections
+}
+
+/// Error returned when there are no free slots to record a range.
+#[derive(Debug, PartialEq)]
+pub struct PhysMapFullError;
+
+struct Slot {
+    ready: AtomicBool,
+    region: UnsafeCell<MaybeUninit<PhysRegion>>,
+}
+
+/// Registry of up to N ranges.
+pub struct PhysMap<const N: usize> {
+    slots: [Slot; N],
+    next: AtomicUsize, // Index of the next slot to claim
+}
+
+// Safety: each slot is written once, by the only caller to claim it, before
+// it's marked ready, and only read once it's ready.
+unsafe impl<const N: usize> Sync for PhysMap<N> {}
+
+impl<const N: usize> PhysMap<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    ready: AtomicBool::new(false),
+                    region: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record a range.  Ranges may overlap, e.g. a reservation within a RAM
+    /// bank.
+    pub fn record(&self, region: PhysRegion) -> Result<(), PhysMapFullError> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        let Some(slot) = self.slots.get(i) else {
+            return Err(PhysMapFullError);
+        };
+        // Safety: the slot was claimed above, so nothing else writes it, and
+        // it isn't read until it's marked ready.
+        unsafe { (*slot.region.get()).write(region) };
+        slot.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Iterate over the ranges recorded so far, in the order recorded.
+    pub fn iter(&self) -> impl Iterator<Item = &PhysRegion> {
+        self.slots.iter().filter(|slot| slot.ready.load(Ordering::Acquire)).map(|slot| {
+            // Safety: ready slots have been written, and are never written again.
+            unsafe { (*slot.region.get()).assume_init_ref() }
+        })
+    }
+
+    /// Iterate over the ranges recorded so far, in address order.  Ranges
+    /// starting at the same address are ordered largest first, so banks come
+    /// before what's in them.  This doesn't allocate, so it's quadratic.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &PhysRegion> {
+        // Sort by start, then largest first, then the order recorded
+        let key = |(i, r): (usize, &PhysRegion)| (r.range.start(), usize::MAX - r.range.size(), i);
+        let mut last = None;
+        core::iter::from_fn(move || {
+            let next = self
+                .iter()
+                .enumerate()
+                .map(key)
+                .filter(|k| last.is_none_or(|last| *k > last))
+                .min()?;
+            last = Some(next);
+            self.iter().nth(next.2)
+        })
+    }
+
+    /// Return a report of the recorded ranges, which can be printed.
+    /// `free_bytes` is the memory the page allocator has free, if it's
+    /// known.
+    pub fn report(&self, free_bytes: Option<usize>) -> Report<'_, N> {
+        Report { map: self, free_bytes }
+    }
+}
+
+impl<const N: usize> Default for PhysMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lists the recorded ranges in address order, a line each, with their
+/// names and sizes, then the free total.
+pub struct Report<'a, const N: usize> {
+    map: &'a PhysMap<N>,
+    free_bytes: Option<usize>,
+}
+
+impl<const N: usize> fmt::Display for Report<'_, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Physical memory map:")?;
+        for region in self.map.iter_sorted() {
+            write!(f, "  {} {:>10}  {}", region.range, Size(region.range.size()), region.name)?;
+            if let Some(flags) = region.flags {
+                write!(f, " ({flags})")?;
+            }
+            writeln!(f)?;
+        }
+        match self.free_bytes {
+            Some(free) => writeln!(f, "  free: {}", Size(free)),
+            None => writeln!(f, "  free: unknown"),
+        }
+    }
+}
+
+/// A number of bytes, printed in the largest unit it has at least one of,
+/// to one decimal place, e.g. "1.5 MiB".  Any width given pads it on the
+/// left.  It doesn't allocate, so it can be printed when the heap can't be
+/// used.
+pub struct Size(pub usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let bytes = self.0 as u128;
+        let i = (1..UNITS.len()).rev().find(|i| bytes >> (10 * i) > 0).unwrap_or(0);
+        let tenths = (bytes * 10) >> (10 * i);
+        let (whole, tenth, unit) = (tenths / 10, tenths % 10, UNITS[i]);
+
+        let digits = whole.checked_ilog10().unwrap_or(0) as usize + 1;
+        let len = digits + if tenth != 0 { 2 } else { 0 } + 1 + unit.len();
+        for _ in len..f.width().unwrap_or(0) {
+            f.write_char(' ')?;
+        }
+        write!(f, "{whole}")?;
+        if tenth != 0 {
+            write!(f, ".{tenth}")?;
+        }
+        write!(f, " {unit}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &'static str, start: u64, len: usize) -> PhysRegion {
+        PhysRegion { name, range: PhysRange::with_len(start, len), flags: None }
+    }
+
+    #[test]
+    fn sizes_are_readable() {
+        let sizes = [0, 1023, 1024, 1536, 4096 * 3, 0x3b40_0000, 1 << 30, 5 << 40];
+        let printed: Vec<_> = sizes.iter().map(|&s| Size(s).to_string()).collect();
+        assert_eq!(
+            printed,
+            ["0 B", "1023 B", "1 KiB", "1.5 KiB", "12 KiB", "948 MiB", "1 GiB", "5 TiB"]
+        );
+        assert_eq!(format!("{:>8}|", Size(2048)), "   2 KiB|");
+    }
+
+    #[test]
+    fn report_in_address_order() {
+        let map = PhysMap::<4>::new();
+        map.record(PhysRegion { flags: Some(MapFlags::RX), ..region("text", 0x8_0000, 0x2000) })
+            .unwrap();
+        map.record(region("dtb", 0x100, 0x80)).unwrap();
+        map.record(region("memory", 0, 0x1000_0000)).unwrap();
+        map.record(region("dup", 0x100, 0x80)).unwrap();
+        assert_eq!(map.record(region("full", 0, 1)), Err(PhysMapFullError));
+
+        let names: Vec<_> = map.iter_sorted().map(|r| r.name).collect();
+        assert_eq!(names, ["memory", "dtb", "dup", "text"]);
+
+        let report = map.report(Some(0x80_0000)).to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "Physical memory map:");
+        assert_eq!(lines[1], "  0x0000000000000000..0x0000000010000000    256 MiB  memory");
+        assert_eq!(
+            lines[4],
+            "  0x0000000000080000..0x0000000000082000      8 KiB  text (R-X normal)"
+        );
+        assert_eq!(lines[5], "  free: 8 MiB");
+    }
+}
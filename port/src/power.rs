@@ -0,0 +1,79 @@
+/// power resets or powers off the machine, e.g. to reboot after a panic
+/// rather than hanging, or to stop once the kernel's tests have run.  How to
+/// do so depends on the machine and its firmware, so each arch sets its
+/// operations with `set_power_ops`, e.g. using PSCI on aarch64.  Until it
+/// does, the operations loop forever instead.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// The power operations of an arch.  Neither returns.
+pub struct PowerOps {
+    pub reboot: fn() -> !,
+    pub power_off: fn() -> !,
+}
+
+/// The arch's power operations, or null if there aren't any.
+static POWER_OPS: AtomicPtr<PowerOps> = AtomicPtr::new(null_mut());
+
+/// Set the arch's power operations.
+pub fn set_power_ops(ops: &'static PowerOps) {
+    POWER_OPS.store(ops as *const PowerOps as *mut PowerOps, Ordering::Release);
+}
+
+fn power_ops() -> Option<&'static PowerOps> {
+    // Safety: only set_power_ops stores to POWER_OPS, from a 'static
+    unsafe { POWER_OPS.load(Ordering::Acquire).as_ref() }
+}
+
+/// Return true if the arch has set its power operations.
+pub fn available() -> bool {
+    power_ops().is_some()
+}
+
+/// Reset the machine, after flushing any buffered console output.  If the
+/// arch can't, loop forever instead.
+pub fn reboot() -> ! {
+    crate::devcons::flush();
+    if let Some(ops) = power_ops() {
+        (ops.reboot)();
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Power off the machine, after flushing any buffered console output.  If
+/// the arch can't, loop forever instead.
+pub fn power_off() -> ! {
+    crate::devcons::flush();
+    if let Some(ops) = power_ops() {
+        (ops.power_off)();
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_reboot() -> ! {
+        panic!("reboot");
+    }
+
+    fn fake_power_off() -> ! {
+        panic!("power off");
+    }
+
+    #[test]
+    fn calls_power_ops() {
+        static OPS: PowerOps = PowerOps { reboot: fake_reboot, power_off: fake_power_off };
+        set_power_ops(&OPS);
+        assert!(available());
+        let err = std::panic::catch_unwind(|| reboot()).unwrap_err();
+        assert_eq!(*err.downcast_ref::<&str>().unwrap(), "reboot");
+        let err = std::panic::catch_unwind(|| power_off()).unwrap_err();
+        assert_eq!(*err.downcast_ref::<&str>().unwrap(), "power off");
+    }
+}
@@ -0,0 +1,45 @@
+/// qemu lets the kernel stop QEMU with an exit status, so that tests run in
+/// the kernel can report whether they passed, e.g. in CI.  How to do so
+/// depends on the machine, so each arch registers a function for it, e.g.
+/// using semihosting on aarch64.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+static EXIT_FN: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// Set the function `exit_qemu` uses to stop QEMU.
+pub fn set_exit_fn(exit_fn: fn(bool) -> !) {
+    EXIT_FN.store(exit_fn as *mut (), Ordering::Release);
+}
+
+/// Stop QEMU, with an exit status saying whether the kernel's tests passed,
+/// after flushing any buffered console output.  If no way to do so has been
+/// set, power off instead, losing the status, which loops forever if the
+/// arch can't power off either.
+pub fn exit_qemu(success: bool) -> ! {
+    crate::devcons::flush();
+    let exit_fn = EXIT_FN.load(Ordering::Acquire);
+    if !exit_fn.is_null() {
+        // Safety: only set_exit_fn stores to EXIT_FN, and it stores a
+        // fn(bool) -> !
+        let exit_fn = unsafe { core::mem::transmute::<*mut (), fn(bool) -> !>(exit_fn) };
+        exit_fn(success);
+    }
+    crate::power::power_off()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_exit(success: bool) -> ! {
+        panic!("exit success:{success}");
+    }
+
+    #[test]
+    fn exit_calls_exit_fn() {
+        set_exit_fn(fake_exit);
+        let err = std::panic::catch_unwind(|| exit_qemu(false)).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "exit success:false");
+    }
+}
@@ -0,0 +1,774 @@
+/// regionalloc manages physical memory made up of several discontiguous banks,
+/// e.g. RAM at 0..0x3b400000 and again above 4GiB, using a separate page
+/// allocator for each region of memory.
+///
+/// Each region's allocator describes memory from address 0 up to its
+/// `max_end`, so a region is placed at a base address that's a multiple of
+/// that size, and addresses are translated relative to the base.  Allocations
+/// try each region in address order, and frees are routed to the region
+/// containing the address.  Addresses outside memory, e.g. in the hole
+/// between banks, are rejected rather than being passed to a region, which
+/// would treat them as allocated.
+///
+/// Until `init_from` is called, there's a single region at address 0, which
+/// manages no memory.
+use core::fmt;
+
+use crate::{
+    mem::{MemError, PhysAddr, PhysRange, PhysRangeSet},
+    pagealloc::{
+        CarvedPageAlloc, Metadata, PageAlloc, PageAllocStats, PageAllocSummary, PageMapper,
+        ReserveError, carve_metadata, check_free_range, out_of_memory, usable_ranges,
+    },
+};
+
+/// Page allocator made up of up to `NUM_REGIONS` regions, each managed by an
+/// allocator of type `A`.
+pub struct RegionPageAlloc<A, const NUM_REGIONS: usize> {
+    allocs: [A; NUM_REGIONS],       // Allocator for each region
+    bases: [PhysAddr; NUM_REGIONS], // Base address of each region
+    num_regions: usize,             // Regions in use, from the start
+    memory: Option<PhysRangeSet>,   // Memory covered by the regions
+    peak_allocated_pages: usize,    // Most pages allocated at once since init
+}
+
+impl<A: CarvedPageAlloc, const NUM_REGIONS: usize> RegionPageAlloc<A, NUM_REGIONS> {
+    /// Create an allocator from the allocators for each region, which must
+    /// all be the same size.  Only the first region is used until `init_from`.
+    pub const fn new(allocs: [A; NUM_REGIONS]) -> Self {
+        Self {
+            allocs,
+            bases: [PhysAddr::new(0); NUM_REGIONS],
+            num_regions: 1,
+            memory: None,
+            peak_allocated_pages: 0,
+        }
+    }
+
+    /// Return the range of physical addresses region `i` can describe.
+    fn region_range(&self, i: usize) -> PhysRange {
+        PhysRange::new(self.bases[i], self.bases[i] + self.allocs[i].max_end().addr())
+    }
+
+    /// Return the addresses from the start of the first region to the end of
+    /// the last, for reporting addresses outside them.
+    fn covered_range(&self) -> core::ops::Range<u64> {
+        self.bases[0].addr()..self.max_end().addr()
+    }
+
+    /// Return the part of `range` within region `i`, relative to the base of
+    /// the region, if any.
+    fn relative_range(&self, i: usize, range: &PhysRange) -> Option<PhysRange> {
+        let region = self.region_range(i);
+        let start = range.start().max(region.start());
+        let end = range.end().min(region.end());
+        let base = region.start().addr();
+        (start < end).then(|| PhysRange::with_end(start.addr() - base, end.addr() - base))
+    }
+
+    /// Return `range`, relative to the base of region `i`, as an absolute
+    /// physical range.
+    fn absolute_range(&self, i: usize, range: &PhysRange) -> PhysRange {
+        PhysRange::new(self.bases[i] + range.start().addr(), self.bases[i] + range.end().addr())
+    }
+
+    /// Return `err`, from the allocator for region `i`, with its addresses,
+    /// which are relative to the base of the region, made absolute.
+    fn absolute_error(&self, i: usize, err: MemError) -> MemError {
+        let base = self.bases[i].addr();
+        match err {
+            MemError::Unaligned { addr, required } => {
+                MemError::Unaligned { addr: base + addr, required }
+            }
+            MemError::OutOfRange { addr, range } => MemError::OutOfRange {
+                addr: base + addr,
+                range: base + range.start..base + range.end,
+            },
+            MemError::NotAllocated { pa } => MemError::NotAllocated { pa: pa + base },
+            MemError::NotInMemory { pa } => MemError::NotInMemory { pa: pa + base },
+            err => err,
+        }
+    }
+
+    /// Return the index of the region containing `pa`.  Once initialised, `pa`
+    /// must also be within memory, so that pages in a hole between banks of
+    /// memory can't be freed.
+    fn region_index(&self, pa: PhysAddr) -> Result<usize, MemError> {
+        if let Some(memory) = &self.memory {
+            if !memory.iter().any(|r| r.start() <= pa && pa < r.end()) {
+                return Err(MemError::NotInMemory { pa });
+            }
+        }
+        (0..self.num_regions)
+            .find(|&i| {
+                let region = self.region_range(i);
+                region.start() <= pa && pa < region.end()
+            })
+            .ok_or_else(|| MemError::OutOfRange { addr: pa.addr(), range: self.covered_range() })
+    }
+
+    /// Mark the pages in `range` as allocated or free in each region they fall
+    /// in.  Fails without changing anything if any of `range` isn't covered by
+    /// a region.
+    fn mark_range(&mut self, range: &PhysRange, allocated: bool) -> Result<(), MemError> {
+        let covered: usize = (0..self.num_regions)
+            .filter_map(|i| self.relative_range(i, range))
+            .map(|r| r.size())
+            .sum();
+        if covered < range.size() {
+            let addr = range.end().addr();
+            return Err(MemError::OutOfRange { addr, range: self.covered_range() });
+        }
+
+        for i in 0..self.num_regions {
+            if let Some(relative) = self.relative_range(i, range) {
+                let result = if allocated {
+                    self.allocs[i].mark_allocated(&relative)
+                } else {
+                    self.allocs[i].mark_free(&relative)
+                };
+                result.map_err(|err| self.absolute_error(i, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Try `alloc` on each region in turn until it succeeds.  `alloc` is
+    /// passed the allocator and base address of the region, and may return
+    /// None to skip the region.  Regions that are out of space, or can't
+    /// satisfy an alignment, are skipped too.  If none can, `page_count`
+    /// pages are reported as requested.
+    fn allocate_from_regions(
+        &mut self,
+        page_count: usize,
+        mut alloc: impl FnMut(&mut A, PhysAddr) -> Option<Result<PhysRange, MemError>>,
+    ) -> Result<PhysRange, MemError> {
+        for i in 0..self.num_regions {
+            let Some(result) = alloc(&mut self.allocs[i], self.bases[i]) else {
+                continue;
+            };
+            match result {
+                Ok(range) => {
+                    self.update_peak();
+                    return Ok(self.absolute_range(i, &range));
+                }
+                Err(MemError::OutOfMemory { .. } | MemError::InvalidAlignment { .. }) => {}
+                Err(err) => return Err(self.absolute_error(i, err)),
+            }
+        }
+        Err(out_of_memory(page_count, self.page_size(), self.stats()))
+    }
+
+    /// Record the high-water mark of allocated pages across all regions.
+    /// Called after each allocation.
+    fn update_peak(&mut self) {
+        self.peak_allocated_pages = self.peak_allocated_pages.max(self.stats().allocated_pages);
+    }
+
+    /// Write each run of used or free pages in the memory covered by region `i`.
+    fn write_region_runs(&self, w: &mut impl fmt::Write, i: usize) -> fmt::Result {
+        let Some(memory) = &self.memory else {
+            return Ok(());
+        };
+        for range in memory.iter().filter_map(|r| self.relative_range(i, r)) {
+            let range = self.absolute_range(i, &range);
+            let mut next = range.start();
+            let mut result = Ok(());
+            self.allocs[i].for_each_free_range(|free| {
+                let free = self.absolute_range(i, free);
+                let start = free.start().max(range.start());
+                let end = free.end().min(range.end());
+                if start < end && result.is_ok() {
+                    if next < start {
+                        result = writeln!(w, "    {} used", PhysRange::new(next, start));
+                    }
+                    result = result.and_then(|_| writeln!(w, "    {} free", free));
+                    next = end;
+                }
+            });
+            result?;
+            if next < range.end() {
+                writeln!(w, "    {} used", PhysRange::new(next, range.end()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A: CarvedPageAlloc, const NUM_REGIONS: usize> PageAlloc for RegionPageAlloc<A, NUM_REGIONS> {
+    fn page_size(&self) -> usize {
+        self.allocs[0].page_size()
+    }
+
+    fn max_end(&self) -> PhysAddr {
+        (0..self.num_regions).map(|i| self.region_range(i).end()).max().unwrap_or_default()
+    }
+
+    fn mark_allocated(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, true)
+    }
+
+    fn mark_free(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        self.mark_range(range, false)
+    }
+
+    /// Regions are placed from the lowest address, each at the start of the
+    /// first bank of memory not covered by the previous region, rounded down
+    /// to a multiple of the region size.  Memory beyond the last region is
+    /// ignored.  The state for every region is carved out before any region
+    /// is set up, from wherever `mapper` can reach, so that a region beyond
+    /// its reach, e.g. above 4GiB, can keep its state in lower memory.
+    fn init_from<const N: usize>(
+        &mut self,
+        memory: &PhysRangeSet<N>,
+        reserved: &[PhysRange],
+        mapper: &impl PageMapper,
+    ) -> Result<PageAllocSummary, MemError> {
+        let page_size = self.page_size();
+        let region_size = self.allocs[0].max_end().addr();
+        let (regions, mut usable) =
+            usable_ranges(memory, reserved, page_size, PhysAddr::new(u64::MAX))?;
+
+        self.num_regions = 0;
+        for range in regions.iter() {
+            let mut start = range.start();
+            while start < range.end() {
+                let i = self.num_regions;
+                if i == 0 || start >= self.region_range(i - 1).end() {
+                    if i == NUM_REGIONS {
+                        break;
+                    }
+                    self.bases[i] = PhysAddr::new(start.addr() - start.addr() % region_size);
+                    self.num_regions += 1;
+                }
+                start = self.region_range(self.num_regions - 1).end();
+            }
+        }
+
+        let mut metadata = [const { None }; NUM_REGIONS];
+        for (i, metadata) in metadata.iter_mut().enumerate().take(self.num_regions) {
+            let end = regions.iter().filter_map(|r| self.relative_range(i, r)).map(|r| r.end());
+            let size = self.allocs[i].metadata_size(end.max().unwrap_or_default());
+            *metadata = Some(carve_metadata(&mut usable, size, page_size, mapper)?);
+        }
+
+        // Each region is given only its usable memory, so it has nothing
+        // reserved, and the summary is made up here instead.
+        let mut covered = PhysRangeSet::new();
+        let mut free_pages = 0;
+        for i in 0..self.num_regions {
+            let mut region_usable = PhysRangeSet::<N>::new();
+            for range in usable.iter().filter_map(|r| self.relative_range(i, r)) {
+                region_usable.add(&range)?;
+            }
+            let metadata = metadata[i].take().unwrap_or(Metadata::empty());
+            // Safety: the metadata was carved out of usable memory for the
+            // region, for its end of memory, which is no lower than the end of
+            // its usable memory
+            let summary =
+                unsafe { self.allocs[i].init_with_metadata(&region_usable, &[], metadata) };
+            free_pages += summary?.free_pages;
+
+            for range in regions.iter().filter_map(|r| self.relative_range(i, r)) {
+                covered.add(&self.absolute_range(i, &range))?;
+            }
+        }
+        let total_pages = covered.size() / page_size;
+        self.memory = Some(covered);
+        self.peak_allocated_pages = 0;
+
+        Ok(PageAllocSummary { total_pages, reserved_pages: total_pages - free_pages, free_pages })
+    }
+
+    fn allocate(&mut self) -> Result<PhysAddr, MemError> {
+        let page_size = self.page_size();
+        self.allocate_from_regions(1, |alloc, _| {
+            Some(alloc.allocate().map(|pa| PhysRange::with_pa_len(pa, page_size)))
+        })
+        .map(|range| range.start())
+    }
+
+    /// Only regions with a base that's a multiple of `align` are used, so
+    /// alignments larger than the region size may fail.
+    fn allocate_aligned(&mut self, page_count: usize, align: u64) -> Result<PhysRange, MemError> {
+        if !align.is_power_of_two() || align > self.max_end().addr() {
+            return Err(MemError::InvalidAlignment { align });
+        }
+        self.allocate_from_regions(page_count, |alloc, base| {
+            base.is_multiple_of(align).then(|| alloc.allocate_aligned(page_count, align))
+        })
+    }
+
+    fn allocate_contiguous_within(
+        &mut self,
+        page_count: usize,
+        within: &PhysRange,
+    ) -> Result<PhysRange, MemError> {
+        self.allocate_from_regions(page_count, |alloc, base| {
+            (base < within.end()).then(|| {
+                let start = within.start().addr().saturating_sub(base.addr());
+                let end = within.end().addr() - base.addr();
+                alloc.allocate_contiguous_within(
+                    page_count,
+                    &PhysRange::new(PhysAddr::new(start), PhysAddr::new(end)),
+                )
+            })
+        })
+    }
+
+    fn deallocate(&mut self, pa: PhysAddr) -> Result<(), MemError> {
+        let i = self.region_index(pa)?;
+        self.allocs[i]
+            .deallocate(PhysAddr::new(pa.addr() - self.bases[i].addr()))
+            .map_err(|err| self.absolute_error(i, err))
+    }
+
+    /// The range must lie within a single region, as any range allocated at
+    /// once does.
+    fn free_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        check_free_range(range, self.page_size(), |pa| self.region_index(pa))?;
+        let i = self.region_index(range.start())?;
+        match self.relative_range(i, range) {
+            Some(relative) if relative.size() == range.size() => {
+                self.allocs[i].free_range(&relative).map_err(|err| self.absolute_error(i, err))
+            }
+            _ => Err(MemError::OutOfRange {
+                addr: range.end().addr(),
+                range: self.region_range(i).start().addr()..self.region_range(i).end().addr(),
+            }),
+        }
+    }
+
+    /// The range may span regions.  If part of it can't be reserved, any parts
+    /// already reserved in other regions are released again.
+    fn reserve_range(&mut self, range: &PhysRange) -> Result<PhysRange, ReserveError> {
+        let page_size = self.page_size();
+        let range = range.round_out(page_size as u64);
+        if let Some(pa) =
+            range.step_by_rounded(page_size).find(|&pa| self.region_index(pa).is_err())
+        {
+            return Err(ReserveError::NotInMemory(pa));
+        }
+
+        for i in 0..self.num_regions {
+            let Some(relative) = self.relative_range(i, &range) else {
+                continue;
+            };
+            if let Err(err) = self.allocs[i].reserve_range(&relative) {
+                for j in 0..i {
+                    if let Some(reserved) = self.relative_range(j, &range) {
+                        let _ = self.allocs[j].release_range(&reserved);
+                    }
+                }
+                // Regions are only given usable memory, so any page in memory
+                // a region doesn't manage was reserved by `init_from`.
+                let (ReserveError::Allocated(pa) | ReserveError::NotInMemory(pa)) = err;
+                return Err(ReserveError::Allocated(self.bases[i] + pa.addr()));
+            }
+        }
+        self.update_peak();
+        Ok(range)
+    }
+
+    /// Like `reserve_range`, the range may span regions.
+    fn release_range(&mut self, range: &PhysRange) -> Result<(), MemError> {
+        let page_size = self.page_size();
+        let range = range.round_out(page_size as u64);
+        check_free_range(&range, page_size, |pa| self.region_index(pa))?;
+        for i in 0..self.num_regions {
+            if let Some(relative) = self.relative_range(i, &range) {
+                self.allocs[i]
+                    .release_range(&relative)
+                    .map_err(|err| self.absolute_error(i, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> PageAllocStats {
+        let mut stats = PageAllocStats {
+            peak_allocated_pages: self.peak_allocated_pages,
+            ..Default::default()
+        };
+        for alloc in &self.allocs[..self.num_regions] {
+            let region_stats = alloc.stats();
+            stats.total_pages += region_stats.total_pages;
+            stats.free_pages += region_stats.free_pages;
+            stats.allocated_pages += region_stats.allocated_pages;
+        }
+        stats
+    }
+
+    /// Free ranges that meet at the boundary between two regions are merged.
+    fn for_each_free_range(&self, mut f: impl FnMut(&PhysRange)) {
+        let mut pending: Option<PhysRange> = None;
+        for i in 0..self.num_regions {
+            self.allocs[i].for_each_free_range(|range| {
+                let range = self.absolute_range(i, range);
+                pending = match pending.take() {
+                    Some(prev) if prev.end() == range.start() => Some(prev.add(&range)),
+                    Some(prev) => {
+                        f(&prev);
+                        Some(range)
+                    }
+                    None => Some(range),
+                };
+            });
+        }
+        if let Some(range) = pending {
+            f(&range);
+        }
+    }
+
+    fn for_each_region(&self, mut f: impl FnMut(&PhysRange, PageAllocStats)) {
+        for i in 0..self.num_regions {
+            f(&self.region_range(i), self.allocs[i].stats());
+        }
+    }
+
+    /// The state of each region is carved out by `init_from` here, so its
+    /// ranges are already absolute.
+    fn for_each_metadata_range(&self, mut f: impl FnMut(&PhysRange)) {
+        for alloc in &self.allocs[..self.num_regions] {
+            alloc.for_each_metadata_range(&mut f);
+        }
+    }
+
+    fn remap_metadata(&mut self, mapper: &impl PageMapper) -> Result<(), MemError> {
+        for alloc in &mut self.allocs[..self.num_regions] {
+            alloc.remap_metadata(mapper)?;
+        }
+        Ok(())
+    }
+
+    fn usage_bytes(&self) -> (usize, usize) {
+        self.allocs[..self.num_regions]
+            .iter()
+            .map(|alloc| alloc.usage_bytes())
+            .fold((0, 0), |(used, total), (region_used, region_total)| {
+                (used + region_used, total + region_total)
+            })
+    }
+
+    fn dump(&self, w: &mut impl fmt::Write, show_map: bool) -> fmt::Result {
+        writeln!(
+            w,
+            "Region page allocator: page size: {:#x} regions: {} of {}",
+            self.page_size(),
+            self.num_regions,
+            NUM_REGIONS
+        )?;
+        for range in self.memory.iter().flat_map(|memory| memory.iter()) {
+            writeln!(w, "  memory: {}", range)?;
+        }
+        writeln!(w, "  {}", self.stats())?;
+
+        for i in 0..self.num_regions {
+            writeln!(w, "  region {}: {}", i, self.region_range(i))?;
+            writeln!(w, "    {}", self.allocs[i].stats())?;
+            if show_map {
+                self.write_region_runs(w, i)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmapalloc::BitmapPageAlloc;
+    use crate::buddyalloc::BuddyPageAlloc;
+    use crate::fakemem::{FakeMemory, ScratchMapper};
+
+    /// Up to 3 regions, each of 32 pages of 4 bytes, i.e. 128 bytes of
+    /// physical memory.
+    fn new_alloc<A: CarvedPageAlloc>(new_region: impl Fn() -> A) -> RegionPageAlloc<A, 3> {
+        RegionPageAlloc::new([new_region(), new_region(), new_region()])
+    }
+
+    /// Two banks of memory, with a hole between them, and the second bank
+    /// spanning two regions.
+    fn memory() -> PhysRangeSet<4> {
+        let mut memory = PhysRangeSet::<4>::new();
+        memory.add(&PhysRange::with_end(0x40, 0x80)).unwrap();
+        memory.add(&PhysRange::with_end(0x1040, 0x10c0)).unwrap();
+        memory
+    }
+
+    fn init(alloc: &mut impl PageAlloc) -> PageAllocSummary {
+        alloc.init_from(&memory(), &[PhysRange::with_end(0x40, 0x48)], &ScratchMapper).unwrap()
+    }
+
+    /// Return the ranges carved out for the regions' state.
+    fn metadata(alloc: &impl PageAlloc) -> Vec<PhysRange> {
+        let mut metadata = Vec::new();
+        alloc.for_each_metadata_range(|range| metadata.push(range.clone()));
+        metadata
+    }
+
+    /// Return the number of pages carved out for the regions' state, all of
+    /// which come from the top of memory, in the last region.
+    fn carved_pages(alloc: &impl PageAlloc) -> usize {
+        metadata(alloc).iter().map(|range| range.size() / 4).sum()
+    }
+
+    fn regions(alloc: &impl PageAlloc) -> Vec<(PhysRange, PageAllocStats)> {
+        let mut regions = Vec::new();
+        alloc.for_each_region(|range, stats| regions.push((range.clone(), stats)));
+        regions
+    }
+
+    fn init_from_banks(mut alloc: impl PageAlloc) {
+        let summary = init(&mut alloc);
+        let carved = carved_pages(&alloc);
+        assert_eq!(
+            summary,
+            PageAllocSummary {
+                total_pages: 48,
+                reserved_pages: 2 + carved,
+                free_pages: 46 - carved
+            }
+        );
+        let stats = |total_pages| PageAllocStats {
+            total_pages,
+            free_pages: total_pages,
+            allocated_pages: 0,
+            peak_allocated_pages: 0,
+        };
+        assert_eq!(
+            regions(&alloc),
+            [
+                (PhysRange::with_end(0, 0x80), stats(14)),
+                (PhysRange::with_end(0x1000, 0x1080), stats(16)),
+                (PhysRange::with_end(0x1080, 0x1100), stats(16 - carved)),
+            ]
+        );
+        assert_eq!(alloc.stats(), stats(46 - carved));
+
+        let mut free = Vec::new();
+        alloc.for_each_free_range(|range| free.push(range.clone()));
+        let end = 0x10c0 - 4 * carved as u64;
+        assert_eq!(free, [PhysRange::with_end(0x48, 0x80), PhysRange::with_end(0x1040, end)]);
+    }
+
+    fn allocate_across_regions(mut alloc: impl PageAlloc) -> Result<(), MemError> {
+        init(&mut alloc);
+        let carved = carved_pages(&alloc);
+
+        // Pages come from each region in turn, and only from memory
+        let mut pages = Vec::new();
+        while let Ok(pa) = alloc.allocate() {
+            assert!(
+                (0x48..0x80).contains(&pa.addr()) || (0x1040..0x10c0).contains(&pa.addr()),
+                "{:?}",
+                pa
+            );
+            assert!(!pages.contains(&pa), "{:?} allocated twice", pa);
+            pages.push(pa);
+        }
+        assert_eq!(pages.len(), 46 - carved);
+        assert_eq!(alloc.stats().peak_allocated_pages, 46 - carved);
+
+        for pa in pages {
+            alloc.free(pa)?;
+        }
+        assert_eq!(alloc.stats().allocated_pages, 0);
+
+        // Contiguous allocations don't span regions
+        let range = alloc.allocate_contiguous(16)?;
+        assert_eq!(range, PhysRange::with_end(0x1040, 0x1080));
+        assert_eq!(
+            alloc.allocate_contiguous(17),
+            Err(MemError::OutOfMemory { requested: 68, available: 120 - 4 * carved })
+        );
+
+        // Alignment is relative to physical address 0, not the region
+        let range = alloc.allocate_aligned(2, 0x20)?;
+        assert!(range.start().is_multiple_of(0x20));
+
+        // Limits exclude whole regions
+        let range = alloc.allocate_contiguous_below(2, PhysAddr::new(0x1000))?;
+        assert!(range.end().addr() <= 0x80);
+        alloc.free_range(&range)?;
+        Ok(())
+    }
+
+    fn deallocate_in_hole(mut alloc: impl PageAlloc) -> Result<(), MemError> {
+        init(&mut alloc);
+
+        // Within a region, but not memory, between regions, and beyond the
+        // last region
+        for addr in [0x20, 0x1000, 0x800, 0x2000] {
+            let pa = PhysAddr::new(addr);
+            assert_eq!(alloc.deallocate(pa), Err(MemError::NotInMemory { pa }));
+        }
+        assert_eq!(alloc.stats().free_pages, 46 - carved_pages(&alloc));
+
+        let pa = alloc.allocate()?;
+        alloc.deallocate(pa)?;
+        assert_eq!(alloc.deallocate(pa), Err(MemError::NotAllocated { pa }));
+        Ok(())
+    }
+
+    fn reserve_across_regions(mut alloc: impl PageAlloc) -> Result<(), MemError> {
+        init(&mut alloc);
+
+        let range = PhysRange::with_end(0x1072, 0x1090);
+        assert_eq!(alloc.reserve_range(&range), Ok(PhysRange::with_end(0x1070, 0x1090)));
+        assert_eq!(alloc.stats().allocated_pages, 8);
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(0x1068, 0x1078)),
+            Err(ReserveError::Allocated(PhysAddr::new(0x1070)))
+        );
+        alloc.release_range(&range)?;
+        assert_eq!(alloc.stats().allocated_pages, 0);
+
+        // A conflict in a later region releases the part in the earlier one
+        alloc.reserve_range(&PhysRange::with_end(0x1080, 0x1088)).unwrap();
+        assert_eq!(
+            alloc.reserve_range(&range),
+            Err(ReserveError::Allocated(PhysAddr::new(0x1080)))
+        );
+        assert_eq!(alloc.stats().allocated_pages, 2);
+
+        // Pages reserved by init_from, and holes
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(0x40, 0x50)),
+            Err(ReserveError::Allocated(PhysAddr::new(0x40)))
+        );
+        assert_eq!(
+            alloc.reserve_range(&PhysRange::with_end(0x78, 0x88)),
+            Err(ReserveError::NotInMemory(PhysAddr::new(0x80)))
+        );
+        assert_eq!(alloc.stats().allocated_pages, 2);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(
+        expected = "can't free page PhysAddr(0x0000000000000800): physical address 0x0000000000000800 not in managed memory"
+    )]
+    fn free_in_hole_panics() {
+        let mut alloc = new_alloc(|| BitmapPageAlloc::<2, 2>::new_all_allocated(4));
+        init(&mut alloc);
+        let _ = alloc.free(PhysAddr::new(0x800));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "can't free page PhysAddr(0x0000000000000800) in")]
+    fn free_range_in_hole_panics() {
+        let mut alloc = new_alloc(|| BitmapPageAlloc::<2, 2>::new_all_allocated(4));
+        init(&mut alloc);
+        let _ = alloc.free_range(&PhysRange::with_end(0x800, 0x808));
+    }
+
+    #[test]
+    fn before_init() {
+        // Only the first region, at address 0, exists, and it has no memory
+        let mut alloc = new_alloc(|| BitmapPageAlloc::<2, 2>::new_all_allocated(4));
+        assert_eq!(alloc.max_end(), PhysAddr::new(0x80));
+        assert_eq!(alloc.allocate(), Err(MemError::OutOfMemory { requested: 4, available: 0 }));
+        assert_eq!(
+            alloc.mark_free(&PhysRange::with_end(0x70, 0x90)),
+            Err(MemError::OutOfRange { addr: 0x90, range: 0..0x80 })
+        );
+    }
+
+    #[test]
+    fn metadata_in_reachable_memory() {
+        // Only the first region can be reached, so it holds the bitmaps for
+        // every region, carved from the top of its memory
+        let mut mem = FakeMemory::new(0x80, 0x80, 0u32);
+        let mut alloc = new_alloc(|| BitmapPageAlloc::<2, 2>::new_all_allocated(4));
+        let reserved = [PhysRange::with_end(0x40, 0x48)];
+        let summary = alloc.init_from(&memory(), &reserved, &mem.mapper()).unwrap();
+        assert_eq!(
+            summary,
+            PageAllocSummary { total_pages: 48, reserved_pages: 5, free_pages: 43 }
+        );
+        assert_eq!(
+            metadata(&alloc),
+            [
+                PhysRange::with_end(0x7c, 0x80),
+                PhysRange::with_end(0x78, 0x7c),
+                PhysRange::with_end(0x74, 0x78)
+            ]
+        );
+        assert_eq!(regions(&alloc)[0].1.total_pages, 11);
+
+        // The bitmaps are in the fake memory.  The last region's has every
+        // page free, and the other two have pages outside memory, reserved or
+        // carved out, allocated.
+        assert_eq!(mem.words[0x70 / 4..], [0, 0, 0x0000_ffff, 0xe003_ffff]);
+    }
+
+    #[test]
+    fn dump() {
+        let mut alloc = new_alloc(|| BitmapPageAlloc::<2, 2>::new_all_allocated(4));
+        init(&mut alloc);
+        alloc.allocate_contiguous(2).unwrap();
+
+        let mut s = String::new();
+        alloc.dump(&mut s, true).unwrap();
+        assert_eq!(
+            s,
+            concat!(
+                "Region page allocator: page size: 0x4 regions: 3 of 3\n",
+                "  memory: 0x0000000000000040..0x0000000000000080\n",
+                "  memory: 0x0000000000001040..0x00000000000010c0\n",
+                "  pages: total 43 free 41 allocated 2 peak 2\n",
+                "  region 0: 0x0000000000000000..0x0000000000000080\n",
+                "    pages: total 14 free 12 allocated 2 peak 2\n",
+                "    0x0000000000000040..0x0000000000000050 used\n",
+                "    0x0000000000000050..0x0000000000000080 free\n",
+                "  region 1: 0x0000000000001000..0x0000000000001080\n",
+                "    pages: total 16 free 16 allocated 0 peak 0\n",
+                "    0x0000000000001040..0x0000000000001080 free\n",
+                "  region 2: 0x0000000000001080..0x0000000000001100\n",
+                "    pages: total 13 free 13 allocated 0 peak 0\n",
+                "    0x0000000000001080..0x00000000000010b4 free\n",
+                "    0x00000000000010b4..0x00000000000010c0 used\n",
+            )
+        );
+    }
+
+    macro_rules! region_alloc_tests {
+        ($($name:ident: $alloc:ty,)*) => {$(
+            mod $name {
+                use super::*;
+
+                fn new_region_alloc() -> RegionPageAlloc<$alloc, 3> {
+                    new_alloc(|| <$alloc>::new_all_allocated(4))
+                }
+
+                #[test]
+                fn init_from_banks() {
+                    super::init_from_banks(new_region_alloc())
+                }
+
+                #[test]
+                fn allocate_across_regions() -> Result<(), MemError> {
+                    super::allocate_across_regions(new_region_alloc())
+                }
+
+                #[test]
+                fn deallocate_in_hole() -> Result<(), MemError> {
+                    super::deallocate_in_hole(new_region_alloc())
+                }
+
+                #[test]
+                fn reserve_across_regions() -> Result<(), MemError> {
+                    super::reserve_across_regions(new_region_alloc())
+                }
+            }
+        )*};
+    }
+
+    region_alloc_tests! {
+        bitmap: BitmapPageAlloc<2, 2>,
+        buddy: BuddyPageAlloc<2, 2>,
+    }
+}
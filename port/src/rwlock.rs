@@ -0,0 +1,270 @@
+/// rwlock is a spinning reader-writer lock, for data that's read often and
+/// written rarely, so readers don't serialise each other.  It prefers
+/// writers: once a writer is waiting, new readers wait too, so a steady
+/// stream of readers can't starve writers.
+///
+/// As with `Lock`, a lock that's also taken in interrupt handlers must be
+/// taken with the `_irqsave` variants everywhere else, or a handler could
+/// spin forever on a lock held by the code it interrupted.  In debug builds,
+/// taking a lock the current core already holds panics; see `lockdebug`.
+/// That includes reading while already reading, which would deadlock if a
+/// writer started waiting in between.
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::interrupts;
+use crate::lockdebug::{Spins, Tracker};
+
+const WRITER: usize = 1 << 0; // Held by a writer
+const WRITER_WAITING: usize = 1 << 1; // A writer is waiting for readers to finish
+const READER: usize = 1 << 2; // Unit of the count of readers holding the lock
+
+pub struct RwLock<T: ?Sized> {
+    name: &'static str,
+    state: AtomicUsize,
+    tracker: Tracker,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(name: &'static str, data: T) -> RwLock<T> {
+        RwLock {
+            name,
+            state: AtomicUsize::new(0),
+            tracker: Tracker::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take the lock for reading, if there's no writer holding or waiting
+    /// for it, without waiting.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let location = Location::caller();
+        self.try_acquire_read().then(|| {
+            self.tracker.acquired(self.name, location, false);
+            RwLockReadGuard { lock: self, irq_state: None }
+        })
+    }
+
+    /// Take the lock for reading, waiting for writers holding or waiting for
+    /// it.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.acquire_read(Location::caller());
+        RwLockReadGuard { lock: self, irq_state: None }
+    }
+
+    /// Disable interrupts on this core, then take the lock for reading.  The
+    /// interrupt state is restored once the lock is released.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn read_irqsave(&self) -> RwLockReadGuard<'_, T> {
+        let irq_state = Some(interrupts::disable());
+        self.acquire_read(Location::caller());
+        RwLockReadGuard { lock: self, irq_state }
+    }
+
+    /// Take the lock for writing, if nothing else holds it, without waiting.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let location = Location::caller();
+        self.try_acquire_write().then(|| {
+            self.tracker.acquired(self.name, location, true);
+            RwLockWriteGuard { lock: self, irq_state: None }
+        })
+    }
+
+    /// Take the lock for writing, waiting for anything holding it.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.acquire_write(Location::caller());
+        RwLockWriteGuard { lock: self, irq_state: None }
+    }
+
+    /// Disable interrupts on this core, then take the lock for writing.  The
+    /// interrupt state is restored once the lock is released.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn write_irqsave(&self) -> RwLockWriteGuard<'_, T> {
+        let irq_state = Some(interrupts::disable());
+        self.acquire_write(Location::caller());
+        RwLockWriteGuard { lock: self, irq_state }
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & (WRITER | WRITER_WAITING) == 0
+            && self
+                .state
+                .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn acquire_read(&self, location: &'static Location<'static>) {
+        self.tracker.check_acquire(self.name, location);
+        let mut spins = Spins::default();
+        while !self.try_acquire_read() {
+            self.tracker.spin(self.name, &mut spins);
+            hint::spin_loop();
+        }
+        self.tracker.acquired(self.name, location, false);
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        // Taking the lock clears WRITER_WAITING.  Any other waiting writer
+        // sets it again when it next checks.
+        state & !WRITER_WAITING == 0
+            && self
+                .state
+                .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn acquire_write(&self, location: &'static Location<'static>) {
+        self.tracker.check_acquire(self.name, location);
+        let mut spins = Spins::default();
+        while !self.try_acquire_write() {
+            self.tracker.spin(self.name, &mut spins);
+            // Hold back new readers until the lock is free
+            if self.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            hint::spin_loop();
+        }
+        self.tracker.acquired(self.name, location, true);
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    irq_state: Option<usize>, // Interrupt state to restore, for read_irqsave
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.tracker.released(false);
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        if let Some(state) = self.irq_state {
+            interrupts::restore(state);
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    irq_state: Option<usize>, // Interrupt state to restore, for write_irqsave
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.tracker.released(true);
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        if let Some(state) = self.irq_state {
+            interrupts::restore(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let lock = RwLock::new("test", 1);
+        let r1 = lock.read();
+        let r2 = lock.try_read().unwrap();
+        assert_eq!(*r1 + *r2, 2);
+        assert!(lock.try_write().is_none());
+        drop(r1);
+        assert!(lock.try_write().is_none());
+        drop(r2);
+
+        let mut w = lock.try_write().unwrap();
+        *w += 1;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(w);
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn waiting_writer_holds_back_readers() {
+        let lock = Arc::new(RwLock::new("test", 0));
+        let r = lock.read();
+
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() = 1)
+        };
+        // Once the writer is waiting, new readers must wait too
+        while lock.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+            thread::yield_now();
+        }
+        assert!(lock.try_read().is_none());
+        assert_eq!(*r, 0);
+
+        drop(r);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn readers_never_see_partial_writes() {
+        let lock = Arc::new(RwLock::new("test", (0u64, 0u64)));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        if i % 2 == 0 {
+                            let mut w = lock.write();
+                            w.0 += 1;
+                            w.1 += 1;
+                        } else {
+                            let r = lock.read();
+                            assert_eq!(r.0, r.1);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (8000, 8000));
+    }
+}
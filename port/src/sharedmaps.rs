@@ -0,0 +1,239 @@
+/// sharedmaps keeps count of the users of page mappings that several windows
+/// can share, e.g. two devices whose register blocks are smaller than a page,
+/// and sit in the same page.  Mappings are made a page at a time, but callers
+/// are given a window: the virtual range of just the bytes they asked for, at
+/// the same offset into the mapping as their physical range is into its
+/// pages.  A window whose pages are already mapped with the same flags takes
+/// another reference to that mapping, rather than mapping them again, and the
+/// mapping is only torn down once the last window using it is released.
+///
+/// Like vaalloc, it only does the bookkeeping, in a fixed size array, so that
+/// it can be used before the heap is available.  Mapping and unmapping the
+/// pages is up to the caller.
+use crate::mem::{MapFlags, PAGE_SIZE_4K, PhysRange, VirtAddr, VirtRange};
+
+#[derive(Debug, PartialEq)]
+pub enum SharedMapError {
+    /// The array recording mappings is full.
+    TooManyMappings,
+    /// The window isn't within any recorded mapping.
+    NotMapped,
+}
+
+/// The pages `pages` mapped at `va` with `flags`, used by `refs` windows.
+#[derive(Clone, Debug, PartialEq)]
+struct SharedMap {
+    pages: PhysRange,
+    va: VirtAddr,
+    flags: MapFlags,
+    refs: usize,
+}
+
+impl SharedMap {
+    fn virt_range(&self) -> VirtRange {
+        VirtRange::with_len(self.va, self.pages.size())
+    }
+
+    /// Return the window onto `phys`, which must be within the pages.
+    fn window(&self, phys: &PhysRange) -> VirtRange {
+        let offset = (phys.start().addr() - self.pages.start().addr()) as usize;
+        VirtRange::with_len(self.va + offset, phys.size())
+    }
+}
+
+/// Reference counted page mappings, with room to record up to `N`.
+pub struct SharedMaps<const N: usize> {
+    maps: [Option<SharedMap>; N],
+}
+
+impl<const N: usize> SharedMaps<N> {
+    pub const fn new() -> Self {
+        Self { maps: [const { None }; N] }
+    }
+
+    /// If the pages covering `phys` are within a mapping with `flags`, take
+    /// another reference to it, and return the window onto `phys`.
+    pub fn get(&mut self, phys: &PhysRange, flags: MapFlags) -> Option<VirtRange> {
+        let pages = phys.round_out(PAGE_SIZE_4K as u64);
+        let map = self.maps.iter_mut().flatten().find(|map| {
+            map.flags == flags
+                && map.pages.start() <= pages.start()
+                && pages.end() <= map.pages.end()
+        })?;
+        map.refs += 1;
+        Some(map.window(phys))
+    }
+
+    /// Return the window onto `phys` of a mapping covering its pages, and
+    /// the flags it's mapped with, without taking a reference.
+    pub fn find(&self, phys: &PhysRange) -> Option<(VirtRange, MapFlags)> {
+        let pages = phys.round_out(PAGE_SIZE_4K as u64);
+        let map = self
+            .maps
+            .iter()
+            .flatten()
+            .find(|map| map.pages.start() <= pages.start() && pages.end() <= map.pages.end())?;
+        Some((map.window(phys), map.flags))
+    }
+
+    /// Record that the pages covering `phys` have been mapped at `va` with
+    /// `flags`, with a single reference, and return the window onto `phys`.
+    pub fn insert(
+        &mut self,
+        phys: &PhysRange,
+        va: VirtAddr,
+        flags: MapFlags,
+    ) -> Result<VirtRange, SharedMapError> {
+        let slot = self
+            .maps
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(SharedMapError::TooManyMappings)?;
+        let pages = phys.round_out(PAGE_SIZE_4K as u64);
+        let map = slot.insert(SharedMap { pages, va, flags, refs: 1 });
+        Ok(map.window(phys))
+    }
+
+    /// Drop the reference taken for `window`.  Returns the virtual range of
+    /// the whole mapping if that was the last reference, so that it should
+    /// be unmapped, or None if other windows still use it.
+    pub fn put(&mut self, window: &VirtRange) -> Result<Option<VirtRange>, SharedMapError> {
+        let pages = window.round_out(PAGE_SIZE_4K);
+        let slot = self
+            .maps
+            .iter_mut()
+            .find(|slot| {
+                slot.as_ref().is_some_and(|map| {
+                    let range = map.virt_range();
+                    range.start() <= pages.start() && pages.end() <= range.end()
+                })
+            })
+            .ok_or(SharedMapError::NotMapped)?;
+        let map = slot.as_mut().unwrap();
+        map.refs -= 1;
+        if map.refs > 0 {
+            return Ok(None);
+        }
+        Ok(slot.take().map(|map| map.virt_range()))
+    }
+
+    /// Call `f` with the pages, virtual range, flags and number of windows of
+    /// each mapping.
+    pub fn for_each(&self, mut f: impl FnMut(&PhysRange, &VirtRange, MapFlags, usize)) {
+        for map in self.maps.iter().flatten() {
+            f(&map.pages, &map.virt_range(), map.flags, map.refs);
+        }
+    }
+
+    /// Return the number of windows using the mapping holding `va`, or 0 if
+    /// it isn't mapped.
+    pub fn refs(&self, va: VirtAddr) -> usize {
+        let map = self.maps.iter().flatten().find(|map| {
+            let range = map.virt_range();
+            range.start() <= va && va < range.end()
+        });
+        map.map_or(0, |map| map.refs)
+    }
+}
+
+impl<const N: usize> Default for SharedMaps<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VA: usize = 0x1000_0000;
+
+    fn window(start: usize, len: usize) -> VirtRange {
+        VirtRange::with_len(VirtAddr::new(start), len)
+    }
+
+    #[test]
+    fn windows_in_one_page_share_it() {
+        let mut maps = SharedMaps::<4>::new();
+
+        // The first device maps the page, and gets its offset into it back
+        let uart = PhysRange::with_len(0x7e20_1840, 0x100);
+        assert_eq!(maps.get(&uart, MapFlags::RW), None);
+        let uart_window = maps.insert(&uart, VirtAddr::new(VA), MapFlags::RW).unwrap();
+        assert_eq!(uart_window, window(VA + 0x840, 0x100));
+
+        // A second device in the same page shares the mapping
+        let timer = PhysRange::with_len(0x7e20_1000, 0x20);
+        assert_eq!(maps.get(&timer, MapFlags::RW), Some(window(VA, 0x20)));
+        assert_eq!(maps.refs(VirtAddr::new(VA + 0xfff)), 2);
+
+        // Other flags need a mapping of their own
+        assert_eq!(maps.get(&timer, MapFlags::READ | MapFlags::DEVICE), None);
+
+        // Finding a window doesn't take a reference
+        let timer_window = Some((window(VA + 0x10, 4), MapFlags::RW));
+        assert_eq!(maps.find(&PhysRange::with_len(0x7e20_1010, 4)), timer_window);
+        assert_eq!(maps.refs(VirtAddr::new(VA)), 2);
+
+        // Releasing one window leaves the page mapped for the other
+        assert_eq!(maps.put(&uart_window), Ok(None));
+        assert_eq!(maps.refs(VirtAddr::new(VA)), 1);
+        assert_eq!(maps.get(&uart, MapFlags::RW), Some(uart_window.clone()));
+        assert_eq!(maps.put(&uart_window), Ok(None));
+
+        // Releasing the last one returns the whole page to unmap
+        assert_eq!(maps.put(&window(VA, 0x20)), Ok(Some(window(VA, 0x1000))));
+        assert_eq!(maps.refs(VirtAddr::new(VA)), 0);
+        assert_eq!(maps.put(&window(VA, 0x20)), Err(SharedMapError::NotMapped));
+        assert_eq!(maps.get(&uart, MapFlags::RW), None);
+    }
+
+    #[test]
+    fn window_spanning_pages() {
+        let mut maps = SharedMaps::<4>::new();
+
+        // Both pages are mapped, and the window straddles them
+        let spanning = PhysRange::with_len(0x3f00_0ff0, 0x20);
+        let spanning_window = maps.insert(&spanning, VirtAddr::new(VA), MapFlags::RW).unwrap();
+        assert_eq!(spanning_window, window(VA + 0xff0, 0x20));
+
+        // Windows within either page share the mapping
+        let first = PhysRange::with_len(0x3f00_0000, 0x10);
+        let second = PhysRange::with_len(0x3f00_1100, 0x10);
+        assert_eq!(maps.get(&first, MapFlags::RW), Some(window(VA, 0x10)));
+        assert_eq!(maps.get(&second, MapFlags::RW), Some(window(VA + 0x1100, 0x10)));
+        assert_eq!(maps.refs(VirtAddr::new(VA)), 3);
+        let mut listed = Vec::new();
+        maps.for_each(|pages, va, flags, refs| {
+            listed.push((pages.clone(), va.clone(), flags, refs))
+        });
+        let pages = PhysRange::with_len(0x3f00_0000, 0x2000);
+        assert_eq!(listed, [(pages, window(VA, 0x2000), MapFlags::RW, 3)]);
+
+        // but one spilling past them doesn't
+        let past = PhysRange::with_len(0x3f00_1ff0, 0x20);
+        assert_eq!(maps.get(&past, MapFlags::RW), None);
+
+        assert_eq!(maps.put(&spanning_window), Ok(None));
+        assert_eq!(maps.put(&window(VA, 0x10)), Ok(None));
+        assert_eq!(maps.put(&window(VA + 0x1100, 0x10)), Ok(Some(window(VA, 0x2000))));
+    }
+
+    #[test]
+    fn too_many_mappings() {
+        let mut maps = SharedMaps::<2>::new();
+        for i in 0..2 {
+            let phys = PhysRange::with_len(0x1000 * (i as u64 + 1), 0x100);
+            maps.insert(&phys, VirtAddr::new(VA + 0x1000 * i), MapFlags::RW).unwrap();
+        }
+        let phys = PhysRange::with_len(0x8000, 0x100);
+        assert_eq!(
+            maps.insert(&phys, VirtAddr::new(VA + 0x8000), MapFlags::RW),
+            Err(SharedMapError::TooManyMappings)
+        );
+
+        // Sharing an existing mapping doesn't need a slot
+        let phys = PhysRange::with_len(0x1080, 0x10);
+        assert_eq!(maps.get(&phys, MapFlags::RW), Some(window(VA + 0x80, 0x10)));
+    }
+}
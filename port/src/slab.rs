@@ -0,0 +1,434 @@
+/// slab allocates fixed size kernel objects, such as page table tracking
+/// nodes, from caches of whole pages, so that objects that come and go often
+/// don't fragment the heap.  Each page, or slab, starts with a header, and
+/// the rest is divided into slots for objects, with the free slots linked
+/// through the slots themselves.  The header of an object's slab is found by
+/// rounding the object's address down to the page, so allocating and freeing
+/// are O(1).
+///
+/// Pages come from a `SlabPages`, usually the page allocator through the
+/// direct map.  A cache keeps one empty slab for reuse, and returns any
+/// others to the page allocator as soon as they empty, or all of them on
+/// `shrink`.
+use crate::mcslock::{Lock, LockNode};
+use crate::mem::PAGE_SIZE_4K;
+use core::fmt;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+/// Size of each slab, which is also its alignment.
+pub const SLAB_SIZE: usize = PAGE_SIZE_4K;
+
+/// Number of empty slabs a cache keeps when objects are freed.
+const MAX_EMPTY: usize = 1;
+
+/// Gives slab caches whole pages, and takes them back.
+///
+/// # Safety
+///
+/// Pages returned by `alloc_page` must be valid for reads and writes of
+/// `SLAB_SIZE` bytes, aligned to `SLAB_SIZE`, and not used by anything else
+/// until they're passed to `free_page`.
+pub unsafe trait SlabPages {
+    /// Return a page, or None if there's no memory.
+    fn alloc_page(&self) -> Option<NonNull<u8>>;
+
+    /// Take back a page returned by `alloc_page`, which is no longer used.
+    fn free_page(&self, page: NonNull<u8>);
+}
+
+/// Statistics about a slab cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    pub in_use: usize,     // Objects allocated
+    pub slabs: usize,      // Slabs, including empty ones
+    pub high_water: usize, // Most objects allocated at once
+    pub objects_per_slab: usize,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in use, {} slabs of {} objects, high water {}",
+            self.in_use, self.slabs, self.objects_per_slab, self.high_water
+        )
+    }
+}
+
+/// A free slot, linked into its slab's free list.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// Start of each slab.  Slabs with free slots are linked into the cache's
+/// partial list.  Full slabs aren't linked anywhere until a slot is freed.
+struct SlabHeader {
+    prev: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+    free: Option<NonNull<FreeSlot>>,
+    in_use: usize,
+}
+
+/// State of a cache, behind its lock.
+struct Slabs {
+    partial: Option<NonNull<SlabHeader>>, // Slabs with at least one free slot
+    empty: usize,                         // Slabs on the partial list with no objects
+    stats: SlabStats,
+}
+
+impl Slabs {
+    fn push(&mut self, mut slab: NonNull<SlabHeader>) {
+        let header = unsafe { slab.as_mut() };
+        header.prev = None;
+        header.next = self.partial;
+        if let Some(mut next) = self.partial {
+            unsafe { next.as_mut() }.prev = Some(slab);
+        }
+        self.partial = Some(slab);
+    }
+
+    fn unlink(&mut self, mut slab: NonNull<SlabHeader>) {
+        let header = unsafe { slab.as_mut() };
+        match header.prev {
+            Some(mut prev) => unsafe { prev.as_mut() }.next = header.next,
+            None => self.partial = header.next,
+        }
+        if let Some(mut next) = header.next {
+            unsafe { next.as_mut() }.prev = header.prev;
+        }
+        header.prev = None;
+        header.next = None;
+    }
+}
+
+/// A cache of `T`s, allocated from slabs of pages from `P`.
+pub struct SlabCache<T, P: SlabPages> {
+    name: &'static str,
+    pages: P,
+    init: Option<fn() -> T>,
+    slabs: Lock<Slabs>,
+}
+
+impl<T, P: SlabPages> SlabCache<T, P> {
+    /// Size and alignment of each slot, big enough for either a `T` or a
+    /// free list link.
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+    const SLOT_SIZE: usize =
+        max(mem::size_of::<T>(), mem::size_of::<FreeSlot>()).next_multiple_of(Self::SLOT_ALIGN);
+
+    /// Offset of the first slot in a slab, after the header.
+    const FIRST_SLOT: usize = mem::size_of::<SlabHeader>().next_multiple_of(Self::SLOT_ALIGN);
+
+    /// Number of objects in each slab.
+    pub const OBJECTS_PER_SLAB: usize =
+        SLAB_SIZE.saturating_sub(Self::FIRST_SLOT) / Self::SLOT_SIZE;
+
+    /// Create a cache of `T`s, with pages from `pages`.  If `init` is given,
+    /// `alloc_init` uses it to initialise objects.  Panics if a `T` doesn't
+    /// fit in a slab after the header.
+    pub const fn new(name: &'static str, pages: P, init: Option<fn() -> T>) -> Self {
+        assert!(Self::OBJECTS_PER_SLAB > 0, "slab: object too big for a slab");
+        Self {
+            name,
+            pages,
+            init,
+            slabs: Lock::new(
+                name,
+                Slabs {
+                    partial: None,
+                    empty: 0,
+                    stats: SlabStats {
+                        in_use: 0,
+                        slabs: 0,
+                        high_water: 0,
+                        objects_per_slab: Self::OBJECTS_PER_SLAB,
+                    },
+                },
+            ),
+        }
+    }
+
+    /// Allocate an object holding `value`.  If there's no memory, `value` is
+    /// returned.
+    pub fn alloc(&self, value: T) -> Result<SlabBox<'_, T, P>, T> {
+        let Some(slot) = self.alloc_slot() else {
+            return Err(value);
+        };
+        unsafe { slot.write(value) };
+        Ok(SlabBox { ptr: slot, cache: self })
+    }
+
+    /// Allocate an object initialised by the cache's initialiser, or None
+    /// if there's no memory.  Panics if the cache has no initialiser.
+    pub fn alloc_init(&self) -> Option<SlabBox<'_, T, P>> {
+        let Some(init) = self.init else {
+            panic!("slab {}: no initialiser", self.name);
+        };
+        self.alloc(init()).ok()
+    }
+
+    /// Return statistics about the cache.
+    pub fn stats(&self) -> SlabStats {
+        let node = LockNode::new();
+        self.slabs.lock(&node).stats
+    }
+
+    /// Return all empty slabs to `P`, returning the number freed.
+    pub fn shrink(&self) -> usize {
+        let node = LockNode::new();
+        let mut slabs = self.slabs.lock(&node);
+        let mut freed = 0;
+        let mut next = slabs.partial;
+        while let Some(slab) = next {
+            let header = unsafe { slab.as_ref() };
+            next = header.next;
+            if header.in_use == 0 {
+                self.free_slab(&mut slabs, slab);
+                freed += 1;
+            }
+        }
+        freed
+    }
+
+    fn alloc_slot(&self) -> Option<NonNull<T>> {
+        let node = LockNode::new();
+        let mut slabs = self.slabs.lock(&node);
+        let mut slab = match slabs.partial {
+            Some(slab) => slab,
+            None => {
+                let slab = self.new_slab()?;
+                slabs.push(slab);
+                slabs.empty += 1;
+                slabs.stats.slabs += 1;
+                slab
+            }
+        };
+        let header = unsafe { slab.as_mut() };
+        let slot = header.free.expect("slab on partial list is full");
+        header.free = unsafe { slot.as_ref() }.next;
+        if header.in_use == 0 {
+            slabs.empty -= 1;
+        }
+        header.in_use += 1;
+        if header.free.is_none() {
+            slabs.unlink(slab);
+        }
+        slabs.stats.in_use += 1;
+        slabs.stats.high_water = slabs.stats.high_water.max(slabs.stats.in_use);
+        Some(slot.cast())
+    }
+
+    /// Return the slot at `ptr` to its slab.  The object must already have
+    /// been dropped.
+    fn free_slot(&self, ptr: NonNull<T>) {
+        let slab = ptr.as_ptr().cast::<SlabHeader>().map_addr(|a| a & !(SLAB_SIZE - 1));
+        let slab = unsafe { NonNull::new_unchecked(slab) };
+        let slot = ptr.cast::<FreeSlot>();
+
+        let node = LockNode::new();
+        let mut slabs = self.slabs.lock(&node);
+        let mut slab_ptr = slab;
+        let header = unsafe { slab_ptr.as_mut() };
+        let was_full = header.free.is_none();
+        unsafe { slot.write(FreeSlot { next: header.free }) };
+        header.free = Some(slot);
+        header.in_use -= 1;
+        let empty = header.in_use == 0;
+        if was_full {
+            slabs.push(slab);
+        }
+        slabs.stats.in_use -= 1;
+        if empty {
+            slabs.empty += 1;
+            if slabs.empty > MAX_EMPTY {
+                self.free_slab(&mut slabs, slab);
+            }
+        }
+    }
+
+    /// Get a page from `P`, and set it up as a slab with all slots free.
+    fn new_slab(&self) -> Option<NonNull<SlabHeader>> {
+        let page = self.pages.alloc_page()?;
+        assert_eq!(page.addr().get() % SLAB_SIZE, 0, "slab {}: page not aligned", self.name);
+        let mut free = None;
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot = unsafe { page.add(Self::FIRST_SLOT + i * Self::SLOT_SIZE) }.cast();
+            unsafe { ptr::write(slot.as_ptr(), FreeSlot { next: free }) };
+            free = Some(slot);
+        }
+        let slab = page.cast::<SlabHeader>();
+        unsafe { slab.write(SlabHeader { prev: None, next: None, free, in_use: 0 }) };
+        Some(slab)
+    }
+
+    /// Unlink an empty slab and return its page to `P`.
+    fn free_slab(&self, slabs: &mut Slabs, slab: NonNull<SlabHeader>) {
+        slabs.unlink(slab);
+        slabs.empty -= 1;
+        slabs.stats.slabs -= 1;
+        self.pages.free_page(slab.cast());
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+/// An object allocated from a `SlabCache`, which is dropped and returned to
+/// the cache when this is dropped.
+pub struct SlabBox<'a, T, P: SlabPages> {
+    ptr: NonNull<T>,
+    cache: &'a SlabCache<T, P>,
+}
+
+impl<T, P: SlabPages> Deref for SlabBox<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, P: SlabPages> DerefMut for SlabBox<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, P: SlabPages> Drop for SlabBox<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        self.cache.free_slot(self.ptr);
+    }
+}
+
+// Safety: a SlabBox owns its T, and the cache is shared behind its lock
+unsafe impl<T: Send, P: SlabPages + Sync> Send for SlabBox<'_, T, P> {}
+unsafe impl<T: Sync, P: SlabPages + Sync> Sync for SlabBox<'_, T, P> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{Layout, alloc, dealloc};
+    use std::cell::Cell;
+
+    /// Pages from the host allocator, counting those in use.
+    struct HostPages {
+        in_use: Cell<usize>,
+        limit: usize,
+    }
+
+    impl HostPages {
+        fn new(limit: usize) -> Self {
+            Self { in_use: Cell::new(0), limit }
+        }
+
+        fn layout() -> Layout {
+            Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+        }
+    }
+
+    unsafe impl SlabPages for HostPages {
+        fn alloc_page(&self) -> Option<NonNull<u8>> {
+            if self.in_use.get() == self.limit {
+                return None;
+            }
+            self.in_use.set(self.in_use.get() + 1);
+            NonNull::new(unsafe { alloc(Self::layout()) })
+        }
+
+        fn free_page(&self, page: NonNull<u8>) {
+            self.in_use.set(self.in_use.get() - 1);
+            unsafe { dealloc(page.as_ptr(), Self::layout()) };
+        }
+    }
+
+    type Cache<T> = SlabCache<T, HostPages>;
+
+    #[test]
+    fn grows_by_slabs() {
+        let cache: Cache<[u64; 8]> = SlabCache::new("test", HostPages::new(usize::MAX), None);
+        let per_slab = Cache::<[u64; 8]>::OBJECTS_PER_SLAB;
+        assert_eq!(per_slab, (SLAB_SIZE - mem::size_of::<SlabHeader>()) / 64);
+
+        let objects: Vec<_> =
+            (0..per_slab + 1).map(|i| cache.alloc([i as u64; 8]).unwrap()).collect();
+        assert_eq!(cache.pages.in_use.get(), 2);
+        for (i, o) in objects.iter().enumerate() {
+            assert_eq!(o[7], i as u64);
+        }
+        let stats = cache.stats();
+        assert_eq!(
+            stats,
+            SlabStats {
+                in_use: per_slab + 1,
+                slabs: 2,
+                high_water: per_slab + 1,
+                objects_per_slab: per_slab
+            }
+        );
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let cache: Cache<u32> = SlabCache::new("test", HostPages::new(1), Some(|| 7));
+        let a = cache.alloc_init().unwrap();
+        let b = cache.alloc(5).unwrap();
+        assert_eq!((*a, *b), (7, 5));
+        let addr = &*a as *const u32;
+        drop(a);
+        let c = cache.alloc(9).unwrap();
+        assert_eq!(&*c as *const u32, addr);
+
+        // Out of pages once the slab is full
+        let rest: Vec<_> =
+            (2..Cache::<u32>::OBJECTS_PER_SLAB).map(|i| cache.alloc(i as u32).unwrap()).collect();
+        assert_eq!(cache.alloc(1).err(), Some(1));
+        assert!(cache.alloc_init().is_none());
+        drop(rest);
+        assert!(cache.alloc_init().is_some());
+        assert_eq!(cache.stats().high_water, Cache::<u32>::OBJECTS_PER_SLAB);
+    }
+
+    #[test]
+    fn objects_are_dropped() {
+        let value = std::rc::Rc::new(());
+        let cache = SlabCache::new("test", HostPages::new(1), None);
+        let o = cache.alloc(value.clone()).unwrap();
+        assert_eq!(std::rc::Rc::strong_count(&value), 2);
+        drop(o);
+        assert_eq!(std::rc::Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn empty_slabs_are_freed() {
+        let cache: Cache<[u8; 1024]> = SlabCache::new("test", HostPages::new(usize::MAX), None);
+        let per_slab = Cache::<[u8; 1024]>::OBJECTS_PER_SLAB;
+        let mut objects: Vec<_> =
+            (0..per_slab * 3).map(|_| cache.alloc([0; 1024]).unwrap()).collect();
+        assert_eq!(cache.stats().slabs, 3);
+
+        // All but one empty slab go back as they empty
+        objects.truncate(per_slab);
+        assert_eq!(cache.stats().slabs, 2);
+        assert_eq!(cache.pages.in_use.get(), 2);
+
+        // Nothing to shrink while objects are in use, other than empty slabs
+        assert_eq!(cache.shrink(), 1);
+        assert_eq!(cache.shrink(), 0);
+        drop(objects);
+        assert_eq!(cache.shrink(), 1);
+        assert_eq!(cache.pages.in_use.get(), 0);
+        assert_eq!(cache.stats().slabs, 0);
+        assert_eq!(cache.stats().in_use, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "slab test: no initialiser")]
+    fn alloc_init_without_initialiser_panics() {
+        let cache: Cache<u8> = SlabCache::new("test", HostPages::new(1), None);
+        let _ = cache.alloc_init();
+    }
+}
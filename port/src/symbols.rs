@@ -0,0 +1,198 @@
+/// symbols resolves kernel addresses to the function they're in, so that
+/// backtraces and fault reports can say e.g. "port::fdt::parse+0x1c4" rather
+/// than leaving a round trip through nm or addr2line on the host.
+///
+/// With the `symbols` feature, space for the table is reserved in the
+/// `.ksyms` section of the kernel, and xtask fills it in after linking with
+/// the kernel's function symbols.  Without it, or if the table wasn't
+/// filled in, `resolve` returns None.
+///
+/// The table is little endian, with the addresses sorted, and each name
+/// running from its offset to the next one:
+///
+/// ```text
+/// magic:   b"KSYM"
+/// count:   u32
+/// addrs:   [u64; count]
+/// offsets: [u32; count + 1]   Offsets of the names in `names`
+/// names:   [u8]               UTF-8, not terminated
+/// ```
+///
+/// An empty name marks the end of the symbol before it, so that addresses
+/// beyond the last function aren't attributed to it.
+use core::fmt;
+
+use crate::mem::VirtAddr;
+
+pub const MAGIC: [u8; 4] = *b"KSYM";
+
+/// Space reserved for the table in the kernel with the `symbols` feature.
+pub const TABLE_SIZE: usize = 1024 * 1024;
+
+#[cfg(feature = "symbols")]
+#[repr(C, align(8))]
+struct Reserved([u8; TABLE_SIZE]);
+
+/// Filled in by xtask after linking.
+#[cfg(feature = "symbols")]
+#[unsafe(link_section = ".ksyms")]
+#[used]
+static KSYMS: Reserved = Reserved([0; TABLE_SIZE]);
+
+/// A table of symbols, in the format above.
+pub struct SymbolTable<'a> {
+    addrs: &'a [u8],
+    offsets: &'a [u8],
+    names: &'a [u8],
+    count: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Returns the table in `bytes`, or None if it's empty or malformed.
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let (magic, rest) = bytes.split_at_checked(4)?;
+        if magic != MAGIC {
+            return None;
+        }
+        let (count, rest) = rest.split_at_checked(4)?;
+        let count = u32::from_le_bytes(count.try_into().ok()?) as usize;
+        let (addrs, rest) = rest.split_at_checked(count.checked_mul(8)?)?;
+        let (offsets, names) = rest.split_at_checked(count.checked_add(1)?.checked_mul(4)?)?;
+        let table = Self { addrs, offsets, names, count };
+        if table.offset(count) > names.len() {
+            return None;
+        }
+        Some(table)
+    }
+
+    /// Number of symbols.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn addr(&self, i: usize) -> usize {
+        let bytes = &self.addrs[i * 8..i * 8 + 8];
+        u64::from_le_bytes(bytes.try_into().unwrap()) as usize
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        let bytes = &self.offsets[i * 4..i * 4 + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap()) as usize
+    }
+
+    fn name(&self, i: usize) -> Option<&'a str> {
+        let name = self.names.get(self.offset(i)..self.offset(i + 1))?;
+        core::str::from_utf8(name).ok()
+    }
+
+    /// Returns the name of the symbol at or before `addr`, and the offset
+    /// of `addr` from it, or None if there isn't one.
+    pub fn resolve(&self, addr: usize) -> Option<(&'a str, usize)> {
+        // Binary search for the first symbol after addr
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.addr(mid) <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let i = lo.checked_sub(1)?;
+        let name = self.name(i).filter(|name| !name.is_empty())?;
+        Some((name, addr - self.addr(i)))
+    }
+}
+
+/// Returns the kernel's symbol table, if it has one.
+#[cfg(feature = "symbols")]
+fn kernel_table() -> Option<SymbolTable<'static>> {
+    // The table is filled in after compiling, so hide its contents from the
+    // optimiser, which would otherwise see zeros.
+    SymbolTable::new(core::hint::black_box(&KSYMS.0[..]))
+}
+
+#[cfg(not(feature = "symbols"))]
+fn kernel_table() -> Option<SymbolTable<'static>> {
+    None
+}
+
+/// Returns the name of the kernel function containing `va`, and the offset
+/// of `va` into it, or None if it's not known.
+pub fn resolve(va: VirtAddr) -> Option<(&'static str, usize)> {
+    kernel_table()?.resolve(va.addr())
+}
+
+/// A kernel address, printed with the function it's in, if it's known,
+/// e.g. "0xffff800000112345 port::fdt::parse+0x1c4".
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = resolve(VirtAddr::new(self.0)) {
+            write!(f, " {name}+{offset:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a table of `symbols`, as xtask would build it.
+    fn table(symbols: &[(u64, &str)]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((symbols.len() as u32).to_le_bytes());
+        for (addr, _) in symbols {
+            bytes.extend(addr.to_le_bytes());
+        }
+        let mut offset = 0u32;
+        for (_, name) in symbols {
+            bytes.extend(offset.to_le_bytes());
+            offset += name.len() as u32;
+        }
+        bytes.extend(offset.to_le_bytes());
+        for (_, name) in symbols {
+            bytes.extend(name.as_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn resolve_nearest_preceding() {
+        let bytes =
+            table(&[(0x1000, "start"), (0x1040, "kernel_main"), (0x1400, "trap"), (0x1500, "")]);
+        let table = SymbolTable::new(&bytes).unwrap();
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.resolve(0xfff), None);
+        assert_eq!(table.resolve(0x1000), Some(("start", 0)));
+        assert_eq!(table.resolve(0x1404), Some(("trap", 4)));
+        assert_eq!(table.resolve(0x1040 + 0x1c4), Some(("kernel_main", 0x1c4)));
+        // Beyond the end of the last function
+        assert_eq!(table.resolve(0x1500), None);
+        assert_eq!(table.resolve(usize::MAX), None);
+    }
+
+    #[test]
+    fn reject_malformed_tables() {
+        assert!(SymbolTable::new(&[]).is_none());
+        // Not filled in
+        assert!(SymbolTable::new(&[0; 64]).is_none());
+        let bytes = table(&[(0x1000, "start")]);
+        assert!(SymbolTable::new(&bytes[..bytes.len() - 1]).is_none());
+        let empty = table(&[]);
+        assert!(SymbolTable::new(&empty).unwrap().resolve(0x1000).is_none());
+    }
+
+    #[test]
+    fn kernel_table_absent_in_tests() {
+        assert_eq!(resolve(VirtAddr::new(0x1000)), None);
+        assert_eq!(Symbolized(0x1000).to_string(), "0x0000000000001000");
+    }
+}
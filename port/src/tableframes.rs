@@ -0,0 +1,130 @@
+/// tableframes abstracts where the arch page table code gets the 4KiB frames
+/// its tables live in.  The table manipulation in each arch's vm.rs takes its
+/// frames from a `TableFrameSource`, and leaves activating tables and TLB
+/// maintenance to a thin arch layer, so that in the kernel the frames come
+/// from the page allocator, while hosted tests can build whole hierarchies in
+/// `VecFrames` and check the descriptors written bit for bit.
+use crate::mem::{MemError, PhysAddr};
+
+#[cfg(any(test, feature = "vec_frames"))]
+use alloc::{boxed::Box, vec::Vec};
+
+/// A source of frames for page tables.
+pub trait TableFrameSource {
+    /// Allocate a 4KiB frame for a table.  Its contents are undefined.
+    fn alloc_frame(&mut self) -> Result<PhysAddr, MemError>;
+
+    /// Free the frame at `pa`, which was allocated by `alloc_frame`, and is
+    /// no longer referenced by any table.
+    fn free_frame(&mut self, pa: PhysAddr);
+
+    /// Return a pointer through which the frame at `pa` can be accessed.
+    fn frame_ptr(&mut self, pa: PhysAddr) -> *mut u8;
+}
+
+#[cfg(any(test, feature = "vec_frames"))]
+#[repr(C, align(4096))]
+struct Frame([u8; 4096]);
+
+/// Frames for hosted tests, in heap allocated buffers, at made up physical
+/// addresses handed out from `base`, lowest free first.  The addresses only
+/// depend on the order of allocations and frees, so the tables built are the
+/// same on every run.  New frames are filled with junk, so code relying on
+/// them being zeroed fails.  Only compiled for tests, or with the vec_frames
+/// feature.
+#[cfg(any(test, feature = "vec_frames"))]
+pub struct VecFrames {
+    base: PhysAddr,
+    frames: Vec<Option<Box<Frame>>>,
+}
+
+#[cfg(any(test, feature = "vec_frames"))]
+impl VecFrames {
+    pub const JUNK: u8 = 0xa5;
+
+    pub fn new(base: PhysAddr) -> Self {
+        assert!(base.is_multiple_of(4096), "base {base:?} isn't page aligned");
+        Self { base, frames: Vec::new() }
+    }
+
+    /// Return the number of frames allocated and not yet freed.
+    pub fn allocated(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.is_some()).count()
+    }
+
+    /// Return the index of the frame at `pa`, panicking unless it's
+    /// allocated.
+    fn index(&self, pa: PhysAddr) -> usize {
+        let offset = pa.addr().wrapping_sub(self.base.addr());
+        let index = (offset / 4096) as usize;
+        match self.frames.get(index) {
+            Some(Some(_)) if offset.is_multiple_of(4096) => index,
+            _ => panic!("{pa:?} isn't an allocated frame"),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "vec_frames"))]
+impl TableFrameSource for VecFrames {
+    fn alloc_frame(&mut self) -> Result<PhysAddr, MemError> {
+        let index = match self.frames.iter().position(|frame| frame.is_none()) {
+            Some(index) => index,
+            None => {
+                self.frames.push(None);
+                self.frames.len() - 1
+            }
+        };
+        self.frames[index] = Some(Box::new(Frame([Self::JUNK; 4096])));
+        Ok(self.base + (index * 4096) as u64)
+    }
+
+    fn free_frame(&mut self, pa: PhysAddr) {
+        let index = self.index(pa);
+        self.frames[index] = None;
+    }
+
+    fn frame_ptr(&mut self, pa: PhysAddr) -> *mut u8 {
+        let index = self.index(pa);
+        self.frames[index].as_mut().unwrap().0.as_mut_ptr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_deterministic() {
+        let mut frames = VecFrames::new(PhysAddr::new(0x4000_0000));
+        let pas: Vec<_> = (0..3).map(|_| frames.alloc_frame().unwrap()).collect();
+        assert_eq!(pas, [0x4000_0000, 0x4000_1000, 0x4000_2000].map(PhysAddr::new));
+        assert_eq!(frames.allocated(), 3);
+
+        // Frames are distinct, aligned, and start out as junk
+        let ptrs: Vec<_> = pas.iter().map(|&pa| frames.frame_ptr(pa)).collect();
+        for ptr in &ptrs {
+            assert!(ptr.addr().is_multiple_of(4096));
+            let frame = unsafe { core::slice::from_raw_parts(*ptr, 4096) };
+            assert!(frame.iter().all(|&b| b == VecFrames::JUNK));
+        }
+        unsafe { ptrs[1].write(1) };
+        assert_eq!(unsafe { ptrs[0].read() }, VecFrames::JUNK);
+
+        // The lowest free frame is reused first
+        frames.free_frame(pas[1]);
+        frames.free_frame(pas[0]);
+        assert_eq!(frames.allocated(), 1);
+        assert_eq!(frames.alloc_frame().unwrap(), pas[0]);
+        assert_eq!(frames.alloc_frame().unwrap(), pas[1]);
+        assert_eq!(frames.alloc_frame().unwrap(), PhysAddr::new(0x4000_3000));
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't an allocated frame")]
+    fn free_unallocated_frame_panics() {
+        let mut frames = VecFrames::new(PhysAddr::new(0x4000_0000));
+        let pa = frames.alloc_frame().unwrap();
+        frames.free_frame(pa);
+        frames.free_frame(pa);
+    }
+}
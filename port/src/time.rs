@@ -0,0 +1,167 @@
+/// time gives the kernel a monotonic clock, in nanoseconds since the
+/// counter it's read from started, e.g. at reset, for delays and timeouts.
+/// Each arch sets its counter and the counter's frequency with `set_clock`.
+/// Until it does, `now` is always 0, so deadlines never expire, and loops
+/// waiting on them behave as if they had no timeout, and `delay` returns
+/// at once.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Function returning the counter, or null if there isn't one.
+static COUNTER_FN: AtomicPtr<()> = AtomicPtr::new(null_mut());
+static FREQ_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Set the clock to `counter`, which returns a count that increases
+/// monotonically at `freq_hz`, and which is the same on every core.  Panics
+/// if `freq_hz` is 0.
+pub fn set_clock(counter: fn() -> u64, freq_hz: u64) {
+    assert!(freq_hz > 0, "time: clock frequency must not be 0");
+    FREQ_HZ.store(freq_hz, Ordering::Relaxed);
+    COUNTER_FN.store(counter as *mut (), Ordering::Release);
+}
+
+/// Return true if the arch has set a clock.
+pub fn available() -> bool {
+    !COUNTER_FN.load(Ordering::Acquire).is_null()
+}
+
+/// Return the frequency of the clock's counter in Hz, or 0 if there's no
+/// clock.
+pub fn freq_hz() -> u64 {
+    if available() { FREQ_HZ.load(Ordering::Relaxed) } else { 0 }
+}
+
+/// Convert `ticks` of a counter running at `freq_hz` to nanoseconds.  The
+/// product is 128 bits, so it can't overflow for any count, and the result
+/// saturates, which a 1GHz counter would take over 500 years to reach.
+pub fn ticks_to_nanos(ticks: u64, freq_hz: u64) -> u64 {
+    let nanos = ticks as u128 * NANOS_PER_SEC / freq_hz as u128;
+    nanos.try_into().unwrap_or(u64::MAX)
+}
+
+/// Convert `nanos` to ticks of a counter running at `freq_hz`, rounding up,
+/// so the count is never reached before the time, e.g. when setting a timer.
+/// The result saturates, as for `ticks_to_nanos`.
+pub fn nanos_to_ticks(nanos: u64, freq_hz: u64) -> u64 {
+    let ticks = (nanos as u128 * freq_hz as u128).div_ceil(NANOS_PER_SEC);
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Return the time in nanoseconds, or 0 if there's no clock.
+pub fn now() -> u64 {
+    let counter_fn = COUNTER_FN.load(Ordering::Acquire);
+    if counter_fn.is_null() {
+        return 0;
+    }
+    // Safety: only set_clock stores to COUNTER_FN, and it stores a
+    // fn() -> u64
+    let counter_fn = unsafe { core::mem::transmute::<*mut (), fn() -> u64>(counter_fn) };
+    ticks_to_nanos(counter_fn(), FREQ_HZ.load(Ordering::Relaxed))
+}
+
+/// Return the time since boot, or rather since the counter started.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now())
+}
+
+/// Spin for at least `duration`.  Returns at once if there's no clock.
+pub fn delay(duration: Duration) {
+    if !available() {
+        return;
+    }
+    let deadline = Deadline::after(duration);
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+}
+
+/// A time to give up waiting for something, e.g.
+///
+/// ```ignore
+/// let deadline = Deadline::after(Duration::from_millis(100));
+/// while !ready() {
+///     if deadline.expired() {
+///         return Err(Timeout);
+///     }
+///     core::hint::spin_loop();
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    end: u64, // In nanoseconds, as returned by now
+}
+
+impl Deadline {
+    /// Return the deadline `timeout` from now.
+    pub fn after(timeout: Duration) -> Deadline {
+        let timeout = timeout.as_nanos().try_into().unwrap_or(u64::MAX);
+        Deadline { end: now().saturating_add(timeout) }
+    }
+
+    /// Return the deadline at `nanos`, a time as returned by `now`.
+    pub const fn at(nanos: u64) -> Deadline {
+        Deadline { end: nanos }
+    }
+
+    /// Return the time of the deadline in nanoseconds, as returned by `now`.
+    pub const fn nanos(&self) -> u64 {
+        self.end
+    }
+
+    /// Return true if the deadline has passed.
+    pub fn expired(&self) -> bool {
+        now() >= self.end
+    }
+
+    /// Return the time left until the deadline, or zero if it's passed.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_nanos(self.end.saturating_sub(now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_ticks_without_overflow() {
+        assert_eq!(ticks_to_nanos(54_000_000, 54_000_000), 1_000_000_000);
+        assert_eq!(ticks_to_nanos(1, 19_200_000), 52);
+        // A year at 1GHz overflows ticks * 10^9 in 64 bits
+        let year = 365 * 24 * 3600;
+        assert_eq!(ticks_to_nanos(year * 1_000_000_000, 1_000_000_000), year * 1_000_000_000);
+        assert_eq!(ticks_to_nanos(u64::MAX, 1), u64::MAX);
+    }
+
+    #[test]
+    fn converts_nanos_rounding_up() {
+        assert_eq!(nanos_to_ticks(1_000_000_000, 54_000_000), 54_000_000);
+        assert_eq!(nanos_to_ticks(52, 19_200_000), 1);
+        assert_eq!(nanos_to_ticks(53, 19_200_000), 2);
+        assert_eq!(nanos_to_ticks(0, 19_200_000), 0);
+        assert!(ticks_to_nanos(nanos_to_ticks(12_345, 19_200_000), 19_200_000) >= 12_345);
+        assert_eq!(nanos_to_ticks(u64::MAX, 1_000_000_000_000), u64::MAX);
+    }
+
+    #[test]
+    fn deadlines_expire() {
+        static TICKS: AtomicU64 = AtomicU64::new(0);
+        set_clock(|| TICKS.load(Ordering::Relaxed), 1000);
+        TICKS.store(5000, Ordering::Relaxed);
+        assert_eq!(now(), 5_000_000_000);
+        assert_eq!(uptime(), Duration::from_secs(5));
+
+        let deadline = Deadline::after(Duration::from_millis(20));
+        assert!(!deadline.expired());
+        assert_eq!(deadline.remaining(), Duration::from_millis(20));
+        TICKS.store(5019, Ordering::Relaxed);
+        assert!(!deadline.expired());
+        TICKS.store(5020, Ordering::Relaxed);
+        assert!(deadline.expired());
+        assert_eq!(deadline.remaining(), Duration::ZERO);
+        assert_eq!(Deadline::after(Duration::MAX).remaining().as_nanos() as u64, u64::MAX - now());
+    }
+}
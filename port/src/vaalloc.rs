@@ -0,0 +1,161 @@
+/// vaalloc hands out ranges of virtual address space from a fixed arena, e.g.
+/// for mapping device registers at addresses that don't collide.
+///
+/// It's a simple first-fit allocator, recording the allocated ranges sorted by
+/// address in a fixed size array, so that it can be used before the heap is
+/// available.  It only manages addresses - mapping them is up to the caller.
+use crate::mem::{PAGE_SIZE_4K, VirtAddr, VirtRange};
+
+#[derive(Debug, PartialEq)]
+pub enum VaAllocError {
+    /// No free range in the arena is big enough.
+    Exhausted,
+    /// The array recording allocations is full.
+    TooManyAllocations,
+    /// The range wasn't returned by alloc, or has already been freed.
+    NotAllocated,
+}
+
+/// Allocator for virtual address ranges within an arena, with room to record
+/// up to `N` allocations.
+pub struct VaAlloc<const N: usize> {
+    arena_start: VirtAddr,
+    arena_end: VirtAddr,
+    allocs: [VirtRange; N], // Allocated ranges, sorted by start
+    len: usize,             // Number of allocated ranges
+}
+
+impl<const N: usize> VaAlloc<N> {
+    /// Create an allocator for the `size` bytes from `start`, which should
+    /// both be page aligned.
+    pub const fn new(start: VirtAddr, size: usize) -> Self {
+        Self {
+            arena_start: start,
+            arena_end: VirtAddr::new(start.addr() + size),
+            allocs: [const { VirtRange(VirtAddr(0)..VirtAddr(0)) }; N],
+            len: 0,
+        }
+    }
+
+    /// Allocate a range of at least `size` bytes, rounded up to a whole
+    /// number of pages, starting at a multiple of `align`, which must be a
+    /// power of two no smaller than a page.  The lowest such free range is
+    /// used.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Result<VirtRange, VaAllocError> {
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE_4K);
+        let size = size.max(1).next_multiple_of(PAGE_SIZE_4K);
+
+        // Try the gap before each allocation in turn, then the gap at the end
+        let mut gap_start = self.arena_start;
+        for i in 0..=self.len {
+            let gap_end = if i < self.len { self.allocs[i].start() } else { self.arena_end };
+            let start = gap_start.round_up(align);
+            if start.addr().checked_add(size).is_some_and(|end| end <= gap_end.addr()) {
+                if self.len == N {
+                    return Err(VaAllocError::TooManyAllocations);
+                }
+                let range = VirtRange::with_len(start, size);
+                self.allocs[i..=self.len].rotate_right(1);
+                self.allocs[i] = range.clone();
+                self.len += 1;
+                return Ok(range);
+            }
+            if i < self.len {
+                gap_start = self.allocs[i].end();
+            }
+        }
+        Err(VaAllocError::Exhausted)
+    }
+
+    /// Free a range previously returned by alloc.
+    pub fn free(&mut self, range: &VirtRange) -> Result<(), VaAllocError> {
+        let i = self.allocs[..self.len]
+            .iter()
+            .position(|r| r == range)
+            .ok_or(VaAllocError::NotAllocated)?;
+        self.allocs[i..self.len].rotate_left(1);
+        self.len -= 1;
+        Ok(())
+    }
+
+    /// Return the allocated ranges, sorted by address.
+    pub fn allocated(&self) -> &[VirtRange] {
+        &self.allocs[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::PAGE_SIZE_2M;
+
+    const BASE: usize = 0x1000_0000;
+
+    /// An arena of 4 2MiB blocks.
+    fn new_alloc<const N: usize>() -> VaAlloc<N> {
+        VaAlloc::new(VirtAddr::new(BASE), 4 * PAGE_SIZE_2M)
+    }
+
+    fn range(start: usize, size: usize) -> VirtRange {
+        VirtRange::with_len(VirtAddr::new(start), size)
+    }
+
+    #[test]
+    fn alloc_first_fit() {
+        let mut alloc = new_alloc::<8>();
+        assert_eq!(alloc.alloc(0x100, PAGE_SIZE_4K), Ok(range(BASE, 0x1000)));
+        assert_eq!(alloc.alloc(0x2000, PAGE_SIZE_4K), Ok(range(BASE + 0x1000, 0x2000)));
+
+        // Aligned allocations skip the gap that's too small
+        assert_eq!(
+            alloc.alloc(PAGE_SIZE_2M, PAGE_SIZE_2M),
+            Ok(range(BASE + PAGE_SIZE_2M, PAGE_SIZE_2M))
+        );
+        assert_eq!(alloc.alloc(0x1000, PAGE_SIZE_4K), Ok(range(BASE + 0x3000, 0x1000)));
+
+        // Freed ranges are reused
+        alloc.free(&range(BASE + 0x1000, 0x2000)).unwrap();
+        assert_eq!(alloc.alloc(0x1000, PAGE_SIZE_4K), Ok(range(BASE + 0x1000, 0x1000)));
+        assert_eq!(
+            alloc.allocated(),
+            [
+                range(BASE, 0x1000),
+                range(BASE + 0x1000, 0x1000),
+                range(BASE + 0x3000, 0x1000),
+                range(BASE + PAGE_SIZE_2M, PAGE_SIZE_2M)
+            ]
+        );
+    }
+
+    #[test]
+    fn alloc_exhausted() {
+        let mut alloc = new_alloc::<8>();
+        assert_eq!(alloc.alloc(5 * PAGE_SIZE_2M, PAGE_SIZE_4K), Err(VaAllocError::Exhausted));
+        assert!(alloc.alloc(3 * PAGE_SIZE_2M, PAGE_SIZE_4K).is_ok());
+        assert!(alloc.alloc(0x1000, PAGE_SIZE_4K).is_ok());
+        assert_eq!(alloc.alloc(PAGE_SIZE_2M, PAGE_SIZE_2M), Err(VaAllocError::Exhausted));
+        assert_eq!(
+            alloc.alloc(PAGE_SIZE_2M - 0x1000, PAGE_SIZE_4K),
+            Ok(range(BASE + 3 * PAGE_SIZE_2M + 0x1000, PAGE_SIZE_2M - 0x1000))
+        );
+        assert_eq!(alloc.alloc(0x1000, PAGE_SIZE_4K), Err(VaAllocError::Exhausted));
+    }
+
+    #[test]
+    fn alloc_too_many() {
+        let mut alloc = new_alloc::<2>();
+        assert!(alloc.alloc(0x1000, PAGE_SIZE_4K).is_ok());
+        assert!(alloc.alloc(0x1000, PAGE_SIZE_4K).is_ok());
+        assert_eq!(alloc.alloc(0x1000, PAGE_SIZE_4K), Err(VaAllocError::TooManyAllocations));
+    }
+
+    #[test]
+    fn free_unallocated() {
+        let mut alloc = new_alloc::<8>();
+        let allocated = alloc.alloc(0x2000, PAGE_SIZE_4K).unwrap();
+        assert_eq!(alloc.free(&range(BASE, 0x1000)), Err(VaAllocError::NotAllocated));
+        assert_eq!(alloc.free(&allocated), Ok(()));
+        assert_eq!(alloc.free(&allocated), Err(VaAllocError::NotAllocated));
+        assert!(alloc.allocated().is_empty());
+    }
+}
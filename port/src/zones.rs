@@ -0,0 +1,303 @@
+/// zones divide physical memory by address, for devices that can't address
+/// all of it, e.g. 32-bit DMA engines, or the Raspberry Pi's VideoCore, which
+/// only sees the first 1GiB.  An allocation names the least restrictive zone
+/// it can use, and whether it may fall back to more restrictive zones, so
+/// memory that only some devices can use is left for them where possible.
+///
+/// The zone boundaries depend on the board, so come from the arch's param.rs.
+/// The pages in each zone, and how many are free, are counted with atomics,
+/// as for `MemAccounts`, so they can be reported without locks.
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mem::{PAddrRepr, PhysAddr, PhysRange};
+use crate::physmap::Size;
+
+/// A range of physical memory, from the most restrictive, at the lowest
+/// addresses, to the least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Dma,    // Addressable by the most limited devices
+    Dma32,  // Addressable with 32 bits
+    Normal, // Everything else
+}
+
+impl Zone {
+    pub const COUNT: usize = 3;
+
+    pub const ALL: [Zone; Self::COUNT] = [Self::Dma, Self::Dma32, Self::Normal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dma => "dma",
+            Self::Dma32 => "dma32",
+            Self::Normal => "normal",
+        }
+    }
+}
+
+/// Where each zone ends.  Dma starts at 0, and Normal runs to the top of
+/// physical memory.  A zone may be empty, e.g. if the board has no device
+/// that needs it, by ending it where the zone before ends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoneBounds {
+    dma_end: PhysAddr,
+    dma32_end: PhysAddr,
+}
+
+impl ZoneBounds {
+    pub const fn new(dma_end: PAddrRepr, dma32_end: PAddrRepr) -> Self {
+        assert!(dma_end <= dma32_end);
+        Self { dma_end: PhysAddr::new(dma_end), dma32_end: PhysAddr::new(dma32_end) }
+    }
+
+    /// Return the physical addresses in `zone`.
+    pub fn range(&self, zone: Zone) -> PhysRange {
+        match zone {
+            Zone::Dma => PhysRange::new(PhysAddr::new(0), self.dma_end),
+            Zone::Dma32 => PhysRange::new(self.dma_end, self.dma32_end),
+            Zone::Normal => PhysRange::new(self.dma32_end, PhysAddr::new(PAddrRepr::MAX)),
+        }
+    }
+
+    /// Return the zone `pa` is in.
+    pub fn zone_of(&self, pa: PhysAddr) -> Zone {
+        if pa < self.dma_end {
+            Zone::Dma
+        } else if pa < self.dma32_end {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// Call `f` with each zone `range` overlaps, and the part of `range` in
+    /// it.
+    pub fn split(&self, range: &PhysRange, mut f: impl FnMut(Zone, &PhysRange)) {
+        for zone in Zone::ALL {
+            let zone_range = self.range(zone);
+            let start = range.start().max(zone_range.start());
+            let end = range.end().min(zone_range.end());
+            if start < end {
+                f(zone, &PhysRange::new(start, end));
+            }
+        }
+    }
+}
+
+/// The zones an allocation may come from: `zone`, the least restrictive it
+/// can use, then if `fallback` is set, each more restrictive zone in turn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoneRequest {
+    pub zone: Zone,
+    pub fallback: bool,
+}
+
+impl ZoneRequest {
+    /// Only memory from `zone`.
+    pub const fn only(zone: Zone) -> Self {
+        Self { zone, fallback: false }
+    }
+
+    /// Memory from `zone`, or failing that, any more restrictive zone.
+    pub const fn or_lower(zone: Zone) -> Self {
+        Self { zone, fallback: true }
+    }
+
+    /// Return the zones to try, in order.
+    pub fn zones(&self) -> impl Iterator<Item = Zone> {
+        let tried = if self.fallback { self.zone as usize + 1 } else { 1 };
+        Zone::ALL[..=self.zone as usize].iter().rev().take(tried).copied()
+    }
+}
+
+/// Counts of the pages in each zone, and how many are free.
+pub struct ZoneAccounts {
+    bounds: ZoneBounds,
+    total: [AtomicUsize; Zone::COUNT],
+    free: [AtomicUsize; Zone::COUNT],
+    page_size: usize,
+}
+
+impl ZoneAccounts {
+    pub const fn new(bounds: ZoneBounds, page_size: usize) -> Self {
+        Self {
+            bounds,
+            total: [const { AtomicUsize::new(0) }; Zone::COUNT],
+            free: [const { AtomicUsize::new(0) }; Zone::COUNT],
+            page_size,
+        }
+    }
+
+    pub fn bounds(&self) -> &ZoneBounds {
+        &self.bounds
+    }
+
+    /// Count the pages of `range`, which is free memory the allocator was
+    /// initialised with, in the zones they're in.
+    pub fn add_memory(&self, range: &PhysRange) {
+        self.bounds.split(range, |zone, part| {
+            let pages = self.pages(part);
+            self.total[zone as usize].fetch_add(pages, Ordering::Relaxed);
+            self.free[zone as usize].fetch_add(pages, Ordering::Relaxed);
+        });
+    }
+
+    /// Count the pages of `range` allocated.  Pages allocated before the
+    /// memory was added, e.g. early page tables, aren't counted as free, so
+    /// the count stops at zero.
+    pub fn alloc(&self, range: &PhysRange) {
+        self.bounds.split(range, |zone, part| {
+            let pages = self.pages(part);
+            let _ = self.free[zone as usize].fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |count| Some(count.saturating_sub(pages)),
+            );
+        });
+    }
+
+    /// Count the pages of `range` freed.  Pages that were reserved when the
+    /// memory was added, and are then released, are added to the total.
+    pub fn free(&self, range: &PhysRange) {
+        self.bounds.split(range, |zone, part| {
+            let free = self.free[zone as usize].fetch_add(self.pages(part), Ordering::Relaxed);
+            self.total[zone as usize].fetch_max(free + self.pages(part), Ordering::Relaxed);
+        });
+    }
+
+    /// Number of pages in `zone`.
+    pub fn total_pages(&self, zone: Zone) -> usize {
+        self.total[zone as usize].load(Ordering::Relaxed)
+    }
+
+    /// Number of pages free in `zone`.
+    pub fn free_pages(&self, zone: Zone) -> usize {
+        self.free[zone as usize].load(Ordering::Relaxed)
+    }
+
+    /// Return a report of each zone's memory, which can be printed.
+    pub fn report(&self) -> Report<'_> {
+        Report { accounts: self }
+    }
+
+    fn pages(&self, range: &PhysRange) -> usize {
+        range.round_out(self.page_size as PAddrRepr).size() / self.page_size
+    }
+}
+
+/// Lists each zone with memory, a line each, with its total and free memory,
+/// and its range.
+pub struct Report<'a> {
+    accounts: &'a ZoneAccounts,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Memory by zone:")?;
+        for zone in Zone::ALL {
+            let total = self.accounts.total_pages(zone) * self.accounts.page_size;
+            if total == 0 {
+                continue;
+            }
+            let free = self.accounts.free_pages(zone) * self.accounts.page_size;
+            writeln!(
+                f,
+                "  {:<8}{:>10}  {:>10} free  {}",
+                zone.name(),
+                Size(total),
+                Size(free),
+                self.accounts.bounds.range(zone)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: ZoneBounds = ZoneBounds::new(0x4000_0000, 0x1_0000_0000);
+
+    #[test]
+    fn zone_of_addresses() {
+        assert_eq!(BOUNDS.zone_of(PhysAddr::new(0)), Zone::Dma);
+        assert_eq!(BOUNDS.zone_of(PhysAddr::new(0x3fff_ffff)), Zone::Dma);
+        assert_eq!(BOUNDS.zone_of(PhysAddr::new(0x4000_0000)), Zone::Dma32);
+        assert_eq!(BOUNDS.zone_of(PhysAddr::new(0xffff_ffff)), Zone::Dma32);
+        assert_eq!(BOUNDS.zone_of(PhysAddr::new(0x1_0000_0000)), Zone::Normal);
+
+        // An empty zone has no addresses
+        let no_dma = ZoneBounds::new(0, 0x1_0000_0000);
+        assert!(no_dma.range(Zone::Dma).is_empty());
+        assert_eq!(no_dma.zone_of(PhysAddr::new(0)), Zone::Dma32);
+    }
+
+    #[test]
+    fn split_across_zones() {
+        let mut parts = Vec::new();
+        BOUNDS.split(&PhysRange::with_end(0x3000_0000, 0x1_2000_0000), |zone, part| {
+            parts.push((zone, part.clone()))
+        });
+        assert_eq!(
+            parts,
+            [
+                (Zone::Dma, PhysRange::with_end(0x3000_0000, 0x4000_0000)),
+                (Zone::Dma32, PhysRange::with_end(0x4000_0000, 0x1_0000_0000)),
+                (Zone::Normal, PhysRange::with_end(0x1_0000_0000, 0x1_2000_0000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn request_fallback_order() {
+        let zones = |request: ZoneRequest| request.zones().collect::<Vec<_>>();
+        assert_eq!(zones(ZoneRequest::only(Zone::Normal)), [Zone::Normal]);
+        assert_eq!(
+            zones(ZoneRequest::or_lower(Zone::Normal)),
+            [Zone::Normal, Zone::Dma32, Zone::Dma]
+        );
+        assert_eq!(zones(ZoneRequest::or_lower(Zone::Dma32)), [Zone::Dma32, Zone::Dma]);
+        assert_eq!(zones(ZoneRequest::or_lower(Zone::Dma)), [Zone::Dma]);
+    }
+
+    #[test]
+    fn accounts_by_zone() {
+        let accounts = ZoneAccounts::new(BOUNDS, 4096);
+        // Memory below 1GiB, and above 4GiB, as on the Raspberry Pi 4
+        accounts.add_memory(&PhysRange::with_end(0x8_0000, 0x3c00_0000));
+        accounts.add_memory(&PhysRange::with_end(0x1_0000_0000, 0x2_0000_0000));
+        assert_eq!(accounts.total_pages(Zone::Dma), 0x3bf80);
+        assert_eq!(accounts.total_pages(Zone::Dma32), 0);
+        assert_eq!(accounts.total_pages(Zone::Normal), 0x10_0000);
+
+        accounts.alloc(&PhysRange::with_end(0x3bff_f000, 0x3c00_0000));
+        accounts.alloc(&PhysRange::with_end(0x1_0000_0000, 0x1_0000_2000));
+        assert_eq!(accounts.free_pages(Zone::Dma), 0x3bf7f);
+        assert_eq!(accounts.free_pages(Zone::Normal), 0xf_fffe);
+        accounts.free(&PhysRange::with_end(0x1_0000_0000, 0x1_0000_1000));
+        assert_eq!(accounts.free_pages(Zone::Normal), 0xf_ffff);
+
+        // Pages allocated before the memory was added don't underflow
+        accounts.alloc(&PhysRange::with_end(0x4000_0000, 0x4000_1000));
+        assert_eq!(accounts.free_pages(Zone::Dma32), 0);
+
+        // Reserved pages that are released are added to the total
+        accounts.free(&PhysRange::with_end(0x4000_0000, 0x4000_1000));
+        assert_eq!(accounts.total_pages(Zone::Dma32), 1);
+        accounts.alloc(&PhysRange::with_end(0x4000_0000, 0x4000_1000));
+
+        let report = accounts.report().to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Memory by zone:",
+                "  dma      959.5 MiB   959.4 MiB free  0x0000000000000000..0x0000000040000000",
+                "  dma32        4 KiB         0 B free  0x0000000040000000..0x0000000100000000",
+                "  normal       4 GiB     3.9 GiB free  0x0000000100000000..0xffffffffffffffff",
+            ]
+        );
+    }
+}
@@ -1,10 +1,131 @@
-use port::fdt::{DeviceTree, Range, RangeMapping, RegBlock, TranslatedReg};
+use port::fdt::{DeviceTree, ParseError, Range, RangeMapping, RegBlock, TranslatedReg};
+use port::fdtbuilder::FdtBuilder;
+use port::mem::PhysRange;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Build the blob, and check that dtc can read it back, if dtc is on the
+/// PATH.  Without it, the blob is returned unchecked.
+fn build(fdt: &FdtBuilder) -> Vec<u8> {
+    let dtb = fdt.build();
+    let dtc = Command::new("dtc")
+        .args(["-q", "-I", "dtb", "-O", "dts", "-o", "/dev/null", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    if let Ok(mut dtc) = dtc {
+        dtc.stdin.take().unwrap().write_all(&dtb).unwrap();
+        let output = dtc.wait_with_output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "dtc rejected the blob: {stderr}");
+    }
+    dtb
+}
+
+/// A tree laid out like a Raspberry Pi 3's, cut down to what the tests use.
+fn rpi_dtb() -> Vec<u8> {
+    let mut fdt = FdtBuilder::new();
+    fdt.reserve(0, 0x1000);
+    fdt.begin_node("")
+        .property_strs("compatible", &["raspberrypi,3-model-b", "brcm,bcm2837"])
+        .property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 1);
+
+    fdt.begin_node("aliases")
+        .property_str("serial0", "/soc/serial@7e215040")
+        .property_str("serial1", "/soc/serial@7e201000")
+        .property_str("soc", "/soc")
+        .end_node();
+    fdt.begin_node("chosen")
+        .property_str(
+            "bootargs",
+            "coherent_pool=1M 8250.nr_uarts=1 snd_bcm2835.enable_compat_alsa=0 \
+             snd_bcm2835.enable_hdmi=1",
+        )
+        .end_node();
+    fdt.begin_node("reserved-memory")
+        .property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 1)
+        .property("ranges", &[]);
+    fdt.begin_node("linux,cma")
+        .property_str("compatible", "shared-dma-pool")
+        .property_u32("size", 0x400_0000)
+        .property("reusable", &[])
+        .end_node();
+    fdt.end_node();
+    fdt.begin_node("thermal-zones");
+    fdt.begin_node("cpu-thermal").property_u32("polling-delay", 1000).end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc")
+        .property_str("compatible", "simple-bus")
+        .property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 1)
+        .property_ranges(
+            &[(0x7e00_0000, 0x3f00_0000, 0x100_0000), (0x4000_0000, 0x4000_0000, 0x1000)],
+            1,
+            1,
+            1,
+        );
+    fdt.begin_node("watchdog@7e100000")
+        .property_str("compatible", "brcm,bcm2835-pm-wdt")
+        .property_reg(&[(0x7e10_0000, 0x114), (0x7e00_a000, 0x24)], 1, 1)
+        .end_node();
+    fdt.begin_node("spi@7e204000")
+        .property_u32("#address-cells", 1)
+        .property_u32("#size-cells", 0)
+        .property_reg(&[(0x7e20_4000, 0x200)], 1, 1);
+    fdt.begin_node("spidev@0").property_str("compatible", "spidev").property_reg(&[(0, 0)], 1, 0);
+    fdt.end_node().end_node();
+    fdt.begin_node("mmc@7e300000")
+        .property_str("compatible", "brcm,bcm2835-sdhci")
+        .property_reg(&[(0x7e30_0000, 0x100)], 1, 1)
+        .property_str("status", "disabled")
+        .end_node();
+    fdt.begin_node("mmcnr@7e300000")
+        .property_str("compatible", "brcm,bcm2835-sdhci")
+        .property_reg(&[(0x7e30_0000, 0x100)], 1, 1)
+        .property_str("status", "okay")
+        .end_node();
+    fdt.begin_node("serial@7e201000")
+        .property_strs("compatible", &["arm,pl011", "arm,primecell"])
+        .property_reg(&[(0x7e20_1000, 0x200)], 1, 1)
+        .property_str("status", "okay")
+        .end_node();
+    fdt.begin_node("serial@7e215040")
+        .property_str("compatible", "brcm,bcm2835-aux-uart")
+        .property_reg(&[(0x7e21_5040, 0x40)], 1, 1)
+        .end_node();
+    fdt.end_node();
+
+    fdt.begin_node("cpus").property_u32("#address-cells", 1).property_u32("#size-cells", 0);
+    for cpu in 0..4 {
+        fdt.begin_node(&format!("cpu@{cpu}"))
+            .property_str("device_type", "cpu")
+            .property_reg(&[(cpu, 0)], 1, 0)
+            .end_node();
+    }
+    fdt.end_node();
+    fdt.end_node();
+    build(&fdt)
+}
 
-static TEST1_DTB: &[u8] = include_bytes!("../lib/test/fdt/test1.dtb");
+/// A tree with `node` under the root, with the root's cells.
+fn dtb_with(address_cells: u32, size_cells: u32, node: impl FnOnce(&mut FdtBuilder)) -> Vec<u8> {
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("")
+        .property_u32("#address-cells", address_cells)
+        .property_u32("#size-cells", size_cells);
+    node(&mut fdt);
+    fdt.end_node();
+    build(&fdt)
+}
 
 #[test]
 fn find_by_path() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     // Find the first node.  Next token should not be the same node.
     let root = dt.find_by_path("/").unwrap();
@@ -23,13 +144,14 @@ fn find_by_path() {
 
 #[test]
 fn traverse_tree() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     let root = dt.root().unwrap();
     assert_eq!(dt.node_name(&root).unwrap(), "");
     assert_eq!(root.depth(), 0);
 
-    let aliases = dt.children(&root).nth(0).unwrap();
+    let aliases = dt.children(&root).next().unwrap();
     assert_eq!(dt.node_name(&aliases).unwrap(), "aliases");
     assert_eq!(aliases.depth(), 1);
 
@@ -47,7 +169,8 @@ fn traverse_tree() {
 
 #[test]
 fn find_compatible() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     // Simple test for compatible where there's only a single match in the string list
     let mut dma_iter = dt.find_compatible("shared-dma-pool");
@@ -87,7 +210,8 @@ fn find_compatible() {
 
 #[test]
 fn get_cells() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     let node = dt.find_by_path("/reserved-memory").unwrap();
     assert_eq!(
@@ -112,7 +236,8 @@ fn get_cells() {
 
 #[test]
 fn iterate_over_children() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     let children = dt
         .find_by_path("/thermal-zones")
@@ -126,7 +251,8 @@ fn iterate_over_children() {
 
 #[test]
 fn iterate_over_device_types() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     let cpus = dt.find_device_type("cpu").flat_map(|n| dt.node_name(&n)).collect::<Vec<&str>>();
     assert_eq!(cpus, vec!["cpu@0", "cpu@1", "cpu@2", "cpu@3"]);
@@ -134,7 +260,8 @@ fn iterate_over_device_types() {
 
 #[test]
 fn get_reg() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
     let uart_reg_raw = dt
@@ -164,9 +291,56 @@ fn get_reg() {
     );
 }
 
+#[test]
+fn reg_cells() {
+    // Two cells each, as on 64 bit machines
+    let dtb = dtb_with(2, 2, |fdt| {
+        fdt.begin_node("dev@100000000")
+            .property_reg(&[(0x1_0000_0000, 0x2_0000_0000)], 2, 2)
+            .end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+    let dev = dt.find_by_path("/dev@100000000").unwrap();
+    let reg = dt.property_reg_iter(dev).collect::<Vec<RegBlock>>();
+    assert_eq!(reg, vec![RegBlock { addr: 0x1_0000_0000, len: Some(0x2_0000_0000) }]);
+
+    // Mixed, and a trailing partial entry is ignored
+    let dtb = dtb_with(2, 1, |fdt| {
+        let mut value = Vec::new();
+        port::fdtbuilder::push_cells(&mut value, 0x8_0000_1000, 2);
+        port::fdtbuilder::push_cells(&mut value, 0x1000, 1);
+        port::fdtbuilder::push_cells(&mut value, 0x9000, 2);
+        fdt.begin_node("dev").property("reg", &value).end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+    let dev = dt.find_by_path("/dev").unwrap();
+    let reg = dt.property_reg_iter(dev).collect::<Vec<RegBlock>>();
+    assert_eq!(reg, vec![RegBlock { addr: 0x8_0000_1000, len: Some(0x1000) }]);
+
+    // More than two cells isn't supported
+    let dtb = dtb_with(3, 1, |fdt| {
+        fdt.begin_node("dev").property_u32s("reg", &[0, 0, 0x1000, 0x10]).end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+    let dev = dt.find_by_path("/dev").unwrap();
+    assert_eq!(dt.property_reg_iter(dev).next(), None);
+
+    // Without #address-cells and #size-cells, the defaults are 2 and 1
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.begin_node("dev").property_reg(&[(0x1_0000_0000, 0x100)], 2, 1).end_node();
+    fdt.end_node();
+    let dtb = build(&fdt);
+    let dt = DeviceTree::new(&dtb).unwrap();
+    let dev = dt.find_by_path("/dev").unwrap();
+    let reg = dt.property_reg_iter(dev).collect::<Vec<RegBlock>>();
+    assert_eq!(reg, vec![RegBlock { addr: 0x1_0000_0000, len: Some(0x100) }]);
+}
+
 #[test]
 fn get_ranges() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     // Get raw reg
     let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
@@ -191,11 +365,16 @@ fn get_ranges() {
             }),
         ]
     );
+
+    // An empty ranges is an identity mapping
+    let resmem = dt.find_by_path("/reserved-memory").unwrap();
+    assert_eq!(dt.property_range_iter(resmem).collect::<Vec<Range>>(), vec![Range::Identity]);
 }
 
 #[test]
 fn get_translated_reg() {
-    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
 
     // Get translated reg, based on parent ranges
     let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
@@ -205,3 +384,211 @@ fn get_translated_reg() {
         vec![TranslatedReg::Translated(RegBlock { addr: 0x3f20_1000, len: Some(0x200) })]
     );
 }
+
+#[test]
+fn translate_through_buses() {
+    // A 64 bit root, a bus with 1 cell addresses mapped high, and a nested
+    // bus offsetting part of it again, with a device outside its ranges
+    let dtb = dtb_with(2, 2, |fdt| {
+        fdt.begin_node("bus")
+            .property_u32("#address-cells", 1)
+            .property_u32("#size-cells", 1)
+            .property_ranges(&[(0x7c00_0000, 0x4_7c00_0000, 0x400_0000)], 1, 2, 1);
+        fdt.begin_node("inner")
+            .property_u32("#address-cells", 1)
+            .property_u32("#size-cells", 1)
+            .property_ranges(&[(0x0, 0x7d00_0000, 0x10_0000)], 1, 1, 1);
+        fdt.begin_node("dev@1000").property_reg(&[(0x1000, 0x100)], 1, 1).end_node();
+        fdt.begin_node("dev@200000").property_reg(&[(0x20_0000, 0x100)], 1, 1).end_node();
+        fdt.end_node();
+        fdt.end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    let dev = dt.find_by_path("/bus/inner/dev@1000").unwrap();
+    let reg = dt.property_translated_reg_iter(dev).collect::<Vec<TranslatedReg>>();
+    assert_eq!(
+        reg,
+        vec![TranslatedReg::Translated(RegBlock { addr: 0x4_7d00_1000, len: Some(0x100) })]
+    );
+    let dev = dt.find_by_path("/bus/inner/dev@200000").unwrap();
+    let reg = dt.property_translated_reg_iter(dev).collect::<Vec<TranslatedReg>>();
+    assert_eq!(reg, vec![TranslatedReg::Unreachable]);
+}
+
+#[test]
+fn regions_know_their_node() {
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    let uart = dt.find_by_path("/soc/serial@7e201000").unwrap();
+    let region = dt.regions(uart).next().unwrap();
+    assert_eq!((region.node(), region.index(), region.node_name()), (uart, 0, "serial@7e201000"));
+    assert_eq!(region.reg(), RegBlock { addr: 0x7e20_1000, len: Some(0x200) });
+    assert_eq!(
+        format!("{region}"),
+        "/soc/serial@7e201000 reg[0] 0x7e201000..0x7e201200 (untranslated)"
+    );
+
+    let region = region.translate().unwrap();
+    assert_eq!(region.phys_range(), Some(PhysRange::with_len(0x3f20_1000, 0x200)));
+    assert_eq!(
+        format!("{region}"),
+        "/soc/serial@7e201000 reg[0] 0x3f201000..0x3f201200 (translated)"
+    );
+
+    // A reg without a size has no range, and one outside its bus's ranges
+    // stays untranslated
+    let spidev = dt.find_by_path("/soc/spi@7e204000/spidev@0").unwrap();
+    let region = dt.regions(spidev).next().unwrap();
+    assert_eq!(format!("{region}"), "/soc/spi@7e204000/spidev@0 reg[0] 0x0 (untranslated)");
+    let unreachable = dt.translated_regions(spidev).next().unwrap().unwrap_err();
+    assert_eq!(unreachable.reg(), region.reg());
+}
+
+#[test]
+fn resolve_path() {
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    // Absolute paths and aliases, ignoring stdout-path style options
+    let pl011 = dt.find_by_path("/soc/serial@7e201000").unwrap();
+    assert_eq!(dt.resolve_path("/soc/serial@7e201000"), Some(pl011));
+    assert_eq!(dt.resolve_path("serial1"), Some(pl011));
+    assert_eq!(dt.resolve_path("serial1:115200n8"), Some(pl011));
+    let miniuart = dt.resolve_path("serial0").unwrap();
+    assert_eq!(dt.node_name(&miniuart).unwrap(), "serial@7e215040");
+    assert!(dt.is_compatible(&miniuart, "brcm,bcm2835-aux-uart"));
+    assert!(!dt.is_compatible(&miniuart, "arm,pl011"));
+
+    // Paths relative to an alias
+    let soc = dt.resolve_path("soc").unwrap();
+    assert_eq!(dt.node_name(&soc).unwrap(), "soc");
+    assert_eq!(dt.resolve_path("soc/serial@7e201000"), Some(pl011));
+
+    assert_eq!(dt.resolve_path("serial9"), None);
+    assert_eq!(dt.resolve_path("soc/foo"), None);
+
+    // The Raspberry Pi firmware doesn't set stdout-path
+    assert_eq!(dt.stdout_path(), None);
+
+    // Nor was an initrd loaded
+    assert_eq!(dt.initrd_range(), None);
+
+    assert_eq!(
+        dt.bootargs(),
+        Some(
+            "coherent_pool=1M 8250.nr_uarts=1 snd_bcm2835.enable_compat_alsa=0 \
+             snd_bcm2835.enable_hdmi=1"
+        )
+    );
+}
+
+#[test]
+fn chosen() {
+    let dtb = dtb_with(2, 2, |fdt| {
+        fdt.begin_node("aliases").property_str("serial0", "/pl011@9000000").end_node();
+        fdt.begin_node("chosen")
+            .property_str("stdout-path", "serial0:115200n8")
+            .property_str("bootargs", "console=ttyAMA0")
+            .property_u32("linux,initrd-start", 0x4800_0000)
+            .property_u64("linux,initrd-end", 0x4810_0000)
+            .end_node();
+        fdt.begin_node("pl011@9000000").property_str("compatible", "arm,pl011").end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    let pl011 = dt.find_by_path("/pl011@9000000").unwrap();
+    assert_eq!(dt.stdout_path(), Some(pl011));
+    assert_eq!(dt.bootargs(), Some("console=ttyAMA0"));
+    assert_eq!(dt.initrd_range(), Some(PhysRange::with_end(0x4800_0000, 0x4810_0000)));
+
+    // The older name for stdout-path, and an initrd ending before it starts
+    let dtb = dtb_with(2, 2, |fdt| {
+        fdt.begin_node("chosen")
+            .property_str("linux,stdout-path", "/uart")
+            .property_u64("linux,initrd-start", 0x4810_0000)
+            .property_u64("linux,initrd-end", 0x4800_0000)
+            .end_node();
+        fdt.begin_node("uart").end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+    assert_eq!(dt.stdout_path(), dt.find_by_path("/uart"));
+    assert_eq!(dt.bootargs(), None);
+    assert_eq!(dt.initrd_range(), None);
+}
+
+#[test]
+fn memory_banks() {
+    let dtb = dtb_with(2, 2, |fdt| {
+        fdt.begin_node("memory@40000000")
+            .property_str("device_type", "memory")
+            .property_reg(&[(0x4000_0000, 0x4000_0000), (0x1_0000_0000, 0x8000_0000)], 2, 2)
+            .end_node();
+        fdt.begin_node("memory@200000000")
+            .property_str("device_type", "memory")
+            .property_reg(&[(0x2_0000_0000, 0x1000_0000)], 2, 2)
+            .end_node();
+    });
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    let banks = dt
+        .find_device_type("memory")
+        .flat_map(|node| dt.property_translated_reg_iter(node))
+        .flat_map(|reg| reg.regblock())
+        .collect::<Vec<RegBlock>>();
+    assert_eq!(
+        banks,
+        vec![
+            RegBlock { addr: 0x4000_0000, len: Some(0x4000_0000) },
+            RegBlock { addr: 0x1_0000_0000, len: Some(0x8000_0000) },
+            RegBlock { addr: 0x2_0000_0000, len: Some(0x1000_0000) },
+        ]
+    );
+}
+
+#[test]
+fn node_status() {
+    let dtb = rpi_dtb();
+    let dt = DeviceTree::new(&dtb).unwrap();
+
+    let pl011 = dt.find_by_path("/soc/serial@7e201000").unwrap();
+    assert!(dt.is_enabled(&pl011));
+    let soc = dt.find_by_path("/soc").unwrap();
+    assert!(dt.is_enabled(&soc));
+    let disabled = dt.nodes().find(|n| !dt.is_enabled(n)).unwrap();
+    let status = dt.property(&disabled, "status").unwrap();
+    assert_eq!(dt.property_value_as_str(&status), Some("disabled"));
+}
+
+#[test]
+fn malformed() {
+    let dtb = rpi_dtb();
+
+    // Too short for a header
+    assert!(matches!(DeviceTree::new(&dtb[..39]), Err(ParseError::InvalidHeader)));
+
+    // Bad magic
+    let mut bad_magic = dtb.clone();
+    bad_magic[0] ^= 0xff;
+    assert!(matches!(DeviceTree::new(&bad_magic), Err(ParseError::InvalidMagic)));
+
+    // Shorter or longer than the header says
+    assert!(matches!(DeviceTree::new(&dtb[..dtb.len() - 1]), Err(ParseError::BufferTooSmall)));
+    let mut longer = dtb.clone();
+    longer.push(0);
+    assert!(matches!(DeviceTree::new(&longer), Err(ParseError::BufferTooSmall)));
+
+    // An unknown token in the structure block ends the walk, rather than
+    // being read past
+    let dtb = dtb_with(1, 1, |fdt| {
+        fdt.begin_node("a").end_node();
+    });
+    let off_struct = u32::from_be_bytes(dtb[8..12].try_into().unwrap()) as usize;
+    let mut bad_token = dtb.clone();
+    bad_token[off_struct + 3] = 0x7;
+    let dt = DeviceTree::new(&bad_token).unwrap();
+    assert_eq!(dt.root(), None);
+    assert_eq!(dt.nodes().count(), 0);
+    assert_eq!(dt.find_by_path("/a"), None);
+}
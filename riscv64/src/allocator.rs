@@ -1,17 +1,69 @@
+/// The kernel heap starts as a static arena, so it can be used before the
+/// page allocator is ready.  Once translation is on, `init` lets it grow
+/// with pages from the page allocator, reached through the direct map.
 #[cfg(not(test))]
 mod global {
+    use crate::{dmap, pagealloc};
     use core::mem;
-    use core::sync::atomic::AtomicPtr;
     use port::allocator::{
-        Block, BumpAlloc, QuickFit, global::GlobalHeap, global::GlobalQuickAlloc,
+        Block, BumpAlloc, HeapStats, QuickFit, global::GlobalHeap, global::GlobalQuickAlloc,
     };
+    use port::mem::PAGE_SIZE_4K;
+    use port::memaccount::MemCategory;
+    use port::println;
+
+    static mut HEAP: GlobalHeap = GlobalHeap::new();
 
     #[global_allocator]
-    static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(AtomicPtr::new({
-        static mut HEAP: GlobalHeap = GlobalHeap::new();
-        static mut ALLOC: QuickFit = QuickFit::new(BumpAlloc::new(unsafe {
+    static GLOBAL_ALLOCATOR: GlobalQuickAlloc =
+        GlobalQuickAlloc::new(QuickFit::new(BumpAlloc::new(unsafe {
             Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
-        }));
-        &raw mut ALLOC
-    }));
+        })));
+
+    /// Let the heap grow once the static arena is used up.  Call once the
+    /// kernel page tables, with the direct map, are in use.
+    pub fn init() {
+        GLOBAL_ALLOCATOR.set_grow_fn(grow);
+    }
+
+    /// Returns heap statistics, or None if the heap is locked.
+    pub fn try_stats() -> Option<HeapStats> {
+        GLOBAL_ALLOCATOR.try_stats()
+    }
+
+    /// Prints the live allocations, in debug builds, e.g. to find leaks
+    /// after a test.
+    pub fn dump_leaks() {
+        GLOBAL_ALLOCATOR.dump_leaks();
+    }
+
+    /// Allocate contiguous pages for at least `size` bytes of heap.
+    fn grow(size: usize) -> Option<Block> {
+        let pages = size.div_ceil(PAGE_SIZE_4K);
+        let range = pagealloc::allocate_contiguous_physpages_for(pages, MemCategory::Heap).ok()?;
+        let va = match dmap::phys_to_dmap(range.start()) {
+            Ok(va) => va,
+            Err(err) => {
+                println!("error:allocator:grow:pages not in direct map: {}: {}", range, err);
+                let _ = pagealloc::free_physpages_for(&range, MemCategory::Heap);
+                return None;
+            }
+        };
+        Some(unsafe { Block::new_from_raw_parts(va.addr() as *mut u8, range.size()) })
+    }
 }
+
+#[cfg(not(test))]
+pub use global::{dump_leaks, init, try_stats};
+
+/// Host tests use std's allocator, so there's no heap to grow.
+#[cfg(test)]
+pub fn init() {}
+
+#[cfg(test)]
+pub fn try_stats() -> Option<port::allocator::HeapStats> {
+    None
+}
+
+#[cfg(test)]
+pub fn dump_leaks() {}
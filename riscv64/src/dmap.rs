@@ -0,0 +1,114 @@
+/// The direct map makes all physical RAM reachable at a fixed offset from
+/// the start of the DIRECT_MAP region, so that any page can be accessed, e.g.
+/// a page table, without mapping it first.  As on aarch64, only RAM discovered from the device tree
+/// is mapped, using the largest pages possible, and translating any other
+/// address fails.
+use crate::param::DIRECT_MAP;
+use crate::vm::PageTable;
+use port::mcslock::{Lock, LockNode};
+use port::mem::{MapFlags, MemError, PAGE_SIZE_4K, PhysAddr, PhysRange, PhysRangeSet, VirtAddr};
+
+#[cfg(not(test))]
+use port::println;
+
+/// RAM mapped by the direct map.  Empty until `init`.
+static DMAP_RAM: Lock<PhysRangeSet> = Lock::new("dmap", PhysRangeSet::new());
+
+/// Map each range of `memory` into the direct map of `kernel_pt`.  Only whole
+/// pages are mapped, and memory beyond the size of the DIRECT_MAP region is
+/// ignored.  The pages of `readonly`, which must be sorted, e.g. the initrd,
+/// are mapped read-only.
+pub fn init(
+    kernel_pt: &mut PageTable,
+    memory: &PhysRangeSet,
+    readonly: &[PhysRange],
+) -> Result<(), MemError> {
+    let mut mapped = PhysRangeSet::new();
+    for range in memory.iter() {
+        let start = range.start().round_up(PAGE_SIZE_4K as u64);
+        let end = range.end().round_down(PAGE_SIZE_4K as u64);
+        let end = end.min(PhysAddr::new(DIRECT_MAP.size as u64));
+        if start >= end {
+            println!("error:dmap:init:can't direct map memory range:{range}");
+            continue;
+        }
+        let range = PhysRange::new(start, end);
+        split_readonly(&range, readonly, |part, flags| {
+            let va = VirtAddr::new(DIRECT_MAP.start + part.start().addr() as usize);
+            kernel_pt.map_range(va, &part, flags)
+        })?;
+        // Both sets have the same capacity, so this can't fail
+        let _ = mapped.add(&range);
+    }
+
+    let node = LockNode::new();
+    *DMAP_RAM.lock(&node) = mapped;
+    Ok(())
+}
+
+/// Call `f` with each part of the page aligned `range`, and whether it's in
+/// the pages of the sorted `readonly` ranges, in order.
+fn split_readonly<E>(
+    range: &PhysRange,
+    readonly: &[PhysRange],
+    mut f: impl FnMut(PhysRange, MapFlags) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut at = range.start();
+    for ro in readonly.iter().map(|ro| ro.round_out(PAGE_SIZE_4K as u64)) {
+        let ro_start = ro.start().max(at);
+        let ro_end = ro.end().min(range.end());
+        if ro_start >= ro_end {
+            continue;
+        }
+        if at < ro_start {
+            f(PhysRange::new(at, ro_start), MapFlags::RW)?;
+        }
+        f(PhysRange::new(ro_start, ro_end), MapFlags::READ)?;
+        at = ro_end;
+    }
+    if at < range.end() {
+        f(PhysRange::new(at, range.end()), MapFlags::RW)?;
+    }
+    Ok(())
+}
+
+/// Return the direct map address of `pa`.  Fails with `PhysNotMapped` if
+/// it's not in RAM.
+pub fn phys_to_dmap(pa: PhysAddr) -> Result<VirtAddr, MemError> {
+    let node = LockNode::new();
+    let ram = DMAP_RAM.lock(&node);
+    ram.iter()
+        .any(|r| r.start() <= pa && pa < r.end())
+        .then(|| VirtAddr::new(DIRECT_MAP.start + pa.addr() as usize))
+        .ok_or(MemError::PhysNotMapped { pa })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_readonly_parts() {
+        let range = PhysRange::with_len(0x8000_0000, 0x10_0000);
+        let readonly = [
+            PhysRange::with_len(0x7fff_0000, 0x1_0800), // Overlaps the start
+            PhysRange::with_len(0x8008_0100, 0x1000),   // Rounded out to 2 pages
+            PhysRange::with_len(0x8020_0000, 0x1000),   // Beyond the end
+        ];
+        let mut parts = Vec::new();
+        split_readonly::<()>(&range, &readonly, |part, flags| {
+            parts.push((part.start().addr(), part.end().addr(), flags));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            parts,
+            [
+                (0x8000_0000, 0x8000_1000, MapFlags::READ),
+                (0x8000_1000, 0x8008_0000, MapFlags::RW),
+                (0x8008_0000, 0x8008_2000, MapFlags::READ),
+                (0x8008_2000, 0x8010_0000, MapFlags::RW),
+            ]
+        );
+    }
+}
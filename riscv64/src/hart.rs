@@ -0,0 +1,32 @@
+/// hart keeps the current hart's id in tp, so port::percpu can find it with
+/// a register read.  Nothing else uses tp, as there's no thread local
+/// storage in the kernel.  Each hart also has a stack of the locks it holds,
+/// for port::lockdebug.
+use crate::param::MAX_CORES;
+use port::lockdebug::CoreHeld;
+
+static HELD_LOCKS: [CoreHeld; MAX_CORES] = [const { CoreHeld::new() }; MAX_CORES];
+///
+/// Record `hartid`, which SBI passes each hart on entry, in tp, and use it as
+/// the core id.
+#[allow(unused_variables)]
+pub fn init(hartid: usize) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("mv tp, {hartid}", hartid = in(reg) hartid);
+    }
+    port::percpu::set_core_id_fn(hart_id);
+    port::lockdebug::set_held_stacks(&HELD_LOCKS);
+}
+
+/// Return the current hart's id, from tp.
+fn hart_id() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let hartid: usize;
+        core::arch::asm!("mv {hartid}, tp", hartid = out(reg) hartid);
+        hartid
+    }
+    #[cfg(test)]
+    0
+}
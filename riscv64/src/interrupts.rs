@@ -0,0 +1,37 @@
+/// Masks supervisor interrupts on the current hart with sstatus.SIE.
+use port::interrupts::{self, Interrupts};
+
+const SSTATUS_SIE: usize = 1 << 1;
+
+struct Sie;
+
+impl Interrupts for Sie {
+    fn disable() -> usize {
+        #[cfg(not(test))]
+        unsafe {
+            let sstatus: usize;
+            core::arch::asm!(
+                "csrrci {sstatus}, sstatus, {sie}",
+                sstatus = out(reg) sstatus,
+                sie = const SSTATUS_SIE,
+            );
+            sstatus & SSTATUS_SIE
+        }
+        #[cfg(test)]
+        0
+    }
+
+    fn restore(state: usize) {
+        if state & SSTATUS_SIE != 0 {
+            #[cfg(not(test))]
+            unsafe {
+                core::arch::asm!("csrsi sstatus, {sie}", sie = const SSTATUS_SIE);
+            }
+        }
+    }
+}
+
+/// Use sstatus.SIE for port::interrupts.
+pub fn init() {
+    interrupts::init::<Sie>();
+}
@@ -0,0 +1,172 @@
+use crate::param::LAYOUT;
+use port::layout::LayoutError;
+use port::mem::{MapFlags, PAGE_SIZE_4K, PhysRange};
+use port::memaccount::MemCategory;
+use port::physmap::{PhysMap, PhysRegion};
+
+#[cfg(not(test))]
+use port::{print, println};
+
+// These map to definitions in kernel.ld
+unsafe extern "C" {
+    static text: [u64; 0];
+    static etext: [u64; 0];
+    static rodata: [u64; 0];
+    static erodata: [u64; 0];
+    static data: [u64; 0];
+    static edata: [u64; 0];
+    static bss: [u64; 0];
+    static end: [u64; 0];
+}
+
+// The boot stack, defined in l.S
+unsafe extern "C" {
+    static stack: [u64; 0];
+}
+
+/// Size of the boot stack, as set up in l.S.
+const BOOT_STACK_SIZE: usize = 4096 * 4;
+
+fn text_addr() -> usize {
+    unsafe { text.as_ptr().addr() }
+}
+
+fn etext_addr() -> usize {
+    unsafe { etext.as_ptr().addr() }
+}
+
+fn rodata_addr() -> usize {
+    unsafe { rodata.as_ptr().addr() }
+}
+
+fn erodata_addr() -> usize {
+    unsafe { erodata.as_ptr().addr() }
+}
+
+fn data_addr() -> usize {
+    unsafe { data.as_ptr().addr() }
+}
+
+fn edata_addr() -> usize {
+    unsafe { edata.as_ptr().addr() }
+}
+
+fn bss_addr() -> usize {
+    unsafe { bss.as_ptr().addr() }
+}
+
+fn end_addr() -> usize {
+    unsafe { end.as_ptr().addr() }
+}
+
+fn stack_addr() -> usize {
+    unsafe { stack.as_ptr().addr() }
+}
+
+// The kernel is linked to run at its load address, so the addresses of the
+// sections are also their physical addresses.
+
+pub fn text_range() -> PhysRange {
+    PhysRange::with_end(text_addr() as u64, etext_addr() as u64)
+}
+
+pub fn rodata_range() -> PhysRange {
+    PhysRange::with_end(rodata_addr() as u64, erodata_addr() as u64)
+}
+
+pub fn data_range() -> PhysRange {
+    PhysRange::with_end(data_addr() as u64, edata_addr() as u64)
+}
+
+pub fn bss_range() -> PhysRange {
+    PhysRange::with_end(bss_addr() as u64, end_addr() as u64)
+}
+
+/// The boot stack is in the bss, so it's also covered by `bss_range`.
+pub fn boot_stack_range() -> PhysRange {
+    PhysRange::with_len(stack_addr() as u64, BOOT_STACK_SIZE)
+}
+
+/// A section of the kernel image, and the flags it's mapped with.
+pub struct KernelSection {
+    pub name: &'static str,
+    pub range: PhysRange,
+    pub flags: MapFlags,
+}
+
+/// Return the sections of the kernel image, in address order.
+pub fn kernel_sections() -> [KernelSection; 4] {
+    [
+        KernelSection { name: "text", range: text_range(), flags: MapFlags::RX },
+        KernelSection { name: "rodata", range: rodata_range(), flags: MapFlags::READ },
+        KernelSection { name: "data", range: data_range(), flags: MapFlags::RW },
+        KernelSection { name: "bss", range: bss_range(), flags: MapFlags::RW },
+    ]
+}
+
+/// Ranges of physical memory recorded by the memory subsystems as they're set
+/// up, for `report_memory_map`.
+pub static PHYS_MAP: PhysMap<48> = PhysMap::new();
+
+/// Record a range of physical memory in PHYS_MAP, with the flags it's mapped
+/// with, if it's relevant.
+pub fn record_phys_range(name: &'static str, range: PhysRange, flags: Option<MapFlags>) {
+    if let Err(err) = PHYS_MAP.record(PhysRegion { name, range, flags }) {
+        println!("error:kmem:record_phys_range:can't record range. name:{name} err:{err:?}");
+    }
+}
+
+/// Record the physical memory used by the kernel image sections and the boot
+/// stack.
+pub fn record_kernel_phys_ranges() {
+    for section in kernel_sections() {
+        record_phys_range(section.name, section.range, Some(section.flags));
+    }
+    record_phys_range("boot stack", boot_stack_range(), None);
+}
+
+/// Print the physical memory map, in address order, and the memory the page
+/// allocator has free, unless it's locked, then the kernel virtual memory
+/// layout.
+pub fn report_memory_map() {
+    let free = crate::pagealloc::try_stats().map(|stats| stats.free_pages * PAGE_SIZE_4K);
+    print!("{}", PHYS_MAP.report(free));
+    print!("{}", port::layout::report());
+}
+
+/// Check the kernel address space layout in param.rs, then register it with
+/// port.
+pub fn init_layout() -> Result<(), LayoutError> {
+    port::layout::check(&LAYOUT)?;
+    port::layout::set_layout(&LAYOUT);
+    Ok(())
+}
+
+/// Return the memory used for each category that isn't allocated from the
+/// page allocator: the kernel image, the boot stack in its bss, and the DTB
+/// and initrd.
+fn static_memory_usage() -> [(MemCategory, usize); 3] {
+    let image: usize = kernel_sections().iter().map(|section| section.range.size()).sum();
+    let boot_stack = boot_stack_range().size();
+    let boot_data = PHYS_MAP
+        .iter()
+        .filter(|region| matches!(region.name, "dtb" | "initrd"))
+        .map(|region| region.range.size())
+        .sum();
+    [
+        (MemCategory::KernelImage, image - boot_stack),
+        (MemCategory::Stacks, boot_stack),
+        (MemCategory::BootData, boot_data),
+    ]
+}
+
+/// Print the memory used for each category, and the memory the page
+/// allocator has free, unless it's locked.
+pub fn report_memory_usage() {
+    let free = crate::pagealloc::try_stats().map(|stats| stats.free_pages * PAGE_SIZE_4K);
+    print!("{}", crate::pagealloc::MEM_ACCOUNTS.report(&static_memory_usage(), free));
+}
+
+pub fn total_kernel_range() -> PhysRange {
+    PhysRange::with_end(text_addr() as u64, end_addr() as u64)
+}
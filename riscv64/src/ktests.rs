@@ -0,0 +1,185 @@
+/// ktests are tests of the kernel that can only run once it's booted, run
+/// under QEMU by `cargo xtask qemu --ktest`.  See port::ktest.
+use crate::kmem::{self, boot_stack_range, kernel_sections, total_kernel_range};
+use crate::platform::devcons;
+use crate::vm::{PageSize, PageTable};
+use crate::{allocator, dmap, memory_ranges, pagealloc, time};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use port::fdt::DeviceTree;
+use port::ktest;
+use port::mem::{MapFlags, PAGE_SIZE_4K, VirtAddr};
+use port::time::Deadline;
+
+ktest! {
+    fn heap_alloc_free_cycle() {
+        let before = allocator::try_stats().expect("heap locked");
+        for round in 0..8u8 {
+            // Sizes from 1 byte to 512KiB, so both small and large
+            // allocations are cycled
+            let blocks: Vec<Box<[u8]>> =
+                (0..20).map(|i| vec![round ^ i; 1 << i].into_boxed_slice()).collect();
+            for (i, block) in blocks.iter().enumerate() {
+                assert!(block.iter().all(|&b| b == round ^ i as u8), "block {i} corrupted");
+            }
+        }
+        let after = allocator::try_stats().expect("heap locked");
+        assert_eq!(after.allocated_bytes, before.allocated_bytes, "heap leaked");
+        assert_eq!(after.allocs - after.frees, before.allocs - before.frees, "heap leaked");
+    }
+}
+
+ktest! {
+    fn pagealloc_alloc_free_cycle() {
+        let before = pagealloc::try_stats().expect("page allocator locked");
+        for _ in 0..4 {
+            let pages: Vec<_> = (0..64).map(|_| pagealloc::allocate_physpage().unwrap()).collect();
+            for (i, pa) in pages.iter().enumerate() {
+                assert!(pa.is_multiple_of(PAGE_SIZE_4K as u64), "page {pa:?} not aligned");
+                assert!(!pages[..i].contains(pa), "page {pa:?} allocated twice");
+            }
+            for pa in pages {
+                pagealloc::free_physpage(pa).unwrap();
+            }
+        }
+        let contiguous = pagealloc::allocate_contiguous_physpages(16).unwrap();
+        assert_eq!(contiguous.size(), 16 * PAGE_SIZE_4K);
+        pagealloc::free_physpages(&contiguous).unwrap();
+        let after = pagealloc::try_stats().expect("page allocator locked");
+        assert_eq!(after.free_pages, before.free_pages, "pages leaked");
+    }
+}
+
+ktest! {
+    fn map_lookup_round_trip() {
+        let before = pagealloc::try_stats().expect("page allocator locked");
+        // A table of its own, which isn't switched to, so the mapping can be
+        // anywhere
+        let mut pt = PageTable::new().unwrap();
+        let pa = pagealloc::allocate_physpage().unwrap();
+        let va = VirtAddr::new(0x10_0000_0000);
+        pt.map(va, pa, PageSize::Page4K, MapFlags::RW).unwrap();
+
+        let mapping = pt.lookup(VirtAddr::new(va.addr() + 0x123)).expect("page not mapped");
+        assert_eq!(mapping.pa.addr(), pa.addr() + 0x123);
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+        assert!(pt.lookup(VirtAddr::new(va.addr() + PAGE_SIZE_4K)).is_none());
+
+        assert_eq!(pt.unmap(va).unwrap().pa, pa);
+        assert!(pt.lookup(va).is_none());
+        pagealloc::free_physpage(pa).unwrap();
+        // Unmapping frees the tables it empties, but not the root
+        let after = pagealloc::try_stats().expect("page allocator locked");
+        assert_eq!(after.free_pages, before.free_pages - 1, "pages leaked");
+    }
+}
+
+ktest! {
+    fn kmem_layout() {
+        let total = total_kernel_range();
+        let sections = kernel_sections();
+        for (i, section) in sections.iter().enumerate() {
+            let range = &section.range;
+            assert!(range.start() <= range.end(), "{} is inverted: {range}", section.name);
+            assert!(
+                range.start().is_multiple_of(PAGE_SIZE_4K as u64),
+                "{} isn't page aligned: {range}",
+                section.name
+            );
+            assert!(
+                total.start() <= range.start() && range.end() <= total.end(),
+                "{} isn't in the kernel: {range}",
+                section.name
+            );
+            if let Some(prev) = i.checked_sub(1).map(|i| &sections[i]) {
+                assert!(
+                    prev.range.end() <= range.start(),
+                    "{} {} overlaps {} {range}",
+                    prev.name,
+                    prev.range,
+                    section.name
+                );
+            }
+        }
+
+        let bss = kmem::bss_range();
+        let stack = boot_stack_range();
+        assert!(bss.start() <= stack.start() && stack.end() <= bss.end(), "stack {stack}");
+
+        // The tests are in the data section, where the linker script put
+        // them.  The kernel runs at its physical address.
+        let data = kmem::data_range();
+        let tests = port::ktest::registered().as_ptr_range();
+        let (start, end) = (tests.start.addr() as u64, tests.end.addr() as u64);
+        assert!(data.start().addr() <= start && end <= data.end().addr());
+    }
+}
+
+ktest! {
+    fn fdt_from_qemu() {
+        let dtb = kmem::PHYS_MAP.iter().find(|r| r.name == "dtb").expect("no dtb recorded");
+        let dtb_va = dmap::phys_to_dmap(dtb.range.start()).expect("dtb isn't in RAM");
+        let dt = unsafe { DeviceTree::from_usize(dtb_va.addr()) }.unwrap();
+        assert_eq!(dt.size(), dtb.range.size());
+
+        let root = dt.root().unwrap();
+        let compatible = dt.property(&root, "compatible").unwrap();
+        assert!(!dt.property_value_as_str(&compatible).unwrap().is_empty());
+
+        // The memory we're using is all in the DTB's memory nodes
+        let memory = memory_ranges(&dt);
+        assert!(memory.iter().next().is_some(), "no memory nodes");
+        for region in kmem::PHYS_MAP.iter().filter(|r| r.name == "memory") {
+            let range = &region.range;
+            assert!(
+                memory.iter().any(|m| m.start() <= range.start() && range.end() <= m.end()),
+                "memory {range} isn't in the DTB"
+            );
+        }
+
+        // And the uart the console uses
+        let uart = devcons::uart_reg(&dt);
+        assert!(uart.len.is_some_and(|len| len > 0), "uart has no regs");
+        assert!(dt.find_by_path("/chosen").is_some());
+    }
+}
+
+ktest! {
+    fn alarms_fire_in_deadline_order() {
+        // Order each alarm fired in, set by its callback
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        static ORDER: [AtomicUsize; 3] = [const { AtomicUsize::new(usize::MAX) }; 3];
+        fn fired(i: usize) {
+            ORDER[i].store(NEXT.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        let start = port::time::now();
+        let deadlines = [30, 10, 20].map(|ms| Deadline::at(start + ms * 1_000_000));
+        let callbacks: [fn(_); 3] = [|_| fired(0), |_| fired(1), |_| fired(2)];
+        let mut handles = Vec::new();
+        for (deadline, callback) in deadlines.into_iter().zip(callbacks) {
+            handles.push(time::set_alarm(deadline, callback).expect("couldn't set alarm"));
+        }
+        let cancelled = time::set_alarm(Deadline::at(start + 15_000_000), |_| {
+            panic!("cancelled alarm fired")
+        })
+        .unwrap();
+        assert!(time::cancel_alarm(cancelled));
+
+        let timeout = Deadline::after(Duration::from_secs(1));
+        while NEXT.load(Ordering::Relaxed) < 3 {
+            assert!(!timeout.expired(), "only {} alarms fired", NEXT.load(Ordering::Relaxed));
+            core::hint::spin_loop();
+        }
+        let order = ORDER.each_ref().map(|o| o.load(Ordering::Relaxed));
+        assert_eq!(order, [2, 0, 1]);
+        for deadline in deadlines {
+            assert!(deadline.expired(), "fired early");
+        }
+        assert!(!time::cancel_alarm(handles[0]));
+    }
+}
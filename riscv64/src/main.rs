@@ -1,37 +1,222 @@
 #![feature(alloc_error_handler)]
-#![feature(sync_unsafe_cell)]
 #![cfg_attr(not(any(test)), no_std)]
 #![cfg_attr(not(test), no_main)]
 #![allow(clippy::upper_case_acronyms)]
 #![forbid(unsafe_op_in_unsafe_fn)]
 
 mod allocator;
+mod dmap;
+mod hart;
+mod interrupts;
+mod kmem;
+#[cfg(feature = "ktest")]
+mod ktests;
+mod pagealloc;
+mod param;
 mod platform;
 mod runtime;
 mod sbi;
+mod testdev;
+mod time;
+mod trap;
 mod uart16550;
+mod vm;
 
-use port::println;
+extern crate alloc;
 
+use alloc::vec;
+use alloc::vec::Vec;
+use port::{print, println};
+
+use crate::kmem::{kernel_sections, total_kernel_range};
 use crate::platform::{devcons, platform_init};
 use port::fdt::DeviceTree;
+use port::initrd::Initrd;
+use port::mem::{PhysRange, PhysRangeSet};
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("l.S"));
 
+/// Return the ranges of physical memory described by the device tree.
+fn memory_ranges(dt: &DeviceTree) -> PhysRangeSet {
+    let mut memory = PhysRangeSet::new();
+    for range in dt
+        .find_device_type("memory")
+        .flat_map(|memory| dt.property_translated_reg_iter(memory).flat_map(|r| r.regblock()))
+        .map(|memory| PhysRange::from(&memory))
+    {
+        if memory.add(&range).is_err() {
+            println!("error:too many memory ranges, ignoring {range}");
+        }
+    }
+    memory
+}
+
+/// Return true if every hart has the Svpbmt extension, according to its
+/// riscv,isa string, so memory types can be set in the page tables.
+fn harts_have_svpbmt(dt: &DeviceTree) -> bool {
+    let mut cpus = dt.find_device_type("cpu").peekable();
+    cpus.peek().is_some()
+        && cpus.all(|cpu| {
+            dt.property(&cpu, "riscv,isa")
+                .and_then(|isa| dt.property_value_as_str(&isa))
+                .is_some_and(|isa| isa.split('_').any(|ext| ext == "svpbmt"))
+        })
+}
+
+/// Return the ranges of physical memory that mustn't be handed out by the page
+/// allocator: the kernel image, the DTB, the initrd, and anything the device
+/// tree lists under /reserved-memory, such as the SBI firmware.  All but the
+/// kernel image, which kmem records, are recorded in the physical memory map.
+fn reserved_ranges(dt: &DeviceTree, dtb_range: &PhysRange) -> PhysRangeSet {
+    let mut reserved = PhysRangeSet::new();
+    let mut reserve = |range: PhysRange| {
+        if reserved.add(&range).is_err() {
+            panic!("error:too many reserved ranges, can't reserve {range}");
+        }
+    };
+
+    reserve(total_kernel_range());
+    reserve(dtb_range.clone());
+    kmem::record_phys_range("dtb", dtb_range.clone(), None);
+    if let Some(initrd) = dt.initrd_range() {
+        reserve(initrd.clone());
+        kmem::record_phys_range("initrd", initrd, None);
+    }
+
+    if let Some(resmem) = dt.find_by_path("/reserved-memory") {
+        for node in dt.children(&resmem) {
+            for regblock in dt.property_translated_reg_iter(node).flat_map(|r| r.regblock()) {
+                reserve(PhysRange::from(&regblock));
+                kmem::record_phys_range("reserved-memory", PhysRange::from(&regblock), None);
+            }
+        }
+    }
+    reserved
+}
+
+/// Set the initrd at `range`, which `reserved_ranges` kept from the page
+/// allocator and `dmap::init` mapped read-only, as port's initrd, so it's
+/// kept until the files are served.  Its entries are logged.
+fn init_initrd(range: PhysRange) {
+    let va = match dmap::phys_to_dmap(range.start()) {
+        Ok(va) => va,
+        Err(err) => {
+            println!("error:main:init_initrd:initrd isn't in RAM:{range}: {err}");
+            return;
+        }
+    };
+    // Safety: the pages are reserved, so nothing else uses them, and they're
+    // mapped read-only in the direct map for as long as the kernel runs.
+    let bytes = unsafe { core::slice::from_raw_parts(va.addr() as *const u8, range.size()) };
+    match Initrd::new(range.clone(), bytes) {
+        Ok(initrd) => {
+            print!("{}", initrd.listing());
+            port::initrd::init(initrd);
+        }
+        Err(err) => println!("error:main:init_initrd:invalid initrd:{range} err:{err}"),
+    }
+}
+
+/// Allocate more than the static heap holds, so the heap has to grow with
+/// pages from the page allocator, and check the memory can be used.
+fn test_heap_growth() {
+    let blocks: Vec<Vec<u8>> = (0..6).map(|i| vec![i; 1024 * 1024]).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(block.iter().all(|&b| b == i as u8), "heap block {i} corrupted");
+    }
+    drop(blocks);
+    if let Some(stats) = allocator::try_stats() {
+        println!("Heap: {stats}");
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn main9(hartid: usize, dtb_ptr: usize) -> ! {
+    hart::init(hartid);
+    port::devcons::set_early_putb(sbi::early_putb);
+    interrupts::init();
     let dt = unsafe { DeviceTree::from_usize(dtb_ptr).unwrap() };
     crate::devcons::init(&dt);
     platform_init();
+    trap::init();
+    time::init(&dt);
 
     println!();
     println!("r9 from the Internet");
     println!("Domain0 Boot HART = {hartid}");
     println!("DTB found at: {dtb_ptr:#x}");
 
-    #[cfg(not(test))]
+    println!("Binary sections:");
+    for section in kernel_sections() {
+        println!("  {}:\t{} {}", section.name, section.range, section.flags);
+    }
+    if let Err(err) = kmem::init_layout() {
+        panic!("error:main9:invalid kernel address space layout: {err}");
+    }
+    kmem::record_kernel_phys_ranges();
+
+    let memory = memory_ranges(&dt);
+    println!("Physical Memory:");
+    for range in memory.iter() {
+        println!("  {range}");
+        kmem::record_phys_range("memory", range.clone(), None);
+    }
+
+    let dtb_range = PhysRange::with_len(dtb_ptr as u64, dt.size());
+    let reserved = reserved_ranges(&dt, &dtb_range);
+    match pagealloc::init_from(&memory, reserved.as_slice()) {
+        Ok(summary) => println!(
+            "Page allocator: total pages: {} reserved pages: {} free pages: {}",
+            summary.total_pages, summary.reserved_pages, summary.free_pages
+        ),
+        Err(err) => panic!("error:Couldn't initialise page allocator: err: {}", err),
+    }
+
+    // Map the kernel image where it runs, the console registers (and the test
+    // device when testing), and all of RAM in the direct map, then turn on
+    // translation
+    let mut mmio = PhysRangeSet::<2>::new();
+    mmio.add(&PhysRange::from(&devcons::uart_reg(&dt))).unwrap();
+    if cfg!(feature = "qemu_test") {
+        if let Some(test_reg) = testdev::init(&dt) {
+            mmio.add(&test_reg).unwrap();
+        }
+    }
+    if harts_have_svpbmt(&dt) {
+        println!("Svpbmt: memory types set in page tables");
+        vm::enable_svpbmt();
+    }
+    let mut kernel_pt = match vm::init_kernel_page_tables(mmio.as_slice()) {
+        Ok(kernel_pt) => kernel_pt,
+        Err(err) => panic!("error:Couldn't set up kernel page tables: err: {}", err),
+    };
+    let initrd = dt.initrd_range();
+    if let Err(err) = dmap::init(&mut kernel_pt, &memory, initrd.as_slice()) {
+        panic!("error:Couldn't set up direct map: err: {}", err);
+    }
+    unsafe { vm::switch(&kernel_pt) };
+    println!("Switched to kernel page tables, satp: {:#x}", kernel_pt.satp());
+    if let Err(err) = pagealloc::direct_map_ready() {
+        panic!("error:Couldn't reach page allocator through direct map: err: {}", err);
+    }
+    allocator::init();
+    if let Some(initrd) = initrd {
+        init_initrd(initrd);
+    }
+    test_heap_growth();
+    kmem::report_memory_map();
+    kmem::report_memory_usage();
+
+    #[cfg(all(feature = "ktest", not(test)))]
+    port::ktest::run(port::ktest::registered());
+
+    // Booting this far is the test, as failures above panic
+    if cfg!(feature = "qemu_test") {
+        // List what's still allocated, to spot leaks in debug builds
+        allocator::dump_leaks();
+        port::qemu::exit_qemu(true);
+    }
+
     sbi::shutdown();
-    #[cfg(test)]
-    loop {}
 }
@@ -0,0 +1,179 @@
+/// This module acts as an interface between the portable allocator and the
+/// arch-specific use of it.
+///
+/// Unlike aarch64, the kernel runs with translation off until its page tables
+/// are built, so all physical memory is reachable from the start and the
+/// allocator can be initialised in one step, with `init_from`.  Once
+/// translation is on, the allocator's state is reached through the direct map
+/// instead, after `direct_map_ready`.
+use crate::dmap;
+use crate::kmem;
+use port::buddyalloc::BuddyPageAlloc;
+use port::mem::MemError;
+use port::mem::PhysAddr;
+use port::mem::PhysRange;
+use port::mem::PhysRangeSet;
+use port::memaccount::{MemAccounts, MemCategory};
+use port::pagealloc::{PageAlloc, PageAllocStats, PageAllocSummary, PageMapper};
+use port::regionalloc::RegionPageAlloc;
+use port::{
+    mcslock::{Lock, LockNode},
+    mem::PAGE_SIZE_4K,
+};
+
+#[cfg(not(test))]
+use port::println;
+
+/// Each buddy allocator covers 2GiB of physical memory.
+type BackendPageAlloc = BuddyPageAlloc<16, PAGE_SIZE_4K>;
+
+/// Physical memory may be made up of several banks with holes between them,
+/// so memory is managed as up to 4 regions of 2GiB.
+const NUM_REGIONS: usize = 4;
+type PageAllocImpl = RegionPageAlloc<BackendPageAlloc, NUM_REGIONS>;
+
+/// Pages allocated for each category, updated as pages are allocated and
+/// freed with a category.  Pages allocated without one are counted as
+/// Unknown.
+pub static MEM_ACCOUNTS: MemAccounts = MemAccounts::new(PAGE_SIZE_4K);
+
+/// Set up page allocator assuming everything is allocated.
+static PAGE_ALLOC: Lock<PageAllocImpl> = Lock::new(
+    "page_alloc",
+    RegionPageAlloc::new(
+        [const { BackendPageAlloc::new_all_allocated(PAGE_SIZE_4K) }; NUM_REGIONS],
+    ),
+);
+
+/// Reaches physical pages at their physical address, while translation is off.
+struct PhysMapper;
+
+unsafe impl PageMapper for PhysMapper {
+    fn page_ptr(&self, pa: PhysAddr, _page_size: usize) -> Option<*mut u8> {
+        Some(pa.addr() as *mut u8)
+    }
+}
+
+/// Reaches physical pages through the direct map, once translation is on.
+struct DmapMapper;
+
+unsafe impl PageMapper for DmapMapper {
+    fn page_ptr(&self, pa: PhysAddr, _page_size: usize) -> Option<*mut u8> {
+        dmap::phys_to_dmap(pa).ok().map(|va| va.addr() as *mut u8)
+    }
+}
+
+/// Make all physical memory available for allocation, except the reserved
+/// ranges, such as the kernel image, DTB and firmware.  Must be called while
+/// translation is off, as the allocator's state is carved out of memory and
+/// reached at its physical address.
+pub fn init_from(
+    memory: &PhysRangeSet,
+    reserved: &[PhysRange],
+) -> Result<PageAllocSummary, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    let summary = page_alloc.init_from(memory, reserved, &PhysMapper)?;
+    page_alloc.for_each_metadata_range(|range| {
+        kmem::record_phys_range("pagealloc metadata", range.clone(), None);
+    });
+    Ok(summary)
+}
+
+/// Called once translation is on, so that the allocator's state is reached
+/// through the direct map rather than at its physical address.
+pub fn direct_map_ready() -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    lock.remap_metadata(&DmapMapper)
+}
+
+/// Return the page allocator statistics if the allocator isn't locked.  For
+/// use where we can't risk blocking, e.g. when panicking.
+pub fn try_stats() -> Option<PageAllocStats> {
+    let node = LockNode::new();
+    PAGE_ALLOC.try_lock(&node).map(|lock| lock.stats())
+}
+
+/// Try to allocate a physical page.  Note that this is NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_physpage() -> Result<PhysAddr, MemError> {
+    allocate_physpage_for(MemCategory::Unknown)
+}
+
+/// Try to allocate a physical page, counted as used for `category`.  Note
+/// that this is NOT mapped.
+pub fn allocate_physpage_for(category: MemCategory) -> Result<PhysAddr, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.allocate().inspect(|_| MEM_ACCOUNTS.alloc(category, 1)).inspect_err(|err| {
+        println!("error:pagealloc:allocate_physpage:failed to allocate: {}", err);
+    })
+}
+
+/// Return a physical page to the allocator.  The page must not be mapped.
+#[allow(dead_code)]
+pub fn free_physpage(pa: PhysAddr) -> Result<(), MemError> {
+    free_physpage_for(pa, MemCategory::Unknown)
+}
+
+/// Return a physical page allocated for `category` to the allocator.  The
+/// page must not be mapped.
+pub fn free_physpage_for(pa: PhysAddr, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.free(pa).inspect(|_| MEM_ACCOUNTS.free(category, 1)).inspect_err(|err| {
+        println!("error:pagealloc:free_physpage:failed to free pa:{:?}: {}", pa, err);
+    })
+}
+
+/// Try to allocate `page_count` physically contiguous pages.  Note that these
+/// are NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_contiguous_physpages(page_count: usize) -> Result<PhysRange, MemError> {
+    allocate_contiguous_physpages_for(page_count, MemCategory::Unknown)
+}
+
+/// Try to allocate `page_count` physically contiguous pages, counted as used
+/// for `category`.  Note that these are NOT mapped.
+pub fn allocate_contiguous_physpages_for(
+    page_count: usize,
+    category: MemCategory,
+) -> Result<PhysRange, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc
+        .allocate_contiguous(page_count)
+        .inspect(|_| MEM_ACCOUNTS.alloc(category, page_count))
+        .inspect_err(|err| {
+            println!(
+                "error:pagealloc:allocate_contiguous_physpages:failed to allocate {} pages: {}",
+                page_count, err
+            );
+        })
+}
+
+/// Return a range of physical pages to the allocator, e.g. as allocated by
+/// `allocate_contiguous_physpages`.  The pages must not be mapped.
+#[allow(dead_code)]
+pub fn free_physpages(range: &PhysRange) -> Result<(), MemError> {
+    free_physpages_for(range, MemCategory::Unknown)
+}
+
+/// Return a range of physical pages allocated for `category` to the
+/// allocator.  The pages must not be mapped.
+pub fn free_physpages_for(range: &PhysRange, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc
+        .free_range(range)
+        .inspect(|_| MEM_ACCOUNTS.free(category, range.size().div_ceil(PAGE_SIZE_4K)))
+        .inspect_err(|err| {
+            println!("error:pagealloc:free_physpages:failed to free range:{}: {}", range, err);
+        })
+}
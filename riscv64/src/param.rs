@@ -0,0 +1,19 @@
+use port::layout::{Region, RegionKind};
+use port::mem::PAGE_SIZE_1G;
+
+// Layout of the kernel virtual address space, checked at boot by
+// port::layout::check.  The kernel image runs where it's loaded, in the lower
+// half, so only the upper half is laid out here.
+//
+// The direct map of physical RAM, which covers physical addresses up to its
+// size.  This is the bottom of the upper half of the Sv39 address space.
+pub const DIRECT_MAP: Region =
+    Region::new(RegionKind::DirectMap, 0xffff_ffc0_0000_0000, 1 << 37, PAGE_SIZE_1G);
+
+pub const LAYOUT: [Region; 1] = [DIRECT_MAP];
+
+// Number of harts with per-core state.  Only hart 0 runs the kernel.
+pub const MAX_CORES: usize = 1;
+
+// Maximum number of alarms pending on each core
+pub const MAX_ALARMS: usize = 16;
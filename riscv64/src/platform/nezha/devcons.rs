@@ -1,27 +1,32 @@
 // Racy to start.
 
-use core::mem::MaybeUninit;
-
 use crate::uart16550::Uart16550;
-use port::{devcons::Console, fdt::DeviceTree};
+use port::{
+    devcons::Console,
+    fdt::{DeviceTree, RegBlock},
+    oncelock::OnceLock,
+};
 
-pub fn init(dt: &DeviceTree) {
-    let uart0_reg = dt
-        .find_compatible("uart0")
-        .next()
-        .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-        .and_then(|reg| reg.regblock())
-        .unwrap();
+static UART: OnceLock<Uart16550> = OnceLock::new();
 
-    Console::new(|| {
-        let mut uart = Uart16550::new(uart0_reg);
-        uart.init(115_200);
+/// Return the registers of the console uart.
+pub fn uart_reg(dt: &DeviceTree) -> RegBlock {
+    dt.find_compatible("uart0")
+        .next()
+        .and_then(|uart| dt.translated_regions(uart).next())
+        .and_then(Result::ok)
+        .map(|region| region.reg())
+        .unwrap()
+}
 
-        static mut UART: MaybeUninit<Uart16550> = MaybeUninit::uninit();
+pub fn init(dt: &DeviceTree) {
+    let uart0_reg = uart_reg(dt);
 
-        unsafe {
-            UART.write(uart);
-            UART.assume_init_mut()
-        }
+    Console::new(|| {
+        UART.get_or_init(|| {
+            let mut uart = Uart16550::new(uart0_reg);
+            uart.init(115_200);
+            uart
+        })
     });
 }
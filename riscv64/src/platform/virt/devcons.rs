@@ -1,29 +1,32 @@
 // Racy to start.
 
-use core::cell::SyncUnsafeCell;
-use core::mem::MaybeUninit;
-
 use crate::uart16550::Uart16550;
-use port::{devcons::Console, fdt::DeviceTree};
+use port::{
+    devcons::Console,
+    fdt::{DeviceTree, RegBlock},
+    oncelock::OnceLock,
+};
 
-pub fn init(dt: &DeviceTree) {
-    let ns16550a_reg = dt
-        .find_compatible("ns16550a")
+static UART: OnceLock<Uart16550> = OnceLock::new();
+
+/// Return the registers of the console uart.
+pub fn uart_reg(dt: &DeviceTree) -> RegBlock {
+    dt.find_compatible("ns16550a")
         .next()
-        .and_then(|uart| dt.property_translated_reg_iter(uart).next())
-        .and_then(|reg| reg.regblock())
-        .unwrap();
+        .and_then(|uart| dt.translated_regions(uart).next())
+        .and_then(Result::ok)
+        .map(|region| region.reg())
+        .unwrap()
+}
 
-    Console::new(|| {
-        let mut uart = Uart16550::new(ns16550a_reg);
-        uart.init(115_200);
+pub fn init(dt: &DeviceTree) {
+    let ns16550a_reg = uart_reg(dt);
 
-        static CONS: SyncUnsafeCell<MaybeUninit<Uart16550>> =
-            SyncUnsafeCell::new(MaybeUninit::uninit());
-        unsafe {
-            let cons = &mut *CONS.get();
-            cons.write(uart);
-            cons.assume_init_mut()
-        }
+    Console::new(|| {
+        UART.get_or_init(|| {
+            let mut uart = Uart16550::new(ns16550a_reg);
+            uart.init(115_200);
+            uart
+        })
     });
 }
@@ -6,18 +6,40 @@ use alloc::alloc::Layout;
 use core::arch::asm;
 use core::panic::PanicInfo;
 
-use port::{print, println};
+use port::backtrace::{Backtrace, FrameLayout};
+use port::lockdebug::HeldLocks;
+use port::mem::{VirtAddr, VirtRange};
+use port::{early_print, early_println, print, println};
 
 #[unsafe(no_mangle)]
 extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    print!("Panic: ");
-    if let Some(p) = info.location() {
-        println!("line {}, file {}: {}", p.line(), p.file(), info.message());
+    if !port::devcons::console_ready() || port::devcons::locked_here() {
+        // Too early for the console, or the panic is from inside a print, so
+        // use SBI, after anything printed before the console came up
+        port::devcons::dump_early_output();
+        early_println!("Panic: {info}");
+        early_print!("{HeldLocks}");
     } else {
-        println!("no information available.");
+        print!("Panic: ");
+        if let Some(p) = info.location() {
+            println!("line {}, file {}: {}", p.line(), p.file(), info.message());
+        } else {
+            println!("no information available.");
+        }
+        port::ktest::report_panic();
+        print_backtrace(frame_pointer());
+        print!("{HeldLocks}");
+        match crate::allocator::try_stats() {
+            Some(stats) => println!("heap: {}", stats),
+            None => println!("heap: stats unavailable, allocator locked"),
+        }
+        crate::kmem::report_memory_map();
+    }
+    if cfg!(feature = "qemu_test") {
+        port::qemu::exit_qemu(false);
     }
     abort();
 }
@@ -31,7 +53,40 @@ extern "C" fn abort() -> ! {
     }
 }
 
+/// Return the frame pointer of the caller's frame.
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mv {fp}, s0", fp = out(reg) fp) };
+    fp
+}
+
+/// Print the return addresses in the chain of frame records from the frame
+/// pointer `fp`, which must be on the boot stack, the only kernel stack, as
+/// the records are only followed while they're on it.
+fn print_backtrace(fp: usize) {
+    // The kernel runs at its load address, so the stack's physical
+    // addresses are also its virtual addresses
+    let stack = crate::kmem::boot_stack_range();
+    let start = VirtAddr::new(stack.start().addr() as usize);
+    let stack = VirtRange::with_len(start, stack.size());
+    if stack.start().addr() <= fp && fp <= stack.end().addr() {
+        // Safety: the boot stack is in the bss, which is mapped.
+        print!("{}", unsafe { Backtrace::new(fp, stack, FrameLayout::RISCV64) });
+    } else {
+        println!("backtrace: frame pointer {fp:#x} not on the boot stack");
+    }
+}
+
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    panic!("oom");
+fn oom(layout: Layout) -> ! {
+    // Show where memory has gone, unless printing is what ran out
+    if port::devcons::console_ready() && !port::devcons::locked_here() {
+        crate::kmem::report_memory_usage();
+    }
+    let (size, align) = (layout.size(), layout.align());
+    match crate::allocator::try_stats() {
+        Some(stats) => panic!("oom: allocating {size} bytes, align {align}, heap: {stats}"),
+        None => panic!("oom: allocating {size} bytes, align {align}, heap locked"),
+    }
 }
@@ -1,6 +1,8 @@
 //! SBI interface.
 //!
 //! Chapter 5: Legacy Extensions
+//! Chapter 6: Timer Extension
+//! Chapter 10: System Reset Extension
 
 #![allow(dead_code)]
 
@@ -14,6 +16,14 @@ const _SBI_REMOTE_SFENCE_VMA: usize = 6;
 const _SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+const SBI_EXT_TIME: usize = 0x5449_4d45;
+const SBI_TIME_SET_TIMER: usize = 0;
+
+const SBI_EXT_SRST: usize = 0x5352_5354;
+const SBI_SRST_SYSTEM_RESET: usize = 0;
+const SBI_RESET_TYPE_SHUTDOWN: usize = 0;
+const SBI_RESET_REASON_NONE: usize = 0;
+
 #[cfg(target_arch = "riscv64")]
 fn sbi_call_legacy(eid: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let ret;
@@ -34,10 +44,38 @@ fn sbi_call_legacy(_eid: usize, _arg0: usize, _arg1: usize, _arg2: usize) -> usi
     0
 }
 
+/// Call function `fid` of extension `eid`, returning the error and value.
+#[cfg(target_arch = "riscv64")]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize) -> (isize, usize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x16") fid,
+            in("x17") eid
+        );
+    }
+    (error, value)
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn sbi_call(_eid: usize, _fid: usize, _arg0: usize, _arg1: usize) -> (isize, usize) {
+    (0, 0)
+}
+
 pub fn _set_timer(timer: usize) {
     sbi_call_legacy(SBI_SET_TIMER, timer, 0, 0);
 }
 
+/// Interrupt this hart, with the supervisor timer interrupt, once the time
+/// CSR reaches `stime_value`, replacing any value set before.  Until then,
+/// the interrupt isn't pending.
+pub fn set_timer(stime_value: u64) {
+    sbi_call(SBI_EXT_TIME, SBI_TIME_SET_TIMER, stime_value as usize, 0);
+}
+
 #[deprecated = "expected to be deprecated; no replacement"]
 pub fn _consputb(c: u8) {
     sbi_call_legacy(SBI_CONSOLE_PUTCHAR, c as usize, 0, 0);
@@ -48,9 +86,20 @@ pub fn _consgetb() -> u8 {
     sbi_call_legacy(SBI_CONSOLE_GETCHAR, 0, 0, 0).try_into().unwrap()
 }
 
+/// Write a byte with the legacy SBI console, for `port::early_println!`.
+pub fn early_putb(b: u8) {
+    #[allow(deprecated)]
+    _consputb(b);
+}
+
 pub fn shutdown() -> ! {
-    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    sbi_call(SBI_EXT_SRST, SBI_SRST_SYSTEM_RESET, SBI_RESET_TYPE_SHUTDOWN, SBI_RESET_REASON_NONE);
     loop {
-        unsafe { core::arch::asm!("wfi") }
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            core::arch::asm!("wfi")
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        core::hint::spin_loop();
     }
 }
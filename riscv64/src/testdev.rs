@@ -0,0 +1,40 @@
+/// testdev drives the test device on QEMU's virt machine, which stops QEMU
+/// with an exit status when written, for automated tests.  The kernel page
+/// tables map its registers at their physical address, as for the console.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use port::fdt::DeviceTree;
+use port::mem::PhysRange;
+use port::println;
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+/// Address of the test device register, or 0 if there isn't one.
+static TEST_REG: AtomicUsize = AtomicUsize::new(0);
+
+/// Find the test device, and use it for port::qemu::exit_qemu.  Return the
+/// range of its registers, which must be mapped.
+pub fn init(dt: &DeviceTree) -> Option<PhysRange> {
+    let Some(reg) = dt
+        .find_compatible("sifive,test0")
+        .filter(|node| dt.is_enabled(node))
+        .flat_map(|node| dt.translated_regions(node).next())
+        .find_map(|region| region.ok().map(|region| region.reg()))
+    else {
+        println!("warning:testdev:init:no sifive,test0 device, can't exit qemu");
+        return None;
+    };
+    TEST_REG.store(reg.addr as usize, Ordering::Release);
+    port::qemu::set_exit_fn(exit);
+    Some(PhysRange::from(&reg))
+}
+
+/// Stop QEMU with exit code 0 on success, or 1 otherwise.
+fn exit(success: bool) -> ! {
+    let value = if success { FINISHER_PASS } else { (1 << 16) | FINISHER_FAIL };
+    let reg = TEST_REG.load(Ordering::Acquire) as *mut u32;
+    unsafe { reg.write_volatile(value) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
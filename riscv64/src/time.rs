@@ -0,0 +1,89 @@
+/// time sets port::time's clock to the time CSR, which counts at the
+/// timebase-frequency given in /cpus of the device tree, and is the same on
+/// every hart.
+///
+/// Alarms use the SBI timer, which raises the supervisor timer interrupt
+/// once the time CSR reaches the value set, until it's set again.
+use crate::param::{MAX_ALARMS, MAX_CORES};
+use crate::sbi;
+use port::alarm::{AlarmError, AlarmFn, AlarmHandle, AlarmTimer, Alarms};
+use port::fdt::DeviceTree;
+use port::println;
+use port::time::{self, Deadline};
+
+/// sie.STIE, which enables the supervisor timer interrupt.
+#[cfg(not(test))]
+const SIE_STIE: usize = 1 << 5;
+
+static ALARMS: Alarms<MAX_CORES, MAX_ALARMS> = Alarms::new();
+
+static SBI_TIMER_ALARMS: AlarmTimer = AlarmTimer { arm: arm_alarm, disarm: disarm_alarm };
+
+/// Use the time CSR for port::time, if the device tree gives its frequency,
+/// and the SBI timer for alarms.  The trap entry must be set up, to take the
+/// timer's interrupt.
+pub fn init(dt: &DeviceTree) {
+    match timebase_frequency(dt) {
+        Some(freq) if freq > 0 => {
+            time::set_clock(read_time, freq);
+            ALARMS.set_timer(&SBI_TIMER_ALARMS);
+        }
+        _ => println!("error:time:init:no timebase-frequency in /cpus, so no clock"),
+    }
+}
+
+/// Interrupt once the time CSR reaches the deadline.
+fn arm_alarm(deadline: Deadline) {
+    sbi::set_timer(time::nanos_to_ticks(deadline.nanos(), time::freq_hz()));
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("csrs sie, {stie}", stie = in(reg) SIE_STIE);
+    }
+}
+
+/// Stop the timer interrupting.  Its interrupt may still be pending, but it's
+/// masked until the timer is armed again, which moves the compare value on.
+fn disarm_alarm() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("csrc sie, {stie}", stie = in(reg) SIE_STIE);
+    }
+}
+
+/// Fire the alarms that are due, from the supervisor timer interrupt.
+pub fn alarm_interrupt() {
+    ALARMS.fire();
+}
+
+/// Call `callback` from this hart's timer interrupt, with interrupts
+/// disabled, once `deadline` has passed.
+#[allow(dead_code)]
+pub fn set_alarm(deadline: Deadline, callback: AlarmFn) -> Result<AlarmHandle, AlarmError> {
+    ALARMS.set_alarm(deadline, callback)
+}
+
+/// Cancel the alarm with `handle`, returning true if it hadn't yet fired.
+#[allow(dead_code)]
+pub fn cancel_alarm(handle: AlarmHandle) -> bool {
+    ALARMS.cancel(handle)
+}
+
+/// Return the timebase-frequency of /cpus, which is one cell, or two for a
+/// frequency of 4GHz or more.
+fn timebase_frequency(dt: &DeviceTree) -> Option<u64> {
+    let cpus = dt.find_by_path("/cpus")?;
+    let prop = dt.property(&cpus, "timebase-frequency")?;
+    Some(dt.property_value_as_u32_iter(&prop).fold(0, |freq, cell| (freq << 32) | cell as u64))
+}
+
+/// Return the time CSR.
+fn read_time() -> u64 {
+    #[cfg(not(test))]
+    unsafe {
+        let time: u64;
+        core::arch::asm!("rdtime {time}", time = out(reg) time);
+        time
+    }
+    #[cfg(test)]
+    0
+}
@@ -0,0 +1,43 @@
+/// trap takes the traps the kernel runs into, through the entry in trap.S.
+/// The supervisor timer interrupt fires the hart's alarms.  The kernel doesn't
+/// expect any other trap, so anything else panics.
+use crate::time;
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("trap.S"));
+
+/// scause's interrupt bit, set if the trap is an interrupt.
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+/// scause of the supervisor timer interrupt.
+const SCAUSE_SUPERVISOR_TIMER: usize = SCAUSE_INTERRUPT | 5;
+
+#[cfg(not(test))]
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Set up the trap entry, then enable interrupts on this hart.  Each kind of
+/// interrupt is enabled in sie only once something handles it, so none are
+/// taken yet.
+pub fn init() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "csrw sie, zero",
+            "la {tmp}, trapentry",
+            "csrw stvec, {tmp}",
+            "csrsi sstatus, {sie}",
+            tmp = out(reg) _,
+            sie = const SSTATUS_SIE,
+        );
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn trap_handler(scause: usize, sepc: usize, stval: usize) {
+    match scause {
+        SCAUSE_SUPERVISOR_TIMER => time::alarm_interrupt(),
+        _ => panic!(
+            "error:trap:trap_handler:unexpected trap. scause:{scause:#x} sepc:{sepc:#x} stval:{stval:#x}"
+        ),
+    }
+}
@@ -0,0 +1,741 @@
+/// Sv39 page table implementation for riscv64.  Virtual addresses are 39 bits,
+/// sign extended to 64, and are translated through three levels of tables of
+/// 512 entries, mapping 4KiB pages, 2MiB megapages or 1GiB gigapages.
+///
+/// Until the kernel page tables are switched to, translation is off, so tables
+/// are reached at their physical addresses.  After that, they're reached
+/// through the direct map.
+use crate::{dmap, kmem::kernel_sections, pagealloc};
+use core::fmt;
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
+use port::{
+    mem::{
+        MapFlags, MapFlagsError, MemError, MemType, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K,
+        PhysAddr, PhysRange, VirtAddr,
+    },
+    memaccount::MemCategory,
+    tableframes::TableFrameSource,
+};
+
+#[cfg(not(test))]
+use port::println;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+}
+
+impl PageSize {
+    pub const fn size(&self) -> usize {
+        match self {
+            PageSize::Page4K => PAGE_SIZE_4K,
+            PageSize::Page2M => PAGE_SIZE_2M,
+            PageSize::Page1G => PAGE_SIZE_1G,
+        }
+    }
+}
+
+/// Levels of the hierarchy, numbered as in the privileged spec, so the root
+/// table is at level 2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Level2,
+    Level1,
+    Level0,
+}
+
+impl Level {
+    pub fn next(&self) -> Option<Level> {
+        match self {
+            Level::Level2 => Some(Level::Level1),
+            Level::Level1 => Some(Level::Level0),
+            Level::Level0 => None,
+        }
+    }
+
+    /// Size of the page mapped by a leaf entry at this level.
+    pub fn page_size(&self) -> PageSize {
+        match self {
+            Level::Level2 => PageSize::Page1G,
+            Level::Level1 => PageSize::Page2M,
+            Level::Level0 => PageSize::Page4K,
+        }
+    }
+
+    /// Level of the leaf entries mapping pages of `page_size`.
+    pub fn for_page_size(page_size: PageSize) -> Level {
+        match page_size {
+            PageSize::Page1G => Level::Level2,
+            PageSize::Page2M => Level::Level1,
+            PageSize::Page4K => Level::Level0,
+        }
+    }
+}
+
+/// Return the index into the table at `level` for `va`.
+pub fn va_index(va: VirtAddr, level: Level) -> usize {
+    let shift = match level {
+        Level::Level2 => 30,
+        Level::Level1 => 21,
+        Level::Level0 => 12,
+    };
+    (va.addr() >> shift) & 0x1ff
+}
+
+/// Sv39 addresses must have bits 63..39 all equal to bit 38.
+fn is_canonical(va: VirtAddr) -> bool {
+    let high_bits = (va.addr() as isize) >> 38;
+    high_bits == 0 || high_bits == -1
+}
+
+// Page table entry bits
+const PTE_V: u64 = 1 << 0; // Valid
+const PTE_R: u64 = 1 << 1; // Readable
+const PTE_W: u64 = 1 << 2; // Writable
+const PTE_X: u64 = 1 << 3; // Executable
+const PTE_U: u64 = 1 << 4; // Accessible from user mode
+const PTE_G: u64 = 1 << 5; // Global, i.e. in all address spaces
+const PTE_A: u64 = 1 << 6; // Accessed
+const PTE_D: u64 = 1 << 7; // Dirty
+const PTE_PBMT_NC: u64 = 1 << 61; // Svpbmt: non-cacheable, idempotent memory
+const PTE_PBMT_IO: u64 = 2 << 61; // Svpbmt: non-cacheable, strongly ordered I/O
+const PTE_PBMT_MASK: u64 = 3 << 61;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+// satp mode selecting Sv39 translation
+const SATP_MODE_SV39: u64 = 8 << 60;
+
+/// Set once the harts are known to have Svpbmt, so memory types can be
+/// encoded in leaf entries.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Encode memory types in the leaf entries made from now on.  Only for harts
+/// with the Svpbmt extension, since the bits are reserved without it.
+pub fn enable_svpbmt() {
+    SVPBMT.store(true, Ordering::Relaxed);
+}
+
+/// An Sv39 page table entry.  This is documented in the 'Sv39: Page-Based
+/// 39-bit Virtual-Memory System' section of the RISC-V privileged spec.
+#[derive(Copy, Clone, PartialEq)]
+#[repr(transparent)]
+pub struct Entry(pub u64);
+
+impl Entry {
+    pub const fn empty() -> Entry {
+        Entry(0)
+    }
+
+    /// Return a leaf entry mapping `pa` with `flags`.  The accessed and dirty
+    /// bits are set up front, since the hardware may fault rather than set
+    /// them.  Kernel mappings are global.  Memory types are only encoded once
+    /// `enable_svpbmt` has been called, and otherwise come from the
+    /// platform's physical memory attributes.
+    pub fn leaf(pa: PhysAddr, flags: MapFlags) -> Result<Entry, MapFlagsError> {
+        Self::leaf_with_pbmt(pa, flags, SVPBMT.load(Ordering::Relaxed))
+    }
+
+    /// As `leaf`, encoding the memory type with Svpbmt if `pbmt` is set.
+    /// Svpbmt has no relaxed device type, so both device types are I/O.
+    fn leaf_with_pbmt(pa: PhysAddr, flags: MapFlags, pbmt: bool) -> Result<Entry, MapFlagsError> {
+        let flags = flags.validate()?;
+        let mut bits = PTE_V | PTE_R | PTE_A;
+        if flags.contains(MapFlags::WRITE) {
+            bits |= PTE_W | PTE_D;
+        }
+        if flags.contains(MapFlags::EXECUTE) {
+            bits |= PTE_X;
+        }
+        bits |= if flags.contains(MapFlags::USER) { PTE_U } else { PTE_G };
+        if pbmt {
+            bits |= match flags.mem_type() {
+                MemType::NormalCached => 0,
+                MemType::NormalNonCacheable => PTE_PBMT_NC,
+                MemType::DeviceStrict | MemType::DeviceRelaxed => PTE_PBMT_IO,
+            };
+        }
+        Ok(Entry(bits | Self::ppn_bits(pa)))
+    }
+
+    /// Return a non-leaf entry referencing the table at `pa`.
+    pub fn table(pa: PhysAddr) -> Entry {
+        Entry(PTE_V | Self::ppn_bits(pa))
+    }
+
+    fn ppn_bits(pa: PhysAddr) -> u64 {
+        ((pa.addr() >> 12) & PTE_PPN_MASK) << PTE_PPN_SHIFT
+    }
+
+    pub fn valid(self) -> bool {
+        self.0 & PTE_V != 0
+    }
+
+    /// Leaf entries have at least one of the R, W and X bits set.
+    pub fn is_leaf(self) -> bool {
+        self.0 & (PTE_R | PTE_W | PTE_X) != 0
+    }
+
+    pub fn pa(self) -> PhysAddr {
+        PhysAddr::new(((self.0 >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12)
+    }
+
+    /// Return the flags of a leaf entry.
+    pub fn flags(self) -> MapFlags {
+        let mut flags = MapFlags::empty();
+        for (bit, flag) in [
+            (PTE_R, MapFlags::READ),
+            (PTE_W, MapFlags::WRITE),
+            (PTE_X, MapFlags::EXECUTE),
+            (PTE_U, MapFlags::USER),
+        ] {
+            if self.0 & bit != 0 {
+                flags |= flag;
+            }
+        }
+        match self.0 & PTE_PBMT_MASK {
+            PTE_PBMT_NC => flags | MapFlags::NON_CACHEABLE,
+            PTE_PBMT_IO => flags | MapFlags::DEVICE,
+            _ => flags,
+        }
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Entry({:#018x} pa:{:?} {})", self.0, self.pa(), self.flags())
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [Entry; 512],
+}
+
+/// Where the frames for tables come from, and the TLB maintenance needed as
+/// entries change.  The table manipulation is written against this so that it
+/// can be exercised with tables in ordinary memory.
+trait TableWalker {
+    type Frames: TableFrameSource;
+
+    /// Return the source of frames for new tables, and to free emptied ones
+    /// to.
+    fn frames(&mut self) -> &mut Self::Frames;
+
+    /// Invalidate any cached translations for `va`, whose entry has just been
+    /// cleared.
+    fn invalidate(&mut self, va: VirtAddr);
+
+    /// Return a pointer through which the table at `pa` can be accessed.
+    fn table(&mut self, pa: PhysAddr) -> *mut Table {
+        self.frames().frame_ptr(pa) as *mut Table
+    }
+}
+
+/// Set once the kernel page tables are active, after which tables are reached
+/// through the direct map.
+static TRANSLATION_ON: AtomicBool = AtomicBool::new(false);
+
+/// Frames for the kernel's tables, from the page allocator.
+struct KernelFrames;
+
+impl TableFrameSource for KernelFrames {
+    fn alloc_frame(&mut self) -> Result<PhysAddr, MemError> {
+        pagealloc::allocate_physpage_for(MemCategory::PageTables)
+    }
+
+    fn free_frame(&mut self, pa: PhysAddr) {
+        // Errors are logged by the allocator, and there's nothing more to do
+        let _ = pagealloc::free_physpage_for(pa, MemCategory::PageTables);
+    }
+
+    fn frame_ptr(&mut self, pa: PhysAddr) -> *mut u8 {
+        if TRANSLATION_ON.load(Ordering::Acquire) {
+            let va = dmap::phys_to_dmap(pa)
+                .unwrap_or_else(|err| panic!("page table {pa} isn't in RAM: {err}"));
+            va.addr() as *mut u8
+        } else {
+            pa.addr() as *mut u8
+        }
+    }
+}
+
+/// Reaches the kernel's tables, with tables allocated from, and emptied ones
+/// returned to, the page allocator.
+struct KernelTableWalker {
+    frames: KernelFrames,
+}
+
+impl KernelTableWalker {
+    fn new() -> Self {
+        Self { frames: KernelFrames }
+    }
+}
+
+impl TableWalker for KernelTableWalker {
+    type Frames = KernelFrames;
+
+    fn frames(&mut self) -> &mut KernelFrames {
+        &mut self.frames
+    }
+
+    fn invalidate(&mut self, va: VirtAddr) {
+        unsafe { invalidate_tlb_entry(va) };
+    }
+}
+
+/// Allocate a table and clear it.
+fn alloc_cleared_table(walker: &mut impl TableWalker) -> Result<PhysAddr, MemError> {
+    let pa = walker.frames().alloc_frame()?;
+    let table = unsafe { &mut *walker.table(pa) };
+    for entry in table.entries.iter_mut() {
+        unsafe { write_volatile(entry, Entry::empty()) };
+    }
+    Ok(pa)
+}
+
+/// A translation found by walking the page tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub pa: PhysAddr,        // Physical address the virtual address maps to
+    pub page_size: PageSize, // Size of the page containing it
+    pub entry: Entry,        // Leaf entry, holding the permissions
+}
+
+/// Walk the tables from `root` to the leaf entry translating `va`.  Returns
+/// None if there's no valid entry for `va` at any level.
+fn walk(root: PhysAddr, va: VirtAddr, walker: &mut impl TableWalker) -> Option<Mapping> {
+    if !is_canonical(va) {
+        return None;
+    }
+    let mut table = unsafe { &*walker.table(root) };
+    let mut level = Level::Level2;
+    loop {
+        let entry = table.entries[va_index(va, level)];
+        if !entry.valid() {
+            return None;
+        }
+        if entry.is_leaf() {
+            let page_size = level.page_size();
+            let offset = va.addr() & (page_size.size() - 1);
+            let pa = PhysAddr::new(entry.pa().addr() + offset as u64);
+            return Some(Mapping { pa, page_size, entry });
+        }
+        table = unsafe { &*walker.table(entry.pa()) };
+        level = level.next()?;
+    }
+}
+
+/// Map the page of `page_size` at `va` to `pa`, creating any intermediate
+/// tables needed.  Fails if any of the page is already mapped.
+fn map_page(
+    root: PhysAddr,
+    va: VirtAddr,
+    pa: PhysAddr,
+    page_size: PageSize,
+    flags: MapFlags,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    if !is_canonical(va) {
+        println!("error:vm:map_page:va isn't canonical. va:{va:?}");
+        return Err(MemError::NotCanonical { va });
+    }
+    let size = page_size.size();
+    if va.addr() & (size - 1) != 0 || pa.addr() & (size as u64 - 1) != 0 {
+        println!("error:vm:map_page:not on page boundary. va:{va:?} pa:{pa:?} size:{size:#x}");
+        let addr = if va.addr() & (size - 1) != 0 { va.addr() as u64 } else { pa.addr() };
+        return Err(MemError::Unaligned { addr, required: size });
+    }
+    let new_entry = Entry::leaf(pa, flags)?;
+
+    let leaf_level = Level::for_page_size(page_size);
+    let mut table = unsafe { &mut *walker.table(root) };
+    let mut level = Level::Level2;
+    while level != leaf_level {
+        let entry = &mut table.entries[va_index(va, level)];
+        if !entry.valid() {
+            let table_pa = alloc_cleared_table(walker)?;
+            unsafe { write_volatile(entry, Entry::table(table_pa)) };
+        } else if entry.is_leaf() {
+            return Err(MemError::AlreadyMapped { va });
+        }
+        table = unsafe { &mut *walker.table(entry.pa()) };
+        level = level.next().unwrap();
+    }
+
+    let entry = &mut table.entries[va_index(va, level)];
+    if entry.valid() {
+        return Err(MemError::AlreadyMapped { va });
+    }
+    unsafe { write_volatile(entry, new_entry) };
+    Ok(())
+}
+
+/// Clear the leaf entry mapping `va`, freeing any tables left empty, other
+/// than the root.  Returns the mapping that was removed.
+fn unmap_page(
+    root: PhysAddr,
+    va: VirtAddr,
+    walker: &mut impl TableWalker,
+) -> Result<Mapping, MemError> {
+    let Some(mapping) = walk(root, va, walker) else {
+        return Err(MemError::NotMapped { va });
+    };
+
+    // Record the tables on the way down, so emptied ones can be freed
+    let mut tables = [root; 3];
+    let mut depth = 0;
+    let mut level = Level::Level2;
+    loop {
+        let table = unsafe { &mut *walker.table(tables[depth]) };
+        let entry = table.entries[va_index(va, level)];
+        if entry.is_leaf() {
+            break;
+        }
+        depth += 1;
+        tables[depth] = entry.pa();
+        level = level.next().unwrap();
+    }
+
+    // Clear the leaf, then each table's entry in its parent while it's empty
+    loop {
+        let table = unsafe { &mut *walker.table(tables[depth]) };
+        unsafe { write_volatile(&mut table.entries[va_index(va, level)], Entry::empty()) };
+        if depth == 0 || table.entries.iter().any(|e| e.valid()) {
+            break;
+        }
+        walker.frames().free_frame(tables[depth]);
+        depth -= 1;
+        level = match level {
+            Level::Level0 => Level::Level1,
+            _ => Level::Level2,
+        };
+    }
+    walker.invalidate(va);
+    Ok(mapping)
+}
+
+/// Map `phys` at `va`, using the largest pages the alignment of each part
+/// allows.  `va` and `phys` must be page aligned.
+fn map_range(
+    root: PhysAddr,
+    va: VirtAddr,
+    phys: &PhysRange,
+    flags: MapFlags,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    let mut va = va.addr();
+    let mut pa = phys.start().addr();
+    while pa < phys.end().addr() {
+        let remaining = (phys.end().addr() - pa) as usize;
+        let page_size = [PageSize::Page1G, PageSize::Page2M, PageSize::Page4K]
+            .into_iter()
+            .find(|ps| {
+                let size = ps.size();
+                (va | pa as usize) & (size - 1) == 0 && remaining >= size
+            })
+            .unwrap_or(PageSize::Page4K);
+        map_page(root, VirtAddr::new(va), PhysAddr::new(pa), page_size, flags, walker)?;
+        va += page_size.size();
+        pa += page_size.size() as u64;
+    }
+    Ok(())
+}
+
+/// The root of a hierarchy of Sv39 page tables.
+pub struct PageTable {
+    root: PhysAddr,
+}
+
+impl PageTable {
+    /// Create a page table with nothing mapped.
+    pub fn new() -> Result<PageTable, MemError> {
+        Ok(PageTable { root: alloc_cleared_table(&mut KernelTableWalker::new())? })
+    }
+
+    /// Return the value of satp selecting this table, with Sv39 translation
+    /// and ASID 0.
+    pub fn satp(&self) -> u64 {
+        SATP_MODE_SV39 | (self.root.addr() >> 12)
+    }
+
+    /// Map the page of `page_size` at `va` to `pa`.
+    #[allow(dead_code)]
+    pub fn map(
+        &mut self,
+        va: VirtAddr,
+        pa: PhysAddr,
+        page_size: PageSize,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        map_page(self.root, va, pa, page_size, flags, &mut KernelTableWalker::new())
+    }
+
+    /// Map `phys` at `va` using the largest pages possible.
+    pub fn map_range(
+        &mut self,
+        va: VirtAddr,
+        phys: &PhysRange,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        map_range(self.root, va, phys, flags, &mut KernelTableWalker::new())
+    }
+
+    /// Unmap the page containing `va`, returning how it was mapped.
+    #[allow(dead_code)]
+    pub fn unmap(&mut self, va: VirtAddr) -> Result<Mapping, MemError> {
+        unmap_page(self.root, va, &mut KernelTableWalker::new())
+    }
+
+    /// Return the mapping for `va`, or None if it isn't mapped.
+    #[allow(dead_code)]
+    pub fn lookup(&self, va: VirtAddr) -> Option<Mapping> {
+        walk(self.root, va, &mut KernelTableWalker::new())
+    }
+}
+
+/// Build the kernel page tables: the kernel image mapped at its physical
+/// address with the flags of each section, as it's linked to run there, and
+/// `mmio`, e.g. the console registers, mapped likewise.  The direct map is
+/// added by `dmap::init`.
+pub fn init_kernel_page_tables(mmio: &[PhysRange]) -> Result<PageTable, MemError> {
+    let mut kernel_pt = PageTable::new()?;
+    for section in kernel_sections() {
+        let va = VirtAddr::new(section.range.start().addr() as usize);
+        kernel_pt.map_range(va, &section.range, section.flags)?;
+    }
+    for range in mmio {
+        let range = range.round_out(PAGE_SIZE_4K as u64);
+        let va = VirtAddr::new(range.start().addr() as usize);
+        kernel_pt.map_range(va, &range, MapFlags::RW | MapFlags::DEVICE)?;
+    }
+    Ok(kernel_pt)
+}
+
+/// Switch to `page_table`, which must map the kernel image and the direct map
+/// as `init_kernel_page_tables` and `dmap::init` do.
+#[allow(unused_variables)]
+pub unsafe fn switch(page_table: &PageTable) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "sfence.vma",          // ensure the table writes are visible to the walker
+            "csrw satp, {satp}",
+            "sfence.vma",          // flush translations cached from the old tables
+            satp = in(reg) page_table.satp());
+    }
+    TRANSLATION_ON.store(true, Ordering::Release);
+}
+
+/// Invalidate the TLB entries for the page containing `va`.
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_entry(va: VirtAddr) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("sfence.vma {va}, zero", va = in(reg) va.addr());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use port::tableframes::VecFrames;
+
+    /// Software walker over tables in hosted frames, allocated from
+    /// 0x8000_0000, recording the addresses invalidated.
+    struct TestWalker {
+        frames: VecFrames,
+        invalidated: Vec<VirtAddr>,
+    }
+
+    impl TestWalker {
+        fn new() -> Self {
+            Self { frames: VecFrames::new(PhysAddr::new(0x8000_0000)), invalidated: Vec::new() }
+        }
+
+        /// Return the table at `pa`.
+        fn table_at(&mut self, pa: PhysAddr) -> &'static Table {
+            unsafe { &*self.table(pa) }
+        }
+    }
+
+    impl TableWalker for TestWalker {
+        type Frames = VecFrames;
+
+        fn frames(&mut self) -> &mut VecFrames {
+            &mut self.frames
+        }
+
+        fn invalidate(&mut self, va: VirtAddr) {
+            self.invalidated.push(va);
+        }
+    }
+
+    #[test]
+    fn entry_bits() {
+        let pa = PhysAddr::new(0x8020_0000);
+        assert_eq!(Entry::leaf(pa, MapFlags::RX).unwrap().0, 0x2008_0000 | 0x6b);
+        assert_eq!(Entry::leaf(pa, MapFlags::RW).unwrap().0, 0x2008_0000 | 0xe7);
+        assert_eq!(Entry::leaf(pa, MapFlags::READ | MapFlags::USER).unwrap().0, 0x2008_0000 | 0x53);
+        assert_eq!(Entry::table(pa).0, 0x2008_0001);
+        assert!(matches!(Entry::leaf(pa, MapFlags::WRITE), Err(MapFlagsError::NotReadable)));
+
+        let entry = Entry::leaf(PhysAddr::new(0xff_ffff_f000), MapFlags::RW).unwrap();
+        assert_eq!(entry.pa(), PhysAddr::new(0xff_ffff_f000));
+        assert_eq!(entry.flags(), MapFlags::RW);
+        assert!(entry.is_leaf());
+        assert!(!Entry::table(pa).is_leaf());
+    }
+
+    #[test]
+    fn entry_mem_types() {
+        let pa = PhysAddr::new(0x8020_0000);
+        let leaf = |flags, pbmt| Entry::leaf_with_pbmt(pa, flags, pbmt).unwrap();
+        assert_eq!(leaf(MapFlags::RW | MapFlags::WRITE_COMBINING, true).0, 1 << 61 | 0x2008_00e7);
+        assert_eq!(leaf(MapFlags::RW | MapFlags::DEVICE, true).0, 2 << 61 | 0x2008_00e7);
+        for flags in
+            [MapFlags::RX, MapFlags::RW | MapFlags::NON_CACHEABLE, MapFlags::RW | MapFlags::DEVICE]
+        {
+            assert_eq!(leaf(flags, true).flags(), flags);
+        }
+
+        // Relaxed device memory is I/O, the nearest Svpbmt has, and without
+        // Svpbmt, no memory type is encoded
+        let relaxed = MapFlags::RW | MapFlags::DEVICE_RELAXED;
+        assert_eq!(leaf(relaxed, true).flags(), MapFlags::RW | MapFlags::DEVICE);
+        assert_eq!(leaf(MapFlags::RW | MapFlags::DEVICE, false).0, 0x2008_00e7);
+    }
+
+    #[test]
+    fn map_and_walk() {
+        let mut walker = TestWalker::new();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va = VirtAddr::new(0xffff_ffc0_0020_3000);
+        let pa = PhysAddr::new(0x8000_5000);
+        map_page(root, va, pa, PageSize::Page4K, MapFlags::RW, &mut walker).unwrap();
+
+        // Each level is indexed by 9 bits of the address
+        let l2 = walker.table_at(root);
+        assert_eq!(l2.entries.iter().filter(|e| e.valid()).count(), 1);
+        let l1 = walker.table_at(l2.entries[256].pa());
+        assert!(!l2.entries[256].is_leaf());
+        let l0 = walker.table_at(l1.entries[1].pa());
+        assert_eq!(l0.entries[3], Entry::leaf(pa, MapFlags::RW).unwrap());
+
+        let mapping = walk(root, va + 0x123, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x8000_5123));
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+        assert_eq!(walk(root, va + 0x1000, &mut walker), None);
+
+        // Addresses in the hole between the halves can't be mapped
+        let hole = VirtAddr::new(0x0000_0040_0000_0000);
+        assert_eq!(walk(root, hole, &mut walker), None);
+        assert_eq!(
+            map_page(root, hole, pa, PageSize::Page4K, MapFlags::RW, &mut walker),
+            Err(MemError::NotCanonical { va: hole })
+        );
+    }
+
+    #[test]
+    fn map_writes_exact_descriptors() {
+        let va = VirtAddr::new(0xffff_ffc0_4000_0000);
+        let pa = PhysAddr::new(0x1_4000_0000);
+        for (page_size, depth) in
+            [(PageSize::Page4K, 3), (PageSize::Page2M, 2), (PageSize::Page1G, 1)]
+        {
+            let mut walker = TestWalker::new();
+            let root = alloc_cleared_table(&mut walker).unwrap();
+            map_page(root, va, pa, page_size, MapFlags::RW, &mut walker).unwrap();
+
+            // Tables are taken in order after the root, and each points at
+            // the next
+            let mut table = walker.table_at(root);
+            let mut level = Level::Level2;
+            for next_pa in (1..depth).map(|i| 0x8000_0000 + i * 0x1000) {
+                let entry = table.entries[va_index(va, level)];
+                assert_eq!(entry.0, next_pa >> 2 | 0x1, "{page_size:?} {level:?}");
+                table = walker.table_at(PhysAddr::new(next_pa));
+                level = level.next().unwrap();
+            }
+            let leaf = table.entries[va_index(va, level)];
+            assert_eq!(leaf.0, pa.addr() >> 2 | 0xe7, "{page_size:?}");
+            assert_eq!(walker.frames.allocated(), depth as usize);
+        }
+    }
+
+    #[test]
+    fn map_rejects_overlaps() {
+        let mut walker = TestWalker::new();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va = VirtAddr::new(0x8020_0000);
+        let pa = PhysAddr::new(0x8020_0000);
+        map_page(root, va, pa, PageSize::Page2M, MapFlags::RX, &mut walker).unwrap();
+        let mapping = walk(root, va + 0x1_2345, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x8021_2345));
+        assert_eq!(mapping.page_size, PageSize::Page2M);
+
+        for (va, page_size) in [(0x8020_0000, PageSize::Page2M), (0x8030_0000, PageSize::Page4K)] {
+            let va = VirtAddr::new(va);
+            assert_eq!(
+                map_page(root, va, pa, page_size, MapFlags::RW, &mut walker),
+                Err(MemError::AlreadyMapped { va })
+            );
+        }
+        let unaligned = Err(MemError::Unaligned { addr: 0x8020_1000, required: PAGE_SIZE_2M });
+        assert_eq!(
+            map_page(root, va + 0x1000, pa, PageSize::Page2M, MapFlags::RW, &mut walker),
+            unaligned
+        );
+    }
+
+    #[test]
+    fn map_range_uses_largest_pages() {
+        let mut walker = TestWalker::new();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let phys = PhysRange::with_end(0x7fff_f000, 0xc020_1000);
+        let va = VirtAddr::new(0xffff_ffc0_7fff_f000);
+        map_range(root, va, &phys, MapFlags::RW, &mut walker).unwrap();
+        for (offset, page_size) in [
+            (0, PageSize::Page4K),
+            (0x1000, PageSize::Page1G),
+            (0x4000_1000, PageSize::Page2M),
+            (0x4020_1000, PageSize::Page4K),
+        ] {
+            let mapping = walk(root, va + offset, &mut walker).unwrap();
+            assert_eq!(mapping.pa, PhysAddr::new(0x7fff_f000 + offset as u64));
+            assert_eq!(mapping.page_size, page_size);
+        }
+        assert_eq!(walk(root, va + 0x4020_2000, &mut walker), None);
+    }
+
+    #[test]
+    fn unmap_frees_empty_tables() {
+        let mut walker = TestWalker::new();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va1 = VirtAddr::new(0x1000);
+        let va2 = VirtAddr::new(0x2000);
+        map_page(root, va1, PhysAddr::new(0x1000), PageSize::Page4K, MapFlags::RW, &mut walker)
+            .unwrap();
+        map_page(root, va2, PhysAddr::new(0x2000), PageSize::Page4K, MapFlags::RW, &mut walker)
+            .unwrap();
+
+        let mapping = unmap_page(root, va1, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x1000));
+        assert_eq!(walker.frames.allocated(), 3);
+        assert_eq!(unmap_page(root, va1, &mut walker), Err(MemError::NotMapped { va: va1 }));
+
+        // Unmapping the last page frees its tables, but not the root
+        unmap_page(root, va2, &mut walker).unwrap();
+        assert_eq!(walker.frames.allocated(), 1);
+        assert_eq!(walker.invalidated, [va1, va2]);
+        assert!(walker.table_at(root).entries.iter().all(|e| !e.valid()));
+    }
+}
@@ -1,17 +1,35 @@
+/// The kernel heap is a static arena.  Unlike aarch64 and riscv64, there's no
+/// direct map to reach pages from the page allocator, so it doesn't grow.
 #[cfg(not(test))]
 mod global {
     use core::mem;
-    use core::sync::atomic::AtomicPtr;
     use port::allocator::{
-        Block, BumpAlloc, QuickFit, global::GlobalHeap, global::GlobalQuickAlloc,
+        Block, BumpAlloc, HeapStats, QuickFit, global::GlobalHeap, global::GlobalQuickAlloc,
     };
 
+    static mut HEAP: GlobalHeap = GlobalHeap::new();
+
     #[global_allocator]
-    static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(AtomicPtr::new({
-        static mut HEAP: GlobalHeap = GlobalHeap::new();
-        static mut ALLOC: QuickFit = QuickFit::new(BumpAlloc::new(unsafe {
+    static GLOBAL_ALLOCATOR: GlobalQuickAlloc =
+        GlobalQuickAlloc::new(QuickFit::new(BumpAlloc::new(unsafe {
             Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
-        }));
-        &raw mut ALLOC
-    }));
+        })));
+
+    /// Returns heap statistics, or None if the heap is locked.
+    pub fn try_stats() -> Option<HeapStats> {
+        GLOBAL_ALLOCATOR.try_stats()
+    }
+
+    /// Prints the live allocations, in debug builds, e.g. to find leaks
+    /// after a test.
+    pub fn dump_leaks() {
+        GLOBAL_ALLOCATOR.dump_leaks();
+    }
 }
+
+#[cfg(not(test))]
+pub use global::{dump_leaks, try_stats};
+
+/// Host tests use std's allocator, so there are no allocations to list.
+#[cfg(test)]
+pub fn dump_leaks() {}
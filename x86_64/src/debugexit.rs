@@ -0,0 +1,23 @@
+/// debugexit drives QEMU's isa-debug-exit device, which stops QEMU when
+/// written, for automated tests.  QEMU must be run with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`, and exits with status
+/// (value << 1) | 1, so never 0.
+use crate::pio;
+
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+const DEBUG_EXIT_PASS: u32 = 0x10; // QEMU exits with status 33
+const DEBUG_EXIT_FAIL: u32 = 0x11; // QEMU exits with status 35
+
+/// Stop QEMU, with exit status 33 on success, or 35 otherwise.
+fn exit(success: bool) -> ! {
+    let value = if success { DEBUG_EXIT_PASS } else { DEBUG_EXIT_FAIL };
+    unsafe { pio::outl(DEBUG_EXIT_PORT, value) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Use isa-debug-exit for port::qemu::exit_qemu.
+pub fn init() {
+    port::qemu::set_exit_fn(exit);
+}
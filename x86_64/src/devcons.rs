@@ -1,21 +1,15 @@
 // Racy to start.
 
-use core::cell::SyncUnsafeCell;
-use port::devcons::{Console, Uart};
+use crate::uart16550::{COM1, Uart16550};
+use port::devcons::Console;
+use port::println;
 
-struct Uart16550 {
-    port: u16,
-}
-
-impl Uart for Uart16550 {
-    fn putb(&self, b: u8) {
-        crate::uart16550::putb(self.port, b);
-    }
-}
+static UART: Uart16550 = Uart16550::new(COM1);
 
 pub fn init() {
     Console::new(|| {
-        static CONS: SyncUnsafeCell<Uart16550> = SyncUnsafeCell::new(Uart16550 { port: 0x3f8 });
-        unsafe { &mut *CONS.get() }
+        UART.init(115_200);
+        &UART
     });
+    println!("Console: 16550 at {COM1:#x}");
 }
@@ -0,0 +1,39 @@
+/// Masks interrupts on the current core with the interrupt flag.
+use port::interrupts::{self, Interrupts};
+
+const RFLAGS_IF: usize = 1 << 9;
+
+struct InterruptFlag;
+
+impl Interrupts for InterruptFlag {
+    fn disable() -> usize {
+        #[cfg(not(test))]
+        unsafe {
+            let rflags: usize;
+            core::arch::asm!(
+                "pushfq",
+                "popq {rflags}",
+                "cli",
+                rflags = out(reg) rflags,
+                options(att_syntax),
+            );
+            rflags & RFLAGS_IF
+        }
+        #[cfg(test)]
+        0
+    }
+
+    fn restore(state: usize) {
+        if state & RFLAGS_IF != 0 {
+            #[cfg(not(test))]
+            unsafe {
+                core::arch::asm!("sti");
+            }
+        }
+    }
+}
+
+/// Use the interrupt flag for port::interrupts.
+pub fn init() {
+    interrupts::init::<InterruptFlag>();
+}
@@ -0,0 +1,184 @@
+use crate::param::{KERNEL_IMAGE, KZERO, LAYOUT};
+use port::layout::LayoutError;
+use port::mem::{MapFlags, PAGE_SIZE_4K, PhysAddr, PhysRange, VirtAddr, VirtRange};
+use port::memaccount::MemCategory;
+use port::physmap::{PhysMap, PhysRegion};
+
+#[cfg(not(test))]
+use port::{print, println};
+
+// These map to definitions in kernel.ld
+unsafe extern "C" {
+    static boottext: [u64; 0];
+    static esys: [u64; 0];
+    static text: [u64; 0];
+    static etext: [u64; 0];
+    static rodata: [u64; 0];
+    static erodata: [u64; 0];
+    static data: [u64; 0];
+    static edata: [u64; 0];
+    static bss: [u64; 0];
+    static end: [u64; 0];
+}
+
+fn boottext_addr() -> usize {
+    unsafe { boottext.as_ptr().addr() }
+}
+
+fn esys_addr() -> usize {
+    unsafe { esys.as_ptr().addr() }
+}
+
+fn text_addr() -> usize {
+    unsafe { text.as_ptr().addr() }
+}
+
+fn etext_addr() -> usize {
+    unsafe { etext.as_ptr().addr() }
+}
+
+fn rodata_addr() -> usize {
+    unsafe { rodata.as_ptr().addr() }
+}
+
+fn erodata_addr() -> usize {
+    unsafe { erodata.as_ptr().addr() }
+}
+
+fn data_addr() -> usize {
+    unsafe { data.as_ptr().addr() }
+}
+
+fn edata_addr() -> usize {
+    unsafe { edata.as_ptr().addr() }
+}
+
+fn bss_addr() -> usize {
+    unsafe { bss.as_ptr().addr() }
+}
+
+fn end_addr() -> usize {
+    unsafe { end.as_ptr().addr() }
+}
+
+fn kernel_range(start_va: usize, end_va: usize) -> PhysRange {
+    PhysRange::new(
+        from_virt_to_physaddr(VirtAddr::new(start_va)),
+        from_virt_to_physaddr(VirtAddr::new(end_va)),
+    )
+}
+
+/// The boot code, followed by the boot stack and early page tables set up by
+/// l.S, up to the start of text.
+pub fn boot_range() -> PhysRange {
+    kernel_range(boottext_addr(), esys_addr())
+}
+
+pub fn text_range() -> PhysRange {
+    kernel_range(text_addr(), etext_addr())
+}
+
+pub fn rodata_range() -> PhysRange {
+    kernel_range(rodata_addr(), erodata_addr())
+}
+
+pub fn data_range() -> PhysRange {
+    kernel_range(data_addr(), edata_addr())
+}
+
+pub fn bss_range() -> PhysRange {
+    kernel_range(bss_addr(), end_addr())
+}
+
+/// A section of the kernel image, and the flags it's mapped with.
+pub struct KernelSection {
+    pub name: &'static str,
+    pub range: PhysRange,
+    pub flags: MapFlags,
+}
+
+/// Return the sections of the kernel image, in address order.
+pub fn kernel_sections() -> [KernelSection; 5] {
+    [
+        // The boot code isn't needed once main9 is called, but the boot stack
+        // is still in use, so it's mapped as data
+        KernelSection { name: "boot", range: boot_range(), flags: MapFlags::RW },
+        KernelSection { name: "text", range: text_range(), flags: MapFlags::RX },
+        KernelSection { name: "rodata", range: rodata_range(), flags: MapFlags::READ },
+        KernelSection { name: "data", range: data_range(), flags: MapFlags::RW },
+        KernelSection { name: "bss", range: bss_range(), flags: MapFlags::RW },
+    ]
+}
+
+pub fn total_kernel_range() -> PhysRange {
+    kernel_range(boottext_addr(), end_addr())
+}
+
+/// Ranges of physical memory recorded as they're found or set up, for
+/// `report_memory_map`.
+pub static PHYS_MAP: PhysMap<48> = PhysMap::new();
+
+/// Record a range of physical memory in PHYS_MAP, with the flags it's mapped
+/// with, if it's relevant.
+pub fn record_phys_range(name: &'static str, range: PhysRange, flags: Option<MapFlags>) {
+    if let Err(err) = PHYS_MAP.record(PhysRegion { name, range, flags }) {
+        println!("error:kmem:record_phys_range:can't record range. name:{name} err:{err:?}");
+    }
+}
+
+/// Record the physical memory used by the kernel image sections.
+pub fn record_kernel_phys_ranges() {
+    for section in kernel_sections() {
+        record_phys_range(section.name, section.range, Some(section.flags));
+    }
+}
+
+/// Print the physical memory map, in address order, and the memory the page
+/// allocator has free, unless it's locked, then the kernel virtual memory
+/// layout.
+pub fn report_memory_map() {
+    let free = crate::pagealloc::try_stats().map(|stats| stats.free_pages * PAGE_SIZE_4K);
+    print!("{}", PHYS_MAP.report(free));
+    print!("{}", port::layout::report());
+}
+
+/// Check the kernel address space layout in param.rs, and that the kernel
+/// image is within its kernel image region, then register the layout with
+/// port.
+pub fn init_layout() -> Result<(), LayoutError> {
+    port::layout::check(&LAYOUT)?;
+    for section in kernel_sections() {
+        let virt = physrange_as_virtrange_offset_from_kzero(&section.range);
+        KERNEL_IMAGE.check_contains(section.name, &virt)?;
+    }
+    port::layout::set_layout(&LAYOUT);
+    Ok(())
+}
+
+/// Print the memory used for each category, including the kernel image,
+/// which isn't allocated from the page allocator, and the memory the page
+/// allocator has free.
+pub fn report_memory_usage() {
+    let free = crate::pagealloc::stats().free_pages * PAGE_SIZE_4K;
+    let statics = [(MemCategory::KernelImage, total_kernel_range().size())];
+    print!("{}", crate::pagealloc::MEM_ACCOUNTS.report(&statics, Some(free)));
+}
+
+/// Transform the physical address to a virtual address, under the assumption that
+/// the virtual address is the physical address offset from KZERO.
+pub const fn physaddr_as_ptr_mut_offset_from_kzero<T>(pa: PhysAddr) -> *mut T {
+    (pa.addr() as usize).wrapping_add(KZERO) as *mut T
+}
+
+/// Transform the physical range to a virtual range, under the assumption that
+/// the virtual range is the physical range offset from KZERO.
+pub fn physrange_as_virtrange_offset_from_kzero(range: &PhysRange) -> VirtRange {
+    let start = VirtAddr::new((range.start().addr() as usize).wrapping_add(KZERO));
+    VirtRange::with_len(start, range.size())
+}
+
+/// Given a virtual address in the KZERO mapping, return the physical address.
+pub fn from_virt_to_physaddr(va: VirtAddr) -> PhysAddr {
+    debug_assert!(va.addr() >= KZERO, "from_virt_to_physaddr: va {va:?} must be >= KZERO");
+    PhysAddr::new((va.addr() - KZERO) as u64)
+}
@@ -1,6 +1,5 @@
 #![feature(alloc_error_handler)]
 #![feature(naked_functions)]
-#![feature(sync_unsafe_cell)]
 #![cfg_attr(not(any(test)), no_std)]
 #![cfg_attr(not(test), no_main)]
 #![allow(clippy::upper_case_acronyms)]
@@ -8,22 +7,81 @@
 
 mod allocator;
 mod dat;
+mod debugexit;
 mod devcons;
+mod interrupts;
+mod kmem;
+mod multiboot;
+mod pagealloc;
+mod param;
 mod pio;
 mod proc;
+mod time;
 mod uart16550;
+mod vm;
 
+use kmem::{
+    kernel_sections, physaddr_as_ptr_mut_offset_from_kzero,
+    physrange_as_virtrange_offset_from_kzero, total_kernel_range,
+};
+use multiboot::MULTIBOOT_BOOTLOADER_MAGIC;
+use port::mem::{PhysAddr, PhysRange, PhysRangeSet};
 use proc::{Label, swtch};
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("l.S"), options(att_syntax));
 
+use param::MAX_CORES;
+use port::lockdebug::CoreHeld;
 use port::println;
 
+// The locks each core holds, for port::lockdebug
+static HELD_LOCKS: [CoreHeld; MAX_CORES] = [const { CoreHeld::new() }; MAX_CORES];
+
 static mut THRSTACK: [u64; 1024] = [0; 1024];
 static mut CTX: u64 = 0;
 static mut THR: u64 = 0;
 
+fn print_binary_sections() {
+    println!("Binary sections:");
+    for section in kernel_sections() {
+        let size = section.range.size();
+        println!("  {}:\t{} ({size:#x}) {}", section.name, section.range, section.flags);
+    }
+    let total = total_kernel_range();
+    println!("  total:\t{total} ({:#x})", total.size());
+}
+
+/// Return the ranges of physical memory that mustn't be handed out by the page
+/// allocator: the first MiB, which holds the BIOS data, the Multiboot
+/// information and the AP entry code, and the kernel image, which includes the
+/// boot stack and page tables.  The firmware's reservations are already left
+/// out of the memory map's available memory.
+fn reserved_ranges() -> PhysRangeSet {
+    let mut reserved = PhysRangeSet::new();
+    let mut reserve = |range: PhysRange| {
+        if reserved.add(&range).is_err() {
+            panic!("error:too many reserved ranges, can't reserve {range}");
+        }
+    };
+
+    reserve(PhysRange::with_end(0, 1 << 20));
+    kmem::record_phys_range("low memory", PhysRange::with_end(0, 1 << 20), None);
+    reserve(total_kernel_range());
+    reserved
+}
+
+fn print_memory_info() {
+    println!("Memory usage:");
+    let (used, total) = pagealloc::usage_bytes();
+    println!("  Used:\t\t{used:#016x}");
+    println!("  Total:\t{total:#016x}");
+    println!("  {}", pagealloc::stats());
+    pagealloc::for_each_region(|range, stats| println!("  Region {range}: {stats}"));
+    kmem::report_memory_map();
+    kmem::report_memory_usage();
+}
+
 fn jumpback() {
     println!("in a thread");
     unsafe {
@@ -34,10 +92,72 @@ fn jumpback() {
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn main9() {
+pub extern "C" fn main9(_mach: usize, magic: u32, info_pa: usize) {
+    port::lockdebug::set_held_stacks(&HELD_LOCKS);
+    interrupts::init();
     devcons::init();
+    if cfg!(feature = "qemu_test") {
+        debugexit::init();
+    }
     println!();
     println!("r9 from the Internet");
+    if magic != MULTIBOOT_BOOTLOADER_MAGIC {
+        panic!("error:not loaded by a Multiboot boot loader: magic: {magic:#x}");
+    }
+    time::init();
+
+    print_binary_sections();
+    if let Err(err) = kmem::init_layout() {
+        panic!("error:main9:invalid kernel address space layout: {err}");
+    }
+    kmem::record_kernel_phys_ranges();
+
+    // The boot page tables map the first 4GiB at KZERO, which covers the
+    // Multiboot information
+    let map = unsafe {
+        multiboot::memory_map(PhysAddr::new(info_pa as u64), |pa| {
+            physaddr_as_ptr_mut_offset_from_kzero::<u8>(pa)
+        })
+    };
+    let memory = &map.memory;
+    println!("Physical Memory:");
+    for range in memory.iter() {
+        println!("  {range}");
+        kmem::record_phys_range("memory", range.clone(), None);
+    }
+    for (label, range) in map.reservations() {
+        kmem::record_phys_range(label, range.clone(), None);
+    }
+
+    match pagealloc::init_from(memory, reserved_ranges().as_slice()) {
+        Ok(summary) => println!(
+            "Page allocator: total pages: {} reserved pages: {} free pages: {}",
+            summary.total_pages, summary.reserved_pages, summary.free_pages
+        ),
+        Err(err) => panic!("error:Couldn't initialise page allocator: err: {}", err),
+    }
+
+    // Replace the boot page tables with the kernel address space, mapping the
+    // kernel sections with their intended permissions and the rest of RAM at
+    // KZERO
+    let kernel_space = match vm::init_kernel_page_tables(memory) {
+        Ok(kernel_space) => kernel_space,
+        Err(err) => panic!("error:Couldn't set up kernel page tables: err: {}", err),
+    };
+    unsafe { kernel_space.activate() };
+    println!("Switched to kernel page tables, cr3: {}", kernel_space.root());
+
+    // Check the kernel image is mapped, at least as permissively as intended
+    for section in kernel_sections() {
+        kernel_space.assert_mapped(
+            &physrange_as_virtrange_offset_from_kzero(&section.range),
+            section.flags,
+        );
+    }
+    kernel_space.dump();
+
+    print_memory_info();
+
     println!("looping now");
     let mut ctx = Label::new();
     let mut thr = Label::new();
@@ -49,6 +169,13 @@ pub extern "C" fn main9() {
         swtch(&mut ctx, &mut thr);
     }
     println!("came out the other side of a context switch");
+
+    // Booting this far is the test, as failures above panic
+    if cfg!(feature = "qemu_test") {
+        // List what's still allocated, to spot leaks in debug builds
+        allocator::dump_leaks();
+        port::qemu::exit_qemu(true);
+    }
     #[allow(clippy::empty_loop)]
     loop {}
 }
@@ -0,0 +1,248 @@
+/// Parsing of the information passed by a Multiboot (version 1) boot loader,
+/// such as QEMU's -kernel loader.  Only the memory map is used so far.
+///
+/// The memory map is the firmware's (e820 on a PC), and on real machines its
+/// entries may be unsorted, overlap, or disagree, so nothing about their order
+/// is trusted: they're merged into range sets, and any available memory that
+/// a reservation overlaps is left out.
+use core::ptr::read_unaligned;
+use port::mem::{PhysAddr, PhysRange, PhysRangeSet};
+
+#[cfg(not(test))]
+use port::println;
+
+/// Value passed in eax by a Multiboot compliant boot loader.
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2bad_b002;
+
+const INFO_FLAG_MEMORY: u32 = 1 << 0; // mem_lower and mem_upper are valid
+const INFO_FLAG_MMAP: u32 = 1 << 6; // mmap_length and mmap_addr are valid
+const MMAP_TYPE_AVAILABLE: u32 = 1;
+const MMAP_TYPE_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_TYPE_ACPI_NVS: u32 = 4;
+const MMAP_TYPE_BAD: u32 = 5;
+
+/// Labels for the kinds of reserved memory, indexed as `MemoryMap::reserved`.
+const RESERVED_LABELS: [&str; 4] = ["acpi reclaimable", "acpi nvs", "bad memory", "reserved"];
+
+/// Return the index in `MemoryMap::reserved` for an entry of type `kind`.
+/// Unknown types are treated as reserved.
+fn reserved_index(kind: u32) -> usize {
+    match kind {
+        MMAP_TYPE_ACPI_RECLAIMABLE => 0,
+        MMAP_TYPE_ACPI_NVS => 1,
+        MMAP_TYPE_BAD => 2,
+        _ => 3,
+    }
+}
+
+/// The start of the Multiboot information structure, up to the memory map.
+#[repr(C)]
+struct MultibootInfo {
+    flags: u32,
+    mem_lower: u32, // KiB of memory from 0
+    mem_upper: u32, // KiB of memory from 1MiB
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32, // Size of the memory map in bytes
+    mmap_addr: u32,   // Physical address of the memory map
+}
+
+/// Physical memory as described by the Multiboot information.
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+    pub memory: PhysRangeSet,        // Available RAM, without any reservations
+    pub reserved: [PhysRangeSet; 4], // Reservations, labelled by RESERVED_LABELS
+}
+
+impl MemoryMap {
+    /// Iterate over the reserved ranges, by kind, with their labels.
+    pub fn reservations(&self) -> impl Iterator<Item = (&'static str, &PhysRange)> {
+        RESERVED_LABELS
+            .iter()
+            .zip(self.reserved.iter())
+            .flat_map(|(&label, ranges)| ranges.iter().map(move |range| (label, range)))
+    }
+}
+
+/// Return the memory map described by the Multiboot information at
+/// `info_pa`, reading physical memory through `phys_to_ptr`.  The memory map
+/// is used if there is one, otherwise the lower and upper memory sizes, which
+/// give no reservations.
+pub unsafe fn memory_map(
+    info_pa: PhysAddr,
+    phys_to_ptr: impl Fn(PhysAddr) -> *const u8,
+) -> MemoryMap {
+    let info = unsafe { read_unaligned(phys_to_ptr(info_pa) as *const MultibootInfo) };
+    let mut map = MemoryMap::default();
+    let add = |ranges: &mut PhysRangeSet, range: PhysRange| {
+        if ranges.add(&range).is_err() {
+            println!("error:multiboot:memory_map:too many ranges, ignoring {range}");
+        }
+    };
+
+    if info.flags & INFO_FLAG_MMAP != 0 {
+        // Each entry is preceded by its size, which doesn't include the size
+        // field itself, and entries aren't necessarily aligned
+        let mmap = phys_to_ptr(PhysAddr::new(info.mmap_addr as u64));
+        let mut offset = 0;
+        while offset < info.mmap_length as usize {
+            let entry = unsafe { mmap.add(offset) };
+            let size = unsafe { read_unaligned(entry as *const u32) };
+            let base = unsafe { read_unaligned(entry.add(4) as *const u64) };
+            let len = unsafe { read_unaligned(entry.add(12) as *const u64) };
+            let kind = unsafe { read_unaligned(entry.add(20) as *const u32) };
+            let range = PhysRange::with_end(base, base.saturating_add(len));
+            if kind == MMAP_TYPE_AVAILABLE {
+                add(&mut map.memory, range);
+            } else {
+                add(&mut map.reserved[reserved_index(kind)], range);
+            }
+            offset += size as usize + 4;
+        }
+    } else if info.flags & INFO_FLAG_MEMORY != 0 {
+        add(&mut map.memory, PhysRange::with_end(0, info.mem_lower as u64 * 1024));
+        let upper_start = 1 << 20;
+        let upper_end = upper_start + info.mem_upper as u64 * 1024;
+        add(&mut map.memory, PhysRange::with_end(upper_start, upper_end));
+    } else {
+        println!("error:multiboot:memory_map:no memory information. flags:{:#x}", info.flags);
+    }
+
+    // Reservations win over available memory they overlap
+    for (label, ranges) in RESERVED_LABELS.iter().zip(&map.reserved) {
+        for range in ranges.iter() {
+            if map.memory.remove(range).is_err() {
+                println!("error:multiboot:memory_map:can't exclude {label} {range}");
+            }
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn push_u64(buf: &mut Vec<u8>, v: u64) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Build Multiboot information at offset 0 of a buffer, with the memory
+    /// map, if any, following it.
+    fn info(flags: u32, mem_lower: u32, mem_upper: u32, mmap: &[(u64, u64, u32)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, flags);
+        push_u32(&mut buf, mem_lower);
+        push_u32(&mut buf, mem_upper);
+        buf.resize(44, 0);
+        push_u32(&mut buf, (mmap.len() * 24) as u32);
+        push_u32(&mut buf, 52);
+        for &(base, len, kind) in mmap {
+            push_u32(&mut buf, 20);
+            push_u64(&mut buf, base);
+            push_u64(&mut buf, len);
+            push_u32(&mut buf, kind);
+        }
+        buf
+    }
+
+    fn map(buf: &[u8]) -> MemoryMap {
+        unsafe { memory_map(PhysAddr::new(0), |pa| buf[pa.addr() as usize..].as_ptr()) }
+    }
+
+    fn ranges(buf: &[u8]) -> Vec<PhysRange> {
+        map(buf).memory.iter().cloned().collect()
+    }
+
+    #[test]
+    fn available_memory_from_mmap() {
+        let buf = info(
+            INFO_FLAG_MEMORY | INFO_FLAG_MMAP,
+            639,
+            130_048,
+            &[
+                (0, 0x9_fc00, MMAP_TYPE_AVAILABLE),
+                (0x9_fc00, 0x400, 2),
+                (0xf_0000, 0x1_0000, 2),
+                (0x10_0000, 0x7ee_0000, MMAP_TYPE_AVAILABLE),
+                (0xfffc_0000, 0x4_0000, 2),
+            ],
+        );
+        assert_eq!(
+            ranges(&buf),
+            [PhysRange::with_end(0, 0x9_fc00), PhysRange::with_end(0x10_0000, 0x7fe_0000)]
+        );
+    }
+
+    #[test]
+    fn available_memory_from_sizes() {
+        let buf = info(INFO_FLAG_MEMORY, 639, 130_048, &[]);
+        assert_eq!(
+            ranges(&buf),
+            [PhysRange::with_end(0, 0x9_fc00), PhysRange::with_end(0x10_0000, 0x800_0000)]
+        );
+    }
+
+    #[test]
+    fn reservations_from_mmap() {
+        let buf = info(
+            INFO_FLAG_MMAP,
+            0,
+            0,
+            &[
+                (0x10_0000, 0x7ee_0000, MMAP_TYPE_AVAILABLE),
+                (0x7fe_0000, 0x1_0000, MMAP_TYPE_ACPI_RECLAIMABLE),
+                (0x7ff_0000, 0x1_0000, MMAP_TYPE_ACPI_NVS),
+                (0xfffc_0000, 0x4_0000, 2),
+                (0xfeff_c000, 0x4000, 12),
+            ],
+        );
+        let reservations: Vec<_> =
+            map(&buf).reservations().map(|(label, range)| (label, range.clone())).collect();
+        assert_eq!(
+            reservations,
+            [
+                ("acpi reclaimable", PhysRange::with_end(0x7fe_0000, 0x7ff_0000)),
+                ("acpi nvs", PhysRange::with_end(0x7ff_0000, 0x800_0000)),
+                ("reserved", PhysRange::with_end(0xfeff_c000, 0xff00_0000)),
+                ("reserved", PhysRange::with_end(0xfffc_0000, 0x1_0000_0000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsorted_overlapping_mmap_is_normalised() {
+        // Available entries overlapping each other, out of order, with a
+        // reservation within one, and an empty entry
+        let buf = info(
+            INFO_FLAG_MMAP,
+            0,
+            0,
+            &[
+                (0x10_0000, 0x40_0000, MMAP_TYPE_AVAILABLE),
+                (0, 0x9_fc00, MMAP_TYPE_AVAILABLE),
+                (0x30_0000, 0x30_0000, MMAP_TYPE_AVAILABLE),
+                (0x20_0000, 0x1000, 2),
+                (0x9_f000, 0x1000, MMAP_TYPE_BAD),
+                (0x80_0000, 0, MMAP_TYPE_AVAILABLE),
+            ],
+        );
+        let map = map(&buf);
+        assert_eq!(
+            map.memory.as_slice(),
+            [
+                PhysRange::with_end(0, 0x9_f000),
+                PhysRange::with_end(0x10_0000, 0x20_0000),
+                PhysRange::with_end(0x20_1000, 0x60_0000),
+            ]
+        );
+        assert_eq!(map.reserved[2].as_slice(), [PhysRange::with_end(0x9_f000, 0xa_0000)]);
+    }
+}
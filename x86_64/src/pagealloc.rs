@@ -0,0 +1,133 @@
+/// This module acts as an interface between the portable allocator and the
+/// arch-specific use of it.
+///
+/// The boot page tables map the first 4GiB of physical memory at KZERO, so
+/// memory is reachable from the start and the allocator can be initialised in
+/// one step, with `init_from`.  The allocator's state is kept in that memory,
+/// which stays mapped at KZERO once the kernel page tables are in use.
+use crate::kmem::physaddr_as_ptr_mut_offset_from_kzero;
+use port::buddyalloc::BuddyPageAlloc;
+use port::mem::MemError;
+use port::mem::PhysAddr;
+use port::mem::PhysRange;
+use port::mem::PhysRangeSet;
+use port::memaccount::{MemAccounts, MemCategory};
+use port::pagealloc::{PageAlloc, PageAllocStats, PageAllocSummary, PageMapper};
+use port::regionalloc::RegionPageAlloc;
+use port::{
+    mcslock::{Lock, LockNode},
+    mem::PAGE_SIZE_4K,
+};
+
+#[cfg(not(test))]
+use port::println;
+
+/// Each buddy allocator covers 2GiB of physical memory.
+type BackendPageAlloc = BuddyPageAlloc<16, PAGE_SIZE_4K>;
+
+/// Physical memory may be made up of several banks with holes between them,
+/// so memory is managed as up to 4 regions of 2GiB.
+const NUM_REGIONS: usize = 4;
+type PageAllocImpl = RegionPageAlloc<BackendPageAlloc, NUM_REGIONS>;
+
+/// Pages allocated for each category, updated as pages are allocated and
+/// freed with a category.  Pages allocated without one are counted as
+/// Unknown.
+pub static MEM_ACCOUNTS: MemAccounts = MemAccounts::new(PAGE_SIZE_4K);
+
+/// Set up page allocator assuming everything is allocated.
+static PAGE_ALLOC: Lock<PageAllocImpl> = Lock::new(
+    "page_alloc",
+    RegionPageAlloc::new(
+        [const { BackendPageAlloc::new_all_allocated(PAGE_SIZE_4K) }; NUM_REGIONS],
+    ),
+);
+
+/// Memory mapped at KZERO by the boot page tables.
+const BOOT_MAPPED_END: u64 = 4 << 30;
+
+/// Reaches physical pages mapped at KZERO by the boot page tables.
+struct KzeroMapper;
+
+unsafe impl PageMapper for KzeroMapper {
+    fn page_ptr(&self, pa: PhysAddr, page_size: usize) -> Option<*mut u8> {
+        (pa.addr() + page_size as u64 <= BOOT_MAPPED_END)
+            .then(|| physaddr_as_ptr_mut_offset_from_kzero(pa))
+    }
+}
+
+/// Make all physical memory available for allocation, except the reserved
+/// ranges, such as the kernel image and low memory used by the firmware.
+pub fn init_from(
+    memory: &PhysRangeSet,
+    reserved: &[PhysRange],
+) -> Result<PageAllocSummary, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.init_from(memory, reserved, &KzeroMapper)
+}
+
+/// Try to allocate a physical page.  Note that this is NOT mapped.
+#[allow(dead_code)]
+pub fn allocate_physpage() -> Result<PhysAddr, MemError> {
+    allocate_physpage_for(MemCategory::Unknown)
+}
+
+/// Try to allocate a physical page, counted as used for `category`.  Note
+/// that this is NOT mapped.
+pub fn allocate_physpage_for(category: MemCategory) -> Result<PhysAddr, MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.allocate().inspect(|_| MEM_ACCOUNTS.alloc(category, 1)).inspect_err(|err| {
+        println!("error:pagealloc:allocate_physpage:failed to allocate: {}", err);
+    })
+}
+
+/// Return a physical page to the allocator.  The page must not be mapped.
+#[allow(dead_code)]
+pub fn free_physpage(pa: PhysAddr) -> Result<(), MemError> {
+    free_physpage_for(pa, MemCategory::Unknown)
+}
+
+/// Return a physical page allocated for `category` to the allocator.  The
+/// page must not be mapped.
+pub fn free_physpage_for(pa: PhysAddr, category: MemCategory) -> Result<(), MemError> {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.free(pa).inspect(|_| MEM_ACCOUNTS.free(category, 1)).inspect_err(|err| {
+        println!("error:pagealloc:free_physpage:failed to free pa:{:?}: {}", pa, err);
+    })
+}
+
+/// Return a tuple of (bytes used, total bytes available) based on the page allocator.
+pub fn usage_bytes() -> (usize, usize) {
+    let node = LockNode::new();
+    let mut lock = PAGE_ALLOC.lock(&node);
+    let page_alloc = &mut *lock;
+    page_alloc.usage_bytes()
+}
+
+/// Return the page allocator statistics.
+pub fn stats() -> PageAllocStats {
+    let node = LockNode::new();
+    let lock = PAGE_ALLOC.lock(&node);
+    lock.stats()
+}
+
+/// Return the page allocator statistics if the allocator isn't locked.  For
+/// use where we can't risk blocking, e.g. when panicking while allocating.
+pub fn try_stats() -> Option<PageAllocStats> {
+    let node = LockNode::new();
+    PAGE_ALLOC.try_lock(&node).map(|lock| lock.stats())
+}
+
+/// Call `f` with the physical range and statistics of each region of memory
+/// managed by the page allocator.
+pub fn for_each_region(f: impl FnMut(&PhysRange, PageAllocStats)) {
+    let node = LockNode::new();
+    let lock = PAGE_ALLOC.lock(&node);
+    lock.for_each_region(f)
+}
@@ -0,0 +1,16 @@
+use port::layout::{Region, RegionKind};
+use port::mem::PAGE_SIZE_1G;
+
+// This needs to match KZERO in l.S.  All of RAM is mapped at this offset.
+pub const KZERO: usize = 0xffff_8000_0000_0000;
+
+// Layout of the kernel virtual address space, checked at boot by
+// port::layout::check.  The kernel image is in the mapping of all of RAM at
+// KZERO, so it's the one region.
+pub const KERNEL_IMAGE: Region = Region::new(RegionKind::KernelImage, KZERO, 1 << 46, PAGE_SIZE_1G);
+
+pub const LAYOUT: [Region; 1] = [KERNEL_IMAGE];
+
+// Number of cores with per-core state.  Only the boot processor runs the
+// kernel.
+pub const MAX_CORES: usize = 1;
@@ -1,3 +1,7 @@
+//! Port I/O, for devices such as the 16550 uart, the PIT and the keyboard
+//! controller, which are accessed through the I/O address space rather than
+//! memory.
+
 #![allow(unused_variables, dead_code)]
 
 pub unsafe fn outb(port: u16, b: u8) {
@@ -20,3 +24,36 @@ pub unsafe fn outl(port: u16, l: u32) {
         core::arch::asm!("outl %eax, %dx", in("dx") port, in("ax") l, options(att_syntax));
     }
 }
+
+pub unsafe fn inb(port: u16) -> u8 {
+    #[cfg(not(test))]
+    unsafe {
+        let b: u8;
+        core::arch::asm!("inb %dx, %al", in("dx") port, out("al") b, options(att_syntax));
+        b
+    }
+    #[cfg(test)]
+    0
+}
+
+pub unsafe fn inw(port: u16) -> u16 {
+    #[cfg(not(test))]
+    unsafe {
+        let w: u16;
+        core::arch::asm!("inw %dx, %ax", in("dx") port, out("ax") w, options(att_syntax));
+        w
+    }
+    #[cfg(test)]
+    0
+}
+
+pub unsafe fn inl(port: u16) -> u32 {
+    #[cfg(not(test))]
+    unsafe {
+        let l: u32;
+        core::arch::asm!("inl %dx, %eax", in("dx") port, out("eax") l, options(att_syntax));
+        l
+    }
+    #[cfg(test)]
+    0
+}
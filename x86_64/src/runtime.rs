@@ -6,12 +6,28 @@ use alloc::alloc::Layout;
 use core::panic::PanicInfo;
 
 #[panic_handler]
-pub fn panic(_info: &PanicInfo) -> ! {
+pub fn panic(info: &PanicInfo) -> ! {
+    if cfg!(feature = "qemu_test") {
+        port::println!("Panic: {info}");
+        match crate::allocator::try_stats() {
+            Some(stats) => port::println!("heap: {}", stats),
+            None => port::println!("heap: stats unavailable, allocator locked"),
+        }
+        port::qemu::exit_qemu(false);
+    }
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    panic!("oom");
+fn oom(layout: Layout) -> ! {
+    // Show where memory has gone, unless printing is what ran out
+    if port::devcons::console_ready() && !port::devcons::locked_here() {
+        crate::kmem::report_memory_usage();
+    }
+    let (size, align) = (layout.size(), layout.align());
+    match crate::allocator::try_stats() {
+        Some(stats) => panic!("oom: allocating {size} bytes, align {align}, heap: {stats}"),
+        None => panic!("oom: allocating {size} bytes, align {align}, heap locked"),
+    }
 }
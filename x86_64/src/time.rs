@@ -0,0 +1,80 @@
+/// time sets port::time's clock to the TSC, calibrated once at boot by
+/// counting TSC ticks while channel 2 of the PIT counts down a known
+/// interval.  The PIT runs at a fixed rate, whatever the CPU, but is slow to
+/// read, so it's only used for calibration.  The TSC only keeps good time if
+/// it's invariant, running at a constant rate in every power state, which
+/// CPUID says, so there's a warning if it isn't.
+use crate::pio;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use port::println;
+
+const PIT_FREQ_HZ: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL2_CMD: u8 = 0b1011_0000; // Channel 2, low then high byte, mode 0
+
+// Port B of the keyboard controller, which gates PIT channel 2
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 1 << 0; // Channel 2 counts while set
+const PORT_B_SPEAKER: u8 = 1 << 1; // Channel 2 drives the speaker while set
+const PORT_B_OUT2: u8 = 1 << 5; // Channel 2's output, set at terminal count
+
+/// How long to count TSC ticks for.
+const CALIBRATE_MS: u64 = 10;
+
+/// How many times to poll the PIT before giving up, e.g. if there isn't
+/// one.  Each poll is a slow port read, so this is a few seconds.
+const MAX_POLLS: u64 = 10_000_000;
+
+/// Calibrate the TSC, and use it for port::time.
+pub fn init() {
+    if !has_invariant_tsc() {
+        println!("warning:time:init:TSC isn't invariant, so time may drift");
+    }
+    match calibrate_tsc() {
+        Some(freq) => {
+            port::time::set_clock(rdtsc, freq);
+            println!("TSC: {}.{:03} MHz", freq / 1_000_000, freq / 1000 % 1000);
+        }
+        None => println!("error:time:init:couldn't calibrate TSC against the PIT, so no clock"),
+    }
+}
+
+/// Return true if CPUID says the TSC is invariant.
+fn has_invariant_tsc() -> bool {
+    // Safety: CPUID is available on every x86_64 CPU
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
+fn rdtsc() -> u64 {
+    // Safety: the TSC is available on every x86_64 CPU
+    unsafe { _rdtsc() }
+}
+
+/// Return the TSC frequency in Hz, measured over CALIBRATE_MS of PIT
+/// channel 2 counting down in mode 0, where its output goes high at the end.
+fn calibrate_tsc() -> Option<u64> {
+    let count = PIT_FREQ_HZ * CALIBRATE_MS / 1000;
+    unsafe {
+        // Stop channel 2, with the speaker off, and load the count
+        let port_b = pio::inb(PORT_B) & !(PORT_B_GATE2 | PORT_B_SPEAKER);
+        pio::outb(PORT_B, port_b);
+        pio::outb(PIT_COMMAND, PIT_CHANNEL2_CMD);
+        pio::outb(PIT_CHANNEL2, count as u8);
+        pio::outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+        // Start it, and count TSC ticks until it's done
+        pio::outb(PORT_B, port_b | PORT_B_GATE2);
+        let start = rdtsc();
+        let mut polls = 0;
+        while pio::inb(PORT_B) & PORT_B_OUT2 == 0 {
+            polls += 1;
+            if polls > MAX_POLLS {
+                return None;
+            }
+        }
+        let ticks = rdtsc() - start;
+        pio::outb(PORT_B, port_b);
+        Some(ticks * PIT_FREQ_HZ / count).filter(|&freq| freq > 0)
+    }
+}
@@ -1,7 +1,110 @@
-//! Simple UART driver to get started.
+//! Polled driver for the 16550 uart, e.g. COM1, using port I/O.
 
-pub fn putb(port: u16, b: u8) {
-    unsafe {
-        crate::pio::outb(port, b);
+use crate::pio::{inb, outb};
+use port::devcons::{Uart, count_rx_error};
+
+/// I/O port of the first serial port.
+pub const COM1: u16 = 0x3f8;
+
+/// Frequency of the clock driving the baud rate generator, divided by 16.
+const BASE_BAUD: u32 = 115_200;
+
+// Register offsets from the base port.  DLL and DLM replace RBR/THR and IER
+// while LCR_DLAB is set.
+const RBR: u16 = 0; // Receive buffer (read)
+const THR: u16 = 0; // Transmit holding (write)
+const DLL: u16 = 0; // Divisor latch, low byte
+const IER: u16 = 1; // Interrupt enable
+const DLM: u16 = 1; // Divisor latch, high byte
+const FCR: u16 = 2; // FIFO control (write)
+const LCR: u16 = 3; // Line control
+const MCR: u16 = 4; // Modem control
+const LSR: u16 = 5; // Line status
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_TRIGGER_14: u8 = 3 << 6;
+
+const LCR_8N1: u8 = 0x03; // 8 data bits, no parity, 1 stop bit
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_RX_ERRORS: u8 = 0x1e; // Overrun, parity, framing and break
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+pub struct Uart16550 {
+    port: u16,
+}
+
+impl Uart16550 {
+    pub const fn new(port: u16) -> Self {
+        Self { port }
+    }
+
+    /// Set up the uart for `baud` 8N1, with the FIFOs enabled and
+    /// interrupts disabled, as it's polled.
+    pub fn init(&self, baud: u32) {
+        let divisor = divisor(baud);
+        self.write(IER, 0);
+        self.write(LCR, LCR_DLAB);
+        self.write(DLL, divisor as u8);
+        self.write(DLM, (divisor >> 8) as u8);
+        self.write(LCR, LCR_8N1);
+        self.write(FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_14);
+        self.write(MCR, MCR_DTR | MCR_RTS);
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { inb(self.port + reg) }
+    }
+
+    fn write(&self, reg: u16, b: u8) {
+        unsafe { outb(self.port + reg, b) }
+    }
+}
+
+impl Uart for Uart16550 {
+    fn putb(&self, b: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(THR, b);
+    }
+
+    fn getb(&self) -> Option<u8> {
+        let lsr = self.read(LSR);
+        if lsr & LSR_DATA_READY == 0 {
+            return None;
+        }
+        // The error bits apply to the byte at the head of the FIFO, so read
+        // it either way to discard it
+        let b = self.read(RBR);
+        if lsr & LSR_RX_ERRORS != 0 {
+            count_rx_error();
+            return None;
+        }
+        Some(b)
+    }
+}
+
+/// Return the divisor for `baud`, rounded to the nearest achievable rate.
+fn divisor(baud: u32) -> u16 {
+    ((BASE_BAUD + baud / 2) / baud).clamp(1, u16::MAX as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisor_for_baud() {
+        assert_eq!(divisor(115_200), 1);
+        assert_eq!(divisor(9600), 12);
+        assert_eq!(divisor(1_000_000), 1);
+        assert_eq!(divisor(1), u16::MAX);
     }
 }
@@ -0,0 +1,919 @@
+/// 4-level page table implementation for x86_64.  Virtual addresses are 48
+/// bits, sign extended to 64, and are translated through the PML4, PDPT, PD
+/// and PT, numbered as levels 0 to 3 as on aarch64.  Pages are 4KiB, or 2MiB
+/// and 1GiB when mapped directly from the PD or PDPT.
+///
+/// All of RAM is mapped at KZERO, both by the boot page tables in l.S, which
+/// map the first 4GiB, and by the tables built here, so tables are always
+/// reached at their physical address offset from KZERO.  Until the switch,
+/// that only holds for tables allocated from the first 4GiB.
+use crate::{
+    kmem::{kernel_sections, physaddr_as_ptr_mut_offset_from_kzero, total_kernel_range},
+    pagealloc,
+    param::KZERO,
+};
+use core::fmt;
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicU64, Ordering};
+use port::{
+    mem::{
+        MapFlags, MapFlagsError, MemError, MemType, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K,
+        PhysAddr, PhysRange, PhysRangeSet, VirtAddr, VirtRange,
+    },
+    memaccount::MemCategory,
+};
+
+#[cfg(not(test))]
+use port::println;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+}
+
+impl PageSize {
+    pub const fn size(&self) -> usize {
+        match self {
+            PageSize::Page4K => PAGE_SIZE_4K,
+            PageSize::Page2M => PAGE_SIZE_2M,
+            PageSize::Page1G => PAGE_SIZE_1G,
+        }
+    }
+}
+
+/// Levels of the hierarchy: the PML4, PDPT, PD and PT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Level0,
+    Level1,
+    Level2,
+    Level3,
+}
+
+impl Level {
+    pub fn next(&self) -> Option<Level> {
+        match self {
+            Level::Level0 => Some(Level::Level1),
+            Level::Level1 => Some(Level::Level2),
+            Level::Level2 => Some(Level::Level3),
+            Level::Level3 => None,
+        }
+    }
+
+    /// Size of the page mapped by a leaf entry at this level.  There are no
+    /// leaf entries in the PML4.
+    pub fn page_size(&self) -> Option<PageSize> {
+        match self {
+            Level::Level0 => None,
+            Level::Level1 => Some(PageSize::Page1G),
+            Level::Level2 => Some(PageSize::Page2M),
+            Level::Level3 => Some(PageSize::Page4K),
+        }
+    }
+
+    /// Level of the leaf entries mapping pages of `page_size`.
+    pub fn for_page_size(page_size: PageSize) -> Level {
+        match page_size {
+            PageSize::Page1G => Level::Level1,
+            PageSize::Page2M => Level::Level2,
+            PageSize::Page4K => Level::Level3,
+        }
+    }
+}
+
+/// Return the index into the table at `level` for `va`.
+pub fn va_index(va: VirtAddr, level: Level) -> usize {
+    let shift = match level {
+        Level::Level0 => 39,
+        Level::Level1 => 30,
+        Level::Level2 => 21,
+        Level::Level3 => 12,
+    };
+    (va.addr() >> shift) & 0x1ff
+}
+
+/// Addresses must have bits 63..48 all equal to bit 47.
+fn is_canonical(va: VirtAddr) -> bool {
+    let high_bits = (va.addr() as isize) >> 47;
+    high_bits == 0 || high_bits == -1
+}
+
+// Page table entry bits
+const PTE_P: u64 = 1 << 0; // Present
+const PTE_RW: u64 = 1 << 1; // Writable
+const PTE_US: u64 = 1 << 2; // Accessible from user mode
+const PTE_PWT: u64 = 1 << 3; // Write through
+const PTE_PCD: u64 = 1 << 4; // Cache disable
+const PTE_PS: u64 = 1 << 7; // Page size, i.e. a 2MiB or 1GiB page
+const PTE_G: u64 = 1 << 8; // Global, i.e. kept across CR3 loads
+const PTE_NX: u64 = 1 << 63; // No execute
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// An x86_64 page table entry.  This is documented in the '4-Level Paging and
+/// 5-Level Paging' section of the Intel SDM, volume 3.
+#[derive(Copy, Clone, PartialEq)]
+#[repr(transparent)]
+pub struct Entry(pub u64);
+
+impl Entry {
+    pub const fn empty() -> Entry {
+        Entry(0)
+    }
+
+    /// Return a leaf entry at `level` mapping `pa` with `flags`.  Writes are
+    /// only allowed with WRITE, since CR0.WP is set, and execution only with
+    /// EXECUTE, since EFER.NXE is set.  Kernel mappings are global.  With the
+    /// PAT l.S sets up, NON_CACHEABLE selects write-combining memory,
+    /// DEVICE_RELAXED weakly uncacheable memory, and DEVICE uncacheable
+    /// memory.
+    pub fn leaf(pa: PhysAddr, flags: MapFlags, level: Level) -> Result<Entry, MapFlagsError> {
+        let flags = flags.validate()?;
+        let mut bits = PTE_P;
+        if flags.contains(MapFlags::WRITE) {
+            bits |= PTE_RW;
+        }
+        if !flags.contains(MapFlags::EXECUTE) {
+            bits |= PTE_NX;
+        }
+        bits |= if flags.contains(MapFlags::USER) { PTE_US } else { PTE_G };
+        bits |= match flags.mem_type() {
+            MemType::NormalCached => 0,
+            MemType::NormalNonCacheable => PTE_PWT,
+            MemType::DeviceRelaxed => PTE_PCD,
+            MemType::DeviceStrict => PTE_PCD | PTE_PWT,
+        };
+        if level != Level::Level3 {
+            bits |= PTE_PS;
+        }
+        Ok(Entry(bits | (pa.addr() & PTE_ADDR_MASK)))
+    }
+
+    /// Return a non-leaf entry referencing the table at `pa`.  Permissions
+    /// are the intersection of those at each level, so these allow anything,
+    /// leaving the leaf entries to decide.
+    pub fn table(pa: PhysAddr) -> Entry {
+        Entry(PTE_P | PTE_RW | PTE_US | (pa.addr() & PTE_ADDR_MASK))
+    }
+
+    pub fn present(self) -> bool {
+        self.0 & PTE_P != 0
+    }
+
+    /// Return true if this entry, found at `level`, maps a page rather than
+    /// referencing a table.
+    pub fn is_leaf(self, level: Level) -> bool {
+        match level {
+            Level::Level0 => false,
+            Level::Level1 | Level::Level2 => self.0 & PTE_PS != 0,
+            Level::Level3 => true,
+        }
+    }
+
+    pub fn pa(self) -> PhysAddr {
+        PhysAddr::new(self.0 & PTE_ADDR_MASK)
+    }
+
+    /// Return the flags of a leaf entry.
+    pub fn flags(self) -> MapFlags {
+        let mut flags = MapFlags::READ;
+        if self.0 & PTE_RW != 0 {
+            flags |= MapFlags::WRITE;
+        }
+        if self.0 & PTE_NX == 0 {
+            flags |= MapFlags::EXECUTE;
+        }
+        if self.0 & PTE_US != 0 {
+            flags |= MapFlags::USER;
+        }
+        let mem_type = match (self.0 & PTE_PCD != 0, self.0 & PTE_PWT != 0) {
+            (false, false) => MemType::NormalCached,
+            (false, true) => MemType::NormalNonCacheable,
+            (true, false) => MemType::DeviceRelaxed,
+            (true, true) => MemType::DeviceStrict,
+        };
+        flags.with_mem_type(mem_type)
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Entry({:#018x} pa:{:?})", self.0, self.pa())
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [Entry; 512],
+}
+
+/// How the tables are reached and allocated.  The table manipulation is
+/// written against this so that it can be exercised with tables in ordinary
+/// memory.
+trait TableWalker {
+    /// Return a pointer through which the table at `pa` can be accessed.
+    fn table(&mut self, pa: PhysAddr) -> *mut Table;
+
+    /// Allocate a page for a new table.  Its contents are undefined.
+    fn alloc_table(&mut self) -> Result<PhysAddr, MemError>;
+
+    /// Free the table at `pa`, which is no longer referenced.
+    fn free_table(&mut self, pa: PhysAddr);
+
+    /// Invalidate any cached translations for `va`, whose entry has just been
+    /// cleared.
+    fn invalidate(&mut self, va: VirtAddr);
+}
+
+/// Reaches the kernel's tables through the KZERO mapping, allocating them from
+/// the page allocator.
+struct KernelTableWalker;
+
+impl TableWalker for KernelTableWalker {
+    fn table(&mut self, pa: PhysAddr) -> *mut Table {
+        physaddr_as_ptr_mut_offset_from_kzero(pa)
+    }
+
+    fn alloc_table(&mut self) -> Result<PhysAddr, MemError> {
+        pagealloc::allocate_physpage_for(MemCategory::PageTables)
+    }
+
+    fn free_table(&mut self, pa: PhysAddr) {
+        // Errors are logged by the allocator, and there's nothing more to do
+        let _ = pagealloc::free_physpage_for(pa, MemCategory::PageTables);
+    }
+
+    fn invalidate(&mut self, va: VirtAddr) {
+        unsafe { invalidate_tlb_entry(va) };
+    }
+}
+
+/// Allocate a table and clear it.
+fn alloc_cleared_table(walker: &mut impl TableWalker) -> Result<PhysAddr, MemError> {
+    let pa = walker.alloc_table()?;
+    let table = unsafe { &mut *walker.table(pa) };
+    for entry in table.entries.iter_mut() {
+        unsafe { write_volatile(entry, Entry::empty()) };
+    }
+    Ok(pa)
+}
+
+/// A translation found by walking the page tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub pa: PhysAddr,        // Physical address the virtual address maps to
+    pub page_size: PageSize, // Size of the page containing it
+    pub entry: Entry,        // Leaf entry, holding the permissions
+}
+
+/// Walk the tables from `root` to the leaf entry translating `va`.  Returns
+/// None if there's no present entry for `va` at any level.
+fn walk(root: PhysAddr, va: VirtAddr, walker: &mut impl TableWalker) -> Option<Mapping> {
+    if !is_canonical(va) {
+        return None;
+    }
+    let mut table = unsafe { &*walker.table(root) };
+    let mut level = Level::Level0;
+    loop {
+        let entry = table.entries[va_index(va, level)];
+        if !entry.present() {
+            return None;
+        }
+        if entry.is_leaf(level) {
+            let page_size = level.page_size()?;
+            let offset = va.addr() & (page_size.size() - 1);
+            let pa = PhysAddr::new(entry.pa().addr() + offset as u64);
+            return Some(Mapping { pa, page_size, entry });
+        }
+        table = unsafe { &*walker.table(entry.pa()) };
+        level = level.next()?;
+    }
+}
+
+/// Map the page of `page_size` at `va` to `pa`, creating any intermediate
+/// tables needed.  Fails if any of the page is already mapped.
+fn map_page(
+    root: PhysAddr,
+    va: VirtAddr,
+    pa: PhysAddr,
+    page_size: PageSize,
+    flags: MapFlags,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    if !is_canonical(va) {
+        println!("error:vm:map_page:va isn't canonical. va:{va:?}");
+        return Err(MemError::NotCanonical { va });
+    }
+    let size = page_size.size();
+    if va.addr() & (size - 1) != 0 || pa.addr() & (size as u64 - 1) != 0 {
+        println!("error:vm:map_page:not on page boundary. va:{va:?} pa:{pa:?} size:{size:#x}");
+        let addr = if va.addr() & (size - 1) != 0 { va.addr() as u64 } else { pa.addr() };
+        return Err(MemError::Unaligned { addr, required: size });
+    }
+    let leaf_level = Level::for_page_size(page_size);
+    let new_entry = Entry::leaf(pa, flags, leaf_level)?;
+
+    let mut table = unsafe { &mut *walker.table(root) };
+    let mut level = Level::Level0;
+    while level != leaf_level {
+        let entry = &mut table.entries[va_index(va, level)];
+        if !entry.present() {
+            let table_pa = alloc_cleared_table(walker)?;
+            unsafe { write_volatile(entry, Entry::table(table_pa)) };
+        } else if entry.is_leaf(level) {
+            return Err(MemError::AlreadyMapped { va });
+        }
+        table = unsafe { &mut *walker.table(entry.pa()) };
+        level = level.next().unwrap();
+    }
+
+    let entry = &mut table.entries[va_index(va, level)];
+    if entry.present() {
+        return Err(MemError::AlreadyMapped { va });
+    }
+    unsafe { write_volatile(entry, new_entry) };
+    Ok(())
+}
+
+/// Clear the leaf entry mapping `va`, freeing any tables left empty, other
+/// than the root and the tables of the kernel half referenced directly from
+/// it, which are shared by all address spaces.  Returns the mapping that was
+/// removed.
+fn unmap_page(
+    root: PhysAddr,
+    va: VirtAddr,
+    walker: &mut impl TableWalker,
+) -> Result<Mapping, MemError> {
+    let Some(mapping) = walk(root, va, walker) else {
+        return Err(MemError::NotMapped { va });
+    };
+
+    // Record the tables on the way down, so emptied ones can be freed
+    let levels = [Level::Level0, Level::Level1, Level::Level2, Level::Level3];
+    let mut tables = [root; 4];
+    let mut depth = 0;
+    loop {
+        let table = unsafe { &*walker.table(tables[depth]) };
+        let entry = table.entries[va_index(va, levels[depth])];
+        if entry.is_leaf(levels[depth]) {
+            break;
+        }
+        depth += 1;
+        tables[depth] = entry.pa();
+    }
+
+    // Clear the leaf, then each table's entry in its parent while it's empty
+    loop {
+        let table = unsafe { &mut *walker.table(tables[depth]) };
+        unsafe { write_volatile(&mut table.entries[va_index(va, levels[depth])], Entry::empty()) };
+        let shared = depth == 1 && va_index(va, Level::Level0) >= KERNEL_HALF_START;
+        if depth == 0 || shared || table.entries.iter().any(|e| e.present()) {
+            break;
+        }
+        walker.free_table(tables[depth]);
+        depth -= 1;
+    }
+    walker.invalidate(va);
+    Ok(mapping)
+}
+
+/// Map `phys` at `va`, using the largest pages, up to `largest`, that the
+/// alignment of each part allows.  `va` and `phys` must be page aligned.
+fn map_range(
+    root: PhysAddr,
+    va: VirtAddr,
+    phys: &PhysRange,
+    flags: MapFlags,
+    largest: PageSize,
+    walker: &mut impl TableWalker,
+) -> Result<(), MemError> {
+    let mut va = va.addr();
+    let mut pa = phys.start().addr();
+    while pa < phys.end().addr() {
+        let remaining = (phys.end().addr() - pa) as usize;
+        let page_size = [PageSize::Page1G, PageSize::Page2M, PageSize::Page4K]
+            .into_iter()
+            .filter(|ps| ps.size() <= largest.size())
+            .find(|ps| {
+                let size = ps.size();
+                (va | pa as usize) & (size - 1) == 0 && remaining >= size
+            })
+            .unwrap_or(PageSize::Page4K);
+        map_page(root, VirtAddr::new(va), PhysAddr::new(pa), page_size, flags, walker)?;
+        va += page_size.size();
+        pa += page_size.size() as u64;
+    }
+    Ok(())
+}
+
+/// Index of the first PML4 entry of the kernel half of the address space.
+const KERNEL_HALF_START: usize = 256;
+
+/// Ensure every PML4 entry of the kernel half of `root` references a table,
+/// so that copying them into another root shares all of the kernel half,
+/// including anything mapped after the copy.
+fn populate_kernel_half(root: PhysAddr, walker: &mut impl TableWalker) -> Result<(), MemError> {
+    for i in KERNEL_HALF_START..512 {
+        if !unsafe { (*walker.table(root)).entries[i] }.present() {
+            let table_pa = alloc_cleared_table(walker)?;
+            let root_table = unsafe { &mut *walker.table(root) };
+            unsafe { write_volatile(&mut root_table.entries[i], Entry::table(table_pa)) };
+        }
+    }
+    Ok(())
+}
+
+/// Allocate a root table with nothing mapped in the user half, and the kernel
+/// half sharing the tables of `kernel_root`.
+fn alloc_root(kernel_root: PhysAddr, walker: &mut impl TableWalker) -> Result<PhysAddr, MemError> {
+    let root = alloc_cleared_table(walker)?;
+    let kernel_table = unsafe { &*walker.table(kernel_root) };
+    let root_table = unsafe { &mut *walker.table(root) };
+    for i in KERNEL_HALF_START..512 {
+        unsafe { write_volatile(&mut root_table.entries[i], kernel_table.entries[i]) };
+    }
+    Ok(root)
+}
+
+/// Call `f` with the virtual address and mapping of each page in the tables
+/// from `root`, in address order.
+fn for_each_mapping(
+    root: PhysAddr,
+    walker: &mut impl TableWalker,
+    f: &mut impl FnMut(VirtAddr, Mapping),
+) {
+    fn visit(
+        table_pa: PhysAddr,
+        level: Level,
+        va: usize,
+        walker: &mut impl TableWalker,
+        f: &mut impl FnMut(VirtAddr, Mapping),
+    ) {
+        let shift = 12 + 9 * (3 - level as usize);
+        for i in 0..512 {
+            let entry = unsafe { (*walker.table(table_pa)).entries[i] };
+            if !entry.present() {
+                continue;
+            }
+            // Sign extend from bit 47 to form a canonical address
+            let entry_va = (((va | (i << shift)) << 16) as isize >> 16) as usize;
+            match (entry.is_leaf(level), level.page_size(), level.next()) {
+                (true, Some(page_size), _) => {
+                    f(VirtAddr::new(entry_va), Mapping { pa: entry.pa(), page_size, entry })
+                }
+                (false, _, Some(next)) => visit(entry.pa(), next, entry_va, walker, f),
+                _ => {}
+            }
+        }
+    }
+    visit(root, Level::Level0, 0, walker, f);
+}
+
+/// A run of mappings contiguous in both virtual and physical address, with
+/// the same flags and page size, for printing as one line.
+struct MappingRun {
+    va: VirtAddr,
+    pa: PhysAddr,
+    len: usize,
+    page_size: PageSize,
+    flags: MapFlags,
+}
+
+impl MappingRun {
+    /// Extend the run with the mapping at `va`, returning false if it doesn't
+    /// follow on.
+    fn extend(&mut self, va: VirtAddr, mapping: &Mapping) -> bool {
+        let follows = va.addr() == self.va.addr() + self.len
+            && mapping.pa.addr() == self.pa.addr() + self.len as u64
+            && mapping.page_size == self.page_size
+            && mapping.entry.flags() == self.flags;
+        if follows {
+            self.len += self.page_size.size();
+        }
+        follows
+    }
+}
+
+impl fmt::Display for MappingRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} {} {:?}",
+            VirtRange::with_len(self.va, self.len),
+            PhysRange::with_pa_len(self.pa, self.len),
+            self.flags,
+            self.page_size
+        )
+    }
+}
+
+/// Return the largest page size the processor supports.  1GiB pages are
+/// optional, e.g. QEMU's default CPU model doesn't have them.
+fn largest_page_size() -> PageSize {
+    // CPUID.80000001H:EDX.Page1GB[bit 26]
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+    if cpuid.edx & (1 << 26) != 0 { PageSize::Page1G } else { PageSize::Page2M }
+}
+
+/// Physical address of the root table of the kernel address space, or 0
+/// until init_kernel_page_tables has built it.
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+/// An address space, made up of a hierarchy of 4-level page tables.  Its
+/// methods operate on its own tables, whether or not they're the active ones.
+/// The user half belongs to the address space, while the tables of the kernel
+/// half are shared with the kernel address space, so kernel mappings made
+/// through any address space are visible in all of them.
+pub struct AddressSpace {
+    root: PhysAddr,
+}
+
+impl AddressSpace {
+    /// Return the kernel address space.  Panics if it hasn't been built yet.
+    pub fn kernel() -> AddressSpace {
+        let root = KERNEL_ROOT.load(Ordering::Acquire);
+        assert!(root != 0, "vm:AddressSpace::kernel:kernel page tables not built yet");
+        AddressSpace { root: PhysAddr::new(root) }
+    }
+
+    /// Create an address space with nothing mapped in the user half, sharing
+    /// the kernel half with the kernel address space.
+    #[allow(dead_code)]
+    pub fn new() -> Result<AddressSpace, MemError> {
+        let kernel_root = AddressSpace::kernel().root;
+        Ok(AddressSpace { root: alloc_root(kernel_root, &mut KernelTableWalker)? })
+    }
+
+    /// Return the physical address of the PML4, as loaded into CR3.
+    pub fn root(&self) -> PhysAddr {
+        self.root
+    }
+
+    /// Map the page of `page_size` at `va` to `pa`.
+    #[allow(dead_code)]
+    pub fn map(
+        &mut self,
+        va: VirtAddr,
+        pa: PhysAddr,
+        page_size: PageSize,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        map_page(self.root, va, pa, page_size, flags, &mut KernelTableWalker)
+    }
+
+    /// Map `phys` at `va` using the largest pages possible.
+    pub fn map_range(
+        &mut self,
+        va: VirtAddr,
+        phys: &PhysRange,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        map_range(self.root, va, phys, flags, largest_page_size(), &mut KernelTableWalker)
+    }
+
+    /// Unmap the page containing `va`, returning how it was mapped.
+    #[allow(dead_code)]
+    pub fn unmap(&mut self, va: VirtAddr) -> Result<Mapping, MemError> {
+        unmap_page(self.root, va, &mut KernelTableWalker)
+    }
+
+    /// Return the mapping for `va`, or None if it isn't mapped.
+    pub fn lookup(&self, va: VirtAddr) -> Option<Mapping> {
+        walk(self.root, va, &mut KernelTableWalker)
+    }
+
+    /// Panic unless all of `range` is mapped, with at least the given flags.
+    pub fn assert_mapped(&self, range: &VirtRange, flags: MapFlags) {
+        let mut va = range.start();
+        while va < range.end() {
+            let Some(mapping) = self.lookup(va) else {
+                panic!("vm:assert_mapped:{va:?} isn't mapped. range:{range}");
+            };
+            let actual = mapping.entry.flags();
+            assert!(
+                actual.contains(flags),
+                "vm:assert_mapped:{va:?} is mapped {actual}, expected at least {flags}. range:{range}"
+            );
+
+            let page_size = mapping.page_size.size();
+            match (va.addr() & !(page_size - 1)).checked_add(page_size) {
+                Some(next_va) => va = VirtAddr::new(next_va),
+                None => break,
+            }
+        }
+    }
+
+    /// Print a summary of the mappings, collapsing contiguous runs of
+    /// mappings with the same flags into single lines.
+    pub fn dump(&self) {
+        println!("Mappings (root {:?}):", self.root);
+        let mut run: Option<MappingRun> = None;
+        for_each_mapping(self.root, &mut KernelTableWalker, &mut |va, mapping| {
+            if run.as_mut().is_some_and(|run| run.extend(va, &mapping)) {
+                return;
+            }
+            if let Some(run) = &run {
+                println!("  {run}");
+            }
+            let (page_size, flags) = (mapping.page_size, mapping.entry.flags());
+            run = Some(MappingRun { va, pa: mapping.pa, len: page_size.size(), page_size, flags });
+        });
+        if let Some(run) = &run {
+            println!("  {run}");
+        }
+    }
+
+    /// Make this the active address space by loading CR3, which also flushes
+    /// the TLB of all but global entries.  Only kernel mappings are global,
+    /// and they're the same in every address space, so none are stale.
+    pub unsafe fn activate(&self) {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!(
+                "movq {root}, %cr3",
+                root = in(reg) self.root.addr(),
+                options(att_syntax));
+        }
+    }
+}
+
+/// Build the kernel address space: each section of the kernel image mapped
+/// at KZERO with its intended flags, and the rest of `memory` mapped
+/// read-write at KZERO.  Nothing is identity mapped.  Every entry of the
+/// kernel half of the root references a table, so that it can be shared by
+/// other address spaces.
+pub fn init_kernel_page_tables(memory: &PhysRangeSet) -> Result<AddressSpace, MemError> {
+    let mut kernel_space = AddressSpace { root: alloc_cleared_table(&mut KernelTableWalker)? };
+    for section in kernel_sections() {
+        let va = VirtAddr::new(KZERO + section.range.start().addr() as usize);
+        kernel_space.map_range(va, &section.range, section.flags)?;
+    }
+
+    // Map whole pages of memory either side of the kernel image
+    let kernel = total_kernel_range();
+    for range in memory.iter() {
+        let start = range.start().round_up(PAGE_SIZE_4K as u64);
+        let end = range.end().round_down(PAGE_SIZE_4K as u64);
+        for (start, end) in [(start, end.min(kernel.start())), (start.max(kernel.end()), end)] {
+            if start < end {
+                let va = VirtAddr::new(KZERO + start.addr() as usize);
+                kernel_space.map_range(va, &PhysRange::new(start, end), MapFlags::RW)?;
+            }
+        }
+    }
+    populate_kernel_half(kernel_space.root, &mut KernelTableWalker)?;
+    KERNEL_ROOT.store(kernel_space.root.addr(), Ordering::Release);
+    Ok(kernel_space)
+}
+
+/// Invalidate the TLB entries for the page containing `va`.
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_entry(va: VirtAddr) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("invlpg ({va})", va = in(reg) va.addr(), options(att_syntax));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Software walker over boxed tables, whose physical addresses are their
+    /// addresses in the hosted process.
+    #[derive(Default)]
+    struct TestWalker {
+        invalidated: Vec<VirtAddr>,
+        freed: Vec<PhysAddr>,
+    }
+
+    impl TableWalker for TestWalker {
+        fn table(&mut self, pa: PhysAddr) -> *mut Table {
+            pa.addr() as *mut Table
+        }
+
+        fn alloc_table(&mut self) -> Result<PhysAddr, MemError> {
+            let table = Box::leak(Box::new(Table { entries: [Entry(0xdead_beef); 512] }));
+            Ok(PhysAddr::new((table as *mut Table).addr() as u64))
+        }
+
+        fn free_table(&mut self, pa: PhysAddr) {
+            self.freed.push(pa);
+            drop(unsafe { Box::from_raw(pa.addr() as *mut Table) });
+        }
+
+        fn invalidate(&mut self, va: VirtAddr) {
+            self.invalidated.push(va);
+        }
+    }
+
+    fn table(pa: PhysAddr) -> &'static Table {
+        unsafe { &*(pa.addr() as *const Table) }
+    }
+
+    #[test]
+    fn entry_bits() {
+        let pa = PhysAddr::new(0x20_0000);
+        let leaf = |flags, level| Entry::leaf(pa, flags, level).unwrap().0;
+        assert_eq!(leaf(MapFlags::RX, Level::Level3), 0x20_0000 | 0x101);
+        assert_eq!(leaf(MapFlags::RW, Level::Level3), 1 << 63 | 0x20_0000 | 0x103);
+        assert_eq!(leaf(MapFlags::READ, Level::Level2), 1 << 63 | 0x20_0000 | 0x181);
+        assert_eq!(leaf(MapFlags::RW | MapFlags::USER, Level::Level3), 1 << 63 | 0x20_0000 | 0x7);
+        assert_eq!(
+            leaf(MapFlags::RW | MapFlags::DEVICE, Level::Level3),
+            1 << 63 | 0x20_0000 | 0x11b
+        );
+        assert_eq!(Entry::table(pa).0, 0x20_0007);
+        assert!(matches!(
+            Entry::leaf(pa, MapFlags::RW | MapFlags::EXECUTE, Level::Level3),
+            Err(MapFlagsError::KernelWriteExecute)
+        ));
+
+        assert_eq!(
+            leaf(MapFlags::RW | MapFlags::WRITE_COMBINING, Level::Level3),
+            1 << 63 | 0x20_0000 | 0x10b
+        );
+        assert_eq!(
+            leaf(MapFlags::RW | MapFlags::DEVICE_RELAXED, Level::Level3),
+            1 << 63 | 0x20_0000 | 0x113
+        );
+
+        for flags in [
+            MapFlags::RX,
+            MapFlags::RW | MapFlags::NON_CACHEABLE,
+            MapFlags::RW | MapFlags::DEVICE,
+            MapFlags::RW | MapFlags::DEVICE_RELAXED,
+            MapFlags::READ,
+        ] {
+            let entry =
+                Entry::leaf(PhysAddr::new(0xf_ffff_ffff_f000), flags, Level::Level3).unwrap();
+            assert_eq!(entry.pa(), PhysAddr::new(0xf_ffff_ffff_f000));
+            assert_eq!(entry.flags(), flags);
+        }
+        assert!(Entry::leaf(pa, MapFlags::READ, Level::Level2).unwrap().is_leaf(Level::Level2));
+        assert!(!Entry::table(pa).is_leaf(Level::Level2));
+    }
+
+    #[test]
+    fn map_and_walk() {
+        let mut walker = TestWalker::default();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va = VirtAddr::new(0xffff_8000_4020_3000);
+        let pa = PhysAddr::new(0x8000_5000);
+        map_page(root, va, pa, PageSize::Page4K, MapFlags::RW, &mut walker).unwrap();
+
+        // Each level is indexed by 9 bits of the address
+        let pml4 = table(root);
+        assert_eq!(pml4.entries.iter().filter(|e| e.present()).count(), 1);
+        let pdpt = table(pml4.entries[256].pa());
+        let pd = table(pdpt.entries[1].pa());
+        let pt = table(pd.entries[1].pa());
+        assert_eq!(pt.entries[3], Entry::leaf(pa, MapFlags::RW, Level::Level3).unwrap());
+
+        let mapping = walk(root, va + 0x123, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x8000_5123));
+        assert_eq!(mapping.page_size, PageSize::Page4K);
+        assert_eq!(mapping.entry.flags(), MapFlags::RW);
+        assert_eq!(walk(root, va + 0x1000, &mut walker), None);
+
+        // Addresses in the hole between the halves can't be mapped
+        let hole = VirtAddr::new(0x0000_8000_0000_0000);
+        assert_eq!(walk(root, hole, &mut walker), None);
+        assert_eq!(
+            map_page(root, hole, pa, PageSize::Page4K, MapFlags::RW, &mut walker),
+            Err(MemError::NotCanonical { va: hole })
+        );
+    }
+
+    #[test]
+    fn map_rejects_overlaps() {
+        let mut walker = TestWalker::default();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va = VirtAddr::new(0xffff_8000_0020_0000);
+        let pa = PhysAddr::new(0x20_0000);
+        map_page(root, va, pa, PageSize::Page2M, MapFlags::RX, &mut walker).unwrap();
+        let mapping = walk(root, va + 0x1_2345, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x21_2345));
+        assert_eq!(mapping.page_size, PageSize::Page2M);
+
+        for (va, page_size) in [(va, PageSize::Page2M), (va + 0x10_0000, PageSize::Page4K)] {
+            assert_eq!(
+                map_page(root, va, pa, page_size, MapFlags::RW, &mut walker),
+                Err(MemError::AlreadyMapped { va })
+            );
+        }
+        let unaligned = (va + 0x1000).addr() as u64;
+        assert_eq!(
+            map_page(root, va + 0x1000, pa, PageSize::Page2M, MapFlags::RW, &mut walker),
+            Err(MemError::Unaligned { addr: unaligned, required: PAGE_SIZE_2M })
+        );
+    }
+
+    #[test]
+    fn map_range_uses_largest_pages() {
+        let phys = PhysRange::with_end(0x3fff_f000, 0x8020_1000);
+        let va = VirtAddr::new(0xffff_8000_3fff_f000);
+        for (largest, expected) in [
+            (PageSize::Page1G, [PageSize::Page4K, PageSize::Page1G, PageSize::Page2M]),
+            (PageSize::Page2M, [PageSize::Page4K, PageSize::Page2M, PageSize::Page2M]),
+        ] {
+            let mut walker = TestWalker::default();
+            let root = alloc_cleared_table(&mut walker).unwrap();
+            map_range(root, va, &phys, MapFlags::RW, largest, &mut walker).unwrap();
+            for (offset, page_size) in [0, 0x1000, 0x4000_1000]
+                .into_iter()
+                .zip(expected)
+                .chain([(0x4020_1000, PageSize::Page4K)])
+            {
+                let mapping = walk(root, va + offset, &mut walker).unwrap();
+                assert_eq!(mapping.pa, PhysAddr::new(0x3fff_f000 + offset as u64));
+                assert_eq!(mapping.page_size, page_size);
+            }
+            assert_eq!(walk(root, va + 0x4020_2000, &mut walker), None);
+        }
+    }
+
+    #[test]
+    fn unmap_frees_empty_tables() {
+        let mut walker = TestWalker::default();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+
+        let va1 = VirtAddr::new(0x1000);
+        let va2 = VirtAddr::new(0x2000);
+        map_page(root, va1, PhysAddr::new(0x1000), PageSize::Page4K, MapFlags::RW, &mut walker)
+            .unwrap();
+        map_page(root, va2, PhysAddr::new(0x2000), PageSize::Page4K, MapFlags::RW, &mut walker)
+            .unwrap();
+
+        let mapping = unmap_page(root, va1, &mut walker).unwrap();
+        assert_eq!(mapping.pa, PhysAddr::new(0x1000));
+        assert!(walker.freed.is_empty());
+        assert_eq!(unmap_page(root, va1, &mut walker), Err(MemError::NotMapped { va: va1 }));
+
+        // Unmapping the last page frees its tables, but not the root
+        unmap_page(root, va2, &mut walker).unwrap();
+        assert_eq!(walker.freed.len(), 3);
+        assert_eq!(walker.invalidated, [va1, va2]);
+        assert!(table(root).entries.iter().all(|e| !e.present()));
+    }
+
+    #[test]
+    fn address_spaces_share_kernel_half() {
+        let mut walker = TestWalker::default();
+        let kernel_root = alloc_cleared_table(&mut walker).unwrap();
+        let kernel_va = VirtAddr::new(0xffff_8000_0020_0000);
+        let pa = PhysAddr::new(0x20_0000);
+        map_page(kernel_root, kernel_va, pa, PageSize::Page4K, MapFlags::RX, &mut walker).unwrap();
+        populate_kernel_half(kernel_root, &mut walker).unwrap();
+        assert!(table(kernel_root).entries[256..].iter().all(|e| e.present()));
+        assert!(table(kernel_root).entries[..256].iter().all(|e| !e.present()));
+
+        // Kernel mappings made through either root, before or after the copy,
+        // are visible through both, while user mappings are private
+        let root = alloc_root(kernel_root, &mut walker).unwrap();
+        let late_va = VirtAddr::new(0xffff_c000_0000_0000);
+        map_page(root, late_va, pa, PageSize::Page4K, MapFlags::READ, &mut walker).unwrap();
+        let user_va = VirtAddr::new(0x40_0000);
+        let user_flags = MapFlags::RW | MapFlags::USER;
+        map_page(root, user_va, pa, PageSize::Page4K, user_flags, &mut walker).unwrap();
+        for va in [kernel_va, late_va] {
+            assert_eq!(walk(root, va, &mut walker), walk(kernel_root, va, &mut walker));
+            assert!(walk(root, va, &mut walker).is_some());
+        }
+        assert!(walk(root, user_va, &mut walker).is_some());
+        assert_eq!(walk(kernel_root, user_va, &mut walker), None);
+
+        // Emptying part of the kernel half keeps the shared table in the root
+        walker.freed.clear();
+        unmap_page(root, late_va, &mut walker).unwrap();
+        assert_eq!(walker.freed.len(), 2);
+        assert_eq!(table(root).entries[384], table(kernel_root).entries[384]);
+        assert!(table(root).entries[384].present());
+    }
+
+    #[test]
+    fn for_each_mapping_in_address_order() {
+        let mut walker = TestWalker::default();
+        let root = alloc_cleared_table(&mut walker).unwrap();
+        let mappings = [
+            (0x1000, 0x5000, PageSize::Page4K, MapFlags::RW | MapFlags::USER),
+            (0xffff_8000_0000_0000, 0, PageSize::Page2M, MapFlags::READ),
+            (0xffff_8000_0020_0000, 0x20_0000, PageSize::Page4K, MapFlags::RX),
+            (0xffff_ffff_c000_0000, 0x4000_0000, PageSize::Page1G, MapFlags::RW),
+        ];
+        for (va, pa, page_size, flags) in mappings.iter().rev() {
+            map_page(root, VirtAddr::new(*va), PhysAddr::new(*pa), *page_size, *flags, &mut walker)
+                .unwrap();
+        }
+
+        let mut found = vec![];
+        for_each_mapping(root, &mut walker, &mut |va, mapping| {
+            found.push((va.addr(), mapping.pa.addr(), mapping.page_size, mapping.entry.flags()))
+        });
+        assert_eq!(found, mappings);
+    }
+}
@@ -0,0 +1,60 @@
+/// Builds the table of digests of the kernel's read-only sections that's
+/// embedded in its .kdigests section, from the output of `llvm-objdump
+/// --section-headers`.  The format is described in port::digest, which
+/// reads it.
+use port::digest::{ENTRY_SIZE, MAGIC, NAME_LEN};
+
+/// The sections digested, if the kernel has them: text, and those in the
+/// rodata group.  The others are written at runtime, or, like boottext,
+/// aren't mapped once booted.
+const DIGESTED: [&str; 4] = [".text", ".rodata", ".ksyms", ".extable"];
+
+pub struct Section {
+    pub name: String,
+    pub size: u64,
+    pub vma: u64,
+}
+
+impl Section {
+    /// Return the path to dump the section's contents to, in `dir`.
+    pub fn dump_path(&self, dir: &str) -> String {
+        format!("{dir}/digest{}.bin", self.name)
+    }
+}
+
+/// Parse a line of objdump's section headers: the index, name, size, VMA and
+/// type.  Returns None for other lines.
+fn parse_line(line: &str) -> Option<Section> {
+    let mut fields = line.split_whitespace();
+    fields.next()?.parse::<usize>().ok()?;
+    let name = fields.next()?.to_string();
+    let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let vma = u64::from_str_radix(fields.next()?, 16).ok()?;
+    Some(Section { name, size, vma })
+}
+
+/// Return the non-empty sections to digest, from objdump's section headers.
+pub fn digested_sections(headers: &str) -> Vec<Section> {
+    headers
+        .lines()
+        .filter_map(parse_line)
+        .filter(|section| DIGESTED.contains(&section.name.as_str()) && section.size > 0)
+        .collect()
+}
+
+/// Return the table of the sections' digests, in the format port::digest
+/// reads.
+pub fn digest_table(sections: &[(Section, u64)]) -> Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    table.extend((sections.len() as u32).to_le_bytes());
+    for (section, digest) in sections {
+        let mut name = [0u8; NAME_LEN];
+        name[..section.name.len()].copy_from_slice(section.name.as_bytes());
+        table.extend(name);
+        table.extend(section.vma.to_le_bytes());
+        table.extend(section.size.to_le_bytes());
+        table.extend(digest.to_le_bytes());
+    }
+    debug_assert_eq!(table.len(), 8 + sections.len() * ENTRY_SIZE);
+    table
+}
@@ -1,7 +1,7 @@
 use crate::config::Configuration;
 use config::{apply_to_build_step, apply_to_clippy_step, apply_to_qemu_step};
 use std::{
-    env, fmt,
+    env, fmt, fs,
     path::{Path, PathBuf},
     process::{self, Command},
     str::FromStr,
@@ -9,6 +9,8 @@ use std::{
 use target_lexicon::Triple;
 
 mod config;
+mod digests;
+mod symbols;
 
 type DynError = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, DynError>;
@@ -139,6 +141,8 @@ fn main() {
                     .value_parser(clap::builder::NonEmptyStringValueParser::new())
                     .default_value("default"),
                 clap::arg!(--verbose "Print commands"),
+                clap::arg!(--no_symbols "Don't embed a symbol table for backtraces"),
+                clap::arg!(--no_digests "Don't embed digests of the read-only sections"),
             ]),
         )
         .subcommand(
@@ -169,6 +173,8 @@ fn main() {
                     .value_parser(clap::builder::NonEmptyStringValueParser::new())
                     .default_value("default"),
                 clap::arg!(--verbose "Print commands"),
+                clap::arg!(--no_symbols "Don't embed a symbol table for backtraces"),
+                clap::arg!(--no_digests "Don't embed digests of the read-only sections"),
             ]),
         )
         .subcommand(clap::Command::new("test").about("Runs unit tests").args(&[
@@ -207,6 +213,11 @@ fn main() {
                 clap::arg!(--verbose "Print commands"),
                 clap::arg!(--dump_dtb <file> "Dump the DTB from QEMU to a file")
                     .value_parser(clap::value_parser!(String)),
+                clap::arg!(--test "Build with the qemu_test feature, and exit with its result"),
+                clap::arg!(--ktest "Run the in-kernel tests, and exit with their result")
+                    .conflicts_with("test"),
+                clap::arg!(--no_symbols "Don't embed a symbol table for backtraces"),
+                clap::arg!(--no_digests "Don't embed digests of the read-only sections"),
             ]),
         )
         .subcommand(clap::Command::new("clean").about("Cargo clean"))
@@ -248,30 +259,41 @@ fn cargo() -> String {
 }
 
 fn objcopy() -> String {
-    let llvm_objcopy = {
-        let toolchain = env_or("RUSTUP_TOOLCHAIN", "nightly-x86_64-unknown-none");
-
-        // find host architecture by taking last 3 segments from toolchain
-        let mut arch_segments: Box<[_]> = toolchain.split('-').rev().take(3).collect();
-        arch_segments.reverse();
-        let host = arch_segments.join("-");
-
-        let home = env_or("RUSTUP_HOME", "");
-        let mut path = PathBuf::from(home);
-        path.push("toolchains");
-        path.push(toolchain);
-        path.push("lib");
-        path.push("rustlib");
-        path.push(host);
-        path.push("bin");
-        path.push("llvm-objcopy");
-        if path.exists() {
-            path.into_os_string().into_string().unwrap()
-        } else {
-            "llvm-objcopy".into()
-        }
-    };
-    env_or("OBJCOPY", &llvm_objcopy)
+    env_or("OBJCOPY", &llvm_tool("llvm-objcopy"))
+}
+
+fn nm() -> String {
+    env_or("NM", &llvm_tool("llvm-nm"))
+}
+
+fn objdump() -> String {
+    env_or("OBJDUMP", &llvm_tool("llvm-objdump"))
+}
+
+/// Return the path of `tool` from the toolchain's llvm-tools, if they're
+/// installed, or just its name otherwise.
+fn llvm_tool(tool: &str) -> String {
+    let toolchain = env_or("RUSTUP_TOOLCHAIN", "nightly-x86_64-unknown-none");
+
+    // find host architecture by taking last 3 segments from toolchain
+    let mut arch_segments: Box<[_]> = toolchain.split('-').rev().take(3).collect();
+    arch_segments.reverse();
+    let host = arch_segments.join("-");
+
+    let home = env_or("RUSTUP_HOME", "");
+    let mut path = PathBuf::from(home);
+    path.push("toolchains");
+    path.push(toolchain);
+    path.push("lib");
+    path.push("rustlib");
+    path.push(host);
+    path.push("bin");
+    path.push(tool);
+    if path.exists() {
+        path.into_os_string().into_string().unwrap()
+    } else {
+        tool.into()
+    }
 }
 
 fn load_config(arch: Arch, matches: &clap::ArgMatches) -> Configuration {
@@ -289,10 +311,40 @@ fn verbose(matches: &clap::ArgMatches) -> bool {
     matches.get_flag("verbose")
 }
 
+/// Whether to build and run the kernel so that it exits QEMU with the result
+/// of its tests.  Only the qemu subcommand has the flag.
+fn qemu_test(matches: &clap::ArgMatches) -> bool {
+    let test = matches.try_get_one::<bool>("test").ok().flatten().copied();
+    test.unwrap_or(false) || ktest(matches)
+}
+
+/// Whether to build the kernel to run the tests registered with port::ktest!
+/// once booted, which implies qemu_test.  Only the qemu subcommand has the
+/// flag.
+fn ktest(matches: &clap::ArgMatches) -> bool {
+    matches.try_get_one::<bool>("ktest").ok().flatten().copied().unwrap_or(false)
+}
+
+/// Whether to embed a symbol table in the kernel, so that backtraces show
+/// function names.  Only aarch64 and riscv64 print backtraces.
+fn with_symbols(arch: Arch, matches: &clap::ArgMatches) -> bool {
+    let no_symbols = matches.try_get_one::<bool>("no_symbols").ok().flatten().copied();
+    arch != Arch::X86_64 && !no_symbols.unwrap_or(false)
+}
+
+fn with_digests(arch: Arch, matches: &clap::ArgMatches) -> bool {
+    let no_digests = matches.try_get_one::<bool>("no_digests").ok().flatten().copied();
+    arch == Arch::Aarch64 && !no_digests.unwrap_or(false)
+}
+
 struct BuildStep {
     arch: Arch,
     config: Configuration,
     profile: Profile,
+    qemu_test: bool,
+    ktest: bool,
+    symbols: bool,
+    digests: bool,
     verbose: bool,
 }
 
@@ -301,12 +353,19 @@ impl BuildStep {
         let arch = Arch::from(matches);
         let config = load_config(arch, matches);
         let profile = Profile::from(matches);
+        let qemu_test = qemu_test(matches);
+        let ktest = ktest(matches);
+        let symbols = with_symbols(arch, matches);
+        let digests = with_digests(arch, matches);
         let verbose = verbose(matches);
 
-        Self { arch, config, profile, verbose }
+        Self { arch, config, profile, qemu_test, ktest, symbols, digests, verbose }
     }
 
     fn run(self) -> Result<()> {
+        if self.ktest && self.arch == Arch::X86_64 {
+            return Err("ktest only supported under aarch64 and riscv64".into());
+        }
         let mut cmd = Command::new(cargo());
         cmd.arg("build");
 
@@ -325,6 +384,19 @@ impl BuildStep {
         if self.profile == Profile::Release {
             cmd.arg("--release");
         }
+        if self.qemu_test {
+            cmd.arg("--features")
+                .arg(format!("{}/qemu_test", self.arch.to_string().to_lowercase()));
+        }
+        if self.ktest {
+            cmd.arg("--features").arg(format!("{}/ktest", self.arch.to_string().to_lowercase()));
+        }
+        if self.symbols {
+            cmd.arg("--features").arg(format!("{}/symbols", self.arch.to_string().to_lowercase()));
+        }
+        if self.digests {
+            cmd.arg("--features").arg(format!("{}/digests", self.arch.to_string().to_lowercase()));
+        }
         cmd.arg("-Z").arg("build-std=core,alloc");
         if self.verbose {
             println!("Executing {cmd:?}");
@@ -333,6 +405,137 @@ impl BuildStep {
         if !status.success() {
             return Err("build kernel failed".into());
         }
+        if self.symbols {
+            self.embed_symbols()?;
+        }
+        // After the symbols, as they're in rodata
+        if self.digests {
+            self.embed_digests()?;
+        }
+        Ok(())
+    }
+
+    /// Fill the .ksyms section of the kernel, reserved by port::symbols,
+    /// with a table of the kernel's functions.  The section keeps its size,
+    /// so nothing else in the kernel moves.
+    fn embed_symbols(&self) -> Result<()> {
+        let dir = format!("target/{}/{}", self.arch.target(), self.profile.dir());
+        let kernel = format!("{dir}/{}", self.arch.to_string().to_lowercase());
+        let table_path = format!("{dir}/ksyms.bin");
+
+        let mut cmd = Command::new(nm());
+        cmd.arg("--defined-only").arg("--demangle").arg("--print-size").arg("--numeric-sort");
+        cmd.arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err("nm failed".into());
+        }
+        let mut table = symbols::symbol_table(&String::from_utf8(output.stdout)?);
+
+        // Dump the reserved section, to find its size
+        let mut cmd = Command::new(objcopy());
+        cmd.arg("--dump-section").arg(format!(".ksyms={table_path}")).arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let status = annotated_status(&mut cmd)?;
+        if !status.success() {
+            return Err("objcopy failed: can't find the .ksyms section".into());
+        }
+        let reserved = fs::metadata(workspace().join(&table_path))?.len() as usize;
+        if table.len() > reserved {
+            return Err(format!(
+                "symbol table is {} bytes, but only {reserved} are reserved: increase port::symbols::TABLE_SIZE",
+                table.len()
+            )
+            .into());
+        }
+        table.resize(reserved, 0);
+        fs::write(workspace().join(&table_path), table)?;
+
+        let mut cmd = Command::new(objcopy());
+        cmd.arg("--update-section").arg(format!(".ksyms={table_path}")).arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let status = annotated_status(&mut cmd)?;
+        if !status.success() {
+            return Err("objcopy failed".into());
+        }
+        Ok(())
+    }
+
+    /// Fill the .kdigests section of the kernel, reserved by port::digest,
+    /// with a digest of each of its read-only sections.  The section keeps
+    /// its size, so nothing else in the kernel moves.
+    fn embed_digests(&self) -> Result<()> {
+        let dir = format!("target/{}/{}", self.arch.target(), self.profile.dir());
+        let kernel = format!("{dir}/{}", self.arch.to_string().to_lowercase());
+        let table_path = format!("{dir}/kdigests.bin");
+
+        let mut cmd = Command::new(objdump());
+        cmd.arg("--section-headers").arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err("objdump failed".into());
+        }
+        let sections = digests::digested_sections(&String::from_utf8(output.stdout)?);
+
+        // Dump the reserved section, to find its size, and the sections to
+        // digest, to hash them
+        let mut cmd = Command::new(objcopy());
+        cmd.arg("--dump-section").arg(format!(".kdigests={table_path}"));
+        for section in &sections {
+            cmd.arg("--dump-section").arg(format!("{}={}", section.name, section.dump_path(&dir)));
+        }
+        cmd.arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let status = annotated_status(&mut cmd)?;
+        if !status.success() {
+            return Err("objcopy failed: can't find the .kdigests section".into());
+        }
+        let mut digested = Vec::new();
+        for section in sections {
+            let path = workspace().join(section.dump_path(&dir));
+            let contents = fs::read(&path)?;
+            fs::remove_file(&path)?;
+            digested.push((section, port::digest::fnv1a(&contents)));
+        }
+        let mut table = digests::digest_table(&digested);
+        let reserved = fs::metadata(workspace().join(&table_path))?.len() as usize;
+        if table.len() > reserved {
+            return Err(format!(
+                "digest table is {} bytes, but only {reserved} are reserved: increase port::digest::TABLE_SIZE",
+                table.len()
+            )
+            .into());
+        }
+        table.resize(reserved, 0);
+        fs::write(workspace().join(&table_path), table)?;
+
+        let mut cmd = Command::new(objcopy());
+        cmd.arg("--update-section").arg(format!(".kdigests={table_path}")).arg(&kernel);
+        cmd.current_dir(workspace());
+        if self.verbose {
+            println!("Executing {cmd:?}");
+        }
+        let status = annotated_status(&mut cmd)?;
+        if !status.success() {
+            return Err("objcopy failed".into());
+        }
         Ok(())
     }
 }
@@ -440,6 +643,7 @@ struct QemuStep {
     wait_for_gdb: bool,
     kvm: bool,
     dump_dtb: String,
+    qemu_test: bool,
     verbose: bool,
 }
 
@@ -456,9 +660,28 @@ impl QemuStep {
             .flatten()
             .unwrap_or(&"".to_string())
             .clone();
+        let qemu_test = qemu_test(matches);
         let verbose = verbose(matches);
 
-        Self { arch, config, profile, wait_for_gdb, kvm, dump_dtb, verbose }
+        Self { arch, config, profile, wait_for_gdb, kvm, dump_dtb, qemu_test, verbose }
+    }
+
+    /// Check the exit status of QEMU.  With --test or --ktest, the kernel
+    /// exits QEMU with the result of its tests, which isn't always 0 on
+    /// success, e.g. isa-debug-exit on x86-64 exits with (value << 1) | 1.
+    fn check_status(&self, status: process::ExitStatus) -> Result<()> {
+        if !self.qemu_test {
+            return if status.success() { Ok(()) } else { Err("qemu failed".into()) };
+        }
+        let passed = match self.arch {
+            Arch::X86_64 => status.code() == Some(33),
+            Arch::Aarch64 | Arch::Riscv64 => status.success(),
+        };
+        if !passed {
+            return Err(format!("qemu tests failed: {status}").into());
+        }
+        println!("qemu tests passed");
+        Ok(())
     }
 
     fn run(self) -> Result<()> {
@@ -489,6 +712,10 @@ impl QemuStep {
                 if self.wait_for_gdb {
                     cmd.arg("-s").arg("-S");
                 }
+                if self.qemu_test {
+                    // The kernel uses semihosting for its console, and to exit
+                    cmd.arg("-semihosting-config").arg("enable=on,target=native");
+                }
                 // Show exception level change events in stdout
                 cmd.arg("-d");
                 cmd.arg("int");
@@ -499,9 +726,7 @@ impl QemuStep {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
             Arch::Riscv64 => {
                 let mut cmd = Command::new(qemu_system);
@@ -537,9 +762,7 @@ impl QemuStep {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
             Arch::X86_64 => {
                 let mut cmd = Command::new(qemu_system);
@@ -566,6 +789,9 @@ impl QemuStep {
                 //cmd.arg("id=sdahci0,file=sdahci0.img,if=none");
                 //cmd.arg("-device");
                 //cmd.arg("ide-hd,drive=sdahci0,bus=ahci0.0");
+                if self.qemu_test {
+                    cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+                }
                 cmd.arg("-kernel");
                 cmd.arg(format!("target/{}/{}/r9.elf32", target, dir));
                 cmd.current_dir(workspace());
@@ -573,9 +799,7 @@ impl QemuStep {
                     println!("Executing {cmd:?}");
                 }
                 let status = annotated_status(&mut cmd)?;
-                if !status.success() {
-                    return Err("qemu failed".into());
-                }
+                self.check_status(status)?;
             }
         };
 
@@ -685,7 +909,7 @@ impl TestStep {
             "test".to_string(),
             "--package".to_string(),
             "port".to_string(),
-            "--lib".to_string(),
+            "--tests".to_string(),
         ]);
 
         let rustup_state = RustupState::new();
@@ -700,6 +924,18 @@ impl TestStep {
                 "--target".to_string(),
                 target.to_string(),
             ]);
+            // The riscv64 tests don't depend on the host arch, so run them
+            // on any host
+            if arch != "riscv64" {
+                all_cmd_args.push(vec![
+                    "test".to_string(),
+                    "--package".to_string(),
+                    "riscv64".to_string(),
+                    "--bins".to_string(),
+                    "--target".to_string(),
+                    target.to_string(),
+                ]);
+            }
         }
 
         for cmd_args in all_cmd_args {
@@ -0,0 +1,111 @@
+/// Builds the symbol table that's embedded in the kernel's .ksyms section,
+/// from the output of `llvm-nm --defined-only --demangle --print-size
+/// --numeric-sort`.  The format is described in port::symbols, which reads
+/// it.
+const MAGIC: &[u8; 4] = b"KSYM";
+
+struct Symbol {
+    addr: u64,
+    size: Option<u64>,
+    name: String,
+}
+
+/// Parse a line of nm output: the address, the size if it's known, the
+/// type, and the name, which may contain spaces once demangled.  Returns
+/// None unless it's a function.
+fn parse_line(line: &str) -> Option<Symbol> {
+    let (addr, rest) = line.split_once(' ')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let (size, rest) = match rest.split_once(' ')? {
+        (size, rest) if size.len() > 1 => (u64::from_str_radix(size, 16).ok(), rest),
+        _ => (None, rest),
+    };
+    let (kind, name) = rest.split_once(' ')?;
+    if !matches!(kind, "t" | "T" | "W") || name.starts_with('$') || name.starts_with(".L") {
+        return None;
+    }
+    Some(Symbol { addr, size, name: unescape(strip_hash(name)) })
+}
+
+/// Strip the hash that legacy mangling appends to Rust symbols, e.g.
+/// "port::fdt::parse::h0123456789abcdef".
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+/// Decode the escapes that legacy mangling uses for characters that can't
+/// appear in symbols, which nm leaves in, e.g. "drop_in_place$LT$alloc..vec..Vec$GT$"
+/// is "drop_in_place<alloc::vec::Vec>".
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = tail;
+            continue;
+        }
+        if let Some((escape, tail)) =
+            rest.strip_prefix('$').and_then(|r| r.split_once('$')).filter(|(e, _)| e.len() <= 5)
+        {
+            let c = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            if let Some(c) = c {
+                out.push(c);
+                rest = tail;
+                continue;
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Returns the symbol table for the functions in `nm_output`, sorted by
+/// address, with an empty name marking the end of the last one.
+pub fn symbol_table(nm_output: &str) -> Vec<u8> {
+    let mut symbols: Vec<Symbol> = nm_output.lines().filter_map(parse_line).collect();
+    symbols.sort_by_key(|s| s.addr);
+    symbols.dedup_by_key(|s| s.addr);
+    let end = symbols.iter().filter_map(|s| s.size.map(|size| s.addr + size)).max();
+    if let (Some(end), Some(last)) = (end, symbols.last()) {
+        if end > last.addr {
+            symbols.push(Symbol { addr: end, size: None, name: String::new() });
+        }
+    }
+
+    let mut table = MAGIC.to_vec();
+    table.extend((symbols.len() as u32).to_le_bytes());
+    for s in &symbols {
+        table.extend(s.addr.to_le_bytes());
+    }
+    let mut offset = 0u32;
+    for s in &symbols {
+        table.extend(offset.to_le_bytes());
+        offset += s.name.len() as u32;
+    }
+    table.extend(offset.to_le_bytes());
+    for s in &symbols {
+        table.extend(s.name.as_bytes());
+    }
+    table
+}